@@ -48,5 +48,7 @@ fn plugins(decoded: &toml::Value) {
         .write_all(b"pub(crate) async fn registers(default_startups: Vec<String>) -> rmqtt::Result<()>{\n")
         .unwrap();
     plugin_rs.write_all(inits.join("\n").as_bytes()).unwrap();
+    // Start the default startup plugins once all are registered, in dependency order
+    plugin_rs.write_all(b"\n    rmqtt::Runtime::instance().plugins.startup().await?;").unwrap();
     plugin_rs.write_all(b"\n    Ok(())\n}").unwrap();
 }
@@ -1,11 +1,9 @@
 #![deny(unsafe_code)]
 
 use std::time::Duration;
-use std::{fs::File, io::BufReader};
-
-use rustls::internal::pemfile::{certs, rsa_private_keys};
-use rustls::{NoClientAuth, ServerConfig};
 
+use rmqtt::broker::alarm::Alarms;
+use rmqtt::broker::purge::OfflinePurge;
 use rmqtt::broker::{
     v3::control_message as control_message_v3, v3::handshake as handshake_v3, v3::publish as publish_v3,
     v5::control_message as control_message_v5, v5::handshake as handshake_v5, v5::publish as publish_v5,
@@ -28,6 +26,8 @@ use rmqtt::settings::{listener::Listener, Options, Settings};
 use rmqtt::{log, structopt::StructOpt, tokio};
 use rmqtt::{logger::logger_init, MqttError, Result, Runtime, SessionState};
 
+mod systemd;
+mod tls;
 mod ws;
 
 #[cfg(target_os = "linux")]
@@ -56,6 +56,11 @@ async fn main() {
 
     Settings::logs();
 
+    //init OpenTelemetry tracing
+    if let Err(e) = rmqtt::telemetry::init() {
+        log::error!("Failed to init OpenTelemetry tracing, {:?}", e);
+    }
+
     //register plugin
     plugin::registers(plugin::default_startups())
         .await
@@ -65,35 +70,51 @@ async fn main() {
     //start gRPC server
     Runtime::instance().node.start_grpc_server();
 
+    //reload config on SIGHUP
+    #[cfg(unix)]
+    reload_on_sighup();
+
+    //mark the node as stopping on SIGINT or SIGTERM
+    stopping_on_signal();
+
     //hook, before startup
     Runtime::instance().extends.hook_mgr().await.before_startup().await;
 
+    //start the alarm checks
+    Alarms::instance().start_monitor();
+
+    //start purging the aged messages of the offline sessions
+    OfflinePurge::instance().start();
+
+    //notify systemd once the listeners are running, and ping its watchdog
+    systemd::start();
+
     //tcp
     let mut tcp_listens = Vec::new();
     for (_, listen_cfg) in Runtime::instance().settings.listeners.tcps.iter() {
         let name = format!("{}/{:?}", &listen_cfg.name, &listen_cfg.addr);
-        tcp_listens.push(listen(name, listen_cfg));
+        tcp_listens.push(systemd::watch(listen_cfg.addr, listen(name, listen_cfg)));
     }
 
     //tls
     let mut tls_listens = Vec::new();
     for (_, listen_cfg) in Runtime::instance().settings.listeners.tlss.iter() {
         let name = format!("{}/{:?}", &listen_cfg.name, &listen_cfg.addr);
-        tls_listens.push(listen_tls(name, listen_cfg));
+        tls_listens.push(systemd::watch(listen_cfg.addr, listen_tls(name, listen_cfg)));
     }
 
     //websocket
     let mut ws_listens = Vec::new();
     for (_, listen_cfg) in Runtime::instance().settings.listeners.wss.iter() {
         let name = format!("{}/{:?}", &listen_cfg.name, &listen_cfg.addr);
-        ws_listens.push(listen_ws(name, listen_cfg));
+        ws_listens.push(systemd::watch(listen_cfg.addr, listen_ws(name, listen_cfg)));
     }
 
     //tls-websocket
     let mut wss_listens = Vec::new();
     for (_, listen_cfg) in Runtime::instance().settings.listeners.wsss.iter() {
         let name = format!("{}/{:?}", &listen_cfg.name, &listen_cfg.addr);
-        wss_listens.push(listen_wss(name, listen_cfg));
+        wss_listens.push(systemd::watch(listen_cfg.addr, listen_wss(name, listen_cfg)));
     }
 
     let _ = futures::future::join4(
@@ -103,111 +124,194 @@ async fn main() {
         futures::future::join_all(wss_listens),
     )
     .await;
+    Runtime::instance().node.set_stopping();
+    systemd::stopping();
+    rmqtt::telemetry::shutdown();
     tokio::time::sleep(Duration::from_secs(1)).await;
 }
 
+#[cfg(unix)]
+fn reload_on_sighup() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            log::error!("Failed to listen SIGHUP signal, {:?}", e);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            log::info!("SIGHUP received, reload config");
+            systemd::reloading();
+            tls::reload_certs();
+            let res = Runtime::instance().reload_config().await;
+            systemd::reloaded();
+            match res {
+                Ok(res) => {
+                    if !res.restart_required.is_empty() {
+                        log::warn!(
+                            "Restart is required for the changes to take effect, {:?}",
+                            res.restart_required
+                        );
+                    }
+                }
+                Err(e) => log::error!("Failed to reload config, {:?}", e),
+            }
+        }
+    });
+}
+
+fn stopping_on_signal() {
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            match signal(SignalKind::terminate()) {
+                Ok(mut terminate) => {
+                    tokio::select! {
+                        _ = tokio::signal::ctrl_c() => {},
+                        _ = terminate.recv() => {},
+                    }
+                }
+                Err(e) => {
+                    log::error!("Failed to listen SIGTERM signal, {:?}", e);
+                    let _ = tokio::signal::ctrl_c().await;
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+        log::info!("the node is stopping");
+        Runtime::instance().node.set_stopping();
+    });
+}
+
 async fn listen(name: String, listen_cfg: &Listener) -> Result<()> {
-    async fn _listen(name: &str, listen_cfg: &Listener) -> Result<()> {
+    async fn _listen(
+        name: &str,
+        listen_cfg: &Listener,
+        lst: Option<std::net::TcpListener>,
+        workers: usize,
+    ) -> Result<()> {
         let max_inflight = listen_cfg.max_inflight;
         let handshake_timeout = listen_cfg.handshake_timeout();
         let max_size = listen_cfg.max_packet_size.as_u32();
         let max_qos = listen_cfg.max_qos_allowed;
         let max_awaiting_rel = listen_cfg.max_awaiting_rel;
         let await_rel_timeout = listen_cfg.await_rel_timeout;
-        ntex::server::Server::build()
-            .bind(name, listen_cfg.addr, move || {
-                MqttServer::new()
-                    .v3(v3::MqttServer::new(move |mut handshake: HandshakeV3<TcpStream>| async {
-                        let remote_addr = handshake.io().peer_addr()?;
-                        let local_addr = handshake.io().local_addr()?;
-                        let listen_cfg =
-                            Runtime::instance().settings.listeners.tcp(local_addr.port()).ok_or_else(
-                                || {
-                                    log::error!(
-                                        "tcp listener config is not found, local addr is {:?}",
-                                        local_addr
-                                    );
-                                    MqttError::ListenerConfigError
-                                },
-                            )?;
-                        handshake_v3(listen_cfg, handshake, remote_addr, local_addr).await
-                    })
-                    // .v3(v3::MqttServer::new(handshake_v3)
-                    .inflight(max_inflight)
-                    .handshake_timeout(handshake_timeout)
-                    .max_size(max_size)
-                    .max_awaiting_rel(max_awaiting_rel)
-                    .await_rel_timeout(await_rel_timeout)
-                    .publish(fn_factory_with_config(|session: v3::Session<SessionState>| {
-                        ok::<_, MqttError>(fn_service(move |req| publish_v3(session.clone(), req)))
-                    }))
-                    .control(fn_factory_with_config(
-                        |session: v3::Session<SessionState>| {
-                            ok::<_, MqttError>(fn_service(move |req| {
-                                control_message_v3(session.clone(), req)
-                            }))
-                        },
-                    )))
-                    .v5(v5::MqttServer::new(move |mut handshake: HandshakeV5<TcpStream>| async {
-                        let peer_addr = handshake.io().peer_addr()?;
-                        let local_addr = handshake.io().local_addr()?;
-                        let listen_cfg =
-                            Runtime::instance().settings.listeners.tcp(local_addr.port()).ok_or_else(
-                                || {
-                                    log::error!(
-                                        "tcp listener config is not found, local addr is {:?}",
-                                        local_addr
-                                    );
-                                    MqttError::ListenerConfigError
-                                },
-                            )?;
-                        handshake_v5(listen_cfg, handshake, peer_addr, local_addr).await
-                    })
-                    //v5::MqttServer::new(handshake_v5)
-                    .receive_max(max_inflight as u16)
-                    .handshake_timeout(handshake_timeout)
-                    .max_size(max_size)
-                    .max_qos(max_qos)
-                    //.max_topic_alias(max_topic_alias),
-                    .max_awaiting_rel(max_awaiting_rel)
-                    .await_rel_timeout(await_rel_timeout)
-                    .publish(fn_factory_with_config(|session: v5::Session<SessionState>| {
-                        ok::<_, MqttError>(fn_service(move |req| publish_v5(session.clone(), req)))
-                    }))
-                    .control(fn_factory_with_config(
-                        |session: v5::Session<SessionState>| {
-                            ok::<_, MqttError>(fn_service(move |req| {
-                                control_message_v5(session.clone(), req)
-                            }))
-                        },
-                    )))
-            })?
-            .workers(listen_cfg.workers)
-            .maxconn(listen_cfg.max_connections / listen_cfg.workers)
+        let factory = move || {
+            MqttServer::new()
+                .v3(v3::MqttServer::new(move |mut handshake: HandshakeV3<TcpStream>| async {
+                    let remote_addr = handshake.io().peer_addr()?;
+                    let local_addr = handshake.io().local_addr()?;
+                    let listen_cfg =
+                        Runtime::instance().settings.listeners.tcp(local_addr.port()).ok_or_else(|| {
+                            log::error!("tcp listener config is not found, local addr is {:?}", local_addr);
+                            MqttError::ListenerConfigError
+                        })?;
+                    handshake_v3(listen_cfg, handshake, remote_addr, local_addr).await
+                })
+                // .v3(v3::MqttServer::new(handshake_v3)
+                .inflight(max_inflight)
+                .handshake_timeout(handshake_timeout)
+                .max_size(max_size)
+                .max_awaiting_rel(max_awaiting_rel)
+                .await_rel_timeout(await_rel_timeout)
+                .publish(fn_factory_with_config(|session: v3::Session<SessionState>| {
+                    ok::<_, MqttError>(fn_service(move |req| publish_v3(session.clone(), req)))
+                }))
+                .control(fn_factory_with_config(|session: v3::Session<SessionState>| {
+                    ok::<_, MqttError>(fn_service(move |req| control_message_v3(session.clone(), req)))
+                })))
+                .v5(v5::MqttServer::new(move |mut handshake: HandshakeV5<TcpStream>| async {
+                    let peer_addr = handshake.io().peer_addr()?;
+                    let local_addr = handshake.io().local_addr()?;
+                    let listen_cfg =
+                        Runtime::instance().settings.listeners.tcp(local_addr.port()).ok_or_else(|| {
+                            log::error!("tcp listener config is not found, local addr is {:?}", local_addr);
+                            MqttError::ListenerConfigError
+                        })?;
+                    handshake_v5(listen_cfg, handshake, peer_addr, local_addr).await
+                })
+                //v5::MqttServer::new(handshake_v5)
+                .receive_max(max_inflight as u16)
+                .handshake_timeout(handshake_timeout)
+                .max_size(max_size)
+                .max_qos(max_qos)
+                //.max_topic_alias(max_topic_alias),
+                .max_awaiting_rel(max_awaiting_rel)
+                .await_rel_timeout(await_rel_timeout)
+                .publish(fn_factory_with_config(|session: v5::Session<SessionState>| {
+                    ok::<_, MqttError>(fn_service(move |req| publish_v5(session.clone(), req)))
+                }))
+                .control(fn_factory_with_config(|session: v5::Session<SessionState>| {
+                    ok::<_, MqttError>(fn_service(move |req| control_message_v5(session.clone(), req)))
+                })))
+        };
+        let builder = ntex::server::Server::build();
+        let builder = if let Some(lst) = lst {
+            builder.listen(name, lst, factory)?
+        } else {
+            builder.bind(name, listen_cfg.addr, factory)?
+        };
+        let server = builder
+            .workers(workers)
+            .maxconn((listen_cfg.max_connections + listen_cfg.max_handshaking_limit) / listen_cfg.workers)
             .backlog(listen_cfg.backlog)
-            .run()
-            .await?;
+            .run();
+        systemd::listener_running(listen_cfg.addr);
+        server.await?;
         Ok(())
     }
 
-    _listen(&format!("tcp: {}", name), listen_cfg).await.map_err(|e| {
+    if listen_cfg.reuseport {
+        //One server with a single worker and its own acceptor for each worker
+        let mut servers = Vec::new();
+        for i in 0..listen_cfg.workers {
+            let lst = reuseport_listener(listen_cfg.addr, listen_cfg.backlog).map_err(|e| {
+                log::error!("Listen {:?} failed on {}, {:?}", name, listen_cfg.addr, e);
+                e
+            })?;
+            servers.push(async move {
+                let name = format!("tcp: {}#{}", name, i);
+                _listen(&name, listen_cfg, Some(lst), 1).await.map_err(|e| {
+                    log::error!("Listen {:?} failed on {}, {:?}", name, listen_cfg.addr, e);
+                    e
+                })
+            });
+        }
+        futures::future::try_join_all(servers).await?;
+        return Ok(());
+    }
+
+    _listen(&format!("tcp: {}", name), listen_cfg, None, listen_cfg.workers).await.map_err(|e| {
         log::error!("Listen {:?} failed on {}, {:?}", name, listen_cfg.addr, e);
         e
     })
 }
 
-async fn listen_tls(name: String, listen_cfg: &Listener) -> Result<()> {
-    async fn _listen_tls(name: &str, listen_cfg: &Listener) -> Result<()> {
-        let mut tls_config = ServerConfig::new(NoClientAuth::new());
-
-        let cert_file = &mut BufReader::new(File::open(listen_cfg.cert.as_ref().unwrap())?);
-        let key_file = &mut BufReader::new(File::open(listen_cfg.key.as_ref().unwrap())?);
+///A listening socket with SO_REUSEPORT, so that several sockets can be bound to the same address
+#[cfg(unix)]
+fn reuseport_listener(addr: std::net::SocketAddr, backlog: i32) -> Result<std::net::TcpListener> {
+    use socket2::{Domain, Socket, Type};
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+    Ok(socket.into())
+}
 
-        let cert_chain = certs(cert_file).unwrap();
-        let mut keys = rsa_private_keys(key_file).unwrap();
-        tls_config.set_single_cert(cert_chain, keys.remove(0)).map_err(|e| MqttError::from(e.to_string()))?;
+#[cfg(not(unix))]
+fn reuseport_listener(_addr: std::net::SocketAddr, _backlog: i32) -> Result<std::net::TcpListener> {
+    Err(MqttError::from("SO_REUSEPORT is not supported on this platform"))
+}
 
-        let tls_acceptor = Acceptor::new(tls_config);
+async fn listen_tls(name: String, listen_cfg: &Listener) -> Result<()> {
+    async fn _listen_tls(name: &str, listen_cfg: &Listener) -> Result<()> {
+        let tls_acceptor = Acceptor::new(tls::server_config(listen_cfg)?);
 
         let max_inflight = listen_cfg.max_inflight;
         let handshake_timeout = listen_cfg.handshake_timeout();
@@ -215,7 +319,7 @@ async fn listen_tls(name: String, listen_cfg: &Listener) -> Result<()> {
         let max_qos = listen_cfg.max_qos_allowed;
         let max_awaiting_rel = listen_cfg.max_awaiting_rel;
         let await_rel_timeout = listen_cfg.await_rel_timeout;
-        ntex::server::Server::build()
+        let server = ntex::server::Server::build()
             .bind(name, listen_cfg.addr, move || {
                 pipeline_factory(tls_acceptor.clone())
                     .map_err(|e| ntex_mqtt::MqttError::Service(MqttError::from(e)))
@@ -301,10 +405,11 @@ async fn listen_tls(name: String, listen_cfg: &Listener) -> Result<()> {
                     )
             })?
             .workers(listen_cfg.workers)
-            .maxconn(listen_cfg.max_connections / listen_cfg.workers)
+            .maxconn((listen_cfg.max_connections + listen_cfg.max_handshaking_limit) / listen_cfg.workers)
             .backlog(listen_cfg.backlog)
-            .run()
-            .await?;
+            .run();
+        systemd::listener_running(listen_cfg.addr);
+        server.await?;
         Ok(())
     }
 
@@ -329,9 +434,17 @@ async fn listen_ws(name: String, listen_cfg: &Listener) -> Result<()> {
         let max_qos = listen_cfg.max_qos_allowed;
         let max_awaiting_rel = listen_cfg.max_awaiting_rel;
         let await_rel_timeout = listen_cfg.await_rel_timeout;
-        ntex::server::Server::build()
+        let subprotocols = ws::Subprotocols {
+            allowed: listen_cfg.ws_subprotocols.clone(),
+            required: listen_cfg.ws_subprotocol_required,
+        };
+        let server = ntex::server::Server::build()
             .bind(name, listen_cfg.addr, move || {
-                pipeline_factory(ws::WSServer::new(Duration::from_secs(handshake_timeout as u64))).and_then(
+                pipeline_factory(ws::WSServer::new(
+                    Duration::from_secs(handshake_timeout as u64),
+                    subprotocols.clone(),
+                ))
+                .and_then(
                     MqttServer::new()
                         .v3(v3::MqttServer::new(
                             move |mut handshake: HandshakeV3<ws::WsStream<TcpStream>>| async {
@@ -404,10 +517,11 @@ async fn listen_ws(name: String, listen_cfg: &Listener) -> Result<()> {
                 )
             })?
             .workers(listen_cfg.workers)
-            .maxconn(listen_cfg.max_connections / listen_cfg.workers)
+            .maxconn((listen_cfg.max_connections + listen_cfg.max_handshaking_limit) / listen_cfg.workers)
             .backlog(listen_cfg.backlog)
-            .run()
-            .await?;
+            .run();
+        systemd::listener_running(listen_cfg.addr);
+        server.await?;
         Ok(())
     }
 
@@ -419,16 +533,7 @@ async fn listen_ws(name: String, listen_cfg: &Listener) -> Result<()> {
 
 async fn listen_wss(name: String, listen_cfg: &Listener) -> Result<()> {
     async fn _listen_wss(name: &str, listen_cfg: &Listener) -> Result<()> {
-        let mut tls_config = ServerConfig::new(NoClientAuth::new());
-
-        let cert_file = &mut BufReader::new(File::open(listen_cfg.cert.as_ref().unwrap())?);
-        let key_file = &mut BufReader::new(File::open(listen_cfg.key.as_ref().unwrap())?);
-
-        let cert_chain = certs(cert_file).unwrap();
-        let mut keys = rsa_private_keys(key_file).unwrap();
-        tls_config.set_single_cert(cert_chain, keys.remove(0)).map_err(|e| MqttError::from(e.to_string()))?;
-
-        let tls_acceptor = Acceptor::new(tls_config);
+        let tls_acceptor = Acceptor::new(tls::server_config(listen_cfg)?);
 
         let max_inflight = listen_cfg.max_inflight;
         let handshake_timeout = listen_cfg.handshake_timeout();
@@ -436,11 +541,18 @@ async fn listen_wss(name: String, listen_cfg: &Listener) -> Result<()> {
         let max_qos = listen_cfg.max_qos_allowed;
         let max_awaiting_rel = listen_cfg.max_awaiting_rel;
         let await_rel_timeout = listen_cfg.await_rel_timeout;
-        ntex::server::Server::build()
+        let subprotocols = ws::Subprotocols {
+            allowed: listen_cfg.ws_subprotocols.clone(),
+            required: listen_cfg.ws_subprotocol_required,
+        };
+        let server = ntex::server::Server::build()
             .bind(name, listen_cfg.addr, move || {
                 pipeline_factory(tls_acceptor.clone())
                     .map_err(|e| ntex_mqtt::MqttError::Service(MqttError::from(e)))
-                    .and_then(ws::WSServer::new(Duration::from_secs(handshake_timeout as u64)))
+                    .and_then(ws::WSServer::new(
+                        Duration::from_secs(handshake_timeout as u64),
+                        subprotocols.clone(),
+                    ))
                     .and_then(
                         MqttServer::new()
                             .v3(v3::MqttServer::new(
@@ -517,10 +629,11 @@ async fn listen_wss(name: String, listen_cfg: &Listener) -> Result<()> {
                     )
             })?
             .workers(listen_cfg.workers)
-            .maxconn(listen_cfg.max_connections / listen_cfg.workers)
+            .maxconn((listen_cfg.max_connections + listen_cfg.max_handshaking_limit) / listen_cfg.workers)
             .backlog(listen_cfg.backlog)
-            .run()
-            .await?;
+            .run();
+        systemd::listener_running(listen_cfg.addr);
+        server.await?;
         Ok(())
     }
 
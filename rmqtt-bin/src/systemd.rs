@@ -0,0 +1,147 @@
+//! The systemd notification protocol, with Type=notify the service is started once the listeners
+//! are running, and with WatchdogSec= systemd restarts it if the watchdog pings stop, which they do
+//! when the event loop is blocked or a listener has stopped
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rmqtt::once_cell::sync::Lazy;
+use rmqtt::{log, tokio, Runtime};
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ListenerState {
+    Starting,
+    Running,
+    Stopped,
+}
+
+///States of the listeners of this node, by listen address, set by the listener tasks themselves
+static LISTENERS: Lazy<Mutex<HashMap<SocketAddr, ListenerState>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[inline]
+fn set_listener_state(addr: SocketAddr, state: ListenerState) {
+    LISTENERS.lock().unwrap_or_else(|e| e.into_inner()).insert(addr, state);
+}
+
+///Tracks the task of the listener at addr, it is stopped once the task returns
+pub(crate) fn watch<F: Future>(addr: SocketAddr, listen: F) -> impl Future<Output = F::Output> {
+    set_listener_state(addr, ListenerState::Starting);
+    async move {
+        let res = listen.await;
+        set_listener_state(addr, ListenerState::Stopped);
+        res
+    }
+}
+
+///The listener at addr is bound and its server is running
+#[inline]
+pub(crate) fn listener_running(addr: SocketAddr) {
+    set_listener_state(addr, ListenerState::Running);
+}
+
+#[cfg(unix)]
+fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    if path.starts_with('@') {
+        log::warn!("systemd notify, abstract socket {} is not supported", path);
+        return;
+    }
+    if let Err(e) = UnixDatagram::unbound().and_then(|socket| socket.send_to(state.as_bytes(), &path)) {
+        log::warn!("systemd notify {:?} error, {:?}", state, e);
+    }
+}
+
+#[cfg(not(unix))]
+fn notify(_state: &str) {}
+
+#[inline]
+pub(crate) fn reloading() {
+    notify("RELOADING=1");
+}
+
+#[inline]
+pub(crate) fn reloaded() {
+    notify("READY=1");
+}
+
+#[inline]
+pub(crate) fn stopping() {
+    notify("STOPPING=1");
+}
+
+///Interval of the pings, half of WatchdogSec=, None unless the watchdog is enabled for this process
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+///Sends READY=1 once all listeners accept connections, and then the watchdog pings while the
+///health check passes
+pub(crate) fn start() {
+    if std::env::var_os("NOTIFY_SOCKET").is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        while let Err(e) = check_listeners() {
+            log::debug!("systemd notify, not ready yet, {}", e);
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        notify(&format!("READY=1\nMAINPID={}", std::process::id()));
+        log::info!("systemd notified, ready");
+
+        let interval = match watchdog_interval() {
+            Some(interval) => interval,
+            None => return,
+        };
+        log::info!("systemd watchdog enabled, ping interval: {:?}", interval);
+        loop {
+            tokio::time::sleep(interval).await;
+            match check_health().await {
+                Ok(()) => notify("WATCHDOG=1"),
+                Err(e) => log::error!("systemd watchdog, health check failed, {}", e),
+            }
+        }
+    });
+}
+
+async fn check_health() -> Result<(), String> {
+    //a task spawned on a blocked event loop does not complete in time
+    tokio::time::timeout(CHECK_TIMEOUT, tokio::spawn(async {}))
+        .await
+        .map_err(|_| "the event loop is not responding".to_string())?
+        .map_err(|e| e.to_string())?;
+    check_listeners()
+}
+
+///Whether all the listeners configured are running, from the states set by their tasks, no
+///connection is made to them
+fn check_listeners() -> Result<(), String> {
+    let listeners = &Runtime::instance().settings.listeners;
+    let configured = listeners.tcps.len() + listeners.tlss.len() + listeners.wss.len() + listeners.wsss.len();
+    let states = LISTENERS.lock().unwrap_or_else(|e| e.into_inner());
+    if states.len() < configured {
+        return Err(format!("{} of {} listeners are started", states.len(), configured));
+    }
+    for (addr, state) in states.iter() {
+        match state {
+            ListenerState::Running => {}
+            ListenerState::Starting => return Err(format!("listener {} is not running yet", addr)),
+            ListenerState::Stopped => return Err(format!("listener {} has stopped", addr)),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,153 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::sign::{self, CertifiedKey, SigningKey};
+use rustls::{Certificate, ClientHello, NoClientAuth, ResolvesServerCert, ServerConfig, SignatureScheme};
+
+use rmqtt::once_cell::sync::Lazy;
+use rmqtt::settings::listener::Listener;
+use rmqtt::{log, tokio, MqttError, Result, RwLock};
+
+static RESOLVERS: Lazy<RwLock<Vec<Arc<CertResolver>>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+///The certificate of a TLS listener, replaced when its files are reloaded. The established
+///connections are not affected, the new certificate is used by the next handshakes
+pub(crate) struct CertResolver {
+    cert: String,
+    key: String,
+    certified: RwLock<CertifiedKey>,
+    modified: RwLock<Option<(SystemTime, SystemTime)>>,
+}
+
+impl CertResolver {
+    fn new(cert: &str, key: &str) -> Result<Self> {
+        let modified = Self::modified(cert, key);
+        Ok(Self {
+            cert: cert.into(),
+            key: key.into(),
+            certified: RwLock::new(Self::load(cert, key)?),
+            modified: RwLock::new(modified),
+        })
+    }
+
+    fn load(cert: &str, key: &str) -> Result<CertifiedKey> {
+        let cert_chain = certs(&mut BufReader::new(File::open(cert)?))
+            .map_err(|_| MqttError::from(format!("invalid certificate, {}", cert)))?;
+        if cert_chain.is_empty() {
+            return Err(MqttError::from(format!("no certificate found, {}", cert)));
+        }
+        let mut keys = rsa_private_keys(&mut BufReader::new(File::open(key)?))
+            .map_err(|_| MqttError::from(format!("invalid private key, {}", key)))?;
+        if keys.is_empty() {
+            keys = pkcs8_private_keys(&mut BufReader::new(File::open(key)?))
+                .map_err(|_| MqttError::from(format!("invalid private key, {}", key)))?;
+        }
+        let key = keys.first().ok_or_else(|| MqttError::from(format!("no private key found, {}", key)))?;
+        let signing_key = sign::any_supported_type(key)
+            .map_err(|_| MqttError::from("the private key type is not supported"))?;
+        Self::check_key_pair(&cert_chain[0], signing_key.as_ref())
+            .map_err(|e| MqttError::from(format!("{}, {}", e, cert)))?;
+        Ok(CertifiedKey::new(cert_chain, Arc::new(signing_key)))
+    }
+
+    ///Whether the private key is the one of the certificate, a signature made with the key is
+    ///verified with the public key of the certificate, such as to refuse a half-written renewal
+    fn check_key_pair(cert: &Certificate, key: &dyn SigningKey) -> Result<()> {
+        let schemes = [
+            SignatureScheme::RSA_PKCS1_SHA256,
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::ED25519,
+        ];
+        let signer = key
+            .choose_scheme(&schemes)
+            .ok_or_else(|| MqttError::from("the private key type is not supported"))?;
+        let alg = match signer.get_scheme() {
+            SignatureScheme::RSA_PKCS1_SHA256 => &webpki::RSA_PKCS1_2048_8192_SHA256,
+            SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+            _ => &webpki::ED25519,
+        };
+        let msg = b"rmqtt certificate and private key check";
+        let signature = signer.sign(msg).map_err(|e| MqttError::from(format!("{:?}", e)))?;
+        webpki::EndEntityCert::from(&cert.0)
+            .and_then(|cert| cert.verify_signature(alg, msg, &signature))
+            .map_err(|e| MqttError::from(format!("the private key does not match the certificate, {:?}", e)))
+    }
+
+    #[inline]
+    fn modified(cert: &str, key: &str) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some((modified(cert)?, modified(key)?))
+    }
+
+    ///Loads the certificate again, the current one is kept if it fails, such as when the private
+    ///key does not match the certificate yet
+    pub(crate) fn reload(&self) -> Result<()> {
+        let modified = Self::modified(&self.cert, &self.key);
+        let certified = Self::load(&self.cert, &self.key)?;
+        *self.certified.write() = certified;
+        *self.modified.write() = modified;
+        log::info!("TLS certificate reloaded, cert: {}, key: {}", self.cert, self.key);
+        Ok(())
+    }
+
+    ///Loads the certificate again if its files have been modified
+    fn reload_modified(&self) {
+        let modified = Self::modified(&self.cert, &self.key);
+        if modified.is_none() || modified == *self.modified.read() {
+            return;
+        }
+        if let Err(e) = self.reload() {
+            log::warn!("Failed to reload TLS certificate, cert: {}, key: {}, {:?}", self.cert, self.key, e);
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        Some(self.certified.read().clone())
+    }
+}
+
+///The TLS config of a listener, its certificate files are checked for changes every
+///cert_reload_interval
+pub(crate) fn server_config(listen_cfg: &Listener) -> Result<ServerConfig> {
+    let cert = listen_cfg.cert.as_ref().ok_or_else(|| MqttError::from("cert is not configured"))?;
+    let key = listen_cfg.key.as_ref().ok_or_else(|| MqttError::from("key is not configured"))?;
+    let resolver = Arc::new(CertResolver::new(cert, key)?);
+    RESOLVERS.write().push(resolver.clone());
+
+    let interval = listen_cfg.cert_reload_interval;
+    if !interval.is_zero() {
+        let resolver = resolver.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                resolver.reload_modified();
+            }
+        });
+    }
+
+    let mut tls_config = ServerConfig::new(NoClientAuth::new());
+    tls_config.cert_resolver = resolver;
+    Ok(tls_config)
+}
+
+///Reloads the certificates of all TLS listeners
+pub(crate) fn reload_certs() {
+    let resolvers = RESOLVERS.read().clone();
+    for resolver in resolvers {
+        if let Err(e) = resolver.reload() {
+            log::warn!(
+                "Failed to reload TLS certificate, cert: {}, key: {}, {:?}",
+                resolver.cert,
+                resolver.key,
+                e
+            );
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use std::future::Future;
 use std::pin::Pin;
+use std::rc::Rc;
 use std::task::{Context, Poll};
 use std::{
     io::{self, ErrorKind},
@@ -17,6 +18,7 @@ use rmqtt::ntex_mqtt;
 use rmqtt::pin_project_lite;
 use rmqtt::tokio_tungstenite::accept_hdr_async;
 use rmqtt::tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use rmqtt::tokio_tungstenite::tungstenite::http::StatusCode;
 use rmqtt::tokio_tungstenite::tungstenite::Error as WSError;
 use rmqtt::tokio_tungstenite::tungstenite::Message;
 use rmqtt::tokio_tungstenite::WebSocketStream;
@@ -24,20 +26,30 @@ use rmqtt::{log, MqttError};
 
 pub(self) const ZERO: std::time::Duration = std::time::Duration::from_millis(0);
 
+///Subprotocol negotiation of the WebSocket handshake
+#[derive(Clone)]
+pub struct Subprotocols {
+    ///accepted subprotocols
+    pub allowed: Vec<String>,
+    ///a handshake that offers none of them is rejected
+    pub required: bool,
+}
+
 pub struct WSServer<T> {
     timeout: time::Duration,
+    subprotocols: Rc<Subprotocols>,
     io: marker::PhantomData<T>,
 }
 
 impl<T: AsyncRead + AsyncWrite> WSServer<T> {
-    pub fn new(timeout: time::Duration) -> Self {
-        WSServer { timeout, io: marker::PhantomData }
+    pub fn new(timeout: time::Duration, subprotocols: Subprotocols) -> Self {
+        WSServer { timeout, subprotocols: Rc::new(subprotocols), io: marker::PhantomData }
     }
 }
 
 impl<T> Clone for WSServer<T> {
     fn clone(&self) -> Self {
-        Self { timeout: self.timeout, io: marker::PhantomData }
+        Self { timeout: self.timeout, subprotocols: self.subprotocols.clone(), io: marker::PhantomData }
     }
 }
 
@@ -52,13 +64,18 @@ impl<T: AsyncRead + AsyncWrite + Unpin + 'static> ServiceFactory for WSServer<T>
     type Future = Ready<Self::Service, Self::InitError>;
 
     fn new_service(&self, _: ()) -> Self::Future {
-        Ready::Ok(WSService { timeout: self.timeout, io: marker::PhantomData })
+        Ready::Ok(WSService {
+            timeout: self.timeout,
+            subprotocols: self.subprotocols.clone(),
+            io: marker::PhantomData,
+        })
     }
 }
 
 pub struct WSService<T> {
     io: marker::PhantomData<T>,
     timeout: time::Duration,
+    subprotocols: Rc<Subprotocols>,
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin + 'static> Service for WSService<T> {
@@ -75,7 +92,13 @@ impl<T: AsyncRead + AsyncWrite + Unpin + 'static> Service for WSService<T> {
     #[inline]
     fn call(&self, req: Self::Request) -> Self::Future {
         WSServiceFut {
-            fut: accept_hdr_async(req, on_handshake).boxed_local(),
+            fut: {
+                let subprotocols = self.subprotocols.clone();
+                accept_hdr_async(req, move |req: &Request, response: Response| {
+                    on_handshake(&subprotocols, req, response)
+                })
+                .boxed_local()
+            },
             delay: if self.timeout == ZERO { None } else { Some(sleep(self.timeout)) },
         }
     }
@@ -191,15 +214,41 @@ fn to_error(e: WSError) -> io::Error {
     }
 }
 
-fn on_handshake(req: &Request, mut response: Response) -> std::result::Result<Response, ErrorResponse> {
-    const PROTOCOL_ERROR: &str = "No \"Sec-WebSocket-Protocol: mqtt\" in client request";
-    let mqtt_protocol = req
+///Selects the first subprotocol offered by the client that is allowed. The permessage-deflate
+///extension is not negotiated, Sec-WebSocket-Extensions is not returned, so the clients that offer
+///it send uncompressed frames
+fn on_handshake(
+    subprotocols: &Subprotocols,
+    req: &Request,
+    mut response: Response,
+) -> std::result::Result<Response, ErrorResponse> {
+    let offered = req
         .headers()
-        .get("Sec-WebSocket-Protocol")
-        .ok_or_else(|| ErrorResponse::new(Some(PROTOCOL_ERROR.into())))?;
-    if mqtt_protocol != "mqtt" {
-        return Err(ErrorResponse::new(Some(PROTOCOL_ERROR.into())));
+        .get_all("Sec-WebSocket-Protocol")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|p| p.trim())
+        .collect::<Vec<_>>();
+    match offered.iter().find(|p| subprotocols.allowed.iter().any(|a| a == *p)) {
+        Some(protocol) => {
+            let protocol = HeaderValue::from_str(protocol).map_err(|e| {
+                let mut err = ErrorResponse::new(Some(e.to_string()));
+                *err.status_mut() = StatusCode::BAD_REQUEST;
+                err
+            })?;
+            response.headers_mut().append("Sec-WebSocket-Protocol", protocol);
+        }
+        None if subprotocols.required => {
+            log::debug!("websocket handshake rejected, offered subprotocols: {:?}", offered);
+            let mut err = ErrorResponse::new(Some(format!(
+                "No \"Sec-WebSocket-Protocol\" of {:?} in client request",
+                subprotocols.allowed
+            )));
+            *err.status_mut() = StatusCode::BAD_REQUEST;
+            return Err(err);
+        }
+        None => {}
     }
-    response.headers_mut().append("Sec-WebSocket-Protocol", HeaderValue::from_static("mqtt"));
     Ok(response)
 }
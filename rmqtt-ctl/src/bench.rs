@@ -0,0 +1,318 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hdrhistogram::Histogram;
+use rand::{Rng, SeedableRng};
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Outgoing, Packet, QoS};
+use structopt::StructOpt;
+
+///Length of the send time at the start of the payloads
+const TIMESTAMP_LEN: usize = 16;
+
+///Highest latency recorded, in microseconds, the higher ones are recorded as it
+const LATENCY_MAX: u64 = 60_000_000;
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct BenchParams {
+    #[structopt(long, short = "H", default_value = "127.0.0.1")]
+    host: String,
+    #[structopt(long, short = "p", default_value = "1883")]
+    port: u16,
+    #[structopt(long, short = "u")]
+    username: Option<String>,
+    #[structopt(long, short = "P")]
+    password: Option<String>,
+    ///Number of the publishing clients
+    #[structopt(long, default_value = "10")]
+    publishers: usize,
+    ///Number of the subscribing clients, each subscribes to all topics
+    #[structopt(long, default_value = "10")]
+    subscribers: usize,
+    #[structopt(long, short = "q", default_value = "0")]
+    qos: u8,
+    ///Payload size in bytes, at least 16, the send time
+    #[structopt(long, short = "s", default_value = "256")]
+    payload_size: usize,
+    ///Messages per second of each publisher, 0 means as fast as possible
+    #[structopt(long, short = "r", default_value = "10")]
+    rate: u64,
+    ///Number of the topics, <topic_prefix>/0 to <topic_prefix>/<topics - 1>
+    #[structopt(long, default_value = "100")]
+    topics: usize,
+    #[structopt(long, default_value = "bench")]
+    topic_prefix: String,
+    ///How the topics of the messages are chosen, uniform or zipf, with which a few topics get most
+    ///of the messages
+    #[structopt(long, default_value = "uniform", possible_values = &["uniform", "zipf"])]
+    distribution: String,
+    ///Exponent of the zipf distribution
+    #[structopt(long, default_value = "1.0")]
+    zipf_exponent: f64,
+    ///Duration of publishing, in seconds
+    #[structopt(long, short = "d", default_value = "30")]
+    duration: u64,
+    ///Maximum number of the unacknowledged messages of a client
+    #[structopt(long, default_value = "100")]
+    inflight: u16,
+}
+
+impl BenchParams {
+    #[inline]
+    fn qos(&self) -> anyhow::Result<QoS> {
+        match self.qos {
+            0 => Ok(QoS::AtMostOnce),
+            1 => Ok(QoS::AtLeastOnce),
+            2 => Ok(QoS::ExactlyOnce),
+            qos => Err(anyhow::anyhow!("invalid qos {}", qos)),
+        }
+    }
+
+    #[inline]
+    fn mqtt_options(&self, client_id: String) -> MqttOptions {
+        let mut opts = MqttOptions::new(client_id, &self.host, self.port);
+        opts.set_keep_alive(Duration::from_secs(30));
+        opts.set_inflight(self.inflight);
+        let max_packet_size = self.payload_size + self.topic_prefix.len() + 1024;
+        opts.set_max_packet_size(max_packet_size, max_packet_size);
+        if let Some(username) = self.username.as_ref() {
+            opts.set_credentials(username, self.password.as_deref().unwrap_or_default());
+        }
+        opts
+    }
+
+    #[inline]
+    fn topic(&self, i: usize) -> String {
+        format!("{}/{}", self.topic_prefix, i)
+    }
+}
+
+///Chooses the topic of each message
+enum Topics {
+    Uniform(usize),
+    ///Cumulative probabilities of the topics
+    Zipf(Vec<f64>),
+}
+
+impl Topics {
+    fn new(p: &BenchParams) -> Self {
+        let n = p.topics.max(1);
+        if p.distribution == "zipf" {
+            let weights = (1..=n).map(|k| 1.0 / (k as f64).powf(p.zipf_exponent)).collect::<Vec<_>>();
+            let sum: f64 = weights.iter().sum();
+            let mut acc = 0.0;
+            Topics::Zipf(
+                weights
+                    .into_iter()
+                    .map(|w| {
+                        acc += w / sum;
+                        acc
+                    })
+                    .collect(),
+            )
+        } else {
+            Topics::Uniform(n)
+        }
+    }
+
+    #[inline]
+    fn sample<R: Rng>(&self, rng: &mut R) -> usize {
+        match self {
+            Topics::Uniform(n) => rng.gen_range(0..*n),
+            Topics::Zipf(cdf) => {
+                let x: f64 = rng.gen();
+                cdf.partition_point(|p| *p < x).min(cdf.len() - 1)
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    ///Messages written to the connections of the publishers
+    published: AtomicU64,
+    publish_errors: AtomicU64,
+    received: AtomicU64,
+    connect_errors: AtomicU64,
+}
+
+pub(crate) async fn run(p: BenchParams) -> anyhow::Result<()> {
+    let qos = p.qos()?;
+    let p = Arc::new(BenchParams { payload_size: p.payload_size.max(TIMESTAMP_LEN), ..p });
+    let counters = Arc::new(Counters::default());
+    let publishing = Arc::new(AtomicBool::new(true));
+    let run_id = rand::thread_rng().gen::<u32>();
+
+    //the clients are kept until the end, their connections are closed once they are dropped
+    let mut clients = Vec::new();
+
+    //subscribers
+    let mut subscribers = Vec::new();
+    for i in 0..p.subscribers {
+        let (client, eventloop) =
+            AsyncClient::new(p.mqtt_options(format!("bench-sub-{}-{}", run_id, i)), 1000);
+        client.subscribe(format!("{}/#", p.topic_prefix), qos).await?;
+        clients.push(client);
+        let latencies = Arc::new(Mutex::new(Histogram::<u64>::new_with_bounds(1, LATENCY_MAX, 3)?));
+        subscribers
+            .push((tokio::spawn(subscribe(eventloop, counters.clone(), latencies.clone())), latencies));
+    }
+    //let the subscriptions take effect
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    //publishers
+    let started = Instant::now();
+    let mut publishers = Vec::new();
+    let mut pollers = Vec::new();
+    for i in 0..p.publishers {
+        let (client, eventloop) =
+            AsyncClient::new(p.mqtt_options(format!("bench-pub-{}-{}", run_id, i)), 1000);
+        pollers.push(tokio::spawn(poll(eventloop, counters.clone())));
+        publishers.push(tokio::spawn(publish(client, p.clone(), qos, counters.clone(), publishing.clone())));
+    }
+
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    ticker.tick().await;
+    let duration = Duration::from_secs(p.duration);
+    while started.elapsed() < duration {
+        ticker.tick().await;
+        println!(
+            "{:>4}s published: {}, received: {}, errors: {}",
+            started.elapsed().as_secs(),
+            counters.published.load(Ordering::Relaxed),
+            counters.received.load(Ordering::Relaxed),
+            counters.publish_errors.load(Ordering::Relaxed) + counters.connect_errors.load(Ordering::Relaxed),
+        );
+    }
+    publishing.store(false, Ordering::SeqCst);
+    for publisher in publishers {
+        clients.push(publisher.await?);
+    }
+    let elapsed = started.elapsed();
+
+    //the messages in flight
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    for task in pollers {
+        task.abort();
+    }
+    let mut latencies = Histogram::<u64>::new_with_bounds(1, LATENCY_MAX, 3)?;
+    for (task, subscriber_latencies) in subscribers {
+        task.abort();
+        latencies.add(&*subscriber_latencies.lock().unwrap())?;
+    }
+    drop(clients);
+    report(&p, &counters, elapsed, &latencies);
+    Ok(())
+}
+
+async fn publish(
+    client: AsyncClient,
+    p: Arc<BenchParams>,
+    qos: QoS,
+    counters: Arc<Counters>,
+    publishing: Arc<AtomicBool>,
+) -> AsyncClient {
+    let topics = Topics::new(&p);
+    let mut rng = rand::rngs::StdRng::from_entropy();
+    let mut payload = vec![0u8; p.payload_size];
+    rng.fill(&mut payload[TIMESTAMP_LEN..]);
+    let mut ticker = if p.rate > 0 {
+        Some(tokio::time::interval(Duration::from_nanos(1_000_000_000 / p.rate)))
+    } else {
+        None
+    };
+    while publishing.load(Ordering::Relaxed) {
+        if let Some(ticker) = ticker.as_mut() {
+            ticker.tick().await;
+        }
+        payload[..TIMESTAMP_LEN].copy_from_slice(&now_nanos().to_be_bytes());
+        let topic = p.topic(topics.sample(&mut rng));
+        //queued to the event loop, counted as published once it is written to the connection
+        if client.publish(topic, qos, false, payload.clone()).await.is_err() {
+            counters.publish_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    client
+}
+
+///Drives the connection of a publisher and counts the messages written to it, until it is aborted
+async fn poll(mut eventloop: EventLoop, counters: Arc<Counters>) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Outgoing(Outgoing::Publish(_))) => {
+                counters.published.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(_) => {}
+            Err(_) => {
+                counters.connect_errors.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+        }
+    }
+}
+
+///Records the latencies of the received messages, in microseconds, until it is aborted
+async fn subscribe(mut eventloop: EventLoop, counters: Arc<Counters>, latencies: Arc<Mutex<Histogram<u64>>>) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                counters.received.fetch_add(1, Ordering::Relaxed);
+                if publish.payload.len() >= TIMESTAMP_LEN {
+                    let mut sent = [0u8; TIMESTAMP_LEN];
+                    sent.copy_from_slice(&publish.payload[..TIMESTAMP_LEN]);
+                    let latency = now_nanos().saturating_sub(u128::from_be_bytes(sent));
+                    latencies.lock().unwrap().saturating_record((latency / 1000) as u64);
+                }
+            }
+            Ok(_) => {}
+            Err(_) => {
+                counters.connect_errors.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+        }
+    }
+}
+
+fn report(p: &BenchParams, counters: &Counters, elapsed: Duration, latencies: &Histogram<u64>) {
+    let published = counters.published.load(Ordering::Relaxed);
+    let received = counters.received.load(Ordering::Relaxed);
+    let expected = published * p.subscribers as u64;
+    let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+    println!();
+    println!(
+        "publishers: {}, subscribers: {}, qos: {}, payload: {} bytes, topics: {} ({})",
+        p.publishers, p.subscribers, p.qos, p.payload_size, p.topics, p.distribution
+    );
+    println!(
+        "published: {} ({:.1}/s), publish errors: {}",
+        published,
+        published as f64 / secs,
+        counters.publish_errors.load(Ordering::Relaxed)
+    );
+    println!(
+        "received: {} ({:.1}/s), expected: {}, missing: {}",
+        received,
+        received as f64 / secs,
+        expected,
+        expected.saturating_sub(received)
+    );
+    println!("connection errors: {}", counters.connect_errors.load(Ordering::Relaxed));
+    if latencies.is_empty() {
+        return;
+    }
+    let percentile = |q: f64| latencies.value_at_quantile(q) as f64 / 1000.0;
+    println!(
+        "latency (ms): min {:.3}, p50 {:.3}, p90 {:.3}, p99 {:.3}, p99.9 {:.3}, max {:.3}",
+        latencies.min() as f64 / 1000.0,
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+        percentile(0.999),
+        latencies.max() as f64 / 1000.0
+    );
+}
+
+#[inline]
+fn now_nanos() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default()
+}
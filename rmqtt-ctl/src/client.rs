@@ -0,0 +1,79 @@
+use anyhow::anyhow;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{Client, RequestBuilder};
+
+///The characters of a path segment that are encoded, all but the unreserved ones
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+///A path segment, such as a client ID, percent-encoded
+#[inline]
+pub(crate) fn segment(s: &str) -> String {
+    utf8_percent_encode(s, SEGMENT).to_string()
+}
+
+pub(crate) struct ApiClient {
+    base: String,
+    client: Client,
+}
+
+impl ApiClient {
+    #[inline]
+    pub(crate) fn new(api: &str) -> anyhow::Result<Self> {
+        let base = format!("{}/api/v1", api.trim_end_matches('/'));
+        Ok(Self { base, client: Client::builder().build()? })
+    }
+
+    #[inline]
+    pub(crate) async fn get(&self, path: &str) -> anyhow::Result<serde_json::Value> {
+        Ok(serde_json::from_str(&self.send(self.client.get(self.url(path))).await?)?)
+    }
+
+    #[inline]
+    pub(crate) async fn get_with_query(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> anyhow::Result<serde_json::Value> {
+        Ok(serde_json::from_str(&self.send(self.client.get(self.url(path)).query(query)).await?)?)
+    }
+
+    #[inline]
+    pub(crate) async fn get_text_with_query(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> anyhow::Result<String> {
+        self.send(self.client.get(self.url(path)).query(query)).await
+    }
+
+    #[inline]
+    pub(crate) async fn post(&self, path: &str, body: &serde_json::Value) -> anyhow::Result<String> {
+        self.send(self.client.post(self.url(path)).json(body)).await
+    }
+
+    #[inline]
+    pub(crate) async fn put(&self, path: &str) -> anyhow::Result<String> {
+        self.send(self.client.put(self.url(path))).await
+    }
+
+    #[inline]
+    pub(crate) async fn delete(&self, path: &str) -> anyhow::Result<String> {
+        self.send(self.client.delete(self.url(path))).await
+    }
+
+    #[inline]
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.base, path)
+    }
+
+    async fn send(&self, req: RequestBuilder) -> anyhow::Result<String> {
+        let resp = req.send().await?;
+        let status = resp.status();
+        let body = resp.text().await?;
+        if status.is_success() {
+            Ok(body)
+        } else {
+            Err(anyhow!("{}, {}", status, body))
+        }
+    }
+}
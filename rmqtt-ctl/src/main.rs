@@ -0,0 +1,224 @@
+use std::time::Duration;
+
+use structopt::StructOpt;
+
+use client::{segment, ApiClient};
+
+mod bench;
+mod client;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rmqtt-ctl", about = "Manage a RMQTT cluster through its HTTP API")]
+struct Options {
+    ///Address of the HTTP API, the http_laddr of the rmqtt-http-api plugin
+    #[structopt(long, short = "a", env = "RMQTT_API", default_value = "http://127.0.0.1:6060")]
+    api: String,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    ///Brokers, nodes and health of the cluster
+    Status,
+    ///List, show and kick the clients
+    Clients(ClientsCommand),
+    ///Subscriptions of a client
+    Subscriptions { clientid: String },
+    ///Publish a message
+    Publish(PublishParams),
+    ///List, load, unload and reload the plugins
+    Plugins(PluginsCommand),
+    ///Print the events of a client or of a topic as they happen, until interrupted
+    Tail(TailParams),
+    ///Run synthetic publishers and subscribers against a broker and report the throughput and
+    ///the latency percentiles
+    Bench(bench::BenchParams),
+}
+
+#[derive(StructOpt, Debug)]
+enum ClientsCommand {
+    List {
+        #[structopt(long)]
+        clientid: Option<String>,
+        #[structopt(long)]
+        username: Option<String>,
+        #[structopt(long)]
+        ip_address: Option<String>,
+        #[structopt(long)]
+        connected: Option<bool>,
+        #[structopt(long, default_value = "100")]
+        limit: usize,
+    },
+    Show {
+        clientid: String,
+    },
+    Kick {
+        clientid: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+struct PublishParams {
+    ///Multiple topics separated by ,
+    #[structopt(long, short = "t")]
+    topic: String,
+    #[structopt(long, short = "m")]
+    payload: String,
+    #[structopt(long, short = "q", default_value = "0")]
+    qos: u8,
+    #[structopt(long, short = "r")]
+    retain: bool,
+    #[structopt(long, default_value = "system")]
+    clientid: String,
+    ///The payload is base64 encoded
+    #[structopt(long)]
+    base64: bool,
+}
+
+#[derive(StructOpt, Debug)]
+enum PluginsCommand {
+    List { node: Option<u64> },
+    Config { node: u64, plugin: String },
+    Load { node: u64, plugin: String },
+    Unload { node: u64, plugin: String },
+    Reload { node: u64, plugin: String },
+}
+
+#[derive(StructOpt, Debug)]
+struct TailParams {
+    #[structopt(long, conflicts_with = "topic", required_unless = "topic")]
+    clientid: Option<String>,
+    #[structopt(long)]
+    topic: Option<String>,
+    ///Include the payloads of the messages
+    #[structopt(long)]
+    payload: bool,
+    ///Polling interval, in milliseconds
+    #[structopt(long, default_value = "1000")]
+    interval: u64,
+}
+
+#[tokio::main]
+async fn main() {
+    let opts = Options::from_args();
+    if let Err(e) = run(opts).await {
+        eprintln!("error: {:?}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(opts: Options) -> anyhow::Result<()> {
+    let api = ApiClient::new(&opts.api)?;
+    match opts.command {
+        Command::Status => {
+            print_json(&serde_json::json!({
+                "brokers": api.get("brokers").await?,
+                "nodes": api.get("nodes").await?,
+                "health": api.get("health/check").await?,
+            }));
+        }
+        Command::Clients(ClientsCommand::List { clientid, username, ip_address, connected, limit }) => {
+            let mut query = vec![("_limit", limit.to_string())];
+            query.extend(clientid.map(|v| ("clientid", v)));
+            query.extend(username.map(|v| ("username", v)));
+            query.extend(ip_address.map(|v| ("ip_address", v)));
+            query.extend(connected.map(|v| ("connected", v.to_string())));
+            print_json(&api.get_with_query("clients", &query).await?);
+        }
+        Command::Clients(ClientsCommand::Show { clientid }) => {
+            print_json(&api.get(&format!("clients/{}", segment(&clientid))).await?)
+        }
+        Command::Clients(ClientsCommand::Kick { clientid }) => {
+            println!("{}", api.delete(&format!("clients/{}", segment(&clientid))).await?)
+        }
+        Command::Subscriptions { clientid } => {
+            print_json(&api.get(&format!("subscriptions/{}", segment(&clientid))).await?)
+        }
+        Command::Publish(p) => {
+            let body = serde_json::json!({
+                "topics": p.topic,
+                "clientid": p.clientid,
+                "payload": p.payload,
+                "encoding": if p.base64 { "base64" } else { "plain" },
+                "qos": p.qos,
+                "retain": p.retain,
+            });
+            println!("{}", api.post("mqtt/publish", &body).await?)
+        }
+        Command::Plugins(PluginsCommand::List { node }) => match node {
+            Some(node) => print_json(&api.get(&format!("plugins/{}", node)).await?),
+            None => print_json(&api.get("plugins").await?),
+        },
+        Command::Plugins(PluginsCommand::Config { node, plugin }) => {
+            print_json(&api.get(&format!("plugins/{}/{}/config", node, plugin)).await?)
+        }
+        Command::Plugins(PluginsCommand::Load { node, plugin }) => {
+            println!("{}", api.put(&format!("plugins/{}/{}/load", node, plugin)).await?)
+        }
+        Command::Plugins(PluginsCommand::Unload { node, plugin }) => {
+            println!("{}", api.put(&format!("plugins/{}/{}/unload", node, plugin)).await?)
+        }
+        Command::Plugins(PluginsCommand::Reload { node, plugin }) => {
+            println!("{}", api.put(&format!("plugins/{}/{}/reload", node, plugin)).await?)
+        }
+        Command::Tail(p) => tail(&api, p).await?,
+        Command::Bench(p) => bench::run(p).await?,
+    }
+    Ok(())
+}
+
+///Starts a trace and prints the lines added to its log, the trace is removed on Ctrl-C
+async fn tail(api: &ApiClient, p: TailParams) -> anyhow::Result<()> {
+    let name = format!("rmqtt-ctl-{}", std::process::id());
+    api.post(
+        "trace",
+        &serde_json::json!({
+            "name": name,
+            "clientid": p.clientid,
+            "topic": p.topic,
+            "payload": p.payload,
+            "duration": "24h",
+        }),
+    )
+    .await?;
+
+    let result = tokio::select! {
+        res = follow(api, &name, Duration::from_millis(p.interval)) => res,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+    if let Err(e) = api.delete(&format!("trace/{}", name)).await {
+        eprintln!("remove trace {} error: {:?}", name, e);
+    }
+    result
+}
+
+async fn follow(api: &ApiClient, name: &str, interval: Duration) -> anyhow::Result<()> {
+    //the log is the logs of the nodes, each after a "## node: <id>" line, only the lines of each node
+    //after its offset are downloaded, the offset is moved past the lines printed
+    let mut offsets = std::collections::BTreeMap::<String, usize>::new();
+    loop {
+        let query = offsets.iter().map(|(node, offset)| format!("{}:{}", node, offset)).collect::<Vec<_>>();
+        let log = api
+            .get_text_with_query(&format!("trace/{}/download", name), &[("offsets", query.join(","))])
+            .await?;
+        let mut node = String::new();
+        for line in log.split_inclusive('\n') {
+            if let Some(id) = line.strip_prefix("## node: ") {
+                node = id.trim_end().to_owned();
+                continue;
+            }
+            if let Some(line) = line.strip_suffix('\n') {
+                println!("[node {}] {}", node, line);
+                *offsets.entry(node.clone()).or_default() += line.len() + 1;
+            }
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[inline]
+fn print_json(v: &serde_json::Value) {
+    println!("{}", serde_json::to_string_pretty(v).unwrap_or_else(|_| v.to_string()));
+}
@@ -7,12 +7,16 @@ use serde::ser::{self, Serialize};
 
 use rmqtt::broker::hook::Priority;
 use rmqtt::broker::topic::TopicTree;
+use rmqtt::broker::topic_template::IdentityFilter;
+use rmqtt::settings::schema::{ConfigSchema, Field, FieldType};
 use rmqtt::{
     ahash, dashmap, log,
     serde_json::{self, Value},
     tokio::sync::RwLock,
 };
-use rmqtt::{ClientId, ConnectInfo, MqttError, Password, Result, Superuser, Topic, UserName};
+use rmqtt::{ClientId, ClientTags, ConnectInfo, MqttError, Password, Result, Superuser, Topic, UserName};
+
+use crate::template::TopicTemplates;
 
 type DashSet<V> = dashmap::DashSet<V, ahash::RandomState>;
 
@@ -29,6 +33,11 @@ pub struct PluginConfig {
     #[serde(default = "PluginConfig::priority_default")]
     pub priority: Priority,
 
+    ///Checked before the rules, the topics each client may publish and subscribe to, derived
+    ///from its identity
+    #[serde(default)]
+    pub topic_templates: TopicTemplates,
+
     #[serde(
         default,
         serialize_with = "PluginConfig::serialize_rules",
@@ -46,6 +55,43 @@ impl PluginConfig {
         10
     }
 
+    #[inline]
+    pub fn schema() -> ConfigSchema {
+        ConfigSchema::new()
+            .field(
+                Field::new("disconnect_if_pub_rejected", FieldType::Bool)
+                    .default(Self::disconnect_if_pub_rejected_default())
+                    .descr("Disconnect if publishing is rejected"),
+            )
+            .field(
+                Field::new("priority", FieldType::Integer)
+                    .default(Self::priority_default())
+                    .descr("Hook priority"),
+            )
+            .field(
+                Field::new("topic_templates", FieldType::Object)
+                    .descr("Topics each client may publish and subscribe to, checked before the rules")
+                    .field(Field::new("enable", FieldType::Bool).default(false))
+                    .field(
+                        Field::new("publish", FieldType::Array)
+                            .descr("Topics a client may publish to, with %c and %u"),
+                    )
+                    .field(
+                        Field::new("subscribe", FieldType::Array)
+                            .descr("Topic filters a client may subscribe to, with %c and %u"),
+                    )
+                    .field(
+                        Field::new("fallback_to_rules", FieldType::Bool)
+                            .default(false)
+                            .descr("The rules decide the topics that match no template"),
+                    ),
+            )
+            .field(
+                Field::new("rules", FieldType::Array)
+                    .descr("ACL rules, [access, user, control, topics], checked in order"),
+            )
+    }
+
     #[inline]
     pub fn rules(&self) -> &Vec<Rule> {
         let (_rules, _) = &self.rules;
@@ -96,20 +142,6 @@ pub struct Rule {
     pub topics: Topics,
 }
 
-impl Rule {
-    #[inline]
-    pub async fn add_topic_filter(&self, topic_filter: &str) -> Result<()> {
-        let t = Topic::from_str(topic_filter)?;
-        self.topics.tree.write().await.insert(&t, ());
-        Ok(())
-    }
-
-    #[inline]
-    pub fn add_topic_to_eqs(&self, topic: String) {
-        self.topics.eqs.insert(topic);
-    }
-}
-
 impl std::convert::TryFrom<&serde_json::Value> for Rule {
     type Error = MqttError;
     #[inline]
@@ -146,14 +178,26 @@ pub enum User {
     Username(UserName, Option<Password>, Superuser),
     Clientid(ClientId),
     Ipaddr(String),
+    ///The connections with all these tags, set by the ClientTagging hook after the connect, so
+    ///the rule does not apply to the connect itself
+    Tags(ClientTags),
     All,
 }
 
 impl User {
     #[inline]
-    pub fn hit(&self, connect_info: &ConnectInfo, allow: bool) -> (bool, Superuser) {
+    pub fn hit(
+        &self,
+        connect_info: &ConnectInfo,
+        tags: Option<&ClientTags>,
+        allow: bool,
+    ) -> (bool, Superuser) {
         match self {
             User::All => (true, false),
+            User::Tags(expected) => match tags {
+                Some(tags) => (expected.iter().all(|(k, v)| tags.get(k) == Some(v)), false),
+                None => (false, false),
+            },
             User::Username(name1, password1, superuser) => {
                 match (connect_info.username(), connect_info.password(), password1, allow) {
                     (Some(name2), Some(password2), Some(password1), true) => {
@@ -196,21 +240,46 @@ pub enum Control {
 pub struct Topics {
     pub all: bool,
     pub eqs: Arc<DashSet<String>>,
-    pub eq_placeholders: Vec<String>,
+    pub eq_placeholders: Vec<IdentityFilter>,
     //"sensor/%u/ctrl", "sensor/%c/ctrl"
     pub tree: Arc<RwLock<TopicTree<()>>>,
-    pub placeholders: Vec<String>, //"sensor/%u/ctrl", "sensor/%c/ctrl"
+    pub placeholders: Vec<IdentityFilter>, //"sensor/%u/ctrl", "sensor/%c/ctrl"
 }
 
 impl Topics {
-    pub async fn is_match(&self, topic_filter: &Topic, topic_filter_str: &str) -> bool {
+    pub async fn is_match(
+        &self,
+        topic_filter: &Topic,
+        topic_filter_str: &str,
+        connect_info: &ConnectInfo,
+    ) -> bool {
         if self.all {
             return true;
         }
         if self.eqs.contains(topic_filter_str) {
             return true;
         }
-        self.tree.read().await.is_match(topic_filter)
+        if self.tree.read().await.is_match(topic_filter) {
+            return true;
+        }
+        self.is_placeholders_match(topic_filter_str, connect_info)
+    }
+
+    ///The placeholders are resolved with the client of each check, so that the topics of a
+    ///client are not matched for the other clients, the filters compare the levels of the topic
+    ///with those of the client without building a topic tree
+    #[inline]
+    fn is_placeholders_match(&self, topic_filter_str: &str, connect_info: &ConnectInfo) -> bool {
+        if self.eq_placeholders.is_empty() && self.placeholders.is_empty() {
+            return false;
+        }
+        let client_id = connect_info.client_id();
+        let username = connect_info.username().map(|u| &**u);
+        self.eq_placeholders
+            .iter()
+            .filter_map(|t| t.render(client_id, username))
+            .any(|t| t == topic_filter_str)
+            || self.placeholders.iter().any(|tf| tf.is_match(topic_filter_str, client_id, username))
     }
 }
 
@@ -241,6 +310,15 @@ impl std::convert::TryFrom<(&serde_json::Value, Access)> for User {
                     Err(MqttError::from(err_msg))
                 }
             }
+            Value::Object(map) if map.contains_key("tags") => match map.get("tags") {
+                Some(Value::Object(tags)) if !tags.is_empty() => tags
+                    .iter()
+                    .map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_owned())))
+                    .collect::<Option<ClientTags>>()
+                    .map(User::Tags)
+                    .ok_or_else(|| MqttError::from(err_msg)),
+                _ => Err(MqttError::from(err_msg)),
+            },
             Value::Object(map) => {
                 match (
                     access,
@@ -331,7 +409,7 @@ impl std::convert::TryFrom<Option<&serde_json::Value>> for Topics {
                     match topic {
                         Value::String(topic) => {
                             if topic.contains(PH_U) || topic.contains(PH_C) {
-                                placeholders.push(topic.clone());
+                                placeholders.push(IdentityFilter::new(topic)?);
                             } else {
                                 tree.insert(&Topic::from_str(topic.as_str())?, ());
                             }
@@ -339,7 +417,7 @@ impl std::convert::TryFrom<Option<&serde_json::Value>> for Topics {
                         Value::Object(eq_map) => match eq_map.get("eq") {
                             Some(Value::String(eq)) => {
                                 if eq.contains(PH_U) || eq.contains(PH_C) {
-                                    eq_placeholders.push(eq.clone());
+                                    eq_placeholders.push(IdentityFilter::new(eq)?);
                                 } else {
                                     eqs.insert(eq.clone());
                                 }
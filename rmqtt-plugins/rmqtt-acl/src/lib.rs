@@ -5,20 +5,18 @@ extern crate serde;
 use std::str::FromStr;
 use std::sync::Arc;
 
-use config::{Access, Control, PluginConfig, PH_C, PH_U};
-use rmqtt::{
-    async_trait::async_trait,
-    log, serde_json,
-    tokio::{self, sync::RwLock},
-};
+use config::{Access, Control, PluginConfig};
+use rmqtt::{async_trait::async_trait, log, serde_json, tokio::sync::RwLock};
 use rmqtt::{
     broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
     broker::types::{AuthResult, PublishAclResult, SubscribeAckReason, SubscribeAclResult, Topic},
     plugin::{DynPlugin, DynPluginResult, Plugin},
+    settings::schema::ConfigSchema,
     Result, Runtime,
 };
 
 mod config;
+mod template;
 
 #[inline]
 pub async fn register(
@@ -55,9 +53,14 @@ impl AclPlugin {
         descr: D,
     ) -> Result<Self> {
         let name = name.into();
-        let cfg = Arc::new(RwLock::new(runtime.settings.plugins.load_config::<PluginConfig>(&name)?));
+        let cfg = Arc::new(RwLock::new(
+            runtime
+                .settings
+                .plugins
+                .load_config_with_schema::<PluginConfig>(&name, &PluginConfig::schema())?,
+        ));
         log::debug!("{} AclPlugin cfg: {:?}", name, cfg.read().await);
-        let register = runtime.extends.hook_mgr().await.register();
+        let register = runtime.extends.hook_mgr().await.register_for(&name);
         Ok(Self { runtime, name, descr: descr.into(), register, cfg })
     }
 }
@@ -69,7 +72,6 @@ impl Plugin for AclPlugin {
         log::info!("{} init", self.name);
         let cfg = &self.cfg;
         let priority = cfg.read().await.priority;
-        self.register.add_priority(Type::ClientConnected, priority, Box::new(AclHandler::new(cfg))).await;
         self.register.add_priority(Type::ClientAuthenticate, priority, Box::new(AclHandler::new(cfg))).await;
         self.register
             .add_priority(Type::ClientSubscribeCheckAcl, priority, Box::new(AclHandler::new(cfg)))
@@ -90,9 +92,18 @@ impl Plugin for AclPlugin {
         self.cfg.read().await.to_json()
     }
 
+    #[inline]
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(PluginConfig::schema())
+    }
+
     #[inline]
     async fn load_config(&mut self) -> Result<()> {
-        let new_cfg = self.runtime.settings.plugins.load_config::<PluginConfig>(&self.name)?;
+        let new_cfg = self
+            .runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&self.name, &PluginConfig::schema())?;
         *self.cfg.write().await = new_cfg;
         log::debug!("load_config ok,  {:?}", self.cfg);
         Ok(())
@@ -121,6 +132,11 @@ impl Plugin for AclPlugin {
     fn descr(&self) -> &str {
         &self.descr
     }
+
+    #[inline]
+    fn reloadable(&self) -> bool {
+        false
+    }
 }
 
 struct AclHandler {
@@ -137,47 +153,6 @@ impl AclHandler {
 impl Handler for AclHandler {
     async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
         match param {
-            Parameter::ClientConnected(_session, client) => {
-                let cfg = self.cfg.clone();
-                let client_id = client.id.client_id.clone();
-                let username = client.connect_info.username().cloned();
-                let build_placeholders = async move {
-                    for rule in cfg.read().await.rules() {
-                        for ph_tf in &rule.topics.placeholders {
-                            let mut tf = ph_tf.replace(PH_C, &client_id);
-                            if let Some(un) = &username {
-                                tf = tf.replace(PH_U, un);
-                            } else {
-                                tf = tf.replace(PH_U, "");
-                            }
-                            if let Err(e) = rule.add_topic_filter(&tf).await {
-                                log::error!(
-                                    "acl config error, build_placeholders, add topic filter error, {:?}",
-                                    e
-                                );
-                            }
-                        }
-
-                        for eq_ph_t in &rule.topics.eq_placeholders {
-                            let mut t = eq_ph_t.replace(PH_C, &client_id);
-                            if let Some(un) = &username {
-                                t = t.replace(PH_U, un);
-                            } else {
-                                t = t.replace(PH_U, "");
-                            }
-                            rule.add_topic_to_eqs(t);
-                        }
-
-                        log::debug!("rule.access: {:?}", rule.access);
-                        log::debug!("rule.user: {:?}", rule.user);
-                        log::debug!("rule.control: {:?}", rule.control);
-                        log::debug!("rule.topics.eqs: {:?}", rule.topics.eqs);
-                        log::debug!("rule.topics.tree: {:?}", rule.topics.tree.read().await.list(100));
-                    }
-                };
-                tokio::spawn(build_placeholders);
-            }
-
             Parameter::ClientAuthenticate(connect_info) => {
                 log::debug!("ClientAuthenticate acl");
                 if matches!(
@@ -194,7 +169,7 @@ impl Handler for AclHandler {
                     }
 
                     let allow = matches!(rule.access, Access::Allow);
-                    let (hit, superuser) = rule.user.hit(connect_info, allow);
+                    let (hit, superuser) = rule.user.hit(connect_info, None, allow);
                     if hit {
                         log::debug!("{:?} ClientAuthenticate, rule: {:?}", connect_info.id(), rule);
                         return if allow {
@@ -213,20 +188,45 @@ impl Handler for AclHandler {
                         return (false, acc);
                     }
                 }
+                let topic_filter = &subscribe.topic_filter;
+                let cfg = self.cfg.read().await;
+                let templates = &cfg.topic_templates;
+                if templates.enable {
+                    if templates.subscribe.is_match(topic_filter, &client_info.connect_info) {
+                        return (
+                            false,
+                            Some(HookResult::SubscribeAclResult(SubscribeAclResult::new_success(
+                                subscribe.qos,
+                            ))),
+                        );
+                    } else if !templates.fallback_to_rules {
+                        log::debug!(
+                            "{:?} ClientSubscribeCheckAcl, no topic template matched, topic_filter: {}",
+                            client_info.id,
+                            topic_filter
+                        );
+                        return (
+                            false,
+                            Some(HookResult::SubscribeAclResult(SubscribeAclResult::new_failure(
+                                SubscribeAckReason::NotAuthorized,
+                            ))),
+                        );
+                    }
+                }
                 let topic =
                     Topic::from_str(&subscribe.topic_filter).unwrap_or_else(|_| Topic::from(Vec::new()));
-                let topic_filter = &subscribe.topic_filter;
-                for (idx, rule) in self.cfg.read().await.rules().iter().enumerate() {
+                for (idx, rule) in cfg.rules().iter().enumerate() {
                     if !matches!(rule.control, Control::Subscribe | Control::Pubsub | Control::All) {
                         continue;
                     }
 
                     let allow = matches!(rule.access, Access::Allow);
-                    let (hit, _) = rule.user.hit(&client_info.connect_info, allow);
+                    let (hit, _) =
+                        rule.user.hit(&client_info.connect_info, Some(&client_info.tags.read()), allow);
                     if !hit {
                         continue;
                     }
-                    if !rule.topics.is_match(&topic, topic_filter).await {
+                    if !rule.topics.is_match(&topic, topic_filter, &client_info.connect_info).await {
                         continue;
                     }
                     log::debug!(
@@ -264,19 +264,39 @@ impl Handler for AclHandler {
                     return (false, acc);
                 }
                 let topic_str = publish.topic();
+                let cfg = self.cfg.read().await;
+                let disconnect_if_pub_rejected = cfg.disconnect_if_pub_rejected;
+                let templates = &cfg.topic_templates;
+                if templates.enable {
+                    if templates.publish.is_match(topic_str, &client_info.connect_info) {
+                        return (false, Some(HookResult::PublishAclResult(PublishAclResult::Allow)));
+                    } else if !templates.fallback_to_rules {
+                        log::debug!(
+                            "{:?} MessagePublishCheckAcl, no topic template matched, topic: {}",
+                            client_info.id,
+                            topic_str
+                        );
+                        return (
+                            false,
+                            Some(HookResult::PublishAclResult(PublishAclResult::Rejected(
+                                disconnect_if_pub_rejected,
+                            ))),
+                        );
+                    }
+                }
                 let topic = Topic::from_str(topic_str).unwrap_or_else(|_| Topic::from(Vec::new()));
-                let disconnect_if_pub_rejected = self.cfg.read().await.disconnect_if_pub_rejected;
-                for (idx, rule) in self.cfg.read().await.rules().iter().enumerate() {
+                for (idx, rule) in cfg.rules().iter().enumerate() {
                     if !matches!(rule.control, Control::Publish | Control::Pubsub | Control::All) {
                         continue;
                     }
 
                     let allow = matches!(rule.access, Access::Allow);
-                    let (hit, _) = rule.user.hit(&client_info.connect_info, allow);
+                    let (hit, _) =
+                        rule.user.hit(&client_info.connect_info, Some(&client_info.tags.read()), allow);
                     if !hit {
                         continue;
                     }
-                    if !rule.topics.is_match(&topic, topic_str).await {
+                    if !rule.topics.is_match(&topic, topic_str, &client_info.connect_info).await {
                         continue;
                     }
                     log::debug!(
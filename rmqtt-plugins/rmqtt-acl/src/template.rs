@@ -0,0 +1,61 @@
+use rmqtt::broker::topic_template::IdentityFilter;
+use rmqtt::ConnectInfo;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TopicTemplates {
+    #[serde(default)]
+    pub enable: bool,
+    ///Topics a client may publish to
+    #[serde(default)]
+    pub publish: Templates,
+    ///Topic filters a client may subscribe to
+    #[serde(default)]
+    pub subscribe: Templates,
+    ///The rules decide the topics that match no template, otherwise they are denied
+    #[serde(default)]
+    pub fallback_to_rules: bool,
+}
+
+///Topic filters with the placeholders %c and %u, compiled once with the identity filters of the
+///broker, so that a check compares the levels of the topic with those of the client without
+///building a topic tree
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Templates {
+    templates: Vec<IdentityFilter>,
+}
+
+impl Templates {
+    ///Whether a template covers the topic, or all the topics of the topic filter
+    #[inline]
+    pub fn is_match(&self, topic: &str, connect_info: &ConnectInfo) -> bool {
+        self.is_identity_match(topic, connect_info.client_id(), connect_info.username().map(|u| &**u))
+    }
+
+    #[inline]
+    fn is_identity_match(&self, topic: &str, client_id: &str, username: Option<&str>) -> bool {
+        self.templates.iter().any(|t| t.is_match(topic, client_id, username))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn templates() {
+        let templates: Templates =
+            rmqtt::serde_json::from_str(r#"["devices/%c/up/#", "users/%u/+", "broadcast/#"]"#).unwrap();
+        assert!(templates.is_identity_match("devices/c1/up/a", "c1", None));
+        assert!(templates.is_identity_match("devices/c1/up/+", "c1", None));
+        assert!(!templates.is_identity_match("devices/+/up/a", "c1", None));
+        assert!(!templates.is_identity_match("devices/c2/up/a", "c1", None));
+        assert!(templates.is_identity_match("users/u1/a", "c1", Some("u1")));
+        assert!(!templates.is_identity_match("users/u1/a", "c1", None));
+        assert!(templates.is_identity_match("broadcast/a", "c1", None));
+        //A client ID with a / or starting with $ does not widen the templates
+        assert!(!templates.is_identity_match("devices/a/b/up/a", "a/b", None));
+        assert!(!templates.is_identity_match("$SYS/up/a", "$SYS", None));
+        assert!(rmqtt::serde_json::from_str::<Templates>(r#"["devices/%c/#/up"]"#).is_err());
+    }
+}
@@ -8,6 +8,7 @@ use serde::ser::{self, Serialize};
 
 use rmqtt::broker::hook::Priority;
 use rmqtt::settings::deserialize_duration;
+use rmqtt::settings::schema::{ConfigSchema, Field, FieldType};
 use rmqtt::Result;
 use rmqtt::{ahash, reqwest, serde_json};
 
@@ -68,6 +69,44 @@ impl PluginConfig {
         Duration::from_secs(5)
     }
 
+    #[inline]
+    pub fn schema() -> ConfigSchema {
+        ConfigSchema::new()
+            .field(
+                Field::new("disconnect_if_pub_rejected", FieldType::Bool)
+                    .default(Self::disconnect_if_pub_rejected_default())
+                    .descr("Disconnect if publishing is rejected"),
+            )
+            .field(
+                Field::new("priority", FieldType::Integer)
+                    .default(Self::priority_default())
+                    .descr("Hook priority"),
+            )
+            .field(
+                Field::new("deny_if_error", FieldType::Bool)
+                    .default(Self::deny_if_error_default())
+                    .descr("Return 'Deny' if http request error otherwise 'Ignore'"),
+            )
+            .field(
+                Field::new("http_timeout", FieldType::Duration)
+                    .default_duration(Self::http_timeout_default())
+                    .descr("Timeout of the HTTP requests"),
+            )
+            .field(Field::new("http_headers", FieldType::Object).descr("Headers of all the HTTP requests"))
+            .field(
+                Field::new("http_retry", FieldType::Object)
+                    .descr("Retries of the auth requests of the connects queued during an outage")
+                    .field(Field::new("times", FieldType::Integer).default(Retry::times_default()))
+                    .field(
+                        Field::new("interval", FieldType::Duration)
+                            .default_duration(Retry::interval_default()),
+                    )
+                    .field(Field::new("backoff", FieldType::Float).default(Retry::backoff_default())),
+            )
+            .field(Req::schema_field("http_auth_req").descr("Authentication request"))
+            .field(Req::schema_field("http_acl_req").descr("ACL request"))
+    }
+
     #[inline]
     fn serialize_http_headers<S>(
         headers: &(HeaderMap, HashMap<String, String>),
@@ -151,6 +190,15 @@ pub struct Req {
 }
 
 impl Req {
+    #[inline]
+    fn schema_field(name: &str) -> Field {
+        Field::new(name, FieldType::Object)
+            .field(Field::new("url", FieldType::String).required())
+            .field(Field::new("method", FieldType::String).required().descr("get, post or put"))
+            .field(Field::new("headers", FieldType::Object))
+            .field(Field::new("params", FieldType::Object).required())
+    }
+
     pub fn is_get(&self) -> bool {
         self.method == Method::GET
     }
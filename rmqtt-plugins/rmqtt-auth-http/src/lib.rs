@@ -12,43 +12,62 @@ use serde::ser::Serialize;
 use tokio::sync::RwLock;
 
 use config::PluginConfig;
+use outage::Outage;
 use rmqtt::ntex::util::ByteString;
 use rmqtt::reqwest::Response;
 use rmqtt::{ahash, async_trait, chrono, lazy_static, log, reqwest, serde_json, tokio};
 use rmqtt::{
     broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
     broker::types::{
-        AuthResult, ConnectInfo, Password, PublishAclResult, SubscribeAckReason, SubscribeAclResult,
-        Superuser,
+        AuthResult, ClientTags, ConnectInfo, Password, PublishAclResult, PublishRateLimit,
+        SubscribeAckReason, SubscribeAclResult, Superuser,
     },
     plugin::{DynPlugin, DynPluginResult, Plugin},
+    settings::listener::AuthOutage,
+    settings::schema::ConfigSchema,
     MqttError, Result, Runtime, TopicName,
 };
 
 mod config;
+mod outage;
 
 type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
 
 const CACHEABLE: &str = "X-Cache";
 const SUPERUSER: &str = "X-Superuser";
+const PUBLISH_RATE_LIMIT: &str = "X-Publish-Rate-Limit";
 
 const CACHE_KEY: &str = "ACL-CACHE-MAP";
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Copy)]
 enum ResponseResult {
     Allow(Superuser),
+    ///Allow, with the publish rate limits of the connection instead of those of the listener
+    AllowWithRateLimit(Superuser, PublishRateLimit),
     Deny,
     Ignore,
 }
 
 impl ResponseResult {
     #[inline]
-    fn from(s: &str, superuser: Superuser) -> Self {
+    fn from(s: &str, superuser: Superuser, rate_limit: Option<PublishRateLimit>) -> Self {
         match s {
-            "allow" => ResponseResult::Allow(superuser),
             "deny" => ResponseResult::Deny,
             "ignore" => ResponseResult::Ignore,
-            _ => ResponseResult::Allow(superuser),
+            _ => match rate_limit {
+                Some(rate_limit) => ResponseResult::AllowWithRateLimit(superuser, rate_limit),
+                None => ResponseResult::Allow(superuser),
+            },
+        }
+    }
+
+    #[inline]
+    fn superuser(&self) -> Option<Superuser> {
+        match self {
+            ResponseResult::Allow(superuser) | ResponseResult::AllowWithRateLimit(superuser, _) => {
+                Some(*superuser)
+            }
+            ResponseResult::Deny | ResponseResult::Ignore => None,
         }
     }
 }
@@ -95,16 +114,22 @@ struct AuthHttpPlugin {
     descr: String,
     register: Box<dyn Register>,
     cfg: Arc<RwLock<PluginConfig>>,
+    outage: Arc<Outage>,
 }
 
 impl AuthHttpPlugin {
     #[inline]
     async fn new<S: Into<String>>(runtime: &'static Runtime, name: S, descr: S) -> Result<Self> {
         let name = name.into();
-        let cfg = Arc::new(RwLock::new(runtime.settings.plugins.load_config::<PluginConfig>(&name)?));
+        let cfg = Arc::new(RwLock::new(
+            runtime
+                .settings
+                .plugins
+                .load_config_with_schema::<PluginConfig>(&name, &PluginConfig::schema())?,
+        ));
         log::debug!("{} AuthHttpPlugin cfg: {:?}", name, cfg.read().await);
-        let register = runtime.extends.hook_mgr().await.register();
-        Ok(Self { runtime, name, descr: descr.into(), register, cfg })
+        let register = runtime.extends.hook_mgr().await.register_for(&name);
+        Ok(Self { runtime, name, descr: descr.into(), register, cfg, outage: Arc::new(Outage::new()) })
     }
 }
 
@@ -114,14 +139,26 @@ impl Plugin for AuthHttpPlugin {
     async fn init(&mut self) -> Result<()> {
         log::info!("{} init", self.name);
         let cfg = &self.cfg;
+        let outage = &self.outage;
 
         let priority = cfg.read().await.priority;
-        self.register.add_priority(Type::ClientAuthenticate, priority, Box::new(AuthHandler::new(cfg))).await;
         self.register
-            .add_priority(Type::ClientSubscribeCheckAcl, priority, Box::new(AuthHandler::new(cfg)))
+            .add_priority(Type::ClientAuthenticate, priority, Box::new(AuthHandler::new(cfg, outage)))
+            .await;
+        self.register
+            .add_priority(Type::ClientSubscribeCheckAcl, priority, Box::new(AuthHandler::new(cfg, outage)))
             .await;
         self.register
-            .add_priority(Type::MessagePublishCheckAcl, priority, Box::new(AuthHandler::new(cfg)))
+            .add_priority(Type::MessagePublishCheckAcl, priority, Box::new(AuthHandler::new(cfg, outage)))
+            .await;
+        self.register
+            .add_priority(Type::ClientAclInvalidate, priority, Box::new(AuthHandler::new(cfg, outage)))
+            .await;
+        self.register
+            .add_priority(Type::ClientConnack, priority, Box::new(AuthHandler::new(cfg, outage)))
+            .await;
+        self.register
+            .add_priority(Type::ClientDisconnected, priority, Box::new(AuthHandler::new(cfg, outage)))
             .await;
 
         Ok(())
@@ -137,9 +174,18 @@ impl Plugin for AuthHttpPlugin {
         self.cfg.read().await.to_json()
     }
 
+    #[inline]
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(PluginConfig::schema())
+    }
+
     #[inline]
     async fn load_config(&mut self) -> Result<()> {
-        let new_cfg = self.runtime.settings.plugins.load_config::<PluginConfig>(&self.name)?;
+        let new_cfg = self
+            .runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&self.name, &PluginConfig::schema())?;
         *self.cfg.write().await = new_cfg;
         log::debug!("load_config ok,  {:?}", self.cfg);
         Ok(())
@@ -171,17 +217,18 @@ impl Plugin for AuthHttpPlugin {
 
     #[inline]
     async fn attrs(&self) -> serde_json::Value {
-        serde_json::json!({})
+        self.outage.to_json()
     }
 }
 
 struct AuthHandler {
     cfg: Arc<RwLock<PluginConfig>>,
+    outage: Arc<Outage>,
 }
 
 impl AuthHandler {
-    fn new(cfg: &Arc<RwLock<PluginConfig>>) -> Self {
-        Self { cfg: cfg.clone() }
+    fn new(cfg: &Arc<RwLock<PluginConfig>>, outage: &Arc<Outage>) -> Self {
+        Self { cfg: cfg.clone(), outage: outage.clone() }
     }
 
     async fn response_result(resp: Response) -> Result<(ResponseResult, Superuser, Cacheable)> {
@@ -200,8 +247,19 @@ impl AuthHandler {
                 None
             };
             log::debug!("Cache timeout is {:?}", cache_timeout);
+            //JSON, such as {"messages": 100, "bytes": 1048576}
+            let rate_limit =
+                resp.headers().get(PUBLISH_RATE_LIMIT).and_then(|v| {
+                    match serde_json::from_slice::<PublishRateLimit>(v.as_bytes()) {
+                        Ok(rate_limit) => Some(rate_limit),
+                        Err(e) => {
+                            log::warn!("Parse X-Publish-Rate-Limit error, {:?}", e);
+                            None
+                        }
+                    }
+                });
             let body = resp.text().await.map_err(|e| MqttError::Msg(e.to_string()))?;
-            Ok((ResponseResult::from(body.as_str(), superuser), superuser, cache_timeout))
+            Ok((ResponseResult::from(body.as_str(), superuser, rate_limit), superuser, cache_timeout))
         } else {
             Ok((ResponseResult::Ignore, false, None))
         }
@@ -276,6 +334,7 @@ impl AuthHandler {
     fn replaces<'a>(
         params: &'a mut HashMap<String, String>,
         connect_info: &ConnectInfo,
+        tags: &ClientTags,
         password: Option<&Password>,
         sub_or_pub: Option<(ACLType, &TopicName)>,
     ) -> Result<()> {
@@ -284,26 +343,61 @@ impl AuthHandler {
         let client_id = connect_info.client_id();
         let username = connect_info.username().map(|n| n.as_ref()).unwrap_or("");
         let remote_addr = connect_info.id().remote_addr.map(|addr| addr.ip().to_string()).unwrap_or_default();
+        let (acl_type, topic) =
+            sub_or_pub.map(|(acl_type, topic)| (acl_type.as_str(), &**topic)).unwrap_or(("", ""));
         for v in params.values_mut() {
-            *v = v.replace("%u", username);
-            *v = v.replace("%c", client_id);
-            *v = v.replace("%a", &remote_addr);
-            *v = v.replace("%r", "mqtt");
-            *v = v.replace("%P", &password);
-            if let Some((ref acl_type, topic)) = sub_or_pub {
-                *v = v.replace("%A", acl_type.as_str());
-                *v = v.replace("%t", topic);
-            } else {
-                *v = v.replace("%A", "");
-                *v = v.replace("%t", "");
+            if !v.contains('%') {
+                continue;
             }
+            *v = Self::substitute(v, tags, |c| match c {
+                'u' => Some(username),
+                'c' => Some(client_id),
+                'a' => Some(&remote_addr),
+                'r' => Some("mqtt"),
+                'P' => Some(&password),
+                'A' => Some(acl_type),
+                't' => Some(topic),
+                _ => None,
+            });
         }
         Ok(())
     }
 
+    ///Substitutes the placeholders in one pass, %{key} with the value of the tag key of the client,
+    ///empty if it has none, so that a substituted value, such as a tag value containing %u, is
+    ///never substituted again
+    fn substitute<'a, F>(v: &str, tags: &'a ClientTags, vars: F) -> String
+    where
+        F: Fn(char) -> Option<&'a str>,
+    {
+        let mut out = String::with_capacity(v.len());
+        let mut rest = v;
+        while let Some(pos) = rest.find('%') {
+            out.push_str(&rest[..pos]);
+            rest = &rest[pos + 1..];
+            if rest.starts_with('{') {
+                if let Some(end) = rest.find('}') {
+                    out.push_str(tags.get(&rest[1..end]).map(|v| v.as_str()).unwrap_or_default());
+                    rest = &rest[end + 1..];
+                    continue;
+                }
+            }
+            match rest.chars().next().and_then(|c| vars(c).map(|value| (c, value))) {
+                Some((c, value)) => {
+                    out.push_str(value);
+                    rest = &rest[c.len_utf8()..];
+                }
+                None => out.push('%'),
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+
     async fn request(
         &self,
         connect_info: &ConnectInfo,
+        tags: &ClientTags,
         mut req_cfg: config::Req,
         password: Option<&Password>,
         sub_or_pub: Option<(ACLType, &TopicName)>,
@@ -326,16 +420,16 @@ impl AuthHandler {
 
         let (auth_result, superuser, cacheable) = if req_cfg.is_get() {
             let body = &mut req_cfg.params;
-            Self::replaces(body, connect_info, password, sub_or_pub)?;
+            Self::replaces(body, connect_info, tags, password, sub_or_pub)?;
             Self::http_get_request(req_cfg.url, body, headers, timeout).await?
         } else if req_cfg.json_body() {
             let body = &mut req_cfg.params;
-            Self::replaces(body, connect_info, password, sub_or_pub)?;
+            Self::replaces(body, connect_info, tags, password, sub_or_pub)?;
             Self::http_json_request(req_cfg.url, req_cfg.method, body, headers, timeout).await?
         } else {
             //form body
             let body = &mut req_cfg.params;
-            Self::replaces(body, connect_info, password, sub_or_pub)?;
+            Self::replaces(body, connect_info, tags, password, sub_or_pub)?;
             Self::http_form_request(req_cfg.url, req_cfg.method, body, headers, timeout).await?
         };
         log::debug!("auth_result: {:?}, superuser: {}, cacheable: {:?}", auth_result, superuser, cacheable);
@@ -344,18 +438,17 @@ impl AuthHandler {
 
     async fn auth(&self, connect_info: &ConnectInfo, password: Option<&Password>) -> ResponseResult {
         if let Some(req) = { self.cfg.read().await.http_auth_req.clone() } {
-            match self.request(connect_info, req.clone(), password, None).await {
+            match self.request(connect_info, &ClientTags::new(), req.clone(), password, None).await {
                 Ok((auth_res, _)) => {
                     log::debug!("auth result: {:?}", auth_res);
+                    if let Some(superuser) = auth_res.superuser() {
+                        self.outage.allowed(connect_info, password, superuser);
+                    }
                     auth_res
                 }
                 Err(e) => {
                     log::warn!("{:?} auth error, {:?}", connect_info.id(), e);
-                    if self.cfg.read().await.deny_if_error {
-                        ResponseResult::Deny
-                    } else {
-                        ResponseResult::Ignore
-                    }
+                    self.auth_outage(connect_info, req, password).await
                 }
             }
         } else {
@@ -363,13 +456,84 @@ impl AuthHandler {
         }
     }
 
+    ///The auth request failed, the auth_outage of the listener decides, or deny_if_error if it is
+    ///not set
+    async fn auth_outage(
+        &self,
+        connect_info: &ConnectInfo,
+        req: config::Req,
+        password: Option<&Password>,
+    ) -> ResponseResult {
+        let listener = match Outage::listener(connect_info) {
+            Some(listener) if listener.auth_outage.is_some() => listener,
+            _ => {
+                return if self.cfg.read().await.deny_if_error {
+                    ResponseResult::Deny
+                } else {
+                    ResponseResult::Ignore
+                };
+            }
+        };
+        match listener.auth_outage {
+            Some(AuthOutage::Restricted) => {
+                log::info!("{:?} auth outage, allowed with the restricted ACL", connect_info.id());
+                self.outage.restrict(connect_info.id());
+                ResponseResult::Allow(false)
+            }
+            Some(AuthOutage::Cached) => match self.outage.cached(connect_info, password) {
+                Some(superuser) => {
+                    log::info!("{:?} auth outage, allowed by the cached decision", connect_info.id());
+                    ResponseResult::Allow(superuser)
+                }
+                None => ResponseResult::Deny,
+            },
+            Some(AuthOutage::Queue) => {
+                let retry = self.cfg.read().await.http_retry.clone();
+                let deadline = tokio::time::Instant::now() + listener.auth_outage_queue_timeout;
+                let mut interval = retry.interval;
+                loop {
+                    let now = tokio::time::Instant::now();
+                    if now >= deadline {
+                        log::warn!("{:?} auth outage, the queued connect timed out", connect_info.id());
+                        return ResponseResult::Deny;
+                    }
+                    tokio::time::sleep(interval.min(deadline - now)).await;
+                    match self.request(connect_info, &ClientTags::new(), req.clone(), password, None).await {
+                        Ok((auth_res, _)) => {
+                            if let Some(superuser) = auth_res.superuser() {
+                                self.outage.allowed(connect_info, password, superuser);
+                            }
+                            return auth_res;
+                        }
+                        Err(e) => log::debug!("{:?} auth retry error, {:?}", connect_info.id(), e),
+                    }
+                    interval = interval.mul_f32(retry.backoff.max(1.0));
+                }
+            }
+            Some(AuthOutage::Reject) | None => ResponseResult::Deny,
+        }
+    }
+
     async fn acl(
         &self,
         connect_info: &ConnectInfo,
+        tags: &ClientTags,
         sub_or_pub: Option<(ACLType, &TopicName)>,
     ) -> (ResponseResult, Cacheable) {
+        //Connected during an auth outage, the HTTP server is not asked
+        if self.outage.is_restricted(connect_info.id()) {
+            let allowed = match (Outage::listener(connect_info), sub_or_pub) {
+                (Some(listener), Some((_, topic))) => listener.auth_outage_acl_allows(
+                    topic,
+                    connect_info.client_id(),
+                    connect_info.username().map(|u| u.as_ref()),
+                ),
+                _ => false,
+            };
+            return if allowed { (ResponseResult::Allow(false), None) } else { (ResponseResult::Deny, None) };
+        }
         if let Some(req) = { self.cfg.read().await.http_acl_req.clone() } {
-            match self.request(connect_info, req.clone(), None, sub_or_pub).await {
+            match self.request(connect_info, tags, req.clone(), None, sub_or_pub).await {
                 Ok(acl_res) => {
                     log::debug!("acl result: {:?}", acl_res);
                     acl_res
@@ -407,6 +571,10 @@ impl Handler for AuthHandler {
                     ResponseResult::Allow(superuser) => {
                         (false, Some(HookResult::AuthResult(AuthResult::Allow(superuser))))
                     }
+                    ResponseResult::AllowWithRateLimit(superuser, rate_limit) => (
+                        false,
+                        Some(HookResult::AuthResult(AuthResult::AllowWithRateLimit(superuser, rate_limit))),
+                    ),
                     ResponseResult::Deny => {
                         (false, Some(HookResult::AuthResult(AuthResult::BadUsernameOrPassword)))
                     }
@@ -422,10 +590,15 @@ impl Handler for AuthHandler {
                 }
 
                 //ResponseResult, Cacheable
-                let (acl_res, _) =
-                    self.acl(&client_info.connect_info, Some((ACLType::Sub, &subscribe.topic_filter))).await;
+                let (acl_res, _) = self
+                    .acl(
+                        &client_info.connect_info,
+                        &client_info.tags(),
+                        Some((ACLType::Sub, &subscribe.topic_filter)),
+                    )
+                    .await;
                 return match acl_res {
-                    ResponseResult::Allow(_) => (
+                    ResponseResult::Allow(_) | ResponseResult::AllowWithRateLimit(..) => (
                         false,
                         Some(HookResult::SubscribeAclResult(SubscribeAclResult::new_success(subscribe.qos))),
                     ),
@@ -465,8 +638,13 @@ impl Handler for AuthHandler {
                     acl_res
                 } else {
                     //ResponseResult, Cacheable
-                    let (acl_res, cacheable) =
-                        self.acl(&client_info.connect_info, Some((ACLType::Pub, publish.topic()))).await;
+                    let (acl_res, cacheable) = self
+                        .acl(
+                            &client_info.connect_info,
+                            &client_info.tags(),
+                            Some((ACLType::Pub, publish.topic())),
+                        )
+                        .await;
                     if let Some(tm) = cacheable {
                         let expire = if tm < 0 { tm } else { chrono::Local::now().timestamp_millis() + tm };
                         if let Some(cache_map) = client_info
@@ -482,7 +660,7 @@ impl Handler for AuthHandler {
                 };
 
                 return match acl_res {
-                    ResponseResult::Allow(_) => {
+                    ResponseResult::Allow(_) | ResponseResult::AllowWithRateLimit(..) => {
                         (false, Some(HookResult::PublishAclResult(PublishAclResult::Allow)))
                     }
                     ResponseResult::Deny => (
@@ -494,6 +672,18 @@ impl Handler for AuthHandler {
                     ResponseResult::Ignore => (true, None),
                 };
             }
+            Parameter::ClientAclInvalidate(_session, client_info) => {
+                client_info.extra_attrs.write().await.remove(CACHE_KEY);
+                self.outage.invalidate(&client_info.connect_info);
+            }
+            Parameter::ClientConnack(connect_info, reason) => {
+                if !reason.success() {
+                    self.outage.release(connect_info.id());
+                }
+            }
+            Parameter::ClientDisconnected(_session, client_info, _reason) => {
+                self.outage.release(&client_info.id);
+            }
             _ => {
                 log::error!("unimplemented, {:?}", param)
             }
@@ -0,0 +1,130 @@
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rmqtt::settings::listener::{AuthOutage, Listener};
+use rmqtt::{ahash, chrono};
+use rmqtt::{
+    ClientId, ConnectInfo, DashMap, DashSet, Id, Password, Runtime, Superuser, TimestampMillis, UserName,
+};
+
+///What is kept to answer the connects of the listeners with an auth_outage, when the HTTP server
+///is unreachable
+pub(crate) struct Outage {
+    hasher: ahash::RandomState,
+    ///The last allowed connect of each client ID and username on the listeners with
+    ///auth_outage = "cached", (password hash, superuser, expiry time)
+    decisions: DashMap<(ClientId, Option<UserName>), (u64, Superuser, TimestampMillis)>,
+    ///Decisions recorded since the expired ones were last removed
+    recorded: AtomicUsize,
+    ///The connections allowed with auth_outage = "restricted"
+    restricted: DashSet<Id>,
+}
+
+///The expired decisions are removed every SWEEP_INTERVAL recorded decisions
+const SWEEP_INTERVAL: usize = 1024;
+
+impl Outage {
+    #[inline]
+    pub(crate) fn new() -> Self {
+        Self {
+            hasher: ahash::RandomState::new(),
+            decisions: DashMap::default(),
+            recorded: AtomicUsize::new(0),
+            restricted: DashSet::default(),
+        }
+    }
+
+    ///The listener of the connection
+    #[inline]
+    pub(crate) fn listener(connect_info: &ConnectInfo) -> Option<Listener> {
+        connect_info.id().local_addr.and_then(|addr| Runtime::instance().settings.listeners.get(addr.port()))
+    }
+
+    #[inline]
+    fn key(connect_info: &ConnectInfo) -> (ClientId, Option<UserName>) {
+        (connect_info.client_id().clone(), connect_info.username().cloned())
+    }
+
+    ///Only a hash of the password is kept
+    #[inline]
+    fn password_hash(&self, password: Option<&Password>) -> u64 {
+        let mut hasher = self.hasher.build_hasher();
+        password.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    ///Records a connect allowed by the HTTP server, if its listener has auth_outage = "cached", until
+    ///auth_outage_cache_ttl
+    #[inline]
+    pub(crate) fn allowed(
+        &self,
+        connect_info: &ConnectInfo,
+        password: Option<&Password>,
+        superuser: Superuser,
+    ) {
+        let ttl = match Self::listener(connect_info) {
+            Some(listener) if matches!(listener.auth_outage, Some(AuthOutage::Cached)) => {
+                listener.auth_outage_cache_ttl
+            }
+            _ => return,
+        };
+        let now = chrono::Local::now().timestamp_millis();
+        self.decisions.insert(
+            Self::key(connect_info),
+            (self.password_hash(password), superuser, now + ttl.as_millis() as TimestampMillis),
+        );
+        if self.recorded.fetch_add(1, Ordering::Relaxed) + 1 >= SWEEP_INTERVAL {
+            self.recorded.store(0, Ordering::Relaxed);
+            self.decisions.retain(|_, (_, _, expiry)| *expiry >= now);
+        }
+    }
+
+    ///Forgets the decision of the client, its ACL or its credentials were revoked
+    #[inline]
+    pub(crate) fn invalidate(&self, connect_info: &ConnectInfo) {
+        self.decisions.remove(&Self::key(connect_info));
+    }
+
+    ///The superuser flag of the last allowed connect with the same client ID, username and password,
+    ///None if there is none or it expired
+    #[inline]
+    pub(crate) fn cached(
+        &self,
+        connect_info: &ConnectInfo,
+        password: Option<&Password>,
+    ) -> Option<Superuser> {
+        let (hash, superuser, expiry) = *self.decisions.get(&Self::key(connect_info))?;
+        let fresh = chrono::Local::now().timestamp_millis() <= expiry;
+        if fresh && hash == self.password_hash(password) {
+            Some(superuser)
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub(crate) fn restrict(&self, id: &Id) {
+        self.restricted.insert(id.clone());
+    }
+
+    #[inline]
+    pub(crate) fn is_restricted(&self, id: &Id) -> bool {
+        !self.restricted.is_empty() && self.restricted.contains(id)
+    }
+
+    ///Called when the connection is closed or its connect fails
+    #[inline]
+    pub(crate) fn release(&self, id: &Id) {
+        if !self.restricted.is_empty() {
+            self.restricted.remove(id);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn to_json(&self) -> rmqtt::serde_json::Value {
+        rmqtt::serde_json::json!({
+            "cached_decisions": self.decisions.len(),
+            "restricted_connections": self.restricted.len(),
+        })
+    }
+}
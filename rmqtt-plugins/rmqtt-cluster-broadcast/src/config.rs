@@ -1,6 +1,9 @@
-use rmqtt::grpc::MessageType;
+use std::time::Duration;
+
+use rmqtt::grpc::{codec::Codec, MessageType};
 use rmqtt::serde_json;
-use rmqtt::settings::NodeAddr;
+use rmqtt::settings::schema::{ConfigSchema, Field, FieldType};
+use rmqtt::settings::{deserialize_duration, NodeAddr};
 use rmqtt::Result;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -9,6 +12,21 @@ pub struct PluginConfig {
     pub message_type: MessageType,
 
     pub node_grpc_addrs: Vec<NodeAddr>,
+
+    ///Encoding of the messages sent to the other nodes, bincode or msgpack
+    #[serde(default)]
+    pub message_codec: Codec,
+
+    ///A publish forwarded again to a node within the window is not delivered twice, 0 means no
+    ///deduplication
+    #[serde(
+        default = "PluginConfig::forward_dedup_window_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub forward_dedup_window: Duration,
+    ///Maximum number of the forwarded publishes remembered within the window
+    #[serde(default = "PluginConfig::forward_dedup_max_default")]
+    pub forward_dedup_max: usize,
 }
 
 impl PluginConfig {
@@ -16,6 +34,40 @@ impl PluginConfig {
         98
     }
 
+    fn forward_dedup_window_default() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn forward_dedup_max_default() -> usize {
+        100_000
+    }
+
+    pub fn schema() -> ConfigSchema {
+        ConfigSchema::new()
+            .field(Field::new("message_type", FieldType::Integer).default(Self::message_type_default()))
+            .field(
+                Field::new("node_grpc_addrs", FieldType::Array)
+                    .required()
+                    .descr("gRPC addresses of all the nodes, as \"<node id>@<host>:<port>\""),
+            )
+            .field(
+                Field::new("message_codec", FieldType::String)
+                    .default_of(Codec::default())
+                    .options(vec!["bincode", "msgpack"])
+                    .descr("Encoding of the messages sent to the other nodes"),
+            )
+            .field(
+                Field::new("forward_dedup_window", FieldType::Duration)
+                    .default_duration(Self::forward_dedup_window_default())
+                    .descr("A publish forwarded again to a node within the window is not delivered twice"),
+            )
+            .field(
+                Field::new("forward_dedup_max", FieldType::Integer)
+                    .default(Self::forward_dedup_max_default())
+                    .descr("Maximum number of the forwarded publishes remembered within the window"),
+            )
+    }
+
     #[inline]
     pub fn to_json(&self) -> Result<serde_json::Value> {
         Ok(serde_json::to_value(self)?)
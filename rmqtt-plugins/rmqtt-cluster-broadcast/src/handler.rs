@@ -39,11 +39,29 @@ impl Handler for HookHandler {
                 }
                 match msg {
                     Message::Forwards(from, publish) => {
+                        if self.shared.forward_dedup.is_duplicate(publish) {
+                            log::debug!(
+                                "{:?} duplicate forward is suppressed, forward_id: {:?}",
+                                from,
+                                publish.forward_id
+                            );
+                            let new_acc =
+                                HookResult::GrpcMessageReply(Ok(MessageReply::Forwards(Default::default())));
+                            return (false, Some(new_acc));
+                        }
                         let shared_subs = forwards(from.clone(), publish.clone()).await;
                         let new_acc = HookResult::GrpcMessageReply(Ok(MessageReply::Forwards(shared_subs)));
                         return (false, Some(new_acc));
                     }
                     Message::ForwardsTo(from, publish, sub_rels) => {
+                        if self.shared.forward_dedup.is_duplicate(publish) {
+                            log::debug!(
+                                "{:?} duplicate forward is suppressed, forward_id: {:?}",
+                                from,
+                                publish.forward_id
+                            );
+                            return (false, acc);
+                        }
                         if let Err(droppeds) =
                             self.shared.inner().forwards_to(from.clone(), publish, sub_rels.clone()).await
                         {
@@ -154,8 +172,16 @@ async fn forwards(from: From, publish: Publish) -> SubRelationsMap {
     log::debug!("forwards, From: {:?}, publish: {:?}", from, publish);
     match Runtime::instance().extends.shared().await.forwards_and_get_shareds(from, publish).await {
         Err(droppeds) => {
+            //this node has subscribers, the messages it dropped are reported here
+            let mut relations_map = SubRelationsMap::default();
+            if let Some((to, _, publish, _)) = droppeds.first() {
+                relations_map.insert(
+                    Runtime::instance().node.id(),
+                    vec![(publish.topic.clone(), to.client_id.clone(), publish.qos, None)],
+                );
+            }
             hook_message_dropped(droppeds).await;
-            SubRelationsMap::default()
+            relations_map
         }
         Ok(relations_map) => relations_map,
     }
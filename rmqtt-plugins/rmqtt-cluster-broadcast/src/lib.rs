@@ -7,22 +7,18 @@ use std::sync::Arc;
 use config::PluginConfig;
 use handler::HookHandler;
 use retainer::ClusterRetainer;
-use rmqtt::{
-    ahash,
-    async_trait::async_trait,
-    log,
-    serde_json::{self, json},
-    RwLock,
-};
+use rmqtt::{ahash, async_trait::async_trait, log, serde_json, RwLock};
 use rmqtt::{
     broker::{
+        dedup::ForwardDedup,
         error::MqttError,
         hook::{Register, Type},
         session::SessionOfflineInfo,
         types::{From, Publish, Reason, To},
     },
-    grpc::{GrpcClients, Message, MessageReply, MessageType},
-    plugin::{DynPlugin, DynPluginResult, Plugin},
+    grpc::{codec::Codec, registry::MessageTypes, GrpcClients, Message, MessageReply, MessageType},
+    plugin::{DynPlugin, DynPluginResult, Metric, Plugin},
+    settings::schema::ConfigSchema,
     Result, Runtime,
 };
 use router::ClusterRouter;
@@ -75,26 +71,32 @@ impl ClusterPlugin {
             runtime
                 .settings
                 .plugins
-                .load_config::<PluginConfig>(&name)
-                .map_err(|e| MqttError::from(e.to_string()))?,
+                .load_config_with_schema::<PluginConfig>(&name, &PluginConfig::schema())?,
         ));
         log::debug!("{} ClusterPlugin cfg: {:?}", name, cfg.read());
 
-        let register = runtime.extends.hook_mgr().await.register();
+        let register = runtime.extends.hook_mgr().await.register_for(&name);
         let mut grpc_clients = HashMap::default();
         let node_grpc_addrs = cfg.read().node_grpc_addrs.clone();
         for node_addr in &node_grpc_addrs {
             if node_addr.id != runtime.node.id() {
                 grpc_clients.insert(
                     node_addr.id,
-                    (node_addr.addr.clone(), runtime.node.new_grpc_client(&node_addr.addr).await?),
+                    (
+                        node_addr.addr.clone(),
+                        runtime.node.new_peer_grpc_client(node_addr.id, &node_addr.addr).await?,
+                    ),
                 );
             }
         }
         let grpc_clients = Arc::new(grpc_clients);
         let message_type = cfg.read().message_type;
         let router = ClusterRouter::get_or_init(grpc_clients.clone(), message_type);
-        let shared = ClusterShared::get_or_init(grpc_clients.clone(), message_type);
+        let forward_dedup = {
+            let cfg = cfg.read();
+            ForwardDedup::new(cfg.forward_dedup_window, cfg.forward_dedup_max)
+        };
+        let shared = ClusterShared::get_or_init(grpc_clients.clone(), message_type, forward_dedup);
         let retainer = ClusterRetainer::get_or_init(grpc_clients.clone(), message_type);
         Ok(Self { runtime, name, descr: descr.into(), register, cfg, grpc_clients, shared, retainer, router })
     }
@@ -105,6 +107,8 @@ impl Plugin for ClusterPlugin {
     #[inline]
     async fn init(&mut self) -> Result<()> {
         log::info!("{} init", self.name);
+        MessageTypes::instance().register(&self.name, self.cfg.read().message_type)?;
+        Codec::set_current(self.cfg.read().message_codec);
         self.register
             .add(
                 Type::GrpcMessageReceived,
@@ -124,6 +128,11 @@ impl Plugin for ClusterPlugin {
         self.cfg.read().to_json()
     }
 
+    #[inline]
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(PluginConfig::schema())
+    }
+
     #[inline]
     async fn start(&mut self) -> Result<()> {
         log::info!("{} start", self.name);
@@ -150,18 +159,26 @@ impl Plugin for ClusterPlugin {
     }
 
     #[inline]
-    async fn attrs(&self) -> serde_json::Value {
-        let mut nodes = HashMap::default();
-        for (id, (addr, c)) in self.grpc_clients.iter() {
-            let stats = json!({
-                "channel_tasks": c.channel_tasks(),
-                "active_tasks": c.active_tasks(),
-            });
-            nodes.insert(format!("{}/{:?}", id, addr), stats);
+    fn reloadable(&self) -> bool {
+        false
+    }
+
+    #[inline]
+    async fn metrics(&self) -> Vec<Metric> {
+        let mut metrics = Vec::new();
+        for (id, (_, c)) in self.grpc_clients.iter() {
+            metrics.push(
+                Metric::gauge("grpc_client_channel_tasks", c.channel_tasks() as f64)
+                    .label("peer", id.to_string())
+                    .descr("Messages queued for the peer node"),
+            );
+            metrics.push(
+                Metric::gauge("grpc_client_active_tasks", c.active_tasks() as f64)
+                    .label("peer", id.to_string())
+                    .descr("Messages being sent to the peer node"),
+            );
         }
-        json!({
-            "grpc_clients": nodes,
-        })
+        metrics
     }
 }
 
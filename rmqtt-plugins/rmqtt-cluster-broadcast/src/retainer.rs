@@ -81,4 +81,9 @@ impl RetainStorage for &'static ClusterRetainer {
     fn max(&self) -> isize {
         self.inner.max()
     }
+
+    #[inline]
+    fn bytes(&self) -> usize {
+        self.inner.bytes()
+    }
 }
@@ -6,7 +6,7 @@ use rmqtt::{async_trait::async_trait, itertools, log, once_cell, serde_json};
 use rmqtt::{
     broker::{
         default::DefaultRouter,
-        types::{Id, NodeId, QoS, Route, SharedGroup, TopicName},
+        types::{Id, NodeId, Publish, QoS, Route, SharedGroup, TopicName},
         Router, SubRelationsMap,
     },
     grpc::{GrpcClients, Message, MessageBroadcaster, MessageReply, MessageSender, MessageType},
@@ -55,6 +55,11 @@ impl Router for &'static ClusterRouter {
         self.inner.matches(topic).await
     }
 
+    #[inline]
+    async fn matches_publish(&self, publish: &Publish) -> Result<SubRelationsMap> {
+        self.inner.matches_publish(publish).await
+    }
+
     ///Check online or offline
     async fn is_online(&self, node_id: NodeId, client_id: &str) -> bool {
         self.inner.is_online(node_id, client_id).await
@@ -6,15 +6,18 @@ use rmqtt::grpc::MessageSender;
 use rmqtt::{ahash, async_trait::async_trait, futures, log, once_cell, tokio};
 use rmqtt::{
     broker::{
+        dedup::ForwardDedup,
         default::DefaultShared,
         session::{ClientInfo, Session, SessionOfflineInfo},
         types::{
-            ClientId, From, Id, IsAdmin, IsOnline, NodeId, Publish, QoS, Reason, SessionStatus, SharedGroup,
-            SubsSearchParams, SubsSearchResult, Subscribe, SubscribeReturn, To, TopicFilter, Tx, Unsubscribe,
+            ClientId, ForwardId, From, Id, IsAdmin, IsOnline, NodeId, Publish, QoS, Reason, SessionStatus,
+            SharedGroup, SubsSearchParams, SubsSearchResult, Subscribe, SubscribeReturn, To, TopicFilter, Tx,
+            Unsubscribe,
         },
         Entry, Shared, SubRelations, SubRelationsMap,
     },
-    grpc::{GrpcClients, Message, MessageBroadcaster, MessageReply, MessageType},
+    grpc::{protocol, GrpcClients, Message, MessageBroadcaster, MessageReply, MessageType},
+    settings::DuplicateClientId,
     MqttError, Result, Runtime,
 };
 
@@ -41,6 +44,12 @@ impl Entry for ClusterLockEntry {
         Ok(Box::new(ClusterLockEntry::new(self.inner.try_lock().await?, self.cluster_shared)))
     }
 
+    ///The sessions of the client_id and of its suffixes are looked up on all nodes
+    #[inline]
+    async fn try_lock_connect(&self, policy: DuplicateClientId) -> Result<Box<dyn Entry>> {
+        Ok(Box::new(ClusterLockEntry::new(self.inner.try_lock_connect(policy).await?, self.cluster_shared)))
+    }
+
     #[inline]
     fn id(&self) -> Id {
         self.inner.id()
@@ -191,6 +200,7 @@ pub struct ClusterShared {
     inner: &'static DefaultShared,
     grpc_clients: GrpcClients,
     pub message_type: MessageType,
+    pub(crate) forward_dedup: ForwardDedup,
 }
 
 impl ClusterShared {
@@ -198,9 +208,15 @@ impl ClusterShared {
     pub(crate) fn get_or_init(
         grpc_clients: GrpcClients,
         message_type: MessageType,
+        forward_dedup: ForwardDedup,
     ) -> &'static ClusterShared {
         static INSTANCE: OnceCell<ClusterShared> = OnceCell::new();
-        INSTANCE.get_or_init(|| Self { inner: DefaultShared::instance(), grpc_clients, message_type })
+        INSTANCE.get_or_init(|| Self {
+            inner: DefaultShared::instance(),
+            grpc_clients,
+            message_type,
+            forward_dedup,
+        })
     }
 
     #[inline]
@@ -222,14 +238,19 @@ impl Shared for &'static ClusterShared {
     }
 
     #[inline]
-    async fn forwards(&self, from: From, publish: Publish) -> Result<(), Vec<(To, From, Publish, Reason)>> {
+    async fn forwards(
+        &self,
+        from: From,
+        mut publish: Publish,
+    ) -> Result<(), Vec<(To, From, Publish, Reason)>> {
         let this_node_id = Runtime::instance().node.id();
         let topic = publish.topic();
         log::debug!("forwards, from: {:?}, topic: {:?}", from, topic.to_string());
 
         //Matching subscriptions
+        let mut routed = true;
         let (relations, shared_relations) =
-            match Runtime::instance().extends.router().await.matches(topic).await {
+            match Runtime::instance().extends.router().await.matches_publish(&publish).await {
                 Ok(mut relations_map) => {
                     let mut relations = SubRelations::new();
                     let mut shared_relations = Vec::new();
@@ -247,17 +268,21 @@ impl Shared for &'static ClusterShared {
                 }
                 Err(e) => {
                     log::warn!("forwards, from:{:?}, topic:{:?}, error: {:?}", from, topic, e);
+                    routed = false;
                     (Vec::new(), Vec::new())
                 }
             };
 
         log::debug!("relations: {}, shared_relations:{}", relations.len(), shared_relations.len());
+        let mut has_subscribers = !relations.is_empty() || !shared_relations.is_empty();
 
         //forwards to local
         let local_res = self.inner.forwards_to(from.clone(), &publish, relations).await;
         log::debug!("forwards, from: {:?}, local_res: {:?}", from, local_res);
 
-        //forwards to remote
+        //forwards to remote, the shared subscriptions chosen from the replies are delivered with another
+        //idempotency key, a node receives the same publish twice then
+        publish.forward_id = Some(ForwardId::next());
         let grpc_clients = self.grpc_clients.clone();
         let message_type = self.message_type;
         let inner = self.inner;
@@ -301,11 +326,15 @@ impl Shared for &'static ClusterShared {
 
             add_to_shared_sub_groups(&mut shared_sub_groups, shared_relations);
 
+            //whether a publish no node has subscribers for is known only if every node answered in
+            //this protocol version, the older ones do not report their other subscriptions
+            let mut known = routed && !protocol::is_mixed();
             for (_, reply) in replys {
                 match reply {
                     Ok(reply) => {
                         if let MessageReply::Forwards(mut o_relations_map) = reply {
                             log::debug!("other noade relations: {:?}", o_relations_map);
+                            has_subscribers |= !o_relations_map.is_empty();
                             for (node_id, rels) in o_relations_map.drain() {
                                 for (topic_filter, client_id, qos, group) in rels {
                                     if let Some(group) = group {
@@ -316,9 +345,12 @@ impl Shared for &'static ClusterShared {
                                     }
                                 }
                             }
+                        } else {
+                            known = false;
                         }
                     }
                     Err(e) => {
+                        known = false;
                         log::error!(
                             "forwards Message::Forwards to other node, from: {:?}, error: {:?}",
                             from,
@@ -328,6 +360,11 @@ impl Shared for &'static ClusterShared {
                 }
             }
 
+            if known && !has_subscribers {
+                DefaultShared::no_subscribers(from, publish).await;
+                return;
+            }
+
             //shared subscription choice
             let mut node_shared_subs: HashMap<NodeId, SubRelations> = HashMap::default();
             for (topic_filter, sub_groups) in shared_sub_groups.iter_mut() {
@@ -355,6 +392,8 @@ impl Shared for &'static ClusterShared {
             }
 
             //send to other node
+            let mut publish = publish;
+            publish.forward_id = Some(ForwardId::next());
             let mut delivers = Vec::new();
             for (id, (_addr, grpc_client)) in grpc_clients.iter() {
                 if let Some(sub_rels) = node_shared_subs.remove(id) {
@@ -374,7 +413,12 @@ impl Shared for &'static ClusterShared {
             }
         };
 
-        tokio::spawn(broadcast_fut);
+        //With strict ordering, the next publish is not forwarded until this one is
+        if Runtime::instance().settings.mqtt.strict_ordering {
+            broadcast_fut.await;
+        } else {
+            tokio::spawn(broadcast_fut);
+        }
 
         local_res?;
         Ok(())
@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use rmqtt::grpc::{codec::Codec, MessageType};
+use rmqtt::serde_json;
+use rmqtt::settings::schema::{ConfigSchema, Field, FieldType};
+use rmqtt::settings::{deserialize_duration, NodeAddr, Options};
+use rmqtt::Result;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    #[serde(default = "PluginConfig::message_type_default")]
+    pub message_type: MessageType,
+
+    pub node_grpc_addrs: Vec<NodeAddr>,
+
+    ///Encoding of the messages sent to the other nodes, bincode or msgpack
+    #[serde(default)]
+    pub message_codec: Codec,
+
+    ///Interval of the gossip rounds
+    #[serde(default = "PluginConfig::gossip_interval_default", deserialize_with = "deserialize_duration")]
+    pub gossip_interval: Duration,
+
+    ///Number of the nodes a node gossips with in each round
+    #[serde(default = "PluginConfig::gossip_fanout_default")]
+    pub gossip_fanout: usize,
+
+    ///A node no gossip round has reached for this long is considered dead, its subscriptions and
+    ///sessions are removed until it is reached again
+    #[serde(default = "PluginConfig::node_timeout_default", deserialize_with = "deserialize_duration")]
+    pub node_timeout: Duration,
+
+    ///File in which the incarnation of the node is kept across its restarts
+    #[serde(default = "PluginConfig::incarnation_file_default")]
+    pub incarnation_file: String,
+
+    ///Removed subscriptions are kept this long as tombstones, a node that has not gossiped for
+    ///longer receives the full state of the nodes again
+    #[serde(default = "PluginConfig::tombstone_ttl_default", deserialize_with = "deserialize_duration")]
+    pub tombstone_ttl: Duration,
+
+    ///A publish forwarded again to a node within the window is not delivered twice, 0 means no
+    ///deduplication
+    #[serde(
+        default = "PluginConfig::forward_dedup_window_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub forward_dedup_window: Duration,
+    ///Maximum number of the forwarded publishes remembered within the window
+    #[serde(default = "PluginConfig::forward_dedup_max_default")]
+    pub forward_dedup_max: usize,
+}
+
+impl PluginConfig {
+    fn message_type_default() -> MessageType {
+        97
+    }
+
+    fn gossip_interval_default() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn gossip_fanout_default() -> usize {
+        2
+    }
+
+    fn node_timeout_default() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn incarnation_file_default() -> String {
+        "/var/lib/rmqtt/cluster-crdt/incarnation".into()
+    }
+
+    fn tombstone_ttl_default() -> Duration {
+        Duration::from_secs(3600)
+    }
+
+    fn forward_dedup_window_default() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn forward_dedup_max_default() -> usize {
+        100_000
+    }
+
+    pub fn merge(&mut self, opts: &Options) {
+        if let Some(node_grpc_addrs) = opts.node_grpc_addrs.as_ref() {
+            self.node_grpc_addrs = node_grpc_addrs.clone();
+        }
+    }
+
+    pub fn schema() -> ConfigSchema {
+        ConfigSchema::new()
+            .field(Field::new("message_type", FieldType::Integer).default(Self::message_type_default()))
+            .field(
+                Field::new("node_grpc_addrs", FieldType::Array)
+                    .required()
+                    .descr("gRPC addresses of all the nodes, as \"<node id>@<host>:<port>\""),
+            )
+            .field(
+                Field::new("message_codec", FieldType::String)
+                    .default_of(Codec::default())
+                    .options(vec!["bincode", "msgpack"])
+                    .descr("Encoding of the messages sent to the other nodes"),
+            )
+            .field(
+                Field::new("gossip_interval", FieldType::Duration)
+                    .default_duration(Self::gossip_interval_default())
+                    .descr("Interval of the gossip rounds"),
+            )
+            .field(
+                Field::new("gossip_fanout", FieldType::Integer)
+                    .default(Self::gossip_fanout_default())
+                    .descr("Number of the nodes a node gossips with in each round"),
+            )
+            .field(
+                Field::new("node_timeout", FieldType::Duration)
+                    .default_duration(Self::node_timeout_default())
+                    .descr("A node no gossip round has reached for this long is considered dead"),
+            )
+            .field(
+                Field::new("incarnation_file", FieldType::String)
+                    .default(Self::incarnation_file_default())
+                    .descr("File in which the incarnation of the node is kept across its restarts"),
+            )
+            .field(
+                Field::new("tombstone_ttl", FieldType::Duration)
+                    .default_duration(Self::tombstone_ttl_default())
+                    .descr("Removed subscriptions are kept this long as tombstones"),
+            )
+            .field(
+                Field::new("forward_dedup_window", FieldType::Duration)
+                    .default_duration(Self::forward_dedup_window_default())
+                    .descr("A publish forwarded again to a node within the window is not delivered twice"),
+            )
+            .field(
+                Field::new("forward_dedup_max", FieldType::Integer)
+                    .default(Self::forward_dedup_max_default())
+                    .descr("Maximum number of the forwarded publishes remembered within the window"),
+            )
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+}
@@ -0,0 +1,417 @@
+use std::path::Path;
+use std::time::Duration;
+
+use rmqtt::{
+    broker::types::{ClientId, Id, NodeId, QoS, SharedGroup, TimestampMillis, TopicFilter},
+    HashMap,
+};
+use rmqtt::{chrono, log, serde_json, RwLock};
+
+///Version of a change made by a node. The incarnation is increased at each start of the node and
+///persisted, so that the versions of a node keep increasing across its restarts, whatever its clock
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Version {
+    pub incarnation: TimestampMillis,
+    pub counter: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Key {
+    ///A subscription of a client of the node
+    Sub(TopicFilter, ClientId),
+    ///The session of a client on the node
+    Session(ClientId),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum Value {
+    Sub(Id, QoS, Option<SharedGroup>),
+    ///The session and whether its client is connected
+    Session(Id, bool),
+}
+
+#[derive(Debug, Clone)]
+struct Register {
+    version: Version,
+    ///None is a tombstone
+    value: Option<Value>,
+    updated_at: TimestampMillis,
+}
+
+///The state of a node as known by this node, last-writer-wins registers written by that node only
+#[derive(Default)]
+struct Replica {
+    ///All the changes of the node up to this version are known
+    version: Version,
+    ///The tombstones up to this version have been purged
+    purged: Version,
+    registers: HashMap<Key, Register>,
+}
+
+impl Replica {
+    #[inline]
+    fn subscriptions(&self) -> impl Iterator<Item = (&TopicFilter, &Id, &QoS, &Option<SharedGroup>)> {
+        self.registers.iter().filter_map(|(key, r)| match (key, &r.value) {
+            (Key::Sub(topic_filter, _), Some(Value::Sub(id, qos, shared_group))) => {
+                Some((topic_filter, id, qos, shared_group))
+            }
+            _ => None,
+        })
+    }
+}
+
+///The changes of a node since the version of the receiver
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Delta {
+    node_id: NodeId,
+    version: Version,
+    ///The changes are all the live registers of the node and replace the known ones
+    full: bool,
+    changes: Vec<(Key, Version, Option<Value>)>,
+}
+
+///The version known of each node
+pub(crate) type Digest = Vec<(NodeId, Version)>;
+
+///A subscription change of the other nodes to apply to the router
+#[derive(Debug)]
+pub(crate) enum Change {
+    Add(TopicFilter, Id, QoS, Option<SharedGroup>),
+    Remove(TopicFilter, Id),
+}
+
+///The subscriptions and sessions of the nodes, replicated by gossip. Each node only writes its own
+///registers, the merge of two states keeps the latest version of each register, so the nodes
+///converge to the same state whatever the order the deltas are received in
+pub(crate) struct State {
+    node_id: NodeId,
+    replicas: RwLock<HashMap<NodeId, Replica>>,
+}
+
+///The incarnation of this start of the node, greater than the one persisted in the file by the
+///previous start, and than the start time, so that a node whose file is lost still starts with a
+///greater one than before, unless its clock stepped backwards. The new incarnation is written to the file
+pub(crate) fn next_incarnation(path: &str) -> TimestampMillis {
+    let previous = std::fs::read_to_string(path)
+        .ok()
+        .and_then(|s| s.trim().parse::<TimestampMillis>().ok())
+        .unwrap_or_default();
+    let incarnation = (previous + 1).max(chrono::Local::now().timestamp_millis());
+    let write = || -> std::io::Result<()> {
+        if let Some(dir) = Path::new(path).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, incarnation.to_string())
+    };
+    if let Err(e) = write() {
+        log::warn!("write the incarnation to {} error, {:?}", path, e);
+    }
+    incarnation
+}
+
+impl State {
+    #[inline]
+    pub(crate) fn new(node_id: NodeId, incarnation: TimestampMillis) -> Self {
+        let mut replicas = HashMap::default();
+        replicas
+            .insert(node_id, Replica { version: Version { incarnation, counter: 0 }, ..Default::default() });
+        Self { node_id, replicas: RwLock::new(replicas) }
+    }
+
+    ///Writes a register of this node, None removes it
+    #[inline]
+    pub(crate) fn set(&self, key: Key, value: Option<Value>) {
+        let mut replicas = self.replicas.write();
+        let local = replicas.entry(self.node_id).or_default();
+        if value.is_none() && !local.registers.get(&key).map(|r| r.value.is_some()).unwrap_or(false) {
+            return;
+        }
+        local.version.counter += 1;
+        let updated_at = chrono::Local::now().timestamp_millis();
+        local.registers.insert(key, Register { version: local.version, value, updated_at });
+    }
+
+    #[inline]
+    pub(crate) fn digest(&self) -> Digest {
+        self.replicas.read().iter().map(|(node_id, r)| (*node_id, r.version)).collect()
+    }
+
+    ///The changes the peer with the digest does not know. A node the peer knows an older
+    ///incarnation of, or whose tombstones it has not received before they were purged, is sent in full
+    pub(crate) fn deltas(&self, digest: &[(NodeId, Version)]) -> Vec<Delta> {
+        let known = digest.iter().copied().collect::<HashMap<_, _>>();
+        let mut deltas = Vec::new();
+        for (node_id, r) in self.replicas.read().iter() {
+            let peer_version = known.get(node_id).copied();
+            if peer_version.map(|v| v >= r.version).unwrap_or(false) {
+                continue;
+            }
+            let full = match peer_version {
+                Some(v) => v.incarnation != r.version.incarnation || v < r.purged,
+                None => true,
+            };
+            let changes = r
+                .registers
+                .iter()
+                .filter(|(_, reg)| {
+                    if full {
+                        reg.value.is_some()
+                    } else {
+                        peer_version.map(|v| reg.version > v).unwrap_or(true)
+                    }
+                })
+                .map(|(key, reg)| (key.clone(), reg.version, reg.value.clone()))
+                .collect();
+            deltas.push(Delta { node_id: *node_id, version: r.version, full, changes });
+        }
+        deltas
+    }
+
+    ///Merges the deltas of the nodes that are members of the cluster, returns the subscription
+    ///changes to apply to the router
+    pub(crate) fn merge<F>(&self, deltas: Vec<Delta>, is_member: F) -> Vec<Change>
+    where
+        F: Fn(NodeId) -> bool,
+    {
+        let now = chrono::Local::now().timestamp_millis();
+        let mut changes = Vec::new();
+        let mut replicas = self.replicas.write();
+        for delta in deltas {
+            if delta.node_id == self.node_id || !is_member(delta.node_id) {
+                continue;
+            }
+            let r = replicas.entry(delta.node_id).or_default();
+            if delta.version <= r.version {
+                continue;
+            }
+            if delta.full || delta.version.incarnation != r.version.incarnation {
+                for (topic_filter, id, _, _) in r.subscriptions() {
+                    changes.push(Change::Remove(topic_filter.clone(), id.clone()));
+                }
+                r.registers.clear();
+                //The tombstones of the node are unknown, the nodes behind are sent it in full
+                r.purged = delta.version;
+            }
+            for (key, version, value) in delta.changes {
+                if r.registers.get(&key).map(|reg| reg.version >= version).unwrap_or(false) {
+                    continue;
+                }
+                if let (Key::Sub(topic_filter, _), Some(Register { value: Some(Value::Sub(id, ..)), .. })) =
+                    (&key, r.registers.get(&key))
+                {
+                    changes.push(Change::Remove(topic_filter.clone(), id.clone()));
+                }
+                if let (Key::Sub(topic_filter, _), Some(Value::Sub(id, qos, shared_group))) = (&key, &value) {
+                    changes.push(Change::Add(topic_filter.clone(), id.clone(), *qos, shared_group.clone()));
+                }
+                r.registers.insert(key, Register { version, value, updated_at: now });
+            }
+            r.version = delta.version;
+        }
+        changes
+    }
+
+    ///Forgets the state of a node that is dead or was removed from the cluster, returns the removal
+    ///of its subscriptions to apply to the router. It is received again in full if it comes back
+    #[inline]
+    pub(crate) fn remove_node(&self, node_id: NodeId) -> Vec<Change> {
+        if node_id == self.node_id {
+            return Vec::new();
+        }
+        match self.replicas.write().remove(&node_id) {
+            Some(r) => r
+                .subscriptions()
+                .map(|(topic_filter, id, _, _)| Change::Remove(topic_filter.clone(), id.clone()))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    ///Removes the tombstones older than ttl
+    #[inline]
+    pub(crate) fn purge(&self, ttl: Duration) {
+        let expired = chrono::Local::now().timestamp_millis() - ttl.as_millis() as TimestampMillis;
+        for r in self.replicas.write().values_mut() {
+            let mut purged = r.purged;
+            r.registers.retain(|_, reg| {
+                let keep = reg.value.is_some() || reg.updated_at > expired;
+                if !keep {
+                    purged = purged.max(reg.version);
+                }
+                keep
+            });
+            r.purged = purged;
+        }
+    }
+
+    ///The session of the client on the node and whether its client is connected
+    #[inline]
+    pub(crate) fn session(&self, node_id: NodeId, client_id: &ClientId) -> Option<(Id, bool)> {
+        let replicas = self.replicas.read();
+        match replicas.get(&node_id)?.registers.get(&Key::Session(client_id.clone()))?.value.as_ref()? {
+            Value::Session(id, connected) => Some((id.clone(), *connected)),
+            Value::Sub(..) => None,
+        }
+    }
+
+    ///(node, subscriptions, sessions, tombstones), sorted by node
+    #[inline]
+    pub(crate) fn counts(&self) -> Vec<(NodeId, usize, usize, usize)> {
+        let mut counts = self
+            .replicas
+            .read()
+            .iter()
+            .map(|(node_id, r)| {
+                let (mut subs, mut sessions, mut tombstones) = (0, 0, 0);
+                for reg in r.registers.values() {
+                    match reg.value {
+                        Some(Value::Sub(..)) => subs += 1,
+                        Some(Value::Session(..)) => sessions += 1,
+                        None => tombstones += 1,
+                    }
+                }
+                (*node_id, subs, sessions, tombstones)
+            })
+            .collect::<Vec<_>>();
+        counts.sort_by_key(|c| c.0);
+        counts
+    }
+
+    #[inline]
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let counts = self.counts();
+        let replicas = self.replicas.read();
+        let counts = counts
+            .into_iter()
+            .map(|(node_id, subs, sessions, tombstones)| {
+                let version = replicas.get(&node_id).map(|r| r.version).unwrap_or_default();
+                serde_json::json!({
+                    "node_id": node_id,
+                    "incarnation": version.incarnation,
+                    "version": version.counter,
+                    "subscriptions": subs,
+                    "sessions": sessions,
+                    "tombstones": tombstones,
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!(counts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(node_id: NodeId, client_id: &str) -> Id {
+        Id::new(node_id, None, None, ClientId::from(client_id), None)
+    }
+
+    fn sub(state: &State, node_id: NodeId, topic_filter: &str, client_id: &str) {
+        let key = Key::Sub(TopicFilter::from(topic_filter), ClientId::from(client_id));
+        state.set(key, Some(Value::Sub(id(node_id, client_id), QoS::AtLeastOnce, None)));
+    }
+
+    fn unsub(state: &State, topic_filter: &str, client_id: &str) {
+        state.set(Key::Sub(TopicFilter::from(topic_filter), ClientId::from(client_id)), None);
+    }
+
+    fn sync(from: &State, to: &State) -> Vec<Change> {
+        to.merge(from.deltas(&to.digest()), |_| true)
+    }
+
+    fn subs(state: &State, node_id: NodeId) -> Vec<String> {
+        let replicas = state.replicas.read();
+        let mut subs = replicas
+            .get(&node_id)
+            .map(|r| r.subscriptions().map(|(tf, id, _, _)| format!("{}:{}", tf, id.client_id)).collect())
+            .unwrap_or_else(Vec::new);
+        subs.sort();
+        subs
+    }
+
+    #[test]
+    fn deltas() {
+        let (a, b) = (State::new(1, 1), State::new(2, 1));
+        sub(&a, 1, "t/1", "c1");
+        sub(&a, 1, "t/2", "c2");
+        //Unknown to b, sent in full
+        let deltas = a.deltas(&b.digest());
+        let delta = deltas.iter().find(|d| d.node_id == 1).unwrap();
+        assert!(delta.full);
+        assert_eq!(delta.changes.len(), 2);
+        sync(&a, &b);
+        //Known by b, nothing is sent
+        assert!(a.deltas(&b.digest()).iter().all(|d| d.node_id != 1));
+        //Only the changes since the version of b, with the tombstone
+        unsub(&a, "t/1", "c1");
+        sub(&a, 1, "t/3", "c3");
+        let deltas = a.deltas(&b.digest());
+        let delta = deltas.iter().find(|d| d.node_id == 1).unwrap();
+        assert!(!delta.full);
+        assert_eq!(delta.changes.len(), 2);
+        assert!(delta.changes.iter().any(|(_, _, v)| v.is_none()));
+        //The tombstones purged since the version of b, sent in full
+        std::thread::sleep(Duration::from_millis(2));
+        a.purge(Duration::ZERO);
+        let deltas = a.deltas(&b.digest());
+        let delta = deltas.iter().find(|d| d.node_id == 1).unwrap();
+        assert!(delta.full);
+        assert_eq!(delta.changes.len(), 2);
+    }
+
+    #[test]
+    fn merge() {
+        let (a, b, c) = (State::new(1, 1), State::new(2, 1), State::new(3, 1));
+        sub(&a, 1, "t/1", "c1");
+        sub(&a, 1, "t/2", "c2");
+        let changes = sync(&a, &b);
+        assert_eq!(changes.iter().filter(|c| matches!(c, Change::Add(..))).count(), 2);
+        assert_eq!(subs(&b, 1), vec!["t/1:c1", "t/2:c2"]);
+
+        //The same changes received again, or from another node, change nothing
+        assert!(sync(&a, &b).is_empty());
+        sync(&b, &c);
+        assert!(sync(&a, &c).is_empty());
+        assert_eq!(subs(&c, 1), subs(&b, 1));
+
+        //A removal reaches c through b
+        let old = a.deltas(&[]);
+        unsub(&a, "t/1", "c1");
+        let changes = sync(&a, &b);
+        assert!(matches!(&changes[..], [Change::Remove(tf, _)] if &**tf == "t/1"));
+        sync(&b, &c);
+        assert_eq!(subs(&c, 1), vec!["t/2:c2"]);
+        //An older delta received late does not revive it
+        assert!(c.merge(old, |_| true).is_empty());
+        assert_eq!(subs(&c, 1), vec!["t/2:c2"]);
+
+        //A new incarnation of the node replaces its state
+        let a2 = State::new(1, 2);
+        sub(&a2, 1, "t/3", "c3");
+        let changes = sync(&a2, &b);
+        assert!(changes.iter().any(|c| matches!(c, Change::Remove(tf, _) if &**tf == "t/2")));
+        assert!(changes.iter().any(|c| matches!(c, Change::Add(tf, ..) if &**tf == "t/3")));
+        assert_eq!(subs(&b, 1), vec!["t/3:c3"]);
+
+        //The deltas of the nodes that are not members, and of the node itself, are ignored
+        assert!(b.merge(c.deltas(&[]), |node_id| node_id != 1).is_empty());
+        assert_eq!(subs(&b, 1), vec!["t/3:c3"]);
+        let removed = b.remove_node(1);
+        assert_eq!(removed.len(), 1);
+        assert!(subs(&b, 1).is_empty());
+        assert!(b.remove_node(2).is_empty());
+    }
+
+    #[test]
+    fn incarnation() {
+        let path = std::env::temp_dir().join(format!("rmqtt-crdt-incarnation-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, i64::MAX.saturating_sub(1).to_string()).unwrap();
+        assert_eq!(next_incarnation(path), i64::MAX);
+        std::fs::write(path, "1").unwrap();
+        let i1 = next_incarnation(path);
+        assert!(next_incarnation(path) > i1);
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,180 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use rmqtt::rand::seq::SliceRandom;
+use rmqtt::{
+    broker::types::NodeId,
+    grpc::{client::NodeGrpcClient, codec, GrpcClients, Message, MessageReply, MessageType},
+    DashMap, MqttError, Result,
+};
+use rmqtt::{log, once_cell::sync::OnceCell, rand, tokio};
+
+use super::crdt::{Delta, Digest};
+use super::router::ClusterRouter;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum GossipMessage {
+    ///The versions known by the sender, the receiver replies with the changes the sender is missing
+    Digest(Digest),
+    ///The changes the receiver is missing
+    Deltas(Vec<Delta>),
+}
+
+impl GossipMessage {
+    #[inline]
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        codec::encode(self)
+    }
+    #[inline]
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        codec::decode(data)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum GossipMessageReply {
+    ///The changes the sender of the digest is missing and the versions known by the receiver
+    Deltas(Vec<Delta>, Digest),
+}
+
+impl GossipMessageReply {
+    #[inline]
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        codec::encode(self)
+    }
+    #[inline]
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        codec::decode(data)
+    }
+}
+
+///Anti-entropy of the replicated state: each round a node exchanges its digest with a few random
+///nodes, pulls the changes it is missing and pushes the ones they are missing. The nodes of both
+///sides of a partition keep serving with the state they know, and converge once it heals
+pub(crate) struct Gossip {
+    router: &'static ClusterRouter,
+    grpc_clients: GrpcClients,
+    message_type: MessageType,
+    interval: Duration,
+    fanout: usize,
+    node_timeout: Duration,
+    tombstone_ttl: Duration,
+    ///When each node was last reached by a gossip round
+    last_seen: DashMap<NodeId, Instant>,
+    pub rounds: AtomicUsize,
+    pub failures: AtomicUsize,
+}
+
+impl Gossip {
+    #[inline]
+    pub(crate) fn get_or_init(
+        router: &'static ClusterRouter,
+        grpc_clients: GrpcClients,
+        message_type: MessageType,
+        interval: Duration,
+        fanout: usize,
+        node_timeout: Duration,
+        tombstone_ttl: Duration,
+    ) -> &'static Self {
+        static INSTANCE: OnceCell<Gossip> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self {
+            router,
+            last_seen: grpc_clients.keys().map(|node_id| (*node_id, Instant::now())).collect(),
+            grpc_clients,
+            message_type,
+            interval,
+            fanout,
+            node_timeout,
+            tombstone_ttl,
+            rounds: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+        })
+    }
+
+    ///A node of the configuration reached within node_timeout, the state of the other nodes is not
+    ///merged, even when gossiped by a third node
+    #[inline]
+    pub(crate) fn is_member(&self, node_id: NodeId) -> bool {
+        self.grpc_clients.contains_key(&node_id) && !self.is_dead(node_id)
+    }
+
+    #[inline]
+    fn is_dead(&self, node_id: NodeId) -> bool {
+        self.last_seen.get(&node_id).map(|t| t.elapsed() > self.node_timeout).unwrap_or(true)
+    }
+
+    pub(crate) fn start(&'static self) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(self.interval).await;
+                self.round().await;
+            }
+        });
+    }
+
+    async fn round(&self) {
+        self.rounds.fetch_add(1, Ordering::SeqCst);
+        self.router.state.purge(self.tombstone_ttl);
+        let mut targets = self.grpc_clients.iter().collect::<Vec<_>>();
+        targets.shuffle(&mut rand::thread_rng());
+        //Besides the random nodes, those not reached for half of node_timeout are probed, so that
+        //a live node is not taken for dead however many nodes there are
+        let fanout = self.fanout.max(1);
+        let stale = self.node_timeout / 2;
+        let targets = targets.into_iter().enumerate().filter(|(i, (node_id, _))| {
+            *i < fanout || self.last_seen.get(node_id).map(|t| t.elapsed() > stale).unwrap_or(true)
+        });
+        for (_, (node_id, (_, client))) in targets {
+            match self.exchange(client).await {
+                Ok(()) => {
+                    self.last_seen.insert(*node_id, Instant::now());
+                }
+                Err(e) => {
+                    self.failures.fetch_add(1, Ordering::SeqCst);
+                    log::debug!("gossip with node {} error, {:?}", node_id, e);
+                }
+            }
+        }
+        for node_id in self.grpc_clients.keys() {
+            if self.is_dead(*node_id) {
+                self.router.evict(*node_id).await;
+            }
+        }
+    }
+
+    #[inline]
+    async fn exchange(&self, client: &NodeGrpcClient) -> Result<()> {
+        let data = GossipMessage::Digest(self.router.state.digest()).encode()?;
+        let (deltas, digest) = match client.send_message(self.message_type, Message::Data(data)).await? {
+            MessageReply::Data(data) => match GossipMessageReply::decode(&data)? {
+                GossipMessageReply::Deltas(deltas, digest) => (deltas, digest),
+            },
+            MessageReply::Error(e) => return Err(MqttError::from(e)),
+            reply => return Err(MqttError::from(format!("unexpected reply, {:?}", reply))),
+        };
+        self.router.merge(deltas, |node_id| self.is_member(node_id)).await;
+
+        let deltas = self.router.state.deltas(&digest);
+        if !deltas.is_empty() {
+            let data = GossipMessage::Deltas(deltas).encode()?;
+            client.send_message(self.message_type, Message::Data(data)).await?;
+        }
+        Ok(())
+    }
+
+    ///Handles the gossip message of a peer
+    #[inline]
+    pub(crate) async fn received(&self, msg: GossipMessage) -> Result<MessageReply> {
+        match msg {
+            GossipMessage::Digest(digest) => {
+                let reply =
+                    GossipMessageReply::Deltas(self.router.state.deltas(&digest), self.router.state.digest());
+                Ok(MessageReply::Data(reply.encode()?))
+            }
+            GossipMessage::Deltas(deltas) => {
+                self.router.merge(deltas, |node_id| self.is_member(node_id)).await;
+                Ok(MessageReply::Success)
+            }
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use rmqtt::broker::{Router, Shared};
+use rmqtt::{async_trait::async_trait, log};
+use rmqtt::{
+    broker::hook::{Handler, HookResult, Parameter, ReturnType},
+    grpc::{Message, MessageReply},
+    Id, Runtime,
+};
+
+use super::crdt::{Key, Value};
+use super::gossip::{Gossip, GossipMessage};
+use super::{hook_message_dropped, retainer::ClusterRetainer, router::ClusterRouter, shared::ClusterShared};
+
+pub(crate) struct HookHandler {
+    shared: &'static ClusterShared,
+    router: &'static ClusterRouter,
+    retainer: &'static ClusterRetainer,
+    gossip: &'static Gossip,
+}
+
+impl HookHandler {
+    pub(crate) fn new(
+        shared: &'static ClusterShared,
+        router: &'static ClusterRouter,
+        retainer: &'static ClusterRetainer,
+        gossip: &'static Gossip,
+    ) -> Self {
+        Self { shared, router, retainer, gossip }
+    }
+}
+
+#[async_trait]
+impl Handler for HookHandler {
+    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+        match param {
+            Parameter::ClientConnected(_s, c) => {
+                let key = Key::Session(c.id.client_id.clone());
+                self.router.state.set(key, Some(Value::Session(c.id.clone(), true)));
+            }
+
+            Parameter::ClientDisconnected(_s, c, _r) => {
+                let key = Key::Session(c.id.client_id.clone());
+                self.router.state.set(key, Some(Value::Session(c.id.clone(), false)));
+            }
+
+            Parameter::SessionTerminated(_s, c, _r) => {
+                //The session may have been replaced by a new one of the client
+                let node_id = Runtime::instance().node.id();
+                if let Some((id, _)) = self.router.state.session(node_id, &c.id.client_id) {
+                    if id == c.id {
+                        self.router.state.set(Key::Session(c.id.client_id.clone()), None);
+                    }
+                }
+            }
+
+            Parameter::GrpcMessageReceived(typ, msg) => {
+                log::debug!("GrpcMessageReceived, type: {}, msg: {:?}", typ, msg);
+                if self.shared.message_type != *typ {
+                    return (true, acc);
+                }
+                match msg {
+                    Message::ForwardsTo(from, publish, sub_rels) => {
+                        if self.shared.forward_dedup.is_duplicate(publish) {
+                            log::debug!(
+                                "{:?} duplicate forward is suppressed, forward_id: {:?}",
+                                from,
+                                publish.forward_id
+                            );
+                            return (false, acc);
+                        }
+                        if let Err(droppeds) =
+                            self.shared.inner().forwards_to(from.clone(), publish, sub_rels.clone()).await
+                        {
+                            hook_message_dropped(droppeds).await;
+                        }
+                        return (false, acc);
+                    }
+                    Message::Kick(id, clear_subscriptions, is_admin) => {
+                        let entry = self.shared.inner().entry(id.clone());
+                        log::debug!("{:?}", id);
+                        let new_acc = match entry.try_lock().await {
+                            Ok(mut entry) => match entry.kick(*clear_subscriptions, *is_admin).await {
+                                Ok(o) => {
+                                    log::debug!("{:?} offline info: {:?}", id, o);
+                                    HookResult::GrpcMessageReply(Ok(MessageReply::Kick(o)))
+                                }
+                                Err(e) => HookResult::GrpcMessageReply(Err(e)),
+                            },
+                            Err(e) => {
+                                log::warn!("{:?}, try_lock error, {:?}", id, e);
+                                HookResult::GrpcMessageReply(Err(e))
+                            }
+                        };
+                        return (false, Some(new_acc));
+                    }
+                    Message::NumberOfClients => {
+                        let new_acc = HookResult::GrpcMessageReply(Ok(MessageReply::NumberOfClients(
+                            //self.shared.inner().clients().await,
+                            Runtime::instance().stats.connections.count() as usize,
+                        )));
+                        return (false, Some(new_acc));
+                    }
+                    Message::NumberOfSessions => {
+                        let new_acc = HookResult::GrpcMessageReply(Ok(MessageReply::NumberOfSessions(
+                            //self.shared.inner().sessions().await,
+                            Runtime::instance().stats.sessions.count() as usize,
+                        )));
+                        return (false, Some(new_acc));
+                    }
+                    Message::GetRetains(topic_filter) => {
+                        let new_acc = match self.retainer.inner().get(topic_filter).await {
+                            Ok(retains) => {
+                                HookResult::GrpcMessageReply(Ok(MessageReply::GetRetains(retains)))
+                            }
+                            Err(e) => HookResult::GrpcMessageReply(Err(e)),
+                        };
+                        return (false, Some(new_acc));
+                    }
+                    Message::Online(clientid) => {
+                        let new_acc = HookResult::GrpcMessageReply(Ok(MessageReply::Online(
+                            Runtime::instance()
+                                .extends
+                                .router()
+                                .await
+                                .is_online(Runtime::instance().node.id(), clientid)
+                                .await,
+                        )));
+                        return (false, Some(new_acc));
+                    }
+                    Message::SubscriptionsSearch(q) => {
+                        let new_acc = HookResult::GrpcMessageReply(Ok(MessageReply::SubscriptionsSearch(
+                            self.shared.inner()._query_subscriptions(q).await,
+                        )));
+                        return (false, Some(new_acc));
+                    }
+                    Message::SubscriptionsGet(clientid) => {
+                        let id = Id::from(Runtime::instance().node.id(), clientid.clone());
+                        let entry = self.shared.inner().entry(id);
+                        let new_acc = HookResult::GrpcMessageReply(Ok(MessageReply::SubscriptionsGet(
+                            entry.subscriptions().await,
+                        )));
+                        return (false, Some(new_acc));
+                    }
+                    Message::Data(data) => {
+                        let reply = match GossipMessage::decode(data) {
+                            Ok(msg) => self.gossip.received(msg).await,
+                            Err(e) => {
+                                log::error!("GossipMessage::decode, error: {:?}", e);
+                                Ok(MessageReply::Error(e.to_string()))
+                            }
+                        };
+                        return (false, Some(HookResult::GrpcMessageReply(reply)));
+                    }
+                    Message::SessionStatus(clientid) => {
+                        let status = self.shared.inner().session_status(clientid).await;
+                        let new_acc = HookResult::GrpcMessageReply(Ok(MessageReply::SessionStatus(status)));
+                        return (false, Some(new_acc));
+                    }
+
+                    _ => {
+                        log::error!("unimplemented, {:?}", param)
+                    }
+                }
+            }
+            _ => {
+                log::error!("unimplemented, {:?}", param)
+            }
+        }
+        (true, acc)
+    }
+}
@@ -0,0 +1,275 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use config::PluginConfig;
+use gossip::Gossip;
+use handler::HookHandler;
+use retainer::ClusterRetainer;
+use rmqtt::{ahash, async_trait::async_trait, log, serde_json, RwLock};
+use rmqtt::{
+    broker::{
+        dedup::ForwardDedup,
+        error::MqttError,
+        hook::{Register, Type},
+        session::SessionOfflineInfo,
+        types::{From, Publish, Reason, To},
+    },
+    grpc::{codec::Codec, registry::MessageTypes, GrpcClients, Message, MessageReply, MessageType},
+    plugin::{DynPlugin, DynPluginResult, Metric, Plugin},
+    settings::schema::ConfigSchema,
+    Result, Runtime,
+};
+use router::ClusterRouter;
+use shared::ClusterShared;
+
+mod config;
+mod crdt;
+mod gossip;
+mod handler;
+mod retainer;
+mod router;
+mod shared;
+
+type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
+
+#[inline]
+pub async fn register(
+    runtime: &'static Runtime,
+    name: &'static str,
+    descr: &'static str,
+    default_startup: bool,
+    immutable: bool,
+) -> Result<()> {
+    runtime
+        .plugins
+        .register(name, default_startup, immutable, move || -> DynPluginResult {
+            Box::pin(async move {
+                ClusterPlugin::new(runtime, name, descr).await.map(|p| -> DynPlugin { Box::new(p) })
+            })
+        })
+        .await?;
+    Ok(())
+}
+
+struct ClusterPlugin {
+    runtime: &'static Runtime,
+    name: String,
+    descr: String,
+    register: Box<dyn Register>,
+    cfg: Arc<RwLock<PluginConfig>>,
+    grpc_clients: GrpcClients,
+    shared: &'static ClusterShared,
+    retainer: &'static ClusterRetainer,
+    router: &'static ClusterRouter,
+    gossip: &'static Gossip,
+}
+
+impl ClusterPlugin {
+    #[inline]
+    async fn new<S: Into<String>>(runtime: &'static Runtime, name: S, descr: S) -> Result<Self> {
+        let name = name.into();
+        let mut cfg = runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&name, &PluginConfig::schema())?;
+        cfg.merge(&runtime.settings.opts);
+        log::debug!("{} ClusterPlugin cfg: {:?}", name, cfg);
+
+        let register = runtime.extends.hook_mgr().await.register_for(&name);
+        let mut grpc_clients = HashMap::default();
+        for node_addr in &cfg.node_grpc_addrs {
+            if node_addr.id != runtime.node.id() {
+                grpc_clients.insert(
+                    node_addr.id,
+                    (
+                        node_addr.addr.clone(),
+                        runtime.node.new_peer_grpc_client(node_addr.id, &node_addr.addr).await?,
+                    ),
+                );
+            }
+        }
+        let grpc_clients = Arc::new(grpc_clients);
+        let message_type = cfg.message_type;
+        let router = ClusterRouter::get_or_init(crdt::next_incarnation(&cfg.incarnation_file));
+        let shared = ClusterShared::get_or_init(
+            grpc_clients.clone(),
+            message_type,
+            ForwardDedup::new(cfg.forward_dedup_window, cfg.forward_dedup_max),
+        );
+        let retainer = ClusterRetainer::get_or_init(grpc_clients.clone(), message_type);
+        let gossip = Gossip::get_or_init(
+            router,
+            grpc_clients.clone(),
+            message_type,
+            cfg.gossip_interval,
+            cfg.gossip_fanout,
+            cfg.node_timeout,
+            cfg.tombstone_ttl,
+        );
+        Ok(Self {
+            runtime,
+            name,
+            descr: descr.into(),
+            register,
+            cfg: Arc::new(RwLock::new(cfg)),
+            grpc_clients,
+            shared,
+            retainer,
+            router,
+            gossip,
+        })
+    }
+
+    #[inline]
+    async fn hook_register(&self, typ: Type) {
+        self.register
+            .add(typ, Box::new(HookHandler::new(self.shared, self.router, self.retainer, self.gossip)))
+            .await;
+    }
+}
+
+#[async_trait]
+impl Plugin for ClusterPlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name);
+        MessageTypes::instance().register(&self.name, self.cfg.read().message_type)?;
+        Codec::set_current(self.cfg.read().message_codec);
+        self.hook_register(Type::ClientConnected).await;
+        self.hook_register(Type::ClientDisconnected).await;
+        self.hook_register(Type::SessionTerminated).await;
+        self.hook_register(Type::GrpcMessageReceived).await;
+        Ok(())
+    }
+
+    #[inline]
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        self.cfg.read().to_json()
+    }
+
+    #[inline]
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(PluginConfig::schema())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name);
+        self.router.import_local();
+        self.register.start().await;
+        *self.runtime.extends.shared_mut().await = Box::new(self.shared);
+        *self.runtime.extends.router_mut().await = Box::new(self.router);
+        self.gossip.start();
+        Ok(())
+    }
+
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::warn!("{} stop, once the cluster is started, it cannot be stopped", self.name);
+        Ok(false)
+    }
+
+    #[inline]
+    fn version(&self) -> &str {
+        "0.1.0"
+    }
+
+    #[inline]
+    fn descr(&self) -> &str {
+        &self.descr
+    }
+
+    #[inline]
+    fn reloadable(&self) -> bool {
+        false
+    }
+
+    #[inline]
+    async fn attrs(&self) -> serde_json::Value {
+        serde_json::json!({
+            "replicas": self.router.state.to_json(),
+        })
+    }
+
+    #[inline]
+    async fn metrics(&self) -> Vec<Metric> {
+        let mut metrics = Vec::new();
+        for (node_id, subs, sessions, tombstones) in self.router.state.counts() {
+            metrics.push(
+                Metric::gauge("replicated_subscriptions", subs as f64)
+                    .label("node", node_id.to_string())
+                    .descr("Subscriptions of the node in the replicated state"),
+            );
+            metrics.push(
+                Metric::gauge("replicated_sessions", sessions as f64)
+                    .label("node", node_id.to_string())
+                    .descr("Sessions of the node in the replicated state"),
+            );
+            metrics.push(
+                Metric::gauge("tombstones", tombstones as f64)
+                    .label("node", node_id.to_string())
+                    .descr("Removed registers of the node kept until tombstone_ttl"),
+            );
+        }
+        metrics.push(
+            Metric::counter("gossip_rounds", self.gossip.rounds.load(Ordering::SeqCst) as f64)
+                .descr("Gossip rounds of this node"),
+        );
+        metrics.push(
+            Metric::counter("gossip_failures", self.gossip.failures.load(Ordering::SeqCst) as f64)
+                .descr("Gossip exchanges that failed, such as with an unreachable node"),
+        );
+        for (id, (_, c)) in self.grpc_clients.iter() {
+            metrics.push(
+                Metric::gauge("grpc_client_channel_tasks", c.channel_tasks() as f64)
+                    .label("peer", id.to_string())
+                    .descr("Messages queued for the peer node"),
+            );
+            metrics.push(
+                Metric::gauge("grpc_client_active_tasks", c.active_tasks() as f64)
+                    .label("peer", id.to_string())
+                    .descr("Messages being sent to the peer node"),
+            );
+        }
+        metrics
+    }
+}
+
+#[inline]
+pub(crate) async fn kick(
+    grpc_clients: GrpcClients,
+    msg_type: MessageType,
+    msg: Message,
+) -> Result<SessionOfflineInfo> {
+    let reply = rmqtt::grpc::MessageBroadcaster::new(grpc_clients, msg_type, msg)
+        .select_ok(|reply: MessageReply| -> Result<MessageReply> {
+            log::debug!("reply: {:?}", reply);
+            if let MessageReply::Kick(Some(o)) = reply {
+                Ok(MessageReply::Kick(Some(o)))
+            } else {
+                Err(MqttError::None)
+            }
+        })
+        .await?;
+    if let MessageReply::Kick(Some(kicked)) = reply {
+        Ok(kicked)
+    } else {
+        Err(MqttError::None)
+    }
+}
+
+pub(crate) async fn hook_message_dropped(droppeds: Vec<(To, From, Publish, Reason)>) {
+    for (to, from, publish, reason) in droppeds {
+        //hook, message_dropped
+        Runtime::instance().extends.hook_mgr().await.message_dropped(Some(to), from, publish, reason).await;
+    }
+}
@@ -0,0 +1,197 @@
+use once_cell::sync::OnceCell;
+
+use rmqtt::stats::Counter;
+use rmqtt::{async_trait::async_trait, log, once_cell, serde_json, tokio::sync::Mutex};
+use rmqtt::{
+    broker::{
+        default::{DefaultRouter, DefaultShared},
+        types::{Id, NodeId, Publish, QoS, Route, SharedGroup, TimestampMillis, TopicName},
+        Router, Shared, SubRelationsMap,
+    },
+    ClientId, HashMap, Result, Runtime,
+};
+
+use super::crdt::{Change, Delta, Key, State, Value};
+
+///The subscriptions of all the nodes are kept in the local router, those of this node are written
+///to the replicated state and those of the other nodes are applied from it
+pub(crate) struct ClusterRouter {
+    inner: &'static DefaultRouter,
+    pub state: State,
+    //the merges are applied to the router one at a time, in the order of the versions
+    merge_lock: Mutex<()>,
+}
+
+impl ClusterRouter {
+    #[inline]
+    pub(crate) fn get_or_init(incarnation: TimestampMillis) -> &'static Self {
+        static INSTANCE: OnceCell<ClusterRouter> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self {
+            inner: DefaultRouter::instance(),
+            state: State::new(Runtime::instance().node.id(), incarnation),
+            merge_lock: Mutex::new(()),
+        })
+    }
+
+    #[inline]
+    pub(crate) fn _inner(&self) -> &'static DefaultRouter {
+        self.inner
+    }
+
+    ///Writes the sessions and subscriptions of this node from before the plugin is started, when it
+    ///is loaded at runtime into a broker running standalone
+    pub(crate) fn import_local(&self) {
+        let node_id = Runtime::instance().node.id();
+        for entry in DefaultShared::instance().iter() {
+            if let Some(c) = entry.client() {
+                let key = Key::Session(c.id.client_id.clone());
+                self.state.set(key, Some(Value::Session(c.id.clone(), c.is_connected())));
+            }
+        }
+        for entry in self.inner.relations.iter() {
+            for (client_id, (id, qos, shared_group)) in entry.value().iter() {
+                if id.node_id == node_id {
+                    let key = Key::Sub(entry.key().clone(), client_id.clone());
+                    self.state.set(key, Some(Value::Sub(id.clone(), *qos, shared_group.clone())));
+                }
+            }
+        }
+    }
+
+    ///Merges the deltas of a peer and applies the subscription changes of the other nodes
+    pub(crate) async fn merge<F>(&self, deltas: Vec<Delta>, is_member: F)
+    where
+        F: Fn(NodeId) -> bool,
+    {
+        let _guard = self.merge_lock.lock().await;
+        self.apply(self.state.merge(deltas, is_member)).await;
+    }
+
+    ///Removes the subscriptions and sessions of a node that is dead or was removed from the cluster,
+    ///so that the publishes are no longer forwarded to it and its clients are no longer online
+    pub(crate) async fn evict(&self, node_id: NodeId) {
+        let _guard = self.merge_lock.lock().await;
+        let changes = self.state.remove_node(node_id);
+        if !changes.is_empty() {
+            log::warn!("node {} is unreachable, its {} subscriptions are removed", node_id, changes.len());
+        }
+        self.apply(changes).await;
+    }
+
+    async fn apply(&self, changes: Vec<Change>) {
+        for change in changes {
+            log::debug!("merge, {:?}", change);
+            let res = match change {
+                Change::Add(topic_filter, id, qos, shared_group) => {
+                    //During a partition the client may have reconnected elsewhere, the latest
+                    //session keeps the subscription
+                    let newer = self
+                        .inner
+                        .relations
+                        .get(&topic_filter)
+                        .and_then(|rels| rels.get(&id.client_id).map(|(s_id, _, _)| s_id.create_time))
+                        .map(|create_time| create_time > id.create_time)
+                        .unwrap_or(false);
+                    if newer {
+                        continue;
+                    }
+                    self.inner.add(&topic_filter, id, qos, shared_group).await
+                }
+                Change::Remove(topic_filter, id) => self.inner.remove(&topic_filter, id).await.map(|_| ()),
+            };
+            if let Err(e) = res {
+                log::warn!("merge, apply subscription change error, {:?}", e);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Router for &'static ClusterRouter {
+    #[inline]
+    async fn add(
+        &self,
+        topic_filter: &str,
+        id: Id,
+        qos: QoS,
+        shared_group: Option<SharedGroup>,
+    ) -> Result<()> {
+        self.inner.add(topic_filter, id.clone(), qos, shared_group.clone()).await?;
+        let key = Key::Sub(topic_filter.into(), id.client_id.clone());
+        self.state.set(key, Some(Value::Sub(id, qos, shared_group)));
+        Ok(())
+    }
+
+    #[inline]
+    async fn remove(&self, topic_filter: &str, id: Id) -> Result<bool> {
+        let removed = self.inner.remove(topic_filter, id.clone()).await?;
+        if removed {
+            self.state.set(Key::Sub(topic_filter.into(), id.client_id), None);
+        }
+        Ok(removed)
+    }
+
+    #[inline]
+    async fn matches(&self, topic: &TopicName) -> Result<SubRelationsMap> {
+        self.inner.matches(topic).await
+    }
+
+    #[inline]
+    async fn matches_publish(&self, publish: &Publish) -> Result<SubRelationsMap> {
+        self.inner.matches_publish(publish).await
+    }
+
+    ///Check online or offline, the clients of the other nodes as last gossiped
+    async fn is_online(&self, node_id: NodeId, client_id: &str) -> bool {
+        if node_id == Runtime::instance().node.id() {
+            self.inner.is_online(node_id, client_id).await
+        } else {
+            self.state.session(node_id, &ClientId::from(client_id)).map(|(_, c)| c).unwrap_or(false)
+        }
+    }
+
+    #[inline]
+    async fn gets(&self, limit: usize) -> Vec<Route> {
+        self.inner.gets(limit).await
+    }
+
+    #[inline]
+    async fn get(&self, topic: &str) -> Result<Vec<Route>> {
+        self.inner.get(topic).await
+    }
+
+    #[inline]
+    async fn topics_tree(&self) -> usize {
+        self.inner.topics_tree().await
+    }
+
+    #[inline]
+    fn topics(&self) -> Counter {
+        self.inner.topics()
+    }
+
+    #[inline]
+    fn routes(&self) -> Counter {
+        self.inner.routes()
+    }
+
+    #[inline]
+    fn merge_topics(&self, topics_map: &HashMap<NodeId, Counter>) -> Counter {
+        self.inner.merge_topics(topics_map)
+    }
+
+    #[inline]
+    fn merge_routes(&self, routes_map: &HashMap<NodeId, Counter>) -> Counter {
+        self.inner.merge_routes(routes_map)
+    }
+
+    #[inline]
+    async fn list_topics(&self, top: usize) -> Vec<String> {
+        self.inner.list_topics(top).await
+    }
+
+    #[inline]
+    async fn list_relations(&self, top: usize) -> Vec<serde_json::Value> {
+        self.inner.list_relations(top).await
+    }
+}
@@ -0,0 +1,401 @@
+use std::convert::From as _f;
+
+use once_cell::sync::OnceCell;
+
+use rmqtt::grpc::MessageSender;
+use rmqtt::{async_trait::async_trait, futures, log, once_cell, tokio};
+use rmqtt::{
+    broker::{
+        dedup::ForwardDedup,
+        default::DefaultShared,
+        session::{ClientInfo, Session, SessionOfflineInfo},
+        types::{
+            ClientId, ForwardId, From, Id, IsAdmin, Publish, Reason, SessionStatus, SubsSearchParams,
+            SubsSearchResult, Subscribe, SubscribeReturn, To, Tx, Unsubscribe,
+        },
+        Entry, Shared, SubRelations, SubRelationsMap,
+    },
+    grpc::{GrpcClients, Message, MessageBroadcaster, MessageReply, MessageType},
+    settings::DuplicateClientId,
+    MqttError, Result, Runtime,
+};
+
+use super::kick;
+
+pub struct ClusterLockEntry {
+    inner: Box<dyn Entry>,
+    cluster_shared: &'static ClusterShared,
+}
+
+impl ClusterLockEntry {
+    #[inline]
+    pub fn new(inner: Box<dyn Entry>, cluster_shared: &'static ClusterShared) -> Self {
+        Self { inner, cluster_shared }
+    }
+}
+
+#[async_trait]
+impl Entry for ClusterLockEntry {
+    #[inline]
+    async fn try_lock(&self) -> Result<Box<dyn Entry>> {
+        Ok(Box::new(ClusterLockEntry::new(self.inner.try_lock().await?, self.cluster_shared)))
+    }
+
+    ///The sessions of the client_id and of its suffixes are looked up on all nodes
+    #[inline]
+    async fn try_lock_connect(&self, policy: DuplicateClientId) -> Result<Box<dyn Entry>> {
+        Ok(Box::new(ClusterLockEntry::new(self.inner.try_lock_connect(policy).await?, self.cluster_shared)))
+    }
+
+    #[inline]
+    fn id(&self) -> Id {
+        self.inner.id()
+    }
+
+    #[inline]
+    fn id_same(&self) -> Option<bool> {
+        self.inner.id_same()
+    }
+
+    #[inline]
+    fn exist(&self) -> bool {
+        self.inner.exist()
+    }
+
+    #[inline]
+    async fn set(&mut self, session: Session, tx: Tx, conn: ClientInfo) -> Result<()> {
+        self.inner.set(session, tx, conn).await
+    }
+
+    #[inline]
+    async fn remove(&mut self) -> Result<Option<(Session, Tx, ClientInfo)>> {
+        self.inner.remove().await
+    }
+
+    #[inline]
+    async fn remove_with(&mut self, id: &Id) -> Result<Option<(Session, Tx, ClientInfo)>> {
+        self.inner.remove_with(id).await
+    }
+
+    #[inline]
+    async fn kick(
+        &mut self,
+        clear_subscriptions: bool,
+        is_admin: IsAdmin,
+    ) -> Result<Option<SessionOfflineInfo>> {
+        log::debug!("{:?} ClusterLockEntry kick 1 ...", self.client().map(|c| c.id.clone()));
+        if let Some(kicked) = self.inner.kick(clear_subscriptions, is_admin).await? {
+            log::debug!("{:?} broadcast kick reply kicked: {:?}", self.id(), kicked);
+            return Ok(Some(kicked));
+        }
+
+        match kick(
+            self.cluster_shared.grpc_clients.clone(),
+            self.cluster_shared.message_type,
+            Message::Kick(self.id(), true, is_admin),
+        )
+        .await
+        {
+            Ok(kicked) => {
+                log::debug!("{:?} broadcast kick reply kicked: {:?}", self.id(), kicked);
+                log::debug!(
+                    "{:?} clear_subscriptions: {}, {}, {}",
+                    self.id(),
+                    clear_subscriptions,
+                    kicked.subscriptions.is_empty(),
+                    !clear_subscriptions && !kicked.subscriptions.is_empty()
+                );
+                Ok(Some(kicked))
+            }
+            Err(e) => {
+                log::debug!("{:?}, broadcast Message::Kick reply: {:?}", self.id(), e);
+                Ok(None)
+            }
+        }
+    }
+
+    #[inline]
+    async fn online(&self) -> bool {
+        if self.inner.online().await {
+            return true;
+        }
+
+        MessageBroadcaster::new(
+            self.cluster_shared.grpc_clients.clone(),
+            self.cluster_shared.message_type,
+            Message::Online(self.id().client_id.clone()),
+        )
+        .select_ok(|reply: MessageReply| -> Result<bool> {
+            if let MessageReply::Online(true) = reply {
+                Ok(true)
+            } else {
+                Err(MqttError::None)
+            }
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    #[inline]
+    fn is_connected(&self) -> bool {
+        self.inner.is_connected()
+    }
+
+    #[inline]
+    fn session(&self) -> Option<Session> {
+        self.inner.session()
+    }
+
+    #[inline]
+    fn client(&self) -> Option<ClientInfo> {
+        self.inner.client()
+    }
+
+    #[inline]
+    fn tx(&self) -> Option<Tx> {
+        self.inner.tx()
+    }
+
+    #[inline]
+    async fn subscribe(&self, subscribe: &Subscribe) -> Result<SubscribeReturn> {
+        self.inner.subscribe(subscribe).await
+    }
+
+    #[inline]
+    async fn unsubscribe(&self, unsubscribe: &Unsubscribe) -> Result<bool> {
+        self.inner.unsubscribe(unsubscribe).await
+    }
+
+    #[inline]
+    async fn publish(&self, from: From, p: Publish) -> Result<(), (From, Publish, Reason)> {
+        self.inner.publish(from, p).await
+    }
+
+    #[inline]
+    async fn subscriptions(&self) -> Option<Vec<SubsSearchResult>> {
+        if let Some(subs) = self.inner.subscriptions().await {
+            return Some(subs);
+        }
+        MessageBroadcaster::new(
+            self.cluster_shared.grpc_clients.clone(),
+            self.cluster_shared.message_type,
+            Message::SubscriptionsGet(self.id().client_id.clone()),
+        )
+        .select_ok(|reply: MessageReply| -> Result<Option<Vec<SubsSearchResult>>> {
+            if let MessageReply::SubscriptionsGet(Some(subs)) = reply {
+                Ok(Some(subs))
+            } else {
+                Err(MqttError::None)
+            }
+        })
+        .await
+        .unwrap_or(None)
+    }
+}
+
+pub struct ClusterShared {
+    inner: &'static DefaultShared,
+    grpc_clients: GrpcClients,
+    pub message_type: MessageType,
+    pub(crate) forward_dedup: ForwardDedup,
+}
+
+impl ClusterShared {
+    #[inline]
+    pub(crate) fn get_or_init(
+        grpc_clients: GrpcClients,
+        message_type: MessageType,
+        forward_dedup: ForwardDedup,
+    ) -> &'static ClusterShared {
+        static INSTANCE: OnceCell<ClusterShared> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self {
+            inner: DefaultShared::instance(),
+            grpc_clients,
+            message_type,
+            forward_dedup,
+        })
+    }
+
+    #[inline]
+    pub(crate) fn inner(&self) -> &'static DefaultShared {
+        self.inner
+    }
+}
+
+#[async_trait]
+impl Shared for &'static ClusterShared {
+    #[inline]
+    fn entry(&self, id: Id) -> Box<dyn Entry> {
+        Box::new(ClusterLockEntry::new(self.inner.entry(id), self))
+    }
+
+    #[inline]
+    fn exist(&self, client_id: &str) -> bool {
+        self.inner.exist(client_id)
+    }
+
+    ///The subscriptions of all the nodes are matched locally, the message is sent to the nodes of the
+    ///matching subscriptions only
+    #[inline]
+    async fn forwards(
+        &self,
+        from: From,
+        mut publish: Publish,
+    ) -> Result<(), Vec<(To, From, Publish, Reason)>> {
+        let topic = publish.topic();
+        log::debug!("forwards, from: {:?}, topic: {:?}", from, topic.to_string());
+
+        let mut relations_map =
+            match Runtime::instance().extends.router().await.matches_publish(&publish).await {
+                Ok(relations_map) => relations_map,
+                Err(e) => {
+                    DefaultShared::router_error(from, publish, e).await;
+                    return Ok(());
+                }
+            };
+
+        if relations_map.is_empty() {
+            DefaultShared::no_subscribers(from, publish).await;
+            return Ok(());
+        }
+
+        //forwards to local
+        let local_res = match relations_map.remove(&Runtime::instance().node.id()) {
+            Some(relations) => self.inner.forwards_to(from.clone(), &publish, relations).await,
+            None => Ok(()),
+        };
+
+        //forwards to remote, a node that is unreachable misses the message
+        if !relations_map.is_empty() {
+            publish.forward_id = Some(ForwardId::next());
+            let mut delivers = Vec::new();
+            for (node_id, relations) in relations_map {
+                match self.grpc_clients.get(&node_id) {
+                    Some((_addr, grpc_client)) => {
+                        let grpc_client = grpc_client.clone();
+                        let message_type = self.message_type;
+                        let msg = Message::ForwardsTo(from.clone(), publish.clone(), relations);
+                        delivers.push(
+                            async move { (node_id, grpc_client.send_message(message_type, msg).await) },
+                        );
+                    }
+                    None => log::warn!("forwards error, node {} is not a member of the cluster", node_id),
+                }
+            }
+            let forwards_fut = async move {
+                for (node_id, res) in futures::future::join_all(delivers).await {
+                    if let Err(e) = res {
+                        log::error!(
+                            "forwards Message::ForwardsTo to other node, from: {:?}, to: {:?}, error: {:?}",
+                            from,
+                            node_id,
+                            e
+                        );
+                    }
+                }
+            };
+            //With strict ordering, the next publish is not forwarded until this one is
+            if Runtime::instance().settings.mqtt.strict_ordering {
+                forwards_fut.await;
+            } else {
+                tokio::spawn(forwards_fut);
+            }
+        }
+
+        local_res
+    }
+
+    #[inline]
+    async fn forwards_and_get_shareds(
+        &self,
+        from: From,
+        publish: Publish,
+    ) -> Result<SubRelationsMap, Vec<(To, From, Publish, Reason)>> {
+        self.inner.forwards_and_get_shareds(from, publish).await
+    }
+
+    #[inline]
+    async fn forwards_to(
+        &self,
+        from: From,
+        publish: &Publish,
+        relations: SubRelations,
+    ) -> Result<(), Vec<(To, From, Publish, Reason)>> {
+        self.inner.forwards_to(from, publish, relations).await
+    }
+
+    #[inline]
+    fn iter(&self) -> Box<dyn Iterator<Item = Box<dyn Entry>> + Sync + Send> {
+        self.inner.iter()
+    }
+
+    #[inline]
+    fn random_session(&self) -> Option<(Session, ClientInfo)> {
+        self.inner.random_session()
+    }
+
+    #[inline]
+    async fn session_status(&self, client_id: &str) -> Option<SessionStatus> {
+        if let Some(status) = self.inner.session_status(client_id).await {
+            return Some(status);
+        }
+        MessageBroadcaster::new(
+            self.grpc_clients.clone(),
+            self.message_type,
+            Message::SessionStatus(ClientId::from(client_id)),
+        )
+        .select_ok(|reply: MessageReply| -> Result<SessionStatus> {
+            if let MessageReply::SessionStatus(Some(status)) = reply {
+                Ok(status)
+            } else {
+                Err(MqttError::None)
+            }
+        })
+        .await
+        .ok()
+    }
+
+    #[inline]
+    async fn clinet_states_count(&self) -> usize {
+        self.inner.clinet_states_count().await
+    }
+
+    #[inline]
+    fn sessions_count(&self) -> usize {
+        self.inner.sessions_count()
+    }
+
+    #[inline]
+    async fn query_subscriptions(&self, mut q: SubsSearchParams) -> Vec<SubsSearchResult> {
+        let limit = q._limit;
+        let mut replys = self.inner.query_subscriptions(q.clone()).await;
+
+        let grpc_clients = self.get_grpc_clients();
+        for c in grpc_clients.iter().map(|(_, (_, c))| c.clone()) {
+            if replys.len() < limit {
+                q._limit = limit - replys.len();
+                let reply = MessageSender::new(c, self.message_type, Message::SubscriptionsSearch(q.clone()))
+                    .send()
+                    .await;
+                match reply {
+                    Ok(MessageReply::SubscriptionsSearch(subs)) => {
+                        replys.extend(subs);
+                    }
+                    Err(e) => {
+                        log::warn!("query_subscriptions, error: {:?}", e);
+                    }
+                    _ => unreachable!(),
+                };
+            } else {
+                break;
+            }
+        }
+
+        replys
+    }
+
+    #[inline]
+    fn get_grpc_clients(&self) -> GrpcClients {
+        self.grpc_clients.clone()
+    }
+}
@@ -1,38 +1,91 @@
 use std::time::Duration;
 
 pub(crate) use backoff::future::retry;
-pub(crate) use backoff::{ExponentialBackoff, ExponentialBackoffBuilder};
+use backoff::{ExponentialBackoff, ExponentialBackoffBuilder};
 use rmqtt_raft::ReadOnlyOption;
 use serde::de::{self, Deserialize, Deserializer};
 use serde::ser::Serializer;
 use serde::Serialize;
 
-use rmqtt::grpc::MessageType;
+use rmqtt::grpc::{codec::Codec, MessageType};
+use rmqtt::settings::schema::{ConfigSchema, Field, FieldType};
 use rmqtt::settings::{deserialize_duration, deserialize_duration_option, NodeAddr, Options};
-use rmqtt::Result;
-use rmqtt::{lazy_static, serde_json};
+use rmqtt::{serde_json, NodeId};
+use rmqtt::{MqttError, Result};
 
-lazy_static::lazy_static! {
-    pub static ref BACKOFF_STRATEGY: ExponentialBackoff = ExponentialBackoffBuilder::new()
-        .with_max_elapsed_time(Some(Duration::from_secs(60)))
-        .with_multiplier(2.5).build();
-}
+use super::HashMap;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct PluginConfig {
     #[serde(default = "PluginConfig::message_type_default")]
     pub message_type: MessageType,
+    #[serde(default)]
     pub node_grpc_addrs: Vec<NodeAddr>,
+    #[serde(default)]
     pub raft_peer_addrs: Vec<NodeAddr>,
+    ///Encoding of the messages sent to the other nodes and of the raft log entries and snapshots,
+    ///bincode or msgpack
+    #[serde(default)]
+    pub message_codec: Codec,
     #[serde(default = "PluginConfig::try_lock_timeout_default", deserialize_with = "deserialize_duration")]
     pub try_lock_timeout: Duration, //Message::HandshakeTryLock
 
+    ///Number of the raft groups the subscriptions are sharded across by the hash of the topic filter,
+    ///group g listens on the port of raft_peer_addrs plus g * raft_group_port_step. Must be the same on
+    ///all nodes and cannot be changed once the cluster has been started
+    #[serde(default = "PluginConfig::raft_groups_default")]
+    pub raft_groups: usize,
+    ///Distance between the ports of the raft groups of a node, the ports of the nodes on the same host
+    ///must not fall into the ranges of each other
+    #[serde(default = "PluginConfig::raft_group_port_step_default")]
+    pub raft_group_port_step: u16,
+
+    ///Capacity of the queue of the subscription changes applied in the background, decoupled from the
+    ///commit of the raft entries, 0 means they are applied inline
+    #[serde(default)]
+    pub apply_pipeline_capacity: usize,
+    ///Maximum number of the queued subscription changes applied in one batch
+    #[serde(default = "PluginConfig::apply_batch_size_default")]
+    pub apply_batch_size: usize,
+
     #[serde(default = "PluginConfig::task_exec_queue_workers_default")]
     pub task_exec_queue_workers: usize,
     #[serde(default = "PluginConfig::task_exec_queue_max_default")]
     pub task_exec_queue_max: usize,
     #[serde(default = "PluginConfig::raft_default")]
     pub raft: RaftConfig,
+
+    ///How the nodes find each other, static lists all of them in node_grpc_addrs and raft_peer_addrs,
+    ///gossip only needs the seed nodes, kubernetes lists the pods of the StatefulSet
+    #[serde(default)]
+    pub discovery: Discovery,
+
+    ///What is done with the publishes forwarded to a node that cannot be reached
+    #[serde(default)]
+    pub partition: Partition,
+
+    ///Timeouts, retries and limits of the requests to the raft groups
+    #[serde(default)]
+    pub mailbox: MailboxConfig,
+
+    ///When the routing lookups of the publishes read the local state of the raft groups
+    #[serde(default)]
+    pub routing_read: RoutingRead,
+
+    ///A publish forwarded again to a node within the window, such as on a retry after a timeout
+    ///whose first attempt was delivered, is not delivered twice, 0 means no deduplication
+    #[serde(
+        default = "PluginConfig::forward_dedup_window_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub forward_dedup_window: Duration,
+    ///Maximum number of the forwarded publishes remembered within the window
+    #[serde(default = "PluginConfig::forward_dedup_max_default")]
+    pub forward_dedup_max: usize,
+
+    ///Limits of the subscriptions kept by the raft state machine
+    #[serde(default)]
+    pub subscription_limits: SubscriptionLimits,
 }
 
 impl PluginConfig {
@@ -41,6 +94,87 @@ impl PluginConfig {
         Ok(serde_json::to_value(self)?)
     }
 
+    pub fn schema() -> ConfigSchema {
+        ConfigSchema::new()
+            .field(Field::new("message_type", FieldType::Integer).default(Self::message_type_default()))
+            .field(
+                Field::new("node_grpc_addrs", FieldType::Array)
+                    .descr("gRPC addresses of the nodes, as \"<node id>@<host>:<port>\""),
+            )
+            .field(
+                Field::new("raft_peer_addrs", FieldType::Array)
+                    .descr("Raft addresses of the nodes, as \"<node id>@<host>:<port>\""),
+            )
+            .field(
+                Field::new("message_codec", FieldType::String)
+                    .default_of(Codec::default())
+                    .options(vec!["bincode", "msgpack"])
+                    .descr("Encoding of the messages sent to the other nodes and of the raft log"),
+            )
+            .field(
+                Field::new("try_lock_timeout", FieldType::Duration)
+                    .default_duration(Self::try_lock_timeout_default())
+                    .descr("Handshake lock timeout"),
+            )
+            .field(
+                Field::new("raft_groups", FieldType::Integer)
+                    .default(Self::raft_groups_default())
+                    .descr("Number of the raft groups the subscriptions are sharded across"),
+            )
+            .field(
+                Field::new("raft_group_port_step", FieldType::Integer)
+                    .default(Self::raft_group_port_step_default())
+                    .descr("Distance between the ports of the raft groups of a node"),
+            )
+            .field(
+                Field::new("apply_pipeline_capacity", FieldType::Integer)
+                    .default(0)
+                    .descr("Capacity of the queue of the subscription changes applied in the background"),
+            )
+            .field(
+                Field::new("apply_batch_size", FieldType::Integer)
+                    .default(Self::apply_batch_size_default())
+                    .descr("Maximum number of the queued subscription changes applied in one batch"),
+            )
+            .field(
+                Field::new("task_exec_queue_workers", FieldType::Integer)
+                    .default(Self::task_exec_queue_workers_default()),
+            )
+            .field(
+                Field::new("task_exec_queue_max", FieldType::Integer)
+                    .default(Self::task_exec_queue_max_default()),
+            )
+            .field(Field::new("raft", FieldType::Object).descr("Settings of the raft nodes"))
+            .field(Discovery::schema_field("discovery").descr("How the nodes find each other"))
+            .field(
+                Partition::schema_field("partition")
+                    .descr("What is done with the publishes forwarded to a node that cannot be reached"),
+            )
+            .field(
+                MailboxConfig::schema_field("mailbox")
+                    .descr("Timeouts, retries and limits of the requests to the raft groups"),
+            )
+            .field(
+                RoutingRead::schema_field("routing_read").descr(
+                    "When the routing lookups of the publishes read the local state of the raft groups",
+                ),
+            )
+            .field(
+                Field::new("forward_dedup_window", FieldType::Duration)
+                    .default_duration(Self::forward_dedup_window_default())
+                    .descr("A publish forwarded again to a node within the window is not delivered twice"),
+            )
+            .field(
+                Field::new("forward_dedup_max", FieldType::Integer)
+                    .default(Self::forward_dedup_max_default())
+                    .descr("Maximum number of the forwarded publishes remembered within the window"),
+            )
+            .field(
+                SubscriptionLimits::schema_field("subscription_limits")
+                    .descr("Limits of the subscriptions kept by the raft state machine"),
+            )
+    }
+
     fn message_type_default() -> MessageType {
         198
     }
@@ -49,6 +183,49 @@ impl PluginConfig {
         Duration::from_secs(10)
     }
 
+    fn raft_groups_default() -> usize {
+        1
+    }
+
+    fn raft_group_port_step_default() -> u16 {
+        100
+    }
+
+    ///Port of a raft group, None if it is out of range
+    #[inline]
+    pub fn group_port(&self, port: u16, group: usize) -> Option<u16> {
+        u16::try_from(port as usize + group * self.raft_group_port_step as usize).ok()
+    }
+
+    ///The ports of the raft groups of the nodes on the same host must not collide
+    pub fn check_group_ports(&self) -> Result<()> {
+        let mut used = HashMap::default();
+        for peer in self.raft_peer_addrs.iter() {
+            let (host, port) = peer
+                .addr
+                .rsplit_once(':')
+                .and_then(|(host, port)| port.parse::<u16>().ok().map(|port| (host, port)))
+                .ok_or_else(|| MqttError::from(format!("invalid raft peer address, {}", peer.addr)))?;
+            for group in 0..self.raft_groups {
+                let port = self.group_port(port, group).ok_or_else(|| {
+                    MqttError::from(format!("raft group {} port out of range, {}", group, peer.addr))
+                })?;
+                if let Some((id, g)) = used.insert((host, port), (peer.id, group)) {
+                    return Err(MqttError::from(format!(
+                        "raft port {}:{} of node {} group {} is also the port of node {} group {}, \
+                         check raft_group_port_step",
+                        host, port, peer.id, group, id, g
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_batch_size_default() -> usize {
+        100
+    }
+
     fn task_exec_queue_workers_default() -> usize {
         500
     }
@@ -57,17 +234,556 @@ impl PluginConfig {
         100_000
     }
 
+    fn forward_dedup_window_default() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn forward_dedup_max_default() -> usize {
+        100_000
+    }
+
     fn raft_default() -> RaftConfig {
         RaftConfig { ..Default::default() }
     }
 
-    pub fn merge(&mut self, opts: &Options) {
+    ///The entries of id 0 are of this node, whose id may not be known in advance, such as with
+    ///node.id_from_machine_id
+    pub fn merge(&mut self, opts: &Options, node_id: NodeId) {
         if let Some(node_grpc_addrs) = opts.node_grpc_addrs.as_ref() {
             self.node_grpc_addrs = node_grpc_addrs.clone();
         }
         if let Some(raft_peer_addrs) = opts.raft_peer_addrs.as_ref() {
             self.raft_peer_addrs = raft_peer_addrs.clone();
         }
+        for addr in self.node_grpc_addrs.iter_mut().chain(self.raft_peer_addrs.iter_mut()) {
+            if addr.id == 0 {
+                addr.id = node_id;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryMode {
+    Static,
+    Gossip,
+    Kubernetes,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Discovery {
+    #[serde(default = "Discovery::mode_default")]
+    pub mode: DiscoveryMode,
+    ///gRPC addresses of the seed nodes, a starting node learns the other nodes from them. Only the
+    ///first seed starts a new cluster if it reaches no other node
+    #[serde(default)]
+    pub seeds: Vec<String>,
+    ///Interval of the gossip rounds
+    #[serde(default = "Discovery::interval_default", deserialize_with = "deserialize_duration")]
+    pub interval: Duration,
+    ///Number of the members a node gossips with in each round
+    #[serde(default = "Discovery::fanout_default")]
+    pub fanout: usize,
+    ///A member whose heartbeat has not advanced for this long is not healthy
+    #[serde(default = "Discovery::suspect_timeout_default", deserialize_with = "deserialize_duration")]
+    pub suspect_timeout: Duration,
+    ///A member whose heartbeat has not advanced for this long is removed
+    #[serde(default = "Discovery::dead_timeout_default", deserialize_with = "deserialize_duration")]
+    pub dead_timeout: Duration,
+    ///Maximum time a starting node gossips with the seeds before it joins the raft cluster
+    #[serde(default = "Discovery::join_timeout_default", deserialize_with = "deserialize_duration")]
+    pub join_timeout: Duration,
+    #[serde(default)]
+    pub kubernetes: Kubernetes,
+}
+
+impl Default for Discovery {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            mode: Self::mode_default(),
+            seeds: Vec::new(),
+            interval: Self::interval_default(),
+            fanout: Self::fanout_default(),
+            suspect_timeout: Self::suspect_timeout_default(),
+            dead_timeout: Self::dead_timeout_default(),
+            join_timeout: Self::join_timeout_default(),
+            kubernetes: Kubernetes::default(),
+        }
+    }
+}
+
+impl Discovery {
+    fn mode_default() -> DiscoveryMode {
+        DiscoveryMode::Static
+    }
+
+    fn interval_default() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn fanout_default() -> usize {
+        3
+    }
+
+    fn suspect_timeout_default() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn dead_timeout_default() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn join_timeout_default() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    #[inline]
+    fn schema_field(name: &str) -> Field {
+        Field::new(name, FieldType::Object)
+            .field(Field::new("mode", FieldType::String).default_of(Self::mode_default()).options(vec![
+                "static",
+                "gossip",
+                "kubernetes",
+            ]))
+            .field(Field::new("seeds", FieldType::Array).descr("gRPC addresses of the seed nodes"))
+            .field(Field::new("interval", FieldType::Duration).default_duration(Self::interval_default()))
+            .field(Field::new("fanout", FieldType::Integer).default(Self::fanout_default()))
+            .field(
+                Field::new("suspect_timeout", FieldType::Duration)
+                    .default_duration(Self::suspect_timeout_default()),
+            )
+            .field(
+                Field::new("dead_timeout", FieldType::Duration)
+                    .default_duration(Self::dead_timeout_default()),
+            )
+            .field(
+                Field::new("join_timeout", FieldType::Duration)
+                    .default_duration(Self::join_timeout_default()),
+            )
+            .field(Kubernetes::schema_field("kubernetes"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KubernetesSource {
+    ///The pods of the endpoints of the service, listed through the Kubernetes API
+    Api,
+    ///Pods 0..replicas of the StatefulSet
+    Dns,
+}
+
+///The nodes are the pods of a StatefulSet governed by a headless service, node n is pod n - 1,
+///reached at "<statefulset>-<n - 1>.<service>.<namespace>.svc.<cluster_domain>"
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Kubernetes {
+    #[serde(default = "Kubernetes::source_default")]
+    pub source: KubernetesSource,
+    ///Name of the headless service
+    #[serde(default = "Kubernetes::service_default")]
+    pub service: String,
+    ///Defaults to the namespace of the service account of the pod
+    #[serde(default)]
+    pub namespace: Option<String>,
+    ///Defaults to the name of the StatefulSet of this pod, from HOSTNAME
+    #[serde(default)]
+    pub statefulset: Option<String>,
+    ///Number of the pods, for the dns source
+    #[serde(default = "Kubernetes::replicas_default")]
+    pub replicas: u64,
+    #[serde(default = "Kubernetes::cluster_domain_default")]
+    pub cluster_domain: String,
+    ///Defaults to the port of rpc.server_addr
+    #[serde(default)]
+    pub grpc_port: Option<u16>,
+    #[serde(default = "Kubernetes::raft_port_default")]
+    pub raft_port: u16,
+    #[serde(default = "Kubernetes::api_server_default")]
+    pub api_server: String,
+    ///Interval of listing the pods again, so that the nodes of a scaled up StatefulSet are reached
+    #[serde(default = "Kubernetes::refresh_interval_default", deserialize_with = "deserialize_duration")]
+    pub refresh_interval: Duration,
+}
+
+impl Default for Kubernetes {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            source: Self::source_default(),
+            service: Self::service_default(),
+            namespace: None,
+            statefulset: None,
+            replicas: Self::replicas_default(),
+            cluster_domain: Self::cluster_domain_default(),
+            grpc_port: None,
+            raft_port: Self::raft_port_default(),
+            api_server: Self::api_server_default(),
+            refresh_interval: Self::refresh_interval_default(),
+        }
+    }
+}
+
+impl Kubernetes {
+    fn source_default() -> KubernetesSource {
+        KubernetesSource::Api
+    }
+
+    fn service_default() -> String {
+        "rmqtt-headless".into()
+    }
+
+    fn replicas_default() -> u64 {
+        3
+    }
+
+    fn cluster_domain_default() -> String {
+        "cluster.local".into()
+    }
+
+    fn raft_port_default() -> u16 {
+        6003
+    }
+
+    fn api_server_default() -> String {
+        "https://kubernetes.default.svc".into()
+    }
+
+    fn refresh_interval_default() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    #[inline]
+    fn schema_field(name: &str) -> Field {
+        Field::new(name, FieldType::Object)
+            .field(
+                Field::new("source", FieldType::String)
+                    .default_of(Self::source_default())
+                    .options(vec!["api", "dns"]),
+            )
+            .field(Field::new("service", FieldType::String).default(Self::service_default()))
+            .field(Field::new("namespace", FieldType::String))
+            .field(Field::new("statefulset", FieldType::String))
+            .field(Field::new("replicas", FieldType::Integer).default(Self::replicas_default()))
+            .field(Field::new("cluster_domain", FieldType::String).default(Self::cluster_domain_default()))
+            .field(Field::new("grpc_port", FieldType::Integer))
+            .field(Field::new("raft_port", FieldType::Integer).default(Self::raft_port_default()))
+            .field(Field::new("api_server", FieldType::String).default(Self::api_server_default()))
+            .field(
+                Field::new("refresh_interval", FieldType::Duration)
+                    .default_duration(Self::refresh_interval_default()),
+            )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionMode {
+    ///The publishes are dropped, with hook message_dropped
+    Drop,
+    ///The publishes to each node are forwarded in order, one at a time, through a buffer of up to
+    ///buffer_capacity publishes, kept while the node cannot be reached for up to buffer_ttl
+    Buffer,
+    ///The publishes are dropped, and once the node has been failing for reassign_after, and is not
+    ///a healthy member of the gossip discovery, the sessions of their subscribers on the node are
+    ///marked offline, so that shared subscriptions choose the members on the other nodes and the
+    ///clients take over their sessions on reconnect without waiting for the node
+    Reassign,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Partition {
+    #[serde(default = "Partition::mode_default")]
+    pub mode: PartitionMode,
+    ///Maximum number of the publishes buffered per node, the publishes beyond it are dropped
+    #[serde(default = "Partition::buffer_capacity_default")]
+    pub buffer_capacity: usize,
+    ///Interval of retrying to forward the buffered publishes
+    #[serde(default = "Partition::retry_interval_default", deserialize_with = "deserialize_duration")]
+    pub retry_interval: Duration,
+    ///The buffered publishes not forwarded within it are dropped
+    #[serde(default = "Partition::buffer_ttl_default", deserialize_with = "deserialize_duration")]
+    pub buffer_ttl: Duration,
+    ///How long the forwards to a node fail before the sessions on it are reassigned
+    #[serde(default = "Partition::reassign_after_default", deserialize_with = "deserialize_duration")]
+    pub reassign_after: Duration,
+}
+
+impl Default for Partition {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            mode: Self::mode_default(),
+            buffer_capacity: Self::buffer_capacity_default(),
+            retry_interval: Self::retry_interval_default(),
+            buffer_ttl: Self::buffer_ttl_default(),
+            reassign_after: Self::reassign_after_default(),
+        }
+    }
+}
+
+impl Partition {
+    fn mode_default() -> PartitionMode {
+        PartitionMode::Drop
+    }
+
+    fn buffer_capacity_default() -> usize {
+        10_000
+    }
+
+    fn retry_interval_default() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn buffer_ttl_default() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn reassign_after_default() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    #[inline]
+    fn schema_field(name: &str) -> Field {
+        Field::new(name, FieldType::Object)
+            .field(
+                Field::new("mode", FieldType::String)
+                    .default_of(Self::mode_default())
+                    .options(vec!["drop", "buffer", "reassign"]),
+            )
+            .field(Field::new("buffer_capacity", FieldType::Integer).default(Self::buffer_capacity_default()))
+            .field(
+                Field::new("retry_interval", FieldType::Duration)
+                    .default_duration(Self::retry_interval_default()),
+            )
+            .field(Field::new("buffer_ttl", FieldType::Duration).default_duration(Self::buffer_ttl_default()))
+            .field(
+                Field::new("reassign_after", FieldType::Duration)
+                    .default_duration(Self::reassign_after_default()),
+            )
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SubscriptionLimits {
+    ///Maximum number of the subscriptions of the cluster, 0 means no limit
+    #[serde(default)]
+    pub max_total: usize,
+    ///Maximum number of the subscriptions of the clients of each node, 0 means no limit
+    #[serde(default)]
+    pub max_per_node: usize,
+    #[serde(default = "SubscriptionLimits::on_exceeded_default")]
+    pub on_exceeded: OnLimitExceeded,
+    ///The subscription_limit alarm is activated while a count is above this ratio of its limit
+    #[serde(default = "SubscriptionLimits::alarm_watermark_default")]
+    pub alarm_watermark: f64,
+    ///Interval of the checks of the counts against the alarm watermark
+    #[serde(
+        default = "SubscriptionLimits::check_interval_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub check_interval: Duration,
+}
+
+impl Default for SubscriptionLimits {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            max_total: 0,
+            max_per_node: 0,
+            on_exceeded: Self::on_exceeded_default(),
+            alarm_watermark: Self::alarm_watermark_default(),
+            check_interval: Self::check_interval_default(),
+        }
+    }
+}
+
+impl SubscriptionLimits {
+    fn on_exceeded_default() -> OnLimitExceeded {
+        OnLimitExceeded::Reject
+    }
+
+    fn alarm_watermark_default() -> f64 {
+        0.9
+    }
+
+    fn check_interval_default() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    #[inline]
+    fn schema_field(name: &str) -> Field {
+        Field::new(name, FieldType::Object)
+            .field(Field::new("max_total", FieldType::Integer).default(0))
+            .field(Field::new("max_per_node", FieldType::Integer).default(0))
+            .field(
+                Field::new("on_exceeded", FieldType::String)
+                    .default_of(Self::on_exceeded_default())
+                    .options(vec!["reject", "reject_wildcard", "allow"]),
+            )
+            .field(Field::new("alarm_watermark", FieldType::Float).default(Self::alarm_watermark_default()))
+            .field(
+                Field::new("check_interval", FieldType::Duration)
+                    .default_duration(Self::check_interval_default()),
+            )
+    }
+
+    #[inline]
+    pub fn is_limited(&self) -> bool {
+        self.max_total > 0 || self.max_per_node > 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnLimitExceeded {
+    ///The new subscriptions are refused with the reason code Quota Exceeded
+    Reject,
+    ///Only the new subscriptions with wildcards are refused, those to a single topic are accepted
+    RejectWildcard,
+    ///The new subscriptions are accepted, the limits only activate the alarm
+    Allow,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RoutingRead {
+    ///Maximum number of the entries the local state of a raft group may be behind its leader for the
+    ///routing lookups to read it, beyond it the subscriptions of the group are read from the leader.
+    ///None means the local state is always read
+    #[serde(default)]
+    pub max_lag: Option<u64>,
+    ///Interval of comparing the applied entries of the raft groups with those of their leaders
+    #[serde(default = "RoutingRead::check_interval_default", deserialize_with = "deserialize_duration")]
+    pub check_interval: Duration,
+}
+
+impl Default for RoutingRead {
+    #[inline]
+    fn default() -> Self {
+        Self { max_lag: None, check_interval: Self::check_interval_default() }
+    }
+}
+
+impl RoutingRead {
+    fn check_interval_default() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    #[inline]
+    fn schema_field(name: &str) -> Field {
+        Field::new(name, FieldType::Object).field(Field::new("max_lag", FieldType::Integer)).field(
+            Field::new("check_interval", FieldType::Duration)
+                .default_duration(Self::check_interval_default()),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MailboxConfig {
+    ///Maximum time a proposal waits to be committed, 0 means no timeout
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub propose_timeout: Duration,
+    ///Maximum time a read of the raft state machine waits, 0 means no timeout
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub query_timeout: Duration,
+    ///Maximum time a raft status request waits, 0 means no timeout
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub status_timeout: Duration,
+    ///Maximum number of the proposals waiting to be committed on this node, a proposal beyond it
+    ///fails immediately, 0 means no limit
+    #[serde(default)]
+    pub max_pending_proposals: usize,
+
+    ///The proposals that are retried in the background, such as on disconnect, are retried with an
+    ///exponential backoff, from retry_initial_interval up to retry_max_interval
+    #[serde(
+        default = "MailboxConfig::retry_initial_interval_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub retry_initial_interval: Duration,
+    #[serde(
+        default = "MailboxConfig::retry_max_interval_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub retry_max_interval: Duration,
+    #[serde(default = "MailboxConfig::retry_multiplier_default")]
+    pub retry_multiplier: f64,
+    ///Maximum time a proposal is retried, 0 means no limit
+    #[serde(default = "MailboxConfig::retry_max_elapsed_default", deserialize_with = "deserialize_duration")]
+    pub retry_max_elapsed: Duration,
+    ///Maximum number of the retries of a proposal, 0 means no limit
+    #[serde(default)]
+    pub max_retries: usize,
+}
+
+impl Default for MailboxConfig {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            propose_timeout: Duration::ZERO,
+            query_timeout: Duration::ZERO,
+            status_timeout: Duration::ZERO,
+            max_pending_proposals: 0,
+            retry_initial_interval: Self::retry_initial_interval_default(),
+            retry_max_interval: Self::retry_max_interval_default(),
+            retry_multiplier: Self::retry_multiplier_default(),
+            retry_max_elapsed: Self::retry_max_elapsed_default(),
+            max_retries: 0,
+        }
+    }
+}
+
+impl MailboxConfig {
+    fn retry_initial_interval_default() -> Duration {
+        Duration::from_millis(500)
+    }
+
+    fn retry_max_interval_default() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn retry_multiplier_default() -> f64 {
+        2.5
+    }
+
+    fn retry_max_elapsed_default() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    #[inline]
+    fn schema_field(name: &str) -> Field {
+        Field::new(name, FieldType::Object)
+            .field(Field::new("propose_timeout", FieldType::Duration).default_duration(Duration::ZERO))
+            .field(Field::new("query_timeout", FieldType::Duration).default_duration(Duration::ZERO))
+            .field(Field::new("status_timeout", FieldType::Duration).default_duration(Duration::ZERO))
+            .field(Field::new("max_pending_proposals", FieldType::Integer).default(0))
+            .field(
+                Field::new("retry_initial_interval", FieldType::Duration)
+                    .default_duration(Self::retry_initial_interval_default()),
+            )
+            .field(
+                Field::new("retry_max_interval", FieldType::Duration)
+                    .default_duration(Self::retry_max_interval_default()),
+            )
+            .field(Field::new("retry_multiplier", FieldType::Float).default(Self::retry_multiplier_default()))
+            .field(
+                Field::new("retry_max_elapsed", FieldType::Duration)
+                    .default_duration(Self::retry_max_elapsed_default()),
+            )
+            .field(Field::new("max_retries", FieldType::Integer).default(0))
+    }
+
+    #[inline]
+    pub(crate) fn backoff(&self) -> ExponentialBackoff {
+        let max_elapsed_time =
+            if self.retry_max_elapsed.is_zero() { None } else { Some(self.retry_max_elapsed) };
+        ExponentialBackoffBuilder::new()
+            .with_initial_interval(self.retry_initial_interval)
+            .with_max_interval(self.retry_max_interval)
+            .with_multiplier(self.retry_multiplier)
+            .with_max_elapsed_time(max_elapsed_time)
+            .build()
     }
 }
 
@@ -260,3 +976,29 @@ impl RaftConfig {
         rop_str.serialize(s)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(raft_groups: usize, raft_group_port_step: u16) -> PluginConfig {
+        serde_json::from_value(serde_json::json!({
+            "raft_peer_addrs": ["1@127.0.0.1:6003", "2@127.0.0.1:6004", "3@10.0.0.1:6003"],
+            "raft_groups": raft_groups,
+            "raft_group_port_step": raft_group_port_step,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn group_ports() {
+        assert!(config(1, 1).check_group_ports().is_ok());
+        assert!(config(2, 1).check_group_ports().is_err());
+        assert!(config(2, 0).check_group_ports().is_err());
+        let cfg = config(4, 100);
+        assert!(cfg.check_group_ports().is_ok());
+        assert_eq!(cfg.group_port(6003, 3), Some(6303));
+        assert_eq!(cfg.group_port(65500, 1), None);
+        assert!(config(2, 65535).check_group_ports().is_err());
+    }
+}
@@ -0,0 +1,340 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use once_cell::sync::OnceCell;
+
+use rmqtt::rand::seq::SliceRandom;
+use rmqtt::{
+    broker::types::{Addr, NodeId, TimestampMillis},
+    grpc::{client::NodeGrpcClient, Message, MessageReply, MessageType},
+    MqttError, Result, Runtime,
+};
+use rmqtt::{chrono, log, once_cell, rand, serde_json, tokio, RwLock};
+
+use super::config::Discovery as DiscoveryConfig;
+use super::message::{RaftGrpcMessage, RaftGrpcMessageReply};
+use super::shared::ClusterShared;
+use super::HashMap;
+
+///A node of the cluster as known by gossip
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Member {
+    pub id: NodeId,
+    pub grpc_addr: Addr,
+    pub raft_addr: Addr,
+    ///Incremented by the node in each gossip round
+    pub heartbeat: u64,
+    ///Time in milliseconds the node started at, a restarted node is newer whatever its heartbeat
+    #[serde(
+        default,
+        skip_serializing_if = "rmqtt::grpc::codec::is_legacy_layout",
+        deserialize_with = "rmqtt::grpc::codec::deserialize_since_legacy"
+    )]
+    pub incarnation: TimestampMillis,
+}
+
+impl Member {
+    ///Whether the member is a later state of the node than known
+    #[inline]
+    fn is_newer(&self, incarnation: TimestampMillis, heartbeat: u64) -> bool {
+        (self.incarnation, self.heartbeat) > (incarnation, heartbeat)
+    }
+}
+
+///Gossip based discovery of the nodes: each round a node sends the members it knows to a few of
+///them, or to the seeds, and merges the members they reply with. A member is healthy while its
+///heartbeat keeps advancing, and is removed once it has not advanced for dead_timeout
+pub(crate) struct Discovery {
+    cfg: DiscoveryConfig,
+    message_type: MessageType,
+    shared: &'static ClusterShared,
+    local: Member,
+    heartbeat: AtomicU64,
+    //member, time its heartbeat last advanced
+    members: RwLock<HashMap<NodeId, (Member, TimestampMillis)>>,
+    //removed member, (incarnation, heartbeat, time it was removed), the gossip of its older states
+    //by the members that have not removed it yet is ignored
+    removeds: RwLock<HashMap<NodeId, (TimestampMillis, u64, TimestampMillis)>>,
+    seeds: Vec<(String, NodeGrpcClient)>,
+}
+
+impl Discovery {
+    #[inline]
+    pub(crate) async fn init(
+        cfg: DiscoveryConfig,
+        message_type: MessageType,
+        shared: &'static ClusterShared,
+        grpc_addr: Addr,
+        raft_addr: Addr,
+    ) -> Result<&'static Discovery> {
+        let mut seeds = Vec::new();
+        for seed in cfg.seeds.iter().filter(|seed| seed.as_str() != &*grpc_addr) {
+            seeds.push((seed.clone(), Runtime::instance().node.new_grpc_client(seed).await?));
+        }
+        let local = Member {
+            id: Runtime::instance().node.id(),
+            grpc_addr,
+            raft_addr,
+            heartbeat: 0,
+            incarnation: chrono::Local::now().timestamp_millis(),
+        };
+        let discovery = Self {
+            cfg,
+            message_type,
+            shared,
+            local,
+            heartbeat: AtomicU64::new(0),
+            members: RwLock::new(HashMap::default()),
+            removeds: RwLock::new(HashMap::default()),
+            seeds,
+        };
+        INSTANCE.set(discovery).map_err(|_| MqttError::from("discovery is already initialized"))?;
+        Ok(INSTANCE.get().unwrap())
+    }
+
+    ///None unless the discovery mode is gossip
+    #[inline]
+    pub(crate) fn get() -> Option<&'static Discovery> {
+        INSTANCE.get()
+    }
+
+    ///This node and the members it knows
+    #[inline]
+    pub(crate) fn members(&self) -> Vec<Member> {
+        let mut local = self.local.clone();
+        local.heartbeat = self.heartbeat.load(Ordering::SeqCst);
+        let mut members = vec![local];
+        members.extend(self.members.read().values().map(|(m, _)| m.clone()));
+        members
+    }
+
+    #[inline]
+    pub(crate) fn healthy_members(&self) -> Vec<Member> {
+        let now = chrono::Local::now().timestamp_millis();
+        let suspect_timeout = self.cfg.suspect_timeout.as_millis() as TimestampMillis;
+        self.members
+            .read()
+            .values()
+            .filter(|(_, advanced_at)| now - *advanced_at < suspect_timeout)
+            .map(|(m, _)| m.clone())
+            .collect()
+    }
+
+    ///Merges the members of a peer, the newly discovered ones get a grpc client. A member with the
+    ///id of a known node at another address is ignored, an error is returned if it has the id of
+    ///this node
+    #[inline]
+    pub(crate) async fn merge(&self, members: Vec<Member>) -> Result<()> {
+        let now = chrono::Local::now().timestamp_millis();
+        let mut discovereds = Vec::new();
+        let mut duplicate = None;
+        {
+            let mut known = self.members.write();
+            let mut removeds = self.removeds.write();
+            for m in members {
+                if m.id == self.local.id {
+                    if m.grpc_addr != self.local.grpc_addr {
+                        duplicate = Some(m);
+                    }
+                    continue;
+                }
+                match known.get_mut(&m.id) {
+                    Some((k, _)) if k.grpc_addr != m.grpc_addr => {
+                        log::error!(
+                            "duplicate node id {}, grpc_addr: {} and {}, the latter is ignored",
+                            m.id,
+                            k.grpc_addr,
+                            m.grpc_addr
+                        );
+                    }
+                    Some((k, advanced_at)) => {
+                        if m.is_newer(k.incarnation, k.heartbeat) {
+                            *k = m;
+                            *advanced_at = now;
+                        }
+                    }
+                    None => {
+                        if let Some((incarnation, heartbeat, _)) = removeds.get(&m.id) {
+                            if !m.is_newer(*incarnation, *heartbeat) {
+                                continue;
+                            }
+                            removeds.remove(&m.id);
+                        }
+                        discovereds.push(m.clone());
+                        known.insert(m.id, (m, now));
+                    }
+                }
+            }
+        }
+        for m in discovereds {
+            log::info!("discovered node {}, grpc_addr: {}, raft_addr: {}", m.id, m.grpc_addr, m.raft_addr);
+            if self.shared.grpc_client(m.id).is_some() {
+                continue;
+            }
+            match Runtime::instance().node.new_peer_grpc_client(m.id, &m.grpc_addr).await {
+                Ok(client) => self.shared.add_node(m.id, m.grpc_addr.clone(), client),
+                Err(e) => log::warn!("node {} grpc client error, {:?}", m.id, e),
+            }
+        }
+        match duplicate {
+            Some(m) => Err(MqttError::from(format!(
+                "duplicate node id {}, it is also the id of the node at {}",
+                m.id, m.grpc_addr
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    ///Removes the members whose heartbeat has not advanced for dead_timeout
+    fn remove_deads(&self) {
+        let now = chrono::Local::now().timestamp_millis();
+        let dead_timeout = self.cfg.dead_timeout.as_millis() as TimestampMillis;
+        let mut members = self.members.write();
+        let mut removeds = self.removeds.write();
+        removeds.retain(|_, (_, _, removed_at)| now - *removed_at < dead_timeout);
+        members.retain(|id, (m, advanced_at)| {
+            if now - *advanced_at < dead_timeout {
+                return true;
+            }
+            log::info!("removed dead node {}, grpc_addr: {}", id, m.grpc_addr);
+            removeds.insert(*id, (m.incarnation, m.heartbeat, now));
+            false
+        });
+    }
+
+    ///Gossips with a few healthy members, and with the seeds while fewer are known. An error is
+    ///returned if another node has the id of this node. Returns whether a node replied
+    async fn round(&self) -> Result<bool> {
+        self.heartbeat.fetch_add(1, Ordering::SeqCst);
+        self.remove_deads();
+        let mut targets = self
+            .healthy_members()
+            .into_iter()
+            .filter_map(|m| self.shared.grpc_client(m.id).map(|c| (m.grpc_addr.to_string(), c)))
+            .collect::<Vec<_>>();
+        targets.shuffle(&mut rand::thread_rng());
+        targets.truncate(self.cfg.fanout.max(1));
+        if targets.len() < self.cfg.fanout {
+            targets.extend(self.seeds.iter().cloned());
+        }
+
+        let members = self.members();
+        let mut replied = false;
+        for (addr, client) in targets {
+            match self.exchange(&client, members.clone()).await {
+                Ok(members) => {
+                    replied = true;
+                    self.merge(members).await?
+                }
+                Err(e) => log::debug!("gossip with {} error, {:?}", addr, e),
+            }
+        }
+        Ok(replied)
+    }
+
+    #[inline]
+    async fn exchange(&self, client: &NodeGrpcClient, members: Vec<Member>) -> Result<Vec<Member>> {
+        let data = RaftGrpcMessage::Gossip(members).encode()?;
+        match client.send_message(self.message_type, Message::Data(data)).await? {
+            MessageReply::Data(data) => match RaftGrpcMessageReply::decode(&data)? {
+                RaftGrpcMessageReply::Gossip(members) => Ok(members),
+                reply => Err(MqttError::from(format!("unexpected reply, {:?}", reply))),
+            },
+            MessageReply::Error(e) => Err(MqttError::from(e)),
+            reply => Err(MqttError::from(format!("unexpected reply, {:?}", reply))),
+        }
+    }
+
+    ///Gossips until the known members stop changing, or join_timeout, and returns the healthy
+    ///members. None are found by the first node of the cluster. The node does not join if another
+    ///node has its id. A node that reaches no other node starts a new cluster only if it is the first
+    ///seed, the others keep gossiping with the seeds, so that a seed down does not split the cluster
+    pub(crate) async fn bootstrap(&self) -> Result<Vec<Member>> {
+        loop {
+            let started = Instant::now();
+            let mut prev_count = 0;
+            let mut replied = false;
+            while started.elapsed() < self.cfg.join_timeout {
+                replied |= self.round().await?;
+                let count = self.members.read().len();
+                if count > 0 && count == prev_count {
+                    break;
+                }
+                prev_count = count;
+                tokio::time::sleep(self.cfg.interval).await;
+            }
+            let members = self.healthy_members();
+            if replied || !members.is_empty() || self.is_first_seed() {
+                log::info!("bootstrap discovered {} nodes: {:?}", members.len(), members);
+                return Ok(members);
+            }
+            log::warn!(
+                "bootstrap reached no node within {:?}, only the first seed {:?} starts a cluster, retrying",
+                self.cfg.join_timeout,
+                self.cfg.seeds.first()
+            );
+        }
+    }
+
+    ///The first of the seeds, or no seed is configured
+    #[inline]
+    fn is_first_seed(&self) -> bool {
+        self.cfg.seeds.first().map(|seed| seed.as_str() == &*self.local.grpc_addr).unwrap_or(true)
+    }
+
+    ///Keeps gossiping, a node discovered later joins the raft cluster by itself
+    pub(crate) fn start(&'static self) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(self.cfg.interval).await;
+                if let Err(e) = self.round().await {
+                    log::error!("gossip discovery, {}", e);
+                }
+            }
+        });
+    }
+
+    #[inline]
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let healthy = self.healthy_members().into_iter().map(|m| m.id).collect::<Vec<_>>();
+        let members = self
+            .members()
+            .into_iter()
+            .map(|m| {
+                serde_json::json!({
+                    "id": m.id,
+                    "grpc_addr": m.grpc_addr,
+                    "raft_addr": m.raft_addr,
+                    "heartbeat": m.heartbeat,
+                    "incarnation": m.incarnation,
+                    "healthy": m.id == self.local.id || healthy.contains(&m.id),
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!(members)
+    }
+}
+
+static INSTANCE: OnceCell<Discovery> = OnceCell::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newer() {
+        let m = Member {
+            id: 1,
+            grpc_addr: "a:1".into(),
+            raft_addr: "a:2".into(),
+            heartbeat: 5,
+            incarnation: 100,
+        };
+        assert!(m.is_newer(100, 4));
+        assert!(!m.is_newer(100, 5));
+        //restarted, the heartbeat starts over
+        let restarted = Member { heartbeat: 0, incarnation: 200, ..m.clone() };
+        assert!(restarted.is_newer(m.incarnation, m.heartbeat));
+        assert!(!m.is_newer(restarted.incarnation, restarted.heartbeat));
+    }
+}
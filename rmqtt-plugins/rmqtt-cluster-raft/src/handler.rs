@@ -1,17 +1,17 @@
 use rmqtt_raft::Mailbox;
 
 use rmqtt::broker::Shared;
-use rmqtt::rust_box::task_exec_queue::SpawnExt;
-use rmqtt::{async_trait::async_trait, log, tokio, MqttError};
+use rmqtt::{async_trait::async_trait, log, tokio};
 use rmqtt::{
     broker::hook::{Handler, HookResult, Parameter, ReturnType},
     grpc::{Message as GrpcMessage, MessageReply},
     Id, Runtime,
 };
 
-use super::config::{retry, BACKOFF_STRATEGY};
+use super::discovery::Discovery;
+use super::mailbox::MailboxExt;
 use super::message::{Message, RaftGrpcMessage, RaftGrpcMessageReply};
-use super::{hook_message_dropped, retainer::ClusterRetainer, shared::ClusterShared, task_exec_queue};
+use super::{hook_message_dropped, retainer::ClusterRetainer, shared::ClusterShared};
 
 pub(crate) struct HookHandler {
     shared: &'static ClusterShared,
@@ -40,23 +40,7 @@ impl Handler for HookHandler {
                     let msg = Message::Disconnected { id: c.id.clone() }.encode().unwrap();
                     let raft_mailbox = self.raft_mailbox.clone();
                     tokio::spawn(async move {
-                        if let Err(e) = retry(BACKOFF_STRATEGY.clone(), || async {
-                            let msg = msg.clone();
-                            let mailbox = raft_mailbox.clone();
-                            let res = async move { mailbox.send(msg).await }
-                                .spawn(task_exec_queue())
-                                .result()
-                                .await
-                                .map_err(|_| {
-                                    MqttError::from(
-                                        "Handler::hook(Message::Disconnected), task execution failure",
-                                    )
-                                })?
-                                .map_err(|e| MqttError::from(e.to_string()))?;
-                            Ok(res)
-                        })
-                        .await
-                        {
+                        if let Err(e) = raft_mailbox.propose_retry(msg).await {
                             log::warn!(
                                 "HookHandler, Message::Disconnected, raft mailbox send error, {:?}",
                                 e
@@ -70,23 +54,7 @@ impl Handler for HookHandler {
                 let msg = Message::SessionTerminated { id: c.id.clone() }.encode().unwrap();
                 let raft_mailbox = self.raft_mailbox.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = retry(BACKOFF_STRATEGY.clone(), || async {
-                        let msg = msg.clone();
-                        let mailbox = raft_mailbox.clone();
-                        let res = async move { mailbox.send(msg).await }
-                            .spawn(task_exec_queue())
-                            .result()
-                            .await
-                            .map_err(|_| {
-                                MqttError::from(
-                                    "Handler::hook(Message::SessionTerminated), task execution failure",
-                                )
-                            })?
-                            .map_err(|e| MqttError::from(e.to_string()))?;
-                        Ok(res)
-                    })
-                    .await
-                    {
+                    if let Err(e) = raft_mailbox.propose_retry(msg).await {
                         log::warn!(
                             "HookHandler, Message::SessionTerminated, raft mailbox send error, {:?}",
                             e
@@ -102,6 +70,14 @@ impl Handler for HookHandler {
                 }
                 match msg {
                     GrpcMessage::ForwardsTo(from, publish, sub_rels) => {
+                        if self.shared.forward_dedup.is_duplicate(publish) {
+                            log::debug!(
+                                "{:?} duplicate forward is suppressed, forward_id: {:?}",
+                                from,
+                                publish.forward_id
+                            );
+                            return (false, acc);
+                        }
                         if let Err(droppeds) =
                             self.shared.forwards_to(from.clone(), publish, sub_rels.clone()).await
                         {
@@ -152,7 +128,7 @@ impl Handler for HookHandler {
                             }
                             Ok(RaftGrpcMessage::GetRaftStatus) => {
                                 let raft_mailbox = self.raft_mailbox.clone();
-                                match raft_mailbox.status().await {
+                                match raft_mailbox.get_status().await {
                                     Ok(status) => {
                                         match RaftGrpcMessageReply::GetRaftStatus(status).encode() {
                                             Ok(ress) => {
@@ -168,6 +144,24 @@ impl Handler for HookHandler {
                                     }
                                 }
                             }
+                            Ok(RaftGrpcMessage::Gossip(members)) => match Discovery::get() {
+                                Some(discovery) => {
+                                    if let Err(e) = discovery.merge(members).await {
+                                        log::error!("gossip discovery, {}", e);
+                                    }
+                                    match RaftGrpcMessageReply::Gossip(discovery.members()).encode() {
+                                        Ok(ress) => {
+                                            HookResult::GrpcMessageReply(Ok(MessageReply::Data(ress)))
+                                        }
+                                        Err(e) => HookResult::GrpcMessageReply(Ok(MessageReply::Error(
+                                            e.to_string(),
+                                        ))),
+                                    }
+                                }
+                                None => HookResult::GrpcMessageReply(Ok(MessageReply::Error(
+                                    "gossip discovery is not enabled".into(),
+                                ))),
+                            },
                         };
                         return (false, Some(new_acc));
                     }
@@ -0,0 +1,167 @@
+use std::sync::Arc;
+
+use rmqtt::{anyhow, log, reqwest, serde_json, tokio, RwLock};
+use rmqtt::{
+    broker::types::{Addr, NodeId},
+    settings::{Node, NodeAddr},
+    MqttError, Result, Runtime,
+};
+
+use super::config::{Kubernetes, KubernetesSource, PluginConfig};
+use super::shared::ClusterShared;
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+///A pod of the StatefulSet, the node id is its ordinal plus 1
+#[derive(Debug, Clone)]
+pub(crate) struct Peer {
+    pub id: NodeId,
+    pub grpc_addr: Addr,
+    pub raft_addr: Addr,
+}
+
+impl Peer {
+    #[inline]
+    pub(crate) fn grpc_node_addr(&self) -> NodeAddr {
+        NodeAddr { id: self.id, addr: self.grpc_addr.clone() }
+    }
+
+    #[inline]
+    pub(crate) fn raft_node_addr(&self) -> NodeAddr {
+        NodeAddr { id: self.id, addr: self.raft_addr.clone() }
+    }
+}
+
+///Lists the pods of the StatefulSet, this pod included
+pub(crate) async fn peers(cfg: &Kubernetes) -> Result<Vec<Peer>> {
+    let statefulset = match cfg.statefulset.as_ref() {
+        Some(statefulset) => statefulset.clone(),
+        None => Node::pod_ordinal().map(|(statefulset, _)| statefulset).ok_or_else(|| {
+            MqttError::from("HOSTNAME is not of a StatefulSet pod, set discovery.kubernetes.statefulset")
+        })?,
+    };
+    let namespace = match cfg.namespace.as_ref() {
+        Some(namespace) => namespace.clone(),
+        None => read_service_account("namespace").await?,
+    };
+    let ordinals = match cfg.source {
+        KubernetesSource::Dns => (0..cfg.replicas).collect::<Vec<_>>(),
+        KubernetesSource::Api => endpoint_ordinals(cfg, &namespace, &statefulset).await?,
+    };
+    let grpc_port = cfg.grpc_port.unwrap_or_else(|| Runtime::instance().settings.rpc.server_addr.port());
+    let peers = ordinals
+        .into_iter()
+        .map(|ordinal| {
+            let host = format!(
+                "{}-{}.{}.{}.svc.{}",
+                statefulset, ordinal, cfg.service, namespace, cfg.cluster_domain
+            );
+            Peer {
+                id: ordinal + 1,
+                grpc_addr: Addr::from(format!("{}:{}", host, grpc_port)),
+                raft_addr: Addr::from(format!("{}:{}", host, cfg.raft_port)),
+            }
+        })
+        .collect::<Vec<_>>();
+    log::debug!("kubernetes peers: {:?}", peers);
+    Ok(peers)
+}
+
+///Adds the pods not known yet to the nodes of the cluster, a pod started later joins the raft
+///cluster by itself
+pub(crate) async fn add_peers(
+    shared: &'static ClusterShared,
+    cfg: &Arc<RwLock<PluginConfig>>,
+    peers: Vec<Peer>,
+) {
+    let id = Runtime::instance().node.id();
+    for peer in peers {
+        if peer.id != id && shared.grpc_client(peer.id).is_none() {
+            match Runtime::instance().node.new_peer_grpc_client(peer.id, &peer.grpc_addr).await {
+                Ok(client) => {
+                    log::info!("kubernetes peer {} added, {}", peer.id, peer.grpc_addr);
+                    shared.add_node(peer.id, peer.grpc_addr.clone(), client)
+                }
+                Err(e) => {
+                    log::warn!("kubernetes peer {} grpc client error, {:?}", peer.id, e);
+                    continue;
+                }
+            }
+        }
+        let mut cfg = cfg.write();
+        if !cfg.node_grpc_addrs.iter().any(|a| a.id == peer.id) {
+            cfg.node_grpc_addrs.push(peer.grpc_node_addr());
+        }
+        if !cfg.raft_peer_addrs.iter().any(|a| a.id == peer.id) {
+            cfg.raft_peer_addrs.push(peer.raft_node_addr());
+        }
+    }
+}
+
+///Lists the pods periodically
+pub(crate) fn start_refresh(shared: &'static ClusterShared, cfg: Arc<RwLock<PluginConfig>>) {
+    tokio::spawn(async move {
+        loop {
+            let k8s_cfg = cfg.read().discovery.kubernetes.clone();
+            tokio::time::sleep(k8s_cfg.refresh_interval).await;
+            match peers(&k8s_cfg).await {
+                Ok(peers) => add_peers(shared, &cfg, peers).await,
+                Err(e) => log::warn!("list kubernetes peers error, {:?}", e),
+            }
+        }
+    });
+}
+
+///Ordinals of the pods of the endpoints of the service, ready or not, as the nodes find each
+///other before they are ready
+async fn endpoint_ordinals(cfg: &Kubernetes, namespace: &str, statefulset: &str) -> Result<Vec<u64>> {
+    let token = read_service_account("token").await?;
+    let ca = tokio::fs::read(format!("{}/ca.crt", SERVICE_ACCOUNT_DIR)).await?;
+    let client = reqwest::Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_pem(&ca).map_err(anyhow::Error::new)?)
+        .build()
+        .map_err(anyhow::Error::new)?;
+    let url = format!("{}/api/v1/namespaces/{}/endpoints/{}", cfg.api_server, namespace, cfg.service);
+    let endpoints = client
+        .get(&url)
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(anyhow::Error::new)?
+        .error_for_status()
+        .map_err(anyhow::Error::new)?
+        .json::<serde_json::Value>()
+        .await
+        .map_err(anyhow::Error::new)?;
+
+    let prefix = format!("{}-", statefulset);
+    let mut ordinals = Vec::new();
+    for subset in endpoints["subsets"].as_array().into_iter().flatten() {
+        let addresses = subset["addresses"].as_array().into_iter().flatten();
+        let not_ready_addresses = subset["notReadyAddresses"].as_array().into_iter().flatten();
+        for address in addresses.chain(not_ready_addresses) {
+            let ordinal = address["hostname"]
+                .as_str()
+                .and_then(|hostname| hostname.strip_prefix(&prefix))
+                .and_then(|ordinal| ordinal.parse::<u64>().ok());
+            if let Some(ordinal) = ordinal {
+                ordinals.push(ordinal);
+            }
+        }
+    }
+    //this pod may not be listed yet
+    if let Some((_, ordinal)) = Node::pod_ordinal() {
+        ordinals.push(ordinal);
+    }
+    ordinals.sort_unstable();
+    ordinals.dedup();
+    Ok(ordinals)
+}
+
+#[inline]
+async fn read_service_account(name: &str) -> Result<String> {
+    let content = tokio::fs::read_to_string(format!("{}/{}", SERVICE_ACCOUNT_DIR, name))
+        .await
+        .map_err(|e| MqttError::from(format!("read service account {}, {:?}", name, e)))?;
+    Ok(content.trim().to_owned())
+}
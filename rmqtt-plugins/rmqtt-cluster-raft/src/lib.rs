@@ -2,14 +2,17 @@
 #[macro_use]
 extern crate serde;
 
-use rmqtt_raft::{Mailbox, Raft};
+use rmqtt_raft::{Mailbox, Raft, Store};
 use std::convert::From as _f;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 
-use config::PluginConfig;
+use config::{DiscoveryMode, PluginConfig};
+use discovery::Discovery;
 use handler::HookHandler;
+use mailbox::{init_mailbox_config, MailboxExt};
 use retainer::ClusterRetainer;
 use rmqtt::{
     ahash, anyhow,
@@ -20,12 +23,18 @@ use rmqtt::{
 };
 use rmqtt::{
     broker::{
+        dedup::ForwardDedup,
         error::MqttError,
         hook::{Register, Type},
         types::{From, Publish, Reason, To},
     },
-    grpc::{client::NodeGrpcClient, GrpcClients, Message, MessageReply, MessageType},
-    plugin::{DynPlugin, DynPluginResult, Plugin},
+    grpc::{
+        client::NodeGrpcClient, codec::Codec, registry::MessageTypes, GrpcClients, Message, MessageReply,
+        MessageType,
+    },
+    plugin::{DynPlugin, DynPluginResult, Metric, Plugin},
+    settings::schema::ConfigSchema,
+    settings::NodeAddr,
     tokio::time::sleep,
     Result, Runtime,
 };
@@ -33,15 +42,21 @@ use rmqtt::{
     once_cell::sync::OnceCell,
     rust_box::task_exec_queue::{Builder, TaskExecQueue},
 };
-use router::ClusterRouter;
+use router::{ClusterRouter, ShardStore};
 use shared::ClusterShared;
+use standalone::LocalState;
 
 mod config;
+mod discovery;
 mod handler;
+mod kubernetes;
+mod mailbox;
 mod message;
+mod partition;
 mod retainer;
 mod router;
 mod shared;
+mod standalone;
 
 type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
 
@@ -70,12 +85,13 @@ struct ClusterPlugin {
     descr: String,
     register: Box<dyn Register>,
     cfg: Arc<RwLock<PluginConfig>>,
-    grpc_clients: GrpcClients,
     shared: &'static ClusterShared,
     retainer: &'static ClusterRetainer,
 
     router: &'static ClusterRouter,
     raft_mailbox: Option<Mailbox>,
+    //not empty if the plugin is loaded at runtime, converting a standalone broker to a cluster node
+    local_state: LocalState,
 }
 
 impl ClusterPlugin {
@@ -85,14 +101,14 @@ impl ClusterPlugin {
         let mut cfg = runtime
             .settings
             .plugins
-            .load_config::<PluginConfig>(&name)
-            .map_err(|e| MqttError::from(e.to_string()))?;
+            .load_config_with_schema::<PluginConfig>(&name, &PluginConfig::schema())?;
         log::info!("{} ClusterPlugin cfg: {:?}", name, cfg);
-        cfg.merge(&runtime.settings.opts);
+        cfg.merge(&runtime.settings.opts, runtime.node.id());
 
         init_task_exec_queue(cfg.task_exec_queue_workers, cfg.task_exec_queue_max);
+        init_mailbox_config(cfg.mailbox.clone());
 
-        let register = runtime.extends.hook_mgr().await.register();
+        let register = runtime.extends.hook_mgr().await.register_for(&name);
         let mut grpc_clients = HashMap::default();
         let mut node_names = HashMap::default();
 
@@ -102,15 +118,27 @@ impl ClusterPlugin {
             if node_addr.id != runtime.node.id() {
                 grpc_clients.insert(
                     node_addr.id,
-                    (node_addr.addr.clone(), runtime.node.new_grpc_client(&node_addr.addr).await?),
+                    (
+                        node_addr.addr.clone(),
+                        runtime.node.new_peer_grpc_client(node_addr.id, &node_addr.addr).await?,
+                    ),
                 );
             }
             node_names.insert(node_addr.id, format!("{}@{}", node_addr.id, node_addr.addr));
         }
         let grpc_clients = Arc::new(grpc_clients);
-        let router = ClusterRouter::get_or_init(cfg.try_lock_timeout);
-        let shared = ClusterShared::get_or_init(router, grpc_clients.clone(), node_names, cfg.message_type);
-        let retainer = ClusterRetainer::get_or_init(grpc_clients.clone(), cfg.message_type);
+        let router =
+            ClusterRouter::get_or_init(cfg.try_lock_timeout, cfg.raft_groups, cfg.routing_read.max_lag);
+        let shared = ClusterShared::get_or_init(
+            router,
+            grpc_clients.clone(),
+            node_names,
+            cfg.message_type,
+            cfg.partition.clone(),
+            ForwardDedup::new(cfg.forward_dedup_window, cfg.forward_dedup_max),
+            cfg.subscription_limits.clone(),
+        );
+        let retainer = ClusterRetainer::get_or_init(shared, cfg.message_type);
         let raft_mailbox = None;
         let cfg = Arc::new(RwLock::new(cfg));
         Ok(Self {
@@ -119,16 +147,20 @@ impl ClusterPlugin {
             descr: descr.into(),
             register,
             cfg,
-            grpc_clients,
             shared,
             retainer,
             router,
             raft_mailbox,
+            local_state: LocalState::default(),
         })
     }
 
     //raft init ...
-    async fn start_raft(cfg: Arc<RwLock<PluginConfig>>, router: &'static ClusterRouter) -> Result<Mailbox> {
+    async fn start_raft<S: Store + Send + Sync + 'static>(
+        cfg: Arc<RwLock<PluginConfig>>,
+        store: S,
+        group: usize,
+    ) -> Result<Mailbox> {
         let raft_peer_addrs = cfg.read().raft_peer_addrs.clone();
 
         let id = Runtime::instance().node.id();
@@ -138,28 +170,30 @@ impl ClusterPlugin {
             .map(|peer| peer.addr.to_string())
             .ok_or_else(|| MqttError::from("raft listening address does not exist"))?;
         let logger = Runtime::instance().logger.clone();
-        log::info!("raft_laddr: {:?}", raft_laddr);
 
         //verify the listening address
-        parse_addr(&raft_laddr).await?;
+        let raft_laddr = group_addr(&cfg, parse_addr(&raft_laddr).await?, group)?.to_string();
+        log::info!("raft group: {}, raft_laddr: {:?}", group, raft_laddr);
 
-        let raft = Raft::new(raft_laddr, router, logger, cfg.read().raft.to_raft_config())
+        let raft = Raft::new(raft_laddr, store, logger, cfg.read().raft.to_raft_config())
             .map_err(|e| MqttError::Error(Box::new(e)))?;
         let mailbox = raft.mailbox();
 
         let mut peer_addrs = Vec::new();
         for peer in raft_peer_addrs.iter() {
             if peer.id != id {
-                peer_addrs.push(parse_addr(&peer.addr).await?.to_string())
+                peer_addrs.push(group_addr(&cfg, parse_addr(&peer.addr).await?, group)?.to_string())
             }
         }
-        log::info!("peer_addrs: {:?}", peer_addrs);
+        log::info!("raft group: {}, peer_addrs: {:?}", group, peer_addrs);
 
         let leader_info =
             raft.find_leader_info(peer_addrs).await.map_err(|e| MqttError::Error(Box::new(e)))?;
 
+        let thread_name =
+            if group == 0 { "cluster-raft".to_string() } else { format!("cluster-raft-{}", group) };
         //        let (status_tx, status_rx) = futures::channel::oneshot::channel::<Result<Status>>();
-        let _child = std::thread::Builder::new().name("cluster-raft".to_string()).spawn(move || {
+        let _child = std::thread::Builder::new().name(thread_name).spawn(move || {
             let rt = tokio::runtime::Builder::new_current_thread()
                 .enable_all()
                 .worker_threads(8)
@@ -206,6 +240,57 @@ impl ClusterPlugin {
             .await;
     }
 
+    async fn wait_started(&self, mailbox: &Mailbox, group: usize) {
+        for i in 0..30 {
+            match mailbox.status().await {
+                Ok(status) => {
+                    if status.is_started() {
+                        break;
+                    }
+                    log::info!(
+                        "{} Initializing cluster, raft group: {}, status({}): {:?}",
+                        self.name,
+                        group,
+                        i,
+                        status
+                    );
+                }
+                Err(e) => {
+                    log::info!("{} init error, raft group: {}, {:?}", self.name, group, e);
+                }
+            }
+            sleep(Duration::from_millis(500)).await;
+        }
+    }
+
+    ///Learns the other nodes from the seeds, they are added to the raft peers the node joins through
+    async fn discover(&self) -> Result<&'static Discovery> {
+        let (discovery_cfg, message_type, grpc_addr, raft_addr) = {
+            let cfg = self.cfg.read();
+            let id = self.runtime.node.id();
+            let grpc_addr = cfg.node_grpc_addrs.iter().find(|a| a.id == id).map(|a| a.addr.clone());
+            let raft_addr = cfg.raft_peer_addrs.iter().find(|a| a.id == id).map(|a| a.addr.clone());
+            (cfg.discovery.clone(), cfg.message_type, grpc_addr, raft_addr)
+        };
+        let grpc_addr =
+            grpc_addr.ok_or_else(|| MqttError::from("grpc address of this node does not exist"))?;
+        let raft_addr =
+            raft_addr.ok_or_else(|| MqttError::from("raft address of this node does not exist"))?;
+        let discovery =
+            Discovery::init(discovery_cfg, message_type, self.shared, grpc_addr, raft_addr).await?;
+        let members = discovery.bootstrap().await?;
+        let mut cfg = self.cfg.write();
+        for m in members {
+            if !cfg.node_grpc_addrs.iter().any(|a| a.id == m.id) {
+                cfg.node_grpc_addrs.push(NodeAddr { id: m.id, addr: m.grpc_addr });
+            }
+            if !cfg.raft_peer_addrs.iter().any(|a| a.id == m.id) {
+                cfg.raft_peer_addrs.push(NodeAddr { id: m.id, addr: m.raft_addr });
+            }
+        }
+        Ok(discovery)
+    }
+
     fn raft_mailbox(&self) -> Mailbox {
         if let Some(raft_mailbox) = &self.raft_mailbox {
             raft_mailbox.clone()
@@ -220,31 +305,56 @@ impl Plugin for ClusterPlugin {
     #[inline]
     async fn init(&mut self) -> Result<()> {
         log::info!("{} init", self.name);
+        MessageTypes::instance().register(&self.name, self.cfg.read().message_type)?;
+        Codec::set_current(self.cfg.read().message_codec);
+
+        let (apply_pipeline_capacity, apply_batch_size) = {
+            let cfg = self.cfg.read();
+            (cfg.apply_pipeline_capacity, cfg.apply_batch_size)
+        };
+        self.router.start_apply_pipeline(apply_pipeline_capacity, apply_batch_size);
+
+        let mode = self.cfg.read().discovery.mode;
+        let discovery = if mode == DiscoveryMode::Gossip { Some(self.discover().await?) } else { None };
+        if mode == DiscoveryMode::Kubernetes {
+            let k8s_cfg = self.cfg.read().discovery.kubernetes.clone();
+            let peers = kubernetes::peers(&k8s_cfg).await?;
+            log::info!("{} kubernetes peers: {:?}", self.name, peers);
+            kubernetes::add_peers(self.shared, &self.cfg, peers).await;
+        }
 
-        let raft_mailbox = Self::start_raft(self.cfg.clone(), self.router).await?;
+        self.cfg.read().check_group_ports()?;
+        self.local_state = LocalState::take();
 
-        for i in 0..30 {
-            match raft_mailbox.status().await {
-                Ok(status) => {
-                    if status.is_started() {
-                        break;
-                    }
-                    log::info!("{} Initializing cluster, raft status({}): {:?}", self.name, i, status);
-                }
-                Err(e) => {
-                    log::info!("{} init error, {:?}", self.name, e);
-                }
-            }
-            sleep(Duration::from_millis(500)).await;
-        }
+        let raft_mailbox = Self::start_raft(self.cfg.clone(), self.router, 0).await?;
+        self.wait_started(&raft_mailbox, 0).await;
 
         self.raft_mailbox.replace(raft_mailbox.clone());
         self.router.set_raft_mailbox(raft_mailbox).await;
 
+        //the subscriptions of the other shards are kept by their own raft group
+        let raft_groups = self.cfg.read().raft_groups;
+        for group in 1..raft_groups {
+            let store = ShardStore { router: self.router, shard: group };
+            let shard_mailbox = Self::start_raft(self.cfg.clone(), store, group).await?;
+            self.wait_started(&shard_mailbox, group).await;
+            self.router.add_shard_mailbox(shard_mailbox).await;
+        }
+        let routing_lag_check_interval = self.cfg.read().routing_read.check_interval;
+        self.router.start_lag_check(routing_lag_check_interval);
+        self.shared.start_limit_check();
+
         self.hook_register(Type::ClientDisconnected).await;
         self.hook_register(Type::SessionTerminated).await;
         self.hook_register(Type::GrpcMessageReceived).await;
 
+        if let Some(discovery) = discovery {
+            discovery.start();
+        }
+        if mode == DiscoveryMode::Kubernetes {
+            kubernetes::start_refresh(self.shared, self.cfg.clone());
+        }
+
         Ok(())
     }
 
@@ -258,20 +368,38 @@ impl Plugin for ClusterPlugin {
         self.cfg.read().to_json()
     }
 
+    #[inline]
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(PluginConfig::schema())
+    }
+
     #[inline]
     async fn start(&mut self) -> Result<()> {
         log::info!("{} start", self.name);
         let raft_mailbox = self.raft_mailbox();
         *self.runtime.extends.router_mut().await = Box::new(self.router);
         *self.runtime.extends.shared_mut().await = Box::new(self.shared);
+        //taken again after the swap, for the sessions and subscriptions made since init, those
+        //taken in init are kept as a snapshot restored from the cluster may have replaced them
+        let mut local_state = LocalState::take();
+        local_state.merge(std::mem::take(&mut self.local_state));
+        if !local_state.is_empty() {
+            local_state.import(self.router).await?;
+        }
         self.register.start().await;
-        let status = raft_mailbox.status().await.map_err(anyhow::Error::new)?;
+        let status = raft_mailbox.get_status().await?;
         log::info!("raft status: {:?}", status);
-        if status.is_started() {
-            Ok(())
-        } else {
-            Err(MqttError::from("Raft cluster status is abnormal"))
+        if !status.is_started() {
+            return Err(MqttError::from("Raft cluster status is abnormal"));
         }
+        for (i, shard_mailbox) in self.router.shard_mailboxes().await.iter().enumerate() {
+            let status = shard_mailbox.get_status().await?;
+            log::info!("raft group: {}, status: {:?}", i + 1, status);
+            if !status.is_started() {
+                return Err(MqttError::from(format!("Raft group {} status is abnormal", i + 1)));
+            }
+        }
+        Ok(())
     }
 
     #[inline]
@@ -291,41 +419,125 @@ impl Plugin for ClusterPlugin {
     }
 
     #[inline]
-    async fn attrs(&self) -> serde_json::Value {
-        let raft_mailbox = self.raft_mailbox();
-        let raft_status = raft_mailbox.status().await.ok();
-
-        let mut pears = HashMap::default();
-        for (id, p) in raft_mailbox.pears() {
-            let stats = json!({
-                "active_tasks": p.active_tasks(),
-                "grpc_fails": p.grpc_fails(),
-            });
-            pears.insert(id, stats);
-        }
+    fn reloadable(&self) -> bool {
+        false
+    }
 
-        let mut nodes = HashMap::default();
-        for (node_id, (_, c)) in self.grpc_clients.iter() {
-            let stats = json!({
-                "channel_tasks": c.channel_tasks(),
-                "active_tasks": c.active_tasks(),
-            });
-            nodes.insert(*node_id, stats);
+    ///Leaves the raft groups, the peers elect a new leader if this node was the leader and no
+    ///longer count it in the quorum, the node is to be shut down afterwards
+    #[inline]
+    async fn evacuate(&self) -> Result<()> {
+        for shard_mailbox in self.router.shard_mailboxes().await {
+            shard_mailbox.leave().await.map_err(anyhow::Error::new)?;
         }
+        self.raft_mailbox().leave().await.map_err(anyhow::Error::new)?;
+        log::warn!("{} the node has left the raft cluster", self.name);
+        Ok(())
+    }
 
-        let exec = task_exec_queue();
+    #[inline]
+    async fn attrs(&self) -> serde_json::Value {
+        let raft_status = self.raft_mailbox().get_status().await.ok();
+        let mut shard_status = Vec::new();
+        for shard_mailbox in self.router.shard_mailboxes().await {
+            shard_status.push(shard_mailbox.get_status().await.ok());
+        }
         json!({
-            "grpc_clients": nodes,
             "raft_status": raft_status,
-            "raft_pears": pears,
-            "client_states": self.router.states_count(),
-            "task_exec_queue": {
-                "waiting_count": exec.waiting_count(),
-                "active_count": exec.active_count(),
-                "completed_count": exec.completed_count(),
-            }
+            "raft_groups": shard_status.len() + 1,
+            "shard_status": shard_status,
+            "discovered_members": Discovery::get().map(|d| d.to_json()),
         })
     }
+
+    #[inline]
+    async fn metrics(&self) -> Vec<Metric> {
+        let mut metrics = Vec::new();
+        for (id, p) in self.raft_mailbox().pears() {
+            metrics.push(
+                Metric::gauge("raft_peer_active_tasks", p.active_tasks() as f64)
+                    .label("peer", id.to_string())
+                    .descr("Raft messages being sent to the peer node"),
+            );
+            metrics.push(
+                Metric::counter("raft_peer_grpc_fails", p.grpc_fails() as f64)
+                    .label("peer", id.to_string())
+                    .descr("Raft messages failed to be sent to the peer node"),
+            );
+        }
+        for (id, (_, c)) in self.shared.grpc_clients().iter() {
+            metrics.push(
+                Metric::gauge("grpc_client_channel_tasks", c.channel_tasks() as f64)
+                    .label("peer", id.to_string())
+                    .descr("Messages queued for the peer node"),
+            );
+            metrics.push(
+                Metric::gauge("grpc_client_active_tasks", c.active_tasks() as f64)
+                    .label("peer", id.to_string())
+                    .descr("Messages being sent to the peer node"),
+            );
+        }
+        metrics.push(
+            Metric::gauge("forward_buffered_messages", self.shared.partitioner.buffered_count() as f64)
+                .descr("Publishes buffered for the nodes that cannot be reached"),
+        );
+        if let Some(discovery) = Discovery::get() {
+            metrics.push(
+                Metric::gauge("discovery_healthy_members", discovery.healthy_members().len() as f64)
+                    .descr("Other nodes found by gossip whose heartbeat is advancing"),
+            );
+        }
+        let exec = task_exec_queue();
+        metrics.push(
+            Metric::gauge("client_states", self.router.states_count() as f64)
+                .descr("Client states in the raft state machine"),
+        );
+        metrics.push(
+            Metric::gauge("subscriptions", self.router.relations_count() as f64)
+                .descr("Subscriptions of the cluster in the raft state machine"),
+        );
+        metrics.push(
+            Metric::counter(
+                "subscriptions_rejected",
+                self.shared.subscriptions_rejected.load(Ordering::SeqCst) as f64,
+            )
+            .descr("Subscriptions refused by this node because a subscription limit was reached"),
+        );
+        metrics.push(
+            Metric::gauge("apply_pipeline_len", self.router.apply_pipeline_len() as f64)
+                .descr("Raft entries waiting to be applied"),
+        );
+        for (group, lag) in self.router.routing_lags().into_iter().enumerate() {
+            metrics.push(
+                Metric::gauge("routing_lag", lag as f64)
+                    .label("group", group.to_string())
+                    .descr("Raft entries the local state of the group is behind its leader, as last checked"),
+            );
+        }
+        metrics.push(
+            Metric::counter(
+                "routing_leader_reads",
+                self.router.routing_leader_reads.load(Ordering::SeqCst) as f64,
+            )
+            .descr("Routing lookups read from the leader of a raft group too far behind"),
+        );
+        metrics.push(Metric::gauge("tasks_waiting", exec.waiting_count() as f64));
+        metrics.push(Metric::gauge("tasks_active", exec.active_count() as f64));
+        metrics.push(Metric::counter("tasks_completed", exec.completed_count() as f64));
+        metrics
+    }
+}
+
+///Address of a raft group, the port of the address of group 0 plus the group times
+///raft_group_port_step
+#[inline]
+fn group_addr(cfg: &RwLock<PluginConfig>, mut addr: SocketAddr, group: usize) -> Result<SocketAddr> {
+    let port = cfg
+        .read()
+        .group_port(addr.port(), group)
+        .ok_or_else(|| MqttError::from(format!("raft group {} port out of range, {}", group, addr)))?;
+    addr.set_port(port);
+    Ok(addr)
 }
 
 async fn parse_addr(addr: &str) -> Result<SocketAddr> {
@@ -0,0 +1,121 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use rmqtt_raft::{Mailbox, Status};
+
+use rmqtt::rust_box::task_exec_queue::SpawnExt;
+use rmqtt::{anyhow, async_trait::async_trait, log, once_cell::sync::OnceCell, tokio, MqttError, Result};
+
+use super::config::{retry, MailboxConfig};
+use super::task_exec_queue;
+
+static MAILBOX_CONFIG: OnceCell<MailboxConfig> = OnceCell::new();
+static PENDING_PROPOSALS: AtomicUsize = AtomicUsize::new(0);
+
+#[inline]
+pub(crate) fn init_mailbox_config(cfg: MailboxConfig) {
+    MAILBOX_CONFIG.set(cfg).ok();
+}
+
+#[inline]
+fn mailbox_config() -> &'static MailboxConfig {
+    MAILBOX_CONFIG.get_or_init(MailboxConfig::default)
+}
+
+///The requests to a raft group, with the timeouts, retries and limits of MailboxConfig
+#[async_trait]
+pub(crate) trait MailboxExt {
+    ///Proposes the message to the raft group and waits for it to be committed
+    async fn propose(&self, msg: Vec<u8>) -> Result<Vec<u8>>;
+
+    ///Proposes the message, retrying with the backoff of MailboxConfig until it is committed
+    async fn propose_retry(&self, msg: Vec<u8>) -> Result<Vec<u8>>;
+
+    ///Reads the raft state machine
+    async fn read(&self, msg: Vec<u8>) -> Result<Vec<u8>>;
+
+    async fn get_status(&self) -> Result<Status>;
+}
+
+#[async_trait]
+impl MailboxExt for Mailbox {
+    #[inline]
+    async fn propose(&self, msg: Vec<u8>) -> Result<Vec<u8>> {
+        let cfg = mailbox_config();
+        let _pending = PendingGuard::acquire(cfg.max_pending_proposals)?;
+        with_timeout(cfg.propose_timeout, self.send(msg)).await
+    }
+
+    #[inline]
+    async fn propose_retry(&self, msg: Vec<u8>) -> Result<Vec<u8>> {
+        let cfg = mailbox_config();
+        let retries = AtomicUsize::new(0);
+        retry(cfg.backoff(), || async {
+            let msg = msg.clone();
+            let mailbox = self.clone();
+            let res = async move { mailbox.propose(msg).await }
+                .spawn(task_exec_queue())
+                .result()
+                .await
+                .map_err(|_| MqttError::from("raft propose, task execution failure"))?;
+            match res {
+                Ok(reply) => Ok(reply),
+                Err(e) => {
+                    let n = retries.fetch_add(1, Ordering::SeqCst);
+                    if cfg.max_retries > 0 && n >= cfg.max_retries {
+                        Err(backoff::Error::permanent(e))
+                    } else {
+                        log::debug!("raft propose failed, retries: {}, {:?}", n, e);
+                        Err(backoff::Error::transient(e))
+                    }
+                }
+            }
+        })
+        .await
+    }
+
+    #[inline]
+    async fn read(&self, msg: Vec<u8>) -> Result<Vec<u8>> {
+        with_timeout(mailbox_config().query_timeout, self.query(msg)).await
+    }
+
+    #[inline]
+    async fn get_status(&self) -> Result<Status> {
+        with_timeout(mailbox_config().status_timeout, self.status()).await
+    }
+}
+
+#[inline]
+async fn with_timeout<T, F>(timeout: Duration, f: F) -> Result<T>
+where
+    F: Future<Output = rmqtt_raft::Result<T>>,
+{
+    let res = if timeout.is_zero() {
+        f.await
+    } else {
+        tokio::time::timeout(timeout, f).await.map_err(|_| MqttError::Timeout(timeout))?
+    };
+    Ok(res.map_err(anyhow::Error::new)?)
+}
+
+struct PendingGuard;
+
+impl PendingGuard {
+    #[inline]
+    fn acquire(max_pending: usize) -> Result<Self> {
+        let pending = PENDING_PROPOSALS.fetch_add(1, Ordering::SeqCst);
+        let guard = PendingGuard;
+        if max_pending > 0 && pending >= max_pending {
+            return Err(MqttError::from(format!("too many pending raft proposals, max: {}", max_pending)));
+        }
+        Ok(guard)
+    }
+}
+
+impl Drop for PendingGuard {
+    #[inline]
+    fn drop(&mut self) {
+        PENDING_PROPOSALS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
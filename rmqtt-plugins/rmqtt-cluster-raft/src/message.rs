@@ -1,9 +1,13 @@
 use rmqtt_raft::Status;
 
-use rmqtt::broker::types::{Id, NodeId, QoS, SharedGroup};
+use rmqtt::broker::types::{Id, NodeId, QoS, SharedGroup, TopicFilter};
+use rmqtt::broker::SubRelationsMap;
+use rmqtt::grpc::codec;
+use rmqtt::settings::DuplicateClientId;
 use rmqtt::Result;
-use rmqtt::{anyhow, bincode};
 
+use super::discovery::Member;
+use super::mailbox::MailboxExt;
 use super::Mailbox;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -16,16 +20,26 @@ pub enum Message<'a> {
     Remove { topic_filter: &'a str, id: Id },
     //get client node id
     GetClientNodeId { client_id: &'a str },
+    //lock the client_id of a new connection, resolving a client_id in use by the policy
+    HandshakeTryLockConnect { id: Id, policy: DuplicateClientId },
+    //number of the entries applied by the raft group
+    AppliedIndex,
+    //the subscriptions of the raft group matching the topic
+    Matches { topic: &'a str },
+    //the sessions of a standalone broker converted to a cluster node, (id, connected)
+    ImportSessions { sessions: Vec<(Id, bool)> },
+    //the subscriptions of a standalone broker converted to a cluster node
+    ImportSubscriptions { subscriptions: Vec<(TopicFilter, Id, QoS, Option<SharedGroup>)> },
 }
 
 impl<'a> Message<'a> {
     #[inline]
     pub fn encode(&self) -> Result<Vec<u8>> {
-        Ok(bincode::serialize(self).map_err(anyhow::Error::new)?)
+        codec::encode(self)
     }
     #[inline]
     pub fn _decode(data: &'a [u8]) -> Result<Self> {
-        Ok(bincode::deserialize::<Self>(data).map_err(anyhow::Error::new)?)
+        codec::decode(data)
     }
 }
 
@@ -33,58 +47,79 @@ impl<'a> Message<'a> {
 pub enum MessageReply {
     Error(String),
     HandshakeTryLock(Option<Id>),
+    //the previous id of the client_id, the id the connection continues with
+    HandshakeTryLockConnect { prev_id: Option<Id>, id: Id },
+    ClientIdInUse,
 }
 
 impl MessageReply {
     #[inline]
     pub fn encode(&self) -> Result<Vec<u8>> {
-        Ok(bincode::serialize(self).map_err(anyhow::Error::new)?)
+        codec::encode(self)
     }
     #[inline]
     pub fn decode(data: &[u8]) -> Result<MessageReply> {
-        Ok(bincode::deserialize::<MessageReply>(data).map_err(anyhow::Error::new)?)
+        codec::decode(data)
     }
 }
 
 #[inline]
 pub(crate) async fn get_client_node_id(raft_mailbox: Mailbox, client_id: &str) -> Result<Option<NodeId>> {
     let msg = Message::GetClientNodeId { client_id }.encode()?;
-    let reply = raft_mailbox.query(msg).await.map_err(anyhow::Error::new)?;
+    let reply = raft_mailbox.read(msg).await?;
     if !reply.is_empty() {
-        Ok(bincode::deserialize(&reply).map_err(anyhow::Error::new)?)
+        codec::decode(&reply)
     } else {
         Ok(None)
     }
 }
 
+///Number of the entries applied by the leader of the raft group of the mailbox
+#[inline]
+pub(crate) async fn get_applied_index(mailbox: Mailbox) -> Result<u64> {
+    let reply = mailbox.read(Message::AppliedIndex.encode()?).await?;
+    codec::decode(&reply)
+}
+
+///The subscriptions matching the topic, as known by the leader of the raft group of the mailbox
+#[inline]
+pub(crate) async fn get_matches(mailbox: Mailbox, topic: &str) -> Result<SubRelationsMap> {
+    let reply = mailbox.read(Message::Matches { topic }.encode()?).await?;
+    codec::decode(&reply)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum RaftGrpcMessage {
     GetRaftStatus,
+    //the members known by the sender
+    Gossip(Vec<Member>),
 }
 
 impl RaftGrpcMessage {
     #[inline]
     pub fn encode(&self) -> Result<Vec<u8>> {
-        Ok(bincode::serialize(self).map_err(anyhow::Error::new)?)
+        codec::encode(self)
     }
     #[inline]
     pub fn decode(data: &[u8]) -> Result<Self> {
-        Ok(bincode::deserialize::<Self>(data).map_err(anyhow::Error::new)?)
+        codec::decode(data)
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum RaftGrpcMessageReply {
     GetRaftStatus(Status),
+    //the members known by the receiver
+    Gossip(Vec<Member>),
 }
 
 impl RaftGrpcMessageReply {
     #[inline]
     pub fn encode(&self) -> Result<Vec<u8>> {
-        Ok(bincode::serialize(self).map_err(anyhow::Error::new)?)
+        codec::encode(self)
     }
     #[inline]
     pub fn decode(data: &[u8]) -> Result<Self> {
-        Ok(bincode::deserialize::<Self>(data).map_err(anyhow::Error::new)?)
+        codec::decode(data)
     }
 }
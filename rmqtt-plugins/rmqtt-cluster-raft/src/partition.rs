@@ -0,0 +1,227 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rmqtt::{ahash, chrono, dashmap, log, tokio, RwLock};
+use rmqtt::{
+    broker::{
+        metrics::DroppedReason,
+        types::{From, Id, NodeId, Publish, Reason, TimestampMillis, To},
+        SubRelations,
+    },
+    grpc::Message,
+    MqttError, Result,
+};
+
+use super::config::{Partition, PartitionMode};
+use super::discovery::Discovery;
+use super::mailbox::MailboxExt;
+use super::message::Message as RaftMessage;
+use super::shared::ClusterShared;
+use super::{hook_message_dropped, ClusterRouter, MessageSender};
+
+type DashMap<K, V> = dashmap::DashMap<K, V, ahash::RandomState>;
+
+type Forward = (From, Publish, SubRelations);
+
+#[derive(Default)]
+struct ForwardBuffer {
+    ///The forwards with the time they were buffered
+    forwards: RwLock<VecDeque<(TimestampMillis, Forward)>>,
+    draining: AtomicBool,
+}
+
+///Handles the publishes that could not be forwarded to a node, as configured by partition.mode
+pub(crate) struct Partitioner {
+    cfg: Partition,
+    router: &'static ClusterRouter,
+    buffers: DashMap<NodeId, Arc<ForwardBuffer>>,
+    ///When the forwards to a node started failing, until one succeeds
+    failing_since: DashMap<NodeId, TimestampMillis>,
+}
+
+impl Partitioner {
+    #[inline]
+    pub(crate) fn new(cfg: Partition, router: &'static ClusterRouter) -> Self {
+        Self { cfg, router, buffers: DashMap::default(), failing_since: DashMap::default() }
+    }
+
+    ///In the buffer mode all the publishes to a node go through its buffer, one at a time, so a
+    ///publish is never forwarded before an earlier one that is retried
+    #[inline]
+    pub(crate) fn is_ordered(&self) -> bool {
+        matches!(self.cfg.mode, PartitionMode::Buffer)
+    }
+
+    ///Called when forwarding to the node has succeeded
+    #[inline]
+    pub(crate) fn reachable(&self, node_id: NodeId) {
+        if !self.failing_since.is_empty() {
+            self.failing_since.remove(&node_id);
+        }
+    }
+
+    ///The forwards to the node have been failing for reassign_after, and the node is not a healthy
+    ///member of the gossip discovery
+    #[inline]
+    fn is_failed(&self, node_id: NodeId) -> bool {
+        let now = chrono::Local::now().timestamp_millis();
+        let since = *self.failing_since.entry(node_id).or_insert(now).value();
+        if now - since < self.cfg.reassign_after.as_millis() as TimestampMillis {
+            return false;
+        }
+        Discovery::get().map(|d| !d.healthy_members().iter().any(|m| m.id == node_id)).unwrap_or(true)
+    }
+
+    #[inline]
+    pub(crate) fn buffered_count(&self) -> usize {
+        self.buffers.iter().map(|buffer| buffer.forwards.read().len()).sum()
+    }
+
+    ///Called when forwarding to the node has failed
+    pub(crate) async fn unreachable(
+        &self,
+        shared: &'static ClusterShared,
+        node_id: NodeId,
+        from: From,
+        publish: Publish,
+        relations: SubRelations,
+    ) {
+        match self.cfg.mode {
+            PartitionMode::Drop => {
+                self.drop_forward(node_id, from, publish, relations, DroppedReason::NODE_UNREACHABLE).await
+            }
+            PartitionMode::Buffer => self.buffer(shared, node_id, (from, publish, relations)).await,
+            PartitionMode::Reassign => {
+                if self.is_failed(node_id) {
+                    self.reassign(node_id, &relations).await;
+                }
+                self.drop_forward(node_id, from, publish, relations, DroppedReason::NODE_UNREACHABLE).await
+            }
+        }
+    }
+
+    ///Appends the publish to the buffer of the node, and starts forwarding the buffer
+    pub(crate) async fn buffer(&self, shared: &'static ClusterShared, node_id: NodeId, forward: Forward) {
+        let buffer = self.buffers.entry(node_id).or_default().value().clone();
+        let overflowed = {
+            let mut forwards = buffer.forwards.write();
+            if forwards.len() < self.cfg.buffer_capacity {
+                forwards.push_back((chrono::Local::now().timestamp_millis(), forward));
+                None
+            } else {
+                Some(forward)
+            }
+        };
+        if let Some((from, publish, relations)) = overflowed {
+            self.drop_forward(node_id, from, publish, relations, DroppedReason::FORWARD_BUFFER_FULL).await;
+        }
+        self.start_drain(shared, node_id, buffer);
+    }
+
+    ///Forwards the buffer at once, and retries every retry_interval while the node cannot be reached
+    fn start_drain(&self, shared: &'static ClusterShared, node_id: NodeId, buffer: Arc<ForwardBuffer>) {
+        if buffer.draining.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let retry_interval = self.cfg.retry_interval;
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = Self::drain(shared, node_id, &buffer).await {
+                    log::debug!("forward buffered publishes to node {} error, {:?}", node_id, e);
+                    tokio::time::sleep(retry_interval).await;
+                    shared.partitioner.drop_expired(node_id, &buffer).await;
+                    continue;
+                }
+                shared.partitioner.reachable(node_id);
+                buffer.draining.store(false, Ordering::SeqCst);
+                //a publish may have been buffered after the buffer was drained
+                if buffer.forwards.read().is_empty() || buffer.draining.swap(true, Ordering::SeqCst) {
+                    break;
+                }
+            }
+        });
+    }
+
+    ///Drops the buffered publishes older than buffer_ttl
+    async fn drop_expired(&self, node_id: NodeId, buffer: &ForwardBuffer) {
+        let expired_at =
+            chrono::Local::now().timestamp_millis() - self.cfg.buffer_ttl.as_millis() as TimestampMillis;
+        let expireds = {
+            let mut forwards = buffer.forwards.write();
+            let n = forwards.iter().take_while(|(buffered_at, _)| *buffered_at < expired_at).count();
+            forwards.drain(..n).map(|(_, forward)| forward).collect::<Vec<_>>()
+        };
+        for (from, publish, relations) in expireds {
+            self.drop_forward(node_id, from, publish, relations, DroppedReason::FORWARD_BUFFER_EXPIRED).await;
+        }
+    }
+
+    async fn drain(shared: &'static ClusterShared, node_id: NodeId, buffer: &ForwardBuffer) -> Result<()> {
+        loop {
+            let forward = buffer.forwards.read().front().map(|(_, forward)| forward.clone());
+            let (from, publish, relations) = match forward {
+                Some(forward) => forward,
+                None => return Ok(()),
+            };
+            let client = shared
+                .grpc_client(node_id)
+                .ok_or_else(|| MqttError::from(format!("grpc_client is not exist, node_id: {}", node_id)))?;
+            MessageSender {
+                client,
+                msg_type: shared.message_type,
+                msg: Message::ForwardsTo(from, publish, relations),
+                max_retries: 0,
+                retry_interval: Duration::ZERO,
+            }
+            .send()
+            .await?;
+            buffer.forwards.write().pop_front();
+        }
+    }
+
+    ///Marks the sessions of the subscribers on the node offline
+    async fn reassign(&self, node_id: NodeId, relations: &SubRelations) {
+        let raft_mailbox = self.router.raft_mailbox().await;
+        for (_, client_id, _, _) in relations {
+            let status = match self.router.status(client_id) {
+                Some(status) if status.online && status.id.node_id == node_id => status,
+                _ => continue,
+            };
+            log::info!("node {} is unreachable, session {:?} is marked offline", node_id, status.id);
+            let msg = match (RaftMessage::Disconnected { id: status.id }).encode() {
+                Ok(msg) => msg,
+                Err(e) => {
+                    log::warn!("reassign, Message::Disconnected encode error, {:?}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = raft_mailbox.propose(msg).await {
+                log::warn!("reassign, Message::Disconnected, raft mailbox send error, {:?}", e);
+            }
+        }
+    }
+
+    async fn drop_forward(
+        &self,
+        node_id: NodeId,
+        from: From,
+        publish: Publish,
+        relations: SubRelations,
+        reason: &'static str,
+    ) {
+        let droppeds = relations
+            .into_iter()
+            .map(|(_, client_id, _, _)| {
+                let to: To = self
+                    .router
+                    .status(&client_id)
+                    .map(|status| status.id)
+                    .unwrap_or_else(|| Id::new(node_id, None, None, client_id, None));
+                (to, from.clone(), publish.clone(), Reason::from_static(reason))
+            })
+            .collect::<Vec<_>>();
+        hook_message_dropped(droppeds).await;
+    }
+}
@@ -11,23 +11,23 @@ use rmqtt::{
     Result,
 };
 
-use super::GrpcClients;
+use super::shared::ClusterShared;
 
 #[allow(dead_code)]
 pub(crate) struct ClusterRetainer {
     inner: &'static DefaultRetainStorage,
-    grpc_clients: GrpcClients,
+    shared: &'static ClusterShared,
     pub message_type: MessageType,
 }
 
 impl ClusterRetainer {
     #[inline]
     pub(crate) fn get_or_init(
-        grpc_clients: GrpcClients,
+        shared: &'static ClusterShared,
         message_type: MessageType,
     ) -> &'static ClusterRetainer {
         static INSTANCE: OnceCell<ClusterRetainer> = OnceCell::new();
-        INSTANCE.get_or_init(|| Self { inner: DefaultRetainStorage::instance(), grpc_clients, message_type })
+        INSTANCE.get_or_init(|| Self { inner: DefaultRetainStorage::instance(), shared, message_type })
     }
 
     #[inline]
@@ -49,7 +49,7 @@ impl RetainStorage for &'static ClusterRetainer {
 
         //get retain info from other nodes
         let replys = MessageBroadcaster::new(
-            self.grpc_clients.clone(),
+            self.shared.grpc_clients(),
             self.message_type,
             Message::GetRetains(topic_filter.clone()),
         )
@@ -84,4 +84,9 @@ impl RetainStorage for &'static ClusterRetainer {
     fn max(&self) -> isize {
         self.inner.max()
     }
+
+    #[inline]
+    fn bytes(&self) -> usize {
+        self.inner.bytes()
+    }
 }
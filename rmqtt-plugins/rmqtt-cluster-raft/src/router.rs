@@ -1,35 +1,70 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use once_cell::sync::OnceCell;
 use rmqtt_raft::{Error, Mailbox, Result as RaftResult, Store};
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, oneshot, RwLock};
 
+use rmqtt::dashmap::mapref::entry::Entry;
 use rmqtt::rust_box::task_exec_queue::SpawnExt;
 use rmqtt::stats::Counter;
-use rmqtt::{
-    ahash, anyhow, async_trait::async_trait, bincode, chrono, dashmap, log, once_cell, serde_json, tokio,
-    MqttError,
-};
+use rmqtt::{ahash, async_trait::async_trait, chrono, dashmap, log, once_cell, serde_json, tokio, MqttError};
 use rmqtt::{
     broker::{
-        default::DefaultRouter,
-        topic::TopicTree,
+        default::{DefaultRouter, RouteChange},
+        topic::{Topic, TopicTree},
         types::{
-            ClientId, Id, IsOnline, NodeId, QoS, Route, SharedGroup, TimestampMillis, TopicFilter, TopicName,
+            fnv1a, ClientId, Id, IsOnline, NodeId, Publish, QoS, Route, SharedGroup, TimestampMillis,
+            TopicFilter, TopicName,
         },
         Router, SubRelationsMap,
     },
+    grpc::codec,
+    settings::DuplicateClientId,
+    telemetry::Span,
     Result,
 };
 
 use crate::task_exec_queue;
 
-use super::config::{retry, BACKOFF_STRATEGY};
-use super::message::{Message, MessageReply};
+use super::mailbox::MailboxExt;
+use super::message::{get_applied_index, get_matches, Message, MessageReply};
 
 type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
 type DashMap<K, V> = dashmap::DashMap<K, V, ahash::RandomState>;
+type Relations = Vec<(TopicFilter, HashMap<ClientId, (Id, QoS, Option<SharedGroup>)>)>;
+
+///Prefix of the snapshots of the raft groups, followed by the version of their layout
+const SNAPSHOT_MAGIC: &[u8] = b"rmqtt-router-snapshot";
+///Version of the layout of the snapshots, to be incremented with each change of the layout, the
+///snapshots of the previous versions are still to be restored
+const SNAPSHOT_VERSION: u16 = 1;
+
+#[inline]
+fn encode_snapshot<T: serde::Serialize>(snapshot: &T) -> RaftResult<Vec<u8>> {
+    let mut data = SNAPSHOT_MAGIC.to_vec();
+    data.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+    data.extend(codec::encode(snapshot).map_err(|e| Error::Other(Box::new(e)))?);
+    Ok(data)
+}
+
+///The version of the layout and the snapshot, None for a snapshot of a broker from before the
+///snapshots were versioned
+#[inline]
+fn decode_envelope(snapshot: &[u8]) -> Option<(u16, &[u8])> {
+    let data = snapshot.strip_prefix(SNAPSHOT_MAGIC)?;
+    if data.len() < 2 {
+        return None;
+    }
+    Some((u16::from_be_bytes([data[0], data[1]]), &data[2..]))
+}
+
+#[inline]
+fn unsupported_snapshot(version: u16) -> Error {
+    Error::Other(Box::new(MqttError::from(format!("unsupported snapshot version, {}", version))))
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct ClientStatus {
@@ -51,25 +86,376 @@ impl ClientStatus {
     }
 }
 
+///Subscription change of a committed entry, applied by the apply pipeline
+enum ApplyOp {
+    Change(RouteChange),
+    Flush(oneshot::Sender<()>),
+}
+
+///Entries applied by a raft group on this node, and how far behind the leader of the group they are
+struct GroupApplied {
+    applied: AtomicU64,
+    //false if the applied entries are unknown, restored from a snapshot of a previous version
+    known: AtomicBool,
+    lag: AtomicU64,
+    stale: AtomicBool,
+}
+
+impl GroupApplied {
+    fn new() -> Self {
+        Self {
+            applied: AtomicU64::new(0),
+            known: AtomicBool::new(true),
+            lag: AtomicU64::new(0),
+            stale: AtomicBool::new(false),
+        }
+    }
+}
+
 pub(crate) struct ClusterRouter {
     inner: &'static DefaultRouter,
+    //raft group 0, keeps the client states and the subscriptions of shard 0
     raft_mailbox: Arc<RwLock<Option<Mailbox>>>,
+    //raft groups 1.., keep the subscriptions of their shard
+    shard_mailboxes: Arc<RwLock<Vec<Mailbox>>>,
+    shards: usize,
     client_states: DashMap<ClientId, ClientStatus>,
+    apply_pipeline: OnceCell<(mpsc::Sender<ApplyOp>, usize)>,
+    //one per raft group
+    applied: Vec<GroupApplied>,
+    routing_max_lag: Option<u64>,
+    pub routing_leader_reads: AtomicUsize,
     pub try_lock_timeout: Duration,
 }
 
 impl ClusterRouter {
     #[inline]
-    pub(crate) fn get_or_init(try_lock_timeout: Duration) -> &'static Self {
+    pub(crate) fn get_or_init(
+        try_lock_timeout: Duration,
+        shards: usize,
+        routing_max_lag: Option<u64>,
+    ) -> &'static Self {
         static INSTANCE: OnceCell<ClusterRouter> = OnceCell::new();
         INSTANCE.get_or_init(|| Self {
             inner: DefaultRouter::instance(),
             raft_mailbox: Arc::new(RwLock::new(None)),
+            shard_mailboxes: Arc::new(RwLock::new(Vec::new())),
+            shards: shards.max(1),
             client_states: DashMap::default(),
+            apply_pipeline: OnceCell::new(),
+            applied: (0..shards.max(1)).map(|_| GroupApplied::new()).collect(),
+            routing_max_lag,
+            routing_leader_reads: AtomicUsize::new(0),
             try_lock_timeout,
         })
     }
 
+    ///Shard of the subscriptions of a topic filter, the raft group its changes are proposed to
+    #[inline]
+    pub(crate) fn shard_of(&self, topic_filter: &str) -> usize {
+        if self.shards <= 1 {
+            return 0;
+        }
+        //the shard of a topic filter must be the same on all nodes
+        (fnv1a(topic_filter.bytes()) % self.shards as u64) as usize
+    }
+
+    #[inline]
+    pub(crate) async fn add_shard_mailbox(&self, mailbox: Mailbox) {
+        self.shard_mailboxes.write().await.push(mailbox);
+    }
+
+    ///Mailboxes of the raft groups 1..
+    #[inline]
+    pub(crate) async fn shard_mailboxes(&self) -> Vec<Mailbox> {
+        self.shard_mailboxes.read().await.clone()
+    }
+
+    ///Mailbox of the raft group of the shard of a topic filter
+    #[inline]
+    async fn shard_mailbox(&self, topic_filter: &str) -> Mailbox {
+        match self.shard_of(topic_filter) {
+            0 => self.raft_mailbox().await,
+            shard => self.shard_mailboxes.read().await[shard - 1].clone(),
+        }
+    }
+
+    ///Mailbox of a raft group, group 0 keeps the client states and the subscriptions of shard 0
+    #[inline]
+    pub(crate) async fn group_mailbox(&self, group: usize) -> Mailbox {
+        match group {
+            0 => self.raft_mailbox().await,
+            group => self.shard_mailboxes.read().await[group - 1].clone(),
+        }
+    }
+
+    #[inline]
+    fn shard_relations(&self, shard: usize) -> Relations {
+        self.inner
+            .relations
+            .iter()
+            .filter(|entry| self.shard_of(entry.key()) == shard)
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+
+    ///Replaces the subscriptions of a shard with the ones of a snapshot of its raft group,
+    ///the subscriptions of the other shards are left as they are
+    async fn restore_shard(&self, shard: usize, relations: Relations) -> RaftResult<()> {
+        for (topic_filter, relation) in self.shard_relations(shard) {
+            for (_, (id, _, _)) in relation {
+                self.inner.remove(&topic_filter, id).await.map_err(|e| Error::Other(Box::new(e)))?;
+            }
+        }
+        for (topic_filter, relation) in relations {
+            for (_, (id, qos, shared_group)) in relation {
+                self.inner
+                    .add(&topic_filter, id, qos, shared_group)
+                    .await
+                    .map_err(|e| Error::Other(Box::new(e)))?;
+            }
+        }
+        Ok(())
+    }
+
+    ///Applies a subscription change of a committed entry
+    async fn apply_subscription(&self, message: Message<'_>) -> RaftResult<()> {
+        match message {
+            Message::Add { topic_filter, id, qos, shared_group } => {
+                log::debug!(
+                    "[Router.add] topic_filter: {:?}, id: {:?}, qos: {:?}, shared_group: {:?}",
+                    topic_filter,
+                    id,
+                    qos,
+                    shared_group
+                );
+                if self.apply_pipeline.get().is_some() {
+                    let topic_filter = TopicFilter::from(topic_filter);
+                    let change = RouteChange::Add { topic_filter, id, qos, shared_group };
+                    self.apply_pipelined(ApplyOp::Change(change)).await?;
+                } else {
+                    self.inner
+                        .add(topic_filter, id, qos, shared_group)
+                        .await
+                        .map_err(|e| Error::Other(Box::new(e)))?;
+                }
+            }
+            Message::Remove { topic_filter, id } => {
+                log::debug!("[Router.remove] topic_filter: {:?}, id: {:?}", topic_filter, id,);
+                if self.apply_pipeline.get().is_some() {
+                    let topic_filter = TopicFilter::from(topic_filter);
+                    self.apply_pipelined(ApplyOp::Change(RouteChange::Remove { topic_filter, id })).await?;
+                } else {
+                    self.inner.remove(topic_filter, id).await.map_err(|e| Error::Other(Box::new(e)))?;
+                }
+            }
+            Message::ImportSubscriptions { subscriptions } => {
+                log::debug!("[Router.ImportSubscriptions] subscriptions: {}", subscriptions.len());
+                for (topic_filter, id, qos, shared_group) in subscriptions {
+                    if self.apply_pipeline.get().is_some() {
+                        let change = RouteChange::Add { topic_filter, id, qos, shared_group };
+                        self.apply_pipelined(ApplyOp::Change(change)).await?;
+                    } else {
+                        self.inner
+                            .add(&topic_filter, id, qos, shared_group)
+                            .await
+                            .map_err(|e| Error::Other(Box::new(e)))?;
+                    }
+                }
+            }
+            _ => {
+                log::error!("unexpected message of a subscription shard, {:?}", message);
+            }
+        }
+        Ok(())
+    }
+
+    ///Applies the subscription changes of the committed entries in the background, in commit order,
+    ///up to batch_size at a time, so that a slow apply does not hold up the raft log. The changes are
+    ///applied inline if capacity is 0.
+    #[inline]
+    pub(crate) fn start_apply_pipeline(&'static self, capacity: usize, batch_size: usize) {
+        if capacity == 0 {
+            return;
+        }
+        let (tx, mut rx) = mpsc::channel(capacity);
+        if self.apply_pipeline.set((tx, capacity)).is_err() {
+            return;
+        }
+        let batch_size = batch_size.max(1);
+        tokio::spawn(async move {
+            let mut batch = Vec::with_capacity(batch_size);
+            while let Some(op) = rx.recv().await {
+                batch.push(op);
+                while batch.len() < batch_size {
+                    match rx.try_recv() {
+                        Ok(op) => batch.push(op),
+                        Err(_) => break,
+                    }
+                }
+                //the changes before a flush are applied as one batch, the hot topic names they
+                //invalidate are resolved again once per batch
+                let mut changes = Vec::with_capacity(batch.len());
+                for op in batch.drain(..) {
+                    match op {
+                        ApplyOp::Change(change) => changes.push(change),
+                        ApplyOp::Flush(done) => {
+                            self.inner.apply_batch(std::mem::take(&mut changes));
+                            let _ = done.send(());
+                        }
+                    }
+                }
+                self.inner.apply_batch(changes);
+            }
+        });
+    }
+
+    ///Queues the change, waits while the pipeline is full
+    #[inline]
+    async fn apply_pipelined(&self, op: ApplyOp) -> RaftResult<()> {
+        if let Some((tx, _)) = self.apply_pipeline.get() {
+            tx.send(op)
+                .await
+                .map_err(|_| Error::Other(Box::new(MqttError::from("apply pipeline is closed"))))?;
+        }
+        Ok(())
+    }
+
+    ///Waits until the changes queued before are applied
+    #[inline]
+    async fn flush_apply_pipeline(&self) {
+        if let Some((tx, _)) = self.apply_pipeline.get() {
+            let (done_tx, done_rx) = oneshot::channel();
+            if tx.send(ApplyOp::Flush(done_tx)).await.is_ok() {
+                let _ = done_rx.await;
+            }
+        }
+    }
+
+    ///Number of the changes waiting in the apply pipeline
+    #[inline]
+    pub(crate) fn apply_pipeline_len(&self) -> usize {
+        self.apply_pipeline.get().map(|(tx, capacity)| capacity - tx.capacity()).unwrap_or_default()
+    }
+
+    #[inline]
+    fn inc_applied(&self, group: usize) {
+        self.applied[group].applied.fetch_add(1, Ordering::SeqCst);
+    }
+
+    ///Number of the entries applied by the raft group on this node, None if unknown
+    #[inline]
+    fn applied_index(&self, group: usize) -> Option<u64> {
+        let applied = &self.applied[group];
+        if applied.known.load(Ordering::SeqCst) {
+            Some(applied.applied.load(Ordering::SeqCst))
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    fn set_applied_index(&self, group: usize, applied_index: Option<u64>) {
+        let applied = &self.applied[group];
+        applied.applied.store(applied_index.unwrap_or_default(), Ordering::SeqCst);
+        applied.known.store(applied_index.is_some(), Ordering::SeqCst);
+    }
+
+    ///Number of the entries each raft group on this node is behind its leader, as last checked
+    #[inline]
+    pub(crate) fn routing_lags(&self) -> Vec<u64> {
+        self.applied.iter().map(|applied| applied.lag.load(Ordering::SeqCst)).collect()
+    }
+
+    ///Compares the applied entries of each raft group with those of its leader every interval.
+    ///The routing lookups read the subscriptions of a group whose local state is more than
+    ///routing_max_lag entries behind from its leader, until it catches up
+    pub(crate) fn start_lag_check(&'static self, interval: Duration) {
+        let max_lag = if let Some(max_lag) = self.routing_max_lag {
+            max_lag
+        } else {
+            return;
+        };
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                for (group, applied) in self.applied.iter().enumerate() {
+                    let mailbox = self.group_mailbox(group).await;
+                    let leader_applied = match get_applied_index(mailbox).await {
+                        Ok(leader_applied) => leader_applied,
+                        Err(e) => {
+                            log::debug!("raft group {} get leader applied index error, {:?}", group, e);
+                            continue;
+                        }
+                    };
+                    //the lag is unknown while either side was restored from a snapshot of a
+                    //previous version, the local state is read as before
+                    let lag = match (leader_applied, self.applied_index(group)) {
+                        (Some(leader_applied), Some(local_applied)) => {
+                            leader_applied.saturating_sub(local_applied) + self.apply_pipeline_len() as u64
+                        }
+                        _ => 0,
+                    };
+                    applied.lag.store(lag, Ordering::SeqCst);
+                    let stale = lag > max_lag;
+                    if applied.stale.swap(stale, Ordering::SeqCst) != stale {
+                        log::info!(
+                            "raft group {} is {} entries behind its leader, routing reads the {} state",
+                            group,
+                            lag,
+                            if stale { "leader" } else { "local" }
+                        );
+                    }
+                }
+            }
+        });
+    }
+
+    ///Reads the local state, the subscriptions of the raft groups too far behind their leader are
+    ///read from the leader instead
+    #[inline]
+    async fn _matches(&self, topic: &TopicName, publish: Option<&Publish>) -> Result<SubRelationsMap> {
+        let stale_groups = self.stale_groups();
+        let mut relations_map = match publish {
+            Some(publish) => self.inner.matches_publish(publish).await?,
+            None => self.inner.matches(topic).await?,
+        };
+        if stale_groups.is_empty() {
+            return Ok(relations_map);
+        }
+        for group in stale_groups {
+            let leader_relations_map = match get_matches(self.group_mailbox(group).await, topic).await {
+                Ok(leader_relations_map) => leader_relations_map,
+                Err(e) => {
+                    log::warn!("[Router.matches] raft group {}, read leader error, {:?}", group, e);
+                    continue;
+                }
+            };
+            self.routing_leader_reads.fetch_add(1, Ordering::SeqCst);
+            for relations in relations_map.values_mut() {
+                relations.retain(|(topic_filter, ..)| self.shard_of(topic_filter) != group);
+            }
+            for (node_id, relations) in leader_relations_map {
+                relations_map.entry(node_id).or_default().extend(
+                    relations.into_iter().filter(|(topic_filter, ..)| self.shard_of(topic_filter) == group),
+                );
+            }
+        }
+        relations_map.retain(|_, relations| !relations.is_empty());
+        Ok(relations_map)
+    }
+
+    ///The raft groups whose local state is too far behind their leader to be read by the routing
+    #[inline]
+    fn stale_groups(&self) -> Vec<usize> {
+        self.applied
+            .iter()
+            .enumerate()
+            .filter(|(_, applied)| applied.stale.load(Ordering::SeqCst))
+            .map(|(group, _)| group)
+            .collect()
+    }
+
     #[inline]
     pub(crate) fn _inner(&self) -> Box<dyn Router> {
         Box::new(self.inner)
@@ -85,6 +471,34 @@ impl ClusterRouter {
         self.raft_mailbox.read().await.as_ref().unwrap().clone()
     }
 
+    ///Locks the client_id for the handshake of id unless another handshake holds it, returns
+    ///whether it is locked and the previous id of the client_id
+    #[inline]
+    fn handshake_try_lock(&self, id: &Id) -> (bool, Option<Id>) {
+        let mut try_lock_ok = false;
+        let mut prev_id = None;
+        self.client_states
+            .entry(id.client_id.clone())
+            .and_modify(|status| {
+                prev_id = Some(status.id.clone());
+                if !status.handshaking(self.try_lock_timeout) {
+                    *status = ClientStatus::new(id.clone(), false, true);
+                    try_lock_ok = true;
+                }
+            })
+            .or_insert_with(|| {
+                try_lock_ok = true;
+                ClientStatus::new(id.clone(), false, true)
+            });
+        (try_lock_ok, prev_id)
+    }
+
+    ///A session of the client_id exists somewhere in the cluster, online, offline or handshaking
+    #[inline]
+    fn client_id_in_use(&self, client_id: &str) -> bool {
+        self.client_states.contains_key(client_id)
+    }
+
     #[inline]
     pub(crate) fn _client_node_id(&self, client_id: &str) -> Option<NodeId> {
         self.client_states.get(client_id).map(|entry| entry.id.node_id)
@@ -95,6 +509,17 @@ impl ClusterRouter {
         self.client_states.get(client_id).map(|entry| entry.id.clone())
     }
 
+    ///Number of the subscriptions of the cluster in the raft state machine
+    #[inline]
+    pub(crate) fn relations_count(&self) -> usize {
+        self.inner.relations_count.count().max(0) as usize
+    }
+
+    #[inline]
+    pub(crate) fn is_subscribed(&self, topic_filter: &str, client_id: &str) -> bool {
+        self.inner.relations.get(topic_filter).map(|r| r.contains_key(client_id)).unwrap_or(false)
+    }
+
     #[inline]
     pub(crate) fn states_count(&self) -> usize {
         self.client_states.len()
@@ -134,9 +559,12 @@ impl Router for &'static ClusterRouter {
             shared_group
         );
 
+        let span = Span::start("raft.propose", None);
+        span.set_attribute("raft.message", "Add");
+        span.set_attribute("mqtt.topic_filter", topic_filter);
         let msg = Message::Add { topic_filter, id, qos, shared_group }.encode()?;
-        let mailbox = self.raft_mailbox().await;
-        let _ = async move { mailbox.send(msg).await.map_err(anyhow::Error::new) }
+        let mailbox = self.shard_mailbox(topic_filter).await;
+        let _ = async move { mailbox.propose(msg).await }
             .spawn(task_exec_queue())
             .result()
             .await
@@ -148,21 +576,13 @@ impl Router for &'static ClusterRouter {
     async fn remove(&self, topic_filter: &str, id: Id) -> Result<bool> {
         log::debug!("[Router.remove] topic_filter: {:?}, id: {:?}", topic_filter, id);
         let msg = Message::Remove { topic_filter, id: id.clone() }.encode()?;
-        let raft_mailbox = self.raft_mailbox().await;
+        let raft_mailbox = self.shard_mailbox(topic_filter).await;
+        let topic_filter = topic_filter.to_owned();
         tokio::spawn(async move {
-            if let Err(e) = retry(BACKOFF_STRATEGY.clone(), || async {
-                let msg = msg.clone();
-                let mailbox = raft_mailbox.clone();
-                let res = async move { mailbox.send(msg).await }
-                    .spawn(task_exec_queue())
-                    .result()
-                    .await
-                    .map_err(|_| MqttError::from("Router::remove(..), task execution failure"))?
-                    .map_err(|e| MqttError::from(e.to_string()))?;
-                Ok(res)
-            })
-            .await
-            {
+            let span = Span::start("raft.propose", None);
+            span.set_attribute("raft.message", "Remove");
+            span.set_attribute("mqtt.topic_filter", topic_filter);
+            if let Err(e) = raft_mailbox.propose_retry(msg).await {
                 log::warn!("[Router.remove] Failed to send Message::Remove, id: {:?}, {:?}", id, e);
             }
         });
@@ -171,7 +591,12 @@ impl Router for &'static ClusterRouter {
 
     #[inline]
     async fn matches(&self, topic: &TopicName) -> Result<SubRelationsMap> {
-        self.inner.matches(topic).await
+        self._matches(topic, None).await
+    }
+
+    #[inline]
+    async fn matches_publish(&self, publish: &Publish) -> Result<SubRelationsMap> {
+        self._matches(publish.topic(), Some(publish)).await
     }
 
     ///Check online or offline
@@ -230,25 +655,12 @@ impl Router for &'static ClusterRouter {
 impl Store for &'static ClusterRouter {
     async fn apply(&mut self, message: &[u8]) -> RaftResult<Vec<u8>> {
         log::debug!("apply, message.len: {:?}", message.len());
-        let message: Message = bincode::deserialize(message).map_err(|e| Error::Other(e))?;
+        self.inc_applied(0);
+        let message: Message = codec::decode(message).map_err(|e| Error::Other(Box::new(e)))?;
         match message {
             Message::HandshakeTryLock { id } => {
                 log::debug!("[Router.HandshakeTryLock] id: {:?}", id);
-                let mut try_lock_ok = false;
-                let mut prev_id = None;
-                self.client_states
-                    .entry(id.client_id.clone())
-                    .and_modify(|status| {
-                        prev_id = Some(status.id.clone());
-                        if !status.handshaking(self.try_lock_timeout) {
-                            *status = ClientStatus::new(id.clone(), false, true);
-                            try_lock_ok = true;
-                        }
-                    })
-                    .or_insert_with(|| {
-                        try_lock_ok = true;
-                        ClientStatus::new(id.clone(), false, true)
-                    });
+                let (try_lock_ok, prev_id) = self.handshake_try_lock(&id);
                 log::debug!(
                     "[Router.HandshakeTryLock] id: {:?}, try_lock_ok: {}, prev_id: {:?}",
                     id,
@@ -314,28 +726,75 @@ impl Store for &'static ClusterRouter {
                     }
                 });
             }
-            Message::Add { topic_filter, id, qos, shared_group } => {
-                log::debug!(
-                    "[Router.add] topic_filter: {:?}, id: {:?}, qos: {:?}, shared_group: {:?}",
-                    topic_filter,
-                    id,
-                    qos,
-                    shared_group
-                );
-                self.inner
-                    .add(topic_filter, id, qos, shared_group)
-                    .await
-                    .map_err(|e| Error::Other(Box::new(e)))?;
+            Message::ImportSessions { sessions } => {
+                log::debug!("[Router.ImportSessions] sessions: {}", sessions.len());
+                for (id, connected) in sessions {
+                    match self.client_states.entry(id.client_id.clone()) {
+                        Entry::Occupied(mut entry) => {
+                            if entry.get().id == id {
+                                entry.get_mut().online = connected;
+                            } else {
+                                log::info!(
+                                    "[Router.ImportSessions] id not the same, input id: {:?}, current status: {:?}",
+                                    id,
+                                    entry.get()
+                                );
+                            }
+                        }
+                        Entry::Vacant(entry) => {
+                            entry.insert(ClientStatus::new(id, connected, false));
+                        }
+                    }
+                }
             }
-            Message::Remove { topic_filter, id } => {
-                log::debug!("[Router.remove] topic_filter: {:?}, id: {:?}", topic_filter, id,);
-                self.inner.remove(topic_filter, id).await.map_err(|e| Error::Other(Box::new(e)))?;
+            Message::Add { .. } | Message::Remove { .. } | Message::ImportSubscriptions { .. } => {
+                self.apply_subscription(message).await?;
             }
             Message::GetClientNodeId { client_id } => {
                 let node_id = self._client_node_id(client_id);
-                let data = bincode::serialize(&node_id).map_err(|e| Error::Other(e))?;
+                let data = codec::encode(&node_id).map_err(|e| Error::Other(Box::new(e)))?;
                 return Ok(data);
             }
+            Message::HandshakeTryLockConnect { id, policy } => {
+                log::debug!("[Router.HandshakeTryLockConnect] id: {:?}, policy: {:?}", id, policy);
+                let online =
+                    self.client_states.get(&id.client_id).map(|status| status.online).unwrap_or(false);
+                let id = match policy {
+                    DuplicateClientId::Reject if online => {
+                        return MessageReply::ClientIdInUse.encode().map_err(|_e| Error::Unknown);
+                    }
+                    DuplicateClientId::Suffix if online => {
+                        let client_id = match Id::suffixed_client_ids(&id.client_id)
+                            .find(|client_id| !self.client_id_in_use(client_id))
+                        {
+                            Some(client_id) => client_id,
+                            None => {
+                                return MessageReply::ClientIdInUse.encode().map_err(|_e| Error::Unknown);
+                            }
+                        };
+                        log::debug!(
+                            "[Router.HandshakeTryLockConnect] id: {:?}, continues with {}",
+                            id,
+                            client_id
+                        );
+                        id.with_client_id(client_id)
+                    }
+                    _ => id,
+                };
+                let (try_lock_ok, prev_id) = self.handshake_try_lock(&id);
+                return if try_lock_ok {
+                    MessageReply::HandshakeTryLockConnect { prev_id, id }
+                        .encode()
+                        .map_err(|_e| Error::Unknown)
+                } else {
+                    MessageReply::Error("Handshake try lock failed".into())
+                        .encode()
+                        .map_err(|_e| Error::Unknown)
+                };
+            }
+            Message::AppliedIndex | Message::Matches { .. } => {
+                log::error!("unexpected message to apply, {:?}", message);
+            }
         }
 
         Ok(Vec::new())
@@ -343,13 +802,20 @@ impl Store for &'static ClusterRouter {
 
     async fn query(&self, query: &[u8]) -> RaftResult<Vec<u8>> {
         log::debug!("query, message.len: {:?}", query.len());
-        let query: Message = bincode::deserialize(query).map_err(|e| Error::Other(e))?;
+        let query: Message = codec::decode(query).map_err(|e| Error::Other(Box::new(e)))?;
         match query {
             Message::GetClientNodeId { client_id } => {
                 let node_id = self._client_node_id(client_id);
-                let data = bincode::serialize(&node_id).map_err(|e| Error::Other(e))?;
+                let data = codec::encode(&node_id).map_err(|e| Error::Other(Box::new(e)))?;
                 return Ok(data);
             }
+            Message::AppliedIndex => {
+                return codec::encode(&self.applied_index(0)).map_err(|e| Error::Other(Box::new(e)));
+            }
+            Message::Matches { topic } => {
+                let relations_map = self.inner.matches(&TopicName::from(topic)).await.unwrap_or_default();
+                return codec::encode(&relations_map).map_err(|e| Error::Other(Box::new(e)));
+            }
             _ => {
                 log::error!("unimplemented, query: {:?}", query)
             }
@@ -359,12 +825,8 @@ impl Store for &'static ClusterRouter {
 
     async fn snapshot(&self) -> RaftResult<Vec<u8>> {
         log::debug!("create snapshot ...");
-        let relations = &self
-            .inner
-            .relations
-            .iter()
-            .map(|entry| (entry.key().clone(), entry.value().clone()))
-            .collect::<Vec<_>>();
+        self.flush_apply_pipeline().await;
+        let relations = &self.shard_relations(0);
         let client_states = &self
             .client_states
             .iter()
@@ -374,37 +836,60 @@ impl Store for &'static ClusterRouter {
         let topics_count = &self.inner.topics_count;
         let relations_count = &self.inner.relations_count;
 
-        let snapshot = bincode::serialize(&(
-            self.inner.topics.read().await.as_ref(),
-            relations,
-            client_states,
-            topics_count,
-            relations_count,
-        ))
-        .map_err(|e| Error::Other(e))?;
+        let applied_index = self.applied_index(0);
+
+        //The topic tree is rebuilt from the relations on restore
+        let snapshot =
+            encode_snapshot(&(relations, client_states, topics_count, relations_count, applied_index))?;
         log::info!("create snapshot, len: {}", snapshot.len());
         Ok(snapshot)
     }
 
     async fn restore(&mut self, snapshot: &[u8]) -> RaftResult<()> {
         log::info!("restore, snapshot.len: {}", snapshot.len());
+        self.flush_apply_pipeline().await;
+
+        let (relations, client_states, topics_count, relations_count, applied_index) =
+            match decode_envelope(snapshot) {
+                Some((1, snapshot)) => codec::decode(snapshot).map_err(|e| Error::Other(Box::new(e)))?,
+                Some((version, _)) => return Err(unsupported_snapshot(version)),
+                None => {
+                    //the topic tree of the unversioned snapshots is rebuilt from the relations, they
+                    //have no applied index
+                    let (_, relations, client_states, topics_count, relations_count): (
+                        TopicTree<()>,
+                        Relations,
+                        Vec<(ClientId, ClientStatus)>,
+                        Counter,
+                        Counter,
+                    ) = codec::decode(snapshot).map_err(|e| Error::Other(Box::new(e)))?;
+                    (relations, client_states, topics_count, relations_count, None)
+                }
+            };
+        self.set_applied_index(0, applied_index);
+
+        if self.shards > 1 {
+            self.restore_shard(0, relations).await?;
+            self.client_states.clear();
+            for (client_id, content) in client_states {
+                self.client_states.insert(client_id, content);
+            }
+            return Ok(());
+        }
 
-        let (topics, relations, client_states, topics_count, relations_count): (
-            TopicTree<()>,
-            Vec<(TopicFilter, HashMap<ClientId, (Id, QoS, Option<SharedGroup>)>)>,
-            Vec<(ClientId, ClientStatus)>,
-            Counter,
-            Counter,
-        ) = bincode::deserialize(snapshot).map_err(|e| Error::Other(e))?;
-
-        *self.inner.topics.write().await = topics;
+        self.inner.topics.clear();
         self.inner.topics_count.set(&topics_count);
 
         self.inner.relations.clear();
+        self.inner.shared_groups.clear();
         for (topic_filter, relation) in relations {
+            let topic =
+                Topic::from_str(&topic_filter).map_err(|e| Error::Other(Box::new(MqttError::from(e))))?;
+            self.inner.topics.insert(&topic, ());
             self.inner.relations.insert(topic_filter, relation);
         }
         self.inner.relations_count.set(&relations_count);
+        self.inner.match_cache.clear();
 
         self.client_states.clear();
         for (client_id, content) in client_states {
@@ -414,3 +899,61 @@ impl Store for &'static ClusterRouter {
         Ok(())
     }
 }
+
+///Raft group of a shard of the subscriptions other than shard 0, which is kept by the raft group
+///of the client states
+#[derive(Clone, Copy)]
+pub(crate) struct ShardStore {
+    pub router: &'static ClusterRouter,
+    pub shard: usize,
+}
+
+#[async_trait]
+impl Store for ShardStore {
+    async fn apply(&mut self, message: &[u8]) -> RaftResult<Vec<u8>> {
+        self.router.inc_applied(self.shard);
+        let message: Message = codec::decode(message).map_err(|e| Error::Other(Box::new(e)))?;
+        self.router.apply_subscription(message).await?;
+        Ok(Vec::new())
+    }
+
+    async fn query(&self, query: &[u8]) -> RaftResult<Vec<u8>> {
+        let query: Message = codec::decode(query).map_err(|e| Error::Other(Box::new(e)))?;
+        match query {
+            Message::AppliedIndex => {
+                codec::encode(&self.router.applied_index(self.shard)).map_err(|e| Error::Other(Box::new(e)))
+            }
+            Message::Matches { topic } => {
+                let relations_map =
+                    self.router.inner.matches(&TopicName::from(topic)).await.unwrap_or_default();
+                codec::encode(&relations_map).map_err(|e| Error::Other(Box::new(e)))
+            }
+            _ => {
+                log::error!("unimplemented, shard: {}, query: {:?}", self.shard, query);
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    async fn snapshot(&self) -> RaftResult<Vec<u8>> {
+        self.router.flush_apply_pipeline().await;
+        let relations = &self.router.shard_relations(self.shard);
+        let snapshot = encode_snapshot(&(relations, self.router.applied_index(self.shard)))?;
+        log::info!("create snapshot, shard: {}, len: {}", self.shard, snapshot.len());
+        Ok(snapshot)
+    }
+
+    async fn restore(&mut self, snapshot: &[u8]) -> RaftResult<()> {
+        log::info!("restore, shard: {}, snapshot.len: {}", self.shard, snapshot.len());
+        self.router.flush_apply_pipeline().await;
+        let (relations, applied_index) = match decode_envelope(snapshot) {
+            Some((1, snapshot)) => {
+                codec::decode::<(Relations, Option<u64>)>(snapshot).map_err(|e| Error::Other(Box::new(e)))?
+            }
+            Some((version, _)) => return Err(unsupported_snapshot(version)),
+            None => return Err(unsupported_snapshot(0)),
+        };
+        self.router.set_applied_index(self.shard, applied_index);
+        self.router.restore_shard(self.shard, relations).await
+    }
+}
@@ -1,32 +1,41 @@
 use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use futures::future::FutureExt;
 use once_cell::sync::OnceCell;
 
-use rmqtt::anyhow::Error;
 use rmqtt::broker::Router;
 use rmqtt::grpc::MessageBroadcaster;
 use rmqtt::serde_json::json;
-use rmqtt::{anyhow, async_trait::async_trait, futures, log, once_cell, serde_json, tokio};
+use rmqtt::{async_trait::async_trait, futures, log, once_cell, serde_json, tokio, RwLock};
 use rmqtt::{
     broker::{
+        alarm::{Alarms, SUBSCRIPTION_LIMIT},
+        dedup::ForwardDedup,
         default::DefaultShared,
         session::{ClientInfo, Session, SessionOfflineInfo},
         types::{
-            From, Id, IsAdmin, NodeId, NodeName, Publish, Reason, SessionStatus, SubsSearchParams,
-            SubsSearchResult, Subscribe, SubscribeReturn, To, Tx, Unsubscribe,
+            Addr, ForwardId, From, Id, IsAdmin, NodeId, NodeName, Publish, Reason, SessionStatus,
+            SubsSearchParams, SubsSearchResult, Subscribe, SubscribeAckReason, SubscribeReturn, To, Tx,
+            Unsubscribe,
         },
         Entry, Shared, SubRelations, SubRelationsMap,
     },
     grpc::{Message, MessageReply, MessageType},
+    settings::DuplicateClientId,
+    telemetry::Span,
     MqttError, Result, Runtime,
 };
 
+use super::config::{OnLimitExceeded, Partition, SubscriptionLimits};
+use super::mailbox::MailboxExt;
 use super::message::{
     get_client_node_id, Message as RaftMessage, MessageReply as RaftMessageReply, RaftGrpcMessage,
     RaftGrpcMessageReply,
 };
+use super::partition::Partitioner;
 use super::{ClusterRouter, GrpcClients, HashMap, MessageSender, NodeGrpcClient};
 
 pub struct ClusterLockEntry {
@@ -50,9 +59,12 @@ impl ClusterLockEntry {
 impl Entry for ClusterLockEntry {
     #[inline]
     async fn try_lock(&self) -> Result<Box<dyn Entry>> {
+        let span = Span::start("raft.propose", None);
+        span.set_attribute("raft.message", "HandshakeTryLock");
+        span.set_attribute("mqtt.clientid", &*self.id().client_id);
         let msg = RaftMessage::HandshakeTryLock { id: self.id() }.encode()?;
         let raft_mailbox = self.cluster_shared.router.raft_mailbox().await;
-        let reply = raft_mailbox.send(msg).await.map_err(anyhow::Error::new)?;
+        let reply = raft_mailbox.propose(msg).await?;
         let mut prev_node_id = None;
         if !reply.is_empty() {
             match RaftMessageReply::decode(&reply)? {
@@ -66,12 +78,41 @@ impl Entry for ClusterLockEntry {
                         self.client().map(|c| c.id.clone()),
                         prev_node_id
                     );
-                } // _ => unreachable!()
+                }
+                reply => {
+                    return Err(MqttError::Msg(format!("unexpected reply, {:?}", reply)));
+                }
             }
         }
         Ok(Box::new(ClusterLockEntry::new(self.inner.try_lock().await?, self.cluster_shared, prev_node_id)))
     }
 
+    ///The policy is applied by the raft state machine to the client states of the whole cluster
+    #[inline]
+    async fn try_lock_connect(&self, policy: DuplicateClientId) -> Result<Box<dyn Entry>> {
+        let span = Span::start("raft.propose", None);
+        span.set_attribute("raft.message", "HandshakeTryLockConnect");
+        span.set_attribute("mqtt.clientid", &*self.id().client_id);
+        let msg = RaftMessage::HandshakeTryLockConnect { id: self.id(), policy }.encode()?;
+        let raft_mailbox = self.cluster_shared.router.raft_mailbox().await;
+        let reply = raft_mailbox.propose(msg).await?;
+        match RaftMessageReply::decode(&reply)? {
+            RaftMessageReply::HandshakeTryLockConnect { prev_id, id } => {
+                let prev_node_id = prev_id.map(|id| id.node_id);
+                log::debug!("{:?} ClusterLockEntry try_lock_connect prev_node_id: {:?}", id, prev_node_id);
+                let inner = if id.client_id == self.id().client_id {
+                    self.inner.try_lock().await?
+                } else {
+                    self.cluster_shared.inner.entry(id).try_lock().await?
+                };
+                Ok(Box::new(ClusterLockEntry::new(inner, self.cluster_shared, prev_node_id)))
+            }
+            RaftMessageReply::ClientIdInUse => Err(MqttError::ClientIdInUse),
+            RaftMessageReply::Error(e) => Err(MqttError::Msg(e)),
+            reply => Err(MqttError::Msg(format!("unexpected reply, {:?}", reply))),
+        }
+    }
+
     #[inline]
     fn id(&self) -> Id {
         self.inner.id()
@@ -89,9 +130,12 @@ impl Entry for ClusterLockEntry {
 
     #[inline]
     async fn set(&mut self, session: Session, tx: Tx, conn: ClientInfo) -> Result<()> {
+        let span = Span::start("raft.propose", None);
+        span.set_attribute("raft.message", "Connected");
+        span.set_attribute("mqtt.clientid", &*session.id.client_id);
         let msg = RaftMessage::Connected { id: session.id.clone() }.encode()?;
         let raft_mailbox = self.cluster_shared.router.raft_mailbox().await;
-        let reply = raft_mailbox.send(msg).await.map_err(anyhow::Error::new)?;
+        let reply = raft_mailbox.propose(msg).await?;
         if !reply.is_empty() {
             let reply = RaftMessageReply::decode(&reply)?;
             match reply {
@@ -213,6 +257,10 @@ impl Entry for ClusterLockEntry {
 
     #[inline]
     async fn subscribe(&self, subscribe: &Subscribe) -> Result<SubscribeReturn> {
+        if self.cluster_shared.is_over_limit(subscribe, &self.id().client_id) {
+            self.cluster_shared.subscriptions_rejected.fetch_add(1, Ordering::SeqCst);
+            return Ok(SubscribeReturn::new_failure(SubscribeAckReason::QuotaExceeded));
+        }
         self.inner.subscribe(subscribe).await
     }
 
@@ -262,9 +310,13 @@ impl Entry for ClusterLockEntry {
 pub struct ClusterShared {
     inner: &'static DefaultShared,
     router: &'static ClusterRouter,
-    grpc_clients: GrpcClients,
-    node_names: HashMap<NodeId, NodeName>,
+    grpc_clients: RwLock<GrpcClients>,
+    node_names: RwLock<HashMap<NodeId, NodeName>>,
     pub message_type: MessageType,
+    pub(crate) partitioner: Partitioner,
+    pub(crate) forward_dedup: ForwardDedup,
+    pub(crate) subscription_limits: SubscriptionLimits,
+    pub(crate) subscriptions_rejected: AtomicUsize,
 }
 
 impl ClusterShared {
@@ -274,17 +326,81 @@ impl ClusterShared {
         grpc_clients: GrpcClients,
         node_names: HashMap<NodeId, NodeName>,
         message_type: MessageType,
+        partition: Partition,
+        forward_dedup: ForwardDedup,
+        subscription_limits: SubscriptionLimits,
     ) -> &'static ClusterShared {
         static INSTANCE: OnceCell<ClusterShared> = OnceCell::new();
         INSTANCE.get_or_init(|| Self {
             inner: DefaultShared::instance(),
             router,
-            grpc_clients,
-            node_names,
+            grpc_clients: RwLock::new(grpc_clients),
+            node_names: RwLock::new(node_names),
             message_type,
+            partitioner: Partitioner::new(partition, router),
+            forward_dedup,
+            subscription_limits,
+            subscriptions_rejected: AtomicUsize::new(0),
         })
     }
 
+    ///A subscription that is not already held by the client is refused while the subscriptions of
+    ///the cluster or of the clients of this node have reached their limit
+    #[inline]
+    fn is_over_limit(&self, subscribe: &Subscribe, client_id: &str) -> bool {
+        let limits = &self.subscription_limits;
+        if !limits.is_limited() {
+            return false;
+        }
+        match limits.on_exceeded {
+            OnLimitExceeded::Allow => return false,
+            OnLimitExceeded::RejectWildcard if !subscribe.topic_filter.contains(|c| c == '+' || c == '#') => {
+                return false
+            }
+            _ => {}
+        }
+        let exceeded = (limits.max_total > 0 && self.router.relations_count() >= limits.max_total)
+            || (limits.max_per_node > 0
+                && Runtime::instance().stats.subscriptions.count().max(0) as usize >= limits.max_per_node);
+        exceeded && !self.router.is_subscribed(&subscribe.topic_filter, client_id)
+    }
+
+    ///Activates the subscription_limit alarm while a count is above the watermark of its limit
+    pub(crate) fn start_limit_check(&'static self) {
+        let limits = &self.subscription_limits;
+        if !limits.is_limited() {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut active = false;
+            loop {
+                tokio::time::sleep(limits.check_interval).await;
+                let usages = [
+                    ("cluster", self.router.relations_count(), limits.max_total),
+                    (
+                        "node",
+                        Runtime::instance().stats.subscriptions.count().max(0) as usize,
+                        limits.max_per_node,
+                    ),
+                ];
+                let high = usages
+                    .iter()
+                    .filter(|(_, count, max)| {
+                        *max > 0 && *count as f64 >= *max as f64 * limits.alarm_watermark
+                    })
+                    .map(|(scope, count, max)| format!("{} subscriptions {}/{}", scope, count, max))
+                    .collect::<Vec<_>>();
+                if !high.is_empty() {
+                    Alarms::instance().activate(SUBSCRIPTION_LIMIT, high.join(", ")).await;
+                    active = true;
+                } else if active {
+                    Alarms::instance().deactivate(SUBSCRIPTION_LIMIT).await;
+                    active = false;
+                }
+            }
+        });
+    }
+
     #[inline]
     pub(crate) fn router(&self) -> &'static ClusterRouter {
         self.router
@@ -297,7 +413,23 @@ impl ClusterShared {
 
     #[inline]
     pub(crate) fn grpc_client(&self, node_id: u64) -> Option<NodeGrpcClient> {
-        self.grpc_clients.get(&node_id).map(|(_, c)| c.clone())
+        self.grpc_clients.read().get(&node_id).map(|(_, c)| c.clone())
+    }
+
+    #[inline]
+    pub(crate) fn grpc_clients(&self) -> GrpcClients {
+        self.grpc_clients.read().clone()
+    }
+
+    ///Adds a node discovered after the start, the senders already running keep the nodes they
+    ///were created with
+    #[inline]
+    pub(crate) fn add_node(&self, id: NodeId, addr: Addr, client: NodeGrpcClient) {
+        let mut grpc_clients = self.grpc_clients.write();
+        let mut clients = grpc_clients.as_ref().clone();
+        clients.insert(id, (addr.clone(), client));
+        *grpc_clients = Arc::new(clients);
+        self.node_names.write().insert(id, format!("{}@{}", id, addr));
     }
 }
 
@@ -314,19 +446,27 @@ impl Shared for &'static ClusterShared {
     }
 
     #[inline]
-    async fn forwards(&self, from: From, publish: Publish) -> Result<(), Vec<(To, From, Publish, Reason)>> {
+    async fn forwards(
+        &self,
+        from: From,
+        mut publish: Publish,
+    ) -> Result<(), Vec<(To, From, Publish, Reason)>> {
         log::debug!("[forwards] from: {:?}, publish: {:?}", from, publish);
 
-        let topic = publish.topic();
         let mut relations_map =
-            match Runtime::instance().extends.router().await.matches(publish.topic()).await {
+            match Runtime::instance().extends.router().await.matches_publish(&publish).await {
                 Ok(relations_map) => relations_map,
                 Err(e) => {
-                    log::warn!("forwards, from:{:?}, topic:{:?}, error: {:?}", from, topic, e);
-                    SubRelationsMap::default()
+                    DefaultShared::router_error(from, publish, e).await;
+                    return Ok(());
                 }
             };
 
+        if relations_map.is_empty() {
+            DefaultShared::no_subscribers(from, publish).await;
+            return Ok(());
+        }
+
         let mut errs = Vec::new();
 
         let this_node_id = Runtime::instance().node.id();
@@ -338,10 +478,17 @@ impl Shared for &'static ClusterShared {
         }
         if !relations_map.is_empty() {
             log::debug!("forwards to other nodes, relations_map:{:?}", relations_map);
+            //the same key on each retry and on the buffered forwards, so that a node delivers it once
+            publish.forward_id = Some(ForwardId::next());
             //forwards to other nodes
+            let shared = *self;
             let mut fut_senders = Vec::new();
             for (node_id, relations) in relations_map {
-                if let Some(client) = self.grpc_client(node_id) {
+                if self.partitioner.is_ordered() {
+                    self.partitioner
+                        .buffer(shared, node_id, (from.clone(), publish.clone(), relations))
+                        .await;
+                } else if let Some(client) = self.grpc_client(node_id) {
                     let from = from.clone();
                     let publish = publish.clone();
                     let message_type = self.message_type;
@@ -349,11 +496,11 @@ impl Shared for &'static ClusterShared {
                         let mut msg_sender = MessageSender {
                             client,
                             msg_type: message_type,
-                            msg: Message::ForwardsTo(from, publish, relations),
+                            msg: Message::ForwardsTo(from, publish, relations.clone()),
                             max_retries: 1,
                             retry_interval: Duration::from_millis(500),
                         };
-                        (node_id, msg_sender.send().await)
+                        (node_id, relations, msg_sender.send().await)
                     };
                     fut_senders.push(fut_sender.boxed());
                 } else {
@@ -365,19 +512,33 @@ impl Shared for &'static ClusterShared {
                 }
             }
 
-            tokio::spawn(async move {
+            let publish = publish.clone();
+            let forwards_fut = async move {
                 let replys = futures::future::join_all(fut_senders).await;
-                for (node_id, reply) in replys {
-                    if let Err(e) = reply {
-                        log::error!(
-                            "forwards Message::ForwardsTo to other node, from: {:?}, to: {:?}, error: {:?}",
-                            from,
-                            node_id,
-                            e
-                        );
+                for (node_id, relations, reply) in replys {
+                    match reply {
+                        Ok(_) => shared.partitioner.reachable(node_id),
+                        Err(e) => {
+                            log::error!(
+                                "forwards Message::ForwardsTo to other node, from: {:?}, to: {:?}, error: {:?}",
+                                from,
+                                node_id,
+                                e
+                            );
+                            shared
+                                .partitioner
+                                .unreachable(shared, node_id, from.clone(), publish.clone(), relations)
+                                .await;
+                        }
                     }
                 }
-            });
+            };
+            //With strict ordering, the next publish is not forwarded until this one is
+            if Runtime::instance().settings.mqtt.strict_ordering {
+                forwards_fut.await;
+            } else {
+                tokio::spawn(forwards_fut);
+            }
         }
 
         if errs.is_empty() {
@@ -443,19 +604,19 @@ impl Shared for &'static ClusterShared {
 
     #[inline]
     fn get_grpc_clients(&self) -> GrpcClients {
-        self.grpc_clients.clone()
+        self.grpc_clients()
     }
 
     #[inline]
     fn node_name(&self, id: NodeId) -> String {
-        self.node_names.get(&id).cloned().unwrap_or_default()
+        self.node_names.read().get(&id).cloned().unwrap_or_default()
     }
 
     #[inline]
     async fn check_health(&self) -> Result<Option<serde_json::Value>> {
         let mut node_statuses = Vec::new();
         let mailbox = self.router.raft_mailbox().await;
-        let status = mailbox.status().await.map_err(Error::new)?;
+        let status = mailbox.get_status().await?;
         let mut leader_ids = HashSet::new();
         node_statuses.push(json!({
             "node_id": status.id,
@@ -464,10 +625,9 @@ impl Shared for &'static ClusterShared {
         leader_ids.insert(status.leader_id);
 
         let data = RaftGrpcMessage::GetRaftStatus.encode()?;
-        let replys =
-            MessageBroadcaster::new(self.grpc_clients.clone(), self.message_type, Message::Data(data))
-                .join_all()
-                .await;
+        let replys = MessageBroadcaster::new(self.grpc_clients(), self.message_type, Message::Data(data))
+            .join_all()
+            .await;
 
         for (node_id, reply) in replys {
             match reply {
@@ -0,0 +1,152 @@
+use std::collections::{BTreeMap, HashSet};
+
+use rmqtt::log;
+use rmqtt::{
+    broker::{
+        default::{DefaultRouter, DefaultShared},
+        types::{ClientId, Id, QoS, SharedGroup, TopicFilter},
+    },
+    Result,
+};
+
+use super::mailbox::MailboxExt;
+use super::message::Message;
+use super::router::ClusterRouter;
+
+///Sessions or subscriptions proposed in one raft entry
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+pub(crate) type Subscription = (TopicFilter, Id, QoS, Option<SharedGroup>);
+
+///The sessions and subscriptions of this node from before the cluster is started, when the plugin
+///is loaded at runtime into a broker running standalone
+#[derive(Default)]
+pub(crate) struct LocalState {
+    //(id, connected)
+    sessions: Vec<(Id, bool)>,
+    subscriptions: Vec<Subscription>,
+}
+
+impl LocalState {
+    ///Taken once before the raft groups are started, as a snapshot restored from the cluster
+    ///replaces the local subscriptions, and once more after the router and shared of the cluster
+    ///are installed, for those made in between
+    #[inline]
+    pub(crate) fn take() -> Self {
+        let sessions = DefaultShared::instance()
+            .iter()
+            .filter_map(|entry| {
+                let connected = entry.client().map(|c| c.is_connected()).unwrap_or_default();
+                entry.session().map(|s| (s.id.clone(), connected))
+            })
+            .collect();
+        let mut subscriptions = Vec::new();
+        for entry in DefaultRouter::instance().relations.iter() {
+            for (id, qos, shared_group) in entry.value().values() {
+                subscriptions.push((entry.key().clone(), id.clone(), *qos, shared_group.clone()));
+            }
+        }
+        Self { sessions, subscriptions }
+    }
+
+    ///Adds the sessions and subscriptions of other that are not in self, those of self win
+    pub(crate) fn merge(&mut self, other: LocalState) {
+        let clients = self.sessions.iter().map(|(id, _)| id.client_id.clone()).collect::<HashSet<ClientId>>();
+        self.sessions.extend(other.sessions.into_iter().filter(|(id, _)| !clients.contains(&id.client_id)));
+        let subscriptions = self
+            .subscriptions
+            .iter()
+            .map(|(topic_filter, id, _, _)| (topic_filter.clone(), id.client_id.clone()))
+            .collect::<HashSet<_>>();
+        self.subscriptions.extend(other.subscriptions.into_iter().filter(|(topic_filter, id, _, _)| {
+            !subscriptions.contains(&(topic_filter.clone(), id.client_id.clone()))
+        }));
+    }
+
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.sessions.is_empty() && self.subscriptions.is_empty()
+    }
+
+    ///Proposes the sessions and subscriptions to the raft groups in batches, so that the other nodes
+    ///route the messages to the clients of this node. The sessions and subscriptions already known
+    ///to the cluster are left as they are.
+    pub(crate) async fn import(self, router: &'static ClusterRouter) -> Result<()> {
+        log::info!(
+            "importing the local state, sessions: {}, subscriptions: {}",
+            self.sessions.len(),
+            self.subscriptions.len()
+        );
+        let raft_mailbox = router.raft_mailbox().await;
+        for sessions in self.sessions.chunks(IMPORT_BATCH_SIZE) {
+            let msg = Message::ImportSessions { sessions: sessions.to_vec() }.encode()?;
+            raft_mailbox.propose(msg).await?;
+        }
+        for (shard, subscriptions) in
+            by_shard(self.subscriptions, |topic_filter| router.shard_of(topic_filter))
+        {
+            let mailbox = router.group_mailbox(shard).await;
+            for subscriptions in subscriptions.chunks(IMPORT_BATCH_SIZE) {
+                let msg = Message::ImportSubscriptions { subscriptions: subscriptions.to_vec() }.encode()?;
+                mailbox.propose(msg).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+///The subscriptions by the shard of their topic filter
+#[inline]
+fn by_shard<F>(subscriptions: Vec<Subscription>, shard_of: F) -> BTreeMap<usize, Vec<Subscription>>
+where
+    F: Fn(&str) -> usize,
+{
+    let mut shards: BTreeMap<usize, Vec<Subscription>> = BTreeMap::new();
+    for subscription in subscriptions {
+        shards.entry(shard_of(&subscription.0)).or_default().push(subscription);
+    }
+    shards
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscription(topic_filter: &str, client_id: &str) -> Subscription {
+        (TopicFilter::from(topic_filter), Id::from(1, ClientId::from(client_id)), QoS::AtLeastOnce, None)
+    }
+
+    #[test]
+    fn merge() {
+        let mut state = LocalState {
+            sessions: vec![(Id::from(1, ClientId::from("c1")), true)],
+            subscriptions: vec![subscription("a/b", "c1")],
+        };
+        state.merge(LocalState {
+            sessions: vec![
+                (Id::from(1, ClientId::from("c1")), false),
+                (Id::from(1, ClientId::from("c2")), true),
+            ],
+            subscriptions: vec![
+                subscription("a/b", "c1"),
+                subscription("a/b", "c2"),
+                subscription("a/#", "c1"),
+            ],
+        });
+        assert_eq!(state.sessions.len(), 2);
+        assert!(state.sessions[0].1);
+        assert_eq!(state.subscriptions.len(), 3);
+        assert!(LocalState::default().is_empty());
+    }
+
+    #[test]
+    fn shards() {
+        let subscriptions =
+            vec![subscription("a/b", "c1"), subscription("x", "c1"), subscription("a/c", "c2")];
+        let shards =
+            by_shard(subscriptions, |topic_filter| if topic_filter.starts_with("a/") { 1 } else { 0 });
+        assert_eq!(shards.len(), 2);
+        assert_eq!(shards[&0].len(), 1);
+        assert_eq!(shards[&1].iter().map(|s| s.0.to_string()).collect::<Vec<_>>(), vec!["a/b", "a/c"]);
+    }
+}
@@ -1,14 +1,19 @@
 #![deny(unsafe_code)]
 
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 use rmqtt::broker::hook::Priority;
-use rmqtt::{async_trait::async_trait, log};
+use rmqtt::{async_trait::async_trait, log, once_cell::sync::OnceCell, DashMap};
 use rmqtt::{
     broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
-    broker::metrics::Metrics,
-    plugin::{DynPlugin, DynPluginResult, Plugin},
-    Result, Runtime,
+    broker::metrics::{DroppedReason, Metrics},
+    plugin::{DynPlugin, DynPluginResult, Metric, Plugin},
+    ConnectAckReason, ConnectAckReasonV5, Result, Runtime,
 };
 
+///Maximum number of topic prefixes of the dropped messages, the rest are counted as "others"
+const MAX_DROPPED_TOPIC_PREFIXES: usize = 1000;
+
 #[inline]
 pub async fn register(
     runtime: &'static Runtime,
@@ -42,7 +47,7 @@ impl CounterPlugin {
         descr: D,
     ) -> Result<Self> {
         let name = name.into();
-        let register = runtime.extends.hook_mgr().await.register();
+        let register = runtime.extends.hook_mgr().await.register_for(&name);
         Ok(Self { name, descr: descr.into(), register })
     }
 }
@@ -136,6 +141,58 @@ impl Plugin for CounterPlugin {
     fn descr(&self) -> &str {
         &self.descr
     }
+
+    #[inline]
+    fn reloadable(&self) -> bool {
+        false
+    }
+
+    #[inline]
+    async fn metrics(&self) -> Vec<Metric> {
+        let counter = DroppedCounter::instance();
+        let by_reason = counter.by_reason.iter().map(|e| {
+            Metric::counter("messages_dropped_by_reason", e.value().load(Ordering::Relaxed) as f64)
+                .label("reason", *e.key())
+                .descr("Dropped messages by the category of the reason")
+        });
+        let by_topic_prefix = counter.by_topic_prefix.iter().map(|e| {
+            Metric::counter("messages_dropped_by_topic_prefix", e.value().load(Ordering::Relaxed) as f64)
+                .label("prefix", e.key().as_str())
+                .descr("Dropped messages by the first level of the topic")
+        });
+        by_reason.chain(by_topic_prefix).collect()
+    }
+}
+
+///Dropped messages by the category of the reason and by the first level of the topic
+struct DroppedCounter {
+    by_reason: DashMap<&'static str, AtomicUsize>,
+    by_topic_prefix: DashMap<String, AtomicUsize>,
+}
+
+impl DroppedCounter {
+    #[inline]
+    fn instance() -> &'static DroppedCounter {
+        static INSTANCE: OnceCell<DroppedCounter> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { by_reason: DashMap::default(), by_topic_prefix: DashMap::default() })
+    }
+
+    #[inline]
+    fn inc(&self, reason: &str, topic: &str) {
+        self.by_reason
+            .entry(DroppedReason::classify(reason).as_str())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+
+        let prefix = topic.split('/').next().unwrap_or_default();
+        if let Some(c) = self.by_topic_prefix.get(prefix) {
+            c.fetch_add(1, Ordering::Relaxed);
+        } else if self.by_topic_prefix.len() < MAX_DROPPED_TOPIC_PREFIXES {
+            self.by_topic_prefix.entry(prefix.into()).or_default().fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.by_topic_prefix.entry("others".into()).or_default().fetch_add(1, Ordering::Relaxed);
+        }
+    }
 }
 
 struct CounterHandler {
@@ -173,6 +230,15 @@ impl Handler for CounterHandler {
                         if connect_info.username().is_none() {
                             self.metrics.client_auth_anonymous_error_inc();
                         }
+                        match reason {
+                            ConnectAckReason::V5(ConnectAckReasonV5::QuotaExceeded) => {
+                                self.metrics.client_connack_quota_exceeded_inc()
+                            }
+                            ConnectAckReason::V5(ConnectAckReasonV5::ConnectionRateExceeded) => {
+                                self.metrics.client_connack_connection_rate_exceeded_inc()
+                            }
+                            _ => {}
+                        }
                     }
                 }
             }
@@ -226,8 +292,10 @@ impl Handler for CounterHandler {
             Parameter::MessageAcked(_session, _client, _f, _p) => {
                 self.metrics.messages_acked_inc();
             }
-            Parameter::MessageDropped(_to, _from, _p, _r) => {
-                self.metrics.messages_dropped_inc(); //@TODO ... elaboration
+            Parameter::MessageDropped(_to, _from, p, r) => {
+                self.metrics.messages_dropped_inc();
+                self.metrics.messages_dropped_reason_inc(r);
+                DroppedCounter::instance().inc(r, &p.topic);
             }
 
             _ => {
@@ -0,0 +1,112 @@
+use std::convert::TryFrom;
+
+use rmqtt::serde_json;
+use rmqtt::settings::schema::{ConfigSchema, Field, FieldType};
+use rmqtt::{MqttError, QoS, Result};
+
+///Placeholder of the topic, replaced by the category of the reason of the drop
+pub const REASON_PLACEHOLDER: &str = "{reason}";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    ///Topic the dropped messages are republished to, "{reason}" is replaced by the category of the
+    ///reason of the drop, such as queue_full
+    #[serde(default = "PluginConfig::topic_default")]
+    pub topic: String,
+    #[serde(default = "PluginConfig::qos_default")]
+    pub qos: u8,
+    ///Categories of the reasons of the drops whose messages are republished
+    #[serde(default = "PluginConfig::reasons_default")]
+    pub reasons: Vec<String>,
+    ///Maximum number of dropped messages waiting to be republished, the excess is discarded
+    #[serde(default = "PluginConfig::queue_capacity_default")]
+    pub queue_capacity: usize,
+    ///Maximum dead letters republished per second, the excess is discarded, 0 is unlimited. The
+    ///drops come in bursts when the broker is overloaded, such as on full queues
+    #[serde(default = "PluginConfig::max_rate_default")]
+    pub max_rate: usize,
+    ///Whether the original payload is included in the dead letters
+    #[serde(default)]
+    pub include_payload: bool,
+}
+
+impl PluginConfig {
+    fn topic_default() -> String {
+        "$SYS/dead-letter/{reason}".into()
+    }
+
+    fn qos_default() -> u8 {
+        1
+    }
+
+    fn reasons_default() -> Vec<String> {
+        ["queue_full", "expired", "forward_failure", "retry_exhausted", "memory_budget"]
+            .iter()
+            .map(|r| r.to_string())
+            .collect()
+    }
+
+    fn queue_capacity_default() -> usize {
+        10_000
+    }
+
+    fn max_rate_default() -> usize {
+        1000
+    }
+
+    pub fn schema() -> ConfigSchema {
+        ConfigSchema::new()
+            .field(Field::new("topic", FieldType::String).default(Self::topic_default()).descr(
+                "Topic the dropped messages are republished to, \"{reason}\" is the reason of the drop",
+            ))
+            .field(Field::new("qos", FieldType::Integer).default(Self::qos_default()).options(vec![0, 1, 2]))
+            .field(
+                Field::new("reasons", FieldType::Array)
+                    .default(Self::reasons_default())
+                    .descr("Categories of the reasons of the drops whose messages are republished"),
+            )
+            .field(
+                Field::new("queue_capacity", FieldType::Integer)
+                    .default(Self::queue_capacity_default())
+                    .descr("Maximum number of dropped messages waiting to be republished"),
+            )
+            .field(
+                Field::new("max_rate", FieldType::Integer)
+                    .default(Self::max_rate_default())
+                    .descr("Maximum dead letters republished per second, 0 is unlimited"),
+            )
+            .field(
+                Field::new("include_payload", FieldType::Bool)
+                    .default(false)
+                    .descr("Whether the original payload is included in the dead letters"),
+            )
+    }
+
+    ///The topic must begin with a fixed prefix, by which the dead letters are recognized
+    #[inline]
+    pub fn validate(&self) -> Result<()> {
+        if self.topic.is_empty() || self.topic.starts_with(REASON_PLACEHOLDER) {
+            return Err(MqttError::from(format!("topic must begin with a fixed prefix, {:?}", self.topic)));
+        }
+        QoS::try_from(self.qos).map_err(|e| MqttError::from(format!("qos is invalid, {:?}", e)))?;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    ///The topic of the dead letters of the reason
+    #[inline]
+    pub fn topic(&self, reason: &str) -> String {
+        self.topic.replace(REASON_PLACEHOLDER, reason)
+    }
+
+    ///Whether the topic is of the dead letters, they are not republished when dropped
+    #[inline]
+    pub fn is_dead_letter(&self, topic: &str) -> bool {
+        let prefix = self.topic.split(REASON_PLACEHOLDER).next().unwrap_or_default();
+        topic.starts_with(prefix)
+    }
+}
@@ -0,0 +1,332 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use config::PluginConfig;
+use rmqtt::{
+    async_trait::async_trait,
+    base64, chrono, dashmap, log,
+    serde_json::{self, json},
+    tokio::{self, sync::mpsc},
+    RwLock,
+};
+use rmqtt::{
+    broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
+    broker::metrics::DroppedReason,
+    plugin::{DynPlugin, DynPluginResult, Metric, Plugin},
+    settings::schema::ConfigSchema,
+    From, Publish, PublishProperties, QoS, Reason, Result, Runtime, To, UserName,
+};
+
+mod config;
+
+#[inline]
+pub async fn register(
+    runtime: &'static Runtime,
+    name: &'static str,
+    descr: &'static str,
+    default_startup: bool,
+    immutable: bool,
+) -> Result<()> {
+    runtime
+        .plugins
+        .register(name, default_startup, immutable, move || -> DynPluginResult {
+            Box::pin(async move {
+                DeadLetterPlugin::new(runtime, name, descr).await.map(|p| -> DynPlugin { Box::new(p) })
+            })
+        })
+        .await?;
+    Ok(())
+}
+
+struct DeadLetter {
+    to: Option<To>,
+    from: From,
+    publish: Publish,
+    reason: Reason,
+    category: DroppedReason,
+}
+
+#[derive(Default)]
+struct Stats {
+    ///Dead letters republished, by the category of the reason
+    republished: dashmap::DashMap<&'static str, AtomicUsize>,
+    ///Dead letters discarded because queue_capacity was reached
+    discarded: AtomicUsize,
+    ///Dead letters discarded because max_rate was reached
+    rate_limited: AtomicUsize,
+    ///Dead letters that could not be delivered, such as without subscribers of the topic
+    undelivered: AtomicUsize,
+}
+
+type PluginConfigType = Arc<RwLock<PluginConfig>>;
+
+struct DeadLetterPlugin {
+    runtime: &'static Runtime,
+    name: String,
+    descr: String,
+    register: Box<dyn Register>,
+    cfg: PluginConfigType,
+    tx: mpsc::Sender<DeadLetter>,
+    stats: Arc<Stats>,
+}
+
+impl DeadLetterPlugin {
+    #[inline]
+    async fn new<N: Into<String>, D: Into<String>>(
+        runtime: &'static Runtime,
+        name: N,
+        descr: D,
+    ) -> Result<Self> {
+        let name = name.into();
+        let cfg = runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&name, &PluginConfig::schema())?;
+        cfg.validate()?;
+        log::info!("{} DeadLetterPlugin cfg: {:?}", name, cfg);
+        let register = runtime.extends.hook_mgr().await.register_for(&name);
+        let stats = Arc::new(Stats::default());
+        let (tx, rx) = mpsc::channel(cfg.queue_capacity.max(1));
+        let cfg = Arc::new(RwLock::new(cfg));
+        tokio::spawn(republish(cfg.clone(), stats.clone(), rx));
+        Ok(Self { runtime, name, descr: descr.into(), register, cfg, tx, stats })
+    }
+}
+
+#[async_trait]
+impl Plugin for DeadLetterPlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name);
+        self.register
+            .add(
+                Type::MessageDropped,
+                Box::new(DeadLetterHandler {
+                    cfg: self.cfg.clone(),
+                    tx: self.tx.clone(),
+                    stats: self.stats.clone(),
+                    window: AtomicI64::new(0),
+                    window_count: AtomicUsize::new(0),
+                }),
+            )
+            .await;
+        Ok(())
+    }
+
+    #[inline]
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        self.cfg.read().to_json()
+    }
+
+    #[inline]
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(PluginConfig::schema())
+    }
+
+    ///queue_capacity takes effect after a restart of the broker
+    #[inline]
+    async fn load_config(&mut self) -> Result<()> {
+        let new_cfg = self
+            .runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&self.name, &PluginConfig::schema())?;
+        new_cfg.validate()?;
+        *self.cfg.write() = new_cfg;
+        log::debug!("load_config ok,  {:?}", self.cfg);
+        Ok(())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name);
+        self.register.start().await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::info!("{} stop", self.name);
+        self.register.stop().await;
+        Ok(true)
+    }
+
+    #[inline]
+    fn version(&self) -> &str {
+        "0.1.0"
+    }
+
+    #[inline]
+    fn descr(&self) -> &str {
+        &self.descr
+    }
+
+    #[inline]
+    async fn metrics(&self) -> Vec<Metric> {
+        let mut metrics = self
+            .stats
+            .republished
+            .iter()
+            .map(|entry| {
+                Metric::counter("republished", entry.value().load(Ordering::SeqCst) as f64)
+                    .label("reason", *entry.key())
+                    .descr("Dropped messages republished to the dead-letter topic")
+            })
+            .collect::<Vec<_>>();
+        metrics.push(
+            Metric::counter("discarded", self.stats.discarded.load(Ordering::SeqCst) as f64)
+                .descr("Dropped messages not republished because queue_capacity was reached"),
+        );
+        metrics.push(
+            Metric::counter("rate_limited", self.stats.rate_limited.load(Ordering::SeqCst) as f64)
+                .descr("Dropped messages not republished because max_rate was reached"),
+        );
+        metrics.push(
+            Metric::counter("undelivered", self.stats.undelivered.load(Ordering::SeqCst) as f64)
+                .descr("Dead letters that could not be delivered, such as without subscribers"),
+        );
+        metrics
+    }
+}
+
+struct DeadLetterHandler {
+    cfg: PluginConfigType,
+    tx: mpsc::Sender<DeadLetter>,
+    stats: Arc<Stats>,
+    ///The second of the dead letters counted in window_count
+    window: AtomicI64,
+    window_count: AtomicUsize,
+}
+
+impl DeadLetterHandler {
+    ///Whether max_rate dead letters are already republished within the current second
+    #[inline]
+    fn is_rate_limited(&self, max_rate: usize) -> bool {
+        if max_rate == 0 {
+            return false;
+        }
+        let now = chrono::Local::now().timestamp();
+        let window = self.window.load(Ordering::SeqCst);
+        if window != now
+            && self.window.compare_exchange(window, now, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+        {
+            self.window_count.store(0, Ordering::SeqCst);
+        }
+        self.window_count.fetch_add(1, Ordering::SeqCst) >= max_rate
+    }
+}
+
+#[async_trait]
+impl Handler for DeadLetterHandler {
+    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+        if let Parameter::MessageDropped(to, from, publish, reason) = param {
+            let category = DroppedReason::classify(reason);
+            let max_rate = {
+                let cfg = self.cfg.read();
+                if cfg.is_dead_letter(&publish.topic) {
+                    //A dead letter that is dropped itself
+                    self.stats.undelivered.fetch_add(1, Ordering::SeqCst);
+                    return (true, acc);
+                }
+                if !cfg.reasons.iter().any(|r| r == category.as_str()) {
+                    return (true, acc);
+                }
+                cfg.max_rate
+            };
+            if self.is_rate_limited(max_rate) {
+                self.stats.rate_limited.fetch_add(1, Ordering::SeqCst);
+                return (true, acc);
+            }
+            let dead_letter = DeadLetter {
+                to: to.clone(),
+                from: from.clone(),
+                publish: publish.clone(),
+                reason: reason.clone(),
+                category,
+            };
+            if self.tx.try_send(dead_letter).is_err() {
+                self.stats.discarded.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        (true, acc)
+    }
+}
+
+///Republishes the dead letters out of the hook, so that their own drops do not re-enter it
+async fn republish(cfg: PluginConfigType, stats: Arc<Stats>, mut rx: mpsc::Receiver<DeadLetter>) {
+    while let Some(dead_letter) = rx.recv().await {
+        let (topic, qos, include_payload) = {
+            let cfg = cfg.read();
+            (
+                cfg.topic(dead_letter.category.as_str()),
+                QoS::try_from(cfg.qos).unwrap_or(QoS::AtLeastOnce),
+                cfg.include_payload,
+            )
+        };
+        let category = dead_letter.category.as_str();
+        let publish = Publish {
+            dup: false,
+            retain: false,
+            qos,
+            topic: topic.into(),
+            packet_id: None,
+            payload: serde_json::to_vec(&to_json(&dead_letter, include_payload)).unwrap_or_default().into(),
+            properties: PublishProperties::default(),
+            create_time: chrono::Local::now().timestamp_millis(),
+            trace_context: None,
+            forward_id: None,
+        };
+        let from = From::new(
+            Runtime::instance().node.id(),
+            None,
+            None,
+            "$dead-letter".into(),
+            Some(UserName::from("system")),
+        );
+        //the dead letters that are dropped are counted as undelivered by the hook
+        match Runtime::instance().extends.shared().await.forwards(from, publish).await {
+            Ok(()) => {
+                stats.republished.entry(category).or_default().fetch_add(1, Ordering::SeqCst);
+            }
+            Err(droppeds) => {
+                for (to, from, p, reason) in droppeds {
+                    Runtime::instance()
+                        .extends
+                        .hook_mgr()
+                        .await
+                        .message_dropped(Some(to), from, p, reason)
+                        .await;
+                }
+            }
+        }
+    }
+}
+
+///The original message and where it was going, the payload is base64 encoded if included
+fn to_json(dead_letter: &DeadLetter, include_payload: bool) -> serde_json::Value {
+    let publish = &dead_letter.publish;
+    json!({
+        "reason": dead_letter.category.as_str(),
+        "reason_detail": dead_letter.reason,
+        "topic": publish.topic,
+        "qos": publish.qos.value(),
+        "retain": publish.retain,
+        "payload": if include_payload { Some(base64::encode(&publish.payload)) } else { None },
+        "payload_size": publish.payload.len(),
+        "user_properties": publish.properties.user_properties,
+        "from": dead_letter.from.to_json(),
+        "to": dead_letter.to.as_ref().map(|to| to.to_json()),
+        "pts": publish.create_time,
+        "ts": chrono::Local::now().timestamp_millis(),
+    })
+}
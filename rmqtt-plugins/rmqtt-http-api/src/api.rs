@@ -1,7 +1,9 @@
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
+use std::str::FromStr;
 
 use salvo::affix;
-use salvo::http::header::{HeaderValue, CONTENT_TYPE};
+use salvo::http::header::{HeaderValue, CONTENT_DISPOSITION, CONTENT_TYPE};
 use salvo::prelude::*;
 
 use rmqtt::{
@@ -11,18 +13,29 @@ use rmqtt::{
     HashMap,
 };
 use rmqtt::{
+    broker::alarm::{Alarm, Alarms},
+    broker::evacuation::{EvacuateParams, Evacuation, EvacuationStatus},
+    broker::history::{ConnectionEvent, ConnectionHistory},
+    broker::hook::HandlerInfo,
+    broker::payload_limit::PayloadLimits,
+    broker::session::PayloadRedaction,
     broker::types::NodeId,
     grpc::{
-        client::NodeGrpcClient, Message as GrpcMessage, MessageBroadcaster, MessageReply as GrpcMessageReply,
-        MessageSender, MessageType,
+        client::NodeGrpcClient, registry::MessageTypes, Message as GrpcMessage, MessageBroadcaster,
+        MessageReply as GrpcMessageReply, MessageSender, MessageType,
     },
+    logger::log_levels,
     node::NodeStatus,
+    plugin::{Metric, MetricType},
     ClientId, Id, MqttError, Publish, PublishProperties, QoS, Result, Retain, Runtime, SubsSearchParams,
     TopicFilter, TopicName, UserName,
 };
 
+use super::topic_metrics::{TopicCount, TopicMetrics, TopicMetricsInfo};
+use super::trace::Tracer;
 use super::types::{
-    ClientSearchParams, Message, MessageReply, PublishParams, SubscribeParams, UnsubscribeParams,
+    AclInvalidateParams, ClientSearchParams, LogLevelParams, Message, MessageReply, PublishParams,
+    SubscribeParams, TopicMetricsParams, TraceParams, UnsubscribeParams,
 };
 use super::PluginConfigType;
 use super::{clients, plugin, subs};
@@ -32,14 +45,18 @@ fn route(cfg: PluginConfigType) -> Router {
         .hoop(affix::inject(cfg))
         .get(list_apis)
         .push(Router::with_path("brokers").get(get_brokers).push(Router::with_path("<id>").get(get_brokers)))
-        .push(Router::with_path("nodes").get(get_nodes).push(Router::with_path("<id>").get(get_nodes)))
+        .push(Router::with_path("nodes").get(get_nodes).push(Router::with_path("<id>").get(get_nodes).push(
+            Router::with_path("evacuation").get(get_evacuation).put(evacuate_node).delete(cancel_evacuation),
+        )))
         .push(Router::with_path("health/check").get(check_health))
         .push(
             Router::with_path("clients").get(search_clients).push(
                 Router::with_path("<clientid>")
                     .get(get_client)
                     .delete(kick_client)
-                    .push(Router::with_path("online").get(check_online)),
+                    .push(Router::with_path("online").get(check_online))
+                    .push(Router::with_path("history").get(get_client_history))
+                    .push(Router::with_path("dump").get(dump_client)),
             ),
         )
         .push(
@@ -60,9 +77,30 @@ fn route(cfg: PluginConfigType) -> Router {
                 .push(Router::with_path("<node>").get(node_plugins))
                 .push(Router::with_path("<node>/<plugin>").get(node_plugin_info))
                 .push(Router::with_path("<node>/<plugin>/config").get(node_plugin_config))
+                .push(Router::with_path("<node>/<plugin>/config/schema").get(node_plugin_config_schema))
                 .push(Router::with_path("<node>/<plugin>/config/reload").put(node_plugin_config_reload))
                 .push(Router::with_path("<node>/<plugin>/load").put(node_plugin_load))
-                .push(Router::with_path("<node>/<plugin>/unload").put(node_plugin_unload)),
+                .push(Router::with_path("<node>/<plugin>/unload").put(node_plugin_unload))
+                .push(Router::with_path("<node>/<plugin>/reload").put(node_plugin_reload))
+                .push(Router::with_path("<node>/<plugin>/send").post(node_plugin_send)),
+        )
+        .push(Router::with_path("hooks/<node>").get(node_hooks))
+        .push(Router::with_path("message-types/<node>").get(node_message_types))
+        .push(Router::with_path("acl/cache").delete(invalidate_acl_cache))
+        .push(Router::with_path("config/reload").put(config_reload))
+        .push(Router::with_path("log/level").get(get_log_levels).put(set_log_level).delete(reset_log_level))
+        .push(
+            Router::with_path("alarms")
+                .get(get_alarms)
+                .push(Router::with_path("deactivated").delete(clear_deactivated_alarms)),
+        )
+        .push(
+            Router::with_path("trace").get(list_traces).post(start_trace).push(
+                Router::with_path("<name>")
+                    .delete(remove_trace)
+                    .push(Router::with_path("stop").put(stop_trace))
+                    .push(Router::with_path("download").get(download_trace)),
+            ),
         )
         .push(
             Router::with_path("stats")
@@ -74,8 +112,16 @@ fn route(cfg: PluginConfigType) -> Router {
             Router::with_path("metrics")
                 .get(get_metrics)
                 .push(Router::with_path("sum").get(get_metrics_sum))
+                .push(Router::with_path("prometheus").get(get_metrics_prometheus))
                 .push(Router::with_path("<id>").get(get_metrics)),
         )
+        .push(
+            Router::with_path("topic-metrics")
+                .get(get_topic_metrics)
+                .push(Router::with_path("register").post(register_topic_metrics))
+                .push(Router::with_path("unregister").post(unregister_topic_metrics)),
+        )
+        .push(Router::with_path("payload-limits").get(get_payload_limits))
 }
 
 pub(crate) async fn listen_and_serve(
@@ -108,6 +154,24 @@ async fn list_apis(res: &mut Response) {
             "path": "/nodes/{node}",
             "descr": "Returns the status of the node"
         },
+        {
+            "name": "evacuate_node",
+            "method": "PUT",
+            "path": "/nodes/{node}/evacuation",
+            "descr": "Drains the node, its clients are redirected to the peers and its cluster roles are handed off"
+        },
+        {
+            "name": "get_evacuation",
+            "method": "GET",
+            "path": "/nodes/{node}/evacuation",
+            "descr": "Returns the progress of the evacuation of the node"
+        },
+        {
+            "name": "cancel_evacuation",
+            "method": "DELETE",
+            "path": "/nodes/{node}/evacuation",
+            "descr": "Cancels the evacuation of the node, it accepts connections again"
+        },
         {
             "name": "check_health",
             "method": "GET",
@@ -138,6 +202,18 @@ async fn list_apis(res: &mut Response) {
             "path": "/clients/{clientid}/online",
             "descr": "Check a client whether online from the cluster"
         },
+        {
+            "name": "get_client_history",
+            "method": "GET",
+            "path": "/clients/{clientid}/history",
+            "descr": "Returns the connect/disconnect events of a client on all nodes of the cluster, the newest first"
+        },
+        {
+            "name": "dump_client",
+            "method": "GET",
+            "path": "/clients/{clientid}/dump",
+            "descr": "Returns the complete state of the session of a client for diagnostics, the payloads redacted"
+        },
 
         {
             "name": "query_subscriptions",
@@ -208,6 +284,12 @@ async fn list_apis(res: &mut Response) {
             "path": "/plugins/{node}/{plugin}/config",
             "descr": "Get a plugin config"
         },
+        {
+            "name": "node_plugin_config_schema",
+            "method": "GET",
+            "path": "/plugins/{node}/{plugin}/config/schema",
+            "descr": "Get a plugin config schema"
+        },
         {
             "name": "node_plugin_config_reload",
             "method": "PUT",
@@ -226,6 +308,106 @@ async fn list_apis(res: &mut Response) {
             "path": "/plugins/{node}/{plugin}/unload",
             "descr": "Unload the specified plugin under the specified node."
         },
+        {
+            "name": "node_plugin_reload",
+            "method": "PUT",
+            "path": "/plugins/{node}/{plugin}/reload",
+            "descr": "Replace the running instance of the specified plugin under the specified node with a new one"
+        },
+        {
+            "name": "node_plugin_send",
+            "method": "POST",
+            "path": "/plugins/{node}/{plugin}/send",
+            "descr": "Send a JSON message to the specified plugin under the specified node, returns its reply"
+        },
+
+        {
+            "name": "node_hooks",
+            "method": "GET",
+            "path": "/hooks/{node}",
+            "descr": "Returns the hook handlers registered on the specified node, in the order they are executed"
+        },
+        {
+            "name": "node_message_types",
+            "method": "GET",
+            "path": "/message-types/{node}",
+            "descr": "Returns the grpc message types registered by the plugins on the specified node"
+        },
+        {
+            "name": "config_reload",
+            "method": "PUT",
+            "path": "/config/reload",
+            "descr": "Reload the main config and the plugin configs on all nodes of the cluster"
+        },
+
+        {
+            "name": "get_log_levels",
+            "method": "GET",
+            "path": "/log/level",
+            "descr": "Returns the default log level and the module log levels of this node"
+        },
+        {
+            "name": "set_log_level",
+            "method": "PUT",
+            "path": "/log/level",
+            "descr": "Change the log level of a module on all nodes of the cluster, with optional automatic revert"
+        },
+        {
+            "name": "reset_log_level",
+            "method": "DELETE",
+            "path": "/log/level?module={module}",
+            "descr": "Revert the log level of a module to the default on all nodes of the cluster"
+        },
+
+        {
+            "name": "get_alarms",
+            "method": "GET",
+            "path": "/alarms?activated={activated}",
+            "descr": "Returns the alarms of all nodes in the cluster, filtered by activated if specified"
+        },
+        {
+            "name": "clear_deactivated_alarms",
+            "method": "DELETE",
+            "path": "/alarms/deactivated",
+            "descr": "Clear the deactivated alarms on all nodes of the cluster"
+        },
+        {
+            "name": "invalidate_acl_cache",
+            "method": "DELETE",
+            "path": "/acl/cache",
+            "descr": "Invalidate the cached authentication and ACL decisions of the clients with the clientid or the username on all nodes of the cluster"
+        },
+
+        {
+            "name": "list_traces",
+            "method": "GET",
+            "path": "/trace",
+            "descr": "Returns all packet traces"
+        },
+        {
+            "name": "start_trace",
+            "method": "POST",
+            "path": "/trace",
+            "descr": "Start a time-limited packet trace on a clientid or topic filter in the cluster"
+        },
+        {
+            "name": "stop_trace",
+            "method": "PUT",
+            "path": "/trace/{name}/stop",
+            "descr": "Stop a packet trace"
+        },
+        {
+            "name": "download_trace",
+            "method": "GET",
+            "path": "/trace/{name}/download",
+            "descr": "Download the packet trace log of all nodes in the cluster"
+        },
+        {
+            "name": "remove_trace",
+            "method": "DELETE",
+            "path": "/trace/{name}",
+            "descr": "Remove a packet trace and its log"
+        },
 
         {
             "name": "get_stats",
@@ -252,6 +434,37 @@ async fn list_apis(res: &mut Response) {
             "path": "/metrics/sum",
             "descr": "Summarize all metrics information from the cluster"
         },
+        {
+            "name": "get_metrics_prometheus",
+            "method": "GET",
+            "path": "/metrics/prometheus",
+            "descr": "Summarize all metrics and topic metrics from the cluster, in Prometheus text format"
+        },
+
+        {
+            "name": "get_topic_metrics",
+            "method": "GET",
+            "path": "/topic-metrics",
+            "descr": "Summarize the metrics of the registered topics from the cluster"
+        },
+        {
+            "name": "register_topic_metrics",
+            "method": "POST",
+            "path": "/topic-metrics/register",
+            "descr": "Register a topic filter for metric collection"
+        },
+        {
+            "name": "unregister_topic_metrics",
+            "method": "POST",
+            "path": "/topic-metrics/unregister",
+            "descr": "Unregister a topic filter from metric collection"
+        },
+        {
+            "name": "get_payload_limits",
+            "method": "GET",
+            "path": "/payload-limits",
+            "descr": "Summarize the oversized publishes rejected by each topic filter of mqtt.topic_payload_limits from the cluster"
+        },
 
     ]);
     res.render(Json(data));
@@ -379,6 +592,110 @@ async fn _get_node(message_type: MessageType, id: NodeId) -> Result<Option<serde
     }
 }
 
+#[handler]
+async fn evacuate_node(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let message_type = cfg.read().message_type;
+    let node_id = if let Some(node_id) = req.param::<NodeId>("id") {
+        node_id
+    } else {
+        return res.set_status_code(StatusCode::NOT_FOUND);
+    };
+    let params = match req.parse_json::<EvacuateParams>().await {
+        Ok(p) => p,
+        Err(e) => return res.set_status_error(StatusError::bad_request().with_detail(e.to_string())),
+    };
+
+    match _evacuate_node(node_id, params, message_type).await {
+        Ok(()) => res.render(Text::Plain("ok")),
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+async fn _evacuate_node(node_id: NodeId, params: EvacuateParams, message_type: MessageType) -> Result<()> {
+    if node_id == Runtime::instance().node.id() {
+        Evacuation::instance().start(params)
+    } else {
+        let c = get_grpc_client(node_id).await?;
+        let msg = Message::Evacuate(params).encode()?;
+        let reply = MessageSender::new(c, message_type, GrpcMessage::Data(msg)).send().await?;
+        match reply {
+            GrpcMessageReply::Data(msg) => match MessageReply::decode(&msg)? {
+                MessageReply::Evacuate => Ok(()),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[handler]
+async fn get_evacuation(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let message_type = cfg.read().message_type;
+    let node_id = if let Some(node_id) = req.param::<NodeId>("id") {
+        node_id
+    } else {
+        return res.set_status_code(StatusCode::NOT_FOUND);
+    };
+
+    match _get_evacuation(node_id, message_type).await {
+        Ok(status) => res.render(Json(status)),
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+async fn _get_evacuation(node_id: NodeId, message_type: MessageType) -> Result<EvacuationStatus> {
+    if node_id == Runtime::instance().node.id() {
+        Ok(Evacuation::instance().status().await)
+    } else {
+        let c = get_grpc_client(node_id).await?;
+        let msg = Message::GetEvacuation.encode()?;
+        let reply = MessageSender::new(c, message_type, GrpcMessage::Data(msg)).send().await?;
+        match reply {
+            GrpcMessageReply::Data(msg) => match MessageReply::decode(&msg)? {
+                MessageReply::GetEvacuation(status) => Ok(status),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[handler]
+async fn cancel_evacuation(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let message_type = cfg.read().message_type;
+    let node_id = if let Some(node_id) = req.param::<NodeId>("id") {
+        node_id
+    } else {
+        return res.set_status_code(StatusCode::NOT_FOUND);
+    };
+
+    match _cancel_evacuation(node_id, message_type).await {
+        Ok(true) => res.render(Text::Plain("ok")),
+        Ok(false) => res.set_status_code(StatusCode::NOT_FOUND),
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+async fn _cancel_evacuation(node_id: NodeId, message_type: MessageType) -> Result<bool> {
+    if node_id == Runtime::instance().node.id() {
+        Ok(Evacuation::instance().cancel())
+    } else {
+        let c = get_grpc_client(node_id).await?;
+        let msg = Message::CancelEvacuation.encode()?;
+        let reply = MessageSender::new(c, message_type, GrpcMessage::Data(msg)).send().await?;
+        match reply {
+            GrpcMessageReply::Data(msg) => match MessageReply::decode(&msg)? {
+                MessageReply::CancelEvacuation(cancelled) => Ok(cancelled),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
 #[inline]
 async fn _get_nodes(message_type: MessageType) -> Result<Vec<serde_json::Value>> {
     let mut nodes = vec![Runtime::instance().node.node_info().await.to_json()];
@@ -551,6 +868,114 @@ async fn check_online(req: &mut Request, res: &mut Response) {
     }
 }
 
+#[handler]
+async fn get_client_history(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let message_type = cfg.read().message_type;
+    let clientid = if let Some(clientid) = req.param::<String>("clientid") {
+        clientid
+    } else {
+        return res.set_status_error(StatusError::bad_request());
+    };
+    match _get_client_history(message_type, &clientid).await {
+        Ok(mut events) => {
+            events.sort_by(|a, b| b.time.cmp(&a.time));
+            res.render(Json(events.iter().map(|e| e.to_json()).collect::<Vec<_>>()))
+        }
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+#[inline]
+async fn _get_client_history(message_type: MessageType, clientid: &str) -> Result<Vec<ConnectionEvent>> {
+    let mut events = ConnectionHistory::instance().get(&ClientId::from(clientid));
+
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let msg = Message::GetConnectionHistory { clientid }.encode()?;
+        for reply in
+            MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(msg)).join_all().await
+        {
+            match reply {
+                (_, Ok(GrpcMessageReply::Data(msg))) => match MessageReply::decode(&msg)? {
+                    MessageReply::GetConnectionHistory(o_events) => events.extend(o_events),
+                    _ => unreachable!(),
+                },
+                (id, Ok(GrpcMessageReply::Error(e))) => {
+                    log::warn!(
+                        "Get GrpcMessage::GetConnectionHistory from other node({}), error: {:?}",
+                        id,
+                        e
+                    );
+                }
+                (_, Ok(_)) => unreachable!(),
+                (id, Err(e)) => {
+                    log::warn!(
+                        "Get GrpcMessage::GetConnectionHistory from other node({}), error: {:?}",
+                        id,
+                        e
+                    );
+                }
+            }
+        }
+    }
+    Ok(events)
+}
+
+#[handler]
+async fn dump_client(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let message_type = cfg.read().message_type;
+    let clientid = if let Some(clientid) = req.param::<String>("clientid") {
+        clientid
+    } else {
+        return res.set_status_error(StatusError::bad_request());
+    };
+    let redaction = match req.query::<String>("payload").map(|p| PayloadRedaction::from_str(&p)) {
+        Some(Ok(redaction)) => redaction,
+        Some(Err(e)) => return res.set_status_error(StatusError::bad_request().with_detail(e.to_string())),
+        None => PayloadRedaction::default(),
+    };
+    match _dump_client(message_type, &clientid, redaction).await {
+        Ok(Some(dump)) => res.render(Json(dump)),
+        Ok(None) | Err(MqttError::None) => res.set_status_code(StatusCode::NOT_FOUND),
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+///The session is dumped by the node it is on
+async fn _dump_client(
+    message_type: MessageType,
+    clientid: &str,
+    redaction: PayloadRedaction,
+) -> Result<Option<serde_json::Value>> {
+    if let Some(dump) = clients::dump(clientid, redaction).await {
+        return Ok(Some(dump));
+    }
+
+    let check_result = |reply: GrpcMessageReply| match reply {
+        GrpcMessageReply::Data(res) => match MessageReply::decode(&res) {
+            Ok(MessageReply::ClientDump(Some(dump))) => Ok(dump),
+            Ok(MessageReply::ClientDump(None)) => Err(MqttError::None),
+            Err(e) => Err(e),
+            _ => unreachable!(),
+        },
+        GrpcMessageReply::Error(e) => Err(MqttError::from(e)),
+        _ => unreachable!(),
+    };
+
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let q = Message::ClientDump { clientid, redaction }.encode()?;
+        let dump = MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(q))
+            .select_ok(check_result)
+            .await?;
+        return Ok(Some(serde_json::from_slice(&dump)?));
+    }
+
+    Ok(None)
+}
+
 #[handler]
 async fn query_subscriptions(req: &mut Request, depot: &mut Depot, res: &mut Response) {
     let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
@@ -681,6 +1106,8 @@ async fn _publish(
         payload,
         properties: PublishProperties::default(),
         create_time: chrono::Local::now().timestamp_millis(),
+        trace_context: None,
+        forward_id: None,
     };
 
     let mut futs = Vec::new();
@@ -1036,7 +1463,7 @@ async fn _node_plugin_config(node_id: NodeId, name: &str, message_type: MessageT
 }
 
 #[handler]
-async fn node_plugin_config_reload(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+async fn node_plugin_config_schema(req: &mut Request, depot: &mut Depot, res: &mut Response) {
     let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
     let message_type = cfg.read().message_type;
     let node_id = if let Some(node_id) = req.param::<NodeId>("node") {
@@ -1050,31 +1477,40 @@ async fn node_plugin_config_reload(req: &mut Request, depot: &mut Depot, res: &m
         return res.set_status_code(StatusCode::NOT_FOUND);
     };
 
-    match _node_plugin_config_reload(node_id, &name, message_type).await {
-        Ok(()) => res.render(Text::Plain("ok")),
+    match _node_plugin_config_schema(node_id, &name, message_type).await {
+        Ok(schema) => {
+            res.headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json; charset=utf-8"));
+            res.write_body(schema).ok();
+        }
         Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
     }
 }
 
-async fn _node_plugin_config_reload(node_id: NodeId, name: &str, message_type: MessageType) -> Result<()> {
-    if node_id == Runtime::instance().node.id() {
-        Runtime::instance().plugins.load_config(name).await
+async fn _node_plugin_config_schema(
+    node_id: NodeId,
+    name: &str,
+    message_type: MessageType,
+) -> Result<Vec<u8>> {
+    let schema = if node_id == Runtime::instance().node.id() {
+        plugin::get_plugin_config_schema(name).await?
     } else {
         let c = get_grpc_client(node_id).await?;
-        let msg = Message::ReloadPluginConfig { name }.encode()?;
+        let msg = Message::GetPluginConfigSchema { name }.encode()?;
         let reply = MessageSender::new(c, message_type, GrpcMessage::Data(msg)).send().await?;
         match reply {
             GrpcMessageReply::Data(msg) => match MessageReply::decode(&msg)? {
-                MessageReply::ReloadPluginConfig => Ok(()),
+                MessageReply::GetPluginConfigSchema(schema) => schema,
                 _ => unreachable!(),
             },
             _ => unreachable!(),
         }
-    }
+    };
+    Ok(schema)
 }
 
 #[handler]
-async fn node_plugin_load(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+async fn node_plugin_config_reload(req: &mut Request, depot: &mut Depot, res: &mut Response) {
     let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
     let message_type = cfg.read().message_type;
     let node_id = if let Some(node_id) = req.param::<NodeId>("node") {
@@ -1088,15 +1524,53 @@ async fn node_plugin_load(req: &mut Request, depot: &mut Depot, res: &mut Respon
         return res.set_status_code(StatusCode::NOT_FOUND);
     };
 
-    match _node_plugin_load(node_id, &name, message_type).await {
+    match _node_plugin_config_reload(node_id, &name, message_type).await {
         Ok(()) => res.render(Text::Plain("ok")),
         Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
     }
 }
 
-async fn _node_plugin_load(node_id: NodeId, name: &str, message_type: MessageType) -> Result<()> {
+async fn _node_plugin_config_reload(node_id: NodeId, name: &str, message_type: MessageType) -> Result<()> {
     if node_id == Runtime::instance().node.id() {
-        Runtime::instance().plugins.start(name).await
+        Runtime::instance().plugins.load_config(name).await
+    } else {
+        let c = get_grpc_client(node_id).await?;
+        let msg = Message::ReloadPluginConfig { name }.encode()?;
+        let reply = MessageSender::new(c, message_type, GrpcMessage::Data(msg)).send().await?;
+        match reply {
+            GrpcMessageReply::Data(msg) => match MessageReply::decode(&msg)? {
+                MessageReply::ReloadPluginConfig => Ok(()),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[handler]
+async fn node_plugin_load(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let message_type = cfg.read().message_type;
+    let node_id = if let Some(node_id) = req.param::<NodeId>("node") {
+        node_id
+    } else {
+        return res.set_status_code(StatusCode::NOT_FOUND);
+    };
+    let name = if let Some(name) = req.param::<String>("plugin") {
+        name
+    } else {
+        return res.set_status_code(StatusCode::NOT_FOUND);
+    };
+
+    match _node_plugin_load(node_id, &name, message_type).await {
+        Ok(()) => res.render(Text::Plain("ok")),
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+async fn _node_plugin_load(node_id: NodeId, name: &str, message_type: MessageType) -> Result<()> {
+    if node_id == Runtime::instance().node.id() {
+        Runtime::instance().plugins.start(name).await
     } else {
         let c = get_grpc_client(node_id).await?;
         let msg = Message::LoadPlugin { name }.encode()?;
@@ -1112,41 +1586,652 @@ async fn _node_plugin_load(node_id: NodeId, name: &str, message_type: MessageTyp
 }
 
 #[handler]
-async fn node_plugin_unload(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+async fn node_plugin_unload(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let message_type = cfg.read().message_type;
+    let node_id = if let Some(node_id) = req.param::<NodeId>("node") {
+        node_id
+    } else {
+        return res.set_status_code(StatusCode::NOT_FOUND);
+    };
+    let name = if let Some(name) = req.param::<String>("plugin") {
+        name
+    } else {
+        return res.set_status_code(StatusCode::NOT_FOUND);
+    };
+
+    match _node_plugin_unload(node_id, &name, message_type).await {
+        Ok(r) => res.render(Json(r)),
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+async fn _node_plugin_unload(node_id: NodeId, name: &str, message_type: MessageType) -> Result<bool> {
+    if node_id == Runtime::instance().node.id() {
+        Runtime::instance().plugins.stop(name).await
+    } else {
+        let c = get_grpc_client(node_id).await?;
+        let msg = Message::UnloadPlugin { name }.encode()?;
+        let reply = MessageSender::new(c, message_type, GrpcMessage::Data(msg)).send().await?;
+        match reply {
+            GrpcMessageReply::Data(msg) => match MessageReply::decode(&msg)? {
+                MessageReply::UnloadPlugin(ok) => Ok(ok),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[handler]
+async fn node_plugin_reload(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let message_type = cfg.read().message_type;
+    let node_id = if let Some(node_id) = req.param::<NodeId>("node") {
+        node_id
+    } else {
+        return res.set_status_code(StatusCode::NOT_FOUND);
+    };
+    let name = if let Some(name) = req.param::<String>("plugin") {
+        name
+    } else {
+        return res.set_status_code(StatusCode::NOT_FOUND);
+    };
+
+    match _node_plugin_reload(node_id, &name, message_type).await {
+        Ok(()) => res.render(Text::Plain("ok")),
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+async fn _node_plugin_reload(node_id: NodeId, name: &str, message_type: MessageType) -> Result<()> {
+    if node_id == Runtime::instance().node.id() {
+        Runtime::instance().plugins.reload(name).await
+    } else {
+        let c = get_grpc_client(node_id).await?;
+        let msg = Message::ReloadPlugin { name }.encode()?;
+        let reply = MessageSender::new(c, message_type, GrpcMessage::Data(msg)).send().await?;
+        match reply {
+            GrpcMessageReply::Data(msg) => match MessageReply::decode(&msg)? {
+                MessageReply::ReloadPlugin => Ok(()),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[handler]
+async fn node_plugin_send(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let message_type = cfg.read().message_type;
+    let node_id = if let Some(node_id) = req.param::<NodeId>("node") {
+        node_id
+    } else {
+        return res.set_status_code(StatusCode::NOT_FOUND);
+    };
+    let name = if let Some(name) = req.param::<String>("plugin") {
+        name
+    } else {
+        return res.set_status_code(StatusCode::NOT_FOUND);
+    };
+    if !cfg.read().send_plugins.contains(&name) {
+        return res.set_status_error(
+            StatusError::forbidden().with_detail(format!("sending to {} is not allowed", name)),
+        );
+    }
+    let msg = match req.parse_json::<serde_json::Value>().await {
+        Ok(msg) => msg,
+        Err(e) => return res.set_status_error(StatusError::bad_request().with_detail(e.to_string())),
+    };
+
+    match _node_plugin_send(node_id, &name, msg, message_type).await {
+        Ok(reply) => {
+            res.headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json; charset=utf-8"));
+            res.write_body(reply).ok();
+        }
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+async fn _node_plugin_send(
+    node_id: NodeId,
+    name: &str,
+    msg: serde_json::Value,
+    message_type: MessageType,
+) -> Result<Vec<u8>> {
+    let msg = serde_json::to_vec(&msg)?;
+    if node_id == Runtime::instance().node.id() {
+        plugin::send_plugin(name, &msg).await
+    } else {
+        let c = get_grpc_client(node_id).await?;
+        let msg = Message::SendPlugin { name, msg }.encode()?;
+        let reply = MessageSender::new(c, message_type, GrpcMessage::Data(msg)).send().await?;
+        match reply {
+            GrpcMessageReply::Data(msg) => match MessageReply::decode(&msg)? {
+                MessageReply::SendPlugin(reply) => Ok(reply),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[handler]
+async fn node_hooks(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let message_type = cfg.read().message_type;
+    let node_id = if let Some(node_id) = req.param::<NodeId>("node") {
+        node_id
+    } else {
+        return res.set_status_code(StatusCode::NOT_FOUND);
+    };
+    match _node_hooks(node_id, message_type).await {
+        Ok(handlers) => res.render(Json(handlers)),
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+async fn _node_hooks(node_id: NodeId, message_type: MessageType) -> Result<Vec<HandlerInfo>> {
+    if node_id == Runtime::instance().node.id() {
+        Ok(Runtime::instance().extends.hook_mgr().await.handlers().await)
+    } else {
+        let c = get_grpc_client(node_id).await?;
+        let msg = Message::GetHooks.encode()?;
+        let reply = MessageSender::new(c, message_type, GrpcMessage::Data(msg)).send().await?;
+        match reply {
+            GrpcMessageReply::Data(msg) => match MessageReply::decode(&msg)? {
+                MessageReply::GetHooks(handlers) => Ok(handlers),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[handler]
+async fn node_message_types(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let message_type = cfg.read().message_type;
+    let node_id = if let Some(node_id) = req.param::<NodeId>("node") {
+        node_id
+    } else {
+        return res.set_status_code(StatusCode::NOT_FOUND);
+    };
+    match _node_message_types(node_id, message_type).await {
+        Ok(message_types) => {
+            let message_types = message_types
+                .into_iter()
+                .map(|(typ, name)| json!({"message_type": typ, "name": name}))
+                .collect::<Vec<_>>();
+            res.render(Json(message_types))
+        }
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+async fn _node_message_types(
+    node_id: NodeId,
+    message_type: MessageType,
+) -> Result<Vec<(MessageType, String)>> {
+    if node_id == Runtime::instance().node.id() {
+        Ok(MessageTypes::instance().list())
+    } else {
+        let c = get_grpc_client(node_id).await?;
+        let msg = Message::GetMessageTypes.encode()?;
+        let reply = MessageSender::new(c, message_type, GrpcMessage::Data(msg)).send().await?;
+        match reply {
+            GrpcMessageReply::Data(msg) => match MessageReply::decode(&msg)? {
+                MessageReply::GetMessageTypes(message_types) => Ok(message_types),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[handler]
+async fn config_reload(depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let message_type = cfg.read().message_type;
+
+    match _config_reload(message_type).await {
+        Ok(replys) => res.render(Json(replys)),
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+async fn _config_reload(message_type: MessageType) -> Result<HashMap<NodeId, serde_json::Value>> {
+    let mut replys = HashMap::default();
+    replys.insert(Runtime::instance().node.id(), Runtime::instance().reload_config().await?.to_json());
+
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let msg = Message::ReloadConfig.encode()?;
+        for reply in
+            MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(msg)).join_all().await
+        {
+            let data = match reply {
+                (id, Ok(GrpcMessageReply::Data(msg))) => match MessageReply::decode(&msg)? {
+                    MessageReply::ReloadConfig(res) => (id, res.to_json()),
+                    _ => unreachable!(),
+                },
+                (id, Ok(GrpcMessageReply::Error(e))) => (id, serde_json::Value::String(e)),
+                (_, Ok(_)) => unreachable!(),
+                (id, Err(e)) => {
+                    log::warn!("Reload config on other node({}), error: {:?}", id, e);
+                    (id, serde_json::Value::String(e.to_string()))
+                }
+            };
+            replys.insert(data.0, data.1);
+        }
+    }
+    Ok(replys)
+}
+
+#[handler]
+async fn get_log_levels(res: &mut Response) {
+    res.render(Json(log_levels().to_json()))
+}
+
+#[handler]
+async fn set_log_level(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let message_type = cfg.read().message_type;
+    let params = match req.parse_json::<LogLevelParams>().await {
+        Ok(p) => p,
+        Err(e) => return res.set_status_error(StatusError::bad_request().with_detail(e.to_string())),
+    };
+    let item = match log_levels().set(&params.module, &params.level, params.duration) {
+        Ok(item) => item,
+        Err(e) => return res.set_status_error(StatusError::bad_request().with_detail(e.to_string())),
+    };
+    let msg =
+        Message::LogLevelSet { module: &params.module, level: &params.level, duration: params.duration };
+    match _broadcast(message_type, msg).await {
+        Ok(()) => res.render(Json(item)),
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+#[handler]
+async fn reset_log_level(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let message_type = cfg.read().message_type;
+    let module = req.query::<String>("module").unwrap_or_default();
+    let removed = log_levels().reset(&module);
+    match _broadcast(message_type, Message::LogLevelReset { module: &module }).await {
+        Ok(()) => res.render(Json(removed)),
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+#[handler]
+async fn list_traces(res: &mut Response) {
+    let traces = Tracer::instance().list().iter().map(|t| t.to_json()).collect::<Vec<_>>();
+    res.render(Json(traces))
+}
+
+#[handler]
+async fn start_trace(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let (message_type, trace_dir) = {
+        let cfg = cfg.read();
+        (cfg.message_type, cfg.trace_dir.clone())
+    };
+    let params = match req.parse_json::<TraceParams>().await {
+        Ok(p) => p,
+        Err(e) => return res.set_status_error(StatusError::bad_request().with_detail(e.to_string())),
+    };
+    let target = match params.target() {
+        Ok(target) => target,
+        Err(e) => return res.set_status_error(StatusError::bad_request().with_detail(e.to_string())),
+    };
+
+    let info = match Tracer::instance().start(
+        &trace_dir,
+        &params.name,
+        target.clone(),
+        params.payload,
+        params.duration,
+    ) {
+        Ok(info) => info,
+        Err(e) => return res.set_status_error(StatusError::bad_request().with_detail(e.to_string())),
+    };
+
+    let msg = Message::TraceStart {
+        name: &params.name,
+        target,
+        payload: params.payload,
+        duration: params.duration,
+    };
+    match _broadcast(message_type, msg).await {
+        Ok(()) => res.render(Json(info.to_json())),
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+#[handler]
+async fn stop_trace(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let message_type = cfg.read().message_type;
+    let name = if let Some(name) = req.param::<String>("name") {
+        name
+    } else {
+        return res.set_status_code(StatusCode::NOT_FOUND);
+    };
+    if !Tracer::instance().stop(&name) {
+        return res.set_status_code(StatusCode::NOT_FOUND);
+    }
+    match _broadcast(message_type, Message::TraceStop { name: &name }).await {
+        Ok(()) => res.render(Text::Plain("ok")),
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+#[handler]
+async fn remove_trace(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let (message_type, trace_dir) = {
+        let cfg = cfg.read();
+        (cfg.message_type, cfg.trace_dir.clone())
+    };
+    let name = if let Some(name) = req.param::<String>("name") {
+        name
+    } else {
+        return res.set_status_code(StatusCode::NOT_FOUND);
+    };
+    match Tracer::instance().remove(&trace_dir, &name).await {
+        Ok(true) => {}
+        Ok(false) => return res.set_status_code(StatusCode::NOT_FOUND),
+        Err(e) => return res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+    match _broadcast(message_type, Message::TraceRemove { name: &name }).await {
+        Ok(()) => res.render(Text::Plain("ok")),
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+async fn _broadcast(message_type: MessageType, msg: Message<'_>) -> Result<()> {
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let msg = msg.encode()?;
+        for reply in
+            MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(msg)).join_all().await
+        {
+            match reply {
+                (_, Ok(GrpcMessageReply::Data(_))) => {}
+                (id, Ok(GrpcMessageReply::Error(e))) => {
+                    log::warn!("Broadcast to other node({}), error: {:?}", id, e);
+                }
+                (_, Ok(_)) => unreachable!(),
+                (id, Err(e)) => {
+                    log::warn!("Broadcast to other node({}), error: {:?}", id, e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[handler]
+async fn invalidate_acl_cache(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let message_type = cfg.read().message_type;
+    let q = AclInvalidateParams {
+        clientid: req.query::<String>("clientid").map(ClientId::from),
+        username: req.query::<String>("username"),
+    };
+    if q.clientid.is_none() && q.username.is_none() {
+        return res.set_status_error(
+            StatusError::bad_request().with_detail("at least one of clientid and username is required"),
+        );
+    }
+    match _invalidate_acl_cache(message_type, q).await {
+        Ok(n) => res.render(Json(n)),
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+#[inline]
+async fn _invalidate_acl_cache(message_type: MessageType, q: AclInvalidateParams) -> Result<usize> {
+    let mut n = clients::acl_invalidate(&q).await;
+
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let msg = Message::AclInvalidate(q).encode()?;
+        for reply in
+            MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(msg)).join_all().await
+        {
+            match reply {
+                (_, Ok(GrpcMessageReply::Data(msg))) => match MessageReply::decode(&msg)? {
+                    MessageReply::AclInvalidate(o_n) => n += o_n,
+                    _ => unreachable!(),
+                },
+                (id, Ok(GrpcMessageReply::Error(e))) => {
+                    log::warn!("Get GrpcMessage::AclInvalidate from other node({}), error: {:?}", id, e);
+                }
+                (_, Ok(_)) => unreachable!(),
+                (id, Err(e)) => {
+                    log::warn!("Get GrpcMessage::AclInvalidate from other node({}), error: {:?}", id, e);
+                }
+            }
+        }
+    }
+    Ok(n)
+}
+
+#[handler]
+async fn get_payload_limits(depot: &mut Depot, res: &mut Response) {
     let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
     let message_type = cfg.read().message_type;
-    let node_id = if let Some(node_id) = req.param::<NodeId>("node") {
-        node_id
-    } else {
-        return res.set_status_code(StatusCode::NOT_FOUND);
+    match _get_payload_limits(message_type).await {
+        Ok(limits) => res.render(Json(limits)),
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+#[inline]
+async fn _get_payload_limits(message_type: MessageType) -> Result<Vec<serde_json::Value>> {
+    let mut limits = PayloadLimits::instance().violations();
+
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let msg = Message::GetPayloadLimits.encode()?;
+        for reply in
+            MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(msg)).join_all().await
+        {
+            match reply {
+                (_, Ok(GrpcMessageReply::Data(msg))) => match MessageReply::decode(&msg)? {
+                    MessageReply::GetPayloadLimits(o_limits) => {
+                        for (topic_filter, _, o_violations) in o_limits {
+                            if let Some((_, _, violations)) =
+                                limits.iter_mut().find(|(tf, _, _)| *tf == topic_filter)
+                            {
+                                *violations += o_violations;
+                            }
+                        }
+                    }
+                    _ => unreachable!(),
+                },
+                (id, Ok(GrpcMessageReply::Error(e))) => {
+                    log::warn!("Get GrpcMessage::GetPayloadLimits from other node({}), error: {:?}", id, e);
+                }
+                (_, Ok(_)) => unreachable!(),
+                (id, Err(e)) => {
+                    log::warn!("Get GrpcMessage::GetPayloadLimits from other node({}), error: {:?}", id, e);
+                }
+            }
+        }
+    }
+    Ok(limits
+        .into_iter()
+        .map(|(topic_filter, max_size, violations)| {
+            json!({
+                "topic_filter": topic_filter,
+                "max_size": max_size,
+                "violations": violations,
+            })
+        })
+        .collect())
+}
+
+#[handler]
+async fn get_alarms(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let message_type = cfg.read().message_type;
+    let activated = req.query::<bool>("activated");
+    match _get_alarms(message_type).await {
+        Ok(alarms) => {
+            let alarms = alarms
+                .iter()
+                .filter(|a| activated.map(|activated| activated == a.is_active()).unwrap_or(true))
+                .map(|a| a.to_json())
+                .collect::<Vec<_>>();
+            res.render(Json(alarms))
+        }
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+#[inline]
+async fn _get_alarms(message_type: MessageType) -> Result<Vec<Alarm>> {
+    let mut alarms = Alarms::instance().actives();
+    alarms.extend(Alarms::instance().deactivateds());
+
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let msg = Message::GetAlarms.encode()?;
+        for reply in
+            MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(msg)).join_all().await
+        {
+            match reply {
+                (_, Ok(GrpcMessageReply::Data(msg))) => match MessageReply::decode(&msg)? {
+                    MessageReply::GetAlarms(o_alarms) => alarms.extend(o_alarms),
+                    _ => unreachable!(),
+                },
+                (id, Ok(GrpcMessageReply::Error(e))) => {
+                    log::warn!("Get GrpcMessage::GetAlarms from other node({}), error: {:?}", id, e);
+                }
+                (_, Ok(_)) => unreachable!(),
+                (id, Err(e)) => {
+                    log::warn!("Get GrpcMessage::GetAlarms from other node({}), error: {:?}", id, e);
+                }
+            }
+        }
+    }
+    Ok(alarms)
+}
+
+#[handler]
+async fn clear_deactivated_alarms(depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let message_type = cfg.read().message_type;
+    Alarms::instance().clear_deactivateds();
+    match _broadcast(message_type, Message::ClearDeactivatedAlarms).await {
+        Ok(()) => res.render(Text::Plain("ok")),
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+#[handler]
+async fn download_trace(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let (message_type, trace_dir) = {
+        let cfg = cfg.read();
+        (cfg.message_type, cfg.trace_dir.clone())
     };
-    let name = if let Some(name) = req.param::<String>("plugin") {
+    let name = if let Some(name) = req.param::<String>("name") {
         name
     } else {
         return res.set_status_code(StatusCode::NOT_FOUND);
     };
+    //"<node id>:<offset>,...", the log of each node from its offset on, in bytes
+    let offsets = match req
+        .query::<String>("offsets")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|o| !o.is_empty())
+        .map(|o| {
+            o.split_once(':')
+                .and_then(|(id, offset)| Some((id.parse::<NodeId>().ok()?, offset.parse::<u64>().ok()?)))
+        })
+        .collect::<Option<HashMap<_, _>>>()
+    {
+        Some(offsets) => offsets,
+        None => {
+            return res.set_status_error(StatusError::bad_request().with_detail("invalid offsets"));
+        }
+    };
 
-    match _node_plugin_unload(node_id, &name, message_type).await {
-        Ok(r) => res.render(Json(r)),
+    match _download_trace(message_type, &trace_dir, &name, &offsets).await {
+        Ok(Some(data)) => {
+            res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+            if let Ok(disposition) = HeaderValue::from_str(&format!("attachment; filename=\"{}.log\"", name))
+            {
+                res.headers_mut().insert(CONTENT_DISPOSITION, disposition);
+            }
+            res.write_body(data).ok();
+        }
+        Ok(None) => res.set_status_code(StatusCode::NOT_FOUND),
         Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
     }
 }
 
-async fn _node_plugin_unload(node_id: NodeId, name: &str, message_type: MessageType) -> Result<bool> {
-    if node_id == Runtime::instance().node.id() {
-        Runtime::instance().plugins.stop(name).await
-    } else {
-        let c = get_grpc_client(node_id).await?;
-        let msg = Message::UnloadPlugin { name }.encode()?;
-        let reply = MessageSender::new(c, message_type, GrpcMessage::Data(msg)).send().await?;
-        match reply {
-            GrpcMessageReply::Data(msg) => match MessageReply::decode(&msg)? {
-                MessageReply::UnloadPlugin(ok) => Ok(ok),
-                _ => unreachable!(),
-            },
-            _ => unreachable!(),
+async fn _download_trace(
+    message_type: MessageType,
+    trace_dir: &str,
+    name: &str,
+    offsets: &HashMap<NodeId, u64>,
+) -> Result<Option<Vec<u8>>> {
+    let this_id = Runtime::instance().node.id();
+    let offset = |id: NodeId| offsets.get(&id).copied().unwrap_or_default();
+    let mut data = match Tracer::instance().read(trace_dir, name, offset(this_id))? {
+        Some(log) => {
+            let mut data = format!("## node: {}\n", this_id).into_bytes();
+            data.extend(log);
+            data
+        }
+        None => return Ok(None),
+    };
+
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        //TraceLog without an offset, so that the nodes of a previous version return their whole log
+        let replys = futures::future::join_all(grpc_clients.iter().map(|(id, (_, c))| async move {
+            let msg = match offset(*id) {
+                0 => Message::TraceLog { name }.encode(),
+                offset => Message::TraceLogFrom { name, offset }.encode(),
+            };
+            let reply = match msg {
+                Ok(msg) => MessageSender::new(c.clone(), message_type, GrpcMessage::Data(msg)).send().await,
+                Err(e) => Err(e),
+            };
+            (*id, reply)
+        }))
+        .await;
+        for reply in replys {
+            match reply {
+                (id, Ok(GrpcMessageReply::Data(msg))) => match MessageReply::decode(&msg)? {
+                    MessageReply::TraceLog(Some(log)) => {
+                        data.extend(format!("## node: {}\n", id).into_bytes());
+                        data.extend(log);
+                    }
+                    MessageReply::TraceLog(None) => {}
+                    _ => unreachable!(),
+                },
+                (id, Ok(GrpcMessageReply::Error(e))) => {
+                    log::warn!("Get trace log from other node({}), error: {:?}", id, e);
+                }
+                (_, Ok(_)) => unreachable!(),
+                (id, Err(e)) => {
+                    log::warn!("Get trace log from other node({}), error: {:?}", id, e);
+                }
+            }
         }
     }
+    Ok(Some(data))
 }
 
 #[handler]
@@ -1431,6 +2516,311 @@ async fn _build_metrics(id: NodeId, metrics: serde_json::Value) -> serde_json::V
     data
 }
 
+#[handler]
+async fn get_metrics_prometheus(depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let (message_type, top_k) = {
+        let cfg = cfg.read();
+        (cfg.message_type, cfg.topic_metrics_top_k)
+    };
+
+    let metrics_sum = match _get_metrics_sum(message_type).await {
+        Ok(metrics_sum) => metrics_sum,
+        Err(e) => return res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    };
+    let topic_metrics = match _get_topic_metrics_sum(message_type, top_k).await {
+        Ok(topic_metrics) => topic_metrics,
+        Err(e) => return res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    };
+    let node_stats = match _get_stats_all(message_type).await {
+        Ok(node_stats) => node_stats,
+        Err(e) => return res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    };
+    let plugin_metrics = match _get_plugin_metrics_all(message_type).await {
+        Ok(plugin_metrics) => plugin_metrics,
+        Err(e) => return res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    };
+
+    let mut body = String::new();
+    if let Some(metrics) = metrics_sum.as_object() {
+        for (name, val) in metrics.iter() {
+            let name = format!("rmqtt_{}", name.replace('.', "_"));
+            body.push_str(&format!("# TYPE {} counter\n{} {}\n", name, name, val));
+        }
+    }
+
+    let process_gauges = [
+        ("rmqtt_process_cpu_usage_percent", "process_cpu.count"),
+        ("rmqtt_process_resident_memory_bytes", "process_memory.count"),
+        ("rmqtt_process_open_fds", "process_fds.count"),
+        ("rmqtt_tasks_active", "tasks_active.count"),
+        ("rmqtt_tasks_waiting", "tasks_waiting.count"),
+    ];
+    for (name, key) in process_gauges.iter() {
+        body.push_str(&format!("# TYPE {} gauge\n", name));
+        for stats in node_stats.iter() {
+            if let (Some(id), Some(val)) = (stats["node"]["id"].as_u64(), stats["stats"].get(key)) {
+                body.push_str(&format!("{}{{node=\"{}\"}} {}\n", name, id, val));
+            }
+        }
+    }
+
+    body.push_str("# TYPE rmqtt_connections_tagged gauge\n");
+    for stats in node_stats.iter() {
+        if let (Some(id), Some(tagged)) =
+            (stats["node"]["id"].as_u64(), stats["stats"]["tagged_connections"].as_array())
+        {
+            for t in tagged.iter() {
+                body.push_str(&format!(
+                    "rmqtt_connections_tagged{{node=\"{}\",tag=\"{}\",value=\"{}\"}} {}\n",
+                    id,
+                    escape_label_value(t["tag"].as_str().unwrap_or_default()),
+                    escape_label_value(t["value"].as_str().unwrap_or_default()),
+                    t["count"]
+                ));
+            }
+        }
+    }
+
+    //the messages and bytes are summed over the nodes that answer and restart from 0 when the topic
+    //filter is registered again, so they go down as well
+    let topic_gauges: [(&str, fn(&TopicMetricsInfo) -> i64); 4] = [
+        ("rmqtt_topic_messages", |m| m.messages as i64),
+        ("rmqtt_topic_bytes", |m| m.bytes as i64),
+        ("rmqtt_topic_subscribers", |m| m.subscribers as i64),
+        ("rmqtt_topic_last_publish_timestamp_ms", |m| m.last_publish_at),
+    ];
+    for (name, value) in topic_gauges.iter() {
+        body.push_str(&format!("# TYPE {} gauge\n", name));
+        for m in topic_metrics.iter() {
+            body.push_str(&format!(
+                "{}{{topic=\"{}\"}} {}\n",
+                name,
+                escape_label_value(&m.topic_filter),
+                value(m)
+            ));
+        }
+    }
+
+    //the top_k most published topics of each wildcard topic filter, which bounds the series
+    let topic_counters: [(&str, fn(&TopicCount) -> usize); 2] =
+        [("rmqtt_topic_top_messages", |c| c.messages), ("rmqtt_topic_top_bytes", |c| c.bytes)];
+    for (name, value) in topic_counters.iter() {
+        body.push_str(&format!("# TYPE {} gauge\n", name));
+        for m in topic_metrics.iter() {
+            for c in m.topics.iter() {
+                body.push_str(&format!(
+                    "{}{{topic_filter=\"{}\",topic=\"{}\"}} {}\n",
+                    name,
+                    escape_label_value(&m.topic_filter),
+                    escape_label_value(&c.topic),
+                    value(c)
+                ));
+            }
+        }
+    }
+
+    //plugin metrics, grouped by name so that each one is declared once
+    let mut families: BTreeMap<String, (MetricType, String, Vec<String>)> = BTreeMap::new();
+    for (id, plugins) in plugin_metrics.iter() {
+        for (plugin, metrics) in plugins.iter() {
+            for m in metrics.iter() {
+                let name = metric_name(&format!("{}_{}", plugin, m.name));
+                let mut labels = vec![format!("node=\"{}\"", id)];
+                labels.extend(
+                    m.labels.iter().map(|(k, v)| format!("{}=\"{}\"", metric_name(k), escape_label_value(v))),
+                );
+                let family =
+                    families.entry(name.clone()).or_insert_with(|| (m.typ, m.descr.clone(), Vec::new()));
+                family.2.push(format!("{}{{{}}} {}\n", name, labels.join(","), m.value));
+            }
+        }
+    }
+    for (name, (typ, descr, samples)) in families {
+        if !descr.is_empty() {
+            body.push_str(&format!("# HELP {} {}\n", name, descr.replace('\\', "\\\\").replace('\n', "\\n")));
+        }
+        body.push_str(&format!("# TYPE {} {}\n", name, typ.as_str()));
+        for sample in samples {
+            body.push_str(&sample);
+        }
+    }
+
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; version=0.0.4"));
+    res.write_body(body).ok();
+}
+
+#[inline]
+fn escape_label_value(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+///Replaces the characters not allowed in a metric or label name
+#[inline]
+fn metric_name(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' }).collect()
+}
+
+async fn _get_plugin_metrics_all(
+    message_type: MessageType,
+) -> Result<Vec<(NodeId, Vec<(String, Vec<Metric>)>)>> {
+    let mut metrics = vec![(Runtime::instance().node.id(), Runtime::instance().plugins.metrics().await)];
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let msg = Message::GetPluginMetrics.encode()?;
+        for reply in
+            MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(msg)).join_all().await
+        {
+            match reply {
+                (id, Ok(GrpcMessageReply::Data(msg))) => match MessageReply::decode(&msg)? {
+                    MessageReply::GetPluginMetrics(node_metrics) => metrics.push((id, node_metrics)),
+                    _ => unreachable!(),
+                },
+                (_, Ok(_)) => unreachable!(),
+                (id, Err(e)) => {
+                    log::warn!("Get GrpcMessage::GetPluginMetrics from other node({}), error: {:?}", id, e);
+                }
+            };
+        }
+    }
+    Ok(metrics)
+}
+
+#[handler]
+async fn get_topic_metrics(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let (message_type, top_k) = {
+        let cfg = cfg.read();
+        (cfg.message_type, cfg.topic_metrics_top_k)
+    };
+    let topic = req.query::<String>("topic");
+
+    match _get_topic_metrics_sum(message_type, top_k).await {
+        Ok(topic_metrics) => {
+            let topic_metrics = topic_metrics
+                .iter()
+                .filter(|m| topic.as_ref().map(|t| t.as_str() == m.topic_filter.as_ref()).unwrap_or(true))
+                .map(|m| m.to_json())
+                .collect::<Vec<_>>();
+            res.render(Json(topic_metrics))
+        }
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+async fn _get_topic_metrics_sum(message_type: MessageType, top_k: usize) -> Result<Vec<TopicMetricsInfo>> {
+    let mut topic_metrics: HashMap<TopicFilter, TopicMetricsInfo> =
+        TopicMetrics::instance().list(top_k).await.into_iter().map(|m| (m.topic_filter.clone(), m)).collect();
+
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let msg = Message::TopicMetricsInfo.encode()?;
+        for reply in
+            MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(msg)).join_all().await
+        {
+            match reply {
+                (_id, Ok(GrpcMessageReply::Data(msg))) => match MessageReply::decode(&msg)? {
+                    MessageReply::TopicMetricsInfo(infos) => {
+                        for info in infos {
+                            topic_metrics
+                                .entry(info.topic_filter.clone())
+                                .or_insert_with(|| TopicMetricsInfo {
+                                    topic_filter: info.topic_filter.clone(),
+                                    ..Default::default()
+                                })
+                                .add(&info);
+                        }
+                    }
+                    _ => unreachable!(),
+                },
+                (_, Ok(_)) => unreachable!(),
+                (id, Err(e)) => {
+                    log::warn!("Get GrpcMessage::TopicMetricsInfo from other node({}), error: {:?}", id, e);
+                }
+            };
+        }
+    }
+
+    let mut topic_metrics = topic_metrics.into_values().collect::<Vec<_>>();
+    for m in topic_metrics.iter_mut() {
+        m.truncate_topics(top_k);
+    }
+    topic_metrics.sort_by(|a, b| a.topic_filter.cmp(&b.topic_filter));
+    Ok(topic_metrics)
+}
+
+#[handler]
+async fn register_topic_metrics(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let (message_type, max_filters) = {
+        let cfg = cfg.read();
+        (cfg.message_type, cfg.topic_metrics_max_filters)
+    };
+    let params = match req.parse_json::<TopicMetricsParams>().await {
+        Ok(p) => p,
+        Err(e) => return res.set_status_error(StatusError::bad_request().with_detail(e.to_string())),
+    };
+
+    match _register_topic_metrics(message_type, &params.topic, true, max_filters).await {
+        Ok(ok) => res.render(Json(ok)),
+        Err(e) => res.set_status_error(StatusError::bad_request().with_detail(e.to_string())),
+    }
+}
+
+#[handler]
+async fn unregister_topic_metrics(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let message_type = cfg.read().message_type;
+    let params = match req.parse_json::<TopicMetricsParams>().await {
+        Ok(p) => p,
+        Err(e) => return res.set_status_error(StatusError::bad_request().with_detail(e.to_string())),
+    };
+
+    match _register_topic_metrics(message_type, &params.topic, false, 0).await {
+        Ok(ok) => res.render(Json(ok)),
+        Err(e) => res.set_status_error(StatusError::bad_request().with_detail(e.to_string())),
+    }
+}
+
+///Register or unregister the topic filter on all nodes of the cluster, the registration fails
+///once max_filters are registered on this node
+async fn _register_topic_metrics(
+    message_type: MessageType,
+    topic_filter: &str,
+    register: bool,
+    max_filters: usize,
+) -> Result<bool> {
+    let ok = if register {
+        TopicMetrics::instance().register(topic_filter, max_filters)?
+    } else {
+        TopicMetrics::instance().unregister(topic_filter)?
+    };
+
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let msg = if register {
+            Message::TopicMetricsRegister { topic_filter }.encode()?
+        } else {
+            Message::TopicMetricsUnregister { topic_filter }.encode()?
+        };
+        for reply in
+            MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(msg)).join_all().await
+        {
+            match reply {
+                (_, Ok(GrpcMessageReply::Data(_))) => {}
+                (id, Ok(GrpcMessageReply::Error(e))) => {
+                    log::warn!("Register topic metrics to other node({}), error: {:?}", id, e);
+                }
+                (_, Ok(_)) => unreachable!(),
+                (id, Err(e)) => {
+                    log::warn!("Register topic metrics to other node({}), error: {:?}", id, e);
+                }
+            }
+        }
+    }
+    Ok(ok)
+}
+
 #[inline]
 async fn get_grpc_client(node_id: NodeId) -> Result<NodeGrpcClient> {
     Runtime::instance()
@@ -1,7 +1,12 @@
+use std::sync::atomic::Ordering;
+
+use rmqtt::broker::session::PayloadRedaction;
 use rmqtt::{broker::Entry, ClientId, ClientInfo, Id, Runtime, Session, TimestampMillis};
-use rmqtt::{chrono, futures};
+use rmqtt::{chrono, futures, serde_json};
 
-use super::types::{ClientSearchParams as SearchParams, ClientSearchResult as SearchResult};
+use super::types::{
+    AclInvalidateParams, ClientSearchParams as SearchParams, ClientSearchResult as SearchResult,
+};
 
 pub(crate) async fn get(clientid: &str) -> Option<SearchResult> {
     let shared = Runtime::instance().extends.shared().await;
@@ -19,6 +24,27 @@ pub(crate) async fn get(clientid: &str) -> Option<SearchResult> {
     Some(build_result(Some(s), Some(c)).await)
 }
 
+///The complete state of the client and its session on this node, for diagnostics
+pub(crate) async fn dump(clientid: &str, redaction: PayloadRedaction) -> Option<serde_json::Value> {
+    let shared = Runtime::instance().extends.shared().await;
+    if !shared.exist(clientid) {
+        return None;
+    }
+
+    let id = Id::from(Runtime::instance().node.id(), ClientId::from(clientid));
+    let peer = shared.entry(id);
+    let (s, c) = if let (Some(s), Some(c)) = (peer.session(), peer.client()) {
+        (s, c)
+    } else {
+        return None;
+    };
+    Some(serde_json::json!({
+        "node_id": Runtime::instance().node.id(),
+        "client": c.to_json().await,
+        "session": s.dump(redaction).await,
+    }))
+}
+
 pub(crate) async fn search(q: &SearchParams) -> Vec<SearchResult> {
     let limit = q._limit;
     let mut curr: usize = 0;
@@ -77,21 +103,52 @@ async fn build_result(s: Option<Session>, c: Option<ClientInfo>) -> SearchResult
         connected_at,
         disconnected_at,
         disconnected_reason,
+        disconnected_kind: c.disconnected_kind(),
         keepalive: c.connect_info.keep_alive(),
         clean_start: c.connect_info.clean_start(),
         session_present: c.session_present,
         expiry_interval,
         created_at: s.created_at / 1000,
         subscriptions_cnt: s.subscriptions.len(),
-        max_subscriptions: s.listen_cfg.max_subscriptions,
+        max_subscriptions: s.max_subscriptions,
         extra_attrs,
+        tags: c.tags(),
 
         inflight,
-        max_inflight: s.listen_cfg.max_inflight,
+        max_inflight: s.max_inflight,
 
         mqueue_len: s.deliver_queue.len(),
         max_mqueue: s.listen_cfg.max_mqueue_len,
+
+        recv_msg: s.stats.msgs_in.load(Ordering::Relaxed),
+        recv_bytes: s.stats.bytes_in.load(Ordering::Relaxed),
+        send_msg: s.stats.msgs_out.load(Ordering::Relaxed),
+        send_bytes: s.stats.bytes_out.load(Ordering::Relaxed),
+        ackeds: s.stats.acked.load(Ordering::Relaxed),
+        dropped: s.stats.dropped.load(Ordering::Relaxed),
+        last_activity: s.stats.last_activity.load(Ordering::Relaxed) / 1000,
+    }
+}
+
+///Invalidates the cached authentication and ACL decisions of the clients of this node with the
+///client ID or the username, returns the number of these clients
+pub(crate) async fn acl_invalidate(q: &AclInvalidateParams) -> usize {
+    let clients = Runtime::instance()
+        .extends
+        .shared()
+        .await
+        .iter()
+        .filter_map(|entry| entry.session().zip(entry.client()))
+        .filter(|(s, c)| {
+            q.clientid.as_ref().map(|clientid| *clientid == s.id.client_id).unwrap_or(true)
+                && q.username.as_ref().map(|username| username == c.username_ref()).unwrap_or(true)
+        })
+        .collect::<Vec<_>>();
+    let hook_mgr = Runtime::instance().extends.hook_mgr().await;
+    for (s, c) in clients.iter() {
+        hook_mgr.client_acl_invalidate(s, c).await;
     }
+    clients.len()
 }
 
 fn filtering(q: &SearchParams, entry: &dyn Entry) -> bool {
@@ -4,6 +4,7 @@ use std::time::Duration;
 use rmqtt::serde_json;
 use rmqtt::{
     grpc::MessageType,
+    settings::schema::{ConfigSchema, Field, FieldType},
     settings::{deserialize_addr, deserialize_duration},
     Result,
 };
@@ -27,6 +28,31 @@ pub struct PluginConfig {
 
     #[serde(default = "PluginConfig::message_type_default")]
     pub message_type: MessageType,
+
+    #[serde(default = "PluginConfig::trace_dir_default")]
+    pub trace_dir: String,
+
+    ///Maximum number of topic filters registered for topic metrics, 0 is unlimited
+    #[serde(default = "PluginConfig::topic_metrics_max_filters_default")]
+    pub topic_metrics_max_filters: usize,
+
+    ///Maximum number of topics tracked under each wildcard topic filter, 0 disables the
+    ///per-topic breakdown
+    #[serde(default = "PluginConfig::topic_metrics_max_topics_default")]
+    pub topic_metrics_max_topics: usize,
+
+    ///Topics are tracked by their first N levels, 0 keeps the whole topic
+    #[serde(default)]
+    pub topic_metrics_collapse_levels: usize,
+
+    ///Number of the most published topics returned for each topic filter
+    #[serde(default = "PluginConfig::topic_metrics_top_k_default")]
+    pub topic_metrics_top_k: usize,
+
+    ///Plugins that accept messages through POST /plugins/{node}/{plugin}/send, the others are
+    ///refused with 403
+    #[serde(default = "PluginConfig::send_plugins_default")]
+    pub send_plugins: Vec<String>,
 }
 
 impl PluginConfig {
@@ -50,17 +76,84 @@ impl PluginConfig {
         99
     }
 
+    fn trace_dir_default() -> String {
+        "/var/log/rmqtt/trace".into()
+    }
+
+    fn topic_metrics_max_filters_default() -> usize {
+        1000
+    }
+
+    fn topic_metrics_max_topics_default() -> usize {
+        1000
+    }
+
+    fn topic_metrics_top_k_default() -> usize {
+        20
+    }
+
+    fn send_plugins_default() -> Vec<String> {
+        vec!["rmqtt-last-value".into()]
+    }
+
     #[inline]
     pub fn to_json(&self) -> Result<serde_json::Value> {
         Ok(serde_json::to_value(self)?)
     }
 
+    pub fn schema() -> ConfigSchema {
+        ConfigSchema::new()
+            .field(Field::new("workers", FieldType::Integer).default(Self::workers_default()))
+            .field(Field::new("max_row_limit", FieldType::Integer).default(Self::max_row_limit_default()))
+            .field(
+                Field::new("http_laddr", FieldType::Addr)
+                    .default(Self::http_laddr_default().to_string())
+                    .descr("Address of the HTTP listener"),
+            )
+            .field(
+                Field::new("metrics_sample_interval", FieldType::Duration)
+                    .default_duration(Self::metrics_sample_interval_default()),
+            )
+            .field(Field::new("message_type", FieldType::Integer).default(Self::message_type_default()))
+            .field(Field::new("trace_dir", FieldType::String).default(Self::trace_dir_default()))
+            .field(
+                Field::new("topic_metrics_max_filters", FieldType::Integer)
+                    .default(Self::topic_metrics_max_filters_default())
+                    .descr("Maximum number of topic filters registered for topic metrics, 0 is unlimited"),
+            )
+            .field(
+                Field::new("topic_metrics_max_topics", FieldType::Integer)
+                    .default(Self::topic_metrics_max_topics_default())
+                    .descr("Maximum number of topics tracked under each wildcard topic filter"),
+            )
+            .field(
+                Field::new("topic_metrics_collapse_levels", FieldType::Integer)
+                    .default(0)
+                    .descr("Topics are tracked by their first N levels, 0 keeps the whole topic"),
+            )
+            .field(
+                Field::new("topic_metrics_top_k", FieldType::Integer)
+                    .default(Self::topic_metrics_top_k_default())
+                    .descr("Number of the most published topics returned for each topic filter"),
+            )
+            .field(
+                Field::new("send_plugins", FieldType::Array)
+                    .default(Self::send_plugins_default())
+                    .descr("Plugins that accept messages through POST /plugins/{node}/{plugin}/send"),
+            )
+    }
+
     #[inline]
     pub fn changed(&self, other: &Self) -> bool {
         self.workers != other.workers
             || self.max_row_limit != other.max_row_limit
             || self.http_laddr != other.http_laddr
             || self.metrics_sample_interval != other.metrics_sample_interval
+            || self.trace_dir != other.trace_dir
+            || self.topic_metrics_max_filters != other.topic_metrics_max_filters
+            || self.topic_metrics_max_topics != other.topic_metrics_max_topics
+            || self.topic_metrics_collapse_levels != other.topic_metrics_collapse_levels
+            || self.topic_metrics_top_k != other.topic_metrics_top_k
     }
 
     #[inline]
@@ -1,22 +1,30 @@
-use rmqtt::{async_trait::async_trait, log};
+use rmqtt::{async_trait::async_trait, log, logger::log_levels, serde_json};
 use rmqtt::{
+    broker::alarm::Alarms,
+    broker::evacuation::Evacuation,
+    broker::history::ConnectionHistory,
     broker::hook::{Handler, HookResult, Parameter, ReturnType},
-    grpc::{Message as GrpcMessage, MessageReply as GrpcMessageReply, MessageType},
-    Runtime,
+    broker::payload_limit::PayloadLimits,
+    grpc::{registry::MessageTypes, Message as GrpcMessage, MessageReply as GrpcMessageReply, MessageType},
+    ClientId, Runtime,
 };
 
 use super::clients;
 use super::plugin;
 use super::subs;
+use super::topic_metrics::TopicMetrics;
+use super::trace::Tracer;
 use super::types::{Message, MessageReply};
+use super::PluginConfigType;
 
 pub(crate) struct HookHandler {
     pub message_type: MessageType,
+    cfg: PluginConfigType,
 }
 
 impl HookHandler {
-    pub(crate) fn new(message_type: MessageType) -> Self {
-        Self { message_type }
+    pub(crate) fn new(message_type: MessageType, cfg: PluginConfigType) -> Self {
+        Self { message_type, cfg }
     }
 }
 
@@ -181,6 +189,23 @@ impl Handler for HookHandler {
                                     ))),
                                 }
                             }
+                            Ok(Message::GetPluginConfigSchema { name }) => {
+                                match plugin::get_plugin_config_schema(name).await {
+                                    Ok(schema) => {
+                                        match MessageReply::GetPluginConfigSchema(schema).encode() {
+                                            Ok(ress) => {
+                                                HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                            }
+                                            Err(e) => HookResult::GrpcMessageReply(Ok(
+                                                GrpcMessageReply::Error(e.to_string()),
+                                            )),
+                                        }
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
                             Ok(Message::ReloadPluginConfig { name }) => {
                                 match Runtime::instance().plugins.load_config(name).await {
                                     Ok(()) => match MessageReply::ReloadPluginConfig.encode() {
@@ -226,6 +251,326 @@ impl Handler for HookHandler {
                                     ))),
                                 }
                             }
+                            Ok(Message::TopicMetricsRegister { topic_filter }) => {
+                                let max_filters = self.cfg.read().topic_metrics_max_filters;
+                                match TopicMetrics::instance().register(topic_filter, max_filters) {
+                                    Ok(ok) => match MessageReply::TopicMetricsRegister(ok).encode() {
+                                        Ok(ress) => {
+                                            HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                        }
+                                        Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                            e.to_string(),
+                                        ))),
+                                    },
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::TopicMetricsUnregister { topic_filter }) => {
+                                match TopicMetrics::instance().unregister(topic_filter) {
+                                    Ok(ok) => match MessageReply::TopicMetricsUnregister(ok).encode() {
+                                        Ok(ress) => {
+                                            HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                        }
+                                        Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                            e.to_string(),
+                                        ))),
+                                    },
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::GetPayloadLimits) => {
+                                let limits = PayloadLimits::instance().violations();
+                                match MessageReply::GetPayloadLimits(limits).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::TopicMetricsInfo) => {
+                                let top_k = self.cfg.read().topic_metrics_top_k;
+                                let infos = TopicMetrics::instance().list(top_k).await;
+                                match MessageReply::TopicMetricsInfo(infos).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::ReloadConfig) => match Runtime::instance().reload_config().await {
+                                Ok(res) => match MessageReply::ReloadConfig(res).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                },
+                                Err(e) => {
+                                    HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(e.to_string())))
+                                }
+                            },
+                            Ok(Message::TraceStart { name, target, payload, duration }) => {
+                                let trace_dir = self.cfg.read().trace_dir.clone();
+                                match Tracer::instance().start(&trace_dir, name, target, payload, duration) {
+                                    Ok(info) => match MessageReply::TraceStart(info).encode() {
+                                        Ok(ress) => {
+                                            HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                        }
+                                        Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                            e.to_string(),
+                                        ))),
+                                    },
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::LogLevelSet { module, level, duration }) => {
+                                match log_levels()
+                                    .set(module, level, duration)
+                                    .and_then(|_| MessageReply::LogLevelSet.encode())
+                                {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::LogLevelReset { module }) => {
+                                match MessageReply::LogLevelReset(log_levels().reset(module)).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::GetHooks) => {
+                                let handlers = Runtime::instance().extends.hook_mgr().await.handlers().await;
+                                match MessageReply::GetHooks(handlers).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::GetMessageTypes) => {
+                                let message_types = MessageTypes::instance().list();
+                                match MessageReply::GetMessageTypes(message_types).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::GetPluginMetrics) => {
+                                let metrics = Runtime::instance().plugins.metrics().await;
+                                match MessageReply::GetPluginMetrics(metrics).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::Evacuate(params)) => match Evacuation::instance().start(params) {
+                                Ok(()) => match MessageReply::Evacuate.encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                },
+                                Err(e) => {
+                                    HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(e.to_string())))
+                                }
+                            },
+                            Ok(Message::GetEvacuation) => {
+                                let status = Evacuation::instance().status().await;
+                                match MessageReply::GetEvacuation(status).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::CancelEvacuation) => {
+                                let cancelled = Evacuation::instance().cancel();
+                                match MessageReply::CancelEvacuation(cancelled).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::GetAlarms) => {
+                                let mut alarms = Alarms::instance().actives();
+                                alarms.extend(Alarms::instance().deactivateds());
+                                match MessageReply::GetAlarms(alarms).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::ClearDeactivatedAlarms) => {
+                                let c = Alarms::instance().clear_deactivateds();
+                                match MessageReply::ClearDeactivatedAlarms(c).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::AclInvalidate(q)) => {
+                                let n = clients::acl_invalidate(&q).await;
+                                match MessageReply::AclInvalidate(n).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::ReloadPlugin { name }) => {
+                                match Runtime::instance().plugins.reload(name).await {
+                                    Ok(()) => match MessageReply::ReloadPlugin.encode() {
+                                        Ok(ress) => {
+                                            HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                        }
+                                        Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                            e.to_string(),
+                                        ))),
+                                    },
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::SendPlugin { name, msg }) => {
+                                match plugin::send_plugin(name, &msg).await {
+                                    Ok(reply) => match MessageReply::SendPlugin(reply).encode() {
+                                        Ok(ress) => {
+                                            HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                        }
+                                        Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                            e.to_string(),
+                                        ))),
+                                    },
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::ClientDump { clientid, redaction }) => {
+                                let dump = clients::dump(clientid, redaction)
+                                    .await
+                                    .and_then(|dump| serde_json::to_vec(&dump).ok());
+                                match MessageReply::ClientDump(dump).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::GetConnectionHistory { clientid }) => {
+                                let events = ConnectionHistory::instance().get(&ClientId::from(clientid));
+                                match MessageReply::GetConnectionHistory(events).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::TraceStop { name }) => {
+                                match MessageReply::TraceStop(Tracer::instance().stop(name)).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::TraceRemove { name }) => {
+                                let trace_dir = self.cfg.read().trace_dir.clone();
+                                match Tracer::instance().remove(&trace_dir, name).await {
+                                    Ok(ok) => match MessageReply::TraceRemove(ok).encode() {
+                                        Ok(ress) => {
+                                            HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                        }
+                                        Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                            e.to_string(),
+                                        ))),
+                                    },
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::TraceLog { name }) => {
+                                let trace_dir = self.cfg.read().trace_dir.clone();
+                                match Tracer::instance().read(&trace_dir, name, 0) {
+                                    Ok(data) => match MessageReply::TraceLog(data).encode() {
+                                        Ok(ress) => {
+                                            HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                        }
+                                        Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                            e.to_string(),
+                                        ))),
+                                    },
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::TraceLogFrom { name, offset }) => {
+                                let trace_dir = self.cfg.read().trace_dir.clone();
+                                match Tracer::instance().read(&trace_dir, name, offset) {
+                                    Ok(data) => match MessageReply::TraceLog(data).encode() {
+                                        Ok(ress) => {
+                                            HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                        }
+                                        Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                            e.to_string(),
+                                        ))),
+                                    },
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
                         };
                         return (false, Some(new_acc));
                     }
@@ -234,6 +579,60 @@ impl Handler for HookHandler {
                     }
                 }
             }
+            Parameter::MessagePublish(_session, client, publish) => {
+                let (max_topics, collapse_levels) = {
+                    let cfg = self.cfg.read();
+                    (cfg.topic_metrics_max_topics, cfg.topic_metrics_collapse_levels)
+                };
+                TopicMetrics::instance().record(
+                    &publish.topic,
+                    publish.payload.len(),
+                    max_topics,
+                    collapse_levels,
+                );
+                Tracer::instance().record(&client.id.client_id, format_args!("PUBLISH in"), Some(publish));
+            }
+            Parameter::ClientConnect(connect_info) => {
+                Tracer::instance().record(connect_info.client_id(), format_args!("CONNECT in"), None);
+            }
+            Parameter::ClientConnack(connect_info, reason) => {
+                Tracer::instance().record(
+                    connect_info.client_id(),
+                    format_args!("CONNACK out, reason: {:?}", reason),
+                    None,
+                );
+            }
+            Parameter::ClientSubscribe(_session, client, sub) => {
+                Tracer::instance().record(
+                    &client.id.client_id,
+                    format_args!("SUBSCRIBE in, topic_filter: {}, qos: {:?}", sub.topic_filter, sub.qos),
+                    None,
+                );
+            }
+            Parameter::ClientUnsubscribe(_session, client, unsub) => {
+                Tracer::instance().record(
+                    &client.id.client_id,
+                    format_args!("UNSUBSCRIBE in, topic_filter: {}", unsub.topic_filter),
+                    None,
+                );
+            }
+            Parameter::MessageDelivered(_session, client, _from, publish) => {
+                Tracer::instance().record(&client.id.client_id, format_args!("PUBLISH out"), Some(publish));
+            }
+            Parameter::MessageAcked(_session, client, _from, publish) => {
+                Tracer::instance().record(&client.id.client_id, format_args!("PUBLISH acked"), Some(publish));
+            }
+            Parameter::ClientDisconnected(_session, client, reason) => {
+                Tracer::instance().record(
+                    &client.id.client_id,
+                    format_args!(
+                        "DISCONNECT, kind: {}, reason: {}",
+                        client.disconnected_kind().map(|k| k.as_str()).unwrap_or("unknown"),
+                        reason
+                    ),
+                    None,
+                );
+            }
             _ => {
                 log::error!("unimplemented, {:?}", param)
             }
@@ -13,7 +13,9 @@ use rmqtt::{
 };
 use rmqtt::{
     broker::hook::{Register, Type},
+    grpc::registry::MessageTypes,
     plugin::{DynPlugin, DynPluginResult, Plugin},
+    settings::schema::ConfigSchema,
     Result, Runtime,
 };
 
@@ -23,6 +25,8 @@ mod config;
 mod handler;
 mod plugin;
 mod subs;
+mod topic_metrics;
+mod trace;
 mod types;
 
 type ShutdownTX = oneshot::Sender<()>;
@@ -60,9 +64,14 @@ impl HttpApiPlugin {
     #[inline]
     async fn new<S: Into<String>>(runtime: &'static Runtime, name: S, descr: S) -> Result<Self> {
         let name = name.into();
-        let cfg = Arc::new(RwLock::new(runtime.settings.plugins.load_config::<PluginConfig>(&name)?));
+        let cfg = Arc::new(RwLock::new(
+            runtime
+                .settings
+                .plugins
+                .load_config_with_schema::<PluginConfig>(&name, &PluginConfig::schema())?,
+        ));
         log::debug!("{} HttpApiPlugin cfg: {:?}", name, cfg.read());
-        let register = runtime.extends.hook_mgr().await.register();
+        let register = runtime.extends.hook_mgr().await.register_for(&name);
         let shutdown_tx = Some(Self::start(runtime, cfg.clone()));
         Ok(Self { runtime, name, descr: descr.into(), register, cfg, shutdown_tx })
     }
@@ -99,7 +108,20 @@ impl Plugin for HttpApiPlugin {
     async fn init(&mut self) -> Result<()> {
         log::info!("{} init", self.name);
         let mgs_type = self.cfg.read().message_type;
-        self.register.add(Type::GrpcMessageReceived, Box::new(handler::HookHandler::new(mgs_type))).await;
+        MessageTypes::instance().register(&self.name, mgs_type)?;
+        for typ in [
+            Type::GrpcMessageReceived,
+            Type::MessagePublish,
+            Type::ClientConnect,
+            Type::ClientConnack,
+            Type::ClientSubscribe,
+            Type::ClientUnsubscribe,
+            Type::MessageDelivered,
+            Type::MessageAcked,
+            Type::ClientDisconnected,
+        ] {
+            self.register.add(typ, Box::new(handler::HookHandler::new(mgs_type, self.cfg.clone()))).await;
+        }
         Ok(())
     }
 
@@ -113,9 +135,18 @@ impl Plugin for HttpApiPlugin {
         self.cfg.read().to_json()
     }
 
+    #[inline]
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(PluginConfig::schema())
+    }
+
     #[inline]
     async fn load_config(&mut self) -> Result<()> {
-        let new_cfg = self.runtime.settings.plugins.load_config::<PluginConfig>(&self.name)?;
+        let new_cfg = self
+            .runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&self.name, &PluginConfig::schema())?;
         if !self.cfg.read().changed(&new_cfg) {
             return Ok(());
         }
@@ -160,4 +191,9 @@ impl Plugin for HttpApiPlugin {
     fn descr(&self) -> &str {
         &self.descr
     }
+
+    #[inline]
+    fn reloadable(&self) -> bool {
+        false
+    }
 }
@@ -29,3 +29,20 @@ pub(crate) async fn get_plugin_config(name: &str) -> Result<Vec<u8>> {
     let data = Runtime::instance().plugins.get_config(name).await.map(|cfg| serde_json::to_vec(&cfg))??;
     Ok(data)
 }
+
+#[inline]
+pub(crate) async fn send_plugin(name: &str, msg: &[u8]) -> Result<Vec<u8>> {
+    let msg = serde_json::from_slice(msg)?;
+    let reply = Runtime::instance().plugins.send(name, msg).await?;
+    Ok(serde_json::to_vec(&reply)?)
+}
+
+#[inline]
+pub(crate) async fn get_plugin_config_schema(name: &str) -> Result<Vec<u8>> {
+    let data = Runtime::instance()
+        .plugins
+        .get_config_schema(name)
+        .await
+        .map(|schema| serde_json::to_vec(&schema))??;
+    Ok(data)
+}
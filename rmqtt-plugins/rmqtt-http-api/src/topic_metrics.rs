@@ -0,0 +1,329 @@
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+
+use rmqtt::broker::default::DefaultRouter;
+use rmqtt::broker::topic::{Topic, TopicTree, VecToTopic};
+use rmqtt::dashmap::mapref::entry::Entry;
+use rmqtt::{chrono, once_cell::sync::OnceCell, serde_json, DashMap, HashMap, RwLock};
+use rmqtt::{MqttError, Result, Runtime, TimestampMillis, TopicFilter, TopicName};
+
+pub(crate) struct TopicMetrics {
+    filters: RwLock<TopicTree<()>>,
+    items: DashMap<TopicFilter, TopicMetricsItem>,
+}
+
+#[derive(Default)]
+struct TopicMetricsItem {
+    messages: AtomicUsize,
+    bytes: AtomicUsize,
+    last_publish_at: AtomicI64,
+    created_at: TimestampMillis,
+    ///The most published topics under a wildcard topic filter
+    topics: RwLock<TopicCounts>,
+}
+
+///The counts of the tracked topics, ordered by messages so that the least published one is found
+///in O(log n)
+#[derive(Default)]
+struct TopicCounts {
+    counts: HashMap<TopicName, TopicCount>,
+    by_messages: BTreeSet<(usize, TopicName)>,
+}
+
+impl TopicCounts {
+    ///Space-saving top-k, once max_topics are tracked a new topic replaces the least published
+    ///one and takes over its counts, which are kept as the error of the new topic
+    #[inline]
+    fn track(&mut self, topic: TopicName, payload_len: usize, max_topics: usize) {
+        if let Some(count) = self.counts.get_mut(&topic) {
+            self.by_messages.remove(&(count.messages, topic.clone()));
+            count.messages += 1;
+            count.bytes += payload_len;
+            self.by_messages.insert((count.messages, topic));
+            return;
+        }
+        let mut count = TopicCount { topic: topic.clone(), messages: 1, bytes: payload_len, error: 0 };
+        while self.counts.len() >= max_topics {
+            let least = match self.by_messages.iter().next().cloned() {
+                Some(least) => least,
+                None => break,
+            };
+            self.by_messages.remove(&least);
+            let (_, least) = least;
+            if let Some(evicted) = self.counts.remove(&least) {
+                count.messages = evicted.messages + 1;
+                count.bytes = evicted.bytes + payload_len;
+                count.error = evicted.messages;
+            }
+        }
+        self.by_messages.insert((count.messages, topic.clone()));
+        self.counts.insert(topic, count);
+    }
+}
+
+impl TopicMetricsItem {
+    #[inline]
+    fn track(&self, topic: TopicName, payload_len: usize, max_topics: usize) {
+        self.topics.write().track(topic, payload_len, max_topics)
+    }
+
+    #[inline]
+    fn top(&self, top_k: usize) -> Vec<TopicCount> {
+        let topics = self.topics.read();
+        topics
+            .by_messages
+            .iter()
+            .rev()
+            .take(top_k)
+            .filter_map(|(_, t)| topics.counts.get(t).cloned())
+            .collect()
+    }
+}
+
+impl TopicMetrics {
+    #[inline]
+    pub(crate) fn instance() -> &'static TopicMetrics {
+        static INSTANCE: OnceCell<TopicMetrics> = OnceCell::new();
+        INSTANCE
+            .get_or_init(|| Self { filters: RwLock::new(TopicTree::default()), items: DashMap::default() })
+    }
+
+    ///Register a topic filter for metric collection, returns false if it already exists
+    #[inline]
+    pub(crate) fn register(&self, topic_filter: &str, max_filters: usize) -> Result<bool> {
+        let topic = Topic::from_str(topic_filter)?;
+        //the registrations are serialized by the filters lock, so the maximum is not exceeded, the
+        //length is read before the entry is locked
+        let mut filters = self.filters.write();
+        let len = self.items.len();
+        match self.items.entry(TopicFilter::from(topic_filter)) {
+            Entry::Occupied(_) => Ok(false),
+            Entry::Vacant(_) if max_filters > 0 && len >= max_filters => {
+                Err(MqttError::from(format!("too many topic filters, the maximum is {}", max_filters)))
+            }
+            Entry::Vacant(entry) => {
+                filters.insert(&topic, ());
+                entry.insert(TopicMetricsItem {
+                    created_at: chrono::Local::now().timestamp_millis(),
+                    ..Default::default()
+                });
+                Ok(true)
+            }
+        }
+    }
+
+    #[inline]
+    pub(crate) fn unregister(&self, topic_filter: &str) -> Result<bool> {
+        let topic = Topic::from_str(topic_filter)?;
+        let mut filters = self.filters.write();
+        filters.remove(&topic, &());
+        Ok(self.items.remove(topic_filter).is_some())
+    }
+
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    ///Accumulate a published message on all registered topic filters that match the topic, and
+    ///on the topic itself under wildcard topic filters, collapsed to its first collapse_levels
+    ///levels if not 0
+    #[inline]
+    pub(crate) fn record(
+        &self,
+        topic_name: &TopicName,
+        payload_len: usize,
+        max_topics: usize,
+        collapse_levels: usize,
+    ) {
+        if self.is_empty() {
+            return;
+        }
+        let topic = match Topic::from_str(topic_name) {
+            Ok(t) => t,
+            Err(_) => return,
+        };
+        let now = chrono::Local::now().timestamp_millis();
+        let filters = self.filters.read();
+        for (levels, _) in filters.matches(&topic).iter() {
+            let topic_filter = levels.to_topic_filter();
+            if let Some(item) = self.items.get(&topic_filter) {
+                item.messages.fetch_add(1, Ordering::Relaxed);
+                item.bytes.fetch_add(payload_len, Ordering::Relaxed);
+                item.last_publish_at.store(now, Ordering::Relaxed);
+                if max_topics > 0 && is_wildcard(&topic_filter) {
+                    item.track(collapse(topic_name, collapse_levels), payload_len, max_topics);
+                }
+            }
+        }
+    }
+
+    ///Local metrics of the registered topic filters, with the top_k most published topics of each
+    #[inline]
+    pub(crate) async fn list(&self, top_k: usize) -> Vec<TopicMetricsInfo> {
+        let mut infos = self
+            .items
+            .iter()
+            .map(|entry| {
+                let item = entry.value();
+                TopicMetricsInfo {
+                    topic_filter: entry.key().clone(),
+                    messages: item.messages.load(Ordering::Relaxed),
+                    bytes: item.bytes.load(Ordering::Relaxed),
+                    subscribers: 0,
+                    last_publish_at: item.last_publish_at.load(Ordering::Relaxed),
+                    created_at: item.created_at,
+                    topics: item.top(top_k),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Self::count_subscribers(&mut infos);
+        infos
+    }
+
+    ///Counts the subscriptions of this node that receive the messages of some topic of each topic
+    ///filter, such as a/+ and a/# for a/b. The topic filters of the router are compared once each,
+    ///not the subscriptions of every session
+    #[inline]
+    fn count_subscribers(infos: &mut [TopicMetricsInfo]) {
+        if infos.is_empty() {
+            return;
+        }
+        let node_id = Runtime::instance().node.id();
+        for entry in DefaultRouter::instance().relations.iter() {
+            let mut subscribers = None;
+            for info in infos.iter_mut() {
+                if overlaps(entry.key(), &info.topic_filter) {
+                    info.subscribers += *subscribers.get_or_insert_with(|| {
+                        entry.value().values().filter(|(id, _, _)| id.node_id == node_id).count()
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct TopicMetricsInfo {
+    pub topic_filter: TopicFilter,
+    pub messages: usize,
+    pub bytes: usize,
+    pub subscribers: usize,
+    pub last_publish_at: TimestampMillis,
+    pub created_at: TimestampMillis,
+    #[serde(
+        default,
+        skip_serializing_if = "rmqtt::grpc::codec::is_legacy_layout",
+        deserialize_with = "rmqtt::grpc::codec::deserialize_since_legacy"
+    )]
+    pub topics: Vec<TopicCount>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct TopicCount {
+    pub topic: TopicName,
+    pub messages: usize,
+    pub bytes: usize,
+    ///The messages may be overcounted by up to this much, see TopicMetricsItem::track
+    pub error: usize,
+}
+
+impl TopicMetricsInfo {
+    #[inline]
+    pub fn add(&mut self, other: &TopicMetricsInfo) {
+        self.messages += other.messages;
+        self.bytes += other.bytes;
+        self.subscribers += other.subscribers;
+        self.last_publish_at = self.last_publish_at.max(other.last_publish_at);
+        if self.created_at == 0 || (other.created_at > 0 && other.created_at < self.created_at) {
+            self.created_at = other.created_at;
+        }
+        for other in other.topics.iter() {
+            match self.topics.iter_mut().find(|c| c.topic == other.topic) {
+                Some(count) => {
+                    count.messages += other.messages;
+                    count.bytes += other.bytes;
+                    count.error += other.error;
+                }
+                None => self.topics.push(other.clone()),
+            }
+        }
+    }
+
+    ///Keeps the top_k most published topics after the topics of several nodes are added
+    #[inline]
+    pub fn truncate_topics(&mut self, top_k: usize) {
+        sort_and_truncate(&mut self.topics, top_k);
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "topic": self.topic_filter,
+            "messages": self.messages,
+            "bytes": self.bytes,
+            "subscribers": self.subscribers,
+            "last_publish_at": format_timestamp_millis(self.last_publish_at),
+            "created_at": format_timestamp_millis(self.created_at),
+            "topics": self.topics.iter().map(|c| serde_json::json!({
+                "topic": c.topic,
+                "messages": c.messages,
+                "bytes": c.bytes,
+                "error": c.error,
+            })).collect::<Vec<_>>(),
+        })
+    }
+}
+
+#[inline]
+fn sort_and_truncate(topics: &mut Vec<TopicCount>, top_k: usize) {
+    topics.sort_by(|a, b| b.messages.cmp(&a.messages).then_with(|| a.topic.cmp(&b.topic)));
+    topics.truncate(top_k);
+}
+
+///Whether a topic matches both topic filters
+#[inline]
+fn overlaps(a: &str, b: &str) -> bool {
+    let mut a = a.split('/');
+    let mut b = b.split('/');
+    loop {
+        match (a.next(), b.next()) {
+            (Some("#"), _) | (_, Some("#")) => return true,
+            (None, None) => return true,
+            (Some(x), Some(y)) if x == "+" || y == "+" || x == y => {}
+            _ => return false,
+        }
+    }
+}
+
+#[inline]
+fn is_wildcard(topic_filter: &str) -> bool {
+    topic_filter.contains(|c| c == '+' || c == '#')
+}
+
+///a/b/c/d collapsed to 2 levels is a/b/#
+#[inline]
+fn collapse(topic: &TopicName, levels: usize) -> TopicName {
+    if levels > 0 {
+        if let Some((idx, _)) = topic.match_indices('/').nth(levels - 1) {
+            return TopicName::from(format!("{}/#", &topic[..idx]));
+        }
+    }
+    topic.clone()
+}
+
+#[inline]
+fn format_timestamp_millis(t: TimestampMillis) -> String {
+    if t <= 0 {
+        "".into()
+    } else {
+        use chrono::TimeZone;
+        if let chrono::LocalResult::Single(t) = chrono::Local.timestamp_millis_opt(t) {
+            t.format("%Y-%m-%d %H:%M:%S%.3f").to_string()
+        } else {
+            "".into()
+        }
+    }
+}
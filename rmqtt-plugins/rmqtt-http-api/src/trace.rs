@@ -0,0 +1,281 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use rmqtt::broker::topic::{Topic, TopicTree};
+use rmqtt::tokio::{sync::mpsc, task::JoinHandle};
+use rmqtt::{chrono, log, once_cell::sync::OnceCell, serde_json, tokio, DashMap};
+use rmqtt::{ClientId, MqttError, Publish, Result, TimestampMillis, TopicFilter};
+
+///Maximum number of the lines waiting to be written to the file of a trace, the others are dropped
+const QUEUE_CAPACITY: usize = 10_000;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub enum TraceTarget {
+    ClientId(ClientId),
+    Topic(TopicFilter),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TraceInfo {
+    pub name: String,
+    pub target: TraceTarget,
+    pub payload: bool,
+    pub start_at: TimestampMillis,
+    pub end_at: TimestampMillis,
+}
+
+impl TraceInfo {
+    #[inline]
+    pub fn is_running(&self) -> bool {
+        chrono::Local::now().timestamp_millis() < self.end_at
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        let (typ, target) = match &self.target {
+            TraceTarget::ClientId(clientid) => ("clientid", clientid),
+            TraceTarget::Topic(topic) => ("topic", topic),
+        };
+        serde_json::json!({
+            "name": self.name,
+            "type": typ,
+            "target": target,
+            "payload": self.payload,
+            "start_at": self.start_at,
+            "end_at": self.end_at,
+            "status": if self.is_running() { "running" } else { "stopped" },
+        })
+    }
+}
+
+struct Trace {
+    info: TraceInfo,
+    topic_filter: Option<TopicTree<()>>,
+    tx: Option<mpsc::Sender<String>>,
+    writer: Option<JoinHandle<()>>,
+    dropped: usize,
+}
+
+impl Trace {
+    #[inline]
+    fn is_match(&self, clientid: &str, topic: Option<&Topic>) -> bool {
+        match (&self.info.target, topic, &self.topic_filter) {
+            (TraceTarget::ClientId(c), _, _) => c == clientid,
+            (TraceTarget::Topic(_), Some(topic), Some(filter)) => filter.is_match(topic),
+            _ => false,
+        }
+    }
+
+    #[inline]
+    fn send(&mut self, line: String) {
+        if let Some(tx) = self.tx.as_ref() {
+            if tx.try_send(line).is_err() {
+                self.dropped += 1;
+            }
+        }
+    }
+
+    ///Closes the queue, the writer flushes the lines already queued and exits
+    #[inline]
+    fn stop(&mut self, active: &AtomicUsize) -> Option<JoinHandle<()>> {
+        if self.tx.take().is_some() {
+            active.fetch_sub(1, Ordering::SeqCst);
+            if self.dropped > 0 {
+                log::warn!(
+                    "trace {} dropped {} lines, the writer could not keep up",
+                    self.info.name,
+                    self.dropped
+                );
+            }
+        }
+        self.writer.take()
+    }
+}
+
+///Writes the lines of a trace to its file, flushing whenever the queue is drained
+fn write_loop(name: String, file: File, mut rx: mpsc::Receiver<String>) {
+    let mut w = BufWriter::new(file);
+    while let Some(line) = rx.blocking_recv() {
+        let mut res = w.write_all(line.as_bytes());
+        while res.is_ok() {
+            match rx.try_recv() {
+                Ok(line) => res = w.write_all(line.as_bytes()),
+                Err(_) => break,
+            }
+        }
+        if let Err(e) = res.and_then(|_| w.flush()) {
+            log::warn!("trace {} write error, {:?}", name, e);
+            return;
+        }
+    }
+}
+
+///Time-limited packet traces on a clientid or a topic filter
+pub(crate) struct Tracer {
+    traces: DashMap<String, Trace>,
+    active: AtomicUsize,
+}
+
+impl Tracer {
+    #[inline]
+    pub(crate) fn instance() -> &'static Tracer {
+        static INSTANCE: OnceCell<Tracer> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { traces: DashMap::default(), active: AtomicUsize::new(0) })
+    }
+
+    #[inline]
+    fn file(dir: &str, name: &str) -> PathBuf {
+        PathBuf::from(dir).join(format!("{}.log", name))
+    }
+
+    #[inline]
+    pub(crate) fn start(
+        &self,
+        dir: &str,
+        name: &str,
+        target: TraceTarget,
+        payload: bool,
+        duration: Duration,
+    ) -> Result<TraceInfo> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            return Err(MqttError::from("trace name can only contain letters, digits, '_' and '-'"));
+        }
+        if let Some(t) = self.traces.get(name) {
+            if t.info.is_running() {
+                return Err(MqttError::from(format!("trace {} is already running", name)));
+            }
+        }
+        let topic_filter = if let TraceTarget::Topic(topic_filter) = &target {
+            let mut tree = TopicTree::default();
+            tree.insert(&Topic::from_str(topic_filter)?, ());
+            Some(tree)
+        } else {
+            None
+        };
+
+        fs::create_dir_all(dir)?;
+        let file = OpenOptions::new().create(true).write(true).truncate(true).open(Self::file(dir, name))?;
+
+        let start_at = chrono::Local::now().timestamp_millis();
+        let info = TraceInfo {
+            name: name.into(),
+            target,
+            payload,
+            start_at,
+            end_at: start_at + duration.as_millis() as TimestampMillis,
+        };
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        let writer = {
+            let name = info.name.clone();
+            tokio::task::spawn_blocking(move || write_loop(name, file, rx))
+        };
+        self.active.fetch_add(1, Ordering::SeqCst);
+        let trace =
+            Trace { info: info.clone(), topic_filter, tx: Some(tx), writer: Some(writer), dropped: 0 };
+        if let Some(mut old) = self.traces.insert(name.into(), trace) {
+            old.stop(&self.active);
+        }
+        Ok(info)
+    }
+
+    #[inline]
+    pub(crate) fn stop(&self, name: &str) -> bool {
+        if let Some(mut t) = self.traces.get_mut(name) {
+            t.info.end_at = chrono::Local::now().timestamp_millis();
+            t.stop(&self.active);
+            true
+        } else {
+            false
+        }
+    }
+
+    ///Stops the trace, waits for its writer and removes its file, the trace is forgotten only once
+    ///the file is gone
+    #[inline]
+    pub(crate) async fn remove(&self, dir: &str, name: &str) -> Result<bool> {
+        let writer = match self.traces.get_mut(name) {
+            Some(mut t) => {
+                t.info.end_at = t.info.end_at.min(chrono::Local::now().timestamp_millis());
+                t.stop(&self.active)
+            }
+            None => return Ok(false),
+        };
+        if let Some(writer) = writer {
+            let _ = writer.await;
+        }
+        match fs::remove_file(Self::file(dir, name)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.traces.remove(name);
+        Ok(true)
+    }
+
+    #[inline]
+    pub(crate) fn list(&self) -> Vec<TraceInfo> {
+        self.traces.iter().map(|t| t.info.clone()).collect()
+    }
+
+    ///The complete lines of the log of a trace from the offset, in bytes, on, the line being written
+    ///is left out, so that the logs of the nodes can be concatenated and read again from an offset
+    #[inline]
+    pub(crate) fn read(&self, dir: &str, name: &str, offset: u64) -> Result<Option<Vec<u8>>> {
+        if !self.traces.contains_key(name) {
+            return Ok(None);
+        }
+        let mut data = Vec::new();
+        let mut file = File::open(Self::file(dir, name))?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_to_end(&mut data)?;
+        data.truncate(data.iter().rposition(|b| *b == b'\n').map(|i| i + 1).unwrap_or_default());
+        Ok(Some(data))
+    }
+
+    ///Record a packet of the client, the topic filter traces only record PUBLISH packets. Nothing is
+    ///formatted unless a running trace matches
+    #[inline]
+    pub(crate) fn record(&self, clientid: &str, packet: fmt::Arguments<'_>, publish: Option<&Publish>) {
+        if self.active.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let topic = publish.and_then(|p| Topic::from_str(&p.topic).ok());
+        let now = chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
+        for mut t in self.traces.iter_mut() {
+            if t.tx.is_none() || !t.is_match(clientid, topic.as_ref()) {
+                continue;
+            }
+            if !t.info.is_running() {
+                t.stop(&self.active);
+                continue;
+            }
+            let line = if let Some(p) = publish {
+                let mut line = format!(
+                    "{} [{}] {}, topic: {}, qos: {:?}, retain: {}, dup: {}, packet_id: {:?}, payload_len: {}",
+                    now,
+                    clientid,
+                    packet,
+                    p.topic,
+                    p.qos,
+                    p.retain,
+                    p.dup,
+                    p.packet_id,
+                    p.payload.len()
+                );
+                if t.info.payload {
+                    line.push_str(&format!(", payload: {:?}", String::from_utf8_lossy(&p.payload)));
+                }
+                line.push('\n');
+                line
+            } else {
+                format!("{} [{}] {}\n", now, clientid, packet)
+            };
+            t.send(line);
+        }
+    }
+}
@@ -1,13 +1,25 @@
 use std::time::Duration;
 
+use rmqtt::broker::alarm::Alarm;
+use rmqtt::broker::evacuation::{EvacuateParams, EvacuationStatus};
+use rmqtt::broker::history::ConnectionEvent;
+use rmqtt::broker::hook::HandlerInfo;
+use rmqtt::broker::session::PayloadRedaction;
 use rmqtt::chrono::LocalResult;
+use rmqtt::grpc::{codec, MessageType};
 use rmqtt::node::{BrokerInfo, NodeInfo, NodeStatus};
-use rmqtt::plugin::PluginInfo;
-use rmqtt::settings::{deserialize_datetime_option, serialize_datetime_option};
+use rmqtt::plugin::{Metric, PluginInfo};
+use rmqtt::settings::{
+    deserialize_datetime_option, deserialize_duration, deserialize_duration_option,
+    serialize_datetime_option, ReloadResult,
+};
 use rmqtt::Result;
-use rmqtt::{anyhow, bincode, chrono, serde_json, HashMap, MqttError, QoS, Reason};
+use rmqtt::{anyhow, chrono, serde_json, DisconnectKind, HashMap, MqttError, QoS, Reason};
 use rmqtt::{metrics::Metrics, stats::Stats};
-use rmqtt::{ClientId, NodeId, Timestamp, TopicFilter, TopicName, UserName};
+use rmqtt::{ClientId, ClientTags, NodeId, Timestamp, TopicFilter, TopicName, UserName};
+
+use super::topic_metrics::TopicMetricsInfo;
+use super::trace::{TraceInfo, TraceTarget};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Message<'a> {
@@ -17,6 +29,7 @@ pub enum Message<'a> {
     MetricsInfo,
     ClientSearch(Box<ClientSearchParams>),
     ClientGet { clientid: &'a str },
+    ClientDump { clientid: &'a str, redaction: PayloadRedaction },
     Subscribe(SubscribeParams),
     Unsubscribe(UnsubscribeParams),
     GetPlugins,
@@ -25,16 +38,41 @@ pub enum Message<'a> {
     ReloadPluginConfig { name: &'a str },
     LoadPlugin { name: &'a str },
     UnloadPlugin { name: &'a str },
+    TopicMetricsRegister { topic_filter: &'a str },
+    TopicMetricsUnregister { topic_filter: &'a str },
+    TopicMetricsInfo,
+    ReloadConfig,
+    TraceStart { name: &'a str, target: TraceTarget, payload: bool, duration: Duration },
+    TraceStop { name: &'a str },
+    TraceRemove { name: &'a str },
+    TraceLog { name: &'a str },
+    LogLevelSet { module: &'a str, level: &'a str, duration: Option<Duration> },
+    LogLevelReset { module: &'a str },
+    GetAlarms,
+    ClearDeactivatedAlarms,
+    GetConnectionHistory { clientid: &'a str },
+    ReloadPlugin { name: &'a str },
+    GetPluginConfigSchema { name: &'a str },
+    GetHooks,
+    GetPluginMetrics,
+    Evacuate(EvacuateParams),
+    GetEvacuation,
+    CancelEvacuation,
+    SendPlugin { name: &'a str, msg: Vec<u8> },
+    AclInvalidate(AclInvalidateParams),
+    GetPayloadLimits,
+    GetMessageTypes,
+    TraceLogFrom { name: &'a str, offset: u64 },
 }
 
 impl<'a> Message<'a> {
     #[inline]
     pub fn encode(&self) -> Result<Vec<u8>> {
-        Ok(bincode::serialize(self).map_err(anyhow::Error::new)?)
+        codec::encode(self)
     }
     #[inline]
     pub fn decode(data: &[u8]) -> Result<Message> {
-        Ok(bincode::deserialize::<Message>(data).map_err(anyhow::Error::new)?)
+        codec::decode(data)
     }
 }
 
@@ -46,6 +84,7 @@ pub enum MessageReply {
     MetricsInfo(Metrics),
     ClientSearch(Vec<ClientSearchResult>),
     ClientGet(Option<ClientSearchResult>),
+    ClientDump(Option<Vec<u8>>),
     Subscribe(HashMap<TopicFilter, (bool, Option<String>)>),
     Unsubscribe,
     GetPlugins(Vec<PluginInfo>),
@@ -54,16 +93,40 @@ pub enum MessageReply {
     ReloadPluginConfig,
     LoadPlugin,
     UnloadPlugin(bool),
+    TopicMetricsRegister(bool),
+    TopicMetricsUnregister(bool),
+    TopicMetricsInfo(Vec<TopicMetricsInfo>),
+    ReloadConfig(ReloadResult),
+    TraceStart(TraceInfo),
+    TraceStop(bool),
+    TraceRemove(bool),
+    TraceLog(Option<Vec<u8>>),
+    LogLevelSet,
+    LogLevelReset(bool),
+    GetAlarms(Vec<Alarm>),
+    ClearDeactivatedAlarms(usize),
+    GetConnectionHistory(Vec<ConnectionEvent>),
+    ReloadPlugin,
+    GetPluginConfigSchema(Vec<u8>),
+    GetHooks(Vec<HandlerInfo>),
+    GetPluginMetrics(Vec<(String, Vec<Metric>)>),
+    Evacuate,
+    GetEvacuation(EvacuationStatus),
+    CancelEvacuation(bool),
+    SendPlugin(Vec<u8>),
+    AclInvalidate(usize),
+    GetPayloadLimits(Vec<(TopicFilter, usize, usize)>),
+    GetMessageTypes(Vec<(MessageType, String)>),
 }
 
 impl MessageReply {
     #[inline]
     pub fn encode(&self) -> Result<Vec<u8>> {
-        Ok(bincode::serialize(self).map_err(anyhow::Error::new)?)
+        codec::encode(self)
     }
     #[inline]
     pub fn decode(data: &[u8]) -> Result<MessageReply> {
-        Ok(bincode::deserialize::<MessageReply>(data).map_err(anyhow::Error::new)?)
+        codec::decode(data)
     }
 }
 
@@ -128,6 +191,12 @@ pub struct ClientSearchResult {
     pub connected_at: Timestamp,
     pub disconnected_at: Timestamp,
     pub disconnected_reason: Reason,
+    #[serde(
+        default,
+        skip_serializing_if = "rmqtt::grpc::codec::is_legacy_layout",
+        deserialize_with = "rmqtt::grpc::codec::deserialize_since_legacy"
+    )]
+    pub disconnected_kind: Option<DisconnectKind>,
     pub keepalive: u16,
     pub clean_start: bool,
     pub session_present: bool,
@@ -136,6 +205,12 @@ pub struct ClientSearchResult {
     pub subscriptions_cnt: usize,
     pub max_subscriptions: usize,
     pub extra_attrs: usize,
+    #[serde(
+        default,
+        skip_serializing_if = "rmqtt::grpc::codec::is_legacy_layout",
+        deserialize_with = "rmqtt::grpc::codec::deserialize_since_legacy"
+    )]
+    pub tags: ClientTags,
 
     pub inflight: usize,
     pub max_inflight: usize,
@@ -147,11 +222,14 @@ pub struct ClientSearchResult {
     //    pub awaiting_rel:0,
     //    pub max_awaiting_rel:s.listen_cfg.max_awaiting_rel,
     //    pub awaiting_rel_dropped:0,
-
-    //     pub recv_msg:0,	//Number of received PUBLISH packets
-    //     pub send_msg:0,	//Number of sent PUBLISH packets
+    pub recv_msg: usize, //Number of received PUBLISH packets
+    pub recv_bytes: usize,
+    pub send_msg: usize, //Number of sent PUBLISH packets
+    pub send_bytes: usize,
     //     pub resend_msg:0, //Resent message data
-    //     pub ackeds:0,  //Number of Acked received
+    pub ackeds: usize, //Number of Acked received
+    pub dropped: usize,
+    pub last_activity: Timestamp,
 }
 
 impl ClientSearchResult {
@@ -169,6 +247,7 @@ impl ClientSearchResult {
             "connected_at": format_timestamp(self.connected_at),
             "disconnected_at": format_timestamp(self.disconnected_at),
             "disconnected_reason": self.disconnected_reason,
+            "disconnected_kind": self.disconnected_kind,
             "keepalive": self.keepalive,
             "clean_start": self.clean_start,
             "session_present": self.session_present,
@@ -177,6 +256,7 @@ impl ClientSearchResult {
             "subscriptions_cnt": self.subscriptions_cnt,
             "max_subscriptions": self.max_subscriptions,
             "extra_attrs": self.extra_attrs,
+            "tags": self.tags,
 
             "inflight": self.inflight,
             "max_inflight": self.max_inflight,
@@ -190,10 +270,14 @@ impl ClientSearchResult {
             //"max_awaiting_rel": s.listen_cfg.max_awaiting_rel,
             //"awaiting_rel_dropped": 0,
 
-            // "recv_msg": 0,	//Number of received PUBLISH packets
-            // "send_msg": 0,	//Number of sent PUBLISH packets
+            "recv_msg": self.recv_msg,
+            "recv_bytes": self.recv_bytes,
+            "send_msg": self.send_msg,
+            "send_bytes": self.send_bytes,
             // "resend_msg": 0, //Resent message data
-            // "ackeds": 0,  //Number of Acked received
+            "ackeds": self.ackeds,
+            "dropped": self.dropped,
+            "last_activity": format_timestamp(self.last_activity),
 
         });
         data
@@ -286,6 +370,60 @@ pub struct UnsubscribeParams {
     pub clientid: ClientId,
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct AclInvalidateParams {
+    //For clientid and username, with at least one of them specified
+    pub clientid: Option<ClientId>,
+    pub username: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct TopicMetricsParams {
+    pub topic: TopicFilter,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct TraceParams {
+    //Trace name, only letters, digits, '_' and '-' are allowed
+    pub name: String,
+    //For clientid and topic, with one of them specified
+    pub clientid: Option<ClientId>,
+    pub topic: Option<TopicFilter>,
+    //Trace duration, Default: 5m
+    #[serde(default = "TraceParams::duration_default", deserialize_with = "deserialize_duration")]
+    pub duration: Duration,
+    //Whether to record the message payload, Default: false
+    #[serde(default)]
+    pub payload: bool,
+}
+
+impl TraceParams {
+    fn duration_default() -> Duration {
+        Duration::from_secs(300)
+    }
+
+    #[inline]
+    pub fn target(&self) -> Result<TraceTarget> {
+        match (&self.clientid, &self.topic) {
+            (Some(clientid), None) => Ok(TraceTarget::ClientId(clientid.clone())),
+            (None, Some(topic)) => Ok(TraceTarget::Topic(topic.clone())),
+            _ => Err(MqttError::Msg("one of clientid or topic must be specified".into())),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct LogLevelParams {
+    //Module path, such as rmqtt::broker::session, applies to all modules if empty
+    #[serde(default)]
+    pub module: String,
+    //Value: trace, debug, info, warn, error
+    pub level: String,
+    //The level reverts after the duration, never reverts if not specified
+    #[serde(default, deserialize_with = "deserialize_duration_option")]
+    pub duration: Option<Duration>,
+}
+
 #[inline]
 fn format_timestamp(t: i64) -> String {
     if t <= 0 {
@@ -0,0 +1,193 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use salvo::affix;
+use salvo::http::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE};
+use salvo::prelude::*;
+
+use rmqtt::{anyhow, futures, log, serde_json::json, tokio::sync::oneshot, Result};
+
+use super::session::{Polled, Sessions};
+use super::PluginConfigType;
+
+fn route(cfg: PluginConfigType, sessions: Arc<Sessions>) -> Router {
+    Router::with_path("mqtt/sessions").hoop(affix::inject(cfg).inject(sessions)).post(create_session).push(
+        Router::with_path("<id>")
+            .get(poll)
+            .post(send)
+            .delete(close_session)
+            .push(Router::with_path("stream").get(stream)),
+    )
+}
+
+pub(crate) async fn listen_and_serve(
+    laddr: SocketAddr,
+    cfg: PluginConfigType,
+    sessions: Arc<Sessions>,
+    rx: oneshot::Receiver<()>,
+) -> Result<()> {
+    log::info!("HTTP polling transport Listening on {}", laddr);
+    Server::new(TcpListener::bind(laddr))
+        .try_serve_with_graceful_shutdown(route(cfg, sessions), async {
+            rx.await.ok();
+        })
+        .await
+        .map_err(anyhow::Error::new)?;
+    Ok(())
+}
+
+#[inline]
+fn octet_stream(res: &mut Response) {
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+    res.headers_mut().insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+}
+
+///The address of the client, the last one of X-Forwarded-For if the request comes from a trusted proxy
+#[inline]
+fn peer_addr(req: &Request, trusted_proxies: &[IpAddr]) -> Option<SocketAddr> {
+    let remote_addr = req.remote_addr().and_then(|addr| {
+        if let Some(ipv4) = addr.as_ipv4() {
+            Some(SocketAddr::V4(*ipv4))
+        } else {
+            addr.as_ipv6().map(|ipv6| SocketAddr::V6(*ipv6))
+        }
+    })?;
+    if !trusted_proxies.contains(&remote_addr.ip()) {
+        return Some(remote_addr);
+    }
+    match req.headers().get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
+        Some(forwarded) => forwarded
+            .rsplit(',')
+            .next()
+            .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+            .map(|ip| SocketAddr::new(ip, 0)),
+        None => Some(remote_addr),
+    }
+}
+
+#[handler]
+async fn create_session(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let sessions = depot.obtain::<Arc<Sessions>>().cloned().unwrap();
+    let (mqtt_addr, max_sessions, max_buffer_size, peer_addr) = {
+        let cfg = cfg.read();
+        (cfg.mqtt_addr, cfg.max_sessions, *cfg.max_buffer_size, peer_addr(req, &cfg.trusted_proxies))
+    };
+    let peer_addr = match peer_addr {
+        Some(peer_addr) => peer_addr,
+        None => {
+            return res.set_status_error(
+                StatusError::bad_request().with_detail("the address of the client is unknown"),
+            )
+        }
+    };
+    match sessions.create(mqtt_addr, peer_addr, max_sessions, max_buffer_size).await {
+        Ok(id) => {
+            res.set_status_code(StatusCode::CREATED);
+            res.render(Json(json!({ "session_id": id })));
+        }
+        Err(e) => res.set_status_error(StatusError::service_unavailable().with_detail(e.to_string())),
+    }
+}
+
+///The body is the MQTT data of the client, passed to the broker as it is
+#[handler]
+async fn send(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let sessions = depot.obtain::<Arc<Sessions>>().cloned().unwrap();
+    let max_request_size = *cfg.read().max_request_size;
+    let session = match req.param::<String>("id").and_then(|id| sessions.get(&id)) {
+        Some(session) => session,
+        None => return res.set_status_code(StatusCode::NOT_FOUND),
+    };
+    let data = match req.payload().await {
+        Ok(data) if data.len() > max_request_size => {
+            return res.set_status_code(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        Ok(data) => data,
+        Err(e) => return res.set_status_error(StatusError::bad_request().with_detail(e.to_string())),
+    };
+    match session.send(data).await {
+        Ok(()) => {
+            sessions.stats.bytes_in.fetch_add(data.len(), Ordering::SeqCst);
+            res.set_status_code(StatusCode::NO_CONTENT);
+        }
+        Err(e) => res.set_status_error(StatusError::gone().with_detail(e.to_string())),
+    }
+}
+
+///Long polling, returns the MQTT data of the broker once some is available, no content if none
+///arrives within poll_timeout, gone once the broker has closed the connection
+#[handler]
+async fn poll(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let sessions = depot.obtain::<Arc<Sessions>>().cloned().unwrap();
+    let (poll_timeout, max_buffer_size) = {
+        let cfg = cfg.read();
+        (cfg.poll_timeout, *cfg.max_buffer_size)
+    };
+    let id = req.param::<String>("id").unwrap_or_default();
+    let session = match sessions.get(&id) {
+        Some(session) => session,
+        None => return res.set_status_code(StatusCode::NOT_FOUND),
+    };
+    match session.poll(poll_timeout, max_buffer_size).await {
+        Polled::Data(data) if data.is_empty() => res.set_status_code(StatusCode::NO_CONTENT),
+        Polled::Data(data) => {
+            octet_stream(res);
+            res.write_body(data).ok();
+        }
+        Polled::Closed => {
+            sessions.remove(&id);
+            res.set_status_code(StatusCode::GONE);
+        }
+    }
+}
+
+///Streaming, the response stays open and carries the MQTT data of the broker as it arrives,
+///until the broker closes the connection
+#[handler]
+async fn stream(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let cfg = depot.obtain::<PluginConfigType>().cloned().unwrap();
+    let sessions = depot.obtain::<Arc<Sessions>>().cloned().unwrap();
+    let (poll_timeout, max_buffer_size) = {
+        let cfg = cfg.read();
+        (cfg.poll_timeout, *cfg.max_buffer_size)
+    };
+    let id = req.param::<String>("id").unwrap_or_default();
+    let session = match sessions.get(&id) {
+        Some(session) => session,
+        None => return res.set_status_code(StatusCode::NOT_FOUND),
+    };
+    let data = futures::stream::unfold((session, sessions, id), move |(session, sessions, id)| async move {
+        loop {
+            match session.poll(poll_timeout, max_buffer_size).await {
+                Polled::Data(data) if data.is_empty() => continue,
+                Polled::Data(data) => {
+                    return Some((Ok::<_, std::io::Error>(data), (session, sessions, id)));
+                }
+                Polled::Closed => {
+                    sessions.remove(&id);
+                    return None;
+                }
+            }
+        }
+    });
+    octet_stream(res);
+    if let Err(e) = res.streaming(data) {
+        res.set_status_error(StatusError::internal_server_error().with_detail(e.to_string()));
+    }
+}
+
+///Closes the connection to the broker
+#[handler]
+async fn close_session(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let sessions = depot.obtain::<Arc<Sessions>>().cloned().unwrap();
+    let removed = req.param::<String>("id").map(|id| sessions.remove(&id)).unwrap_or(false);
+    if removed {
+        res.set_status_code(StatusCode::NO_CONTENT);
+    } else {
+        res.set_status_code(StatusCode::NOT_FOUND);
+    }
+}
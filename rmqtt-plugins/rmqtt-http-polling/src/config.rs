@@ -0,0 +1,126 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use rmqtt::serde_json;
+use rmqtt::{
+    settings::schema::{ConfigSchema, Field, FieldType},
+    settings::{deserialize_addr, deserialize_duration, Bytesize},
+    Result,
+};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    #[serde(default = "PluginConfig::workers_default")]
+    pub workers: usize,
+    ///Address of the HTTP listener of the polling transport, plain HTTP, on the loopback by default
+    ///for a TLS terminating reverse proxy in front of it
+    #[serde(default = "PluginConfig::http_laddr_default", deserialize_with = "deserialize_addr")]
+    pub http_laddr: SocketAddr,
+    ///The reverse proxies whose X-Forwarded-For header gives the address of the client
+    #[serde(default = "PluginConfig::trusted_proxies_default")]
+    pub trusted_proxies: Vec<IpAddr>,
+    ///Address of the MQTT TCP listener the sessions are connected to, its settings apply to them
+    #[serde(default = "PluginConfig::mqtt_addr_default", deserialize_with = "deserialize_addr")]
+    pub mqtt_addr: SocketAddr,
+    ///Maximum time a poll waits for data from the broker
+    #[serde(default = "PluginConfig::poll_timeout_default", deserialize_with = "deserialize_duration")]
+    pub poll_timeout: Duration,
+    ///A session without any request for this long is closed
+    #[serde(default = "PluginConfig::session_timeout_default", deserialize_with = "deserialize_duration")]
+    pub session_timeout: Duration,
+    #[serde(default = "PluginConfig::max_sessions_default")]
+    pub max_sessions: usize,
+    ///Maximum size of the data sent by the client in one request
+    #[serde(default = "PluginConfig::max_request_size_default")]
+    pub max_request_size: Bytesize,
+    ///Maximum data from the broker buffered for a session between the polls, the broker is not read
+    ///further until it is polled
+    #[serde(default = "PluginConfig::max_buffer_size_default")]
+    pub max_buffer_size: Bytesize,
+}
+
+impl PluginConfig {
+    fn workers_default() -> usize {
+        1
+    }
+
+    fn http_laddr_default() -> SocketAddr {
+        "127.0.0.1:6070".parse::<std::net::SocketAddr>().unwrap()
+    }
+
+    fn trusted_proxies_default() -> Vec<IpAddr> {
+        vec![IpAddr::from([127, 0, 0, 1]), IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1])]
+    }
+
+    fn mqtt_addr_default() -> SocketAddr {
+        "127.0.0.1:1883".parse::<std::net::SocketAddr>().unwrap()
+    }
+
+    fn poll_timeout_default() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn session_timeout_default() -> Duration {
+        Duration::from_secs(90)
+    }
+
+    fn max_sessions_default() -> usize {
+        10_000
+    }
+
+    fn max_request_size_default() -> Bytesize {
+        Bytesize::from(1024 * 1024)
+    }
+
+    fn max_buffer_size_default() -> Bytesize {
+        Bytesize::from(1024 * 1024)
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    pub fn schema() -> ConfigSchema {
+        ConfigSchema::new()
+            .field(Field::new("workers", FieldType::Integer).default(Self::workers_default()))
+            .field(
+                Field::new("http_laddr", FieldType::Addr)
+                    .default(Self::http_laddr_default().to_string())
+                    .descr("Address of the HTTP listener of the polling transport"),
+            )
+            .field(
+                Field::new("trusted_proxies", FieldType::Array)
+                    .default_of(Self::trusted_proxies_default())
+                    .descr(
+                        "The reverse proxies whose X-Forwarded-For header gives the address of the client",
+                    ),
+            )
+            .field(
+                Field::new("mqtt_addr", FieldType::Addr)
+                    .default(Self::mqtt_addr_default().to_string())
+                    .descr("Address of the MQTT TCP listener the sessions are connected to"),
+            )
+            .field(
+                Field::new("poll_timeout", FieldType::Duration)
+                    .default_duration(Self::poll_timeout_default())
+                    .descr("Maximum time a poll waits for data from the broker"),
+            )
+            .field(
+                Field::new("session_timeout", FieldType::Duration)
+                    .default_duration(Self::session_timeout_default())
+                    .descr("A session without any request for this long is closed"),
+            )
+            .field(Field::new("max_sessions", FieldType::Integer).default(Self::max_sessions_default()))
+            .field(
+                Field::new("max_request_size", FieldType::Bytesize)
+                    .default_bytesize(Self::max_request_size_default())
+                    .descr("Maximum size of the data sent by the client in one request"),
+            )
+            .field(
+                Field::new("max_buffer_size", FieldType::Bytesize)
+                    .default_bytesize(Self::max_buffer_size_default())
+                    .descr("Maximum data from the broker buffered for a session between the polls"),
+            )
+    }
+}
@@ -0,0 +1,184 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use config::PluginConfig;
+use rmqtt::{
+    async_trait::async_trait,
+    log, serde_json,
+    tokio::{self, sync::oneshot},
+    RwLock,
+};
+use rmqtt::{
+    plugin::{DynPlugin, DynPluginResult, Metric, Plugin},
+    settings::schema::ConfigSchema,
+    Result, Runtime,
+};
+use session::Sessions;
+
+mod api;
+mod config;
+mod session;
+
+type ShutdownTX = oneshot::Sender<()>;
+type PluginConfigType = Arc<RwLock<PluginConfig>>;
+
+#[inline]
+pub async fn register(
+    runtime: &'static Runtime,
+    name: &'static str,
+    descr: &'static str,
+    default_startup: bool,
+    immutable: bool,
+) -> Result<()> {
+    runtime
+        .plugins
+        .register(name, default_startup, immutable, move || -> DynPluginResult {
+            Box::pin(async move {
+                HttpPollingPlugin::new(runtime, name, descr).await.map(|p| -> DynPlugin { Box::new(p) })
+            })
+        })
+        .await?;
+    Ok(())
+}
+
+struct HttpPollingPlugin {
+    name: String,
+    descr: String,
+    cfg: PluginConfigType,
+    sessions: Arc<Sessions>,
+    shutdown_tx: Option<ShutdownTX>,
+}
+
+impl HttpPollingPlugin {
+    #[inline]
+    async fn new<S: Into<String>>(runtime: &'static Runtime, name: S, descr: S) -> Result<Self> {
+        let name = name.into();
+        let cfg = Arc::new(RwLock::new(
+            runtime
+                .settings
+                .plugins
+                .load_config_with_schema::<PluginConfig>(&name, &PluginConfig::schema())?,
+        ));
+        log::debug!("{} HttpPollingPlugin cfg: {:?}", name, cfg.read());
+        Ok(Self {
+            name,
+            descr: descr.into(),
+            cfg,
+            sessions: Arc::new(Sessions::default()),
+            shutdown_tx: None,
+        })
+    }
+
+    fn start_server(cfg: PluginConfigType, sessions: Arc<Sessions>) -> ShutdownTX {
+        let (shutdown_tx, shutdown_rx): (oneshot::Sender<()>, oneshot::Receiver<()>) = oneshot::channel();
+
+        let _child = std::thread::Builder::new().name("http-polling".to_string()).spawn(move || {
+            let cfg1 = cfg.clone();
+            let runner = async move {
+                let (laddr, session_timeout) = {
+                    let cfg = cfg1.read();
+                    (cfg.http_laddr, cfg.session_timeout)
+                };
+                let idle_sessions = sessions.clone();
+                tokio::spawn(async move {
+                    loop {
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        idle_sessions.remove_idle(session_timeout);
+                    }
+                });
+                if let Err(e) = api::listen_and_serve(laddr, cfg1, sessions.clone(), shutdown_rx).await {
+                    log::error!("{:?}", e);
+                }
+                sessions.clear();
+            };
+
+            let rt = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .worker_threads(cfg.read().workers.max(1))
+                .thread_name("http-polling-worker")
+                .thread_stack_size(4 * 1024 * 1024)
+                .build()
+                .unwrap();
+            rt.block_on(runner);
+            log::info!("Exit HTTP polling transport, ..., http://{:?}", cfg.read().http_laddr);
+        });
+        shutdown_tx
+    }
+}
+
+#[async_trait]
+impl Plugin for HttpPollingPlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name);
+        Ok(())
+    }
+
+    #[inline]
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        self.cfg.read().to_json()
+    }
+
+    #[inline]
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(PluginConfig::schema())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name);
+        if self.shutdown_tx.is_none() {
+            self.shutdown_tx = Some(Self::start_server(self.cfg.clone(), self.sessions.clone()));
+        }
+        Ok(())
+    }
+
+    ///The HTTP listener is closed, and so are the connections of the sessions to the broker
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::info!("{} stop", self.name);
+        if let Some(tx) = self.shutdown_tx.take() {
+            if let Err(e) = tx.send(()) {
+                log::warn!("shutdown_tx send fail, {:?}", e);
+            }
+        }
+        Ok(true)
+    }
+
+    #[inline]
+    fn version(&self) -> &str {
+        "0.1.0"
+    }
+
+    #[inline]
+    fn descr(&self) -> &str {
+        &self.descr
+    }
+
+    #[inline]
+    async fn metrics(&self) -> Vec<Metric> {
+        let stats = &self.sessions.stats;
+        vec![
+            Metric::gauge("sessions", self.sessions.len() as f64)
+                .descr("Sessions connected to the broker through the HTTP transport"),
+            Metric::counter("sessions_created", stats.sessions_created.load(Ordering::SeqCst) as f64)
+                .descr("Sessions created"),
+            Metric::counter("sessions_expired", stats.sessions_expired.load(Ordering::SeqCst) as f64)
+                .descr("Sessions closed after session_timeout without a request"),
+            Metric::counter("bytes_in", stats.bytes_in.load(Ordering::SeqCst) as f64)
+                .descr("Bytes sent by the clients to the broker"),
+            Metric::counter("bytes_out", stats.bytes_out.load(Ordering::SeqCst) as f64)
+                .descr("Bytes sent by the broker to the clients"),
+        ]
+    }
+}
@@ -0,0 +1,215 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rmqtt::broker::proxied::ProxiedPeers;
+use rmqtt::bytes::{Bytes, BytesMut};
+use rmqtt::rand::Rng;
+use rmqtt::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use rmqtt::tokio::net::{tcp::OwnedWriteHalf, TcpStream};
+use rmqtt::tokio::sync::{mpsc, Mutex};
+use rmqtt::tokio::task::JoinHandle;
+use rmqtt::{ahash, chrono, dashmap, log, rand, tokio, TimestampMillis};
+use rmqtt::{MqttError, Result};
+
+type DashMap<K, V> = dashmap::DashMap<K, V, ahash::RandomState>;
+
+const READ_BUF_SIZE: usize = 16 * 1024;
+
+pub(crate) enum Polled {
+    ///The data from the broker, empty if none arrived within the poll timeout
+    Data(Bytes),
+    ///The broker closed the connection and all its data has been polled
+    Closed,
+}
+
+///A connection to the MQTT listener on behalf of a polling client. The client sends the MQTT
+///packets in the body of its requests and polls the packets of the broker, the data is passed
+///through as it is. The broker sees the connection at the address of the client, see ProxiedPeers
+pub(crate) struct Session {
+    writer: Mutex<OwnedWriteHalf>,
+    rx: Mutex<mpsc::Receiver<Bytes>>,
+    last_active: AtomicI64,
+    reader: JoinHandle<()>,
+    local_addr: SocketAddr,
+    stats: Arc<Stats>,
+}
+
+impl Session {
+    async fn connect(
+        mqtt_addr: SocketAddr,
+        peer_addr: SocketAddr,
+        max_buffer_size: usize,
+        stats: Arc<Stats>,
+    ) -> Result<Self> {
+        let stream = TcpStream::connect(mqtt_addr).await?;
+        stream.set_nodelay(true)?;
+        let local_addr = stream.local_addr()?;
+        ProxiedPeers::instance().register(local_addr, peer_addr);
+        let (mut read_half, write_half) = stream.into_split();
+        let reader_stats = stats.clone();
+        let (tx, rx) = mpsc::channel((max_buffer_size / READ_BUF_SIZE).max(1));
+        let reader = tokio::spawn(async move {
+            let mut buf = BytesMut::with_capacity(READ_BUF_SIZE);
+            loop {
+                buf.reserve(READ_BUF_SIZE);
+                match read_half.read_buf(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        reader_stats.bytes_out.fetch_add(n, Ordering::SeqCst);
+                        if tx.send(buf.split().freeze()).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        log::debug!("read from the broker error, {:?}", e);
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            writer: Mutex::new(write_half),
+            rx: Mutex::new(rx),
+            last_active: AtomicI64::new(chrono::Local::now().timestamp_millis()),
+            reader,
+            local_addr,
+            stats,
+        })
+    }
+
+    #[inline]
+    fn touch(&self) {
+        self.last_active.store(chrono::Local::now().timestamp_millis(), Ordering::SeqCst);
+    }
+
+    ///Sends the data of the client to the broker
+    #[inline]
+    pub(crate) async fn send(&self, data: &[u8]) -> Result<()> {
+        self.touch();
+        self.writer.lock().await.write_all(data).await?;
+        Ok(())
+    }
+
+    ///Waits up to timeout for data from the broker, then returns all that is buffered, up to
+    ///max_size. The polls of a session are served one at a time
+    pub(crate) async fn poll(&self, timeout: Duration, max_size: usize) -> Polled {
+        self.touch();
+        let mut rx = self.rx.lock().await;
+        let first = match tokio::time::timeout(timeout, rx.recv()).await {
+            Ok(Some(data)) => data,
+            Ok(None) => return Polled::Closed,
+            Err(_) => return Polled::Data(Bytes::new()),
+        };
+        self.touch();
+        if first.len() >= max_size {
+            return Polled::Data(first);
+        }
+        let mut data = BytesMut::from(&first[..]);
+        while data.len() < max_size {
+            match rx.try_recv() {
+                Ok(more) => data.extend_from_slice(&more),
+                Err(_) => break,
+            }
+        }
+        Polled::Data(data.freeze())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.reader.abort();
+        ProxiedPeers::instance().unregister(&self.local_addr);
+        self.stats.sessions.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Stats {
+    ///Bytes sent by the clients to the broker
+    pub bytes_in: AtomicUsize,
+    ///Bytes sent by the broker to the clients
+    pub bytes_out: AtomicUsize,
+    pub sessions_created: AtomicUsize,
+    pub sessions_expired: AtomicUsize,
+    ///The sessions not dropped yet, reserved before they connect so that max_sessions is not exceeded
+    pub sessions: AtomicUsize,
+}
+
+#[derive(Default)]
+pub(crate) struct Sessions {
+    sessions: DashMap<String, Arc<Session>>,
+    pub stats: Arc<Stats>,
+}
+
+impl Sessions {
+    ///Connects a new session of the client of peer_addr to the MQTT listener, returns its id
+    pub(crate) async fn create(
+        &self,
+        mqtt_addr: SocketAddr,
+        peer_addr: SocketAddr,
+        max_sessions: usize,
+        max_buffer_size: usize,
+    ) -> Result<String> {
+        if self
+            .stats
+            .sessions
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                if n < max_sessions {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .is_err()
+        {
+            return Err(MqttError::from("too many sessions"));
+        }
+        let session = match Session::connect(mqtt_addr, peer_addr, max_buffer_size, self.stats.clone()).await
+        {
+            Ok(session) => session,
+            Err(e) => {
+                self.stats.sessions.fetch_sub(1, Ordering::SeqCst);
+                return Err(MqttError::from(format!("connect to {} error, {:?}", mqtt_addr, e)));
+            }
+        };
+        let id = format!("{:032x}", rand::thread_rng().gen::<u128>());
+        self.sessions.insert(id.clone(), Arc::new(session));
+        self.stats.sessions_created.fetch_add(1, Ordering::SeqCst);
+        Ok(id)
+    }
+
+    #[inline]
+    pub(crate) fn get(&self, id: &str) -> Option<Arc<Session>> {
+        self.sessions.get(id).map(|entry| entry.value().clone())
+    }
+
+    ///Closes the connection of the session to the broker
+    #[inline]
+    pub(crate) fn remove(&self, id: &str) -> bool {
+        self.sessions.remove(id).is_some()
+    }
+
+    #[inline]
+    pub(crate) fn clear(&self) {
+        self.sessions.clear();
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    ///Closes the sessions without any request for longer than timeout
+    pub(crate) fn remove_idle(&self, timeout: Duration) {
+        let expired = chrono::Local::now().timestamp_millis() - timeout.as_millis() as TimestampMillis;
+        let before = self.sessions.len();
+        self.sessions.retain(|_, session| session.last_active.load(Ordering::SeqCst) > expired);
+        let removed = before.saturating_sub(self.sessions.len());
+        if removed > 0 {
+            self.stats.sessions_expired.fetch_add(removed, Ordering::SeqCst);
+            log::debug!("{} idle sessions closed", removed);
+        }
+    }
+}
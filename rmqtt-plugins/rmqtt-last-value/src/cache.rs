@@ -0,0 +1,139 @@
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use rmqtt::broker::retain::RetainTree;
+use rmqtt::rust_box::dequemap::DequeMap;
+use rmqtt::{ahash, chrono, HashMap, Result, Retain, RwLock, Topic, TopicFilter, TopicName};
+
+///Number of the shards of the cache, a publish only locks the shard of its topic
+const SHARDS: usize = 64;
+
+struct Inner {
+    ///The last messages of each topic, from the oldest to the latest
+    values: RetainTree<VecDeque<Retain>>,
+    ///Topics from the least to the most recently published
+    order: DequeMap<TopicName, ()>,
+}
+
+impl Default for Inner {
+    fn default() -> Self {
+        Self { values: RetainTree::default(), order: DequeMap::default() }
+    }
+}
+
+///The last messages of each topic on this node, in shards by topic, each shard evicts its least
+///recently published topics beyond its share of max_topics
+pub(crate) struct LastValues {
+    shards: Vec<RwLock<Inner>>,
+    len: AtomicUsize,
+    pub(crate) evicted: AtomicUsize,
+}
+
+impl LastValues {
+    pub(crate) fn new() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| RwLock::new(Inner::default())).collect(),
+            len: AtomicUsize::new(0),
+            evicted: AtomicUsize::new(0),
+        }
+    }
+
+    #[inline]
+    fn shard(&self, topic: &TopicName) -> &RwLock<Inner> {
+        let mut hasher = ahash::AHasher::default();
+        topic.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    #[inline]
+    pub(crate) fn set(&self, topic: &Topic, value: Retain, history_size: usize, max_topics: usize) {
+        let max_topics = (max_topics + SHARDS - 1) / SHARDS;
+        let topic_name = value.publish.topic.clone();
+        let mut inner = self.shard(&topic_name).write();
+        let mut history = inner.values.remove(topic).unwrap_or_default();
+        history.push_back(value);
+        while history.len() > history_size.max(1) {
+            history.pop_front();
+        }
+        inner.values.insert(topic, history);
+        if inner.order.remove(&topic_name).is_none() {
+            self.len.fetch_add(1, Ordering::SeqCst);
+        }
+        inner.order.insert(topic_name, ());
+        while inner.order.len() > max_topics {
+            if let Some((topic, _)) = inner.order.pop_front() {
+                if let Ok(topic) = Topic::from_str(&topic) {
+                    inner.values.remove(&topic);
+                }
+                self.len.fetch_sub(1, Ordering::SeqCst);
+                self.evicted.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    ///The last `n` messages of each topic matching the topic filter, not older than max_age,
+    ///0 means no limit
+    #[inline]
+    pub(crate) fn get(
+        &self,
+        topic_filter: &TopicFilter,
+        n: usize,
+        max_age: Duration,
+    ) -> Result<Vec<(TopicName, Retain)>> {
+        let topic = Topic::from_str(topic_filter)?;
+        let oldest = if max_age.is_zero() {
+            0
+        } else {
+            chrono::Local::now().timestamp_millis() - max_age.as_millis() as i64
+        };
+        let mut values = Vec::new();
+        for shard in self.shards.iter() {
+            shard.read().values.matches_with(&topic, |_, history| {
+                let skip = history.len().saturating_sub(n);
+                for r in history.iter().skip(skip).filter(|r| r.publish.create_time >= oldest) {
+                    values.push((r.publish.topic.clone(), r.clone()));
+                }
+            });
+        }
+        Ok(values)
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    pub(crate) fn clear(&self) {
+        for shard in self.shards.iter() {
+            let mut inner = shard.write();
+            self.len.fetch_sub(inner.order.len(), Ordering::SeqCst);
+            *inner = Inner::default();
+        }
+    }
+}
+
+///Collects the messages of the nodes by topic
+#[inline]
+pub(crate) fn merge(values: Vec<(TopicName, Retain)>, merged: &mut HashMap<TopicName, Vec<Retain>>) {
+    for (topic, value) in values {
+        merged.entry(topic).or_default().push(value);
+    }
+}
+
+///The last `n` messages of each topic, sorted by topic and then from the oldest to the latest
+#[inline]
+pub(crate) fn last(merged: HashMap<TopicName, Vec<Retain>>, n: usize) -> Vec<(TopicName, Retain)> {
+    let mut topics = merged.into_iter().collect::<Vec<_>>();
+    topics.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mut values = Vec::new();
+    for (topic, mut history) in topics {
+        history.sort_by_key(|r| r.publish.create_time);
+        let skip = history.len().saturating_sub(n);
+        values.extend(history.into_iter().skip(skip).map(|r| (topic.clone(), r)));
+    }
+    values
+}
@@ -0,0 +1,135 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::de::{self, Deserialize};
+use serde::ser::{self, Serialize};
+
+use rmqtt::broker::topic::TopicTree;
+use rmqtt::grpc::MessageType;
+use rmqtt::serde_json;
+use rmqtt::settings::deserialize_duration;
+use rmqtt::settings::schema::{ConfigSchema, Field, FieldType};
+use rmqtt::{Result, Topic};
+
+type TopicsType = (Arc<TopicTree<()>>, Vec<String>);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    #[serde(default = "PluginConfig::message_type_default")]
+    pub message_type: MessageType,
+    ///Topic filters of the messages whose last value is kept, none by default
+    #[serde(
+        default = "PluginConfig::topics_default",
+        deserialize_with = "PluginConfig::deserialize_topics",
+        serialize_with = "PluginConfig::serialize_topics"
+    )]
+    pub topics: TopicsType,
+    ///Maximum number of topics kept on each node, the least recently published topic is evicted
+    #[serde(default = "PluginConfig::max_topics_default")]
+    pub max_topics: usize,
+    ///Messages kept per topic, the last value and the messages before it that can be replayed
+    #[serde(default = "PluginConfig::history_size_default")]
+    pub history_size: usize,
+    ///Messages older than this are not delivered nor queried, 0 means no limit
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub history_max_age: Duration,
+    ///Subscribing to <subscribe_prefix><topic filter> subscribes to the topic filter and delivers
+    ///the last values of the matching topics, empty to disable
+    #[serde(default = "PluginConfig::subscribe_prefix_default")]
+    pub subscribe_prefix: String,
+    ///Subscribing to <replay_prefix><n>/<topic filter> subscribes to the topic filter and delivers
+    ///the last n messages of the matching topics, empty to disable
+    #[serde(default = "PluginConfig::replay_prefix_default")]
+    pub replay_prefix: String,
+}
+
+impl PluginConfig {
+    fn message_type_default() -> MessageType {
+        139
+    }
+
+    fn topics_default() -> TopicsType {
+        (Arc::new(TopicTree::default()), Vec::new())
+    }
+
+    fn max_topics_default() -> usize {
+        100_000
+    }
+
+    fn history_size_default() -> usize {
+        1
+    }
+
+    fn subscribe_prefix_default() -> String {
+        "$lastvalue/".into()
+    }
+
+    fn replay_prefix_default() -> String {
+        "$replay/".into()
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    pub fn schema() -> ConfigSchema {
+        ConfigSchema::new()
+            .field(Field::new("message_type", FieldType::Integer).default(Self::message_type_default()))
+            .field(
+                Field::new("topics", FieldType::Array)
+                    .default_of(Self::topics_default().1)
+                    .descr("Topic filters of the messages whose last value is kept"),
+            )
+            .field(
+                Field::new("max_topics", FieldType::Integer)
+                    .default(Self::max_topics_default())
+                    .descr("Maximum number of topics kept on each node"),
+            )
+            .field(
+                Field::new("history_size", FieldType::Integer)
+                    .default(Self::history_size_default())
+                    .descr("Messages kept per topic"),
+            )
+            .field(
+                Field::new("history_max_age", FieldType::Duration)
+                    .default_duration(Duration::ZERO)
+                    .descr("Messages older than this are not delivered nor queried, 0 means no limit"),
+            )
+            .field(
+                Field::new("subscribe_prefix", FieldType::String)
+                    .default(Self::subscribe_prefix_default())
+                    .descr("Prefix of the subscriptions that deliver the last values, empty to disable"),
+            )
+            .field(
+                Field::new("replay_prefix", FieldType::String)
+                    .default(Self::replay_prefix_default())
+                    .descr("Prefix of the subscriptions that replay the last messages, empty to disable"),
+            )
+    }
+
+    #[inline]
+    pub fn is_match(&self, topic: &Topic) -> bool {
+        self.topics.0.is_match(topic)
+    }
+
+    fn serialize_topics<S>(topics: &TopicsType, s: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        topics.1.as_slice().serialize(s)
+    }
+
+    fn deserialize_topics<'de, D>(deserializer: D) -> std::result::Result<TopicsType, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let topics_cfg: Vec<String> = Vec::deserialize(deserializer)?;
+        let mut topics = TopicTree::default();
+        for topic in topics_cfg.iter() {
+            topics.insert(&Topic::from_str(topic).map_err(|e| de::Error::custom(format!("{:?}", e)))?, ());
+        }
+        Ok((Arc::new(topics), topics_cfg))
+    }
+}
@@ -0,0 +1,404 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use cache::LastValues;
+use config::PluginConfig;
+use rmqtt::{async_trait::async_trait, base64, dashmap, log, serde_json, tokio::sync::RwLock};
+use rmqtt::{
+    broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
+    grpc::{codec, registry::MessageTypes, Message, MessageBroadcaster, MessageReply, MessageType},
+    plugin::{DynPlugin, DynPluginResult, Metric, Plugin},
+    settings::schema::ConfigSchema,
+    ClientId, HashMap, QoSEx, Result, Retain, Runtime, Topic, TopicFilter, TopicName,
+};
+
+mod cache;
+mod config;
+
+#[inline]
+pub async fn register(
+    runtime: &'static Runtime,
+    name: &'static str,
+    descr: &'static str,
+    default_startup: bool,
+    immutable: bool,
+) -> Result<()> {
+    runtime
+        .plugins
+        .register(name, default_startup, immutable, move || -> DynPluginResult {
+            Box::pin(async move {
+                LastValuePlugin::new(runtime, name, descr).await.map(|p| -> DynPlugin { Box::new(p) })
+            })
+        })
+        .await?;
+    Ok(())
+}
+
+struct Shared {
+    values: LastValues,
+    ///Topic filters subscribed with the subscribe or replay prefix and the number of messages
+    ///requested per topic, waiting for the subscription to succeed
+    pending: dashmap::DashMap<ClientId, Vec<(TopicFilter, usize)>>,
+}
+
+#[derive(Deserialize)]
+struct QueryParams {
+    #[serde(default = "QueryParams::topic_filter_default")]
+    topic_filter: String,
+    #[serde(default = "QueryParams::limit_default")]
+    limit: usize,
+    ///Messages per topic
+    #[serde(default = "QueryParams::history_default")]
+    history: usize,
+}
+
+impl QueryParams {
+    fn topic_filter_default() -> String {
+        "#".into()
+    }
+
+    fn limit_default() -> usize {
+        100
+    }
+
+    fn history_default() -> usize {
+        1
+    }
+}
+
+struct LastValuePlugin {
+    runtime: &'static Runtime,
+    name: String,
+    descr: String,
+    register: Box<dyn Register>,
+    cfg: Arc<RwLock<PluginConfig>>,
+    shared: Arc<Shared>,
+    message_type: Option<MessageType>,
+}
+
+impl LastValuePlugin {
+    #[inline]
+    async fn new<N: Into<String>, D: Into<String>>(
+        runtime: &'static Runtime,
+        name: N,
+        descr: D,
+    ) -> Result<Self> {
+        let name = name.into();
+        let cfg = runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&name, &PluginConfig::schema())?;
+        log::info!("{} LastValuePlugin cfg: {:?}", name, cfg);
+        let register = runtime.extends.hook_mgr().await.register_for(&name);
+        let shared = Shared { values: LastValues::new(), pending: dashmap::DashMap::default() };
+        Ok(Self {
+            runtime,
+            name,
+            descr: descr.into(),
+            register,
+            cfg: Arc::new(RwLock::new(cfg)),
+            shared: Arc::new(shared),
+            message_type: None,
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for LastValuePlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name);
+        let cfg = &self.cfg;
+        let shared = &self.shared;
+        self.register.add(Type::MessagePublish, Box::new(LastValueHandler::new(cfg, shared))).await;
+        self.register.add(Type::ClientSubscribe, Box::new(LastValueHandler::new(cfg, shared))).await;
+        self.register.add(Type::ClientUnsubscribe, Box::new(LastValueHandler::new(cfg, shared))).await;
+        self.register.add(Type::SessionSubscribed, Box::new(LastValueHandler::new(cfg, shared))).await;
+        self.register.add(Type::SessionTerminated, Box::new(LastValueHandler::new(cfg, shared))).await;
+        self.register.add(Type::GrpcMessageReceived, Box::new(LastValueHandler::new(cfg, shared))).await;
+        Ok(())
+    }
+
+    #[inline]
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        self.cfg.read().await.to_json()
+    }
+
+    #[inline]
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(PluginConfig::schema())
+    }
+
+    #[inline]
+    async fn load_config(&mut self) -> Result<()> {
+        let new_cfg = self
+            .runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&self.name, &PluginConfig::schema())?;
+        *self.cfg.write().await = new_cfg;
+        log::debug!("load_config ok,  {:?}", self.cfg);
+        Ok(())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name);
+        if self.message_type.is_none() {
+            let message_type = self.cfg.read().await.message_type;
+            MessageTypes::instance().register(&self.name, message_type)?;
+            self.message_type.replace(message_type);
+        }
+        self.register.start().await;
+        Ok(())
+    }
+
+    ///The cached values are discarded
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::info!("{} stop", self.name);
+        self.register.stop().await;
+        if let Some(message_type) = self.message_type.take() {
+            MessageTypes::instance().unregister(&self.name, message_type);
+        }
+        self.shared.values.clear();
+        self.shared.pending.clear();
+        Ok(true)
+    }
+
+    #[inline]
+    fn version(&self) -> &str {
+        "0.1.0"
+    }
+
+    #[inline]
+    fn descr(&self) -> &str {
+        &self.descr
+    }
+
+    ///{"topic_filter": "sensors/#", "limit": 100, "history": 1}, returns the last `history` messages
+    ///of the topics matching the topic filter on all nodes
+    #[inline]
+    async fn send(&self, msg: serde_json::Value) -> Result<serde_json::Value> {
+        let params: QueryParams = serde_json::from_value(msg)?;
+        let topic_filter = TopicFilter::from(params.topic_filter);
+        let cfg = self.cfg.read().await.clone();
+        let values = get(&self.shared, &cfg, &topic_filter, params.history).await?;
+        let values = values
+            .into_iter()
+            .take(params.limit)
+            .map(|(topic, r)| {
+                serde_json::json!({
+                    "topic": topic,
+                    "payload": base64::encode(&r.publish.payload),
+                    "qos": r.publish.qos.value(),
+                    "retain": r.publish.retain,
+                    "clientid": r.from.client_id,
+                    "time": r.publish.create_time,
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(serde_json::Value::Array(values))
+    }
+
+    #[inline]
+    async fn attrs(&self) -> serde_json::Value {
+        serde_json::json!({
+            "topics": self.shared.values.len(),
+        })
+    }
+
+    #[inline]
+    async fn metrics(&self) -> Vec<Metric> {
+        vec![
+            Metric::gauge("topics", self.shared.values.len() as f64)
+                .descr("Topics whose last value is cached on this node"),
+            Metric::counter("evicted", self.shared.values.evicted.load(Ordering::SeqCst) as f64)
+                .descr("Topics evicted because max_topics was reached"),
+        ]
+    }
+}
+
+///The last `n` messages of the topics matching the topic filter on all nodes, sorted by topic
+///and then from the oldest to the latest
+async fn get(
+    shared: &Shared,
+    cfg: &PluginConfig,
+    topic_filter: &TopicFilter,
+    n: usize,
+) -> Result<Vec<(TopicName, Retain)>> {
+    let n = n.clamp(1, cfg.history_size.max(1));
+    let mut merged = HashMap::default();
+    cache::merge(shared.values.get(topic_filter, n, cfg.history_max_age)?, &mut merged);
+
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let msg = Message::Data(codec::encode(&(topic_filter, n))?);
+        for (node_id, reply) in MessageBroadcaster::new(grpc_clients, cfg.message_type, msg).join_all().await
+        {
+            match reply {
+                Ok(MessageReply::GetRetains(values)) => cache::merge(values, &mut merged),
+                Ok(_) => {}
+                Err(e) => log::warn!("get last values from node {}, error: {:?}", node_id, e),
+            }
+        }
+    }
+    Ok(cache::last(merged, n))
+}
+
+struct LastValueHandler {
+    cfg: Arc<RwLock<PluginConfig>>,
+    shared: Arc<Shared>,
+}
+
+impl LastValueHandler {
+    fn new(cfg: &Arc<RwLock<PluginConfig>>, shared: &Arc<Shared>) -> Self {
+        Self { cfg: cfg.clone(), shared: shared.clone() }
+    }
+
+    ///The topic filter without the subscribe or replay prefix and the number of messages requested
+    ///per topic, None if it has neither prefix
+    #[inline]
+    async fn strip_prefix(&self, topic_filter: &TopicFilter) -> Option<(TopicFilter, usize)> {
+        let cfg = self.cfg.read().await;
+        if !cfg.subscribe_prefix.is_empty() {
+            if let Some(tf) = topic_filter.strip_prefix(cfg.subscribe_prefix.as_str()) {
+                return Some((TopicFilter::from(tf), 1));
+            }
+        }
+        if !cfg.replay_prefix.is_empty() {
+            if let Some((n, tf)) =
+                topic_filter.strip_prefix(cfg.replay_prefix.as_str()).and_then(|tf| tf.split_once('/'))
+            {
+                match n.parse::<usize>() {
+                    Ok(n) if n > 0 && !tf.is_empty() => return Some((TopicFilter::from(tf), n)),
+                    _ => log::debug!("invalid replay subscription, {}", topic_filter),
+                }
+            }
+        }
+        None
+    }
+}
+
+#[async_trait]
+impl Handler for LastValueHandler {
+    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+        match param {
+            Parameter::MessagePublish(_session, c, publish) => {
+                //A previous handler may have modified the message
+                let publish = match &acc {
+                    Some(HookResult::Publish(p)) => p,
+                    _ => *publish,
+                };
+                let topic = match Topic::from_str(&publish.topic) {
+                    Ok(topic) => topic,
+                    Err(e) => {
+                        log::warn!("invalid topic {}, {:?}", publish.topic, e);
+                        return (true, acc);
+                    }
+                };
+                let cfg = self.cfg.read().await;
+                if cfg.is_match(&topic) {
+                    let value = Retain { from: c.id.clone(), publish: publish.clone() };
+                    self.shared.values.set(&topic, value, cfg.history_size, cfg.max_topics);
+                }
+            }
+
+            Parameter::ClientSubscribe(session, _c, sub) => {
+                //A previous handler may have modified the topic filter
+                let topic_filter = match &acc {
+                    Some(HookResult::TopicFilter(Some(tf))) => tf,
+                    _ => &sub.topic_filter,
+                };
+                if let Some((topic_filter, n)) = self.strip_prefix(topic_filter).await {
+                    self.shared
+                        .pending
+                        .entry(session.id.client_id.clone())
+                        .or_default()
+                        .push((topic_filter.clone(), n));
+                    return (true, Some(HookResult::TopicFilter(Some(topic_filter))));
+                }
+            }
+
+            Parameter::ClientUnsubscribe(_session, _c, unsub) => {
+                let topic_filter = match &acc {
+                    Some(HookResult::TopicFilter(Some(tf))) => tf,
+                    _ => &unsub.topic_filter,
+                };
+                if let Some((topic_filter, _)) = self.strip_prefix(topic_filter).await {
+                    return (true, Some(HookResult::TopicFilter(Some(topic_filter))));
+                }
+            }
+
+            Parameter::SessionSubscribed(session, _c, sub) => {
+                let requested = match self.shared.pending.get_mut(&session.id.client_id) {
+                    Some(mut pending) => pending
+                        .iter()
+                        .position(|(tf, _)| *tf == sub.topic_filter)
+                        .map(|pos| pending.remove(pos).1),
+                    None => None,
+                };
+                self.shared.pending.remove_if(&session.id.client_id, |_, pending| pending.is_empty());
+                let n = match requested {
+                    Some(n) => n,
+                    None => return (true, acc),
+                };
+
+                let cfg = self.cfg.read().await.clone();
+                let values = match get(&self.shared, &cfg, &sub.topic_filter, n).await {
+                    Ok(values) => values,
+                    Err(e) => {
+                        log::warn!(
+                            "{:?} get last values of {}, error: {:?}",
+                            session.id,
+                            sub.topic_filter,
+                            e
+                        );
+                        return (true, acc);
+                    }
+                };
+                //The retained message of a topic, its latest message, has already been sent for the
+                //subscription
+                let retain_available = session.listen_cfg.retain_available;
+                let mut values = values.into_iter().peekable();
+                let mut replays = Vec::new();
+                while let Some((topic, r)) = values.next() {
+                    let latest = values.peek().map(|(next, _)| *next != topic).unwrap_or(true);
+                    if !(latest && r.publish.retain && retain_available) {
+                        replays.push((topic, r));
+                    }
+                }
+                if let Err(e) = session.send_retain_messages(replays, sub.qos).await {
+                    log::warn!("{:?} send last values of {}, error: {:?}", session.id, sub.topic_filter, e);
+                }
+            }
+
+            Parameter::SessionTerminated(session, _c, _reason) => {
+                self.shared.pending.remove(&session.id.client_id);
+            }
+
+            Parameter::GrpcMessageReceived(typ, Message::Data(data)) => {
+                if self.cfg.read().await.message_type != *typ {
+                    return (true, acc);
+                }
+                let max_age = self.cfg.read().await.history_max_age;
+                let reply = codec::decode::<(TopicFilter, usize)>(data)
+                    .and_then(|(topic_filter, n)| self.shared.values.get(&topic_filter, n, max_age))
+                    .map(MessageReply::GetRetains);
+                return (false, Some(HookResult::GrpcMessageReply(reply)));
+            }
+            _ => {}
+        }
+        (true, acc)
+    }
+}
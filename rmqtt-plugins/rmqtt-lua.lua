@@ -0,0 +1,34 @@
+-- Example script of the rmqtt-lua plugin, all functions are optional.
+--
+-- client is a table with the fields node, ipaddress, clientid, username and create_time,
+-- on_auth also gets password, keepalive, proto_ver and clean_session.
+-- Each Lua state keeps its own globals, do not rely on state shared between calls.
+-- The table, string, math, utf8 and coroutine libraries are available, io, os, dofile,
+-- loadfile and require are not.
+
+-- Returns true to allow, false to deny, nil to leave the decision to the other plugins,
+-- a second return value of true marks a superuser, which bypasses the ACL.
+function on_auth(client)
+    if client.username == "blocked" then
+        return false
+    end
+    return nil
+end
+
+-- Returns the new topic or nil to keep it, a topic starting with $ or with a wildcard is
+-- refused and the message keeps its topic.
+function on_rewrite_topic(client, topic)
+    local rest = string.match(topic, "^legacy/(.+)$")
+    if rest then
+        return "v2/" .. rest
+    end
+    return nil
+end
+
+-- Returns false to drop the message.
+function on_filter(client, topic, payload)
+    if #payload > 0 and string.find(payload, "forbidden", 1, true) then
+        return false
+    end
+    return true
+end
@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use rmqtt::broker::hook::Priority;
+use rmqtt::serde_json;
+use rmqtt::settings::schema::{ConfigSchema, Field, FieldType};
+use rmqtt::settings::{deserialize_duration, Bytesize};
+use rmqtt::Result;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    //Lua script, see rmqtt-plugins/rmqtt-lua.lua
+    #[serde(default = "PluginConfig::script_default")]
+    pub script: String,
+
+    //Hook priority, handlers are executed from the highest priority to the lowest
+    #[serde(default = "PluginConfig::priority_default")]
+    pub priority: Priority,
+
+    //Maximum number of Lua instructions executed by one call of a script function,
+    //a call exceeding it is aborted, 0 means unlimited
+    #[serde(default = "PluginConfig::instruction_limit_default")]
+    pub instruction_limit: u32,
+
+    //Maximum memory of each Lua state, an allocation exceeding it fails the call, 0 means unlimited
+    #[serde(default = "PluginConfig::memory_limit_default")]
+    pub memory_limit: Bytesize,
+
+    //Number of Lua states the script is loaded into, calls run in parallel up to this number
+    #[serde(default = "PluginConfig::instances_default")]
+    pub instances: usize,
+
+    //Interval of the check for a modified script, which is then reloaded, 0 disables it
+    #[serde(default = "PluginConfig::reload_interval_default", deserialize_with = "deserialize_duration")]
+    pub reload_interval: Duration,
+}
+
+impl PluginConfig {
+    fn script_default() -> String {
+        "./rmqtt-plugins/rmqtt-lua.lua".into()
+    }
+
+    fn priority_default() -> Priority {
+        50
+    }
+
+    fn instruction_limit_default() -> u32 {
+        1_000_000
+    }
+
+    fn memory_limit_default() -> Bytesize {
+        Bytesize::from(16 * 1024 * 1024)
+    }
+
+    fn instances_default() -> usize {
+        4
+    }
+
+    fn reload_interval_default() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    pub fn schema() -> ConfigSchema {
+        ConfigSchema::new()
+            .field(
+                Field::new("script", FieldType::String).default(Self::script_default()).descr("Lua script"),
+            )
+            .field(
+                Field::new("priority", FieldType::Integer)
+                    .default(Self::priority_default())
+                    .descr("Hook priority, handlers are executed from the highest priority to the lowest"),
+            )
+            .field(
+                Field::new("instruction_limit", FieldType::Integer)
+                    .default(Self::instruction_limit_default())
+                    .descr("Maximum number of Lua instructions executed by one call, 0 means unlimited"),
+            )
+            .field(
+                Field::new("memory_limit", FieldType::Bytesize)
+                    .default_bytesize(Self::memory_limit_default())
+                    .descr("Maximum memory of each Lua state, 0 means unlimited"),
+            )
+            .field(
+                Field::new("instances", FieldType::Integer)
+                    .default(Self::instances_default())
+                    .descr("Number of Lua states the script is loaded into"),
+            )
+            .field(
+                Field::new("reload_interval", FieldType::Duration)
+                    .default_duration(Self::reload_interval_default())
+                    .descr("Interval of the check for a modified script, 0 disables it"),
+            )
+    }
+}
@@ -0,0 +1,338 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+use std::sync::Arc;
+
+use config::PluginConfig;
+use rmqtt::{
+    async_trait::async_trait,
+    log, serde_json,
+    tokio::{self, sync::RwLock, task::JoinHandle},
+};
+use rmqtt::{
+    broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
+    broker::types::{AuthResult, PublishAclResult},
+    plugin::{DynPlugin, DynPluginResult, Plugin},
+    settings::schema::ConfigSchema,
+    ClientInfo, MqttError, Result, Runtime, TopicName,
+};
+use script::Script;
+
+mod config;
+mod script;
+
+#[inline]
+pub async fn register(
+    runtime: &'static Runtime,
+    name: &'static str,
+    descr: &'static str,
+    default_startup: bool,
+    immutable: bool,
+) -> Result<()> {
+    runtime
+        .plugins
+        .register(name, default_startup, immutable, move || -> DynPluginResult {
+            Box::pin(async move {
+                LuaPlugin::new(runtime, name, descr).await.map(|p| -> DynPlugin { Box::new(p) })
+            })
+        })
+        .await?;
+    Ok(())
+}
+
+struct LuaPlugin {
+    runtime: &'static Runtime,
+    name: String,
+    descr: String,
+    register: Box<dyn Register>,
+    cfg: Arc<RwLock<PluginConfig>>,
+    script: Arc<RwLock<Arc<Script>>>,
+    watcher: Option<JoinHandle<()>>,
+}
+
+impl LuaPlugin {
+    #[inline]
+    async fn new<N: Into<String>, D: Into<String>>(
+        runtime: &'static Runtime,
+        name: N,
+        descr: D,
+    ) -> Result<Self> {
+        let name = name.into();
+        let cfg = runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&name, &PluginConfig::schema())?;
+        log::info!("{} LuaPlugin cfg: {:?}", name, cfg);
+        let script = load_script(&cfg).await?;
+        log::info!("{} loaded {}, functions: {:?}", name, script.path, script.functions());
+        let register = runtime.extends.hook_mgr().await.register_for(&name);
+        Ok(Self {
+            runtime,
+            name,
+            descr: descr.into(),
+            register,
+            cfg: Arc::new(RwLock::new(cfg)),
+            script: Arc::new(RwLock::new(Arc::new(script))),
+            watcher: None,
+        })
+    }
+
+    ///Reloads the script when its file is modified, a script that fails to load is logged
+    ///and the previous one is kept
+    #[inline]
+    async fn start_watcher(&mut self) {
+        let interval = self.cfg.read().await.reload_interval;
+        if interval.is_zero() {
+            return;
+        }
+        let name = self.name.clone();
+        let cfg = self.cfg.clone();
+        let script = self.script.clone();
+        self.watcher = Some(tokio::spawn(async move {
+            //Modification time of a script that failed to load, it is not retried until modified again
+            let mut failed = None;
+            loop {
+                tokio::time::sleep(interval).await;
+                let (path, modified) = {
+                    let script = script.read().await;
+                    (script.path.clone(), script.is_modified())
+                };
+                let mtime = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+                if !modified || (failed.is_some() && failed == mtime) {
+                    continue;
+                }
+                let cfg = cfg.read().await.clone();
+                match load_script(&cfg).await {
+                    Ok(new_script) => {
+                        log::info!("{} reloaded {}, functions: {:?}", name, path, new_script.functions());
+                        *script.write().await = Arc::new(new_script);
+                        failed = None;
+                    }
+                    Err(e) => {
+                        log::error!("{} reload {} failed, the previous script is kept, {}", name, path, e);
+                        failed = mtime;
+                    }
+                }
+            }
+        }));
+    }
+
+    #[inline]
+    fn stop_watcher(&mut self) {
+        if let Some(watcher) = self.watcher.take() {
+            watcher.abort();
+        }
+    }
+}
+
+#[inline]
+async fn load_script(cfg: &PluginConfig) -> Result<Script> {
+    let (path, instances, limit) = (cfg.script.clone(), cfg.instances, cfg.instruction_limit);
+    let memory_limit = *cfg.memory_limit;
+    tokio::task::spawn_blocking(move || Script::load(&path, instances, limit, memory_limit))
+        .await
+        .map_err(|e| MqttError::from(e.to_string()))?
+}
+
+#[async_trait]
+impl Plugin for LuaPlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name);
+        let priority = self.cfg.read().await.priority;
+        let script = &self.script;
+        self.register
+            .add_priority(Type::ClientAuthenticate, priority, Box::new(LuaHandler::new(script)))
+            .await;
+        self.register.add_priority(Type::MessagePublish, priority, Box::new(LuaHandler::new(script))).await;
+        self.register
+            .add_priority(Type::MessagePublishCheckAcl, priority, Box::new(LuaHandler::new(script)))
+            .await;
+        Ok(())
+    }
+
+    #[inline]
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        self.cfg.read().await.to_json()
+    }
+
+    #[inline]
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(PluginConfig::schema())
+    }
+
+    ///The script is reloaded with the new config, the hook priority only changes on restart
+    #[inline]
+    async fn load_config(&mut self) -> Result<()> {
+        let new_cfg = self
+            .runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&self.name, &PluginConfig::schema())?;
+        let script = load_script(&new_cfg).await?;
+        *self.script.write().await = Arc::new(script);
+        *self.cfg.write().await = new_cfg;
+        log::debug!("load_config ok,  {:?}", self.cfg);
+        if self.watcher.is_some() {
+            self.stop_watcher();
+            self.start_watcher().await;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name);
+        self.register.start().await;
+        if self.watcher.is_none() {
+            self.start_watcher().await;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::info!("{} stop", self.name);
+        self.register.stop().await;
+        self.stop_watcher();
+        Ok(true)
+    }
+
+    #[inline]
+    fn version(&self) -> &str {
+        "0.1.0"
+    }
+
+    #[inline]
+    fn descr(&self) -> &str {
+        &self.descr
+    }
+
+    ///The decisions of the script are taken before the ACL rules
+    #[inline]
+    fn hooks_before(&self) -> Vec<String> {
+        vec!["rmqtt-acl".into()]
+    }
+
+    #[inline]
+    async fn attrs(&self) -> serde_json::Value {
+        let script = self.script.read().await;
+        serde_json::json!({
+            "script": script.path,
+            "functions": script.functions(),
+        })
+    }
+}
+
+struct LuaHandler {
+    script: Arc<RwLock<Arc<Script>>>,
+}
+
+impl LuaHandler {
+    fn new(script: &Arc<RwLock<Arc<Script>>>) -> Self {
+        Self { script: script.clone() }
+    }
+}
+
+#[async_trait]
+impl Handler for LuaHandler {
+    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+        let script = self.script.read().await.clone();
+        match param {
+            Parameter::ClientAuthenticate(connect_info) => {
+                if matches!(
+                    acc,
+                    Some(HookResult::AuthResult(AuthResult::BadUsernameOrPassword))
+                        | Some(HookResult::AuthResult(AuthResult::NotAuthorized))
+                ) {
+                    return (false, acc);
+                }
+                if !script.has_auth() {
+                    return (true, acc);
+                }
+                let mut client = connect_info.to_json();
+                if let Some(obj) = client.as_object_mut() {
+                    let password = connect_info.password().map(|p| String::from_utf8_lossy(p).to_string());
+                    obj.insert("password".into(), serde_json::json!(password));
+                }
+                match blocking(script, move |script| script.auth(&client)).await {
+                    Ok(Some((true, superuser))) => {
+                        return (false, Some(HookResult::AuthResult(AuthResult::Allow(superuser))))
+                    }
+                    Ok(Some((false, _))) => {
+                        return (false, Some(HookResult::AuthResult(AuthResult::NotAuthorized)))
+                    }
+                    Ok(None) => {}
+                    Err(e) => log::warn!("{:?} {}", connect_info.id(), e),
+                }
+            }
+
+            Parameter::MessagePublish(_session, c, publish) => {
+                //A previous handler may have modified the message
+                let publish = match &acc {
+                    Some(HookResult::Publish(p)) => p,
+                    _ => *publish,
+                };
+                if !script.has_rewrite_topic() {
+                    return (true, acc);
+                }
+                let (client, topic) = (client_json(c), publish.topic.clone());
+                match blocking(script, move |script| script.rewrite_topic(&client, &topic)).await {
+                    Ok(Some(topic)) => {
+                        log::debug!("{:?} topic rewritten, {} -> {}", c.id, publish.topic, topic);
+                        let mut publish = publish.clone();
+                        publish.topic = TopicName::from(topic);
+                        return (true, Some(HookResult::Publish(publish)));
+                    }
+                    Ok(None) => {}
+                    Err(e) => log::warn!("{:?} {}", c.id, e),
+                }
+            }
+
+            Parameter::MessagePublishCheckAcl(_session, c, publish) => {
+                if let Some(HookResult::PublishAclResult(PublishAclResult::Rejected(_))) = acc {
+                    return (false, acc);
+                }
+                if !script.has_filter() {
+                    return (true, acc);
+                }
+                let (client, topic, payload) =
+                    (client_json(c), publish.topic.clone(), publish.payload.clone());
+                match blocking(script, move |script| script.filter(&client, &topic, &payload)).await {
+                    Ok(false) => {
+                        log::debug!("{:?} message dropped by the filter, topic: {}", c.id, publish.topic);
+                        return (
+                            false,
+                            Some(HookResult::PublishAclResult(PublishAclResult::Rejected(false))),
+                        );
+                    }
+                    Ok(true) => {}
+                    Err(e) => log::warn!("{:?} {}", c.id, e),
+                }
+            }
+            _ => {}
+        }
+        (true, acc)
+    }
+}
+
+///Runs a call of the script on the blocking threads, a Lua call does not yield
+#[inline]
+async fn blocking<R, F>(script: Arc<Script>, f: F) -> Result<R>
+where
+    F: FnOnce(&Script) -> Result<R> + Send + 'static,
+    R: Send + 'static,
+{
+    tokio::task::spawn_blocking(move || f(&script)).await.map_err(|e| MqttError::from(e.to_string()))?
+}
+
+#[inline]
+fn client_json(c: &ClientInfo) -> serde_json::Value {
+    c.id.to_json()
+}
@@ -0,0 +1,255 @@
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use mlua::{Function, HookTriggers, Lua, LuaOptions, LuaSerdeExt, StdLib, Value};
+
+use rmqtt::{serde_json, MqttError, Result};
+
+//Instructions between two checks of the instruction limit
+const CHECK_STEP: u32 = 1000;
+
+const ON_AUTH: &str = "on_auth";
+const ON_REWRITE_TOPIC: &str = "on_rewrite_topic";
+const ON_FILTER: &str = "on_filter";
+
+//Functions of the base library reading files, removed with the io and os libraries
+const UNSAFE_GLOBALS: [&str; 3] = ["dofile", "loadfile", "require"];
+
+struct State {
+    lua: Lua,
+    steps: Arc<AtomicU32>,
+}
+
+///A script loaded into a fixed number of Lua states, each call takes a free state, or waits for
+///the next one. The states only have the table, string, math, utf8 and coroutine libraries, no
+///access to the files or the process.
+pub(crate) struct Script {
+    pub path: String,
+    modified: Option<SystemTime>,
+    states: Vec<Mutex<State>>,
+    next: AtomicUsize,
+    has_auth: bool,
+    has_rewrite_topic: bool,
+    has_filter: bool,
+}
+
+impl Script {
+    #[inline]
+    pub fn load(path: &str, instances: usize, instruction_limit: u32, memory_limit: usize) -> Result<Self> {
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let src = std::fs::read_to_string(path)
+            .map_err(|e| MqttError::from(format!("read lua script {} error, {:?}", path, e)))?;
+        let mut states = Vec::new();
+        for _ in 0..instances.max(1) {
+            let state = Self::new_state(path, &src, instruction_limit, memory_limit)
+                .map_err(|e| MqttError::from(format!("load lua script {} error, {}", path, e)))?;
+            states.push(Mutex::new(state));
+        }
+        let (has_auth, has_rewrite_topic, has_filter) = {
+            let state = states[0].lock().unwrap_or_else(|e| e.into_inner());
+            let globals = state.lua.globals();
+            let has = |name: &str| matches!(globals.get::<_, Value>(name), Ok(Value::Function(_)));
+            (has(ON_AUTH), has(ON_REWRITE_TOPIC), has(ON_FILTER))
+        };
+        Ok(Self {
+            path: path.into(),
+            modified,
+            states,
+            next: AtomicUsize::new(0),
+            has_auth,
+            has_rewrite_topic,
+            has_filter,
+        })
+    }
+
+    #[inline]
+    fn new_state(path: &str, src: &str, instruction_limit: u32, memory_limit: usize) -> mlua::Result<State> {
+        let lua = Lua::new_with(
+            StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8 | StdLib::COROUTINE,
+            LuaOptions::default(),
+        )?;
+        for name in UNSAFE_GLOBALS {
+            lua.globals().set(name, Value::Nil)?;
+        }
+        if memory_limit > 0 {
+            lua.set_memory_limit(memory_limit)?;
+        }
+        let steps = Arc::new(AtomicU32::new(0));
+        if instruction_limit > 0 {
+            let max_steps = (instruction_limit / CHECK_STEP).max(1);
+            let counter = steps.clone();
+            lua.set_hook(
+                HookTriggers { every_nth_instruction: Some(CHECK_STEP), ..Default::default() },
+                move |_, _| {
+                    if counter.fetch_add(1, Ordering::Relaxed) >= max_steps {
+                        Err(mlua::Error::RuntimeError("instruction limit exceeded".into()))
+                    } else {
+                        Ok(())
+                    }
+                },
+            )?;
+        }
+        lua.load(src).set_name(path)?.exec()?;
+        Ok(State { lua, steps })
+    }
+
+    ///Modification time of the script file has changed since it was loaded
+    #[inline]
+    pub fn is_modified(&self) -> bool {
+        std::fs::metadata(&self.path).and_then(|m| m.modified()).ok() != self.modified
+    }
+
+    ///Blocks until a state is free, to be called off the async workers
+    #[inline]
+    fn call<R, F>(&self, name: &str, f: F) -> Result<R>
+    where
+        F: FnOnce(&Lua, Function) -> mlua::Result<R>,
+    {
+        let n = self.states.len();
+        let idx = self.next.fetch_add(1, Ordering::Relaxed) % n;
+        let state = (0..n)
+            .find_map(|i| self.states[(idx + i) % n].try_lock().ok())
+            .unwrap_or_else(|| self.states[idx].lock().unwrap_or_else(|e| e.into_inner()));
+        state.steps.store(0, Ordering::Relaxed);
+        let res = state.lua.globals().get::<_, Function>(name).and_then(|func| f(&state.lua, func));
+        res.map_err(|e| MqttError::from(format!("lua {} error, {}", name, e)))
+    }
+
+    ///on_auth(client), returns true to allow, false to deny, nil to leave the decision to
+    ///the other handlers, a second return value of true marks a superuser
+    #[inline]
+    pub fn auth(&self, client: &serde_json::Value) -> Result<Option<(bool, bool)>> {
+        if !self.has_auth {
+            return Ok(None);
+        }
+        self.call(ON_AUTH, |lua, func| {
+            let (allow, superuser) = func.call::<_, (Option<bool>, Option<bool>)>(lua.to_value(client)?)?;
+            Ok(allow.map(|allow| (allow, superuser.unwrap_or(false))))
+        })
+    }
+
+    ///on_rewrite_topic(client, topic), returns the new topic or nil to keep it, a topic that is not
+    ///a valid topic name, or starts with $, is an error
+    #[inline]
+    pub fn rewrite_topic(&self, client: &serde_json::Value, topic: &str) -> Result<Option<String>> {
+        if !self.has_rewrite_topic {
+            return Ok(None);
+        }
+        let new_topic = self.call(ON_REWRITE_TOPIC, |lua, func| {
+            func.call::<_, Option<String>>((lua.to_value(client)?, topic))
+        })?;
+        match new_topic {
+            Some(t) if !is_valid_topic(&t) => {
+                Err(MqttError::from(format!("lua {} error, invalid topic {:?}", ON_REWRITE_TOPIC, t)))
+            }
+            t => Ok(t),
+        }
+    }
+
+    ///on_filter(client, topic, payload), returns false to drop the message
+    #[inline]
+    pub fn filter(&self, client: &serde_json::Value, topic: &str, payload: &[u8]) -> Result<bool> {
+        if !self.has_filter {
+            return Ok(true);
+        }
+        self.call(ON_FILTER, |lua, func| {
+            let keep =
+                func.call::<_, Option<bool>>((lua.to_value(client)?, topic, lua.create_string(payload)?))?;
+            Ok(keep.unwrap_or(true))
+        })
+    }
+
+    #[inline]
+    pub fn has_auth(&self) -> bool {
+        self.has_auth
+    }
+
+    #[inline]
+    pub fn has_rewrite_topic(&self) -> bool {
+        self.has_rewrite_topic
+    }
+
+    #[inline]
+    pub fn has_filter(&self) -> bool {
+        self.has_filter
+    }
+
+    #[inline]
+    pub fn functions(&self) -> Vec<&'static str> {
+        [(ON_AUTH, self.has_auth), (ON_REWRITE_TOPIC, self.has_rewrite_topic), (ON_FILTER, self.has_filter)]
+            .iter()
+            .filter_map(|(name, has)| if *has { Some(*name) } else { None })
+            .collect()
+    }
+}
+
+///A topic name a client could publish to, not a system topic
+#[inline]
+fn is_valid_topic(topic: &str) -> bool {
+    !topic.is_empty()
+        && topic.len() <= u16::MAX as usize
+        && !topic.starts_with('$')
+        && !topic.contains(&['+', '#', '\0'][..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(src: &str) -> Script {
+        let path = std::env::temp_dir().join(format!("rmqtt-lua-{}.lua", rmqtt::rand::random::<u64>()));
+        std::fs::write(&path, src).unwrap();
+        let script = Script::load(path.to_str().unwrap(), 1, 100_000, 1024 * 1024);
+        std::fs::remove_file(&path).ok();
+        script.unwrap()
+    }
+
+    #[test]
+    fn sandbox() {
+        let script = load(
+            r#"
+            function on_filter(client, topic, payload)
+                return io == nil and os == nil and dofile == nil and loadfile == nil and require == nil
+            end
+            "#,
+        );
+        assert!(script.filter(&serde_json::json!({}), "a", b"").unwrap());
+    }
+
+    #[test]
+    fn limits() {
+        let script = load(
+            r#"
+            function on_filter(client, topic, payload)
+                if topic == "loop" then
+                    while true do end
+                end
+                local t = {}
+                for i = 1, 10000000 do t[i] = string.rep("x", 100) .. i end
+                return true
+            end
+            "#,
+        );
+        assert!(script.filter(&serde_json::json!({}), "loop", b"").is_err());
+        assert!(script.filter(&serde_json::json!({}), "alloc", b"").is_err());
+    }
+
+    #[test]
+    fn rewrite_topic() {
+        let script = load(
+            r#"
+            function on_rewrite_topic(client, topic)
+                if topic == "keep" then return nil end
+                return topic
+            end
+            "#,
+        );
+        let client = serde_json::json!({});
+        assert_eq!(script.rewrite_topic(&client, "keep").unwrap(), None);
+        assert_eq!(script.rewrite_topic(&client, "a/b").unwrap(), Some("a/b".into()));
+        assert!(script.rewrite_topic(&client, "$SYS/a").is_err());
+        assert!(script.rewrite_topic(&client, "a/#").is_err());
+        assert!(script.rewrite_topic(&client, "").is_err());
+    }
+}
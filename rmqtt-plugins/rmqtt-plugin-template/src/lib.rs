@@ -34,8 +34,9 @@ struct Template {
 impl Template {
     #[inline]
     async fn new<S: Into<String>>(runtime: &'static Runtime, name: S, descr: S) -> Result<Self> {
-        let register = runtime.extends.hook_mgr().await.register();
-        Ok(Self { _runtime: runtime, name: name.into(), descr: descr.into(), register })
+        let name = name.into();
+        let register = runtime.extends.hook_mgr().await.register_for(&name);
+        Ok(Self { _runtime: runtime, name, descr: descr.into(), register })
     }
 }
 
@@ -0,0 +1,113 @@
+use std::io::{Read, Write};
+
+use rmqtt::bytes::Bytes;
+use rmqtt::ntex::util::ByteString;
+use rmqtt::{anyhow, MqttError, Publish, Result};
+
+use crate::config::{Algorithm, Compression};
+
+///User property marking a payload compressed by the plugin, its value is the algorithm, gzip or
+///zstd. It is reserved, removed from the messages published by the clients.
+pub(crate) const CONTENT_ENCODING: &str = "$rmqtt-replication/content-encoding";
+
+impl Algorithm {
+    #[inline]
+    fn as_str(&self) -> &'static str {
+        match self {
+            Algorithm::Gzip => "gzip",
+            Algorithm::Zstd => "zstd",
+        }
+    }
+
+    #[inline]
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "gzip" => Some(Algorithm::Gzip),
+            "zstd" => Some(Algorithm::Zstd),
+            _ => None,
+        }
+    }
+
+    fn compress(&self, data: &[u8], level: Option<i32>) -> Result<Vec<u8>> {
+        match self {
+            Algorithm::Gzip => {
+                let level = level.map(|l| flate2::Compression::new(l.clamp(0, 9) as u32)).unwrap_or_default();
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+                encoder.write_all(data).map_err(anyhow::Error::new)?;
+                Ok(encoder.finish().map_err(anyhow::Error::new)?)
+            }
+            Algorithm::Zstd => {
+                Ok(zstd::encode_all(data, level.unwrap_or_default()).map_err(anyhow::Error::new)?)
+            }
+        }
+    }
+
+    ///Fails if the payload is larger than max_size once decompressed
+    fn decompress(&self, data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let limit = max_size as u64 + 1;
+        match self {
+            Algorithm::Gzip => flate2::read::GzDecoder::new(data)
+                .take(limit)
+                .read_to_end(&mut out)
+                .map_err(anyhow::Error::new)?,
+            Algorithm::Zstd => zstd::stream::read::Decoder::new(data)
+                .map_err(anyhow::Error::new)?
+                .take(limit)
+                .read_to_end(&mut out)
+                .map_err(anyhow::Error::new)?,
+        };
+        if out.len() > max_size {
+            return Err(MqttError::from(format!("decompressed payload is larger than {} bytes", max_size)));
+        }
+        Ok(out)
+    }
+}
+
+///Compresses the payload unless it is smaller than min_size, is already encoded, or does not get
+///smaller. Returns the number of the bytes saved, None if it is left as it is
+pub(crate) fn compress(publish: &mut Publish, compression: &Compression) -> Result<Option<usize>> {
+    if publish.payload.len() < compression.min_size || is_compressed(publish) {
+        return Ok(None);
+    }
+    let compressed = compression.algorithm.compress(&publish.payload, compression.level)?;
+    if compressed.len() >= publish.payload.len() {
+        return Ok(None);
+    }
+    let saved = publish.payload.len() - compressed.len();
+    publish.payload = Bytes::from(compressed);
+    publish.properties.user_properties.push((
+        ByteString::from_static(CONTENT_ENCODING),
+        ByteString::from_static(compression.algorithm.as_str()),
+    ));
+    Ok(Some(saved))
+}
+
+///Decompresses a payload compressed by compress and removes its marker, returns false if it
+///is not compressed. Fails if the payload is larger than max_size once decompressed.
+pub(crate) fn decompress(publish: &mut Publish, max_size: usize) -> Result<bool> {
+    let algorithm = match content_encoding(publish) {
+        Some(name) => Algorithm::from_name(&name)
+            .ok_or_else(|| MqttError::from(format!("unsupported content-encoding, {}", name)))?,
+        None => return Ok(false),
+    };
+    publish.payload = Bytes::from(algorithm.decompress(&publish.payload, max_size)?);
+    publish.properties.user_properties.retain(|(k, _)| &**k != CONTENT_ENCODING);
+    Ok(true)
+}
+
+///Removes the reserved marker from a message published by a client
+#[inline]
+pub(crate) fn strip(publish: &mut Publish) {
+    publish.properties.user_properties.retain(|(k, _)| &**k != CONTENT_ENCODING);
+}
+
+#[inline]
+pub(crate) fn is_compressed(publish: &Publish) -> bool {
+    content_encoding(publish).is_some()
+}
+
+#[inline]
+fn content_encoding(publish: &Publish) -> Option<ByteString> {
+    publish.properties.user_properties.iter().find(|(k, _)| &**k == CONTENT_ENCODING).map(|(_, v)| v.clone())
+}
@@ -0,0 +1,209 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::de::{self, Deserialize};
+use serde::ser::{self, Serialize};
+
+use rmqtt::broker::topic::TopicTree;
+use rmqtt::broker::topic_template::TopicTemplate;
+use rmqtt::serde_json;
+use rmqtt::settings::schema::{ConfigSchema, Field, FieldType};
+use rmqtt::settings::{deserialize_addr_option, deserialize_duration, Bytesize, Secret};
+use rmqtt::{Result, Topic};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    ///Name of the local cluster, the same on all of its nodes and unique among the replicated clusters
+    pub cluster_id: String,
+    #[serde(default = "PluginConfig::queue_capacity_default")]
+    pub queue_capacity: usize,
+    #[serde(default = "PluginConfig::batch_size_default")]
+    pub batch_size: usize,
+    #[serde(default = "PluginConfig::retry_interval_default", deserialize_with = "deserialize_duration")]
+    pub retry_interval: Duration,
+    ///A remote cluster is reported as stalled while the oldest message being sent to it is older
+    #[serde(default = "PluginConfig::stall_timeout_default", deserialize_with = "deserialize_duration")]
+    pub stall_timeout: Duration,
+    ///Timeout of a request to a remote cluster
+    #[serde(default = "PluginConfig::request_timeout_default", deserialize_with = "deserialize_duration")]
+    pub request_timeout: Duration,
+    ///Address of the replication endpoint of the node, receiving the messages of the remote clusters,
+    ///separate from the cluster gRPC port, the endpoint is not started if it is not set
+    #[serde(default, deserialize_with = "deserialize_addr_option")]
+    pub laddr: Option<SocketAddr>,
+    ///Secret with which the remote clusters sign their requests to the endpoint, required with laddr
+    #[serde(default)]
+    pub secret: Secret,
+    ///A request whose timestamp is further from the local time is refused, the requests accepted
+    ///within it are not accepted again
+    #[serde(default = "PluginConfig::max_clock_skew_default", deserialize_with = "deserialize_duration")]
+    pub max_clock_skew: Duration,
+    ///Maximum size of the body of a request to the endpoint
+    #[serde(default = "PluginConfig::max_request_size_default")]
+    pub max_request_size: Bytesize,
+    ///The received messages whose payload was compressed by the origin cluster are decompressed
+    ///before they are published, up to the largest max_packet_size of the listeners, otherwise they
+    ///are published compressed, with the $rmqtt-replication/content-encoding user property, for the
+    ///subscribers to decompress
+    #[serde(default = "PluginConfig::decompress_default")]
+    pub decompress: bool,
+    #[serde(default, rename = "remote")]
+    pub remotes: Vec<Remote>,
+}
+
+impl PluginConfig {
+    fn queue_capacity_default() -> usize {
+        100_000
+    }
+
+    fn batch_size_default() -> usize {
+        100
+    }
+
+    fn retry_interval_default() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn stall_timeout_default() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn request_timeout_default() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn max_clock_skew_default() -> Duration {
+        Duration::from_secs(300)
+    }
+
+    fn max_request_size_default() -> Bytesize {
+        Bytesize::from(64 * 1024 * 1024)
+    }
+
+    fn decompress_default() -> bool {
+        true
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    pub fn schema() -> ConfigSchema {
+        ConfigSchema::new()
+            .field(
+                Field::new("cluster_id", FieldType::String)
+                    .required()
+                    .descr("Name of the local cluster, unique among the replicated clusters"),
+            )
+            .field(Field::new("queue_capacity", FieldType::Integer).default(Self::queue_capacity_default()))
+            .field(Field::new("batch_size", FieldType::Integer).default(Self::batch_size_default()))
+            .field(
+                Field::new("retry_interval", FieldType::Duration)
+                    .default_duration(Self::retry_interval_default()),
+            )
+            .field(
+                Field::new("stall_timeout", FieldType::Duration)
+                    .default_duration(Self::stall_timeout_default())
+                    .descr("A remote cluster is stalled while the oldest message being sent is older"),
+            )
+            .field(
+                Field::new("request_timeout", FieldType::Duration)
+                    .default_duration(Self::request_timeout_default())
+                    .descr("Timeout of a request to a remote cluster"),
+            )
+            .field(
+                Field::new("laddr", FieldType::Addr)
+                    .descr("Address of the replication endpoint of the node, not started if it is not set"),
+            )
+            .field(
+                Field::new("secret", FieldType::String)
+                    .descr("Secret with which the remote clusters sign their requests, required with laddr"),
+            )
+            .field(
+                Field::new("max_clock_skew", FieldType::Duration)
+                    .default_duration(Self::max_clock_skew_default())
+                    .descr("A request whose timestamp is further from the local time is refused"),
+            )
+            .field(
+                Field::new("max_request_size", FieldType::Bytesize)
+                    .default_bytesize(Self::max_request_size_default())
+                    .descr("Maximum size of the body of a request to the endpoint"),
+            )
+            .field(
+                Field::new("decompress", FieldType::Bool)
+                    .default(Self::decompress_default())
+                    .descr("Whether the compressed payloads received are decompressed"),
+            )
+            .field(Field::new("remote", FieldType::Array).descr("The remote clusters replicated to"))
+    }
+}
+
+type TopicsType = (Arc<TopicTree<()>>, Vec<String>);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Remote {
+    ///cluster_id of the remote cluster
+    pub name: String,
+    ///URLs of the replication endpoints of the nodes of the remote cluster, the messages are sent to
+    ///one of them
+    pub urls: Vec<String>,
+    ///Secret of the endpoints of the remote cluster, with which the requests are signed
+    pub secret: Secret,
+    ///Topic filters of the messages replicated to the remote cluster
+    #[serde(deserialize_with = "Remote::deserialize_topics", serialize_with = "Remote::serialize_topics")]
+    pub topics: TopicsType,
+    ///Topic of the messages in the remote cluster, the local topic if it is not set
+    #[serde(default)]
+    pub topic_template: Option<TopicTemplate>,
+    ///Compression of the payloads sent to the remote cluster, not compressed if it is not set
+    #[serde(default)]
+    pub compression: Option<Compression>,
+}
+
+impl Remote {
+    fn serialize_topics<S>(topics: &TopicsType, s: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        topics.1.as_slice().serialize(s)
+    }
+
+    fn deserialize_topics<'de, D>(deserializer: D) -> std::result::Result<TopicsType, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let topics_cfg: Vec<String> = Vec::deserialize(deserializer)?;
+        let mut topics = TopicTree::default();
+        for topic in topics_cfg.iter() {
+            topics.insert(&Topic::from_str(topic).map_err(|e| de::Error::custom(format!("{:?}", e)))?, ());
+        }
+        Ok((Arc::new(topics), topics_cfg))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    Gzip,
+    Zstd,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Compression {
+    pub algorithm: Algorithm,
+    ///Payloads smaller than it are sent as they are
+    #[serde(default = "Compression::min_size_default")]
+    pub min_size: usize,
+    ///Compression level, the default of the algorithm if it is not set
+    #[serde(default)]
+    pub level: Option<i32>,
+}
+
+impl Compression {
+    fn min_size_default() -> usize {
+        1024
+    }
+}
@@ -0,0 +1,465 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use config::PluginConfig;
+use replicator::{ConnState, Replicated, Replicator};
+use rmqtt::{
+    async_trait::async_trait,
+    chrono, dashmap, log, serde_json,
+    tokio::{
+        self,
+        sync::{oneshot, RwLock},
+    },
+};
+use rmqtt::{
+    broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
+    plugin::{DynPlugin, DynPluginResult, Metric, Plugin},
+    settings::schema::ConfigSchema,
+    From, MqttError, Result, Retain, Runtime,
+};
+use server::Endpoint;
+use signature::{Replays, Signer};
+use wire::Batch;
+
+mod compression;
+mod config;
+mod replicator;
+mod server;
+mod signature;
+mod wire;
+
+#[inline]
+pub async fn register(
+    runtime: &'static Runtime,
+    name: &'static str,
+    descr: &'static str,
+    default_startup: bool,
+    immutable: bool,
+) -> Result<()> {
+    runtime
+        .plugins
+        .register(name, default_startup, immutable, move || -> DynPluginResult {
+            Box::pin(async move {
+                ReplicationPlugin::new(runtime, name, descr).await.map(|p| -> DynPlugin { Box::new(p) })
+            })
+        })
+        .await?;
+    Ok(())
+}
+
+#[derive(Default)]
+struct ReceiveStats {
+    received: AtomicUsize,
+    ///Age of the last message received from the origin cluster, in milliseconds
+    lag: AtomicI64,
+}
+
+#[derive(Default)]
+struct Shared {
+    replicators: RwLock<Vec<Replicator>>,
+    receives: dashmap::DashMap<String, ReceiveStats>,
+    loops_prevented: AtomicUsize,
+}
+
+///Parameters of Plugin::send()
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum Command {
+    Health,
+}
+
+struct ReplicationPlugin {
+    runtime: &'static Runtime,
+    name: String,
+    descr: String,
+    register: Box<dyn Register>,
+    cfg: Arc<RwLock<PluginConfig>>,
+    shared: Arc<Shared>,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+}
+
+impl ReplicationPlugin {
+    #[inline]
+    async fn new<N: Into<String>, D: Into<String>>(
+        runtime: &'static Runtime,
+        name: N,
+        descr: D,
+    ) -> Result<Self> {
+        let name = name.into();
+        let cfg = runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&name, &PluginConfig::schema())?;
+        log::info!("{} ReplicationPlugin cfg: {:?}", name, cfg);
+        Self::check_config(&cfg)?;
+        let register = runtime.extends.hook_mgr().await.register_for(&name);
+        Ok(Self {
+            runtime,
+            name,
+            descr: descr.into(),
+            register,
+            cfg: Arc::new(RwLock::new(cfg)),
+            shared: Arc::new(Shared::default()),
+            shutdown_tx: None,
+        })
+    }
+
+    #[inline]
+    fn check_config(cfg: &PluginConfig) -> Result<()> {
+        if cfg.cluster_id.is_empty() {
+            return Err(MqttError::from("cluster_id is empty"));
+        }
+        if cfg.laddr.is_some() && cfg.secret.is_empty() {
+            return Err(MqttError::from("secret is empty, the replication endpoint requires it"));
+        }
+        Ok(())
+    }
+
+    ///Starts the replication endpoint if laddr is set, the previous one is stopped
+    #[inline]
+    async fn start_endpoint(&mut self) -> Result<()> {
+        self.stop_endpoint();
+        let cfg = self.cfg.read().await;
+        let laddr = match cfg.laddr {
+            Some(laddr) => laddr,
+            None => return Ok(()),
+        };
+        let signer = Signer::new(cfg.secret.expose()).ok_or_else(|| MqttError::from("invalid secret"))?;
+        let endpoint = Arc::new(Endpoint {
+            handler: ReplicationHandler::new(&self.cfg, &self.shared),
+            signer,
+            max_clock_skew: cfg.max_clock_skew.as_secs() as i64,
+            max_request_size: *cfg.max_request_size,
+            replays: Replays::default(),
+        });
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            if let Err(e) = server::listen_and_serve(laddr, endpoint, shutdown_rx).await {
+                log::error!("replication endpoint {} error, {:?}", laddr, e);
+            }
+        });
+        self.shutdown_tx = Some(shutdown_tx);
+        Ok(())
+    }
+
+    #[inline]
+    fn stop_endpoint(&mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            if tx.send(()).is_err() {
+                log::warn!("replication endpoint is stopped already");
+            }
+        }
+    }
+
+    #[inline]
+    async fn start_replicators(&self) -> Result<()> {
+        let cfg = self.cfg.read().await.clone();
+        let mut replicators = Vec::new();
+        for remote in cfg.remotes.iter() {
+            if remote.name == cfg.cluster_id {
+                return Err(MqttError::from(format!("remote {} is the local cluster", remote.name)));
+            }
+            replicators.push(Replicator::new(remote, &cfg).await?);
+        }
+        *self.shared.replicators.write().await = replicators;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Plugin for ReplicationPlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name);
+        let cfg = &self.cfg;
+        let shared = &self.shared;
+        self.register.add(Type::MessagePublish, Box::new(ReplicationHandler::new(cfg, shared))).await;
+        Ok(())
+    }
+
+    #[inline]
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        self.cfg.read().await.to_json()
+    }
+
+    #[inline]
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(PluginConfig::schema())
+    }
+
+    ///The remote clusters are reconnected and the endpoint is restarted with the new config, the
+    ///queued messages are discarded
+    #[inline]
+    async fn load_config(&mut self) -> Result<()> {
+        let new_cfg = self
+            .runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&self.name, &PluginConfig::schema())?;
+        Self::check_config(&new_cfg)?;
+        *self.cfg.write().await = new_cfg;
+        log::debug!("load_config ok,  {:?}", self.cfg);
+        if self.shutdown_tx.is_some() || !self.shared.replicators.read().await.is_empty() {
+            self.start_replicators().await?;
+            self.start_endpoint().await?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name);
+        self.start_replicators().await?;
+        self.start_endpoint().await?;
+        self.register.start().await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::info!("{} stop", self.name);
+        self.register.stop().await;
+        self.stop_endpoint();
+        self.shared.replicators.write().await.clear();
+        Ok(true)
+    }
+
+    #[inline]
+    fn version(&self) -> &str {
+        "0.1.0"
+    }
+
+    #[inline]
+    fn descr(&self) -> &str {
+        &self.descr
+    }
+
+    #[inline]
+    async fn attrs(&self) -> serde_json::Value {
+        let replicators = self.shared.replicators.read().await;
+        serde_json::json!({
+            "cluster_id": self.cfg.read().await.cluster_id,
+            "remotes": replicators.iter().map(|r| r.name.clone()).collect::<Vec<_>>(),
+            "endpoint": self.cfg.read().await.laddr,
+        })
+    }
+
+    #[inline]
+    async fn send(&self, msg: serde_json::Value) -> Result<serde_json::Value> {
+        match serde_json::from_value::<Command>(msg)? {
+            Command::Health => {
+                let replicators = self.shared.replicators.read().await;
+                Ok(serde_json::json!({
+                    "cluster_id": self.cfg.read().await.cluster_id,
+                    "remotes": replicators.iter().map(|r| r.health()).collect::<Vec<_>>(),
+                }))
+            }
+        }
+    }
+
+    #[inline]
+    async fn metrics(&self) -> Vec<Metric> {
+        let mut metrics = Vec::new();
+        for r in self.shared.replicators.read().await.iter() {
+            metrics.push(
+                Metric::gauge("connected", (r.stats.state() == ConnState::Connected) as u8 as f64)
+                    .label("remote", r.name.clone())
+                    .descr("1 if the last batch sent to the remote cluster was accepted"),
+            );
+            metrics.push(
+                Metric::gauge("stalled", r.is_stalled() as u8 as f64)
+                    .label("remote", r.name.clone())
+                    .descr("1 if the oldest message being replicated is older than stall_timeout"),
+            );
+            metrics.push(
+                Metric::gauge("last_delivery_at", r.stats.last_delivery_at.load(Ordering::SeqCst) as f64)
+                    .label("remote", r.name.clone())
+                    .descr("Time in milliseconds of the last batch accepted by the remote cluster"),
+            );
+            metrics.push(
+                Metric::gauge("delivery_lag_ms", r.stats.delivery_lag.load(Ordering::SeqCst) as f64)
+                    .label("remote", r.name.clone())
+                    .descr("Age of the oldest message of the last batch accepted by the remote cluster"),
+            );
+            metrics.push(
+                Metric::gauge("queue_len", r.queue_len() as f64)
+                    .label("remote", r.name.clone())
+                    .descr("Messages waiting to be replicated to the remote cluster"),
+            );
+            metrics.push(
+                Metric::gauge("lag_ms", r.lag() as f64)
+                    .label("remote", r.name.clone())
+                    .descr("Age of the oldest message being replicated to the remote cluster"),
+            );
+            metrics.push(
+                Metric::counter("sent", r.stats.sent.load(Ordering::SeqCst) as f64)
+                    .label("remote", r.name.clone())
+                    .descr("Messages replicated to the remote cluster"),
+            );
+            metrics.push(
+                Metric::counter("dropped", r.stats.dropped.load(Ordering::SeqCst) as f64)
+                    .label("remote", r.name.clone())
+                    .descr("Messages dropped because the replication queue was full"),
+            );
+            metrics.push(
+                Metric::counter("send_fails", r.stats.send_fails.load(Ordering::SeqCst) as f64)
+                    .label("remote", r.name.clone())
+                    .descr("Failed attempts to send a batch to the remote cluster"),
+            );
+            metrics.push(
+                Metric::counter("compressed", r.stats.compressed.load(Ordering::SeqCst) as f64)
+                    .label("remote", r.name.clone())
+                    .descr("Messages whose payload was compressed for the remote cluster"),
+            );
+            metrics.push(
+                Metric::counter(
+                    "compression_saved_bytes",
+                    r.stats.compression_saved_bytes.load(Ordering::SeqCst) as f64,
+                )
+                .label("remote", r.name.clone())
+                .descr("Payload bytes saved by compression"),
+            );
+        }
+        for entry in self.shared.receives.iter() {
+            metrics.push(
+                Metric::counter("received", entry.received.load(Ordering::SeqCst) as f64)
+                    .label("origin", entry.key().clone())
+                    .descr("Messages received from the origin cluster"),
+            );
+            metrics.push(
+                Metric::gauge("receive_lag_ms", entry.lag.load(Ordering::SeqCst) as f64)
+                    .label("origin", entry.key().clone())
+                    .descr("Age of the last message received from the origin cluster"),
+            );
+        }
+        metrics.push(
+            Metric::counter("loops_prevented", self.shared.loops_prevented.load(Ordering::SeqCst) as f64)
+                .descr("Received messages discarded because they had passed through the local cluster"),
+        );
+        metrics
+    }
+}
+
+pub(crate) struct ReplicationHandler {
+    cfg: Arc<RwLock<PluginConfig>>,
+    shared: Arc<Shared>,
+}
+
+impl ReplicationHandler {
+    fn new(cfg: &Arc<RwLock<PluginConfig>>, shared: &Arc<Shared>) -> Self {
+        Self { cfg: cfg.clone(), shared: shared.clone() }
+    }
+
+    #[inline]
+    async fn replicate(&self, msg: Replicated) {
+        for r in self.shared.replicators.read().await.iter() {
+            if r.is_match(&msg.publish.topic) {
+                r.replicate(msg.clone());
+            }
+        }
+    }
+
+    ///Publishes the messages of a remote cluster in the local cluster and passes them on to the
+    ///other remote clusters
+    pub(crate) async fn receive(&self, data: &[u8]) -> Result<()> {
+        let msgs: Vec<Replicated> = Batch::decode(data)?;
+        let (cluster_id, decompress) = {
+            let cfg = self.cfg.read().await;
+            (cfg.cluster_id.clone(), cfg.decompress)
+        };
+        let now = chrono::Local::now().timestamp_millis();
+        for mut msg in msgs {
+            if msg.path.contains(&cluster_id) {
+                self.shared.loops_prevented.fetch_add(1, Ordering::SeqCst);
+                continue;
+            }
+            {
+                let stats = self.shared.receives.entry(msg.origin().to_string()).or_default();
+                stats.received.fetch_add(1, Ordering::SeqCst);
+                stats.lag.store((now - msg.publish.create_time).max(0), Ordering::SeqCst);
+            }
+
+            let from = From::new(
+                Runtime::instance().node.id(),
+                None,
+                None,
+                msg.client_id.clone(),
+                msg.username.clone(),
+            );
+            let mut publish = msg.publish.clone();
+            if decompress && compression::is_compressed(&publish) {
+                let max_size = Runtime::instance().settings.listeners.max_packet_size();
+                let decompressed = tokio::task::spawn_blocking(move || {
+                    compression::decompress(&mut publish, max_size).map(|_| publish)
+                })
+                .await
+                .map_err(|e| MqttError::from(e.to_string()));
+                publish = match decompressed {
+                    Ok(Ok(publish)) => publish,
+                    Ok(Err(e)) | Err(e) => {
+                        log::warn!("{} message decompress error, {:?}", msg.origin(), e);
+                        continue;
+                    }
+                };
+            }
+            if publish.retain {
+                Runtime::instance()
+                    .extends
+                    .retain()
+                    .await
+                    .set(&publish.topic, Retain { from: from.clone(), publish: publish.clone() })
+                    .await?;
+            }
+            if let Err(droppeds) = Runtime::instance().extends.shared().await.forwards(from, publish).await {
+                for (to, from, p, reason) in droppeds {
+                    Runtime::instance()
+                        .extends
+                        .hook_mgr()
+                        .await
+                        .message_dropped(Some(to), from, p, reason)
+                        .await;
+                }
+            }
+
+            msg.path.push(cluster_id.clone());
+            self.replicate(msg).await;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Handler for ReplicationHandler {
+    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+        match param {
+            Parameter::MessagePublish(_session, c, publish) => {
+                //A previous handler may have modified the message
+                let publish = match &acc {
+                    Some(HookResult::Publish(p)) => p,
+                    _ => *publish,
+                };
+                let mut publish = publish.clone();
+                compression::strip(&mut publish);
+                rmqtt::telemetry::inject_user_property(&mut publish);
+                let msg = Replicated {
+                    path: vec![self.cfg.read().await.cluster_id.clone()],
+                    client_id: c.id.client_id.clone(),
+                    username: c.id.username.clone(),
+                    publish,
+                };
+                self.replicate(msg).await;
+            }
+            _ => {}
+        }
+        (true, acc)
+    }
+}
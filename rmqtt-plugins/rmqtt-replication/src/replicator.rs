@@ -0,0 +1,308 @@
+use std::sync::atomic::{AtomicI64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rmqtt::tokio::{self, sync::mpsc, task::JoinHandle};
+use rmqtt::{anyhow, chrono, log, reqwest, serde_json};
+use rmqtt::{ClientId, MqttError, Publish, Result, Runtime, Topic, UserName};
+
+use crate::compression;
+use crate::config::{Compression, PluginConfig, Remote};
+use crate::signature::{Signer, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use crate::wire::Batch;
+
+///A message exchanged between the clusters, sent in the format of the wire module
+#[derive(Clone, Debug)]
+pub(crate) struct Replicated {
+    ///cluster_id of the clusters the message has passed through, the origin first
+    pub path: Vec<String>,
+    pub client_id: ClientId,
+    pub username: Option<UserName>,
+    pub publish: Publish,
+}
+
+impl Replicated {
+    #[inline]
+    pub fn origin(&self) -> &str {
+        self.path.first().map(|o| o.as_str()).unwrap_or_default()
+    }
+}
+
+///State of the connection to a remote cluster, as of the last attempt to send a batch
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ConnState {
+    ///Nothing has been sent yet
+    Unknown = 0,
+    Connected = 1,
+    ///The last attempt failed, the batch is being retried on the next node
+    Retrying = 2,
+}
+
+impl ConnState {
+    #[inline]
+    fn from_u8(v: u8) -> Self {
+        match v {
+            1 => ConnState::Connected,
+            2 => ConnState::Retrying,
+            _ => ConnState::Unknown,
+        }
+    }
+
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnState::Unknown => "unknown",
+            ConnState::Connected => "connected",
+            ConnState::Retrying => "retrying",
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct ReplicatorStats {
+    pub sent: AtomicUsize,
+    pub dropped: AtomicUsize,
+    pub send_fails: AtomicUsize,
+    pub compressed: AtomicUsize,
+    pub compression_saved_bytes: AtomicUsize,
+    ///Creation time of the oldest message being sent, 0 when none
+    pub sending_since: AtomicI64,
+    state: AtomicU8,
+    ///Index in urls of the node the batches are sent to
+    node_idx: AtomicUsize,
+    ///Time of the last batch accepted by the remote cluster, 0 when none
+    pub last_delivery_at: AtomicI64,
+    ///Age of the oldest message of the last batch accepted by the remote cluster, in milliseconds
+    pub delivery_lag: AtomicI64,
+}
+
+impl ReplicatorStats {
+    #[inline]
+    pub fn state(&self) -> ConnState {
+        ConnState::from_u8(self.state.load(Ordering::SeqCst))
+    }
+}
+
+///Replicates the messages of its topic filters to a remote cluster, asynchronously through a
+///bounded queue, the messages are dropped while the queue is full
+pub(crate) struct Replicator {
+    pub name: String,
+    remote: Remote,
+    queue_capacity: usize,
+    stall_timeout: i64,
+    tx: mpsc::Sender<Replicated>,
+    pub stats: Arc<ReplicatorStats>,
+    worker: JoinHandle<()>,
+}
+
+impl Replicator {
+    #[inline]
+    pub async fn new(remote: &Remote, cfg: &PluginConfig) -> Result<Self> {
+        let signer = Signer::new(remote.secret.expose())
+            .ok_or_else(|| MqttError::from(format!("the secret of remote {} is empty", remote.name)))?;
+        let client =
+            reqwest::Client::builder().timeout(cfg.request_timeout).build().map_err(anyhow::Error::new)?;
+        let stats = Arc::new(ReplicatorStats::default());
+        let queue_capacity = cfg.queue_capacity.max(1);
+        let (tx, rx) = mpsc::channel(queue_capacity);
+        let worker = tokio::spawn(run(
+            remote.name.clone(),
+            client,
+            remote.urls.clone(),
+            signer,
+            cfg.batch_size.max(1),
+            cfg.retry_interval,
+            remote.compression.clone(),
+            rx,
+            stats.clone(),
+        ));
+        Ok(Self {
+            name: remote.name.clone(),
+            remote: remote.clone(),
+            queue_capacity,
+            stall_timeout: cfg.stall_timeout.as_millis() as i64,
+            tx,
+            stats,
+            worker,
+        })
+    }
+
+    #[inline]
+    pub fn is_match(&self, topic: &Topic) -> bool {
+        self.remote.topics.0.is_match(topic)
+    }
+
+    ///Queues a message unless the remote cluster is already on its path, with the topic of
+    ///topic_template
+    #[inline]
+    pub fn replicate(&self, mut msg: Replicated) {
+        if msg.path.iter().any(|c| c == &self.name) {
+            return;
+        }
+        if let Some(topic_template) = &self.remote.topic_template {
+            if let Some(topic) = topic_template.render(
+                &msg.publish.topic,
+                &msg.client_id,
+                msg.username.as_deref(),
+                Runtime::instance().node.id(),
+            ) {
+                msg.publish.topic = topic;
+            }
+        }
+        if let Err(e) = self.tx.try_send(msg) {
+            self.stats.dropped.fetch_add(1, Ordering::SeqCst);
+            log::debug!("{} replication queue is full, message dropped, {}", self.name, e);
+        }
+    }
+
+    #[inline]
+    pub fn queue_len(&self) -> usize {
+        self.queue_capacity - self.tx.capacity()
+    }
+
+    ///Age of the oldest message being sent, in milliseconds
+    #[inline]
+    pub fn lag(&self) -> i64 {
+        match self.stats.sending_since.load(Ordering::SeqCst) {
+            0 => 0,
+            since => (chrono::Local::now().timestamp_millis() - since).max(0),
+        }
+    }
+
+    ///The oldest message being sent is older than stall_timeout
+    #[inline]
+    pub fn is_stalled(&self) -> bool {
+        self.lag() > self.stall_timeout
+    }
+
+    #[inline]
+    pub fn health(&self) -> serde_json::Value {
+        let node_idx = self.stats.node_idx.load(Ordering::SeqCst);
+        serde_json::json!({
+            "remote": self.name,
+            "state": self.stats.state().as_str(),
+            "url": self.remote.urls.get(node_idx),
+            "stalled": self.is_stalled(),
+            "queue_len": self.queue_len(),
+            "lag_ms": self.lag(),
+            "last_delivery_at": self.stats.last_delivery_at.load(Ordering::SeqCst),
+            "delivery_lag_ms": self.stats.delivery_lag.load(Ordering::SeqCst),
+            "sent": self.stats.sent.load(Ordering::SeqCst),
+            "dropped": self.stats.dropped.load(Ordering::SeqCst),
+            "send_fails": self.stats.send_fails.load(Ordering::SeqCst),
+        })
+    }
+}
+
+impl Drop for Replicator {
+    fn drop(&mut self) {
+        self.worker.abort();
+    }
+}
+
+///Sends the queued messages in batches, a batch that fails is retried on the next node of the
+///remote cluster until it is accepted. The payloads are compressed here rather than when queued,
+///off the publish path
+#[allow(clippy::too_many_arguments)]
+async fn run(
+    name: String,
+    client: reqwest::Client,
+    urls: Vec<String>,
+    signer: Signer,
+    batch_size: usize,
+    retry_interval: Duration,
+    compression: Option<Compression>,
+    mut rx: mpsc::Receiver<Replicated>,
+    stats: Arc<ReplicatorStats>,
+) {
+    if urls.is_empty() {
+        log::error!("{} urls is empty, nothing is replicated", name);
+        return;
+    }
+    let mut idx = 0;
+    while let Some(msg) = rx.recv().await {
+        let mut batch = vec![msg];
+        while batch.len() < batch_size {
+            match rx.try_recv() {
+                Ok(msg) => batch.push(msg),
+                Err(_) => break,
+            }
+        }
+        if let Some(compression) = &compression {
+            let (compression, name, stats) = (compression.clone(), name.clone(), stats.clone());
+            batch = match tokio::task::spawn_blocking(move || {
+                for msg in batch.iter_mut() {
+                    match compression::compress(&mut msg.publish, &compression) {
+                        Ok(Some(saved)) => {
+                            stats.compressed.fetch_add(1, Ordering::SeqCst);
+                            stats.compression_saved_bytes.fetch_add(saved, Ordering::SeqCst);
+                        }
+                        Ok(None) => {}
+                        Err(e) => log::warn!("{} compress error, sent uncompressed, {:?}", name, e),
+                    }
+                }
+                batch
+            })
+            .await
+            {
+                Ok(batch) => batch,
+                Err(e) => {
+                    log::error!("{} compress task error, {:?}", name, e);
+                    continue;
+                }
+            };
+        }
+        let data = match Batch::encode(&batch) {
+            Ok(data) => data,
+            Err(e) => {
+                log::error!("{} encode error, {:?}", name, e);
+                stats.dropped.fetch_add(batch.len(), Ordering::SeqCst);
+                continue;
+            }
+        };
+        stats.sending_since.store(batch[0].publish.create_time, Ordering::SeqCst);
+        loop {
+            let url = &urls[idx];
+            match send(&client, url, &signer, data.clone()).await {
+                Ok(_) => {
+                    let now = chrono::Local::now().timestamp_millis();
+                    stats.sent.fetch_add(batch.len(), Ordering::SeqCst);
+                    stats.state.store(ConnState::Connected as u8, Ordering::SeqCst);
+                    stats.last_delivery_at.store(now, Ordering::SeqCst);
+                    stats.delivery_lag.store((now - batch[0].publish.create_time).max(0), Ordering::SeqCst);
+                    break;
+                }
+                Err(e) => {
+                    log::warn!("{} send to {} error, {:?}", name, url, e);
+                    stats.send_fails.fetch_add(1, Ordering::SeqCst);
+                    stats.state.store(ConnState::Retrying as u8, Ordering::SeqCst);
+                    idx = (idx + 1) % urls.len();
+                    stats.node_idx.store(idx, Ordering::SeqCst);
+                    tokio::time::sleep(retry_interval).await;
+                }
+            }
+        }
+        stats.sending_since.store(0, Ordering::SeqCst);
+    }
+}
+
+///Sends a batch signed with the secret of the remote cluster
+#[inline]
+async fn send(client: &reqwest::Client, url: &str, signer: &Signer, data: Vec<u8>) -> Result<()> {
+    let timestamp = chrono::Local::now().timestamp().to_string();
+    let signature = signer.sign(&timestamp, &data);
+    let resp = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(TIMESTAMP_HEADER, timestamp)
+        .header(SIGNATURE_HEADER, signature)
+        .body(data)
+        .send()
+        .await
+        .map_err(anyhow::Error::new)?;
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(MqttError::from(format!("response status is {}", resp.status())))
+    }
+}
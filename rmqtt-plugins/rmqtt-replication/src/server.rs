@@ -0,0 +1,62 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use salvo::affix;
+use salvo::prelude::*;
+
+use rmqtt::{anyhow, chrono, log, tokio::sync::oneshot, Result};
+
+use crate::signature::{Replays, Signer, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use crate::ReplicationHandler;
+
+///The replication endpoint, it only accepts the requests signed with the secret of the local
+///cluster, once each, within the clock skew
+pub(crate) struct Endpoint {
+    pub handler: ReplicationHandler,
+    pub signer: Signer,
+    pub max_clock_skew: i64,
+    pub max_request_size: usize,
+    pub replays: Replays,
+}
+
+pub(crate) async fn listen_and_serve(
+    laddr: SocketAddr,
+    endpoint: Arc<Endpoint>,
+    rx: oneshot::Receiver<()>,
+) -> Result<()> {
+    log::info!("Replication endpoint listening on {}", laddr);
+    let router = Router::with_path("replicate").hoop(affix::inject(endpoint)).post(replicate);
+    Server::new(TcpListener::bind(laddr))
+        .try_serve_with_graceful_shutdown(router, async {
+            rx.await.ok();
+        })
+        .await
+        .map_err(anyhow::Error::new)?;
+    Ok(())
+}
+
+#[handler]
+async fn replicate(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let endpoint = depot.obtain::<Arc<Endpoint>>().cloned().unwrap();
+    let timestamp = req.header::<String>(TIMESTAMP_HEADER).unwrap_or_default();
+    let signature = req.header::<String>(SIGNATURE_HEADER).unwrap_or_default();
+    let now = chrono::Local::now().timestamp();
+    let ts = match timestamp.parse::<i64>() {
+        Ok(ts) if (now - ts).abs() <= endpoint.max_clock_skew => ts,
+        _ => return res.set_status_error(StatusError::unauthorized().with_detail("invalid timestamp")),
+    };
+    let body = match req.payload_with_max_size(endpoint.max_request_size).await {
+        Ok(body) => body.clone(),
+        Err(e) => return res.set_status_error(StatusError::bad_request().with_detail(e.to_string())),
+    };
+    if !endpoint.signer.verify(&timestamp, &body, &signature) {
+        return res.set_status_error(StatusError::unauthorized().with_detail("invalid signature"));
+    }
+    if !endpoint.replays.check(&signature, ts, now - endpoint.max_clock_skew) {
+        return res.set_status_error(StatusError::unauthorized().with_detail("replayed request"));
+    }
+    if let Err(e) = endpoint.handler.receive(&body).await {
+        log::warn!("receive replicated messages error, {:?}", e);
+        res.set_status_error(StatusError::bad_request().with_detail(e.to_string()));
+    }
+}
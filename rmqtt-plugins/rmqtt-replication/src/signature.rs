@@ -0,0 +1,100 @@
+use std::collections::{HashSet, VecDeque};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use rmqtt::RwLock;
+
+type HmacSha256 = Hmac<Sha256>;
+
+///Header of the Unix time in seconds at which a request is sent, signed with the body
+pub(crate) const TIMESTAMP_HEADER: &str = "X-Rmqtt-Timestamp";
+///Header of the HMAC-SHA256 signature of a request, in lowercase hex
+pub(crate) const SIGNATURE_HEADER: &str = "X-Rmqtt-Signature";
+
+///Signs and verifies the requests between the clusters with a shared secret, built once
+#[derive(Clone)]
+pub(crate) struct Signer {
+    mac: HmacSha256,
+}
+
+impl Signer {
+    #[inline]
+    pub fn new(secret: &str) -> Option<Self> {
+        if secret.is_empty() {
+            return None;
+        }
+        HmacSha256::new_from_slice(secret.as_bytes()).ok().map(|mac| Self { mac })
+    }
+
+    ///Signs "{timestamp}.{body}"
+    #[inline]
+    pub fn sign(&self, timestamp: &str, body: &[u8]) -> String {
+        let mut mac = self.mac.clone();
+        mac.update(timestamp.as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    ///Compares in constant time
+    #[inline]
+    pub fn verify(&self, timestamp: &str, body: &[u8], signature: &str) -> bool {
+        let expected = self.sign(timestamp, body);
+        expected.len() == signature.len()
+            && expected.bytes().zip(signature.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+}
+
+///Signatures of the requests accepted within the clock skew, a request is accepted once
+#[derive(Default)]
+pub(crate) struct Replays {
+    inner: RwLock<(HashSet<String>, VecDeque<(i64, String)>)>,
+}
+
+impl Replays {
+    ///False if the signature was seen, the signatures older than expired_before are forgotten, their
+    ///requests are refused by their timestamp
+    #[inline]
+    pub fn check(&self, signature: &str, timestamp: i64, expired_before: i64) -> bool {
+        let mut inner = self.inner.write();
+        let (seen, order) = &mut *inner;
+        while order.front().map(|(t, _)| *t < expired_before).unwrap_or_default() {
+            if let Some((_, s)) = order.pop_front() {
+                seen.remove(&s);
+            }
+        }
+        if !seen.insert(signature.to_owned()) {
+            return false;
+        }
+        order.push_back((timestamp, signature.to_owned()));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_verify() {
+        assert!(Signer::new("").is_none());
+        let signer = Signer::new("secret").unwrap();
+        let signature = signer.sign("1697000000", b"body");
+        assert_eq!(signature.len(), 64);
+        assert!(signer.verify("1697000000", b"body", &signature));
+        assert!(!signer.verify("1697000001", b"body", &signature));
+        assert!(!signer.verify("1697000000", b"body2", &signature));
+        assert!(!Signer::new("other").unwrap().verify("1697000000", b"body", &signature));
+    }
+
+    #[test]
+    fn replays() {
+        let replays = Replays::default();
+        assert!(replays.check("a", 100, 0));
+        assert!(!replays.check("a", 100, 0));
+        assert!(replays.check("b", 200, 0));
+        assert!(replays.check("a", 300, 150));
+        assert!(!replays.check("b", 300, 150));
+    }
+}
@@ -0,0 +1,208 @@
+use std::convert::TryFrom;
+use std::num::NonZeroU32;
+
+use rmqtt::bytes::Bytes;
+use rmqtt::ntex::util::ByteString;
+use rmqtt::{base64, log, MqttError, Publish, PublishProperties, QoS, Result};
+
+use crate::replicator::Replicated;
+
+///Version of the format of the batches exchanged between the clusters, a receiver refuses the
+///batches of a version it does not know. The fields added to a version are optional, so that the
+///clusters of the same version and of different releases understand each other.
+pub(crate) const WIRE_VERSION: u32 = 1;
+
+///A batch of messages, the body of a request to the replication endpoint, in JSON
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Batch {
+    pub version: u32,
+    pub messages: Vec<WireMessage>,
+}
+
+impl Batch {
+    #[inline]
+    pub fn encode(msgs: &[Replicated]) -> Result<Vec<u8>> {
+        let batch = Batch { version: WIRE_VERSION, messages: msgs.iter().map(WireMessage::from).collect() };
+        Ok(rmqtt::serde_json::to_vec(&batch)?)
+    }
+
+    ///The messages that are not valid are skipped with a warning, the others are delivered
+    #[inline]
+    pub fn decode(data: &[u8]) -> Result<Vec<Replicated>> {
+        let batch: Batch = rmqtt::serde_json::from_slice(data)?;
+        if batch.version > WIRE_VERSION {
+            return Err(MqttError::from(format!("unsupported wire version {}", batch.version)));
+        }
+        Ok(batch
+            .messages
+            .into_iter()
+            .filter_map(|m| {
+                Replicated::try_from(m).map_err(|e| log::warn!("invalid replicated message, {:?}", e)).ok()
+            })
+            .collect())
+    }
+}
+
+///A replicated message, independent of the internal types of the broker, the binary fields are
+///in base64
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct WireMessage {
+    ///cluster_id of the clusters the message has passed through, the origin first
+    pub path: Vec<String>,
+    pub client_id: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    pub topic: String,
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+    pub payload: String,
+    #[serde(default)]
+    pub user_properties: Vec<(String, String)>,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    #[serde(default)]
+    pub response_topic: Option<String>,
+    #[serde(default)]
+    pub correlation_data: Option<String>,
+    #[serde(default)]
+    pub message_expiry_interval: Option<u32>,
+    #[serde(default)]
+    pub is_utf8_payload: Option<bool>,
+    ///Time in milliseconds at which the message was published in the origin cluster
+    pub create_time: i64,
+}
+
+impl From<&Replicated> for WireMessage {
+    #[inline]
+    fn from(msg: &Replicated) -> Self {
+        let p = &msg.publish;
+        WireMessage {
+            path: msg.path.clone(),
+            client_id: msg.client_id.to_string(),
+            username: msg.username.as_ref().map(|u| u.to_string()),
+            topic: p.topic.to_string(),
+            qos: p.qos.value(),
+            retain: p.retain,
+            payload: base64::encode(&p.payload),
+            user_properties: p
+                .properties
+                .user_properties
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            content_type: p.properties.content_type.as_ref().map(|c| c.to_string()),
+            response_topic: p.properties.response_topic.as_ref().map(|t| t.to_string()),
+            correlation_data: p.properties.correlation_data.as_ref().map(base64::encode),
+            message_expiry_interval: p.properties.message_expiry_interval.map(|i| i.get()),
+            is_utf8_payload: p.properties.is_utf8_payload,
+            create_time: p.create_time,
+        }
+    }
+}
+
+impl TryFrom<WireMessage> for Replicated {
+    type Error = MqttError;
+
+    #[inline]
+    fn try_from(m: WireMessage) -> Result<Self> {
+        let qos = match m.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            qos => return Err(MqttError::from(format!("invalid qos {}", qos))),
+        };
+        if m.topic.is_empty() || m.topic.contains(&['+', '#'][..]) {
+            return Err(MqttError::from(format!("invalid topic {:?}", m.topic)));
+        }
+        let decode =
+            |data: &str| base64::decode(data).map(Bytes::from).map_err(|e| MqttError::from(e.to_string()));
+        let properties = PublishProperties {
+            topic_alias: None,
+            correlation_data: m.correlation_data.as_deref().map(decode).transpose()?,
+            message_expiry_interval: m.message_expiry_interval.and_then(NonZeroU32::new),
+            content_type: m.content_type.map(ByteString::from),
+            user_properties: m
+                .user_properties
+                .into_iter()
+                .map(|(k, v)| (ByteString::from(k), ByteString::from(v)))
+                .collect(),
+            is_utf8_payload: m.is_utf8_payload,
+            response_topic: m.response_topic.map(ByteString::from),
+            subscription_ids: None,
+        };
+        let publish = Publish {
+            dup: false,
+            retain: m.retain,
+            qos,
+            topic: ByteString::from(m.topic),
+            packet_id: None,
+            payload: decode(&m.payload)?,
+            properties,
+            create_time: m.create_time,
+            trace_context: None,
+            forward_id: None,
+        };
+        Ok(Replicated {
+            path: m.path,
+            client_id: m.client_id.into(),
+            username: m.username.map(|u| u.into()),
+            publish,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let mut publish = Publish {
+            dup: false,
+            retain: true,
+            qos: QoS::AtLeastOnce,
+            topic: ByteString::from("a/b"),
+            packet_id: None,
+            payload: Bytes::from_static(b"\x00\x01payload"),
+            properties: PublishProperties::default(),
+            create_time: 1697000000000,
+            trace_context: None,
+            forward_id: None,
+        };
+        publish.properties.user_properties.push((ByteString::from("k"), ByteString::from("v")));
+        publish.properties.correlation_data = Some(Bytes::from_static(b"\xff"));
+        let msg = Replicated {
+            path: vec!["dc1".into()],
+            client_id: "c1".into(),
+            username: Some("u1".into()),
+            publish,
+        };
+        let data = Batch::encode(&[msg]).unwrap();
+        let msgs = Batch::decode(&data).unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].path, vec!["dc1".to_string()]);
+        assert_eq!(&*msgs[0].client_id, "c1");
+        assert_eq!(msgs[0].username.as_deref(), Some("u1"));
+        assert!(msgs[0].publish.retain);
+        assert_eq!(msgs[0].publish.qos, QoS::AtLeastOnce);
+        assert_eq!(msgs[0].publish.payload, Bytes::from_static(b"\x00\x01payload"));
+        assert_eq!(msgs[0].publish.properties.user_properties.len(), 1);
+        assert_eq!(msgs[0].publish.properties.correlation_data, Some(Bytes::from_static(b"\xff")));
+        assert_eq!(msgs[0].publish.create_time, 1697000000000);
+    }
+
+    #[test]
+    fn invalid() {
+        let data = br#"{"version":2,"messages":[]}"#;
+        assert!(Batch::decode(data).is_err());
+        let data = br#"{"version":1,"messages":[
+            {"path":["dc1"],"client_id":"c1","topic":"a/#","qos":0,"payload":"","create_time":0},
+            {"path":["dc1"],"client_id":"c1","topic":"a/b","qos":3,"payload":"","create_time":0},
+            {"path":["dc1"],"client_id":"c1","topic":"a/b","qos":1,"payload":"YQ==","create_time":0,"new_field":1}
+        ]}"#;
+        let msgs = Batch::decode(data).unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].publish.payload, Bytes::from_static(b"a"));
+    }
+}
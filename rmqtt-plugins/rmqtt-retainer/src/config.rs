@@ -4,8 +4,9 @@ use serde::de::{Deserialize, Deserializer};
 
 use rmqtt::grpc::MessageType;
 use rmqtt::serde_json;
+use rmqtt::settings::schema::{ConfigSchema, Field, FieldType};
 use rmqtt::settings::{deserialize_duration, Bytesize};
-use rmqtt::Result;
+use rmqtt::{HashMap, Result};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct PluginConfig {
@@ -30,6 +31,11 @@ pub struct PluginConfig {
     // the PUBLISH message, the message expiration interval in the PUBLISH message shall prevail.
     #[serde(default = "PluginConfig::expiry_interval_default", deserialize_with = "deserialize_duration")]
     pub expiry_interval: Duration, // = "10m"
+
+    // The quotas of the retained messages of each tenant, the tenant of a topic is its mountpoint, its first
+    // tenant_levels levels.
+    #[serde(default)]
+    pub tenant_quota: TenantQuota,
 }
 
 impl PluginConfig {
@@ -57,6 +63,132 @@ impl PluginConfig {
     pub fn to_json(&self) -> Result<serde_json::Value> {
         Ok(serde_json::to_value(self)?)
     }
+
+    pub fn schema() -> ConfigSchema {
+        ConfigSchema::new()
+            .field(Field::new("message_type", FieldType::Integer).default(Self::message_type_default()))
+            .field(
+                Field::new("storage_type", FieldType::String)
+                    .default_of(Self::storage_type_default())
+                    .descr("ram, disc or disc_only"),
+            )
+            .field(
+                Field::new("max_retained_messages", FieldType::Integer)
+                    .default(Self::max_retained_messages_default())
+                    .descr("The maximum number of retained messages, 0 indicates no limit"),
+            )
+            .field(
+                Field::new("max_payload_size", FieldType::Bytesize)
+                    .default_bytesize(Self::max_payload_size_default())
+                    .descr("The maximum payload size of the retained messages"),
+            )
+            .field(
+                Field::new("expiry_interval", FieldType::Duration)
+                    .default_duration(Self::expiry_interval_default())
+                    .descr("The expiration time of the retained messages, 0 means they never expire"),
+            )
+            .field(
+                TenantQuota::schema_field("tenant_quota")
+                    .descr("The quotas of the retained messages of each tenant"),
+            )
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TenantQuota {
+    ///Number of the leading topic levels naming the tenant, 0 disables the quotas
+    #[serde(default)]
+    pub tenant_levels: usize,
+    ///Maximum number of the retained messages of a tenant, 0 means no limit
+    #[serde(default)]
+    pub max_messages: usize,
+    ///Maximum total payload size of the retained messages of a tenant, 0 means no limit
+    #[serde(default = "TenantQuota::max_bytes_default")]
+    pub max_bytes: Bytesize,
+    ///What is done with a retained message of a tenant over its quota
+    #[serde(default)]
+    pub on_exceeded: QuotaExceeded,
+    ///Limits of the tenants that differ from the defaults above, by tenant
+    #[serde(default)]
+    pub tenants: HashMap<String, TenantLimits>,
+}
+
+impl Default for TenantQuota {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            tenant_levels: 0,
+            max_messages: 0,
+            max_bytes: Self::max_bytes_default(),
+            on_exceeded: QuotaExceeded::default(),
+            tenants: HashMap::default(),
+        }
+    }
+}
+
+impl TenantQuota {
+    fn max_bytes_default() -> Bytesize {
+        Bytesize::from(0)
+    }
+
+    #[inline]
+    fn schema_field(name: &str) -> Field {
+        Field::new(name, FieldType::Object)
+            .field(Field::new("tenant_levels", FieldType::Integer).default(0))
+            .field(Field::new("max_messages", FieldType::Integer).default(0))
+            .field(Field::new("max_bytes", FieldType::Bytesize).default_bytesize(Self::max_bytes_default()))
+            .field(
+                Field::new("on_exceeded", FieldType::String)
+                    .default_of(QuotaExceeded::default())
+                    .options(vec!["reject", "evict"]),
+            )
+            .field(Field::new("tenants", FieldType::Object))
+    }
+
+    ///The tenant of a topic, None if the quotas are disabled
+    #[inline]
+    pub fn tenant(&self, topic: &str) -> Option<String> {
+        if self.tenant_levels == 0 {
+            return None;
+        }
+        Some(topic.split('/').take(self.tenant_levels).collect::<Vec<_>>().join("/"))
+    }
+
+    ///(max_messages, max_bytes) of a tenant
+    #[inline]
+    pub fn limits(&self, tenant: &str) -> (usize, usize) {
+        match self.tenants.get(tenant) {
+            Some(limits) => (
+                limits.max_messages.unwrap_or(self.max_messages),
+                limits.max_bytes.as_ref().map(|b| **b).unwrap_or(*self.max_bytes),
+            ),
+            None => (self.max_messages, *self.max_bytes),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TenantLimits {
+    #[serde(default)]
+    pub max_messages: Option<usize>,
+    #[serde(default)]
+    pub max_bytes: Option<Bytesize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaExceeded {
+    ///The message is not retained, it is still delivered to the subscribers
+    Reject,
+    ///The oldest retained messages of the tenant are removed to make room for it
+    Evict,
+}
+
+impl Default for QuotaExceeded {
+    #[inline]
+    fn default() -> Self {
+        QuotaExceeded::Reject
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -9,13 +9,15 @@ use rmqtt::grpc::MessageType;
 use rmqtt::{async_trait::async_trait, log, serde_json, tokio::sync::RwLock, tokio_cron_scheduler::Job};
 use rmqtt::{
     broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
-    grpc::{Message, MessageReply},
-    plugin::{DynPlugin, DynPluginResult, Plugin},
+    grpc::{registry::MessageTypes, Message, MessageReply},
+    plugin::{DynPlugin, DynPluginResult, Metric, Plugin},
+    settings::schema::ConfigSchema,
     Result, Runtime,
 };
 use std::sync::Arc;
 
 mod config;
+mod quota;
 mod retainer;
 
 #[inline]
@@ -54,9 +56,12 @@ impl RetainerPlugin {
         descr: D,
     ) -> Result<Self> {
         let name = name.into();
-        let cfg = runtime.settings.plugins.load_config::<PluginConfig>(&name)?;
+        let cfg = runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&name, &PluginConfig::schema())?;
         log::info!("{} RetainerPlugin cfg: {:?}", name, cfg);
-        let register = runtime.extends.hook_mgr().await.register();
+        let register = runtime.extends.hook_mgr().await.register_for(&name);
         let message_type = cfg.message_type;
         let cfg = Arc::new(RwLock::new(cfg));
         let retainer = Retainer::get_or_init(cfg.clone(), message_type);
@@ -72,6 +77,7 @@ impl Plugin for RetainerPlugin {
         log::info!("{} init", self.name);
         let cfg = &self.cfg;
         let message_type = cfg.read().await.message_type;
+        MessageTypes::instance().register(&self.name, message_type)?;
         self.register
             .add(Type::GrpcMessageReceived, Box::new(RetainHandler::new(self.retainer, cfg, message_type)))
             .await;
@@ -107,10 +113,26 @@ impl Plugin for RetainerPlugin {
         self.cfg.read().await.to_json()
     }
 
+    #[inline]
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(PluginConfig::schema())
+    }
+
     #[inline]
     async fn load_config(&mut self) -> Result<()> {
-        let new_cfg = self.runtime.settings.plugins.load_config::<PluginConfig>(&self.name)?;
-        *self.cfg.write().await = new_cfg;
+        let new_cfg = self
+            .runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&self.name, &PluginConfig::schema())?;
+        let mut cfg = self.cfg.write().await;
+        let recount = cfg.tenant_quota.tenant_levels != new_cfg.tenant_quota.tenant_levels;
+        *cfg = new_cfg;
+        drop(cfg);
+        //the messages are accounted to other tenants
+        if recount {
+            self.retainer.recount().await;
+        }
         log::debug!("load_config ok,  {:?}", self.cfg);
         Ok(())
     }
@@ -135,10 +157,71 @@ impl Plugin for RetainerPlugin {
         "0.1.0"
     }
 
+    ///The retained messages of the tenants on this node against their quotas, {"tenant": <tenant>}
+    ///to query a single tenant
+    #[inline]
+    async fn send(&self, msg: serde_json::Value) -> Result<serde_json::Value> {
+        let tenant = msg.get("tenant").and_then(|t| t.as_str());
+        let quota = self.cfg.read().await.tenant_quota.clone();
+        let usages = self
+            .retainer
+            .usages
+            .usages()
+            .into_iter()
+            .filter(|(t, ..)| tenant.map(|tenant| tenant == t).unwrap_or(true))
+            .map(|(tenant, messages, bytes, rejected, evicted)| {
+                let (max_messages, max_bytes) = quota.limits(&tenant);
+                serde_json::json!({
+                    "tenant": tenant,
+                    "messages": messages,
+                    "bytes": bytes,
+                    "max_messages": max_messages,
+                    "max_bytes": max_bytes,
+                    "rejected": rejected,
+                    "evicted": evicted,
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(serde_json::Value::Array(usages))
+    }
+
+    #[inline]
+    async fn metrics(&self) -> Vec<Metric> {
+        let mut metrics = Vec::new();
+        for (tenant, messages, bytes, rejected, evicted) in self.retainer.usages.usages() {
+            metrics.push(
+                Metric::gauge("tenant_messages", messages as f64)
+                    .label("tenant", tenant.clone())
+                    .descr("Retained messages of the tenant on this node"),
+            );
+            metrics.push(
+                Metric::gauge("tenant_bytes", bytes as f64)
+                    .label("tenant", tenant.clone())
+                    .descr("Total payload size of the retained messages of the tenant on this node"),
+            );
+            metrics.push(
+                Metric::counter("tenant_rejected", rejected as f64)
+                    .label("tenant", tenant.clone())
+                    .descr("Retained messages not stored because the tenant quota was exceeded"),
+            );
+            metrics.push(
+                Metric::counter("tenant_evicted", evicted as f64)
+                    .label("tenant", tenant)
+                    .descr("Retained messages removed to make room within the tenant quota"),
+            );
+        }
+        metrics
+    }
+
     #[inline]
     fn descr(&self) -> &str {
         &self.descr
     }
+
+    #[inline]
+    fn reloadable(&self) -> bool {
+        false
+    }
 }
 
 struct RetainHandler {
@@ -0,0 +1,119 @@
+use rmqtt::rust_box::dequemap::DequeMap;
+use rmqtt::{HashMap, RwLock, TopicName};
+
+use crate::config::{QuotaExceeded, TenantQuota};
+
+#[derive(Default)]
+struct Usage {
+    bytes: usize,
+    rejected: usize,
+    evicted: usize,
+    ///Payload size of the retained messages of the tenant, from the oldest to the latest
+    topics: DequeMap<TopicName, usize>,
+}
+
+///The retained messages of each tenant on this node, against its quota
+pub(crate) struct TenantUsages {
+    usages: RwLock<HashMap<String, Usage>>,
+}
+
+impl TenantUsages {
+    #[inline]
+    pub(crate) fn new() -> Self {
+        Self { usages: RwLock::new(HashMap::default()) }
+    }
+
+    ///Accounts a retained message about to be stored, an empty payload removes it. Returns the
+    ///topics of the tenant to be removed to make room for it, or None if it is rejected
+    #[inline]
+    pub(crate) fn acquire(
+        &self,
+        quota: &TenantQuota,
+        topic: &TopicName,
+        payload_len: usize,
+    ) -> Option<Vec<TopicName>> {
+        let tenant = match quota.tenant(topic) {
+            Some(tenant) => tenant,
+            None => return Some(Vec::new()),
+        };
+        let mut usages = self.usages.write();
+        let usage = usages.entry(tenant.clone()).or_default();
+        let old = usage.topics.remove(topic);
+        if let Some(old) = old {
+            usage.bytes -= old;
+        }
+        if payload_len == 0 {
+            return Some(Vec::new());
+        }
+
+        let (max_messages, max_bytes) = quota.limits(&tenant);
+        let exceeded = |usage: &Usage| {
+            (max_messages > 0 && usage.topics.len() >= max_messages)
+                || (max_bytes > 0 && usage.bytes + payload_len > max_bytes)
+        };
+        let mut evicteds = Vec::new();
+        if exceeded(usage) {
+            if quota.on_exceeded == QuotaExceeded::Reject || (max_bytes > 0 && payload_len > max_bytes) {
+                if let Some(old) = old {
+                    usage.bytes += old;
+                    usage.topics.insert(topic.clone(), old);
+                }
+                usage.rejected += 1;
+                return None;
+            }
+            while exceeded(usage) {
+                match usage.topics.pop_front() {
+                    Some((evicted, len)) => {
+                        usage.bytes -= len;
+                        usage.evicted += 1;
+                        evicteds.push(evicted);
+                    }
+                    None => break,
+                }
+            }
+        }
+        usage.bytes += payload_len;
+        usage.topics.insert(topic.clone(), payload_len);
+        Some(evicteds)
+    }
+
+    ///Releases a retained message that has been removed, such as on expiry
+    #[inline]
+    pub(crate) fn release(&self, quota: &TenantQuota, topic: &TopicName) {
+        if let Some(tenant) = quota.tenant(topic) {
+            if let Some(usage) = self.usages.write().get_mut(&tenant) {
+                if let Some(len) = usage.topics.remove(topic) {
+                    usage.bytes -= len;
+                }
+            }
+        }
+    }
+
+    ///(tenant, messages, bytes, rejected, evicted), sorted by tenant
+    #[inline]
+    pub(crate) fn usages(&self) -> Vec<(String, usize, usize, usize, usize)> {
+        let mut usages = self
+            .usages
+            .read()
+            .iter()
+            .map(|(tenant, u)| (tenant.clone(), u.topics.len(), u.bytes, u.rejected, u.evicted))
+            .collect::<Vec<_>>();
+        usages.sort_by(|a, b| a.0.cmp(&b.0));
+        usages
+    }
+
+    ///Accounts the stored retained messages from scratch, from the oldest to the latest, those over
+    ///a quota are kept
+    #[inline]
+    pub(crate) fn reset(&self, quota: &TenantQuota, retains: impl Iterator<Item = (TopicName, usize)>) {
+        let mut usages = HashMap::default();
+        for (topic, len) in retains {
+            if let Some(tenant) = quota.tenant(&topic) {
+                let usage: &mut Usage = usages.entry(tenant).or_default();
+                usage.bytes += len;
+                usage.topics.insert(topic, len);
+            }
+        }
+        *self.usages.write() = usages;
+    }
+}
@@ -1,3 +1,4 @@
+use crate::quota::TenantUsages;
 use crate::PluginConfig;
 use once_cell::sync::OnceCell;
 use rmqtt::{async_trait::async_trait, log, once_cell, tokio::sync::RwLock, Runtime};
@@ -16,6 +17,7 @@ pub(crate) struct Retainer {
     inner: &'static DefaultRetainStorage,
     cfg: Arc<RwLock<PluginConfig>>,
     pub message_type: MessageType,
+    pub(crate) usages: TenantUsages,
 }
 
 impl Retainer {
@@ -25,7 +27,12 @@ impl Retainer {
         message_type: MessageType,
     ) -> &'static Retainer {
         static INSTANCE: OnceCell<Retainer> = OnceCell::new();
-        INSTANCE.get_or_init(|| Self { inner: DefaultRetainStorage::instance(), cfg, message_type })
+        INSTANCE.get_or_init(|| Self {
+            inner: DefaultRetainStorage::instance(),
+            cfg,
+            message_type,
+            usages: TenantUsages::new(),
+        })
     }
 
     #[inline]
@@ -33,9 +40,30 @@ impl Retainer {
         Box::new(self.inner)
     }
 
+    ///Removes the expired messages and releases their quotas
     #[inline]
     pub(crate) async fn remove_expired_messages(&self) {
-        self.inner.remove_expired_messages().await;
+        let removeds = self.inner.remove_expired_messages().await;
+        if !removeds.is_empty() {
+            let quota = self.cfg.read().await.tenant_quota.clone();
+            for topic in removeds.iter() {
+                self.usages.release(&quota, topic);
+            }
+        }
+    }
+
+    ///Accounts the stored messages again, when the tenants are made of other levels of the topics
+    #[inline]
+    pub(crate) async fn recount(&self) {
+        let quota = self.cfg.read().await.tenant_quota.clone();
+        let mut retains = Vec::new();
+        self.inner
+            .for_each(|r| {
+                retains.push((r.publish.create_time, r.publish.topic.clone(), r.publish.payload.len()))
+            })
+            .await;
+        retains.sort_by_key(|(create_time, ..)| *create_time);
+        self.usages.reset(&quota, retains.into_iter().map(|(_, topic, len)| (topic, len)));
     }
 }
 
@@ -43,11 +71,11 @@ impl Retainer {
 impl RetainStorage for &'static Retainer {
     ///topic - concrete topic
     async fn set(&self, topic: &TopicName, retain: Retain) -> Result<()> {
-        let (max_retained_messages, max_payload_size, expiry_interval) = {
+        let (max_retained_messages, max_payload_size, expiry_interval, tenant_quota) = {
             let cfg = self.cfg.read().await;
             let expiry_interval =
                 if cfg.expiry_interval.is_zero() { None } else { Some(cfg.expiry_interval) };
-            (cfg.max_retained_messages, *cfg.max_payload_size, expiry_interval)
+            (cfg.max_retained_messages, *cfg.max_payload_size, expiry_interval, cfg.tenant_quota.clone())
         };
 
         if retain.publish.payload.len() > max_payload_size {
@@ -65,6 +93,23 @@ impl RetainStorage for &'static Retainer {
             return Ok(());
         }
 
+        match self.usages.acquire(&tenant_quota, topic, retain.publish.payload.len()) {
+            Some(evicteds) => {
+                for evicted in evicteds {
+                    log::debug!("The retained message is evicted by the tenant quota, topic: {:?}", evicted);
+                    self.inner.remove(&evicted).await?;
+                }
+            }
+            None => {
+                log::warn!(
+                    "The retained message has exceeded the quota of tenant: {:?}, topic: {:?}",
+                    tenant_quota.tenant(topic),
+                    topic
+                );
+                return Ok(());
+            }
+        }
+
         self.inner.set_with_timeout(topic, retain, expiry_interval).await
     }
 
@@ -115,4 +160,9 @@ impl RetainStorage for &'static Retainer {
     fn max(&self) -> isize {
         self.inner.max()
     }
+
+    #[inline]
+    fn bytes(&self) -> usize {
+        self.inner.bytes()
+    }
 }
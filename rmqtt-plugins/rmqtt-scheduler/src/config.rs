@@ -0,0 +1,115 @@
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use rmqtt::grpc::MessageType;
+use rmqtt::settings::deserialize_duration;
+use rmqtt::settings::schema::{ConfigSchema, Field, FieldType};
+use rmqtt::{base64, bytes::Bytes, serde_json, tokio_cron_scheduler::Job};
+use rmqtt::{MqttError, NodeId, QoS, Result, TimestampMillis};
+
+///Placeholders of the payload, replaced when the message is published
+pub const NODE_PLACEHOLDER: &str = "${node}";
+pub const TIMESTAMP_PLACEHOLDER: &str = "${timestamp}";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    #[serde(default = "PluginConfig::message_type_default")]
+    pub message_type: MessageType,
+    ///Interval of the checks of the nodes running the plugin, among which the rules are shared
+    #[serde(default = "PluginConfig::heartbeat_interval_default", deserialize_with = "deserialize_duration")]
+    pub heartbeat_interval: Duration,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+impl PluginConfig {
+    fn message_type_default() -> MessageType {
+        140
+    }
+
+    fn heartbeat_interval_default() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    #[inline]
+    pub fn validate(&self) -> Result<()> {
+        for (i, rule) in self.rules.iter().enumerate() {
+            rule.validate()?;
+            if self.rules[..i].iter().any(|r| r.name == rule.name) {
+                return Err(MqttError::from(format!("rule {} is duplicated", rule.name)));
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    pub fn schema() -> ConfigSchema {
+        ConfigSchema::new()
+            .field(Field::new("message_type", FieldType::Integer).default(Self::message_type_default()))
+            .field(
+                Field::new("heartbeat_interval", FieldType::Duration)
+                    .default_duration(Self::heartbeat_interval_default())
+                    .descr("Interval of the checks of the nodes running the plugin"),
+            )
+            .field(Field::new("rules", FieldType::Array).descr("Messages published on a cron schedule"))
+    }
+}
+
+///A message published on a cron schedule
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Rule {
+    pub name: String,
+    ///sec min hour day-of-month month day-of-week [year], in UTC, such as "0 0 8 * * *"
+    pub cron: String,
+    pub topic: String,
+    ///"${node}" and "${timestamp}", in milliseconds, are replaced when the message is published
+    #[serde(default)]
+    pub payload: String,
+    ///The payload is base64 encoded, the placeholders are not replaced
+    #[serde(default)]
+    pub payload_base64: bool,
+    #[serde(default)]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+    #[serde(default)]
+    pub user_properties: Vec<(String, String)>,
+}
+
+impl Rule {
+    #[inline]
+    pub fn validate(&self) -> Result<()> {
+        if self.name.is_empty() {
+            return Err(MqttError::from("rule name is empty"));
+        }
+        if self.topic.is_empty() || self.topic.contains(|c| c == '+' || c == '#') {
+            return Err(MqttError::from(format!("rule {}, topic is invalid, {:?}", self.name, self.topic)));
+        }
+        QoS::try_from(self.qos)
+            .map_err(|e| MqttError::from(format!("rule {}, qos is invalid, {:?}", self.name, e)))?;
+        Job::new_async(self.cron.as_str(), |_, _| Box::pin(async {}))
+            .map_err(|e| MqttError::from(format!("rule {}, cron is invalid, {:?}", self.name, e)))?;
+        if self.payload_base64 {
+            base64::decode(&self.payload)
+                .map_err(|e| MqttError::from(format!("rule {}, payload is invalid, {:?}", self.name, e)))?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn payload(&self, node_id: NodeId, timestamp: TimestampMillis) -> Bytes {
+        if self.payload_base64 {
+            Bytes::from(base64::decode(&self.payload).unwrap_or_default())
+        } else {
+            Bytes::from(
+                self.payload
+                    .replace(NODE_PLACEHOLDER, &node_id.to_string())
+                    .replace(TIMESTAMP_PLACEHOLDER, &timestamp.to_string()),
+            )
+        }
+    }
+}
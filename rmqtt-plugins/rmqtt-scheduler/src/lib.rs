@@ -0,0 +1,603 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+
+use config::{PluginConfig, Rule};
+use rmqtt::{
+    async_trait::async_trait,
+    chrono, dashmap, log, serde_json,
+    tokio::{self, task::JoinHandle},
+    tokio_cron_scheduler::Job,
+    RwLock,
+};
+use rmqtt::{
+    broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
+    fnv1a,
+    grpc::{codec, registry::MessageTypes, Message, MessageBroadcaster, MessageReply, MessageType},
+    plugin::{DynPlugin, DynPluginResult, Metric, Plugin},
+    settings::schema::ConfigSchema,
+    From, HashMap, MqttError, NodeId, Publish, PublishProperties, QoS, Result, Retain, Runtime,
+    TimestampMillis, UserName,
+};
+
+mod config;
+
+#[inline]
+pub async fn register(
+    runtime: &'static Runtime,
+    name: &'static str,
+    descr: &'static str,
+    default_startup: bool,
+    immutable: bool,
+) -> Result<()> {
+    runtime
+        .plugins
+        .register(name, default_startup, immutable, move || -> DynPluginResult {
+            Box::pin(async move {
+                SchedulerPlugin::new(runtime, name, descr).await.map(|p| -> DynPlugin { Box::new(p) })
+            })
+        })
+        .await?;
+    Ok(())
+}
+
+///Messages between the nodes running the plugin, the rules added and removed at runtime carry the
+///time of the change as their version, those of the config have version 0
+#[derive(Serialize, Deserialize, Debug)]
+enum SchedulerMessage {
+    Ping,
+    Add(Rule, TimestampMillis),
+    Remove(String, TimestampMillis),
+}
+
+///Reply to a ping, in MessageReply::Data
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Pong {
+    ///The rules of the node, with their versions
+    rules: Vec<(Rule, TimestampMillis)>,
+    ///The versions of the rules removed at runtime
+    removed: Vec<(String, TimestampMillis)>,
+    ///The owners of the rules, if the node is the coordinator
+    owners: Option<HashMap<String, NodeId>>,
+}
+
+///Parameters of Plugin::send()
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum Command {
+    List,
+    Add { rule: Rule },
+    Remove { name: String },
+}
+
+struct RuleState {
+    rule: Rule,
+    version: TimestampMillis,
+    job: Job,
+    fired: AtomicUsize,
+    last_fired_at: AtomicI64,
+}
+
+///The nodes exchange their rules at each heartbeat, the newest version of a rule wins, so that the
+///nodes that join later or missed a change catch up. The node of the lowest id among those running
+///the plugin is the coordinator, it chooses the owner of each rule among the nodes holding its newest
+///version, and the other nodes take the owners from it, so that all fire by the same view.
+struct Scheduler {
+    rules: dashmap::DashMap<String, Arc<RuleState>>,
+    ///The versions of the rules removed at runtime, so that they are not added back by the nodes
+    ///that missed the removal
+    removed: dashmap::DashMap<String, TimestampMillis>,
+    ///The nodes running the plugin, this one included, sorted
+    nodes: RwLock<Vec<NodeId>>,
+    ///The node firing each rule, as chosen by the coordinator
+    owners: RwLock<HashMap<String, NodeId>>,
+}
+
+impl Scheduler {
+    fn new() -> Self {
+        Self {
+            rules: dashmap::DashMap::default(),
+            removed: dashmap::DashMap::default(),
+            nodes: RwLock::new(vec![Runtime::instance().node.id()]),
+            owners: RwLock::new(HashMap::default()),
+        }
+    }
+
+    ///The version of the rule on this node, or of its removal
+    #[inline]
+    fn version_of(&self, name: &str) -> Option<TimestampMillis> {
+        let added = self.rules.get(name).map(|state| state.version);
+        let removed = self.removed.get(name).map(|removed| *removed.value());
+        added.max(removed)
+    }
+
+    ///Schedules the rule on this node, a rule of the same name is replaced, unless the rule or its
+    ///removal is newer than version
+    async fn add(self: &Arc<Self>, rule: Rule, version: TimestampMillis) -> Result<()> {
+        rule.validate()?;
+        if self.version_of(&rule.name) > Some(version) {
+            return Ok(());
+        }
+        let scheduler = Arc::downgrade(self);
+        let name = rule.name.clone();
+        let job = Job::new_async(rule.cron.as_str(), move |_uuid, _l| {
+            let scheduler: Weak<Scheduler> = scheduler.clone();
+            let name = name.clone();
+            Box::pin(async move {
+                if let Some(scheduler) = scheduler.upgrade() {
+                    scheduler.fire(&name).await;
+                }
+            })
+        })
+        .map_err(|e| MqttError::from(format!("rule {}, cron is invalid, {:?}", rule.name, e)))?;
+        self.unschedule(&rule.name).await;
+        self.removed.remove(&rule.name);
+        Runtime::instance()
+            .sched
+            .add(job.clone())
+            .await
+            .map_err(|e| MqttError::from(format!("rule {}, schedule error, {:?}", rule.name, e)))?;
+        let state =
+            RuleState { rule, version, job, fired: AtomicUsize::new(0), last_fired_at: AtomicI64::new(0) };
+        self.rules.insert(state.rule.name.clone(), Arc::new(state));
+        Ok(())
+    }
+
+    ///Removes the rule from this node, unless it is newer than version
+    async fn remove(&self, name: &str, version: TimestampMillis) -> bool {
+        if self.version_of(name) > Some(version) {
+            return false;
+        }
+        self.removed.insert(name.to_owned(), version);
+        self.unschedule(name).await
+    }
+
+    async fn unschedule(&self, name: &str) -> bool {
+        if let Some((_, state)) = self.rules.remove(name) {
+            if let Err(e) = Runtime::instance().sched.remove(&state.job.guid()).await {
+                log::warn!("rule {}, unschedule error, {:?}", name, e);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    ///Unschedules the rules of this node, the removals are kept
+    async fn clear(&self) {
+        let names = self.rules.iter().map(|entry| entry.key().clone()).collect::<Vec<_>>();
+        for name in names {
+            self.unschedule(&name).await;
+        }
+    }
+
+    ///The node that fires the rule, as chosen by the coordinator
+    #[inline]
+    fn owner(&self, name: &str) -> Option<NodeId> {
+        self.owners.read().get(name).copied()
+    }
+
+    #[inline]
+    fn is_coordinator(&self) -> bool {
+        self.nodes.read().first() == Some(&Runtime::instance().node.id())
+    }
+
+    #[inline]
+    fn local_rules(&self) -> Vec<(Rule, TimestampMillis)> {
+        self.rules.iter().map(|entry| (entry.value().rule.clone(), entry.value().version)).collect()
+    }
+
+    fn pong(&self) -> Pong {
+        Pong {
+            rules: self.local_rules(),
+            removed: self.removed.iter().map(|entry| (entry.key().clone(), *entry.value())).collect(),
+            owners: if self.is_coordinator() { Some(self.owners.read().clone()) } else { None },
+        }
+    }
+
+    ///Pings the other nodes running the plugin, catches up with their rules, and takes the owners
+    ///of the rules from the coordinator, or chooses them if this node is the coordinator
+    async fn refresh_nodes(self: &Arc<Self>, message_type: MessageType) {
+        let this_node_id = Runtime::instance().node.id();
+        let mut nodes = vec![this_node_id];
+        let mut pongs = Vec::new();
+        let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+        if !grpc_clients.is_empty() {
+            match codec::encode(&SchedulerMessage::Ping) {
+                Ok(msg) => {
+                    for (node_id, reply) in
+                        MessageBroadcaster::new(grpc_clients, message_type, Message::Data(msg))
+                            .join_all()
+                            .await
+                    {
+                        match reply.and_then(|reply| match reply {
+                            MessageReply::Data(data) => codec::decode::<Pong>(&data),
+                            MessageReply::Success => Ok(Pong::default()),
+                            reply => Err(MqttError::from(format!("unexpected reply, {:?}", reply))),
+                        }) {
+                            Ok(pong) => {
+                                nodes.push(node_id);
+                                pongs.push((node_id, pong));
+                            }
+                            Err(e) => log::debug!("ping node {}, error: {:?}", node_id, e),
+                        }
+                    }
+                }
+                Err(e) => log::warn!("encode ping error, {:?}", e),
+            }
+        }
+
+        for (node_id, pong) in pongs.iter() {
+            for (rule, version) in pong.rules.iter() {
+                if self.version_of(&rule.name) < Some(*version) {
+                    log::info!("rule {} version {} is taken from node {}", rule.name, version, node_id);
+                    if let Err(e) = self.add(rule.clone(), *version).await {
+                        log::warn!("rule {} of node {}, {:?}", rule.name, node_id, e);
+                    }
+                }
+            }
+            for (name, version) in pong.removed.iter() {
+                if self.version_of(name) < Some(*version) {
+                    log::info!("rule {} is removed, as on node {}", name, node_id);
+                    self.remove(name, *version).await;
+                }
+            }
+        }
+
+        nodes.sort_unstable();
+        let coordinator = nodes[0];
+        let owners = if coordinator == this_node_id {
+            let mut holders = vec![(this_node_id, self.local_rules())];
+            holders.extend(pongs.into_iter().map(|(node_id, pong)| (node_id, pong.rules)));
+            choose_owners(holders)
+        } else {
+            pongs
+                .into_iter()
+                .find(|(node_id, _)| *node_id == coordinator)
+                .and_then(|(_, pong)| pong.owners)
+                .unwrap_or_default()
+        };
+        *self.nodes.write() = nodes;
+        *self.owners.write() = owners;
+    }
+
+    ///Publishes the message of the rule if this node is its owner
+    async fn fire(&self, name: &str) {
+        let state = match self.rules.get(name) {
+            Some(state) => state.value().clone(),
+            None => return,
+        };
+        let node_id = Runtime::instance().node.id();
+        if self.owner(name) != Some(node_id) {
+            return;
+        }
+        let rule = &state.rule;
+        let now = chrono::Local::now().timestamp_millis();
+        let mut properties = PublishProperties::default();
+        properties.user_properties =
+            rule.user_properties.iter().map(|(k, v)| (k.as_str().into(), v.as_str().into())).collect();
+        let publish = Publish {
+            dup: false,
+            retain: rule.retain,
+            qos: QoS::try_from(rule.qos).unwrap_or(QoS::AtMostOnce),
+            topic: rule.topic.as_str().into(),
+            packet_id: None,
+            payload: rule.payload(node_id, now),
+            properties,
+            create_time: now,
+            trace_context: None,
+            forward_id: None,
+        };
+        let from = From::new(node_id, None, None, "$scheduler".into(), Some(UserName::from("system")));
+        state.fired.fetch_add(1, Ordering::SeqCst);
+        state.last_fired_at.store(now, Ordering::SeqCst);
+        log::debug!("rule {} fired, topic: {}", rule.name, rule.topic);
+
+        if publish.retain {
+            let retain = Retain { from: from.clone(), publish: publish.clone() };
+            if let Err(e) = Runtime::instance().extends.retain().await.set(&publish.topic, retain).await {
+                log::warn!("rule {}, retain error, {:?}", rule.name, e);
+            }
+        }
+        Runtime::instance().metrics.messages_publish_inc();
+        if let Err(droppeds) = Runtime::instance().extends.shared().await.forwards(from, publish).await {
+            for (to, from, p, reason) in droppeds {
+                Runtime::instance().extends.hook_mgr().await.message_dropped(Some(to), from, p, reason).await;
+            }
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut rules = self
+            .rules
+            .iter()
+            .map(|entry| {
+                let state = entry.value();
+                serde_json::json!({
+                    "rule": state.rule,
+                    "version": state.version,
+                    "owner": self.owner(&state.rule.name),
+                    "fired": state.fired.load(Ordering::SeqCst),
+                    "last_fired_at": state.last_fired_at.load(Ordering::SeqCst),
+                })
+            })
+            .collect::<Vec<_>>();
+        rules.sort_by(|a, b| a["rule"]["name"].as_str().cmp(&b["rule"]["name"].as_str()));
+        serde_json::json!({
+            "nodes": *self.nodes.read(),
+            "rules": rules,
+        })
+    }
+}
+
+///Chooses the owner of each rule among the nodes holding its newest version, those of the same
+///version and rule, the rules are spread over them by the hash of the rule name and the node id
+fn choose_owners(holders: Vec<(NodeId, Vec<(Rule, TimestampMillis)>)>) -> HashMap<String, NodeId> {
+    let mut newest: HashMap<String, (Rule, TimestampMillis, NodeId)> = HashMap::default();
+    for (node_id, rules) in holders.iter() {
+        for (rule, version) in rules.iter() {
+            match newest.get(&rule.name) {
+                Some((_, v, n)) if (*v, std::cmp::Reverse(*n)) >= (*version, std::cmp::Reverse(*node_id)) => {
+                }
+                _ => {
+                    newest.insert(rule.name.clone(), (rule.clone(), *version, *node_id));
+                }
+            }
+        }
+    }
+    newest
+        .into_iter()
+        .filter_map(|(name, (rule, version, _))| {
+            holders
+                .iter()
+                .filter(|(_, rules)| rules.iter().any(|(r, v)| *v == version && *r == rule))
+                .map(|(node_id, _)| *node_id)
+                .max_by_key(|node_id| fnv1a(name.bytes().chain(node_id.to_be_bytes())))
+                .map(|node_id| (name, node_id))
+        })
+        .collect()
+}
+
+type PluginConfigType = Arc<RwLock<PluginConfig>>;
+
+struct SchedulerPlugin {
+    runtime: &'static Runtime,
+    name: String,
+    descr: String,
+    register: Box<dyn Register>,
+    cfg: PluginConfigType,
+    scheduler: Arc<Scheduler>,
+    heartbeat: Option<JoinHandle<()>>,
+    message_type: Option<MessageType>,
+}
+
+impl SchedulerPlugin {
+    #[inline]
+    async fn new<N: Into<String>, D: Into<String>>(
+        runtime: &'static Runtime,
+        name: N,
+        descr: D,
+    ) -> Result<Self> {
+        let name = name.into();
+        let cfg = runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&name, &PluginConfig::schema())?;
+        cfg.validate()?;
+        log::info!("{} SchedulerPlugin cfg: {:?}", name, cfg);
+        let register = runtime.extends.hook_mgr().await.register_for(&name);
+        Ok(Self {
+            runtime,
+            name,
+            descr: descr.into(),
+            register,
+            cfg: Arc::new(RwLock::new(cfg)),
+            scheduler: Arc::new(Scheduler::new()),
+            heartbeat: None,
+            message_type: None,
+        })
+    }
+
+    ///Schedules the rules of the config, replacing all others of this node
+    async fn schedule(&self) -> Result<()> {
+        self.scheduler.clear().await;
+        self.scheduler.removed.clear();
+        let rules = self.cfg.read().rules.clone();
+        for rule in rules {
+            self.scheduler.add(rule, 0).await?;
+        }
+        Ok(())
+    }
+
+    ///Sends the message to the other nodes running the plugin
+    async fn broadcast(&self, msg: SchedulerMessage) -> Result<serde_json::Value> {
+        let mut replies = serde_json::Map::new();
+        let grpc_clients = self.runtime.extends.shared().await.get_grpc_clients();
+        if !grpc_clients.is_empty() {
+            let message_type = self.cfg.read().message_type;
+            let msg = Message::Data(codec::encode(&msg)?);
+            for (node_id, reply) in MessageBroadcaster::new(grpc_clients, message_type, msg).join_all().await
+            {
+                let reply = match reply {
+                    Ok(MessageReply::Success) => serde_json::Value::Bool(true),
+                    Ok(MessageReply::Error(e)) => serde_json::Value::String(e),
+                    Ok(reply) => serde_json::Value::String(format!("unexpected reply, {:?}", reply)),
+                    Err(e) => serde_json::Value::String(e.to_string()),
+                };
+                replies.insert(node_id.to_string(), reply);
+            }
+        }
+        Ok(serde_json::Value::Object(replies))
+    }
+}
+
+#[async_trait]
+impl Plugin for SchedulerPlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name);
+        self.register
+            .add(
+                Type::GrpcMessageReceived,
+                Box::new(SchedulerHandler { cfg: self.cfg.clone(), scheduler: self.scheduler.clone() }),
+            )
+            .await;
+        Ok(())
+    }
+
+    #[inline]
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        self.cfg.read().to_json()
+    }
+
+    #[inline]
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(PluginConfig::schema())
+    }
+
+    ///The rules added at runtime are replaced by those of the config
+    #[inline]
+    async fn load_config(&mut self) -> Result<()> {
+        let new_cfg = self
+            .runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&self.name, &PluginConfig::schema())?;
+        new_cfg.validate()?;
+        *self.cfg.write() = new_cfg;
+        if self.heartbeat.is_some() {
+            self.schedule().await?;
+        }
+        log::debug!("load_config ok,  {:?}", self.cfg);
+        Ok(())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name);
+        let (message_type, heartbeat_interval) = {
+            let cfg = self.cfg.read();
+            (cfg.message_type, cfg.heartbeat_interval)
+        };
+        if self.message_type.is_none() {
+            MessageTypes::instance().register(&self.name, message_type)?;
+            self.message_type.replace(message_type);
+        }
+        self.register.start().await;
+        self.schedule().await?;
+        self.scheduler.refresh_nodes(message_type).await;
+        let scheduler = self.scheduler.clone();
+        self.heartbeat = Some(tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(heartbeat_interval).await;
+                scheduler.refresh_nodes(message_type).await;
+            }
+        }));
+        Ok(())
+    }
+
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::info!("{} stop", self.name);
+        self.register.stop().await;
+        if let Some(message_type) = self.message_type.take() {
+            MessageTypes::instance().unregister(&self.name, message_type);
+        }
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.abort();
+        }
+        self.scheduler.clear().await;
+        Ok(true)
+    }
+
+    #[inline]
+    fn version(&self) -> &str {
+        "0.1.0"
+    }
+
+    #[inline]
+    fn descr(&self) -> &str {
+        &self.descr
+    }
+
+    ///{"action": "list"}, the rules of this node and the node that fires each of them.
+    ///{"action": "add", "rule": {...}} and {"action": "remove", "name": "..."}, on all nodes
+    ///running the plugin, the nodes that join later take them at the next heartbeat
+    #[inline]
+    async fn send(&self, msg: serde_json::Value) -> Result<serde_json::Value> {
+        match serde_json::from_value::<Command>(msg)? {
+            Command::List => Ok(self.scheduler.to_json()),
+            Command::Add { rule } => {
+                let version = chrono::Local::now().timestamp_millis();
+                self.scheduler.add(rule.clone(), version).await?;
+                let nodes = self.broadcast(SchedulerMessage::Add(rule, version)).await?;
+                Ok(serde_json::json!({ "added": true, "nodes": nodes }))
+            }
+            Command::Remove { name } => {
+                let version = chrono::Local::now().timestamp_millis();
+                let removed = self.scheduler.remove(&name, version).await;
+                let nodes = self.broadcast(SchedulerMessage::Remove(name, version)).await?;
+                Ok(serde_json::json!({ "removed": removed, "nodes": nodes }))
+            }
+        }
+    }
+
+    #[inline]
+    async fn metrics(&self) -> Vec<Metric> {
+        let mut metrics = self
+            .scheduler
+            .rules
+            .iter()
+            .map(|entry| {
+                Metric::counter("fired", entry.value().fired.load(Ordering::SeqCst) as f64)
+                    .label("rule", entry.key().clone())
+                    .descr("Messages of the rule published by this node")
+            })
+            .collect::<Vec<_>>();
+        metrics.push(
+            Metric::gauge("rules", self.scheduler.rules.len() as f64).descr("Rules scheduled on this node"),
+        );
+        metrics.push(
+            Metric::gauge("nodes", self.scheduler.nodes.read().len() as f64)
+                .descr("Nodes running the plugin among which the rules are shared"),
+        );
+        metrics
+    }
+}
+
+struct SchedulerHandler {
+    cfg: PluginConfigType,
+    scheduler: Arc<Scheduler>,
+}
+
+#[async_trait]
+impl Handler for SchedulerHandler {
+    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+        if let Parameter::GrpcMessageReceived(typ, Message::Data(data)) = param {
+            if self.cfg.read().message_type != *typ {
+                return (true, acc);
+            }
+            let reply = match codec::decode::<SchedulerMessage>(data) {
+                Ok(SchedulerMessage::Ping) => codec::encode(&self.scheduler.pong()).map(MessageReply::Data),
+                Ok(SchedulerMessage::Add(rule, version)) => {
+                    self.scheduler.add(rule, version).await.map(|_| MessageReply::Success)
+                }
+                Ok(SchedulerMessage::Remove(name, version)) => {
+                    self.scheduler.remove(&name, version).await;
+                    Ok(MessageReply::Success)
+                }
+                Err(e) => Err(e),
+            };
+            return (false, Some(HookResult::GrpcMessageReply(reply)));
+        }
+        (true, acc)
+    }
+}
@@ -0,0 +1,4 @@
+fn main() {
+    let out = std::env::var("OUT_DIR").unwrap();
+    tonic_build::configure().out_dir(out).compile(&["sidecar.proto"], &["proto"]).unwrap();
+}
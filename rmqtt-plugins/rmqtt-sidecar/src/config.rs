@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use rmqtt::serde_json;
+use rmqtt::settings::deserialize_duration;
+use rmqtt::settings::schema::{ConfigSchema, Field, FieldType};
+use rmqtt::Result;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    //Out-of-process plugins, each one serves the rmqtt.sidecar.v1.Sidecar gRPC service
+    #[serde(default)]
+    pub sidecars: Vec<SidecarConfig>,
+
+    //Timeout of a call to a sidecar, a decision hook falls through to the other handlers on timeout
+    #[serde(default = "PluginConfig::timeout_default", deserialize_with = "deserialize_duration")]
+    pub timeout: Duration,
+
+    //Interval of the health check of a connected sidecar and of the reconnection attempts
+    #[serde(default = "PluginConfig::check_interval_default", deserialize_with = "deserialize_duration")]
+    pub check_interval: Duration,
+
+    //Capacity of the queue of the notifications of a sidecar, a notification is dropped when it is full
+    #[serde(default = "PluginConfig::notify_queue_capacity_default")]
+    pub notify_queue_capacity: usize,
+
+    //Maximum number of the notifications of a sidecar sent concurrently
+    #[serde(default = "PluginConfig::notify_concurrency_default")]
+    pub notify_concurrency: usize,
+}
+
+impl PluginConfig {
+    fn timeout_default() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn check_interval_default() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn notify_queue_capacity_default() -> usize {
+        10_000
+    }
+
+    fn notify_concurrency_default() -> usize {
+        16
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    pub fn schema() -> ConfigSchema {
+        ConfigSchema::new()
+            .field(
+                Field::new("sidecars", FieldType::Array)
+                    .descr("Out-of-process plugins serving the rmqtt.sidecar.v1.Sidecar gRPC service"),
+            )
+            .field(
+                Field::new("timeout", FieldType::Duration)
+                    .default_duration(Self::timeout_default())
+                    .descr("Timeout of a call to a sidecar"),
+            )
+            .field(
+                Field::new("check_interval", FieldType::Duration)
+                    .default_duration(Self::check_interval_default())
+                    .descr("Interval of the health checks and of the reconnection attempts"),
+            )
+            .field(
+                Field::new("notify_queue_capacity", FieldType::Integer)
+                    .default(Self::notify_queue_capacity_default())
+                    .descr("Capacity of the queue of the notifications of a sidecar"),
+            )
+            .field(
+                Field::new("notify_concurrency", FieldType::Integer)
+                    .default(Self::notify_concurrency_default())
+                    .descr("Maximum number of the notifications of a sidecar sent concurrently"),
+            )
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SidecarConfig {
+    pub name: String,
+    //Such as "http://127.0.0.1:50051"
+    pub addr: String,
+}
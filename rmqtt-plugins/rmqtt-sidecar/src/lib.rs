@@ -0,0 +1,184 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+use std::sync::Arc;
+
+use config::PluginConfig;
+use rmqtt::{async_trait::async_trait, log, serde_json, tokio, tokio::sync::RwLock};
+use rmqtt::{
+    plugin::{DynPlugin, DynPluginResult, Plugin},
+    settings::schema::ConfigSchema,
+    Result, Runtime,
+};
+use sidecar::RetainerSlot;
+use tokio::task::JoinHandle;
+
+mod config;
+mod sidecar;
+
+#[allow(clippy::all)]
+pub mod pb {
+    tonic::include_proto!("rmqtt.sidecar.v1");
+}
+
+#[inline]
+pub async fn register(
+    runtime: &'static Runtime,
+    name: &'static str,
+    descr: &'static str,
+    default_startup: bool,
+    immutable: bool,
+) -> Result<()> {
+    runtime
+        .plugins
+        .register(name, default_startup, immutable, move || -> DynPluginResult {
+            Box::pin(async move {
+                SidecarPlugin::new(runtime, name, descr).await.map(|p| -> DynPlugin { Box::new(p) })
+            })
+        })
+        .await?;
+    Ok(())
+}
+
+struct SidecarPlugin {
+    runtime: &'static Runtime,
+    name: String,
+    descr: String,
+    cfg: Arc<RwLock<PluginConfig>>,
+    sidecars: Vec<JoinHandle<()>>,
+    retainer: Arc<RetainerSlot>,
+}
+
+impl SidecarPlugin {
+    #[inline]
+    async fn new<N: Into<String>, D: Into<String>>(
+        runtime: &'static Runtime,
+        name: N,
+        descr: D,
+    ) -> Result<Self> {
+        let name = name.into();
+        let cfg = runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&name, &PluginConfig::schema())?;
+        log::info!("{} SidecarPlugin cfg: {:?}", name, cfg);
+        Ok(Self {
+            runtime,
+            name,
+            descr: descr.into(),
+            cfg: Arc::new(RwLock::new(cfg)),
+            sidecars: Vec::new(),
+            retainer: Arc::new(RetainerSlot::default()),
+        })
+    }
+
+    #[inline]
+    async fn start_sidecars(&mut self) {
+        let sidecars = self.cfg.read().await.sidecars.clone();
+        for sidecar in sidecars {
+            self.sidecars.push(tokio::spawn(sidecar::run(
+                self.runtime,
+                self.name.clone(),
+                sidecar,
+                self.cfg.clone(),
+                self.retainer.clone(),
+            )));
+        }
+    }
+
+    ///Aborting a connection drops its register, which removes the hooks of the sidecar
+    #[inline]
+    async fn stop_sidecars(&mut self) {
+        for sidecar in self.sidecars.drain(..) {
+            sidecar.abort();
+        }
+        if self.retainer.restore(self.runtime).await {
+            log::warn!("{} the retained messages fall back to the storage the sidecar replaced", self.name);
+        }
+    }
+}
+
+#[async_trait]
+impl Plugin for SidecarPlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name);
+        Ok(())
+    }
+
+    #[inline]
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        self.cfg.read().await.to_json()
+    }
+
+    #[inline]
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(PluginConfig::schema())
+    }
+
+    ///The sidecars are reconnected with the new config
+    #[inline]
+    async fn load_config(&mut self) -> Result<()> {
+        let new_cfg = self
+            .runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&self.name, &PluginConfig::schema())?;
+        *self.cfg.write().await = new_cfg;
+        log::debug!("load_config ok,  {:?}", self.cfg);
+        if !self.sidecars.is_empty() {
+            self.stop_sidecars().await;
+            self.start_sidecars().await;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name);
+        if self.sidecars.is_empty() {
+            self.start_sidecars().await;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::info!("{} stop", self.name);
+        self.stop_sidecars().await;
+        Ok(true)
+    }
+
+    #[inline]
+    fn version(&self) -> &str {
+        "0.1.0"
+    }
+
+    #[inline]
+    fn descr(&self) -> &str {
+        &self.descr
+    }
+
+    ///Stopping restores the retained message storage a sidecar replaced, which would be the storage
+    ///of the old instance for a new instance started alongside, load_config reconnects the sidecars
+    ///instead
+    #[inline]
+    fn reloadable(&self) -> bool {
+        false
+    }
+
+    #[inline]
+    async fn attrs(&self) -> serde_json::Value {
+        serde_json::json!({
+            "protocol_version": sidecar::PROTOCOL_VERSION,
+            "sidecars": self.sidecars.len(),
+            "retainer": self.retainer.is_installed().await,
+        })
+    }
+}
@@ -0,0 +1,466 @@
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tonic::transport::{Channel, Endpoint};
+
+use rmqtt::futures::{self, StreamExt};
+use rmqtt::{
+    async_trait::async_trait,
+    base64, log,
+    serde_json::{self, json},
+    tokio::{
+        self,
+        sync::{mpsc, Mutex, RwLock},
+    },
+};
+use rmqtt::{
+    broker::hook::{Handler, HookResult, Parameter, ReturnType, Type},
+    broker::types::{AuthResult, Publish, PublishAclResult, QoSEx, SubscribeAckReason, SubscribeAclResult},
+    broker::RetainStorage,
+    grpc::codec,
+    MqttError, QoS, Result, Retain, Runtime, TopicFilter, TopicName,
+};
+
+use crate::config::{PluginConfig, SidecarConfig};
+use crate::pb::{self, sidecar_client::SidecarClient};
+
+///Version of the sidecar protocol, increased on an incompatible change
+pub const PROTOCOL_VERSION: u32 = 1;
+
+type Client = SidecarClient<Channel>;
+
+///Keep a sidecar connected, register its hooks while it is healthy, the hooks and the retained
+///message storage are removed when it becomes unreachable and installed again after it reconnects.
+pub(crate) async fn run(
+    runtime: &'static Runtime,
+    owner: String,
+    sidecar: SidecarConfig,
+    cfg: Arc<RwLock<PluginConfig>>,
+    retainer: Arc<RetainerSlot>,
+) {
+    loop {
+        let (timeout, check_interval, notify_queue_capacity, notify_concurrency) = {
+            let cfg = cfg.read().await;
+            (cfg.timeout, cfg.check_interval, cfg.notify_queue_capacity, cfg.notify_concurrency)
+        };
+        match connect(&sidecar.addr, timeout).await {
+            Ok(mut client) => match describe(runtime, &mut client).await {
+                Ok(desc) => {
+                    log::info!(
+                        "{} sidecar {}({}) connected, hooks: {:?}, retainer: {}",
+                        owner,
+                        sidecar.name,
+                        desc.name,
+                        desc.hooks,
+                        desc.retainer
+                    );
+                    let register =
+                        runtime.extends.hook_mgr().await.register_for(&format!("{}/{}", owner, sidecar.name));
+                    let (notify_tx, notify_rx) = mpsc::channel(notify_queue_capacity.max(1));
+                    for h in desc.hooks.iter() {
+                        if let Some(typ) = hook_type(&h.hook) {
+                            let handler = SidecarHandler {
+                                name: sidecar.name.clone(),
+                                client: client.clone(),
+                                notify_tx: notify_tx.clone(),
+                            };
+                            register.add_priority(typ, h.priority, Box::new(handler)).await;
+                        } else {
+                            log::warn!("{} sidecar {} unsupported hook: {}", owner, sidecar.name, h.hook);
+                        }
+                    }
+                    drop(notify_tx);
+                    register.start().await;
+                    let retainer_installed = desc.retainer
+                        && retainer
+                            .install(runtime, SidecarRetainer::new(client.clone(), desc.retain_max))
+                            .await;
+                    if desc.retainer && !retainer_installed {
+                        log::warn!(
+                            "{} sidecar {} retainer is ignored, another sidecar stores the retained messages",
+                            owner,
+                            sidecar.name
+                        );
+                    }
+
+                    let notifier = notify(&sidecar.name, client.clone(), notify_rx, notify_concurrency);
+                    tokio::select! {
+                        _ = notifier => {}
+                        e = check(runtime, &mut client, check_interval) => {
+                            log::warn!("{} sidecar {} is unreachable, {:?}", owner, sidecar.name, e);
+                        }
+                    }
+                    register.stop().await;
+                    if retainer_installed && retainer.restore(runtime).await {
+                        log::warn!(
+                            "{} sidecar {} is unreachable, the previous retained storage is used again",
+                            owner,
+                            sidecar.name
+                        );
+                    }
+                }
+                Err(e) => log::warn!("{} sidecar {} describe failed, {:?}", owner, sidecar.name, e),
+            },
+            Err(e) => log::warn!("{} sidecar {} connect failed, {:?}", owner, sidecar.name, e),
+        }
+        tokio::time::sleep(check_interval).await;
+    }
+}
+
+///Describes the sidecar every check_interval, returns the error once it fails
+async fn check(runtime: &'static Runtime, client: &mut Client, check_interval: Duration) -> MqttError {
+    loop {
+        tokio::time::sleep(check_interval).await;
+        if let Err(e) = describe(runtime, client).await {
+            return e;
+        }
+    }
+}
+
+///Sends the queued notifications, at most concurrency at a time
+async fn notify(name: &str, client: Client, rx: mpsc::Receiver<pb::HookRequest>, concurrency: usize) {
+    futures::stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|req| (req, rx)) })
+        .for_each_concurrent(concurrency.max(1), |req| {
+            let mut client = client.clone();
+            async move {
+                let hook = req.hook.clone();
+                if let Err(e) = client.on_hook(req).await {
+                    log::warn!("sidecar {} {} failed, {:?}", name, hook, e);
+                }
+            }
+        })
+        .await
+}
+
+#[inline]
+async fn connect(addr: &str, timeout: Duration) -> Result<Client> {
+    let channel = Endpoint::from_shared(addr.to_owned())
+        .map_err(|e| MqttError::from(e.to_string()))?
+        .connect_timeout(timeout)
+        .timeout(timeout)
+        .connect()
+        .await
+        .map_err(|e| MqttError::from(e.to_string()))?;
+    Ok(SidecarClient::new(channel))
+}
+
+#[inline]
+async fn describe(runtime: &'static Runtime, client: &mut Client) -> Result<pb::DescribeReply> {
+    let req = pb::DescribeRequest {
+        protocol_version: PROTOCOL_VERSION,
+        node_id: runtime.node.id(),
+        broker_version: runtime.node.broker_info().await.version,
+    };
+    let reply = client.describe(req).await.map_err(|e| MqttError::from(e.to_string()))?.into_inner();
+    if reply.protocol_version != PROTOCOL_VERSION {
+        return Err(MqttError::from(format!(
+            "unsupported sidecar protocol version: {}, expected: {}",
+            reply.protocol_version, PROTOCOL_VERSION
+        )));
+    }
+    Ok(reply)
+}
+
+#[inline]
+fn hook_type(name: &str) -> Option<Type> {
+    let typ = match name {
+        "session_created" => Type::SessionCreated,
+        "session_terminated" => Type::SessionTerminated,
+        "session_subscribed" => Type::SessionSubscribed,
+        "session_unsubscribed" => Type::SessionUnsubscribed,
+        "client_authenticate" => Type::ClientAuthenticate,
+        "client_connected" => Type::ClientConnected,
+        "client_disconnected" => Type::ClientDisconnected,
+        "client_subscribe_check_acl" => Type::ClientSubscribeCheckAcl,
+        "subscribe_authorized" => Type::SubscribeAuthorized,
+        "message_publish_check_acl" => Type::MessagePublishCheckAcl,
+        "message_publish" => Type::MessagePublish,
+        "message_delivered" => Type::MessageDelivered,
+        "message_acked" => Type::MessageAcked,
+        "message_dropped" => Type::MessageDropped,
+        "message_offline" => Type::MessageOffline,
+        _ => return None,
+    };
+    Some(typ)
+}
+
+struct SidecarHandler {
+    name: String,
+    client: Client,
+    notify_tx: mpsc::Sender<pb::HookRequest>,
+}
+
+#[async_trait]
+impl Handler for SidecarHandler {
+    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+        //A denial of a previous handler is final
+        let denied = match &acc {
+            Some(HookResult::AuthResult(AuthResult::BadUsernameOrPassword))
+            | Some(HookResult::AuthResult(AuthResult::NotAuthorized)) => true,
+            Some(HookResult::SubscribeAclResult(r)) => r.failure(),
+            Some(HookResult::PublishAclResult(PublishAclResult::Rejected(_))) => true,
+            _ => false,
+        };
+        if denied {
+            return (false, acc);
+        }
+
+        let (hook, body, decision) = match hook_body(param) {
+            Some(b) => b,
+            None => return (true, acc),
+        };
+        let req = pb::HookRequest { hook: hook.into(), body: body.to_string() };
+
+        //Notifications do not wait for the sidecar, they are queued
+        if !decision {
+            if let Err(e) = self.notify_tx.try_send(req) {
+                log::warn!("sidecar {} {} is dropped, {}", self.name, hook, e);
+            }
+            return (true, acc);
+        }
+
+        let reply = match self.client.clone().on_hook(req).await {
+            Ok(reply) => reply.into_inner(),
+            Err(e) => {
+                log::warn!("sidecar {} {} failed, {:?}", self.name, hook, e);
+                return (true, acc);
+            }
+        };
+        match reply.result.and_then(|r| to_hook_result(param, r)) {
+            Some(r) => (reply.proceed, Some(r)),
+            None => (reply.proceed, acc),
+        }
+    }
+}
+
+#[inline]
+fn to_hook_result(param: &Parameter, result: pb::hook_reply::Result) -> Option<HookResult> {
+    use pb::auth_result::Kind;
+    use pb::hook_reply::Result as R;
+    match (param, result) {
+        (Parameter::ClientAuthenticate(_), R::Auth(auth)) => {
+            let r = match Kind::from_i32(auth.kind)? {
+                Kind::Allow => AuthResult::Allow(auth.superuser),
+                Kind::BadUsernameOrPassword => AuthResult::BadUsernameOrPassword,
+                Kind::NotAuthorized => AuthResult::NotAuthorized,
+            };
+            Some(HookResult::AuthResult(r))
+        }
+        (Parameter::ClientSubscribeCheckAcl(_, _, sub), R::SubscribeAcl(acl)) => {
+            let r = if acl.allow {
+                let qos = QoS::try_from(acl.qos as u8).unwrap_or(sub.qos);
+                SubscribeAclResult::new_success(sub.qos.less_value(qos))
+            } else {
+                SubscribeAclResult::new_failure(SubscribeAckReason::NotAuthorized)
+            };
+            Some(HookResult::SubscribeAclResult(r))
+        }
+        (Parameter::MessagePublishCheckAcl(_, _, _), R::PublishAcl(acl)) => {
+            let r =
+                if acl.allow { PublishAclResult::Allow } else { PublishAclResult::Rejected(acl.disconnect) };
+            Some(HookResult::PublishAclResult(r))
+        }
+        _ => None,
+    }
+}
+
+#[inline]
+fn message_json(p: &Publish) -> serde_json::Value {
+    json!({
+        "dup": p.dup(),
+        "retain": p.retain(),
+        "qos": p.qos().value(),
+        "topic": p.topic(),
+        "packet_id": p.packet_id(),
+        "payload": base64::encode(p.payload()),
+        "create_time": p.create_time(),
+    })
+}
+
+///Hook name, JSON body and whether the hook waits for a decision of the sidecar
+#[inline]
+fn hook_body(param: &Parameter) -> Option<(&'static str, serde_json::Value, bool)> {
+    let body = match param {
+        Parameter::ClientAuthenticate(connect_info) => {
+            let mut body = connect_info.to_json();
+            if let Some(obj) = body.as_object_mut() {
+                let password = connect_info.password().map(|p| String::from_utf8_lossy(p).to_string());
+                obj.insert("password".into(), json!(password));
+            }
+            ("client_authenticate", body, true)
+        }
+        Parameter::ClientSubscribeCheckAcl(_, c, sub) => (
+            "client_subscribe_check_acl",
+            json!({"client": c.id.to_json(), "topic": sub.topic_filter, "qos": sub.qos.value()}),
+            true,
+        ),
+        Parameter::MessagePublishCheckAcl(_, c, p) => {
+            ("message_publish_check_acl", json!({"client": c.id.to_json(), "message": message_json(p)}), true)
+        }
+
+        Parameter::SessionCreated(_, c) => ("session_created", json!({"client": c.id.to_json()}), false),
+        Parameter::SessionTerminated(_, c, reason) => {
+            ("session_terminated", json!({"client": c.id.to_json(), "reason": reason}), false)
+        }
+        Parameter::SessionSubscribed(_, c, sub) => (
+            "session_subscribed",
+            json!({"client": c.id.to_json(), "topic": sub.topic_filter, "qos": sub.qos.value()}),
+            false,
+        ),
+        Parameter::SessionUnsubscribed(_, c, unsub) => {
+            ("session_unsubscribed", json!({"client": c.id.to_json(), "topic": unsub.topic_filter}), false)
+        }
+        Parameter::ClientConnected(_, c) => ("client_connected", c.connect_info.to_json(), false),
+        Parameter::ClientDisconnected(_, c, reason) => {
+            let body =
+                json!({"client": c.id.to_json(), "reason": reason, "reason_kind": c.disconnected_kind()});
+            ("client_disconnected", body, false)
+        }
+        Parameter::SubscribeAuthorized(_, c, sub) => (
+            "subscribe_authorized",
+            json!({"client": c.id.to_json(), "topic": sub.topic_filter, "qos": sub.qos.value()}),
+            false,
+        ),
+        Parameter::MessagePublish(_, c, p) => {
+            ("message_publish", json!({"client": c.id.to_json(), "message": message_json(p)}), false)
+        }
+        Parameter::MessageDelivered(_, c, from, p) => (
+            "message_delivered",
+            json!({"client": c.id.to_json(), "from": from.to_json(), "message": message_json(p)}),
+            false,
+        ),
+        Parameter::MessageAcked(_, c, from, p) => (
+            "message_acked",
+            json!({"client": c.id.to_json(), "from": from.to_json(), "message": message_json(p)}),
+            false,
+        ),
+        Parameter::MessageDropped(to, from, p, reason) => (
+            "message_dropped",
+            json!({
+                "to": to.as_ref().map(|to| to.to_json()),
+                "from": from.to_json(),
+                "message": message_json(p),
+                "reason": reason
+            }),
+            false,
+        ),
+        Parameter::MessageOffline(_, c, from, p) => (
+            "message_offline",
+            json!({"client": c.id.to_json(), "from": from.to_json(), "message": message_json(p)}),
+            false,
+        ),
+        _ => return None,
+    };
+    Some(body)
+}
+
+///The retained message storage installed by a sidecar and the storage it replaced, one sidecar at
+///a time stores the retained messages
+#[derive(Default)]
+pub(crate) struct RetainerSlot {
+    //address of the installed storage, the replaced storage
+    inner: Mutex<Option<(usize, Box<dyn RetainStorage>)>>,
+}
+
+impl RetainerSlot {
+    ///False if another sidecar stores the retained messages
+    async fn install(&self, runtime: &'static Runtime, retainer: SidecarRetainer) -> bool {
+        let mut inner = self.inner.lock().await;
+        if inner.is_some() {
+            return false;
+        }
+        let retainer: Box<dyn RetainStorage> = Box::new(retainer);
+        let addr = storage_addr(retainer.as_ref());
+        let replaced = std::mem::replace(&mut *runtime.extends.retain_mut().await, retainer);
+        *inner = Some((addr, replaced));
+        true
+    }
+
+    ///Puts back the replaced storage, unless the storage of the sidecar has been replaced since,
+    ///such as by rmqtt-retainer
+    pub(crate) async fn restore(&self, runtime: &'static Runtime) -> bool {
+        if let Some((addr, replaced)) = self.inner.lock().await.take() {
+            let mut current = runtime.extends.retain_mut().await;
+            if storage_addr(current.as_ref()) == addr {
+                *current = replaced;
+                return true;
+            }
+        }
+        false
+    }
+
+    #[inline]
+    pub(crate) async fn is_installed(&self) -> bool {
+        self.inner.lock().await.is_some()
+    }
+}
+
+#[inline]
+fn storage_addr(storage: &dyn RetainStorage) -> usize {
+    storage as *const dyn RetainStorage as *const () as usize
+}
+
+///Retained message storage of a sidecar
+pub(crate) struct SidecarRetainer {
+    client: Client,
+    count: AtomicIsize,
+    max: isize,
+}
+
+impl SidecarRetainer {
+    #[inline]
+    fn new(client: Client, max: i64) -> Self {
+        Self { client, count: AtomicIsize::new(0), max: max as isize }
+    }
+}
+
+#[async_trait]
+impl RetainStorage for SidecarRetainer {
+    async fn set(&self, topic: &TopicName, retain: Retain) -> Result<()> {
+        let req = pb::RetainSetRequest {
+            topic: topic.to_string(),
+            qos: retain.publish.qos().value() as u32,
+            payload: retain.publish.payload().to_vec(),
+            data: codec::encode(&retain)?,
+        };
+        let reply = self
+            .client
+            .clone()
+            .retain_set(req)
+            .await
+            .map_err(|e| MqttError::from(e.to_string()))?
+            .into_inner();
+        self.count.store(reply.count as isize, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn get(&self, topic_filter: &TopicFilter) -> Result<Vec<(TopicName, Retain)>> {
+        let req = pb::RetainGetRequest { topic_filter: topic_filter.to_string() };
+        let reply = self
+            .client
+            .clone()
+            .retain_get(req)
+            .await
+            .map_err(|e| MqttError::from(e.to_string()))?
+            .into_inner();
+        reply
+            .entries
+            .iter()
+            .map(|e| Ok((TopicName::from(e.topic.clone()), codec::decode::<Retain>(&e.data)?)))
+            .collect()
+    }
+
+    fn count(&self) -> isize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    fn max(&self) -> isize {
+        self.max
+    }
+
+    //the retained messages are held by the sidecar process
+    fn bytes(&self) -> usize {
+        0
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use rmqtt::serde_json;
+use rmqtt::settings::deserialize_duration;
+use rmqtt::settings::schema::{ConfigSchema, Field, FieldType};
+use rmqtt::Result;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    //StatsD / DogStatsD server address (UDP)
+    #[serde(default = "PluginConfig::server_default")]
+    pub server: SocketAddr,
+
+    //Interval for pushing metrics
+    #[serde(default = "PluginConfig::push_interval_default", deserialize_with = "deserialize_duration")]
+    pub push_interval: Duration,
+
+    //Prefix of all metric names
+    #[serde(default = "PluginConfig::prefix_default")]
+    pub prefix: String,
+
+    //DogStatsD tags, appended to every metric as "|#key:value,..."
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+
+    //Maximum size of a UDP packet
+    #[serde(default = "PluginConfig::max_packet_size_default")]
+    pub max_packet_size: usize,
+}
+
+impl PluginConfig {
+    fn server_default() -> SocketAddr {
+        ([127, 0, 0, 1], 8125).into()
+    }
+
+    fn push_interval_default() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn prefix_default() -> String {
+        "rmqtt".into()
+    }
+
+    fn max_packet_size_default() -> usize {
+        1432
+    }
+
+    #[inline]
+    pub fn schema() -> ConfigSchema {
+        ConfigSchema::new()
+            .field(
+                Field::new("server", FieldType::Addr)
+                    .default(Self::server_default().to_string())
+                    .descr("StatsD / DogStatsD server address (UDP)"),
+            )
+            .field(
+                Field::new("push_interval", FieldType::Duration)
+                    .default_duration(Self::push_interval_default())
+                    .descr("Interval for pushing metrics"),
+            )
+            .field(
+                Field::new("prefix", FieldType::String)
+                    .default(Self::prefix_default())
+                    .descr("Prefix of all metric names"),
+            )
+            .field(
+                Field::new("tags", FieldType::Object)
+                    .default(serde_json::json!({}))
+                    .descr("DogStatsD tags, appended to every metric as \"|#key:value,...\""),
+            )
+            .field(
+                Field::new("max_packet_size", FieldType::Integer)
+                    .default(Self::max_packet_size_default())
+                    .range(64.0, 65507.0)
+                    .descr("Maximum size of a UDP packet"),
+            )
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    ///The tag suffix of the DogStatsD line protocol, empty if no tags are configured
+    #[inline]
+    pub fn tags_suffix(&self) -> String {
+        if self.tags.is_empty() {
+            return String::new();
+        }
+        let tags = self.tags.iter().map(|(k, v)| format!("{}:{}", k, v)).collect::<Vec<_>>();
+        format!("|#{}", tags.join(","))
+    }
+
+    ///Full metric name, "." is used as the separator
+    #[inline]
+    pub fn metric_name(&self, name: &str) -> String {
+        if self.prefix.is_empty() {
+            name.into()
+        } else {
+            format!("{}.{}", self.prefix, name)
+        }
+    }
+}
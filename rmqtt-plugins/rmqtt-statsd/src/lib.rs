@@ -0,0 +1,224 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use config::PluginConfig;
+use rmqtt::{async_trait::async_trait, log, serde_json, tokio, tokio::sync::RwLock};
+use rmqtt::{
+    plugin::{DynPlugin, DynPluginResult, Plugin},
+    settings::schema::ConfigSchema,
+    Result, Runtime,
+};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+mod config;
+
+#[inline]
+pub async fn register(
+    runtime: &'static Runtime,
+    name: &'static str,
+    descr: &'static str,
+    default_startup: bool,
+    immutable: bool,
+) -> Result<()> {
+    runtime
+        .plugins
+        .register(name, default_startup, immutable, move || -> DynPluginResult {
+            Box::pin(async move {
+                StatsdPlugin::new(runtime, name, descr).await.map(|p| -> DynPlugin { Box::new(p) })
+            })
+        })
+        .await?;
+    Ok(())
+}
+
+struct StatsdPlugin {
+    runtime: &'static Runtime,
+    name: String,
+    descr: String,
+    cfg: Arc<RwLock<PluginConfig>>,
+    pusher: Option<JoinHandle<()>>,
+}
+
+impl StatsdPlugin {
+    #[inline]
+    async fn new<N: Into<String>, D: Into<String>>(
+        runtime: &'static Runtime,
+        name: N,
+        descr: D,
+    ) -> Result<Self> {
+        let name = name.into();
+        let cfg = runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&name, &PluginConfig::schema())?;
+        log::info!("{} StatsdPlugin cfg: {:?}", name, cfg);
+        let cfg = Arc::new(RwLock::new(cfg));
+        Ok(Self { runtime, name, descr: descr.into(), cfg, pusher: None })
+    }
+}
+
+#[async_trait]
+impl Plugin for StatsdPlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name);
+        Ok(())
+    }
+
+    #[inline]
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        self.cfg.read().await.to_json()
+    }
+
+    #[inline]
+    async fn load_config(&mut self) -> Result<()> {
+        let new_cfg = self
+            .runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&self.name, &PluginConfig::schema())?;
+        *self.cfg.write().await = new_cfg;
+        log::debug!("load_config ok,  {:?}", self.cfg);
+        Ok(())
+    }
+
+    #[inline]
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(PluginConfig::schema())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name);
+        if self.pusher.is_none() {
+            let socket = bind_for(&self.cfg.read().await.server).await?;
+            self.pusher = Some(tokio::spawn(Pusher::new(self.runtime, self.cfg.clone(), socket).run()));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::info!("{} stop", self.name);
+        if let Some(pusher) = self.pusher.take() {
+            pusher.abort();
+        }
+        Ok(true)
+    }
+
+    #[inline]
+    fn version(&self) -> &str {
+        "0.1.0"
+    }
+
+    #[inline]
+    fn descr(&self) -> &str {
+        &self.descr
+    }
+}
+
+///Periodically pushes the metrics of this node to the StatsD server,
+///`Metrics` are sent as counters (increments since the last push), `Stats` as gauges.
+struct Pusher {
+    runtime: &'static Runtime,
+    cfg: Arc<RwLock<PluginConfig>>,
+    socket: UdpSocket,
+    last_counters: HashMap<String, i64>,
+}
+
+impl Pusher {
+    fn new(runtime: &'static Runtime, cfg: Arc<RwLock<PluginConfig>>, socket: UdpSocket) -> Self {
+        Self { runtime, cfg, socket, last_counters: HashMap::default() }
+    }
+
+    async fn run(mut self) {
+        loop {
+            let interval = self.cfg.read().await.push_interval;
+            tokio::time::sleep(interval).await;
+            if let Err(e) = self.push().await {
+                log::warn!("push metrics to statsd error, {:?}", e);
+            }
+        }
+    }
+
+    async fn push(&mut self) -> Result<()> {
+        let cfg = self.cfg.read().await.clone();
+        let tags = cfg.tags_suffix();
+        let mut lines = Vec::new();
+
+        if let Some(metrics) = self.runtime.metrics.to_json().as_object() {
+            for (name, val) in metrics.iter() {
+                let val = val.as_i64().unwrap_or_default();
+                let last = self.last_counters.insert(name.clone(), val).unwrap_or(val);
+                lines.push(format!("{}:{}|c{}", cfg.metric_name(name), (val - last).max(0), tags));
+            }
+        }
+
+        if let Some(stats) = self.runtime.stats.clone().await.to_json().await.as_object() {
+            for (name, val) in stats.iter() {
+                if val.is_number() {
+                    lines.push(format!("{}:{}|g{}", cfg.metric_name(name), val, tags));
+                }
+            }
+            if let Some(tagged) = stats.get("tagged_connections").and_then(|t| t.as_array()) {
+                for t in tagged.iter() {
+                    let label = format!(
+                        "{}:{}",
+                        t["tag"].as_str().unwrap_or_default(),
+                        t["value"].as_str().unwrap_or_default()
+                    );
+                    let tags =
+                        if tags.is_empty() { format!("|#{}", label) } else { format!("{},{}", tags, label) };
+                    lines.push(format!("{}:{}|g{}", cfg.metric_name("connections_tagged"), t["count"], tags));
+                }
+            }
+        }
+
+        //the server may have moved to the other address family with a reload of the config
+        if self.socket.local_addr()?.is_ipv4() != cfg.server.is_ipv4() {
+            self.socket = bind_for(&cfg.server).await?;
+        }
+        for packet in packets(&lines, cfg.max_packet_size) {
+            self.socket.send_to(packet.as_bytes(), cfg.server).await?;
+        }
+        Ok(())
+    }
+}
+
+///Binds to the unspecified address of the address family of the server, a socket of the other
+///family cannot reach it
+async fn bind_for(server: &SocketAddr) -> std::io::Result<UdpSocket> {
+    let local: SocketAddr =
+        if server.is_ipv4() { (Ipv4Addr::UNSPECIFIED, 0).into() } else { (Ipv6Addr::UNSPECIFIED, 0).into() };
+    UdpSocket::bind(local).await
+}
+
+///Join the lines into packets separated by newlines, no larger than `max_packet_size`
+fn packets(lines: &[String], max_packet_size: usize) -> Vec<String> {
+    let mut packets = Vec::new();
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > max_packet_size {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
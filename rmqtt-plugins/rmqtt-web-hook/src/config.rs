@@ -3,15 +3,19 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use backoff::{ExponentialBackoff, ExponentialBackoffBuilder};
+use regex::Regex;
 use serde::de::{self, Deserialize};
 use serde::ser::{self, Serialize};
 
 use rmqtt::broker::hook::Type;
 use rmqtt::broker::topic::TopicTree;
-use rmqtt::settings::deserialize_duration;
+use rmqtt::settings::schema::{ConfigSchema, Field, FieldType};
+use rmqtt::settings::{deserialize_duration, Secret};
 use rmqtt::{ahash, serde_json};
 use rmqtt::{Result, Topic};
 
+use crate::Signer;
+
 type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -37,6 +41,20 @@ pub struct PluginConfig {
     pub retry_max_elapsed_time: Duration,
     #[serde(default = "PluginConfig::retry_multiplier_default")]
     pub retry_multiplier: f64,
+
+    ///Secret of the HMAC-SHA256 signature of the requests, empty, the requests are not signed
+    #[serde(default)]
+    pub hmac_secret: Secret,
+    ///Header of the signature, in lowercase hex
+    #[serde(default = "PluginConfig::hmac_header_default")]
+    pub hmac_header: String,
+    ///Header of the Unix time in seconds at which the request is sent, signed with the body
+    #[serde(default = "PluginConfig::hmac_timestamp_header_default")]
+    pub hmac_timestamp_header: String,
+
+    ///Built from hmac_secret once the configuration is loaded, see init
+    #[serde(skip)]
+    pub(crate) signer: Option<Signer>,
 }
 
 impl PluginConfig {
@@ -58,6 +76,18 @@ impl PluginConfig {
     fn retry_multiplier_default() -> f64 {
         2.5
     }
+    fn hmac_header_default() -> String {
+        "X-Rmqtt-Signature".into()
+    }
+    fn hmac_timestamp_header_default() -> String {
+        "X-Rmqtt-Timestamp".into()
+    }
+
+    ///Builds the signer once for all the requests
+    #[inline]
+    pub fn init(&mut self) {
+        self.signer = Signer::new(self.hmac_secret.expose(), &self.hmac_header, &self.hmac_timestamp_header);
+    }
 
     fn deserialize_rules<'de, D>(deserializer: D) -> std::result::Result<HashMap<Type, Vec<Rule>>, D::Error>
     where
@@ -76,6 +106,43 @@ impl PluginConfig {
         Ok(serde_json::to_value(self)?)
     }
 
+    pub fn schema() -> ConfigSchema {
+        ConfigSchema::new()
+            .field(Field::new("worker_threads", FieldType::Integer).default(Self::worker_threads_default()))
+            .field(Field::new("queue_capacity", FieldType::Integer).default(Self::queue_capacity_default()))
+            .field(
+                Field::new("concurrency_limit", FieldType::Integer)
+                    .default(Self::concurrency_limit_default()),
+            )
+            .field(Field::new("http_urls", FieldType::Array).descr("Default URLs of the rules"))
+            .field(
+                Field::new("http_timeout", FieldType::Duration)
+                    .default_duration(Self::http_timeout_default()),
+            )
+            .field(
+                Field::new("rule", FieldType::Object)
+                    .descr("Rules of the hooks whose events are sent, by hook"),
+            )
+            .field(
+                Field::new("retry_max_elapsed_time", FieldType::Duration)
+                    .default_duration(Self::retry_max_elapsed_time_default()),
+            )
+            .field(Field::new("retry_multiplier", FieldType::Float).default(Self::retry_multiplier_default()))
+            .field(Field::new("hmac_secret", FieldType::String).descr(
+                "Secret of the HMAC-SHA256 signature of the requests, empty, the requests are not signed",
+            ))
+            .field(
+                Field::new("hmac_header", FieldType::String)
+                    .default(Self::hmac_header_default())
+                    .descr("Header of the signature"),
+            )
+            .field(
+                Field::new("hmac_timestamp_header", FieldType::String)
+                    .default(Self::hmac_timestamp_header_default())
+                    .descr("Header of the Unix time in seconds at which the request is sent"),
+            )
+    }
+
     #[inline]
     pub fn get_backoff_strategy(&self) -> ExponentialBackoff {
         ExponentialBackoffBuilder::new()
@@ -87,6 +154,10 @@ impl PluginConfig {
 
 type TopicsType = Option<(Arc<TopicTree<()>>, Vec<String>)>;
 
+///Placeholders of the template, replaced by the values of the body
+const PLACEHOLDER_START: &str = "${";
+const PLACEHOLDER_END: &str = "}";
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Rule {
     pub action: String,
@@ -98,9 +169,63 @@ pub struct Rule {
         serialize_with = "Rule::serialize_topics"
     )]
     pub topics: TopicsType,
+    ///Regex of the client IDs whose events are sent, that of the client of the event, or of the
+    ///publisher of a message without it
+    #[serde(
+        default,
+        deserialize_with = "Rule::deserialize_clientid",
+        serialize_with = "Rule::serialize_clientid"
+    )]
+    pub clientid: Option<Regex>,
+    ///The JSON sent instead of the body, "${key}" is replaced by the value of the key of the body,
+    ///or of a nested key, such as "${opts.qos}", and is replaced within a longer string by its text
+    #[serde(default)]
+    pub template: Option<serde_json::Value>,
 }
 
 impl Rule {
+    #[inline]
+    pub fn is_clientid_allowed(&self, body: &serde_json::Value) -> bool {
+        if let Some(clientid) = &self.clientid {
+            body.get("clientid")
+                .or_else(|| body.get("from_clientid"))
+                .and_then(|c| c.as_str())
+                .map(|c| clientid.is_match(c))
+                .unwrap_or(false)
+        } else {
+            true
+        }
+    }
+
+    ///The body rendered by the template, the body itself without it
+    #[inline]
+    pub fn render(&self, body: serde_json::Value) -> serde_json::Value {
+        if let Some(template) = &self.template {
+            render(template, &body)
+        } else {
+            body
+        }
+    }
+
+    fn serialize_clientid<S>(clientid: &Option<Regex>, s: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        clientid.as_ref().map(|r| r.as_str()).unwrap_or_default().serialize(s)
+    }
+
+    fn deserialize_clientid<'de, D>(deserializer: D) -> std::result::Result<Option<Regex>, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let clientid = String::deserialize(deserializer)?;
+        if clientid.is_empty() {
+            Ok(None)
+        } else {
+            Regex::new(&clientid).map(Some).map_err(|e| de::Error::custom(format!("{:?}, {}", clientid, e)))
+        }
+    }
+
     fn serialize_topics<S>(topics: &TopicsType, s: S) -> std::result::Result<S::Ok, S::Error>
     where
         S: ser::Serializer,
@@ -131,3 +256,49 @@ impl Rule {
         }
     }
 }
+
+fn render(template: &serde_json::Value, body: &serde_json::Value) -> serde_json::Value {
+    match template {
+        serde_json::Value::String(s) => render_str(s, body),
+        serde_json::Value::Array(items) => {
+            serde_json::Value::Array(items.iter().map(|item| render(item, body)).collect())
+        }
+        serde_json::Value::Object(obj) => {
+            serde_json::Value::Object(obj.iter().map(|(k, v)| (k.clone(), render(v, body))).collect())
+        }
+        _ => template.clone(),
+    }
+}
+
+///A string that is a single placeholder keeps the type of the value, a missing key is null
+fn render_str(s: &str, body: &serde_json::Value) -> serde_json::Value {
+    if let Some(key) = s.strip_prefix(PLACEHOLDER_START).and_then(|s| s.strip_suffix(PLACEHOLDER_END)) {
+        if !key.contains(PLACEHOLDER_END) {
+            return lookup(body, key).cloned().unwrap_or(serde_json::Value::Null);
+        }
+    }
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find(PLACEHOLDER_START) {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + PLACEHOLDER_START.len()..];
+        if let Some(end) = after.find(PLACEHOLDER_END) {
+            match lookup(body, &after[..end]) {
+                Some(serde_json::Value::String(v)) => out.push_str(v),
+                Some(serde_json::Value::Null) | None => {}
+                Some(v) => out.push_str(&v.to_string()),
+            }
+            rest = &after[end + PLACEHOLDER_END.len()..];
+        } else {
+            out.push_str(&rest[start..]);
+            rest = "";
+        }
+    }
+    out.push_str(rest);
+    serde_json::Value::String(out)
+}
+
+#[inline]
+fn lookup<'a>(body: &'a serde_json::Value, key: &str) -> Option<&'a serde_json::Value> {
+    key.split('.').try_fold(body, |value, k| value.get(k))
+}
@@ -10,6 +10,8 @@ use backoff::future::retry;
 use backoff::ExponentialBackoff;
 use futures::channel::mpsc::{channel, Receiver, Sender};
 use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 
 use config::PluginConfig;
 use rmqtt::{
@@ -26,8 +28,9 @@ use rmqtt::{
     broker::hook::{self, Handler, HookResult, Parameter, Register, ReturnType, Type},
     broker::stats::Counter,
     broker::types::{ConnectInfo, Id, QoSEx, MQTT_LEVEL_5},
-    plugin::{DynPlugin, DynPluginResult, Plugin},
-    Result, Runtime, Topic, TopicFilter,
+    plugin::{DynPlugin, DynPluginResult, Metric, Plugin},
+    settings::schema::ConfigSchema,
+    ClientInfo, Result, Runtime, Topic, TopicFilter,
 };
 use rmqtt::{
     once_cell::sync::OnceCell,
@@ -36,6 +39,8 @@ use rmqtt::{
 
 mod config;
 
+type HmacSha256 = Hmac<Sha256>;
+
 #[inline]
 pub async fn register(
     runtime: &'static Runtime,
@@ -69,17 +74,16 @@ impl WebHookPlugin {
     #[inline]
     async fn new<S: Into<String>>(runtime: &'static Runtime, name: S, descr: S) -> Result<Self> {
         let name = name.into();
-        let cfg = Arc::new(RwLock::new(
-            runtime
-                .settings
-                .plugins
-                .load_config::<PluginConfig>(&name)
-                .map_err(|e| MqttError::from(e.to_string()))?,
-        ));
+        let mut cfg = runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&name, &PluginConfig::schema())?;
+        cfg.init();
+        let cfg = Arc::new(RwLock::new(cfg));
         log::debug!("{} WebHookPlugin cfg: {:?}", name, cfg.read());
 
         let tx = Arc::new(RwLock::new(Self::start(runtime, cfg.clone())));
-        let register = runtime.extends.hook_mgr().await.register();
+        let register = runtime.extends.hook_mgr().await.register_for(&name);
         Ok(Self { runtime, name, descr: descr.into(), register, cfg, tx })
     }
 
@@ -154,11 +158,17 @@ impl Plugin for WebHookPlugin {
         self.register.add(Type::ClientDisconnected, Box::new(WebHookHandler { tx: tx.clone() })).await;
         self.register.add(Type::ClientSubscribe, Box::new(WebHookHandler { tx: tx.clone() })).await;
         self.register.add(Type::ClientUnsubscribe, Box::new(WebHookHandler { tx: tx.clone() })).await;
+        self.register.add(Type::SubscribeAuthorized, Box::new(WebHookHandler { tx: tx.clone() })).await;
+        self.register.add(Type::ClientSlow, Box::new(WebHookHandler { tx: tx.clone() })).await;
 
         self.register.add(Type::MessagePublish, Box::new(WebHookHandler { tx: tx.clone() })).await;
         self.register.add(Type::MessageDelivered, Box::new(WebHookHandler { tx: tx.clone() })).await;
         self.register.add(Type::MessageAcked, Box::new(WebHookHandler { tx: tx.clone() })).await;
         self.register.add(Type::MessageDropped, Box::new(WebHookHandler { tx: tx.clone() })).await;
+        self.register.add(Type::MessageOffline, Box::new(WebHookHandler { tx: tx.clone() })).await;
+
+        self.register.add(Type::AlarmActivated, Box::new(WebHookHandler { tx: tx.clone() })).await;
+        self.register.add(Type::AlarmDeactivated, Box::new(WebHookHandler { tx: tx.clone() })).await;
 
         Ok(())
     }
@@ -173,9 +183,19 @@ impl Plugin for WebHookPlugin {
         self.cfg.read().to_json()
     }
 
+    #[inline]
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(PluginConfig::schema())
+    }
+
     #[inline]
     async fn load_config(&mut self) -> Result<()> {
-        let new_cfg = self.runtime.settings.plugins.load_config::<PluginConfig>(&self.name)?;
+        let mut new_cfg = self
+            .runtime
+            .settings
+            .plugins
+            .load_config_with_schema::<PluginConfig>(&self.name, &PluginConfig::schema())?;
+        new_cfg.init();
         let cfg = { self.cfg.read().clone() };
         if cfg.worker_threads != new_cfg.worker_threads
             || cfg.queue_capacity != new_cfg.queue_capacity
@@ -222,15 +242,15 @@ impl Plugin for WebHookPlugin {
     }
 
     #[inline]
-    async fn attrs(&self) -> serde_json::Value {
+    async fn metrics(&self) -> Vec<Metric> {
         let exec = task_exec_queue();
-
-        json!({
-            "active_count": exec.active_count(),
-            "waiting_count": exec.waiting_count(),
-            "completed_count": exec.completed_count(),
-            "failure_count()": fails().count(),
-        })
+        vec![
+            Metric::gauge("tasks_active", exec.active_count() as f64).descr("Requests being sent"),
+            Metric::gauge("tasks_waiting", exec.waiting_count() as f64).descr("Requests waiting to be sent"),
+            Metric::counter("tasks_completed", exec.completed_count() as f64).descr("Requests sent"),
+            Metric::counter("requests_failed", fails().count() as f64)
+                .descr("Requests failed after retrying"),
+        ]
     }
 }
 
@@ -262,9 +282,9 @@ impl WebHookHandler {
         topic: Option<TopicFilter>,
         body: serde_json::Value,
     ) -> Result<()> {
-        let (timeout, default_urls) = {
+        let (timeout, default_urls, signer) = {
             let cfg = cfg.read();
-            (cfg.http_timeout, cfg.http_urls.clone())
+            (cfg.http_timeout, cfg.http_urls.clone(), cfg.signer.clone())
         };
 
         let topic = if let Some(topic) = topic { Some(Topic::from_str(&topic)?) } else { None };
@@ -282,12 +302,12 @@ impl WebHookHandler {
                     true
                 };
 
-                if is_allowed {
+                if is_allowed && r.is_clientid_allowed(&body) {
                     let urls = if r.urls.is_empty() { &default_urls } else { &r.urls };
                     if urls.is_empty() {
                         None
                     } else {
-                        Some((&r.action, urls))
+                        Some((r, urls))
                     }
                 } else {
                     None
@@ -296,11 +316,13 @@ impl WebHookHandler {
 
             //build http send futures
             let mut http_requests = Vec::new();
-            for (action, urls) in action_urls {
+            for (rule, urls) in action_urls {
+                let action = &rule.action;
                 let mut new_body = body.clone();
                 if let Some(obj) = new_body.as_object_mut() {
                     obj.insert("action".into(), serde_json::Value::String(action.clone()));
                 }
+                let new_body = rule.render(new_body);
                 if urls.len() == 1 {
                     log::debug!("action: {}, url: {}", action, urls[0]);
                     http_requests.push(Self::http_request(
@@ -308,6 +330,7 @@ impl WebHookHandler {
                         urls[0].clone(),
                         new_body.arc(),
                         timeout,
+                        signer.clone(),
                     ));
                 } else {
                     let new_body = new_body.arc();
@@ -318,6 +341,7 @@ impl WebHookHandler {
                             url.clone(),
                             new_body.clone(),
                             timeout,
+                            signer.clone(),
                         ));
                     }
                 }
@@ -342,10 +366,11 @@ impl WebHookHandler {
         url: String,
         body: Arc<serde_json::Value>,
         timeout: Duration,
+        signer: Option<Signer>,
     ) {
         if let Err(e) = async move {
             if let Err(e) = retry(backoff_strategy.as_ref().clone(), || async {
-                Ok(Self::_http_request(url.clone(), body.clone(), timeout).await?)
+                Ok(Self::_http_request(url.clone(), body.clone(), timeout, signer.as_ref()).await?)
             })
             .await
             {
@@ -361,17 +386,27 @@ impl WebHookHandler {
         }
     }
 
-    async fn _http_request(url: String, body: Arc<serde_json::Value>, timeout: Duration) -> Result<()> {
+    async fn _http_request(
+        url: String,
+        body: Arc<serde_json::Value>,
+        timeout: Duration,
+        signer: Option<&Signer>,
+    ) -> Result<()> {
         log::debug!("http_request, timeout: {:?}, url: {}, body: {}", timeout, url, body);
 
-        let resp = HTTP_CLIENT
+        let body = serde_json::to_vec(body.as_ref())?;
+        let mut req = HTTP_CLIENT
             .clone()
             .request(reqwest::Method::POST, &url)
             .timeout(timeout)
-            .json(body.as_ref())
-            .send()
-            .await
-            .map_err(|e| MqttError::Anyhow(anyhow!(e)))?;
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        if let Some(signer) = signer {
+            let timestamp = chrono::Local::now().timestamp().to_string();
+            req = req
+                .header(signer.timestamp_header.as_str(), timestamp.as_str())
+                .header(signer.header.as_str(), signer.sign(&timestamp, &body));
+        }
+        let resp = req.body(body).send().await.map_err(|e| MqttError::Anyhow(anyhow!(e)))?;
 
         if resp.status().is_success() {
             Ok(())
@@ -381,6 +416,45 @@ impl WebHookHandler {
     }
 }
 
+///Signs the requests with HMAC-SHA256 of their timestamp and body, so that the web server can
+///check they were sent by the broker and reject those replayed later
+#[derive(Clone)]
+pub(crate) struct Signer {
+    mac: HmacSha256,
+    header: Arc<String>,
+    timestamp_header: Arc<String>,
+}
+
+impl std::fmt::Debug for Signer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Signer {{ header: {}, timestamp_header: {} }}", self.header, self.timestamp_header)
+    }
+}
+
+impl Signer {
+    #[inline]
+    pub(crate) fn new(secret: &str, header: &str, timestamp_header: &str) -> Option<Self> {
+        if secret.is_empty() {
+            return None;
+        }
+        HmacSha256::new_from_slice(secret.as_bytes()).ok().map(|mac| Self {
+            mac,
+            header: Arc::new(header.to_owned()),
+            timestamp_header: Arc::new(timestamp_header.to_owned()),
+        })
+    }
+
+    ///Signs "{timestamp}.{body}"
+    #[inline]
+    fn sign(&self, timestamp: &str, body: &[u8]) -> String {
+        let mut mac = self.mac.clone();
+        mac.update(timestamp.as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
 trait ToBody {
     fn to_body(&self) -> serde_json::Value;
 }
@@ -461,7 +535,7 @@ impl Handler for WebHookHandler {
     async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
         let typ = param.get_type();
 
-        let bodys = match param {
+        let mut bodys = match param {
             Parameter::ClientConnect(conn_info) => {
                 vec![(None, conn_info.to_body())]
             }
@@ -492,7 +566,8 @@ impl Handler for WebHookHandler {
                     "clientid": client.id.client_id,
                     "username": client.id.username,
                     "disconnected_at": client.disconnected_at(),
-                    "reason": reason
+                    "reason": reason,
+                    "reason_kind": client.disconnected_kind(),
                 });
                 vec![(None, body)]
             }
@@ -522,6 +597,19 @@ impl Handler for WebHookHandler {
                 vec![(Some(unsubscribe.topic_filter.clone()), body)]
             }
 
+            Parameter::ClientSlow(_session, client, slow) => {
+                let body = json!({
+                    "node": client.id.node(),
+                    "ipaddress": client.id.remote_addr,
+                    "clientid": client.id.client_id,
+                    "username": client.id.username,
+                    "queue_len": slow.queue_len,
+                    "latency": slow.latency.as_millis() as u64,
+                    "duration": slow.duration.as_millis() as u64,
+                });
+                vec![(None, body)]
+            }
+
             Parameter::SessionSubscribed(_session, client, subscribe) => {
                 let body = json!({
                     "node": client.id.node(),
@@ -536,6 +624,21 @@ impl Handler for WebHookHandler {
                 vec![(Some(subscribe.topic_filter.clone()), body)]
             }
 
+            Parameter::SubscribeAuthorized(_session, client, subscribe) => {
+                let body = json!({
+                    "node": client.id.node(),
+                    "ipaddress": client.id.remote_addr,
+                    "clientid": client.id.client_id,
+                    "username": client.id.username,
+                    "topic": subscribe.topic_filter,
+                    "opts": json!({
+                        "qos": subscribe.qos.value()
+                    }),
+                    "ts": chrono::Local::now().timestamp_millis(),
+                });
+                vec![(Some(subscribe.topic_filter.clone()), body)]
+            }
+
             Parameter::SessionUnsubscribed(_session, client, unsubscribed) => {
                 let topic = unsubscribed.topic_filter.clone();
                 let body = json!({
@@ -619,6 +722,22 @@ impl Handler for WebHookHandler {
                 vec![(Some(topic.clone()), body)]
             }
 
+            Parameter::MessageOffline(_session, client, from, publish) => {
+                let topic = publish.topic();
+                let body = json!({
+                    "dup": publish.dup(),
+                    "retain": publish.retain(),
+                    "qos": publish.qos().value(),
+                    "topic": topic,
+                    "payload": base64::encode(publish.payload()),
+                    "pts": publish.create_time(),
+                    "ts": chrono::Local::now().timestamp_millis(),
+                });
+                let body = client.id.to(body);
+                let body = from.from(body);
+                vec![(Some(topic.clone()), body)]
+            }
+
             Parameter::MessageDropped(to, from, publish, reason) => {
                 let body = json!({
                     "dup": publish.dup(),
@@ -638,12 +757,35 @@ impl Handler for WebHookHandler {
 
                 vec![(None, body)]
             }
+
+            Parameter::AlarmActivated(alarm) | Parameter::AlarmDeactivated(alarm) => {
+                let body = json!({
+                    "node": alarm.node_id,
+                    "name": alarm.name,
+                    "message": alarm.message,
+                    "activated_at": alarm.activated_at,
+                    "deactivated_at": alarm.deactivated_at,
+                    "ts": chrono::Local::now().timestamp_millis(),
+                });
+                vec![(None, body)]
+            }
             _ => {
                 log::error!("parameter is: {:?}", param);
                 Vec::new()
             }
         };
 
+        if let Some(client) = client_of(param) {
+            let tags = client.tags();
+            if !tags.is_empty() {
+                for (_, body) in bodys.iter_mut() {
+                    if let Some(obj) = body.as_object_mut() {
+                        obj.insert("tags".into(), json!(tags));
+                    }
+                }
+            }
+        }
+
         log::debug!("bodys: {:?}", bodys);
 
         if !bodys.is_empty() {
@@ -659,6 +801,28 @@ impl Handler for WebHookHandler {
     }
 }
 
+///The client of the event, whose tags are added to the body
+#[inline]
+fn client_of<'a>(param: &'a Parameter) -> Option<&'a ClientInfo> {
+    match param {
+        Parameter::ClientConnected(_, c)
+        | Parameter::ClientDisconnected(_, c, _)
+        | Parameter::ClientSubscribe(_, c, _)
+        | Parameter::ClientUnsubscribe(_, c, _)
+        | Parameter::ClientSlow(_, c, _)
+        | Parameter::SessionSubscribed(_, c, _)
+        | Parameter::SubscribeAuthorized(_, c, _)
+        | Parameter::SessionUnsubscribed(_, c, _)
+        | Parameter::SessionCreated(_, c)
+        | Parameter::SessionTerminated(_, c, _)
+        | Parameter::MessagePublish(_, c, _)
+        | Parameter::MessageDelivered(_, c, _, _)
+        | Parameter::MessageAcked(_, c, _, _)
+        | Parameter::MessageOffline(_, c, _, _) => Some(c),
+        _ => None,
+    }
+}
+
 static TASK_EXEC_QUEUE: OnceCell<TaskExecQueue> = OnceCell::new();
 
 #[inline]
@@ -0,0 +1,277 @@
+use std::net::SocketAddr;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use governor::{
+    clock::DefaultClock,
+    state::{InMemoryState, NotKeyed},
+    Quota, RateLimiter,
+};
+use once_cell::sync::OnceCell;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::broker::session::PublishRateLimiter;
+use crate::broker::types::PublishRateLimit;
+use crate::settings::listener::Listener;
+use crate::settings::{AdmissionPolicy, Settings};
+use crate::{Metrics, Runtime};
+
+type DirectLimiter = RateLimiter<NotKeyed, InMemoryState, DefaultClock>;
+
+///Admission of the handshakes of all listeners of this node, caps the handshakes processed
+///concurrently and sheds the excess by the configured policy.
+pub struct HandshakeAdmission {
+    permits: Semaphore,
+    pending: AtomicUsize,
+}
+
+///Held while the handshake is processed, holds no permit if admission is disabled
+pub struct Admitted {
+    _permit: Option<SemaphorePermit<'static>>,
+}
+
+impl HandshakeAdmission {
+    #[inline]
+    pub fn instance() -> &'static HandshakeAdmission {
+        static INSTANCE: OnceCell<HandshakeAdmission> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self {
+            permits: Semaphore::new(Settings::instance().handshake_admission.max_concurrent),
+            pending: AtomicUsize::new(0),
+        })
+    }
+
+    ///Returns None if the handshake is shed
+    #[inline]
+    pub async fn admit(&'static self) -> Option<Admitted> {
+        let cfg = &Settings::instance().handshake_admission;
+        if cfg.max_concurrent == 0 {
+            return Some(Admitted { _permit: None });
+        }
+        if let Ok(permit) = self.permits.try_acquire() {
+            return Some(Admitted { _permit: Some(permit) });
+        }
+        if cfg.policy == AdmissionPolicy::Reject {
+            return self.shed();
+        }
+        if self.pending.fetch_add(1, Ordering::SeqCst) >= cfg.max_pending {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            return self.shed();
+        }
+        let permit = tokio::time::timeout(cfg.queue_timeout, self.permits.acquire()).await;
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+        match permit {
+            Ok(Ok(permit)) => Some(Admitted { _permit: Some(permit) }),
+            _ => self.shed(),
+        }
+    }
+
+    #[inline]
+    fn shed(&self) -> Option<Admitted> {
+        Runtime::instance().metrics.client_handshaking_shed_inc();
+        None
+    }
+
+    ///Number of handshakes being processed
+    #[inline]
+    pub fn active(&self) -> usize {
+        Settings::instance()
+            .handshake_admission
+            .max_concurrent
+            .saturating_sub(self.permits.available_permits())
+    }
+
+    ///Number of handshakes waiting for admission
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+}
+
+///Rate limits of the new connections of the listeners, by listen address. A limiter is replaced
+///when the max_conn_rate or max_conn_burst of its listener is reloaded
+pub struct ConnectionRate {
+    limiters: DashMap<SocketAddr, ((NonZeroU32, NonZeroU32), DirectLimiter)>,
+}
+
+impl ConnectionRate {
+    #[inline]
+    pub fn instance() -> &'static ConnectionRate {
+        static INSTANCE: OnceCell<ConnectionRate> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { limiters: DashMap::default() })
+    }
+
+    ///Whether a new connection is within the max_conn_rate of its listener, always true in dry run
+    ///mode, where an exceeded rate is only logged and counted
+    #[inline]
+    pub fn check(&self, listen_cfg: &Listener) -> bool {
+        if self.acquire(listen_cfg) {
+            return true;
+        }
+        if listen_cfg.conn_rate_limit_dry_run {
+            log::debug!("connection rate of the listener {} is exceeded, dry run", listen_cfg.addr);
+            Metrics::instance().client_connect_rate_limit_dry_run_inc();
+            return true;
+        }
+        false
+    }
+
+    #[inline]
+    fn acquire(&self, listen_cfg: &Listener) -> bool {
+        let rate = match NonZeroU32::new(listen_cfg.max_conn_rate) {
+            Some(rate) => rate,
+            None => return true,
+        };
+        let limits = (rate, NonZeroU32::new(listen_cfg.max_conn_burst).unwrap_or(rate));
+        let new_limiter = || RateLimiter::direct(Quota::per_second(limits.0).allow_burst(limits.1));
+        let mut entry = self.limiters.entry(listen_cfg.addr).or_insert_with(|| (limits, new_limiter()));
+        if entry.0 != limits {
+            *entry = (limits, new_limiter());
+        }
+        entry.1.check().is_ok()
+    }
+}
+
+///Publish rate limits of the tenants on this node, shared by their connections, a tenant is made
+///of the first mqtt.tenant_levels levels of the topic. The limiters of the tenants idle for a while
+///are dropped as the tenants grow
+pub struct TenantPublishRate {
+    limiters: DashMap<String, PublishRateLimiter>,
+    sweep_at: AtomicUsize,
+}
+
+impl TenantPublishRate {
+    const MIN_SWEEP_AT: usize = 1024;
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+    #[inline]
+    pub fn instance() -> &'static TenantPublishRate {
+        static INSTANCE: OnceCell<TenantPublishRate> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self {
+            limiters: DashMap::default(),
+            sweep_at: AtomicUsize::new(Self::MIN_SWEEP_AT),
+        })
+    }
+
+    ///The publish rate limits of a tenant
+    #[inline]
+    pub fn limit(settings: &Settings) -> PublishRateLimit {
+        let mqtt = &settings.mqtt;
+        PublishRateLimit {
+            messages: mqtt.max_tenant_publish_rate,
+            bytes: *mqtt.max_tenant_publish_bytes_rate as u64,
+            burst_messages: mqtt.max_tenant_publish_burst,
+            burst_bytes: *mqtt.max_tenant_publish_bytes_burst as u64,
+            dry_run: mqtt.publish_rate_limit_dry_run,
+        }
+    }
+
+    ///The tenant of a topic, None if tenant_levels is 0
+    #[inline]
+    pub fn tenant(topic: &str, tenant_levels: usize) -> Option<String> {
+        if tenant_levels == 0 {
+            return None;
+        }
+        Some(topic.split('/').take(tenant_levels).collect::<Vec<_>>().join("/"))
+    }
+
+    ///Takes the tokens of a message of the tenant, returns false if the rate limits of the tenant
+    ///are exceeded
+    #[inline]
+    pub fn acquire(&self, tenant: &str, payload_len: usize) -> bool {
+        let limit = Self::limit(&Runtime::instance().settings);
+        if limit.is_unlimited() {
+            return true;
+        }
+        if let Some(limiter) = self.limiters.get(tenant) {
+            return limiter.acquire(payload_len);
+        }
+        self.sweep();
+        self.limiters
+            .entry(tenant.to_owned())
+            .or_insert_with(|| PublishRateLimiter::new(limit))
+            .acquire(payload_len)
+    }
+
+    ///Drops the limiters of the idle tenants once their number doubles
+    #[inline]
+    fn sweep(&self) {
+        if self.limiters.len() < self.sweep_at.load(Ordering::SeqCst) {
+            return;
+        }
+        self.limiters.retain(|_, limiter| limiter.idle() < Self::IDLE_TIMEOUT);
+        self.sweep_at.store((self.limiters.len() * 2).max(Self::MIN_SWEEP_AT), Ordering::SeqCst);
+    }
+}
+
+///Connections of the listeners, by listen address, a CONNECT over the max_connections of its
+///listener is refused with a CONNACK
+pub struct ListenerConnections {
+    counts: DashMap<SocketAddr, Arc<AtomicUsize>>,
+}
+
+///Held for the lifetime of a connection, releases its place in the listener on drop
+pub struct ConnectionSlot {
+    count: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionSlot {
+    #[inline]
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ListenerConnections {
+    #[inline]
+    pub fn instance() -> &'static ListenerConnections {
+        static INSTANCE: OnceCell<ListenerConnections> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { counts: DashMap::default() })
+    }
+
+    ///Returns None if the listener at addr has max_connections connections
+    #[inline]
+    pub fn acquire(&self, addr: SocketAddr, max_connections: usize) -> Option<ConnectionSlot> {
+        let count = self.counts.entry(addr).or_default().value().clone();
+        if count.fetch_add(1, Ordering::SeqCst) >= max_connections {
+            count.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(ConnectionSlot { count })
+    }
+
+    ///Number of connections of the listener
+    #[inline]
+    pub fn count(&self, addr: &SocketAddr) -> usize {
+        self.counts.get(addr).map(|c| c.load(Ordering::SeqCst)).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listener_connections() {
+        let addr: SocketAddr = "127.0.0.1:21883".parse().unwrap();
+        let conns = ListenerConnections::instance();
+        let a = conns.acquire(addr, 2).unwrap();
+        let _b = conns.acquire(addr, 2).unwrap();
+        assert!(conns.acquire(addr, 2).is_none());
+        assert_eq!(conns.count(&addr), 2);
+        drop(a);
+        let _c = conns.acquire(addr, 2).unwrap();
+        assert_eq!(conns.count(&addr), 2);
+        assert!(conns.acquire("127.0.0.2:21883".parse().unwrap(), 2).is_some());
+    }
+
+    #[test]
+    fn tenant() {
+        assert_eq!(TenantPublishRate::tenant("t1/a/b", 0), None);
+        assert_eq!(TenantPublishRate::tenant("t1/a/b", 1).as_deref(), Some("t1"));
+        assert_eq!(TenantPublishRate::tenant("t1/a/b", 2).as_deref(), Some("t1/a"));
+        assert_eq!(TenantPublishRate::tenant("t1", 2).as_deref(), Some("t1"));
+    }
+}
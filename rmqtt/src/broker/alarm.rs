@@ -0,0 +1,282 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use chrono::TimeZone;
+use once_cell::sync::OnceCell;
+use parking_lot::RwLock;
+use systemstat::Platform;
+
+use crate::broker::process::fd_usage;
+use crate::broker::types::*;
+use crate::{ClientId, Id, MqttError, NodeId, Result, Runtime, TimestampMillis};
+
+pub const HIGH_MEMORY: &str = "high_memory";
+pub const FD_EXHAUSTION: &str = "fd_exhaustion";
+pub const QUEUE_OVERFLOW: &str = "queue_overflow";
+pub const CLUSTER_PARTITION: &str = "cluster_partition";
+pub const CERTIFICATE_EXPIRING: &str = "certificate_expiring";
+pub const SUBSCRIPTION_LIMIT: &str = "subscription_limit";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Alarm {
+    pub name: String,
+    pub message: String,
+    pub node_id: NodeId,
+    pub activated_at: TimestampMillis,
+    pub deactivated_at: Option<TimestampMillis>,
+}
+
+impl Alarm {
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        self.deactivated_at.is_none()
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "name": self.name,
+            "message": self.message,
+            "node_id": self.node_id,
+            "activated": self.is_active(),
+            "activated_at": self.activated_at,
+            "deactivated_at": self.deactivated_at,
+        })
+    }
+}
+
+///Named alarms of this node, activating or deactivating an alarm fires the
+///alarm_activated/alarm_deactivated hook and publishes it to
+///"$SYS/brokers/{node}/alarms/activate" or "$SYS/brokers/{node}/alarms/deactivate"
+pub struct Alarms {
+    actives: DashMap<String, Alarm>,
+    deactivateds: RwLock<VecDeque<Alarm>>,
+    queue_overflows: AtomicUsize,
+}
+
+impl Alarms {
+    #[inline]
+    pub fn instance() -> &'static Alarms {
+        static INSTANCE: OnceCell<Alarms> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self {
+            actives: DashMap::default(),
+            deactivateds: RwLock::new(VecDeque::new()),
+            queue_overflows: AtomicUsize::new(0),
+        })
+    }
+
+    ///Activate the alarm, returns false if it is already active, only the message is updated then
+    #[inline]
+    pub async fn activate<N: Into<String>, M: Into<String>>(&self, name: N, message: M) -> bool {
+        let name = name.into();
+        let message = message.into();
+        if let Some(mut alarm) = self.actives.get_mut(&name) {
+            alarm.message = message;
+            return false;
+        }
+        let alarm = Alarm {
+            name: name.clone(),
+            message,
+            node_id: Runtime::instance().node.id(),
+            activated_at: chrono::Local::now().timestamp_millis(),
+            deactivated_at: None,
+        };
+        log::warn!("alarm activated, {}: {}", alarm.name, alarm.message);
+        self.actives.insert(name, alarm.clone());
+        Runtime::instance().extends.hook_mgr().await.alarm_activated(&alarm).await;
+        publish_sys("activate", &alarm).await;
+        true
+    }
+
+    ///Deactivate the alarm, returns false if it is not active
+    #[inline]
+    pub async fn deactivate(&self, name: &str) -> bool {
+        let mut alarm = if let Some((_, alarm)) = self.actives.remove(name) {
+            alarm
+        } else {
+            return false;
+        };
+        alarm.deactivated_at = Some(chrono::Local::now().timestamp_millis());
+        log::info!("alarm deactivated, {}", alarm.name);
+        {
+            let mut deactivateds = self.deactivateds.write();
+            deactivateds.push_back(alarm.clone());
+            while deactivateds.len() > Runtime::instance().settings.alarm.history_max {
+                deactivateds.pop_front();
+            }
+        }
+        Runtime::instance().extends.hook_mgr().await.alarm_deactivated(&alarm).await;
+        publish_sys("deactivate", &alarm).await;
+        true
+    }
+
+    #[inline]
+    pub fn is_active(&self, name: &str) -> bool {
+        self.actives.contains_key(name)
+    }
+
+    #[inline]
+    pub fn actives(&self) -> Vec<Alarm> {
+        self.actives.iter().map(|a| a.value().clone()).collect()
+    }
+
+    #[inline]
+    pub fn deactivateds(&self) -> Vec<Alarm> {
+        self.deactivateds.read().iter().cloned().collect()
+    }
+
+    #[inline]
+    pub fn clear_deactivateds(&self) -> usize {
+        let mut deactivateds = self.deactivateds.write();
+        let c = deactivateds.len();
+        deactivateds.clear();
+        c
+    }
+
+    ///A message is dropped because the message queue of a session is full,
+    ///the queue_overflow alarm is activated on the next check
+    #[inline]
+    pub fn queue_overflowed(&self) {
+        self.queue_overflows.fetch_add(1, Ordering::Relaxed);
+    }
+
+    ///Start the periodic resource checks
+    pub fn start_monitor(&'static self) {
+        let interval = Runtime::instance().settings.alarm.check_interval;
+        if interval.is_zero() {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                self.check().await;
+            }
+        });
+    }
+
+    async fn check(&self) {
+        let cfg = &Runtime::instance().settings.alarm;
+
+        if let Ok(mem) = systemstat::System::new().memory() {
+            let used = systemstat::saturating_sub_bytes(mem.total, mem.free).as_u64();
+            let ratio = used as f64 / mem.total.as_u64().max(1) as f64;
+            self.set(
+                HIGH_MEMORY,
+                ratio > cfg.memory_high_watermark,
+                format!(
+                    "memory usage {:.1}% exceeds the high watermark {:.1}%",
+                    ratio * 100.0,
+                    cfg.memory_high_watermark * 100.0
+                ),
+            )
+            .await;
+        }
+
+        if let Some((opens, max)) = fd_usage() {
+            let ratio = opens as f64 / max.max(1) as f64;
+            self.set(
+                FD_EXHAUSTION,
+                ratio > cfg.fd_high_watermark,
+                format!("{} of {} file descriptors are open", opens, max),
+            )
+            .await;
+        }
+
+        let overflows = self.queue_overflows.swap(0, Ordering::Relaxed);
+        self.set(
+            QUEUE_OVERFLOW,
+            overflows > 0,
+            format!("{} messages are dropped because the message queue is full", overflows),
+        )
+        .await;
+
+        let health = Runtime::instance().extends.shared().await.check_health().await;
+        let (partitioned, message) = match health {
+            Ok(Some(health)) => {
+                let status = health.get("status").and_then(|s| s.as_str()).unwrap_or_default();
+                let unreachables = health
+                    .get("nodes")
+                    .and_then(|nodes| nodes.as_array())
+                    .map(|nodes| nodes.iter().filter(|n| n.get("error").is_some()).count())
+                    .unwrap_or_default();
+                (
+                    status != "Ok" || unreachables > 0,
+                    format!("cluster status: {}, unreachable nodes: {}", status, unreachables),
+                )
+            }
+            Ok(None) => (false, String::new()),
+            Err(e) => (true, format!("cluster status check failed, {}", e)),
+        };
+        self.set(CLUSTER_PARTITION, partitioned, message).await;
+
+        let listeners = &Runtime::instance().settings.listeners;
+        let now = chrono::Local::now().timestamp();
+        for l in listeners.tlss.values().chain(listeners.wsss.values()) {
+            let cert = if let Some(cert) = l.cert.as_ref() {
+                cert
+            } else {
+                continue;
+            };
+            let name = format!("{}/{}", CERTIFICATE_EXPIRING, l.name);
+            match cert_not_after(cert) {
+                Ok(not_after) => {
+                    self.set(
+                        &name,
+                        not_after - now < cfg.cert_expiry_warning.as_secs() as i64,
+                        format!(
+                            "certificate {} expires at {}",
+                            cert,
+                            chrono::Local.timestamp_opt(not_after, 0).unwrap().format("%Y-%m-%d %H:%M:%S")
+                        ),
+                    )
+                    .await
+                }
+                Err(e) => log::warn!("check certificate {} error, {:?}", cert, e),
+            }
+        }
+    }
+
+    #[inline]
+    async fn set(&self, name: &str, active: bool, message: String) {
+        if active {
+            self.activate(name, message).await;
+        } else {
+            self.deactivate(name).await;
+        }
+    }
+}
+
+async fn publish_sys(action: &str, alarm: &Alarm) {
+    let payload = match serde_json::to_vec(&alarm.to_json()) {
+        Ok(payload) => payload,
+        Err(e) => {
+            log::warn!("alarm to json error, {:?}", e);
+            return;
+        }
+    };
+    let from = Id::from(alarm.node_id, ClientId::from("$SYS"));
+    let p = Publish {
+        dup: false,
+        retain: false,
+        qos: QoS::AtMostOnce,
+        topic: TopicName::from(format!("$SYS/brokers/{}/alarms/{}", alarm.node_id, action)),
+        packet_id: None,
+        payload: bytes::Bytes::from(payload),
+        properties: PublishProperties::default(),
+        create_time: chrono::Local::now().timestamp_millis(),
+        trace_context: None,
+        forward_id: None,
+    };
+    if let Err(droppeds) = Runtime::instance().extends.shared().await.forwards(from, p).await {
+        log::debug!("publish alarm {}, dropped: {}", alarm.name, droppeds.len());
+    }
+}
+
+///Expiration time of the certificate, in seconds
+fn cert_not_after(cert: &str) -> Result<i64> {
+    let data = std::fs::read(cert)?;
+    let (_, pem) = x509_parser::pem::parse_x509_pem(&data).map_err(|e| MqttError::from(e.to_string()))?;
+    let x509 = pem.parse_x509().map_err(|e| MqttError::from(e.to_string()))?;
+    Ok(x509.validity().not_after.timestamp())
+}
@@ -0,0 +1,134 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+
+use crate::broker::types::*;
+use crate::settings::Settings;
+use crate::Runtime;
+
+//Interval of sampling the retained bytes of the retain storage in use
+const RETAINED_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+///Estimated bytes of the messages held by the message queues, inflight windows and retained
+///storage of this node, checked against the configured budget to apply backpressure to publishers.
+///The retained bytes are sampled from the retain storage in use, whichever plugin provides it.
+///
+///The payload buffer is shared by the copies of a message, each copy is counted in full,
+///so the estimate errs on the high side.
+pub struct MemoryBudget {
+    queued: AtomicUsize,
+    inflight: AtomicUsize,
+    retained: AtomicUsize,
+}
+
+impl MemoryBudget {
+    #[inline]
+    pub fn instance() -> &'static MemoryBudget {
+        static INSTANCE: OnceCell<MemoryBudget> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self {
+            queued: AtomicUsize::new(0),
+            inflight: AtomicUsize::new(0),
+            retained: AtomicUsize::new(0),
+        })
+    }
+
+    ///Estimated bytes held by a message
+    #[inline]
+    pub fn size_of(p: &Publish) -> usize {
+        std::mem::size_of::<Publish>()
+            + p.topic.len()
+            + p.payload.len()
+            + p.properties.user_properties.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>()
+    }
+
+    #[inline]
+    pub fn queued_add(&self, p: &Publish) {
+        self.queued.fetch_add(Self::size_of(p), Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn queued_sub(&self, p: &Publish) {
+        Self::sub(&self.queued, Self::size_of(p));
+    }
+
+    #[inline]
+    pub fn inflight_add(&self, p: &Publish) {
+        self.inflight.fetch_add(Self::size_of(p), Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn inflight_sub(&self, p: &Publish) {
+        Self::sub(&self.inflight, Self::size_of(p));
+    }
+
+    ///Sample the retained bytes of the retain storage in use
+    #[inline]
+    pub async fn sample_retained(&self) {
+        let bytes = Runtime::instance().extends.retain().await.bytes();
+        self.retained.store(bytes, Ordering::Relaxed);
+    }
+
+    ///Sample the retained bytes periodically, so that the messages expired or removed by the
+    ///storage are no longer counted
+    pub(crate) async fn sample_retained_loop() {
+        let mut interval = tokio::time::interval(RETAINED_SAMPLE_INTERVAL);
+        loop {
+            interval.tick().await;
+            Self::instance().sample_retained().await;
+        }
+    }
+
+    #[inline]
+    fn sub(counter: &AtomicUsize, n: usize) {
+        let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| Some(v.saturating_sub(n)));
+    }
+
+    #[inline]
+    pub fn used(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+            + self.inflight.load(Ordering::Relaxed)
+            + self.retained.load(Ordering::Relaxed)
+    }
+
+    ///The used bytes reached max_bytes
+    #[inline]
+    pub fn is_exceeded(&self) -> bool {
+        let max_bytes = *Settings::instance().memory_budget.max_bytes;
+        max_bytes > 0 && self.used() >= max_bytes
+    }
+
+    #[inline]
+    fn is_resumable(&self) -> bool {
+        let cfg = &Settings::instance().memory_budget;
+        *cfg.max_bytes == 0 || (self.used() as f64) < (*cfg.max_bytes as f64 * cfg.resume_ratio)
+    }
+
+    ///Pause the caller while over budget, until the used bytes fall below the resume watermark
+    ///or pause_timeout elapses, returns false if still over budget.
+    #[inline]
+    pub async fn wait(&self) -> bool {
+        if !self.is_exceeded() {
+            return true;
+        }
+        let pause_timeout = Settings::instance().memory_budget.pause_timeout;
+        let wait = async {
+            while !self.is_resumable() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                self.sample_retained().await;
+            }
+        };
+        tokio::time::timeout(pause_timeout, wait).await.is_ok()
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "max_bytes": *Settings::instance().memory_budget.max_bytes,
+            "used": self.used(),
+            "queued": self.queued.load(Ordering::Relaxed),
+            "inflight": self.inflight.load(Ordering::Relaxed),
+            "retained": self.retained.load(Ordering::Relaxed),
+        })
+    }
+}
@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use rust_box::dequemap::DequeMap;
+
+use crate::broker::types::{ForwardId, Publish, TimestampMillis};
+
+const SHARDS: usize = 16;
+
+///The idempotency keys of the publishes forwarded to this node within the window, so that a
+///publish forwarded again, such as on a retry after an ambiguous failure, is delivered only once.
+///The keys are sharded, the forwards of different keys rarely wait for each other.
+pub struct ForwardDedup {
+    window: TimestampMillis,
+    max: usize,
+    shards: Vec<Mutex<DequeMap<ForwardId, TimestampMillis>>>,
+}
+
+impl ForwardDedup {
+    #[inline]
+    pub fn new(window: Duration, max: usize) -> Self {
+        Self {
+            window: window.as_millis() as TimestampMillis,
+            max: (max / SHARDS).max(1),
+            shards: (0..SHARDS).map(|_| Mutex::new(DequeMap::default())).collect(),
+        }
+    }
+
+    ///Same idempotency key as a publish received within the window
+    #[inline]
+    pub fn is_duplicate(&self, publish: &Publish) -> bool {
+        match publish.forward_id {
+            Some(forward_id) if self.window > 0 => {
+                self.is_seen(forward_id, chrono::Local::now().timestamp_millis())
+            }
+            _ => false,
+        }
+    }
+
+    #[inline]
+    fn is_seen(&self, forward_id: ForwardId, now: TimestampMillis) -> bool {
+        let shard = (forward_id.0 ^ forward_id.1) as usize % SHARDS;
+        let mut seen = self.shards[shard].lock();
+        while let Some((_, t)) = seen.front() {
+            if now - *t < self.window {
+                break;
+            }
+            seen.pop_front();
+        }
+        if seen.contains_key(&forward_id) {
+            return true;
+        }
+        seen.insert(forward_id, now);
+        while seen.len() > self.max {
+            seen.pop_front();
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dedup() {
+        let dedup = ForwardDedup::new(Duration::from_secs(60), SHARDS * 2);
+        assert!(!dedup.is_seen(ForwardId(1, 1), 0));
+        assert!(dedup.is_seen(ForwardId(1, 1), 1));
+        assert!(!dedup.is_seen(ForwardId(2, 1), 1));
+
+        //expired out of the window
+        assert!(!dedup.is_seen(ForwardId(1, 1), 60_000));
+
+        //evicted beyond the maximum of the shard
+        for seq in 1..=3 {
+            assert!(!dedup.is_seen(ForwardId(0, (seq * SHARDS) as u64), 60_000));
+        }
+        assert!(!dedup.is_seen(ForwardId(0, SHARDS as u64), 60_000));
+    }
+}
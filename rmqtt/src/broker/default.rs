@@ -4,7 +4,7 @@ use std::iter::Iterator;
 use std::num::NonZeroU16;
 use std::num::NonZeroU32;
 use std::str::FromStr;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use itertools::Itertools;
@@ -16,23 +16,33 @@ use tokio::sync::{self, Mutex, OwnedMutexGuard};
 use tokio::time::Duration;
 use uuid::Uuid;
 
+use crate::broker::alarm::Alarm;
+use crate::broker::budget::MemoryBudget;
 use crate::broker::fitter::{Fitter, FitterManager};
-use crate::broker::hook::{Handler, Hook, HookManager, HookResult, Parameter, Priority, Register, Type};
+use crate::broker::history::ConnectionHistory;
+use crate::broker::hook::{
+    Handler, HandlerInfo, Hook, HookManager, HookResult, Parameter, Priority, Register, Type,
+};
+use crate::broker::metrics::DroppedReason;
+use crate::broker::payload_filter::{self, PayloadJson};
 use crate::broker::session::{ClientInfo, Session, SessionOfflineInfo};
-use crate::broker::topic::{Topic, VecToTopic};
+use crate::broker::topic::{Level, ShardedTopicTree, Topic, TopicTree, VecToString};
 use crate::broker::types::*;
 use crate::settings::listener::Listener;
+use crate::settings::DuplicateClientId;
+use crate::settings::Settings;
 use crate::stats::Counter;
 use crate::{grpc, ClientId, Id, MqttError, NodeId, QoS, Result, Runtime, TopicFilter};
 
 use super::{
-    retain::RetainTree, topic::TopicTree, Entry, IsOnline, RetainStorage, Router, Shared, SharedSubscription,
-    SubRelations, SubRelationsMap,
+    retain::{MatchesWalk, RetainTree},
+    Entry, IsOnline, RetainStorage, Router, Shared, SharedSubscription, SubRelations, SubRelationsMap,
 };
 
 type DashSet<V> = dashmap::DashSet<V, ahash::RandomState>;
 type DashMap<K, V> = dashmap::DashMap<K, V, ahash::RandomState>;
 type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
+type HashSet<V> = std::collections::HashSet<V, ahash::RandomState>;
 
 pub struct LockEntry {
     id: Id,
@@ -131,6 +141,32 @@ impl super::Entry for LockEntry {
         Ok(Box::new(LockEntry::new(self.id.clone(), self.shared, Some(locker))))
     }
 
+    ///The sessions are looked up through Shared::session_status(), so on all nodes when the shared
+    ///is of a cluster. A suffixed client_id is only given if no session of it exists, online or
+    ///offline, so the persistent session of another client is never taken over.
+    #[inline]
+    async fn try_lock_connect(&self, policy: DuplicateClientId) -> Result<Box<dyn Entry>> {
+        let entry = self.try_lock().await?;
+        if policy == DuplicateClientId::Kick {
+            return Ok(entry);
+        }
+        let shared = Runtime::instance().extends.shared().await;
+        let online = shared.session_status(&self.id.client_id).await.map(|s| s.online).unwrap_or(false);
+        if !online {
+            return Ok(entry);
+        }
+        if policy == DuplicateClientId::Reject {
+            return Err(MqttError::ClientIdInUse);
+        }
+        for client_id in Id::suffixed_client_ids(&self.id.client_id) {
+            if shared.session_status(&client_id).await.is_none() {
+                log::debug!("{:?} client_id is in use, continues with {}", self.id, client_id);
+                return LockEntry::new(self.id.with_client_id(client_id), self.shared, None).try_lock().await;
+            }
+        }
+        Err(MqttError::ClientIdInUse)
+    }
+
     #[inline]
     fn id(&self) -> Id {
         self.id.clone()
@@ -262,6 +298,7 @@ impl super::Entry for LockEntry {
             .add(&sub.topic_filter, self.id(), sub.qos, sub.shared_group.clone())
             .await?;
         peer.s.subscriptions.add(sub.topic_filter.clone(), sub.qos, sub.shared_group.clone());
+        peer.s.subscriptions.set_payload_filter(&sub.topic_filter, sub.payload_filter.clone());
         Ok(SubscribeReturn::new_success(sub.qos))
     }
 
@@ -349,10 +386,45 @@ impl DefaultShared {
         self.peers.get(client_id).map(|peer| (peer.tx.clone(), peer.c.id.clone()))
     }
 
+    ///Whether the payload matches the payload filter of the subscription of the client, if any
+    #[inline]
+    pub fn payload_matches(&self, client_id: &str, topic_filter: &str, payload: &PayloadJson) -> bool {
+        if !payload_filter::is_used() {
+            return true;
+        }
+        self.peers
+            .get(client_id)
+            .map(|peer| peer.s.subscriptions.payload_matches(topic_filter, payload))
+            .unwrap_or(true)
+    }
+
     #[inline]
     pub async fn _query_subscriptions(&self, q: &SubsSearchParams) -> Vec<SubsSearchResult> {
         DefaultRouter::instance()._query_subscriptions(q).await
     }
+
+    ///hook, message_dropped, for a publish that no subscription matches. The $ topics, such as
+    ///$SYS, are left out, they are published whether anyone subscribes or not
+    #[inline]
+    pub async fn no_subscribers(from: From, publish: Publish) {
+        if publish.topic.starts_with('$') {
+            return;
+        }
+        Runtime::instance()
+            .extends
+            .hook_mgr()
+            .await
+            .message_dropped(None, from, publish, Reason::from_static(DroppedReason::NO_SUBSCRIBERS))
+            .await;
+    }
+
+    ///hook, message_dropped, for a publish that could not be routed
+    #[inline]
+    pub async fn router_error(from: From, publish: Publish, e: MqttError) {
+        log::warn!("forwards, from:{:?}, topic:{:?}, error: {:?}", from, publish.topic, e);
+        let reason = Reason::from(format!("{}, {}", DroppedReason::ROUTER_ERROR, e));
+        Runtime::instance().extends.hook_mgr().await.message_dropped(None, from, publish, reason).await;
+    }
 }
 
 #[async_trait]
@@ -369,16 +441,20 @@ impl Shared for &'static DefaultShared {
 
     #[inline]
     async fn forwards(&self, from: From, publish: Publish) -> Result<(), Vec<(To, From, Publish, Reason)>> {
-        let topic = publish.topic();
         let mut relations_map =
-            match Runtime::instance().extends.router().await.matches(publish.topic()).await {
+            match Runtime::instance().extends.router().await.matches_publish(&publish).await {
                 Ok(relations_map) => relations_map,
                 Err(e) => {
-                    log::warn!("forwards, from:{:?}, topic:{:?}, error: {:?}", from, topic, e);
-                    SubRelationsMap::default()
+                    DefaultShared::router_error(from, publish, e).await;
+                    return Ok(());
                 }
             };
 
+        if relations_map.is_empty() {
+            DefaultShared::no_subscribers(from, publish).await;
+            return Ok(());
+        }
+
         let this_node_id = Runtime::instance().node.id();
         if let Some(relations) = relations_map.remove(&this_node_id) {
             self.forwards_to(from, &publish, relations).await?;
@@ -397,11 +473,11 @@ impl Shared for &'static DefaultShared {
     ) -> Result<SubRelationsMap, Vec<(To, From, Publish, Reason)>> {
         let topic = publish.topic();
         log::debug!("forwards_and_get_shareds, from: {:?}, topic: {:?}", from, topic.to_string());
-        let relations_map = match Runtime::instance().extends.router().await.matches(topic).await {
+        let relations_map = match Runtime::instance().extends.router().await.matches_publish(&publish).await {
             Ok(relations_map) => relations_map,
             Err(e) => {
-                log::warn!("forwards, from:{:?}, topic:{:?}, error: {:?}", from, topic, e);
-                SubRelationsMap::default()
+                DefaultShared::router_error(from, publish, e).await;
+                return Ok(SubRelationsMap::default());
             }
         };
 
@@ -422,7 +498,14 @@ impl Shared for &'static DefaultShared {
             }
         }
 
-        if !relations.is_empty() {
+        if let Some((topic_filter, client_id, qos, _)) = relations.first() {
+            //one relation without a group tells the origin that this node has subscribers
+            sub_relations_map.entry(Runtime::instance().node.id()).or_default().push((
+                topic_filter.clone(),
+                client_id.clone(),
+                *qos,
+                None,
+            ));
             self.forwards_to(from, &publish, relations).await?;
         }
         Ok(sub_relations_map)
@@ -436,8 +519,13 @@ impl Shared for &'static DefaultShared {
         mut relations: SubRelations,
     ) -> Result<(), Vec<(To, From, Publish, Reason)>> {
         let mut errs = Vec::new();
+        let payload = PayloadJson::new(&publish.payload);
 
         for (topic_filter, client_id, qos, _) in relations.drain(..) {
+            if !self.payload_matches(&client_id, &topic_filter, &payload) {
+                Runtime::instance().metrics.messages_filtered_inc();
+                continue;
+            }
             let mut p = publish.clone();
             p.dup = false;
             p.retain = false;
@@ -540,12 +628,204 @@ impl Iterator for DefaultIter<'_> {
     }
 }
 
+type SharedGroups = HashMap<SharedGroup, Vec<(NodeId, ClientId, QoS, Option<IsOnline>)>>;
+
+///Subscribers resolved for a topic name, the shared subscription groups are kept unresolved
+///so that a subscriber is still chosen for each message
+pub struct Matched {
+    topic: Topic,
+    topic_filters: Vec<TopicFilter>,
+    subs: SubRelationsMap,
+    groups: Vec<(TopicFilter, Arc<SharedGroups>)>,
+    //publishes of the topic name within the second `window`, to detect the hot topic names
+    hits: AtomicUsize,
+    window: AtomicI64,
+}
+
+impl Matched {
+    ///Counts a publish, returns the publishes of the current second
+    #[inline]
+    fn hit(&self, now: i64) -> usize {
+        if self.window.swap(now, Ordering::Relaxed) != now {
+            self.hits.store(0, Ordering::Relaxed);
+        }
+        self.hits.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+///A hot topic name not published for so long is no longer kept resolved
+const HOT_TOPIC_IDLE_SECS: i64 = 60;
+
+///Cache of the subscribers resolved for the recently published topic names, an entry is
+///invalidated when a subscription of a topic filter that matches it changes.
+///
+///The topic names published at least router.hot_topic_rate times per second are hot, their
+///entries are resolved again as soon as they are invalidated rather than on the next publish,
+///and are kept when the cache is full
+pub struct MatchCache {
+    entries: DashMap<TopicName, Arc<Matched>>,
+    //topic filter -> topic names cached with it
+    refs: DashMap<TopicFilter, HashSet<TopicName>>,
+    //changed on each invalidation, a result resolved before it is not cached
+    version: AtomicUsize,
+    hot: DashSet<TopicName>,
+}
+
+impl MatchCache {
+    #[inline]
+    fn new() -> Self {
+        Self {
+            entries: DashMap::default(),
+            refs: DashMap::default(),
+            version: AtomicUsize::new(0),
+            hot: DashSet::default(),
+        }
+    }
+
+    #[inline]
+    fn get(&self, topic_name: &str) -> Option<Arc<Matched>> {
+        self.entries.get(topic_name).map(|m| m.value().clone())
+    }
+
+    ///Counts a publish of the topic name, which becomes hot once its rate reaches hot_topic_rate
+    #[inline]
+    fn hit(&self, topic_name: &TopicName, matched: &Matched) {
+        let router = &Settings::instance().router;
+        let hits = matched.hit(monotonic_secs());
+        if router.hot_topic_rate > 0
+            && hits == router.hot_topic_rate
+            && self.hot.len() < router.hot_topic_max
+            && self.hot.insert(topic_name.clone())
+        {
+            log::debug!("{:?} is a hot topic, {} publishes per second", topic_name, hits);
+        }
+    }
+
+    #[inline]
+    fn version(&self) -> usize {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    fn insert(&self, topic_name: &TopicName, matched: Arc<Matched>, version: usize) {
+        let max = Settings::instance().router.match_cache_size;
+        if max == 0 {
+            return;
+        }
+        if self.entries.len() >= max + self.hot.len() {
+            self.evict();
+        }
+        for topic_filter in matched.topic_filters.iter() {
+            self.refs.entry(topic_filter.clone()).or_default().insert(topic_name.clone());
+        }
+        //The version is checked while holding the entry, an invalidation either precedes
+        //the check or removes the entry after it is inserted
+        let entry = self.entries.entry(topic_name.clone());
+        if self.version() == version {
+            entry.insert(matched);
+        }
+    }
+
+    ///Invalidate the entries matched by the topic filter, `added` is the parsed topic filter
+    ///when it has no subscriptions before, it is then not referenced by any entry yet.
+    ///Returns the entries of the hot topic names invalidated, to be resolved again
+    #[inline]
+    fn invalidate(&self, topic_filter: &str, added: Option<&Topic>) -> Vec<(TopicName, Arc<Matched>)> {
+        self.version.fetch_add(1, Ordering::SeqCst);
+        let mut removeds = Vec::new();
+        if let Some((_, topic_names)) = self.refs.remove(topic_filter) {
+            for topic_name in topic_names {
+                removeds.extend(self.entries.remove(&topic_name));
+            }
+        }
+        if let Some(topic) = added.filter(|_| !self.entries.is_empty()) {
+            if topic.levels().iter().any(|l| matches!(l, Level::SingleWildcard | Level::MultiWildcard)) {
+                let mut tree = TopicTree::default();
+                tree.insert(topic, ());
+                self.entries.retain(|topic_name, matched| {
+                    if tree.is_match(&matched.topic) {
+                        removeds.push((topic_name.clone(), matched.clone()));
+                        false
+                    } else {
+                        true
+                    }
+                });
+            } else {
+                removeds.extend(self.entries.remove(topic_filter));
+            }
+        }
+        if self.hot.is_empty() {
+            return Vec::new();
+        }
+        let now = monotonic_secs();
+        removeds.into_iter().filter(|(topic_name, matched)| self.is_hot(topic_name, matched, now)).collect()
+    }
+
+    ///Whether the topic name is hot, one not published for HOT_TOPIC_IDLE_SECS is no longer hot
+    #[inline]
+    fn is_hot(&self, topic_name: &TopicName, matched: &Matched, now: i64) -> bool {
+        if !self.hot.contains(topic_name) {
+            return false;
+        }
+        if now - matched.window.load(Ordering::Relaxed) > HOT_TOPIC_IDLE_SECS {
+            self.hot.remove(topic_name);
+            return false;
+        }
+        true
+    }
+
+    ///Removes the entries of the topic names that are not hot, when the cache is full
+    #[inline]
+    fn evict(&self) {
+        self.version.fetch_add(1, Ordering::SeqCst);
+        let now = monotonic_secs();
+        self.entries.retain(|topic_name, matched| self.is_hot(topic_name, matched, now));
+        self.refs.retain(|_, topic_names| {
+            topic_names.retain(|topic_name| self.entries.contains_key(topic_name));
+            !topic_names.is_empty()
+        });
+    }
+
+    #[inline]
+    pub fn hot_len(&self) -> usize {
+        self.hot.len()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    #[inline]
+    pub fn clear(&self) {
+        self.version.fetch_add(1, Ordering::SeqCst);
+        self.entries.clear();
+        self.refs.clear();
+    }
+}
+
+///A subscription change applied by DefaultRouter::apply_batch
+#[derive(Debug)]
+pub enum RouteChange {
+    Add { topic_filter: TopicFilter, id: Id, qos: QoS, shared_group: Option<SharedGroup> },
+    Remove { topic_filter: TopicFilter, id: Id },
+}
+
 #[allow(clippy::type_complexity)]
 pub struct DefaultRouter {
-    pub topics: RwLock<TopicTree<()>>,
+    pub topics: ShardedTopicTree<()>,
     pub topics_count: Counter,
     pub relations: DashMap<TopicFilter, HashMap<ClientId, (Id, QoS, Option<SharedGroup>)>>,
     pub relations_count: Counter,
+    ///Members of the shared subscription groups of a topic filter, built on the first message
+    ///and reused by the following messages until the subscriptions of the topic filter change
+    pub shared_groups: DashMap<TopicFilter, Arc<SharedGroups>>,
+    pub match_cache: MatchCache,
 }
 
 impl DefaultRouter {
@@ -553,17 +833,19 @@ impl DefaultRouter {
     pub fn instance() -> &'static DefaultRouter {
         static INSTANCE: OnceCell<DefaultRouter> = OnceCell::new();
         INSTANCE.get_or_init(|| Self {
-            topics: RwLock::new(TopicTree::default()),
+            topics: ShardedTopicTree::new(Settings::instance().router.shards),
             topics_count: Counter::new(),
             relations: DashMap::default(),
             relations_count: Counter::new(),
+            shared_groups: DashMap::default(),
+            match_cache: MatchCache::new(),
         })
     }
 
     #[inline]
     pub async fn _has_matches(&self, topic: &str) -> Result<bool> {
         let topic = Topic::from_str(topic)?;
-        Ok(self.topics.read().await.is_match(&topic))
+        Ok(self.topics.is_match(&topic))
     }
 
     #[inline]
@@ -572,37 +854,98 @@ impl DefaultRouter {
         let node_id = Runtime::instance().node.id();
         let routes = self
             .topics
-            .read()
-            .await
             .matches(&topic)
-            .iter()
+            .into_iter()
             .unique()
-            .map(|(topic_filter, _)| Route { node_id, topic: topic_filter.to_topic_filter() })
+            .map(|(topic_filter, _)| Route { node_id, topic: topic_filter })
             .collect::<Vec<_>>();
         Ok(routes)
     }
 
-    #[allow(clippy::type_complexity)]
     #[inline]
     pub async fn _matches(&self, topic_name: &TopicName) -> Result<SubRelationsMap> {
-        let mut subs: SubRelationsMap = HashMap::default();
-        let topic = Topic::from_str(topic_name)?;
-        for (topic_filter, _node_ids) in self.topics.read().await.matches(&topic).iter() {
-            let topic_filter = topic_filter.to_topic_filter();
+        self._matches_with(topic_name, None).await
+    }
+
+    ///The members of this node whose payload filter does not match the publish are not chosen
+    ///from the shared subscription groups
+    #[inline]
+    pub async fn _matches_with(
+        &self,
+        topic_name: &TopicName,
+        publish: Option<&Publish>,
+    ) -> Result<SubRelationsMap> {
+        let matched = if let Some(matched) = self.match_cache.get(topic_name) {
+            self.match_cache.hit(topic_name, &matched);
+            matched
+        } else {
+            let version = self.match_cache.version();
+            let matched = Arc::new(self._resolve(topic_name)?);
+            self.match_cache.insert(topic_name, matched.clone(), version);
+            matched
+        };
+
+        let mut subs = matched.subs.clone();
+        let payload = publish.filter(|_| payload_filter::is_used()).map(|p| PayloadJson::new(&p.payload));
+        let this_node_id = Runtime::instance().node.id();
+        let shared = DefaultShared::instance();
+        //select a subscriber from shared subscribe groups
+        for (topic_filter, groups) in matched.groups.iter() {
+            for (group, s_subs) in groups.iter() {
+                log::debug!("group: {}, s_subs: {:?}", group, s_subs);
+                let filtered;
+                let s_subs = if let Some(payload) = payload.as_ref() {
+                    filtered = s_subs
+                        .iter()
+                        .filter(|(node_id, client_id, ..)| {
+                            *node_id != this_node_id
+                                || shared.payload_matches(client_id, topic_filter, payload)
+                        })
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    &filtered
+                } else {
+                    s_subs
+                };
+                if let Some((idx, is_online)) =
+                    Runtime::instance().extends.shared_subscription().await.choice(s_subs).await
+                {
+                    let (node_id, client_id, qos, _) = &s_subs[idx];
+                    subs.entry(*node_id).or_default().push((
+                        topic_filter.clone(),
+                        client_id.clone(),
+                        *qos,
+                        Some((group.clone(), is_online)),
+                    ))
+                }
+            }
+        }
 
-            let mut groups: HashMap<SharedGroup, Vec<(NodeId, ClientId, QoS, Option<IsOnline>)>> =
-                HashMap::default();
+        log::debug!("{:?} this_subs: {:?}", topic_name, subs);
+        Ok(subs)
+    }
 
-            if let Some(rels) = self.relations.get(&topic_filter) {
+    #[inline]
+    fn _resolve(&self, topic_name: &TopicName) -> Result<Matched> {
+        let topic = Topic::from_str(topic_name)?;
+        let mut topic_filters = Vec::new();
+        let mut subs: SubRelationsMap = HashMap::default();
+        let mut groups_list = Vec::new();
+        for (topic_filter, _node_ids) in self.topics.matches(&topic) {
+            let groups = if let Some(rels) = self.relations.get(&topic_filter) {
+                let cached = self.shared_groups.get(&topic_filter).map(|groups| groups.value().clone());
+                let mut groups = SharedGroups::default();
                 for (client_id, (id, qos, group)) in rels.iter() {
                     if let Some(group) = group {
-                        let router = Runtime::instance().extends.router().await;
-                        groups.entry(group.clone()).or_default().push((
-                            id.node_id,
-                            client_id.clone(),
-                            *qos,
-                            Some(router.is_online(id.node_id, client_id).await),
-                        ));
+                        if cached.is_none() {
+                            //The online status is checked by the strategy for the selected subscriber only
+                            groups.entry(group.clone()).or_default().push((
+                                id.node_id,
+                                client_id.clone(),
+                                *qos,
+                                None,
+                            ));
+                        }
                     } else {
                         subs.entry(id.node_id).or_default().push((
                             topic_filter.clone(),
@@ -612,27 +955,150 @@ impl DefaultRouter {
                         ))
                     }
                 }
+                //Cached while holding the relations, so that it is not overwritten by a stale one
+                cached.unwrap_or_else(|| {
+                    let groups = Arc::new(groups);
+                    if !groups.is_empty() {
+                        self.shared_groups.insert(topic_filter.clone(), groups.clone());
+                    }
+                    groups
+                })
+            } else {
+                continue;
+            };
+            if !groups.is_empty() {
+                groups_list.push((topic_filter.clone(), groups));
             }
+            topic_filters.push(topic_filter);
+        }
+        Ok(Matched {
+            topic,
+            topic_filters,
+            subs,
+            groups: groups_list,
+            hits: AtomicUsize::new(0),
+            window: AtomicI64::new(monotonic_secs()),
+        })
+    }
 
-            //select a subscriber from shared subscribe groups
-            for (group, mut s_subs) in groups.drain() {
-                log::debug!("group: {}, s_subs: {:?}", group, s_subs);
-                if let Some((idx, is_online)) =
-                    Runtime::instance().extends.shared_subscription().await.choice(&s_subs).await
-                {
-                    let (node_id, client_id, qos, _) = s_subs.remove(idx);
-                    subs.entry(node_id).or_default().push((
-                        topic_filter.clone(),
-                        client_id.clone(),
-                        qos,
-                        Some((group, is_online)),
-                    ))
+    ///Adds the subscription relation, returns the hot topic names to resolve again
+    #[inline]
+    fn _add(
+        &self,
+        topic_filter: &str,
+        id: Id,
+        qos: QoS,
+        shared_group: Option<SharedGroup>,
+    ) -> Result<Vec<(TopicName, Arc<Matched>)>> {
+        log::debug!("{:?} add, topic_filter: {:?}", id, topic_filter);
+        let topic = Topic::from_str(topic_filter)?;
+        //add to topic tree
+        self.topics.insert(&topic, ());
+
+        //add to subscribe relations
+        let mut added = false;
+        let mut rels = self.relations.entry(TopicFilter::from(topic_filter)).or_insert_with(|| {
+            self.topics_count.inc();
+            added = true;
+            HashMap::default()
+        });
+        let old = rels.insert(id.client_id.clone(), (id, qos, shared_group));
+        self.shared_groups.remove(topic_filter);
+        let hot_topics = self.match_cache.invalidate(topic_filter, if added { Some(&topic) } else { None });
+        drop(rels);
+
+        if old.is_none() {
+            self.relations_count.inc();
+        }
+        Ok(hot_topics)
+    }
+
+    ///Removes the subscription relation, returns whether it was removed and the hot topic names
+    ///to resolve again
+    #[inline]
+    fn _remove(&self, topic_filter: &str, id: Id) -> Result<(bool, Vec<(TopicName, Arc<Matched>)>)> {
+        log::debug!("{:?} remove, topic_filter: {:?}", id, topic_filter);
+        //Remove subscription relationship from local
+        let mut hot_topics = Vec::new();
+        let res = if let Some(mut rels) = self.relations.get_mut(topic_filter) {
+            let remove_enable = rels.value().get(&id.client_id).map(|(s_id, _, _)| {
+                if *s_id != id {
+                    log::info!("remove, input id not the same, input id: {:?}, current id: {:?}, topic_filter: {}", id, s_id, topic_filter);
+                    false
+                } else {
+                    true
                 }
+            }).unwrap_or(false);
+            if remove_enable {
+                let remove_ok = rels.value_mut().remove(&id.client_id).is_some();
+                if remove_ok {
+                    self.relations_count.dec();
+                    self.shared_groups.remove(topic_filter);
+                    hot_topics = self.match_cache.invalidate(topic_filter, None);
+                }
+                Some((rels.is_empty(), remove_ok))
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        log::debug!("{:?} remove, topic_filter: {:?}, res: {:?}", id, topic_filter, res);
+
+        let remove_ok = if let Some((is_empty, remove_ok)) = res {
+            if is_empty {
+                if self.relations.remove(topic_filter).is_some() {
+                    self.topics_count.dec();
+                }
+                let topic = Topic::from_str(topic_filter)?;
+                self.topics.remove(&topic, &());
+            }
+            remove_ok
+        } else {
+            false
+        };
+        Ok((remove_ok, hot_topics))
+    }
+
+    ///Applies the subscription changes in order, the hot topic names they invalidate are resolved
+    ///again once after the last change instead of after each one
+    #[inline]
+    pub fn apply_batch(&self, changes: Vec<RouteChange>) {
+        let mut hot_topics = Vec::new();
+        for change in changes {
+            let res = match &change {
+                RouteChange::Add { topic_filter, id, qos, shared_group } => {
+                    self._add(topic_filter, id.clone(), *qos, shared_group.clone())
+                }
+                RouteChange::Remove { topic_filter, id } => {
+                    self._remove(topic_filter, id.clone()).map(|(_, hot_topics)| hot_topics)
+                }
+            };
+            match res {
+                Ok(hots) => hot_topics.extend(hots),
+                Err(e) => log::warn!("apply_batch, {:?}, {:?}", change, e),
             }
         }
+        self.refresh_hot(hot_topics);
+    }
 
-        log::debug!("{:?} this_subs: {:?}", topic_name, subs);
-        Ok(subs)
+    ///Resolves again the hot topic names invalidated by a subscription change, so that their
+    ///next publish does not have to
+    #[inline]
+    fn refresh_hot(&self, hot_topics: Vec<(TopicName, Arc<Matched>)>) {
+        for (topic_name, old) in hot_topics {
+            let version = self.match_cache.version();
+            match self._resolve(&topic_name) {
+                Ok(matched) => {
+                    //The rate is kept, so that a hot topic name no longer published becomes idle
+                    matched.window.store(old.window.load(Ordering::Relaxed), Ordering::Relaxed);
+                    matched.hits.store(old.hits.load(Ordering::Relaxed), Ordering::Relaxed);
+                    self.match_cache.insert(&topic_name, Arc::new(matched), version)
+                }
+                Err(e) => log::warn!("{:?} resolve hot topic error, {:?}", topic_name, e),
+            }
+        }
     }
 
     #[inline]
@@ -721,13 +1187,10 @@ impl DefaultRouter {
         let mut curr: usize = 0;
 
         self.topics
-            .read()
-            .await
             .matches(&topic)
-            .iter()
+            .into_iter()
             .unique()
             .flat_map(|(topic_filter, _)| {
-                let topic_filter = topic_filter.to_topic_filter();
                 if let Some(entry) = self.relations.get(&topic_filter) {
                     entry
                         .iter()
@@ -817,68 +1280,15 @@ impl Router for &'static DefaultRouter {
         qos: QoS,
         shared_group: Option<SharedGroup>,
     ) -> Result<()> {
-        log::debug!("{:?} add, topic_filter: {:?}", id, topic_filter);
-        let topic = Topic::from_str(topic_filter)?;
-        //add to topic tree
-        self.topics.write().await.insert(&topic, ());
-
-        //add to subscribe relations
-        let old = self
-            .relations
-            .entry(TopicFilter::from(topic_filter))
-            .or_insert_with(|| {
-                self.topics_count.inc();
-                HashMap::default()
-            })
-            .insert(id.client_id.clone(), (id, qos, shared_group));
-
-        if old.is_none() {
-            self.relations_count.inc();
-        }
-
+        let hot_topics = self._add(topic_filter, id, qos, shared_group)?;
+        self.refresh_hot(hot_topics);
         Ok(())
     }
 
     #[inline]
     async fn remove(&self, topic_filter: &str, id: Id) -> Result<bool> {
-        log::debug!("{:?} remove, topic_filter: {:?}", id, topic_filter);
-        //Remove subscription relationship from local
-        let res = if let Some(mut rels) = self.relations.get_mut(topic_filter) {
-            let remove_enable = rels.value().get(&id.client_id).map(|(s_id, _, _)| {
-                if *s_id != id {
-                    log::info!("remove, input id not the same, input id: {:?}, current id: {:?}, topic_filter: {}", id, s_id, topic_filter);
-                    false
-                } else {
-                    true
-                }
-            }).unwrap_or(false);
-            if remove_enable {
-                let remove_ok = rels.value_mut().remove(&id.client_id).is_some();
-                if remove_ok {
-                    self.relations_count.dec();
-                }
-                Some((rels.is_empty(), remove_ok))
-            } else {
-                None
-            }
-        } else {
-            None
-        };
-
-        log::debug!("{:?} remove, topic_filter: {:?}, res: {:?}", id, topic_filter, res);
-
-        let remove_ok = if let Some((is_empty, remove_ok)) = res {
-            if is_empty {
-                if self.relations.remove(topic_filter).is_some() {
-                    self.topics_count.dec();
-                }
-                let topic = Topic::from_str(topic_filter)?;
-                self.topics.write().await.remove(&topic, &());
-            }
-            remove_ok
-        } else {
-            false
-        };
+        let (remove_ok, hot_topics) = self._remove(topic_filter, id)?;
+        self.refresh_hot(hot_topics);
         Ok(remove_ok)
     }
 
@@ -887,6 +1297,11 @@ impl Router for &'static DefaultRouter {
         Ok(self._matches(topic).await?)
     }
 
+    #[inline]
+    async fn matches_publish(&self, publish: &Publish) -> Result<SubRelationsMap> {
+        Ok(self._matches_with(publish.topic(), Some(publish)).await?)
+    }
+
     #[inline]
     async fn gets(&self, limit: usize) -> Vec<Route> {
         let mut curr: usize = 0;
@@ -916,13 +1331,10 @@ impl Router for &'static DefaultRouter {
         let topic = Topic::from_str(topic)?;
         let routes = self
             .topics
-            .read()
-            .await
             .matches(&topic)
-            .iter()
+            .into_iter()
             .unique()
             .flat_map(|(topic_filter, _)| {
-                let topic_filter = topic_filter.to_topic_filter();
                 if let Some(entry) = self.relations.get(&topic_filter) {
                     entry
                         .iter()
@@ -940,7 +1352,7 @@ impl Router for &'static DefaultRouter {
 
     #[inline]
     async fn topics_tree(&self) -> usize {
-        self.topics.read().await.values_size()
+        self.topics.values_size()
     }
 
     #[inline]
@@ -983,7 +1395,7 @@ impl Router for &'static DefaultRouter {
 
     #[inline]
     async fn list_topics(&self, top: usize) -> Vec<String> {
-        self.topics.read().await.list(top)
+        self.topics.list(top)
     }
 
     #[inline]
@@ -1024,28 +1436,51 @@ impl DefaultSharedSubscription {
 #[async_trait]
 impl SharedSubscription for &'static DefaultSharedSubscription {}
 
+///Nodes of the retained messages tree visited per hold of its read lock by a lookup
+const RETAIN_WALK_CHUNK: usize = 10_000;
+
 pub struct DefaultRetainStorage {
     messages: RwLock<RetainTree<TimedValue<Retain>>>,
+    bytes: AtomicUsize,
 }
 
 impl DefaultRetainStorage {
     #[inline]
     pub fn instance() -> &'static DefaultRetainStorage {
         static INSTANCE: OnceCell<DefaultRetainStorage> = OnceCell::new();
-        INSTANCE.get_or_init(|| Self { messages: RwLock::new(RetainTree::default()) })
+        INSTANCE
+            .get_or_init(|| Self { messages: RwLock::new(RetainTree::default()), bytes: AtomicUsize::new(0) })
     }
 
+    ///Returns the topics of the removed messages
     #[inline]
-    pub async fn remove_expired_messages(&self) {
+    pub async fn remove_expired_messages(&self) -> Vec<TopicName> {
+        let mut removeds = Vec::new();
         let mut messages = self.messages.write().await;
         messages.retain(|tv| {
             if tv.is_expired() {
                 Runtime::instance().stats.retaineds.dec();
+                self.bytes_sub(&tv.value().publish);
+                removeds.push(tv.value().publish.topic.clone());
                 false
             } else {
                 true
             }
         });
+        removeds
+    }
+
+    ///Walk the retained messages that are not expired, including those of the topics starting with $
+    #[inline]
+    pub async fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&Retain),
+    {
+        self.messages.read().await.values_with(|tv| {
+            if !tv.is_expired() {
+                f(tv.value())
+            }
+        });
     }
 
     #[inline]
@@ -1058,7 +1493,11 @@ impl DefaultRetainStorage {
         let topic = Topic::from_str(topic)?;
         let mut messages = self.messages.write().await;
         let old = messages.remove(&topic);
+        if let Some(old) = old.as_ref() {
+            self.bytes_sub(&old.value().publish);
+        }
         if !retain.publish.is_empty() {
+            self.bytes.fetch_add(MemoryBudget::size_of(&retain.publish), Ordering::Relaxed);
             messages.insert(&topic, TimedValue::new(retain, timeout));
             if old.is_none() {
                 Runtime::instance().stats.retaineds.inc();
@@ -1068,6 +1507,25 @@ impl DefaultRetainStorage {
         }
         Ok(())
     }
+
+    ///Removes the retained message of the topic, returns whether there was one
+    #[inline]
+    pub async fn remove(&self, topic: &TopicName) -> Result<bool> {
+        let topic = Topic::from_str(topic)?;
+        if let Some(old) = self.messages.write().await.remove(&topic) {
+            self.bytes_sub(&old.value().publish);
+            Runtime::instance().stats.retaineds.dec();
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    #[inline]
+    fn bytes_sub(&self, p: &Publish) {
+        let n = MemoryBudget::size_of(p);
+        let _ = self.bytes.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| Some(v.saturating_sub(n)));
+    }
 }
 
 #[async_trait]
@@ -1080,20 +1538,21 @@ impl RetainStorage for &'static DefaultRetainStorage {
     #[inline]
     async fn get(&self, topic_filter: &TopicFilter) -> Result<Vec<(TopicName, Retain)>> {
         let topic = Topic::from_str(topic_filter)?;
-        let retains = self
-            .messages
-            .read()
-            .await
-            .matches(&topic)
-            .drain(..)
-            .filter_map(|(t, r)| {
-                if r.is_expired() {
-                    None
-                } else {
-                    Some((TopicName::from(t.to_string()), r.into_value()))
+        let mut retains = Vec::new();
+        //the read lock is released between the chunks, so a filter such as # over many retained
+        //messages does not hold off the writers until the whole tree is walked
+        let mut walk = MatchesWalk::new(&topic);
+        loop {
+            self.messages.read().await.matches_walk(&mut walk, RETAIN_WALK_CHUNK, |levels, r| {
+                if !r.is_expired() {
+                    retains.push((TopicName::from(levels.to_string()), r.value().clone()));
                 }
-            })
-            .collect::<Vec<(TopicName, Retain)>>();
+            });
+            if walk.is_done() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
         Ok(retains)
     }
 
@@ -1106,6 +1565,11 @@ impl RetainStorage for &'static DefaultRetainStorage {
     fn max(&self) -> isize {
         Runtime::instance().stats.retaineds.max()
     }
+
+    #[inline]
+    fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
 }
 
 pub struct DefaultFitterManager {}
@@ -1142,11 +1606,19 @@ impl DefaultFitter {
 impl Fitter for DefaultFitter {
     #[inline]
     fn keep_alive(&self, keep_alive: &mut u16) -> Result<u16> {
+        let is_v5 = self.client.protocol() == MQTT_LEVEL_5;
+        if let (true, Some(server_keepalive)) = (is_v5, self.listen_cfg.server_keepalive) {
+            *keep_alive = server_keepalive;
+        }
+        //0 disables the keepalive, it exceeds any maximum
+        if is_v5 && *keep_alive == 0 && self.listen_cfg.max_keepalive > 0 {
+            *keep_alive = self.listen_cfg.max_keepalive;
+        }
         if *keep_alive == 0 {
             return Err(MqttError::from("Keepalive must be greater than 0"));
         }
         if *keep_alive < self.listen_cfg.min_keepalive {
-            if self.client.protocol() == MQTT_LEVEL_5 {
+            if is_v5 {
                 *keep_alive = self.listen_cfg.min_keepalive;
             } else {
                 return Err(MqttError::from(format!(
@@ -1155,7 +1627,18 @@ impl Fitter for DefaultFitter {
                 )));
             }
         }
-        Ok(((*keep_alive as f32 * self.listen_cfg.keepalive_backoff) * 2.0) as u16)
+        if self.listen_cfg.max_keepalive > 0 && *keep_alive > self.listen_cfg.max_keepalive {
+            if is_v5 {
+                *keep_alive = self.listen_cfg.max_keepalive;
+            } else {
+                return Err(MqttError::from(format!(
+                    "Keepalive is too large, cannot be greater than {}",
+                    self.listen_cfg.max_keepalive
+                )));
+            }
+        }
+        let factor = self.listen_cfg.keepalive_factor.unwrap_or(self.listen_cfg.keepalive_backoff * 2.0);
+        Ok((*keep_alive as f32 * factor) as u16)
     }
 
     #[inline]
@@ -1176,20 +1659,29 @@ impl Fitter for DefaultFitter {
             None
         };
 
-        receive_max.unwrap_or_else(|| {
-            let max_inflight =
-                if self.listen_cfg.max_inflight == 0 { 16 } else { self.listen_cfg.max_inflight };
-            NonZeroU16::new(max_inflight as u16).unwrap()
-        })
+        //The Receive Maximum of the client is capped by the configured max_inflight
+        let max_inflight = NonZeroU16::new(self.listen_cfg.max_inflight.min(u16::MAX as usize) as u16);
+        match (receive_max, max_inflight) {
+            (Some(receive_max), Some(max_inflight)) => receive_max.min(max_inflight),
+            (Some(receive_max), None) => receive_max,
+            (None, Some(max_inflight)) => max_inflight,
+            (None, None) => NonZeroU16::new(16).unwrap(),
+        }
+    }
+
+    #[inline]
+    fn max_subscriptions(&self) -> usize {
+        self.listen_cfg.max_subscriptions
     }
 
     #[inline]
     fn session_expiry_interval(&self) -> Duration {
-        if let ConnectInfo::V5(_, connect) = &self.client.connect_info {
+        let interval = if let ConnectInfo::V5(_, connect) = &self.client.connect_info {
             Duration::from_secs(connect.session_expiry_interval_secs.unwrap_or_default() as u64)
         } else {
             self.listen_cfg.session_expiry_interval
-        }
+        };
+        self.listen_cfg.session_expiry_interval_limit(interval)
     }
 
     #[inline]
@@ -1212,16 +1704,35 @@ impl Fitter for DefaultFitter {
             self.listen_cfg.max_packet_size.as_u32()
         }
     }
+
+    #[inline]
+    fn publish_rate_limit(&self) -> PublishRateLimit {
+        if let Some(rate_limit) = self.client.publish_rate_limit {
+            return rate_limit;
+        }
+        let mqtt = &Runtime::instance().settings.mqtt;
+        let bytes = self.listen_cfg.max_publish_bytes_rate.as_ref().unwrap_or(&mqtt.max_publish_bytes_rate);
+        let burst_bytes =
+            self.listen_cfg.max_publish_bytes_burst.as_ref().unwrap_or(&mqtt.max_publish_bytes_burst);
+        PublishRateLimit {
+            messages: self.listen_cfg.max_publish_rate.unwrap_or(mqtt.max_publish_rate),
+            bytes: **bytes as u64,
+            burst_messages: self.listen_cfg.max_publish_burst.unwrap_or(mqtt.max_publish_burst),
+            burst_bytes: **burst_bytes as u64,
+            dry_run: self.listen_cfg.publish_rate_limit_dry_run.unwrap_or(mqtt.publish_rate_limit_dry_run),
+        }
+    }
 }
 
 struct HookEntry {
     handler: Box<dyn Handler>,
+    owner: String,
     enabled: bool,
 }
 
 impl HookEntry {
-    fn new(handler: Box<dyn Handler>) -> Self {
-        Self { handler, enabled: false }
+    fn new(handler: Box<dyn Handler>, owner: String) -> Self {
+        Self { handler, owner, enabled: false }
     }
 }
 
@@ -1231,17 +1742,27 @@ type HandlerId = String;
 pub struct DefaultHookManager {
     #[allow(clippy::type_complexity)]
     handlers: Arc<DashMap<Type, Arc<sync::RwLock<BTreeMap<(Priority, HandlerId), HookEntry>>>>>,
+    //owner, the enabled status changes staged during a handover, in order
+    #[allow(clippy::type_complexity)]
+    handovers: DashMap<String, Vec<(Type, (Priority, HandlerId), bool)>>,
 }
 
 impl DefaultHookManager {
     #[inline]
     pub fn instance() -> &'static DefaultHookManager {
         static INSTANCE: OnceCell<DefaultHookManager> = OnceCell::new();
-        INSTANCE.get_or_init(|| Self { handlers: Arc::new(DashMap::default()) })
+        INSTANCE
+            .get_or_init(|| Self { handlers: Arc::new(DashMap::default()), handovers: DashMap::default() })
     }
 
     #[inline]
-    async fn add(&self, typ: Type, priority: Priority, handler: Box<dyn Handler>) -> Result<HandlerId> {
+    async fn add(
+        &self,
+        typ: Type,
+        priority: Priority,
+        handler: Box<dyn Handler>,
+        owner: String,
+    ) -> Result<HandlerId> {
         let id = Uuid::new_v4().as_simple().encode_lower(&mut Uuid::encode_buffer()).to_string();
         let type_handlers =
             self.handlers.entry(typ).or_insert(Arc::new(sync::RwLock::new(BTreeMap::default())));
@@ -1251,11 +1772,21 @@ impl DefaultHookManager {
         if contains_key {
             Err(MqttError::from(format!("handler id is repetition, key is {:?}, type is {:?}", key, typ)))
         } else {
-            type_handlers.insert(key, HookEntry::new(handler));
+            type_handlers.insert(key, HookEntry::new(handler, owner));
             Ok(id)
         }
     }
 
+    ///Whether an enabled handler of the hook type is registered, taken as registered while its
+    ///handlers are being changed
+    #[inline]
+    fn is_registered(&self, t: Type) -> bool {
+        self.handlers
+            .get(&t)
+            .map(|h| h.value().try_read().map(|h| h.values().any(|entry| entry.enabled)).unwrap_or(true))
+            .unwrap_or(false)
+    }
+
     #[inline]
     async fn exec<'a>(&'a self, t: Type, p: Parameter<'a>) -> Option<HookResult> {
         let mut acc = None;
@@ -1284,8 +1815,54 @@ impl HookManager for &'static DefaultHookManager {
     }
 
     #[inline]
-    fn register(&self) -> Box<dyn Register> {
-        Box::new(DefaultHookRegister::new(self))
+    fn register_for(&self, owner: &str) -> Box<dyn Register> {
+        Box::new(DefaultHookRegister::new(self, owner))
+    }
+
+    #[inline]
+    async fn handlers(&self) -> Vec<HandlerInfo> {
+        let types = self.handlers.iter().map(|h| (*h.key(), h.value().clone())).collect::<Vec<_>>();
+        let mut infos = Vec::new();
+        for (typ, type_handlers) in types {
+            for ((priority, id), entry) in type_handlers.read().await.iter().rev() {
+                infos.push(HandlerInfo {
+                    typ,
+                    priority: *priority,
+                    id: id.clone(),
+                    owner: entry.owner.clone(),
+                    enabled: entry.enabled,
+                });
+            }
+        }
+        infos
+    }
+
+    #[inline]
+    async fn handover_begin(&self, owner: &str) {
+        self.handovers.entry(owner.into()).or_default();
+    }
+
+    #[inline]
+    async fn handover_end(&self, owner: &str) {
+        let staged = match self.handovers.remove(owner) {
+            Some((_, staged)) => staged,
+            None => return,
+        };
+        let mut by_type: HashMap<Type, Vec<((Priority, HandlerId), bool)>> = HashMap::default();
+        for (typ, key, enabled) in staged {
+            by_type.entry(typ).or_default().push((key, enabled));
+        }
+        for (typ, changes) in by_type {
+            let type_handlers = self.handlers.get(&typ).map(|h| (*h.value()).clone());
+            if let Some(type_handlers) = type_handlers {
+                let mut type_handlers = type_handlers.write().await;
+                for (key, enabled) in changes {
+                    if let Some(entry) = type_handlers.get_mut(&key) {
+                        entry.enabled = enabled;
+                    }
+                }
+            }
+        }
     }
 
     #[inline]
@@ -1309,7 +1886,7 @@ impl HookManager for &'static DefaultHookManager {
         &self,
         connect_info: &ConnectInfo,
         allow_anonymous: bool,
-    ) -> (ConnectAckReason, Superuser) {
+    ) -> (ConnectAckReason, Superuser, Option<PublishRateLimit>) {
         let proto_ver = connect_info.proto_ver();
         let ok = || match proto_ver {
             MQTT_LEVEL_31 => ConnectAckReason::V3(ConnectAckReasonV3::ConnectionAccepted),
@@ -1320,7 +1897,7 @@ impl HookManager for &'static DefaultHookManager {
 
         log::debug!("{:?} username: {:?}", connect_info.id(), connect_info.username());
         if connect_info.username().is_none() && allow_anonymous {
-            return (ok(), false);
+            return (ok(), false, None);
         }
 
         let result = self.exec(Type::ClientAuthenticate, Parameter::ClientAuthenticate(connect_info)).await;
@@ -1328,11 +1905,14 @@ impl HookManager for &'static DefaultHookManager {
         let (bad_user_or_pass, not_auth) = match result {
             Some(HookResult::AuthResult(AuthResult::BadUsernameOrPassword)) => (true, false),
             Some(HookResult::AuthResult(AuthResult::NotAuthorized)) => (false, true),
-            Some(HookResult::AuthResult(AuthResult::Allow(superuser))) => return (ok(), superuser),
+            Some(HookResult::AuthResult(AuthResult::Allow(superuser))) => return (ok(), superuser, None),
+            Some(HookResult::AuthResult(AuthResult::AllowWithRateLimit(superuser, rate_limit))) => {
+                return (ok(), superuser, Some(rate_limit))
+            }
             _ => {
                 //or AuthResult::NotFound
                 if allow_anonymous {
-                    return (ok(), false);
+                    return (ok(), false, None);
                 } else {
                     (false, true)
                 }
@@ -1348,6 +1928,7 @@ impl HookManager for &'static DefaultHookManager {
                     _ => ConnectAckReason::V3(ConnectAckReasonV3::BadUserNameOrPassword),
                 },
                 false,
+                None,
             );
         }
 
@@ -1360,10 +1941,11 @@ impl HookManager for &'static DefaultHookManager {
                     _ => ConnectAckReason::V3(ConnectAckReasonV3::NotAuthorized),
                 },
                 false,
+                None,
             );
         }
 
-        (ok(), false)
+        (ok(), false, None)
     }
 
     ///When sending mqtt:: connectack message
@@ -1375,18 +1957,40 @@ impl HookManager for &'static DefaultHookManager {
         let result =
             self.exec(Type::ClientConnack, Parameter::ClientConnack(connect_info, &return_code)).await;
         log::debug!("{:?} result: {:?}", connect_info.id(), result);
-        if let Some(HookResult::ConnectAckReason(new_return_code)) = result {
+        let return_code = if let Some(HookResult::ConnectAckReason(new_return_code)) = result {
             new_return_code
         } else {
             return_code
+        };
+        if !return_code.success() {
+            ConnectionHistory::instance().connect_failed(connect_info.id(), &return_code);
         }
+        return_code
     }
 
     ///Publish message Dropped
     async fn message_dropped(&self, to: Option<To>, from: From, publish: Publish, reason: Reason) {
+        slog::debug!(Runtime::instance().logger, "message dropped";
+            "clientid" => %to.as_ref().map(|to| &to.client_id).unwrap_or(&from.client_id),
+            "topic" => %publish.topic, "reason" => %reason);
         let _ = self.exec(Type::MessageDropped, Parameter::MessageDropped(to, from, publish, reason)).await;
     }
 
+    ///Cached authentication and ACL decisions invalidated
+    async fn client_acl_invalidate(&self, s: &Session, c: &ClientInfo) {
+        let _ = self.exec(Type::ClientAclInvalidate, Parameter::ClientAclInvalidate(s, c)).await;
+    }
+
+    ///Alarm activated
+    async fn alarm_activated(&self, alarm: &Alarm) {
+        let _ = self.exec(Type::AlarmActivated, Parameter::AlarmActivated(alarm)).await;
+    }
+
+    ///Alarm deactivated
+    async fn alarm_deactivated(&self, alarm: &Alarm) {
+        let _ = self.exec(Type::AlarmDeactivated, Parameter::AlarmDeactivated(alarm)).await;
+    }
+
     ///grpc message received
     async fn grpc_message_received(
         &self,
@@ -1404,17 +2008,25 @@ impl HookManager for &'static DefaultHookManager {
 
 pub struct DefaultHookRegister {
     manager: &'static DefaultHookManager,
+    owner: String,
     type_ids: Arc<DashSet<(Type, (Priority, HandlerId))>>,
 }
 
 impl DefaultHookRegister {
     #[inline]
-    fn new(manager: &'static DefaultHookManager) -> Self {
-        DefaultHookRegister { manager, type_ids: Arc::new(DashSet::default()) }
+    fn new(manager: &'static DefaultHookManager, owner: &str) -> Self {
+        DefaultHookRegister { manager, owner: owner.into(), type_ids: Arc::new(DashSet::default()) }
     }
 
     #[inline]
     async fn adjust_status(&self, b: bool) {
+        if let Some(mut staged) = self.manager.handovers.get_mut(&self.owner) {
+            staged.extend(self.type_ids.iter().map(|type_id| {
+                let (typ, key) = type_id.key();
+                (*typ, key.clone(), b)
+            }));
+            return;
+        }
         for type_id in self.type_ids.iter() {
             let (typ, key) = type_id.key();
             if let Some(type_handlers) = self.manager.handlers.get(typ) {
@@ -1432,7 +2044,7 @@ impl DefaultHookRegister {
 impl Register for DefaultHookRegister {
     #[inline]
     async fn add_priority(&self, typ: Type, priority: Priority, handler: Box<dyn Handler>) {
-        match self.manager.add(typ, priority, handler).await {
+        match self.manager.add(typ, priority, handler, self.owner.clone()).await {
             Ok(id) => {
                 self.type_ids.insert((typ, (priority, id)));
             }
@@ -1453,6 +2065,25 @@ impl Register for DefaultHookRegister {
     }
 }
 
+impl Drop for DefaultHookRegister {
+    //Remove the handlers of a dropped plugin instance, such as the one replaced on reload
+    fn drop(&mut self) {
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let manager = self.manager;
+            let type_ids = self.type_ids.clone();
+            handle.spawn(async move {
+                for type_id in type_ids.iter() {
+                    let (typ, key) = type_id.key();
+                    let type_handlers = manager.handlers.get(typ).map(|h| (*h.value()).clone());
+                    if let Some(type_handlers) = type_handlers {
+                        type_handlers.write().await.remove(key);
+                    }
+                }
+            });
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct DefaultHook {
     manager: &'static DefaultHookManager,
@@ -1469,18 +2100,40 @@ impl DefaultHook {
 
 #[async_trait]
 impl Hook for DefaultHook {
+    #[inline]
+    fn is_registered(&self, typ: Type) -> bool {
+        self.manager.is_registered(typ)
+    }
+
     #[inline]
     async fn session_created(&self) {
         self.manager.exec(Type::SessionCreated, Parameter::SessionCreated(&self.s, &self.c)).await;
     }
 
+    #[inline]
+    async fn client_tagging(&self) {
+        let reply = self.manager.exec(Type::ClientTagging, Parameter::ClientTagging(&self.s, &self.c)).await;
+        if let Some(HookResult::Tags(tags)) = reply {
+            log::debug!("{:?} tags: {:?}", self.s.id, tags);
+            self.c.set_tags(tags);
+        }
+    }
+
     #[inline]
     async fn client_connected(&self) {
+        slog::debug!(Runtime::instance().logger, "client connected";
+            "clientid" => %self.s.id.client_id, "listener" => %self.s.listen_cfg.name);
+        ConnectionHistory::instance().connected(&self.s.id);
         let _ = self.manager.exec(Type::ClientConnected, Parameter::ClientConnected(&self.s, &self.c)).await;
     }
 
     #[inline]
     async fn client_disconnected(&self, r: Reason) {
+        let kind = self.c.disconnected_kind();
+        slog::debug!(Runtime::instance().logger, "client disconnected";
+            "clientid" => %self.s.id.client_id, "listener" => %self.s.listen_cfg.name,
+            "kind" => ?kind, "reason" => %r);
+        ConnectionHistory::instance().disconnected(&self.s.id, kind, &r);
         let _ = self
             .manager
             .exec(Type::ClientDisconnected, Parameter::ClientDisconnected(&self.s, &self.c, r))
@@ -1489,12 +2142,19 @@ impl Hook for DefaultHook {
 
     #[inline]
     async fn session_terminated(&self, r: Reason) {
+        slog::debug!(Runtime::instance().logger, "session terminated";
+            "clientid" => %self.s.id.client_id, "listener" => %self.s.listen_cfg.name, "reason" => %r);
         let _ = self
             .manager
             .exec(Type::SessionTerminated, Parameter::SessionTerminated(&self.s, &self.c, r))
             .await;
     }
 
+    #[inline]
+    async fn client_slow(&self, slow: &SlowSubscriber) {
+        let _ = self.manager.exec(Type::ClientSlow, Parameter::ClientSlow(&self.s, &self.c, slow)).await;
+    }
+
     #[inline]
     async fn client_subscribe_check_acl(&self, sub: &Subscribe) -> Option<SubscribeAclResult> {
         if self.c.superuser {
@@ -1512,6 +2172,14 @@ impl Hook for DefaultHook {
         }
     }
 
+    #[inline]
+    async fn subscribe_authorized(&self, sub: &Subscribe) {
+        let _ = self
+            .manager
+            .exec(Type::SubscribeAuthorized, Parameter::SubscribeAuthorized(&self.s, &self.c, sub))
+            .await;
+    }
+
     #[inline]
     async fn message_publish_check_acl(&self, publish: &Publish) -> PublishAclResult {
         if self.c.superuser {
@@ -1628,4 +2296,12 @@ impl Hook for DefaultHook {
         }
         true
     }
+
+    #[inline]
+    async fn message_offline(&self, from: From, publish: &Publish) {
+        let _ = self
+            .manager
+            .exec(Type::MessageOffline, Parameter::MessageOffline(&self.s, &self.c, from, publish))
+            .await;
+    }
 }
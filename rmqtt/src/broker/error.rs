@@ -43,6 +43,10 @@ pub enum MqttError {
     ParseIntError(ParseIntError),
     #[error("listener config is error")]
     ListenerConfigError,
+    #[error("publish refused, reason: {1}")]
+    PublishAckReason(v5::codec::PublishAckReason, bytestring::ByteString),
+    #[error("client_id is in use")]
+    ClientIdInUse,
     #[error("None")]
     None,
 }
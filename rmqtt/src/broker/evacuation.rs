@@ -0,0 +1,260 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::OnceCell;
+use parking_lot::RwLock;
+use tokio::task::JoinHandle;
+
+use crate::broker::types::*;
+use crate::{MqttError, Result, Runtime, TimestampMillis};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct EvacuateParams {
+    ///Sent to the 5.0 clients in the DISCONNECT and the refused CONNACK, the server they
+    ///should connect to instead
+    #[serde(default)]
+    pub server_reference: Option<String>,
+    ///Connections redirected per second, so that the peers are not flooded with reconnects
+    #[serde(default = "EvacuateParams::rate_default")]
+    pub rate: usize,
+    ///Seconds the clients are given to leave and the offline sessions to be taken over by the peers,
+    ///then the clients still connected are disconnected and the sessions left are discarded
+    #[serde(
+        default = "EvacuateParams::timeout_default",
+        skip_serializing_if = "crate::grpc::codec::is_legacy_layout",
+        deserialize_with = "crate::grpc::codec::deserialize_since_legacy"
+    )]
+    pub timeout: u64,
+}
+
+impl EvacuateParams {
+    fn rate_default() -> usize {
+        100
+    }
+
+    fn timeout_default() -> u64 {
+        300
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EvacuationState {
+    Idle,
+    ///Redirecting the connected clients
+    Draining,
+    ///Handing off the roles of the node in the cluster to the peers
+    HandingOff,
+    Completed,
+    Cancelled,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct EvacuationStatus {
+    pub state: EvacuationState,
+    pub server_reference: Option<String>,
+    pub started_at: Option<TimestampMillis>,
+    pub finished_at: Option<TimestampMillis>,
+    ///Clients redirected so far, each counted once
+    pub redirected: usize,
+    ///Clients disconnected at the timeout
+    #[serde(
+        default,
+        skip_serializing_if = "crate::grpc::codec::is_legacy_layout",
+        deserialize_with = "crate::grpc::codec::deserialize_since_legacy"
+    )]
+    pub kicked: usize,
+    ///Offline sessions discarded at the timeout, their clients did not reconnect to a peer in time
+    #[serde(
+        default,
+        skip_serializing_if = "crate::grpc::codec::is_legacy_layout",
+        deserialize_with = "crate::grpc::codec::deserialize_since_legacy"
+    )]
+    pub discarded_sessions: usize,
+    ///Clients still connected to the node
+    pub connections: usize,
+    ///Sessions of disconnected clients still kept by the node, they move to the peer the client
+    ///reconnects to
+    pub offline_sessions: usize,
+    ///Errors of the plugins that failed to hand off
+    pub handoff_errors: Vec<(String, String)>,
+}
+
+///Draining of this node before it is decommissioned: new connections are refused, the connected
+///clients are redirected to the peers at a bounded rate, where their sessions are taken over, as
+///are the offline sessions when their clients reconnect. At the timeout the clients left are
+///disconnected and the sessions left discarded, then the plugins hand off the roles of the node in
+///the cluster
+pub struct Evacuation {
+    evacuating: AtomicBool,
+    status: RwLock<EvacuationStatus>,
+    task: RwLock<Option<JoinHandle<()>>>,
+}
+
+impl Evacuation {
+    #[inline]
+    pub fn instance() -> &'static Evacuation {
+        static INSTANCE: OnceCell<Evacuation> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self {
+            evacuating: AtomicBool::new(false),
+            status: RwLock::new(EvacuationStatus {
+                state: EvacuationState::Idle,
+                server_reference: None,
+                started_at: None,
+                finished_at: None,
+                redirected: 0,
+                kicked: 0,
+                discarded_sessions: 0,
+                connections: 0,
+                offline_sessions: 0,
+                handoff_errors: Vec::new(),
+            }),
+            task: RwLock::new(None),
+        })
+    }
+
+    ///New connections are refused while true
+    #[inline]
+    pub fn is_evacuating(&self) -> bool {
+        self.evacuating.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    pub fn server_reference(&self) -> Option<String> {
+        self.status.read().server_reference.clone()
+    }
+
+    #[inline]
+    pub async fn status(&self) -> EvacuationStatus {
+        let mut status = self.status.read().clone();
+        let (connections, offline_sessions) = Self::local_sessions().await;
+        status.connections = connections;
+        status.offline_sessions = offline_sessions;
+        status
+    }
+
+    #[inline]
+    pub fn start(&'static self, params: EvacuateParams) -> Result<()> {
+        if self.evacuating.swap(true, Ordering::SeqCst) {
+            return Err(MqttError::from("the node is already evacuated"));
+        }
+        log::warn!("evacuating the node, {:?}", params);
+        {
+            let mut status = self.status.write();
+            status.state = EvacuationState::Draining;
+            status.server_reference = params.server_reference.clone();
+            status.started_at = Some(chrono::Local::now().timestamp_millis());
+            status.finished_at = None;
+            status.redirected = 0;
+            status.kicked = 0;
+            status.discarded_sessions = 0;
+            status.handoff_errors.clear();
+        }
+        *self.task.write() = Some(tokio::spawn(self.run(params)));
+        Ok(())
+    }
+
+    ///Accepts connections again, the redirected clients and the handed off roles stay with the peers
+    #[inline]
+    pub fn cancel(&self) -> bool {
+        if !self.evacuating.swap(false, Ordering::SeqCst) {
+            return false;
+        }
+        if let Some(task) = self.task.write().take() {
+            task.abort();
+        }
+        let mut status = self.status.write();
+        status.state = EvacuationState::Cancelled;
+        status.finished_at = Some(chrono::Local::now().timestamp_millis());
+        log::warn!("evacuation of the node is cancelled");
+        true
+    }
+
+    async fn run(&'static self, params: EvacuateParams) {
+        let rate = params.rate.max(1);
+        let deadline = Instant::now() + Duration::from_secs(params.timeout);
+        //a client is redirected once, it may take a while to leave
+        let mut redirecteds = HashSet::new();
+        loop {
+            let txs = Runtime::instance()
+                .extends
+                .shared()
+                .await
+                .iter()
+                .filter(|entry| entry.is_connected() && !redirecteds.contains(&entry.id().client_id))
+                .filter_map(|entry| entry.tx().map(|tx| (entry.id().client_id.clone(), tx)))
+                .take(rate)
+                .collect::<Vec<_>>();
+            for (client_id, tx) in txs {
+                if tx.unbounded_send(Message::Redirect(params.server_reference.clone())).is_ok() {
+                    redirecteds.insert(client_id);
+                    self.status.write().redirected = redirecteds.len();
+                }
+            }
+            let (connections, offline_sessions) = Self::local_sessions().await;
+            if connections == 0 && offline_sessions == 0 {
+                break;
+            }
+            if Instant::now() >= deadline {
+                self.discard().await;
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+
+        self.status.write().state = EvacuationState::HandingOff;
+        let handoff_errors = Runtime::instance().plugins.evacuate().await;
+        for (name, e) in handoff_errors.iter() {
+            log::error!("{} hand off error, {}", name, e);
+        }
+
+        let mut status = self.status.write();
+        status.handoff_errors = handoff_errors;
+        status.state = EvacuationState::Completed;
+        status.finished_at = Some(chrono::Local::now().timestamp_millis());
+        log::warn!("evacuation of the node is completed, redirected: {}", status.redirected);
+    }
+
+    ///Disconnects the clients left and discards the offline sessions left, with their subscriptions
+    async fn discard(&self) {
+        let ids = Runtime::instance()
+            .extends
+            .shared()
+            .await
+            .iter()
+            .map(|entry| (entry.id(), entry.is_connected()))
+            .collect::<Vec<_>>();
+        let (mut kicked, mut discarded_sessions) = (0, 0);
+        for (id, connected) in ids {
+            let mut entry = Runtime::instance().extends.shared().await.entry(id.clone());
+            match entry.kick(true, true).await {
+                Ok(Some(_)) if connected => kicked += 1,
+                Ok(Some(_)) => discarded_sessions += 1,
+                Ok(None) => {}
+                Err(e) => log::warn!("{:?} evacuation, kick error, {:?}", id, e),
+            }
+        }
+        log::warn!(
+            "evacuation timed out, disconnected clients: {}, discarded offline sessions: {}",
+            kicked,
+            discarded_sessions
+        );
+        let mut status = self.status.write();
+        status.kicked = kicked;
+        status.discarded_sessions = discarded_sessions;
+    }
+
+    ///Connected clients and offline sessions of this node
+    async fn local_sessions() -> (usize, usize) {
+        let (mut connections, mut offline_sessions) = (0, 0);
+        for entry in Runtime::instance().extends.shared().await.iter() {
+            if entry.is_connected() {
+                connections += 1;
+            } else {
+                offline_sessions += 1;
+            }
+        }
+        (connections, offline_sessions)
+    }
+}
@@ -27,9 +27,15 @@ pub trait Fitter: Sync + Send {
     ///max inflight
     fn max_inflight(&self) -> std::num::NonZeroU16;
 
+    ///Maximum number of subscriptions of the session, 0 means unlimited
+    fn max_subscriptions(&self) -> usize;
+
     ///session expiry interval
     fn session_expiry_interval(&self) -> Duration;
 
     ///max packet size
     fn max_packet_size(&self) -> u32;
+
+    ///Publish rate limits of the connection, the auth plugin's, or else those of the listener
+    fn publish_rate_limit(&self) -> PublishRateLimit;
 }
@@ -0,0 +1,130 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use rust_box::dequemap::DequeMap;
+
+use crate::broker::types::*;
+use crate::{ClientId, Id, NodeId, Runtime, TimestampMillis, UserName};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionEventKind {
+    Connected,
+    ConnectFailed,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ConnectionEvent {
+    pub kind: ConnectionEventKind,
+    pub node_id: NodeId,
+    pub client_id: ClientId,
+    pub username: Option<UserName>,
+    pub remote_addr: Option<SocketAddr>,
+    pub reason: Option<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "crate::grpc::codec::is_legacy_layout",
+        deserialize_with = "crate::grpc::codec::deserialize_since_legacy"
+    )]
+    pub disconnected_kind: Option<DisconnectKind>,
+    pub time: TimestampMillis,
+}
+
+impl ConnectionEvent {
+    #[inline]
+    fn new(kind: ConnectionEventKind, id: &Id, reason: Option<String>) -> Self {
+        Self {
+            kind,
+            node_id: id.node_id,
+            client_id: id.client_id.clone(),
+            username: id.username.clone(),
+            remote_addr: id.remote_addr,
+            reason,
+            disconnected_kind: None,
+            time: chrono::Local::now().timestamp_millis(),
+        }
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "event": self.kind,
+            "node_id": self.node_id,
+            "clientid": self.client_id,
+            "username": self.username,
+            "ipaddress": self.remote_addr.map(|addr| addr.ip()),
+            "reason": self.reason,
+            "disconnected_kind": self.disconnected_kind,
+            "time": self.time,
+        })
+    }
+}
+
+///Bounded history of the connect/disconnect events of the clients on this node,
+///ordered by the last activity of the clients.
+pub struct ConnectionHistory {
+    clients: Mutex<DequeMap<ClientId, VecDeque<ConnectionEvent>>>,
+}
+
+impl ConnectionHistory {
+    #[inline]
+    pub fn instance() -> &'static ConnectionHistory {
+        static INSTANCE: OnceCell<ConnectionHistory> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { clients: Mutex::new(DequeMap::default()) })
+    }
+
+    #[inline]
+    pub fn connected(&self, id: &Id) {
+        self.record(ConnectionEvent::new(ConnectionEventKind::Connected, id, None));
+    }
+
+    #[inline]
+    pub fn connect_failed(&self, id: &Id, reason: &ConnectAckReason) {
+        self.record(ConnectionEvent::new(
+            ConnectionEventKind::ConnectFailed,
+            id,
+            Some(reason.reason().into()),
+        ));
+    }
+
+    #[inline]
+    pub fn disconnected(&self, id: &Id, kind: Option<DisconnectKind>, reason: &Reason) {
+        let mut event = ConnectionEvent::new(ConnectionEventKind::Disconnected, id, Some(reason.to_string()));
+        event.disconnected_kind = kind;
+        self.record(event);
+    }
+
+    ///Events of the client on this node, the oldest first
+    #[inline]
+    pub fn get(&self, client_id: &ClientId) -> Vec<ConnectionEvent> {
+        self.clients.lock().get(client_id).map(|events| events.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    #[inline]
+    pub fn clients(&self) -> usize {
+        self.clients.lock().len()
+    }
+
+    #[inline]
+    fn record(&self, event: ConnectionEvent) {
+        let cfg = &Runtime::instance().settings.connection_history;
+        if !cfg.enable {
+            return;
+        }
+        let mut clients = self.clients.lock();
+        //Move the client to the back, the front is the least recently active
+        let mut events = clients.remove(&event.client_id).unwrap_or_default();
+        let client_id = event.client_id.clone();
+        events.push_back(event);
+        while events.len() > cfg.max_events {
+            events.pop_front();
+        }
+        clients.insert(client_id, events);
+        while clients.len() > cfg.max_clients {
+            clients.pop_front();
+        }
+    }
+}
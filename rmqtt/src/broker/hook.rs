@@ -1,3 +1,4 @@
+use crate::broker::alarm::Alarm;
 use crate::broker::types::*;
 use crate::{grpc, ClientInfo, Result, Session};
 
@@ -5,11 +6,38 @@ pub type Priority = u32;
 pub type Proceed = bool;
 pub type ReturnType = (Proceed, Option<HookResult>);
 
+///A handler registered for a hook type
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HandlerInfo {
+    pub typ: Type,
+    pub priority: Priority,
+    pub id: String,
+    ///Name of the plugin that registered the handler
+    pub owner: String,
+    pub enabled: bool,
+}
+
 #[async_trait]
 pub trait HookManager: Sync + Send {
     fn hook(&self, s: &Session, c: &ClientInfo) -> std::rc::Rc<dyn Hook>;
 
-    fn register(&self) -> Box<dyn Register>;
+    fn register(&self) -> Box<dyn Register> {
+        self.register_for("")
+    }
+
+    ///Register of the handlers of a plugin, the owner is shown in the handler list
+    fn register_for(&self, owner: &str) -> Box<dyn Register>;
+
+    ///Registered handlers, of each type in the order they are executed
+    async fn handlers(&self) -> Vec<HandlerInfo>;
+
+    ///From now on the handlers of the owner are not enabled or disabled at once, the changes are
+    ///staged until handover_end, such as while a plugin is reloaded
+    async fn handover_begin(&self, _owner: &str) {}
+
+    ///Applies the staged changes of the owner, the handlers of a hook type are disabled and enabled
+    ///together, so that an event is neither handled by both instances of a plugin nor by none
+    async fn handover_end(&self, _owner: &str) {}
 
     ///Before the server startup
     async fn before_startup(&self);
@@ -22,7 +50,7 @@ pub trait HookManager: Sync + Send {
         &self,
         connect_info: &ConnectInfo,
         allow_anonymous: bool,
-    ) -> (ConnectAckReason, Superuser);
+    ) -> (ConnectAckReason, Superuser, Option<PublishRateLimit>);
 
     ///When sending mqtt:: connectack message
     async fn client_connack(
@@ -34,6 +62,16 @@ pub trait HookManager: Sync + Send {
     ///Publish message Dropped
     async fn message_dropped(&self, to: Option<To>, from: From, p: Publish, reason: Reason);
 
+    ///The cached authentication and ACL decisions of the client are to be discarded, such as
+    ///after its device has been revoked in the auth backend
+    async fn client_acl_invalidate(&self, s: &Session, c: &ClientInfo);
+
+    ///Alarm activated
+    async fn alarm_activated(&self, alarm: &Alarm);
+
+    ///Alarm deactivated
+    async fn alarm_deactivated(&self, alarm: &Alarm);
+
     ///grpc message received
     async fn grpc_message_received(
         &self,
@@ -48,6 +86,8 @@ pub trait Register: Sync + Send {
         self.add_priority(typ, 0, handler).await;
     }
 
+    ///The handlers of a type are executed from the highest priority to the lowest, a handler that
+    ///returns false for Proceed stops the propagation, the later handlers are skipped
     async fn add_priority(&self, typ: Type, priority: Priority, handler: Box<dyn Handler>);
 
     async fn start(&self) {}
@@ -62,12 +102,22 @@ pub trait Handler: Sync + Send {
 
 #[async_trait]
 pub trait Hook: Sync + Send {
+    ///Whether an enabled handler of the hook type is registered, so that the parameters of a hook
+    ///without handlers are not built
+    fn is_registered(&self, _typ: Type) -> bool {
+        true
+    }
+
     ///session created
     async fn session_created(&self);
 
     // ///authenticate
     // async fn client_authenticate(&self, password: Option<Password>) -> ConnectAckReason;
 
+    ///After the mqtt:: connectack message is sent, before client_connected, the plugins attach
+    ///tags to the connection, seen by the later hooks, the session API and the metrics
+    async fn client_tagging(&self);
+
     ///After the mqtt:: connectack message is sent, the connection is created successfully
     async fn client_connected(&self);
 
@@ -77,12 +127,18 @@ pub trait Hook: Sync + Send {
     ///Session terminated
     async fn session_terminated(&self, r: Reason);
 
+    ///The subscriber has been consuming too slowly for a while
+    async fn client_slow(&self, slow: &SlowSubscriber);
+
     ///subscribe check acl
     async fn client_subscribe_check_acl(&self, subscribe: &Subscribe) -> Option<SubscribeAclResult>;
 
     ///publish check acl
     async fn message_publish_check_acl(&self, publish: &Publish) -> PublishAclResult;
 
+    ///Subscription authorized, after the ACL check succeeded, the qos of the subscribe is the granted qos
+    async fn subscribe_authorized(&self, subscribe: &Subscribe);
+
     ///Subscribe message received
     async fn client_subscribe(&self, subscribe: &Subscribe) -> Option<TopicFilter>;
 
@@ -109,6 +165,10 @@ pub trait Hook: Sync + Send {
 
     ///message expiry check
     async fn message_expiry_check(&self, from: From, publish: &Publish) -> MessageExpiry;
+
+    ///Message queued for the session while its client is offline, such as to wake a mobile
+    ///client with a push notification
+    async fn message_offline(&self, from: From, publish: &Publish);
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Deserialize, Serialize)]
@@ -123,11 +183,15 @@ pub enum Type {
     ClientAuthenticate,
     ClientConnect,
     ClientConnack,
+    ClientTagging,
     ClientConnected,
     ClientDisconnected,
     ClientSubscribe,
     ClientUnsubscribe,
     ClientSubscribeCheckAcl,
+    SubscribeAuthorized,
+    ClientSlow,
+    ClientAclInvalidate,
 
     MessagePublishCheckAcl,
     MessagePublish,
@@ -135,6 +199,10 @@ pub enum Type {
     MessageAcked,
     MessageDropped,
     MessageExpiryCheck,
+    MessageOffline,
+
+    AlarmActivated,
+    AlarmDeactivated,
 
     GrpcMessageReceived,
 }
@@ -152,11 +220,15 @@ impl std::convert::From<&str> for Type {
             "client_authenticate" => Type::ClientAuthenticate,
             "client_connect" => Type::ClientConnect,
             "client_connack" => Type::ClientConnack,
+            "client_tagging" => Type::ClientTagging,
             "client_connected" => Type::ClientConnected,
             "client_disconnected" => Type::ClientDisconnected,
             "client_subscribe" => Type::ClientSubscribe,
             "client_unsubscribe" => Type::ClientUnsubscribe,
             "client_subscribe_check_acl" => Type::ClientSubscribeCheckAcl,
+            "subscribe_authorized" => Type::SubscribeAuthorized,
+            "client_slow" => Type::ClientSlow,
+            "client_acl_invalidate" => Type::ClientAclInvalidate,
 
             "message_publish_check_acl" => Type::MessagePublishCheckAcl,
             "message_publish" => Type::MessagePublish,
@@ -164,6 +236,10 @@ impl std::convert::From<&str> for Type {
             "message_acked" => Type::MessageAcked,
             "message_dropped" => Type::MessageDropped,
             "message_expiry_check" => Type::MessageExpiryCheck,
+            "message_offline" => Type::MessageOffline,
+
+            "alarm_activated" => Type::AlarmActivated,
+            "alarm_deactivated" => Type::AlarmDeactivated,
 
             "grpc_message_received" => Type::GrpcMessageReceived,
 
@@ -184,11 +260,15 @@ pub enum Parameter<'a> {
     ClientConnect(&'a ConnectInfo),
     ClientConnack(&'a ConnectInfo, &'a ConnectAckReason),
     ClientAuthenticate(&'a ConnectInfo),
+    ClientTagging(&'a Session, &'a ClientInfo),
     ClientConnected(&'a Session, &'a ClientInfo),
     ClientDisconnected(&'a Session, &'a ClientInfo, Reason),
     ClientSubscribe(&'a Session, &'a ClientInfo, &'a Subscribe),
     ClientUnsubscribe(&'a Session, &'a ClientInfo, &'a Unsubscribe),
     ClientSubscribeCheckAcl(&'a Session, &'a ClientInfo, &'a Subscribe),
+    SubscribeAuthorized(&'a Session, &'a ClientInfo, &'a Subscribe),
+    ClientSlow(&'a Session, &'a ClientInfo, &'a SlowSubscriber),
+    ClientAclInvalidate(&'a Session, &'a ClientInfo),
 
     MessagePublishCheckAcl(&'a Session, &'a ClientInfo, &'a Publish),
     MessagePublish(&'a Session, &'a ClientInfo, &'a Publish),
@@ -196,6 +276,10 @@ pub enum Parameter<'a> {
     MessageAcked(&'a Session, &'a ClientInfo, From, &'a Publish),
     MessageDropped(Option<To>, From, Publish, Reason),
     MessageExpiryCheck(&'a Session, &'a ClientInfo, From, &'a Publish),
+    MessageOffline(&'a Session, &'a ClientInfo, From, &'a Publish),
+
+    AlarmActivated(&'a Alarm),
+    AlarmDeactivated(&'a Alarm),
 
     GrpcMessageReceived(grpc::MessageType, grpc::Message),
 }
@@ -213,11 +297,15 @@ impl<'a> Parameter<'a> {
             Parameter::ClientAuthenticate(_) => Type::ClientAuthenticate,
             Parameter::ClientConnect(_) => Type::ClientConnect,
             Parameter::ClientConnack(_, _) => Type::ClientConnack,
+            Parameter::ClientTagging(_, _) => Type::ClientTagging,
             Parameter::ClientConnected(_, _) => Type::ClientConnected,
             Parameter::ClientDisconnected(_, _, _) => Type::ClientDisconnected,
             Parameter::ClientSubscribe(_, _, _) => Type::ClientSubscribe,
             Parameter::ClientUnsubscribe(_, _, _) => Type::ClientUnsubscribe,
             Parameter::ClientSubscribeCheckAcl(_, _, _) => Type::ClientSubscribeCheckAcl,
+            Parameter::SubscribeAuthorized(_, _, _) => Type::SubscribeAuthorized,
+            Parameter::ClientSlow(_, _, _) => Type::ClientSlow,
+            Parameter::ClientAclInvalidate(_, _) => Type::ClientAclInvalidate,
 
             Parameter::MessagePublishCheckAcl(_, _, _) => Type::MessagePublishCheckAcl,
             Parameter::MessagePublish(_, _, _) => Type::MessagePublish,
@@ -225,6 +313,10 @@ impl<'a> Parameter<'a> {
             Parameter::MessageAcked(_, _, _, _) => Type::MessageAcked,
             Parameter::MessageDropped(_, _, _, _) => Type::MessageDropped,
             Parameter::MessageExpiryCheck(_, _, _, _) => Type::MessageExpiryCheck,
+            Parameter::MessageOffline(_, _, _, _) => Type::MessageOffline,
+
+            Parameter::AlarmActivated(_) => Type::AlarmActivated,
+            Parameter::AlarmDeactivated(_) => Type::AlarmDeactivated,
 
             Parameter::GrpcMessageReceived(_, _) => Type::GrpcMessageReceived,
         }
@@ -239,6 +331,8 @@ pub enum HookResult {
     AuthResult(AuthResult),
     ///ConnectAckReason, for ClientConnack
     ConnectAckReason(ConnectAckReason),
+    ///Tags of the connection, for ClientTagging, a handler adds to those of the previous handlers
+    Tags(ClientTags),
     ///TopicFilters, for ClientSubscribe/ClientUnsubscribe
     TopicFilter(Option<TopicFilter>),
     ///Subscribe AclResult, for ClientSubscribeCheckAcl
@@ -3,12 +3,14 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use rust_box::dequemap::DequeMap;
+use tokio::sync::Notify;
 
+use crate::broker::budget::MemoryBudget;
 use crate::broker::types::{
-    From, Packet, PacketId, PacketV3, PacketV5, Publish, PublishAck2, PublishAck2Reason, TimestampMillis,
-    UserProperties,
+    ClientId, From, Packet, PacketId, PacketV3, PacketV5, Publish, PublishAck2, PublishAck2Reason,
+    TimestampMillis, TopicName, UserProperties,
 };
-use crate::{MqttError, Result};
+use crate::{HashMap, MqttError, Result};
 
 type Queues = DequeMap<PacketId, InflightMessage>;
 
@@ -76,16 +78,132 @@ impl InflightMessage {
 #[derive(Clone)]
 pub struct Inflight {
     cap: usize,
-    interval: TimestampMillis,
+    retry_interval: TimestampMillis,
+    expiry_interval: TimestampMillis,
     next: Arc<AtomicU16>,
     queues: Queues,
+    //retransmission policy, see retry_policy()
+    retry_backoff: f64,
+    retry_max_interval: TimestampMillis,
+    retry_max: usize,
+    retry_on_reconnect_only: bool,
+    //number of times each message has timed out, until it is acked or exhausted
+    retries: HashMap<PacketId, usize>,
+    //effective window size, adapted between 1 and cap when latency_target > 0
+    window: usize,
+    latency_target: TimestampMillis,
+    latency_avg: TimestampMillis,
+    //number of inflight messages by publisher and topic, see contains_topic()
+    topics: HashMap<(ClientId, TopicName), usize>,
+    //notified when the last inflight message of a publisher on a topic leaves the window
+    topic_released: Arc<Notify>,
 }
 
 impl Inflight {
     #[inline]
     pub fn new(cap: usize, retry_interval: TimestampMillis, expiry_interval: TimestampMillis) -> Self {
-        let interval = Self::interval(retry_interval, expiry_interval);
-        Self { cap, interval, next: Arc::new(AtomicU16::new(1)), queues: Queues::default() }
+        Self {
+            cap,
+            retry_interval,
+            expiry_interval,
+            next: Arc::new(AtomicU16::new(1)),
+            queues: Queues::default(),
+            retry_backoff: 1.0,
+            retry_max_interval: 0,
+            retry_max: 0,
+            retry_on_reconnect_only: false,
+            retries: HashMap::default(),
+            window: cap,
+            latency_target: 0,
+            latency_avg: 0,
+            topics: HashMap::default(),
+            topic_released: Arc::new(Notify::new()),
+        }
+    }
+
+    ///Adapt the window to the ack latency of the client, it grows by one for each message acked
+    ///within latency_target and shrinks by one for each acked later, a message that is not acked
+    ///within the retry interval halves it. The window starts at half of cap and never exceeds it.
+    #[inline]
+    pub fn adaptive(mut self, latency_target: TimestampMillis) -> Self {
+        if latency_target > 0 {
+            self.latency_target = latency_target;
+            self.window = (self.cap / 2).max(1);
+        }
+        self
+    }
+
+    ///The retry interval of a message is multiplied by backoff after each retransmission, up to
+    ///max_interval (0 means no limit). A message that times out after max_retries retransmissions
+    ///is exhausted, 0 means no limit.
+    #[inline]
+    pub fn retry_policy(mut self, backoff: f64, max_interval: TimestampMillis, max_retries: usize) -> Self {
+        self.retry_backoff = backoff.max(1.0);
+        self.retry_max_interval = max_interval;
+        self.retry_max = max_retries;
+        self
+    }
+
+    ///The messages never time out, they are resent when the session is resumed
+    #[inline]
+    pub fn retry_on_reconnect_only(mut self, on_reconnect_only: bool) -> Self {
+        self.retry_on_reconnect_only = on_reconnect_only;
+        self
+    }
+
+    #[inline]
+    fn acked(&mut self, m: &InflightMessage) {
+        if self.latency_target <= 0 {
+            return;
+        }
+        let latency = (chrono::Local::now().timestamp_millis() - m.update_time).max(0);
+        self.latency_avg = if self.latency_avg == 0 { latency } else { (self.latency_avg * 7 + latency) / 8 };
+        if self.latency_avg <= self.latency_target {
+            self.window = (self.window + 1).min(self.cap);
+        } else {
+            self.window = (self.window - 1).max(1);
+        }
+    }
+
+    #[inline]
+    fn timed_out(&mut self) {
+        if self.latency_target > 0 {
+            self.window = (self.window / 2).max(1);
+        }
+    }
+
+    ///Effective window size
+    #[inline]
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    ///Smoothed ack latency in milliseconds, 0 when the window is not adaptive
+    #[inline]
+    pub fn latency(&self) -> TimestampMillis {
+        self.latency_avg
+    }
+
+    ///Timeout interval of a message that has been retransmitted n times
+    #[inline]
+    fn interval_of(&self, n: usize) -> TimestampMillis {
+        if self.retry_on_reconnect_only {
+            return 0;
+        }
+        let mut retry_interval = self.retry_interval;
+        if retry_interval > 0 && n > 0 && self.retry_backoff > 1.0 {
+            retry_interval = (retry_interval as f64 * self.retry_backoff.powi(n.min(64) as i32))
+                .min(TimestampMillis::MAX as f64) as TimestampMillis;
+            if self.retry_max_interval > 0 {
+                retry_interval = retry_interval.min(self.retry_max_interval);
+            }
+        }
+        Self::interval(retry_interval, self.expiry_interval)
+    }
+
+    #[inline]
+    fn front_interval(&self, packet_id: &PacketId) -> TimestampMillis {
+        self.interval_of(self.retries.get(packet_id).copied().unwrap_or_default())
     }
 
     #[inline]
@@ -100,11 +218,12 @@ impl Inflight {
 
     #[inline]
     pub fn get_timeout(&self) -> Option<Duration> {
-        if self.interval == 0 {
-            return None;
-        }
-        if let Some((_, m)) = self.queues.front() {
-            let mut t = self.interval - (chrono::Local::now().timestamp_millis() - m.update_time);
+        if let Some((packet_id, m)) = self.queues.front() {
+            let interval = self.front_interval(packet_id);
+            if interval == 0 {
+                return None;
+            }
+            let mut t = interval - (chrono::Local::now().timestamp_millis() - m.update_time);
             if t < 1 {
                 t = 1;
             }
@@ -116,11 +235,8 @@ impl Inflight {
 
     #[inline]
     fn front_timeout(&self) -> bool {
-        if self.interval == 0 {
-            return false;
-        }
-        if let Some((_, m)) = self.queues.front() {
-            if m.timeout(self.interval) {
+        if let Some((packet_id, m)) = self.queues.front() {
+            if m.timeout(self.front_interval(packet_id)) {
                 return true;
             }
         }
@@ -137,32 +253,93 @@ impl Inflight {
         self.queues.front().map(|(packet_id, m)| (packet_id, m))
     }
 
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = (&PacketId, &InflightMessage)> {
+        self.queues.iter().map(|(packet_id, m)| (packet_id, m))
+    }
+
+    ///Number of times the message has timed out
+    #[inline]
+    pub fn retries_of(&self, packet_id: &PacketId) -> usize {
+        self.retries.get(packet_id).copied().unwrap_or_default()
+    }
+
+    ///When the message times out, 0 if it never does
+    #[inline]
+    pub fn timeout_at(&self, packet_id: &PacketId, m: &InflightMessage) -> TimestampMillis {
+        match self.front_interval(packet_id) {
+            0 => 0,
+            interval => m.update_time + interval,
+        }
+    }
+
     #[inline]
     pub fn pop_front(&mut self) -> Option<InflightMessage> {
-        self.queues.pop_front().map(|(_, m)| m)
+        self.queues.pop_front().map(|(_, m)| {
+            MemoryBudget::instance().inflight_sub(&m.publish);
+            self.topic_sub(&m);
+            m
+        })
     }
 
+    ///Also returns the number of times the message has timed out, see is_retry_exhausted()
     #[inline]
-    pub fn pop_front_timeout(&mut self) -> Option<InflightMessage> {
+    pub fn pop_front_timeout(&mut self) -> Option<(InflightMessage, usize)> {
         if self.front_timeout() {
-            self.pop_front()
+            self.timed_out();
+            let m = self.pop_front()?;
+            let mut retries = 0;
+            if let Some(packet_id) = m.publish.packet_id() {
+                let n = self.retries.entry(packet_id).or_default();
+                *n += 1;
+                retries = *n;
+                if self.is_retry_exhausted(retries) {
+                    self.retries.remove(&packet_id);
+                }
+            }
+            Some((m, retries))
         } else {
             None
         }
     }
 
+    ///The message timed out after max_retries retransmissions, it should be dropped
+    #[inline]
+    pub fn is_retry_exhausted(&self, retries: usize) -> bool {
+        self.retry_max > 0 && retries > self.retry_max
+    }
+
     #[inline]
     pub fn push_back(&mut self, m: InflightMessage) {
         if let Some(packet_id) = m.publish.packet_id() {
+            if let Some(old) = self.queues.remove(&packet_id) {
+                MemoryBudget::instance().inflight_sub(&old.publish);
+                self.topic_sub(&old);
+            }
+            MemoryBudget::instance().inflight_add(&m.publish);
+            self.topic_add(&m);
+            //A new message, not a retransmission or a re-release, does not inherit the retries of a
+            //previous packet_id
+            if !m.publish.dup() && m.status != MomentStatus::UnComplete {
+                self.retries.remove(&packet_id);
+            }
             self.queues.insert(packet_id, m);
         } else {
             log::warn!("packet_id is None, inflight message: {:?}", m);
         }
     }
 
+    ///Remove the acked message
     #[inline]
     pub fn remove(&mut self, packet_id: &PacketId) -> Option<InflightMessage> {
-        self.queues.remove(packet_id)
+        let m = self.queues.remove(packet_id);
+        if let Some(m) = m.as_ref() {
+            MemoryBudget::instance().inflight_sub(&m.publish);
+            self.topic_sub(m);
+            self.retries.remove(packet_id);
+            self.acked(m);
+        }
+        m
     }
 
     #[inline]
@@ -187,9 +364,38 @@ impl Inflight {
         self.queues.contains_key(packet_id)
     }
 
+    ///A message of the publisher on the topic is waiting for its acknowledgement
+    #[inline]
+    pub fn contains_topic(&self, from: &From, topic: &TopicName) -> bool {
+        self.topics.contains_key(&(from.client_id.clone(), topic.clone()))
+    }
+
+    ///Notified when contains_topic() turns false for a publisher and topic
+    #[inline]
+    pub fn topic_released(&self) -> Arc<Notify> {
+        self.topic_released.clone()
+    }
+
+    #[inline]
+    fn topic_add(&mut self, m: &InflightMessage) {
+        *self.topics.entry((m.from.client_id.clone(), m.publish.topic.clone())).or_default() += 1;
+    }
+
+    #[inline]
+    fn topic_sub(&mut self, m: &InflightMessage) {
+        let key = (m.from.client_id.clone(), m.publish.topic.clone());
+        if let Some(n) = self.topics.get_mut(&key) {
+            *n -= 1;
+            if *n == 0 {
+                self.topics.remove(&key);
+                self.topic_released.notify_one();
+            }
+        }
+    }
+
     #[inline]
     pub fn has_credit(&self) -> bool {
-        (self.cap - self.queues.len()) > 0
+        self.queues.len() < self.window
     }
 
     #[inline]
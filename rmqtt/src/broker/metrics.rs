@@ -8,10 +8,14 @@ pub struct Metrics {
     client_auth_anonymous: AtomicUsize,
     client_auth_anonymous_error: AtomicUsize,
     client_handshaking_timeout: AtomicUsize,
+    client_handshaking_shed: AtomicUsize,
     client_connect: AtomicUsize,
     client_connack: AtomicUsize,
     client_connack_auth_error: AtomicUsize,
     client_connack_error: AtomicUsize,
+    client_connack_quota_exceeded: AtomicUsize,
+    client_connack_connection_rate_exceeded: AtomicUsize,
+    client_connect_rate_limit_dry_run: AtomicUsize,
     client_connected: AtomicUsize,
     client_disconnected: AtomicUsize,
     client_subscribe_check_acl: AtomicUsize,
@@ -20,14 +24,18 @@ pub struct Metrics {
     client_unsubscribe: AtomicUsize,
     client_subscribe_error: AtomicUsize,
     client_subscribe_auth_error: AtomicUsize,
+    client_subscribe_quota_exceeded: AtomicUsize,
     client_publish_auth_error: AtomicUsize,
     client_publish_error: AtomicUsize,
+    client_publish_quota_exceeded: AtomicUsize,
+    client_publish_rate_limit_dry_run: AtomicUsize,
 
     session_subscribed: AtomicUsize,
     session_unsubscribed: AtomicUsize,
     session_created: AtomicUsize,
     session_resumed: AtomicUsize,
     session_terminated: AtomicUsize,
+    session_mqueue_rate_limit_dry_run: AtomicUsize,
 
     messages_publish: AtomicUsize,
     // messages_received: AtomicUsize,
@@ -39,4 +47,105 @@ pub struct Metrics {
     // messages_sent: AtomicUsize,
     messages_acked: AtomicUsize,
     messages_dropped: AtomicUsize,
+    messages_dropped_queue_full: AtomicUsize,
+    messages_dropped_expired: AtomicUsize,
+    messages_dropped_no_subscribers: AtomicUsize,
+    messages_dropped_acl_denied: AtomicUsize,
+    messages_dropped_forward_failure: AtomicUsize,
+    messages_dropped_memory_budget: AtomicUsize,
+    messages_dropped_retry_exhausted: AtomicUsize,
+    messages_dropped_duplicate: AtomicUsize,
+    messages_dropped_rate_limited: AtomicUsize,
+    ///Messages not delivered to a subscription because its payload filter did not match
+    messages_filtered: AtomicUsize,
+}
+
+impl Metrics {
+    ///Count a dropped message by the category of its reason
+    #[inline]
+    pub fn messages_dropped_reason_inc(&self, reason: &str) {
+        match DroppedReason::classify(reason) {
+            DroppedReason::QueueFull => self.messages_dropped_queue_full_inc(),
+            DroppedReason::Expired => self.messages_dropped_expired_inc(),
+            DroppedReason::NoSubscribers => self.messages_dropped_no_subscribers_inc(),
+            DroppedReason::AclDenied => self.messages_dropped_acl_denied_inc(),
+            DroppedReason::ForwardFailure => self.messages_dropped_forward_failure_inc(),
+            DroppedReason::MemoryBudget => self.messages_dropped_memory_budget_inc(),
+            DroppedReason::RetryExhausted => self.messages_dropped_retry_exhausted_inc(),
+            DroppedReason::Duplicate => self.messages_dropped_duplicate_inc(),
+            DroppedReason::RateLimited => self.messages_dropped_rate_limited_inc(),
+            DroppedReason::Other => {}
+        }
+    }
+}
+
+///Category of the Reason of a dropped message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DroppedReason {
+    QueueFull,
+    Expired,
+    NoSubscribers,
+    AclDenied,
+    ForwardFailure,
+    MemoryBudget,
+    RetryExhausted,
+    Duplicate,
+    RateLimited,
+    Other,
+}
+
+impl DroppedReason {
+    pub const QUEUE_FULL: &'static str = "deliver queue is full";
+    pub const EXPIRED: &'static str = "message is expired";
+    pub const NO_SUBSCRIBERS: &'static str = "no subscribers";
+    pub const MEMORY_BUDGET: &'static str = "memory budget is exceeded";
+    pub const RETRY_EXHAUSTED: &'static str = "retransmission limit is reached";
+    pub const DUPLICATE: &'static str = "duplicate publish is suppressed";
+    pub const RATE_LIMITED: &'static str = "publish rate limit is exceeded";
+    pub const NODE_UNREACHABLE: &'static str = "node is unreachable";
+    pub const FORWARD_BUFFER_FULL: &'static str = "forward buffer is full";
+    pub const FORWARD_BUFFER_EXPIRED: &'static str = "forward buffer is expired";
+    pub const TOPIC_DENIED: &'static str = "topic is in the deny list";
+    ///Prefix of the Reason
+    pub const ROUTER_ERROR: &'static str = "router error";
+    ///Followed by the payload size, the limit and the topic filter
+    pub const PAYLOAD_TOO_LARGE: &'static str = "payload is too large";
+    ///Prefix of the Reason
+    pub const ACL_DENIED: &'static str = "hook::message_publish_check_acl";
+
+    #[inline]
+    pub fn classify(reason: &str) -> Self {
+        match reason {
+            Self::QUEUE_FULL => DroppedReason::QueueFull,
+            Self::EXPIRED => DroppedReason::Expired,
+            Self::NO_SUBSCRIBERS => DroppedReason::NoSubscribers,
+            Self::MEMORY_BUDGET => DroppedReason::MemoryBudget,
+            Self::RETRY_EXHAUSTED => DroppedReason::RetryExhausted,
+            Self::DUPLICATE => DroppedReason::Duplicate,
+            Self::RATE_LIMITED => DroppedReason::RateLimited,
+            r if r.starts_with(Self::ACL_DENIED) => DroppedReason::AclDenied,
+            Self::TOPIC_DENIED => DroppedReason::AclDenied,
+            Self::NODE_UNREACHABLE | Self::FORWARD_BUFFER_FULL | Self::FORWARD_BUFFER_EXPIRED => {
+                DroppedReason::ForwardFailure
+            }
+            r if r.contains("Tx is") || r.contains("Sender is None") => DroppedReason::ForwardFailure,
+            _ => DroppedReason::Other,
+        }
+    }
+
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DroppedReason::QueueFull => "queue_full",
+            DroppedReason::Expired => "expired",
+            DroppedReason::NoSubscribers => "no_subscribers",
+            DroppedReason::AclDenied => "acl_denied",
+            DroppedReason::ForwardFailure => "forward_failure",
+            DroppedReason::MemoryBudget => "memory_budget",
+            DroppedReason::RetryExhausted => "retry_exhausted",
+            DroppedReason::Duplicate => "duplicate",
+            DroppedReason::RateLimited => "rate_limited",
+            DroppedReason::Other => "other",
+        }
+    }
 }
@@ -6,23 +6,36 @@ use crate::broker::session::{ClientInfo, Session, SessionOfflineInfo};
 use crate::broker::types::*;
 use crate::grpc::GrpcClients;
 use crate::settings::listener::Listener;
+use crate::settings::DuplicateClientId;
 use crate::stats::Counter;
 use crate::{ClientId, Id, NodeId, QoS, Result, Runtime, TopicFilter};
 
 type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
 
+pub mod admission;
+pub mod alarm;
+pub mod budget;
+pub mod dedup;
 pub mod default;
 pub mod error;
+pub mod evacuation;
 pub mod executor;
 pub mod fitter;
+pub mod history;
 pub mod hook;
 pub mod inflight;
 pub mod metrics;
+pub mod payload_filter;
+pub mod payload_limit;
+pub mod process;
+pub mod proxied;
+pub mod purge;
 pub mod queue;
 pub mod retain;
 pub mod session;
 pub mod stats;
 pub mod topic;
+pub mod topic_template;
 pub mod types;
 pub mod v3;
 pub mod v5;
@@ -30,6 +43,9 @@ pub mod v5;
 #[async_trait]
 pub trait Entry: Sync + Send {
     async fn try_lock(&self) -> Result<Box<dyn Entry>>;
+    ///Locks the client_id of a new connection, a client_id connected already is resolved by the
+    ///policy, the returned entry may be of a suffixed client_id, MqttError::ClientIdInUse if rejected
+    async fn try_lock_connect(&self, policy: DuplicateClientId) -> Result<Box<dyn Entry>>;
     fn id(&self) -> Id;
     fn id_same(&self) -> Option<bool>;
     async fn set(&mut self, session: Session, tx: Tx, conn: ClientInfo) -> Result<()>;
@@ -63,7 +79,8 @@ pub trait Shared: Sync + Send {
     ///Route and dispense publish message
     async fn forwards(&self, from: From, publish: Publish) -> Result<(), Vec<(To, From, Publish, Reason)>>;
 
-    ///Route and dispense publish message and return shared subscription relations
+    ///Route and dispense publish message and return shared subscription relations, and a relation
+    ///without a group if the publish matched other subscriptions of this node
     async fn forwards_and_get_shareds(
         &self,
         from: From,
@@ -139,6 +156,13 @@ pub trait Router: Sync + Send {
     ///
     async fn matches(&self, topic: &TopicName) -> Result<SubRelationsMap>;
 
+    ///Like matches, the payload filters of the shared subscription members are applied before
+    ///a member is chosen
+    #[inline]
+    async fn matches_publish(&self, publish: &Publish) -> Result<SubRelationsMap> {
+        self.matches(publish.topic()).await
+    }
+
     ///Check online or offline
     #[inline]
     async fn is_online(&self, node_id: NodeId, client_id: &str) -> bool {
@@ -241,4 +265,8 @@ pub trait RetainStorage: Sync + Send {
 
     ///
     fn max(&self) -> isize;
+
+    ///Estimated bytes of the retained messages held in the memory of this node, sampled into the
+    ///memory budget, 0 if they are held elsewhere
+    fn bytes(&self) -> usize;
 }
@@ -0,0 +1,368 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicIsize, Ordering as AtomicOrdering};
+use std::sync::Arc;
+
+use once_cell::sync::OnceCell;
+use serde_json::Value;
+
+use crate::{MqttError, Result};
+
+static FILTERS: AtomicIsize = AtomicIsize::new(0);
+
+///Some subscription of this node has a payload filter, the payloads are not parsed otherwise
+#[inline]
+pub fn is_used() -> bool {
+    FILTERS.load(AtomicOrdering::Relaxed) > 0
+}
+
+#[inline]
+pub(crate) fn count(n: isize) {
+    FILTERS.fetch_add(n, AtomicOrdering::Relaxed);
+}
+
+///The payload of a message parsed as JSON on first use, so that it is parsed once for the payload
+///filters of all the subscriptions the message is delivered to
+pub struct PayloadJson<'a> {
+    payload: &'a [u8],
+    json: OnceCell<Option<Value>>,
+}
+
+impl<'a> PayloadJson<'a> {
+    #[inline]
+    pub fn new(payload: &'a [u8]) -> Self {
+        Self { payload, json: OnceCell::new() }
+    }
+
+    ///None if the payload is not JSON
+    #[inline]
+    pub fn get(&self) -> Option<&Value> {
+        self.json.get_or_init(|| serde_json::from_slice(self.payload).ok()).as_ref()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    ///The value at the path exists and is neither false nor null
+    Truthy(Vec<Segment>),
+    Compare(Vec<Segment>, Op, Value),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+///A predicate on the JSON payload of a message, such as `$.temperature > 30 && $.unit == "C"`.
+///
+///A path starts with $, the whole payload, followed by .key, ["key"] or [index]. A path compared
+///with ==, !=, >, >=, < or <= to a number, a 'string' or "string", true, false or null is true if
+///the value at the path compares so, numbers with numbers and strings with strings. A path alone
+///is true if the value at it exists and is neither false nor null. The predicates are combined
+///with !, && and ||, && before ||, and grouped with parentheses. A payload that is not JSON does
+///not match
+#[derive(Clone)]
+pub struct PayloadFilter {
+    expr: Arc<Expr>,
+    source: Arc<String>,
+}
+
+impl PayloadFilter {
+    #[inline]
+    pub fn matches(&self, payload: &PayloadJson) -> bool {
+        match payload.get() {
+            Some(json) => self.expr.eval(json),
+            None => false,
+        }
+    }
+
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+}
+
+impl FromStr for PayloadFilter {
+    type Err = MqttError;
+
+    #[inline]
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parser = Parser { input: s.as_bytes(), pos: 0 };
+        let expr = parser.or()?;
+        parser.skip_ws();
+        if parser.pos < parser.input.len() {
+            return Err(parser.error("unexpected input"));
+        }
+        Ok(Self { expr: Arc::new(expr), source: Arc::new(s.to_owned()) })
+    }
+}
+
+impl fmt::Debug for PayloadFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PayloadFilter({:?})", self.source)
+    }
+}
+
+impl Expr {
+    fn eval(&self, json: &Value) -> bool {
+        match self {
+            Expr::Truthy(path) => {
+                !matches!(lookup(json, path), None | Some(Value::Null) | Some(Value::Bool(false)))
+            }
+            Expr::Compare(path, op, literal) => match lookup(json, path) {
+                Some(value) => compare(value, *op, literal),
+                None => false,
+            },
+            Expr::Not(expr) => !expr.eval(json),
+            Expr::And(l, r) => l.eval(json) && r.eval(json),
+            Expr::Or(l, r) => l.eval(json) || r.eval(json),
+        }
+    }
+}
+
+#[inline]
+fn lookup<'a>(json: &'a Value, path: &[Segment]) -> Option<&'a Value> {
+    path.iter().try_fold(json, |value, segment| match segment {
+        Segment::Key(key) => value.get(key.as_str()),
+        Segment::Index(idx) => value.get(*idx),
+    })
+}
+
+#[inline]
+fn compare(value: &Value, op: Op, literal: &Value) -> bool {
+    let ordering = match (value, literal) {
+        (Value::Number(a), Value::Number(b)) => match (a.as_f64(), b.as_f64()) {
+            (Some(a), Some(b)) => a.partial_cmp(&b),
+            _ => None,
+        },
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (a, b) => match op {
+            Op::Eq => return a == b,
+            Op::Ne => return a != b,
+            _ => None,
+        },
+    };
+    match (ordering, op) {
+        (Some(ordering), Op::Eq) => ordering == Ordering::Equal,
+        (Some(ordering), Op::Ne) => ordering != Ordering::Equal,
+        (Some(ordering), Op::Gt) => ordering == Ordering::Greater,
+        (Some(ordering), Op::Ge) => ordering != Ordering::Less,
+        (Some(ordering), Op::Lt) => ordering == Ordering::Less,
+        (Some(ordering), Op::Le) => ordering != Ordering::Greater,
+        (None, Op::Ne) => true,
+        (None, _) => false,
+    }
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    #[inline]
+    fn error(&self, msg: &str) -> MqttError {
+        MqttError::from(format!("payload filter, {} at {}", msg, self.pos))
+    }
+
+    #[inline]
+    fn skip_ws(&mut self) {
+        while self.pos < self.input.len() && self.input[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    #[inline]
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_ws();
+        self.input.get(self.pos).copied()
+    }
+
+    #[inline]
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_ws();
+        if self.input[self.pos..].starts_with(token.as_bytes()) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.eat("||") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        while self.eat("&&") {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.eat("(") {
+            let expr = self.or()?;
+            if !self.eat(")") {
+                return Err(self.error("expected )"));
+            }
+            return Ok(expr);
+        }
+        if self.peek() == Some(b'!') && self.input.get(self.pos + 1) != Some(&b'=') {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        let path = self.path()?;
+        let op = if self.eat("==") {
+            Op::Eq
+        } else if self.eat("!=") {
+            Op::Ne
+        } else if self.eat(">=") {
+            Op::Ge
+        } else if self.eat("<=") {
+            Op::Le
+        } else if self.eat(">") {
+            Op::Gt
+        } else if self.eat("<") {
+            Op::Lt
+        } else {
+            return Ok(Expr::Truthy(path));
+        };
+        Ok(Expr::Compare(path, op, self.literal()?))
+    }
+
+    fn path(&mut self) -> Result<Vec<Segment>> {
+        if !self.eat("$") {
+            return Err(self.error("expected a path starting with $"));
+        }
+        let mut path = Vec::new();
+        loop {
+            match self.input.get(self.pos) {
+                Some(b'.') => {
+                    self.pos += 1;
+                    let start = self.pos;
+                    while self.pos < self.input.len()
+                        && (self.input[self.pos].is_ascii_alphanumeric()
+                            || self.input[self.pos] == b'_'
+                            || self.input[self.pos] == b'-')
+                    {
+                        self.pos += 1;
+                    }
+                    if start == self.pos {
+                        return Err(self.error("expected a key"));
+                    }
+                    path.push(Segment::Key(
+                        String::from_utf8_lossy(&self.input[start..self.pos]).into_owned(),
+                    ));
+                }
+                Some(b'[') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(b'"') | Some(b'\'') => path.push(Segment::Key(self.string()?)),
+                        _ => match self.number()? {
+                            Value::Number(n) if n.is_u64() => {
+                                path.push(Segment::Index(n.as_u64().unwrap_or_default() as usize))
+                            }
+                            _ => return Err(self.error("expected an index")),
+                        },
+                    }
+                    if !self.eat("]") {
+                        return Err(self.error("expected ]"));
+                    }
+                }
+                _ => return Ok(path),
+            }
+        }
+    }
+
+    fn literal(&mut self) -> Result<Value> {
+        match self.peek() {
+            Some(b'"') | Some(b'\'') => Ok(Value::String(self.string()?)),
+            _ if self.eat("true") => Ok(Value::Bool(true)),
+            _ if self.eat("false") => Ok(Value::Bool(false)),
+            _ if self.eat("null") => Ok(Value::Null),
+            _ => self.number(),
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let quote = self.input[self.pos];
+        self.pos += 1;
+        let mut s = Vec::new();
+        while let Some(&c) = self.input.get(self.pos) {
+            self.pos += 1;
+            match c {
+                b'\\' => match self.input.get(self.pos) {
+                    Some(&escaped) => {
+                        s.push(escaped);
+                        self.pos += 1;
+                    }
+                    None => break,
+                },
+                c if c == quote => return String::from_utf8(s).map_err(|_| self.error("invalid string")),
+                c => s.push(c),
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+
+    fn number(&mut self) -> Result<Value> {
+        self.skip_ws();
+        let start = self.pos;
+        while self.pos < self.input.len()
+            && (self.input[self.pos].is_ascii_digit() || b"+-.eE".contains(&self.input[self.pos]))
+        {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.input[start..self.pos])
+            .ok()
+            .and_then(|n| serde_json::from_str::<serde_json::Number>(n).ok())
+            .map(Value::Number)
+            .ok_or_else(|| self.error("expected a literal"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn matches() {
+        use super::{PayloadFilter, PayloadJson};
+        use std::str::FromStr;
+
+        let payload = PayloadJson::new(
+            br#"{"temperature": 31.5, "unit": "C", "tags": ["a", "b"], "ok": true, "site": {"id": 7}}"#,
+        );
+        let matches = |filter: &str| PayloadFilter::from_str(filter).unwrap().matches(&payload);
+        assert!(matches("$.temperature > 30"));
+        assert!(!matches("$.temperature <= 30"));
+        assert!(matches(r#"$.temperature > 30 && $.unit == "C""#));
+        assert!(matches("$.unit == 'F' || $.site.id >= 7"));
+        assert!(matches(r#"$.tags[1] == "b" && $["unit"] != 'F'"#));
+        assert!(matches("$.ok && !$.missing"));
+        assert!(!matches("!($.ok || $.missing)"));
+        assert!(!matches("$.unit > 3"));
+        assert!(!PayloadFilter::from_str("$.temperature > 30").unwrap().matches(&PayloadJson::new(b"31")));
+        assert!(PayloadFilter::from_str("temperature > 30").is_err());
+        assert!(PayloadFilter::from_str("$.temperature >").is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use once_cell::sync::OnceCell;
+use parking_lot::RwLock;
+
+use crate::broker::topic::{Topic, TopicTree};
+use crate::broker::types::*;
+use crate::settings::Mqtt;
+use crate::Runtime;
+
+///Maximum payload sizes of the topics matching the topic filters of mqtt.topic_payload_limits,
+///with the number of publishes rejected by each topic filter on this node
+pub struct PayloadLimits {
+    ///Index of the limit of each topic filter
+    tree: TopicTree<usize>,
+    limits: Vec<(TopicFilter, usize, AtomicUsize)>,
+}
+
+impl PayloadLimits {
+    #[inline]
+    fn holder() -> &'static RwLock<Arc<PayloadLimits>> {
+        static INSTANCE: OnceCell<RwLock<Arc<PayloadLimits>>> = OnceCell::new();
+        INSTANCE.get_or_init(|| RwLock::new(Arc::new(Self::new(&Runtime::instance().settings.mqtt, None))))
+    }
+
+    #[inline]
+    pub fn instance() -> Arc<PayloadLimits> {
+        Self::holder().read().clone()
+    }
+
+    ///Apply the limits of a reloaded configuration, the topic filters kept keep their violations
+    #[inline]
+    pub fn reload(cfg: &Mqtt) {
+        let mut holder = Self::holder().write();
+        *holder = Arc::new(Self::new(cfg, Some(&**holder)));
+    }
+
+    fn new(cfg: &Mqtt, prev: Option<&PayloadLimits>) -> Self {
+        let mut tree = TopicTree::default();
+        let mut limits = Vec::new();
+        for (topic_filter, max_size) in cfg.topic_payload_limits.iter() {
+            match Topic::from_str(topic_filter) {
+                Ok(topic) => {
+                    let violations = prev
+                        .and_then(|prev| prev.limits.iter().find(|(tf, _, _)| tf[..] == topic_filter[..]))
+                        .map(|(_, _, violations)| violations.load(Ordering::SeqCst))
+                        .unwrap_or_default();
+                    tree.insert(&topic, limits.len());
+                    limits.push((
+                        TopicFilter::from(topic_filter.as_str()),
+                        *max_size,
+                        AtomicUsize::new(violations),
+                    ));
+                }
+                Err(e) => {
+                    log::warn!("topic_payload_limits, invalid topic filter: {:?}, {:?}", topic_filter, e)
+                }
+            }
+        }
+        Self { tree, limits }
+    }
+
+    ///Returns the topic filter and its limit if the payload exceeds the smallest limit of the
+    ///topic filters matching the topic, and counts the violation
+    #[inline]
+    pub fn check(&self, topic: &str, payload_len: usize) -> Option<(TopicFilter, usize)> {
+        if self.limits.is_empty() {
+            return None;
+        }
+        let topic = Topic::from_str(topic).ok()?;
+        let idx = self
+            .tree
+            .matches(&topic)
+            .iter()
+            .flat_map(|(_, idxs)| idxs.into_iter().copied())
+            .min_by_key(|idx| self.limits[*idx].1)?;
+        let (topic_filter, max_size, violations) = &self.limits[idx];
+        if payload_len > *max_size {
+            violations.fetch_add(1, Ordering::SeqCst);
+            Some((topic_filter.clone(), *max_size))
+        } else {
+            None
+        }
+    }
+
+    ///The topic filters with their limit and the number of violations
+    #[inline]
+    pub fn violations(&self) -> Vec<(TopicFilter, usize, usize)> {
+        self.limits
+            .iter()
+            .map(|(topic_filter, max_size, violations)| {
+                (topic_filter.clone(), *max_size, violations.load(Ordering::SeqCst))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,121 @@
+//! Resource usage of the broker process, read from procfs on Linux, zero on other platforms.
+
+use std::time::{Duration, Instant};
+
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+
+///Clock ticks per second of the times in /proc/self/stat (USER_HZ) if the kernel does not tell
+#[cfg(target_os = "linux")]
+const USER_HZ: f64 = 100.0;
+
+///Type of the AT_CLKTCK entry of the auxiliary vector, the value of sysconf(_SC_CLK_TCK)
+#[cfg(target_os = "linux")]
+const AT_CLKTCK: usize = 17;
+
+///CPU usage of the process in percent since the previous sample, one fully used core is 100%
+#[inline]
+pub fn cpu_usage() -> f64 {
+    static LAST: OnceCell<Mutex<(Instant, u64, f64)>> = OnceCell::new();
+    let ticks = if let Some(ticks) = cpu_ticks() {
+        ticks
+    } else {
+        return 0.0;
+    };
+    let now = Instant::now();
+    let mut last = LAST.get_or_init(|| Mutex::new((now, ticks, 0.0))).lock();
+    let elapsed = now.duration_since(last.0);
+    //Keep the previous value if sampled too frequently
+    if elapsed >= Duration::from_secs(1) {
+        let usage = ticks.saturating_sub(last.1) as f64 / ticks_per_sec() / elapsed.as_secs_f64() * 100.0;
+        *last = (now, ticks, usage);
+    }
+    last.2
+}
+
+///Resident set size of the process, in bytes
+#[inline]
+pub fn memory_rss() -> u64 {
+    #[cfg(target_os = "linux")]
+    {
+        std::fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| {
+                status
+                    .lines()
+                    .find(|l| l.starts_with("VmRSS:"))
+                    .and_then(|l| l.split_whitespace().nth(1))
+                    .and_then(|kb| kb.parse::<u64>().ok())
+            })
+            .map(|kb| kb * 1024)
+            .unwrap_or_default()
+    }
+    #[cfg(not(target_os = "linux"))]
+    0
+}
+
+///(open file descriptors, limit of the open file descriptors)
+#[inline]
+pub fn fd_usage() -> Option<(usize, usize)> {
+    #[cfg(target_os = "linux")]
+    {
+        let opens = std::fs::read_dir("/proc/self/fd").ok()?.count();
+        let limits = std::fs::read_to_string("/proc/self/limits").ok()?;
+        let max = limits
+            .lines()
+            .find(|l| l.starts_with("Max open files"))?
+            .split_whitespace()
+            .nth(3)?
+            .parse()
+            .ok()?;
+        Some((opens, max))
+    }
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+///utime + stime of the process, in clock ticks
+#[inline]
+fn cpu_ticks() -> Option<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+        //The fields after the command name, which is in parentheses and may contain spaces
+        let mut fields = stat.rsplit_once(')')?.1.split_whitespace();
+        let utime = fields.nth(11)?.parse::<u64>().ok()?;
+        let stime = fields.next()?.parse::<u64>().ok()?;
+        Some(utime + stime)
+    }
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+#[inline]
+fn ticks_per_sec() -> f64 {
+    #[cfg(target_os = "linux")]
+    {
+        static CLK_TCK: OnceCell<f64> = OnceCell::new();
+        *CLK_TCK.get_or_init(|| clk_tck().unwrap_or(USER_HZ))
+    }
+    #[cfg(not(target_os = "linux"))]
+    1.0
+}
+
+///sysconf(_SC_CLK_TCK), read from the auxiliary vector given to the process by the kernel
+#[cfg(target_os = "linux")]
+#[inline]
+fn clk_tck() -> Option<f64> {
+    const WORD: usize = std::mem::size_of::<usize>();
+    let auxv = std::fs::read("/proc/self/auxv").ok()?;
+    auxv.chunks_exact(WORD * 2)
+        .map(|entry| {
+            let (typ, value) = entry.split_at(WORD);
+            (
+                usize::from_ne_bytes(typ.try_into().unwrap_or_default()),
+                usize::from_ne_bytes(value.try_into().unwrap_or_default()),
+            )
+        })
+        .find(|(typ, _)| *typ == AT_CLKTCK)
+        .map(|(_, hz)| hz as f64)
+        .filter(|hz| *hz > 0.0)
+}
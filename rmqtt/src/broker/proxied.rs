@@ -0,0 +1,60 @@
+use std::net::SocketAddr;
+
+use once_cell::sync::OnceCell;
+
+use crate::broker::types::DashMap;
+
+///The real addresses of the clients whose connections to a listener are made on their behalf by
+///this process, such as by a transport plugin, keyed by the local address of the connection. The
+///handshake sees the client at its real address, so that the ACL rules, the limits and the
+///connection info do not all see the address of this node.
+pub struct ProxiedPeers {
+    addrs: DashMap<SocketAddr, SocketAddr>,
+}
+
+impl ProxiedPeers {
+    #[inline]
+    pub fn instance() -> &'static ProxiedPeers {
+        static INSTANCE: OnceCell<ProxiedPeers> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { addrs: DashMap::default() })
+    }
+
+    ///Registered before the client sends its CONNECT, on the connection of local_addr
+    #[inline]
+    pub fn register(&self, local_addr: SocketAddr, peer_addr: SocketAddr) {
+        self.addrs.insert(local_addr, peer_addr);
+    }
+
+    ///Unregistered once the connection of local_addr is closed
+    #[inline]
+    pub fn unregister(&self, local_addr: &SocketAddr) {
+        self.addrs.remove(local_addr);
+    }
+
+    ///The real address of the client of a connection accepted from remote_addr
+    #[inline]
+    pub fn resolve(&self, remote_addr: SocketAddr) -> SocketAddr {
+        if self.addrs.is_empty() {
+            return remote_addr;
+        }
+        self.addrs.get(&remote_addr).map(|addr| *addr.value()).unwrap_or(remote_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn resolve() {
+        use super::ProxiedPeers;
+
+        let peers = ProxiedPeers::instance();
+        let local = "127.0.0.1:50001".parse().unwrap();
+        let peer = "203.0.113.7:41000".parse().unwrap();
+        assert_eq!(peers.resolve(local), local);
+        peers.register(local, peer);
+        assert_eq!(peers.resolve(local), peer);
+        assert_eq!(peers.resolve("127.0.0.1:50002".parse().unwrap()), "127.0.0.1:50002".parse().unwrap());
+        peers.unregister(&local);
+        assert_eq!(peers.resolve(local), local);
+    }
+}
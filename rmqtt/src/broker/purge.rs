@@ -0,0 +1,83 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use once_cell::sync::OnceCell;
+
+use crate::broker::types::*;
+use crate::metrics::DroppedReason;
+use crate::{Runtime, Session};
+
+///Purges the messages older than mqtt.offline_message_max_age from the queues of the offline
+///sessions of this node, independently of the MQTT 5.0 message expiry.
+pub struct OfflinePurge {
+    purged: AtomicUsize,
+}
+
+impl OfflinePurge {
+    #[inline]
+    pub fn instance() -> &'static OfflinePurge {
+        static INSTANCE: OnceCell<OfflinePurge> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { purged: AtomicUsize::new(0) })
+    }
+
+    ///Start the periodic purge
+    pub fn start(&'static self) {
+        let cfg = &Runtime::instance().settings.mqtt;
+        if cfg.offline_message_max_age.is_zero() || cfg.offline_message_purge_interval.is_zero() {
+            return;
+        }
+        let interval = cfg.offline_message_purge_interval;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let purged = self.purge().await;
+                if purged > 0 {
+                    log::debug!("purged {} aged offline messages", purged);
+                }
+            }
+        });
+    }
+
+    ///Total number of the purged messages
+    #[inline]
+    pub fn purged(&self) -> usize {
+        self.purged.load(Ordering::Relaxed)
+    }
+
+    async fn purge(&self) -> usize {
+        let max_age =
+            Runtime::instance().settings.mqtt.offline_message_max_age.as_millis() as TimestampMillis;
+        //Collected first, so that no lock of the shared state is held across the hooks
+        let sessions = Runtime::instance()
+            .extends
+            .shared()
+            .await
+            .iter()
+            .filter(|entry| !entry.is_connected())
+            .filter_map(|entry| entry.session())
+            .collect::<Vec<Session>>();
+
+        let mut total = 0;
+        for s in sessions {
+            let now = chrono::Local::now().timestamp_millis();
+            let removed =
+                s.deliver_queue.remove_if(|(_, p): &(From, Publish)| now - p.create_time() >= max_age);
+            if removed.is_empty() {
+                continue;
+            }
+            s.stats.offline_purged_add(removed.len());
+            total += removed.len();
+            for (from, p) in removed {
+                //hook, message_dropped
+                Runtime::instance()
+                    .extends
+                    .hook_mgr()
+                    .await
+                    .message_dropped(Some(s.id.clone()), from, p, Reason::from_static(DroppedReason::EXPIRED))
+                    .await;
+            }
+        }
+        self.purged.fetch_add(total, Ordering::Relaxed);
+        total
+    }
+}
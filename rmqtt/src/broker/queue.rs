@@ -1,12 +1,13 @@
+use std::collections::{BTreeMap, VecDeque};
 use std::num::NonZeroU32;
 use std::pin::Pin;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::Duration;
 
 use anyhow::Result;
-use crossbeam::queue::SegQueue;
 use futures::channel::mpsc;
 use futures::SinkExt;
 use futures::Stream;
@@ -16,6 +17,7 @@ use governor::{
     state::{InMemoryState, NotKeyed},
     Quota, RateLimiter, RatelimitedStream,
 };
+use parking_lot::Mutex;
 
 type DirectLimiter = RateLimiter<NotKeyed, InMemoryState, DefaultClock>;
 
@@ -24,7 +26,7 @@ pub type Receiver<'a, T> = RatelimitedStream<'a, ReceiverStream<T>, InMemoryStat
 pub enum Policy {
     //Discard current value
     Current,
-    //Discard earliest value
+    //Discard earliest value, of the lowest priority
     Early,
 }
 
@@ -72,7 +74,7 @@ impl<T> Sender<T> {
             match (self.policy_fn)(&v) {
                 Policy::Current => return Err(v),
                 Policy::Early => {
-                    let removed = self.queue.pop();
+                    let removed = self.queue.pop_lowest();
                     if let Err(v) = self.queue.push(v) {
                         log::warn!("queue is full, queue len is {}", self.queue.len());
                         return Err(v);
@@ -99,12 +101,20 @@ impl<T> Sender<T> {
 pub struct ReceiverStream<T> {
     rx: mpsc::Receiver<()>,
     queue: Arc<Queue<T>>,
+    dry_run: Option<(Arc<DirectLimiter>, fn())>,
 }
 
 impl<T> Stream for ReceiverStream<T> {
     type Item = Option<T>;
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let result: Option<_> = futures::ready!(Pin::new(&mut self.rx).poll_next(cx));
+        if result.is_some() {
+            if let Some((l, on_exceeded)) = self.dry_run.as_ref() {
+                if l.check().is_err() {
+                    on_exceeded();
+                }
+            }
+        }
         Poll::Ready(match result {
             Some(_) => Some(self.queue.pop()),
             None => None,
@@ -114,29 +124,48 @@ impl<T> Stream for ReceiverStream<T> {
 
 pub struct Limiter {
     l: DirectLimiter,
+    //in dry run mode, l lets everything through and the values over this limit are only counted
+    dry_run: Option<(Arc<DirectLimiter>, fn())>,
 }
 
 impl Limiter {
+    ///burst values per replenish_n_per, at most burst at once
     #[inline]
     pub fn new(burst: NonZeroU32, replenish_n_per: Duration) -> Self {
+        Self::with_burst(burst, replenish_n_per, burst)
+    }
+
+    ///rate values per replenish_n_per, at most burst at once after being idle
+    #[inline]
+    pub fn with_burst(rate: NonZeroU32, replenish_n_per: Duration, burst: NonZeroU32) -> Self {
         assert!(
             replenish_n_per.as_nanos() > 0,
             "illegal parameter, replenish_n_per is {:?}",
             replenish_n_per
         );
 
-        let period = replenish_n_per.as_nanos() as u64 / burst.get() as u64;
+        let period = replenish_n_per.as_nanos() as u64 / rate.get() as u64;
         let period = if period > 0 { Duration::from_nanos(period) } else { Duration::from_nanos(1) };
-        log::debug!("burst: {:?}, {:?}, {:?}", burst, replenish_n_per, period);
+        log::debug!("rate: {:?}, {:?}, {:?}, burst: {:?}", rate, replenish_n_per, period, burst);
         let q = Quota::with_period(period).unwrap().allow_burst(burst);
         let l = RateLimiter::direct(q);
-        Self { l }
+        Self { l, dry_run: None }
+    }
+
+    ///The values over the limit are not delayed, on_exceeded is called for each of them
+    #[inline]
+    pub fn dry_run(self, on_exceeded: fn()) -> Self {
+        let unlimited = Quota::with_period(Duration::from_nanos(1))
+            .unwrap()
+            .allow_burst(NonZeroU32::new(u32::MAX).unwrap());
+        Self { l: RateLimiter::direct(unlimited), dry_run: Some((Arc::new(self.l), on_exceeded)) }
     }
 
     #[inline]
     pub fn channel<T>(&self, queue: Arc<Queue<T>>) -> (Sender<T>, Receiver<'_, T>) {
         let (tx, rx) = mpsc::channel::<()>((queue.capacity() as f64 * 1.5) as usize);
-        let s = ReceiverStream { rx, queue: queue.clone() }.ratelimit_stream(&self.l);
+        let s = ReceiverStream { rx, queue: queue.clone(), dry_run: self.dry_run.clone() }
+            .ratelimit_stream(&self.l);
         (0..queue.len()).for_each(|_| {
             if let Err(e) = tx.clone().try_send(()) {
                 //send offline message
@@ -147,36 +176,188 @@ impl Limiter {
     }
 }
 
+///Called with each value pushed into and popped out of the queue
+pub type MeterFn<T> = fn(&T);
+
+///Priority of a value, the higher values are popped first
+pub type PriorityFn<T> = fn(&T) -> usize;
+
 pub struct Queue<T> {
     cap: usize,
-    inner: SegQueue<T>,
+    //the values of each priority present, changed under the lock so that the values keep their
+    //order, a priority is removed once it has no values left
+    inner: Mutex<BTreeMap<usize, VecDeque<T>>>,
+    len: AtomicUsize,
+    meter: Option<(MeterFn<T>, MeterFn<T>)>,
+    priority: Option<PriorityFn<T>>,
+    max_consecutive: usize,
+    //values of the higher priorities popped in a row while a lower priority was waiting
+    consecutive: AtomicUsize,
+    //the lower priority served next once max_consecutive is reached, they take turns
+    next_lower: AtomicUsize,
 }
 
 impl<T> Drop for Queue<T> {
     #[inline]
     fn drop(&mut self) {
         log::debug!("Queue Drop ... len: {}", self.len());
+        if self.meter.is_some() {
+            while self.pop().is_some() {}
+        }
     }
 }
 
 impl<T> Queue<T> {
     #[inline]
     pub fn new(cap: usize) -> Self {
-        Self { cap, inner: SegQueue::new() }
+        Self {
+            cap,
+            inner: Mutex::new(BTreeMap::new()),
+            len: AtomicUsize::new(0),
+            meter: None,
+            priority: None,
+            max_consecutive: 0,
+            consecutive: AtomicUsize::new(0),
+            next_lower: AtomicUsize::new(0),
+        }
+    }
+
+    ///The values remaining in the queue are popped, and metered, when it is dropped
+    #[inline]
+    pub fn with_meter(cap: usize, on_push: MeterFn<T>, on_pop: MeterFn<T>) -> Self {
+        Self { meter: Some((on_push, on_pop)), ..Self::new(cap) }
+    }
+
+    ///The values of a higher priority are popped first, only the priorities of the values in the
+    ///queue take memory
+    #[inline]
+    pub fn priorities(mut self, f: PriorityFn<T>) -> Self {
+        self.priority = Some(f);
+        self
+    }
+
+    ///After max_consecutive values of the higher priorities are popped in a row while a lower
+    ///priority is waiting, the next pop takes the earliest value of a lower priority, the waiting
+    ///lower priorities take turns so that none is starved, 0 means strict priority
+    #[inline]
+    pub fn max_consecutive(mut self, max_consecutive: usize) -> Self {
+        self.max_consecutive = max_consecutive;
+        self
     }
 
     #[inline]
     pub fn push(&self, v: T) -> Result<(), T> {
-        if self.inner.len() > self.cap {
+        if self.len() > self.cap {
             return Err(v);
         }
-        self.inner.push(v);
+        if let Some((on_push, _)) = self.meter {
+            on_push(&v);
+        }
+        let priority = self.priority.map(|f| f(&v)).unwrap_or_default();
+        self.inner.lock().entry(priority).or_default().push_back(v);
+        self.len.fetch_add(1, Ordering::SeqCst);
         Ok(())
     }
 
+    ///Pop the earliest value of the highest priority, or of a lower priority, in turn, once they
+    ///have waited for max_consecutive values
     #[inline]
     pub fn pop(&self) -> Option<T> {
-        self.inner.pop()
+        let v = {
+            let mut inner = self.inner.lock();
+            let highest = *inner.keys().next_back()?;
+            let mut level = highest;
+            if self.max_consecutive > 0 && inner.len() > 1 {
+                if self.consecutive.load(Ordering::SeqCst) >= self.max_consecutive {
+                    self.consecutive.store(0, Ordering::SeqCst);
+                    let next = self.next_lower.load(Ordering::SeqCst).min(highest);
+                    let lower = inner.range(next..highest).chain(inner.range(..next)).map(|(l, _)| *l).next();
+                    if let Some(lower) = lower {
+                        self.next_lower.store(lower + 1, Ordering::SeqCst);
+                        level = lower;
+                    }
+                } else {
+                    self.consecutive.fetch_add(1, Ordering::SeqCst);
+                }
+            } else {
+                self.consecutive.store(0, Ordering::SeqCst);
+            }
+            Self::pop_level(&mut inner, level)
+        };
+        v.map(|v| self.popped(v))
+    }
+
+    ///Pop the earliest value of the lowest priority
+    #[inline]
+    pub fn pop_lowest(&self) -> Option<T> {
+        let v = {
+            let mut inner = self.inner.lock();
+            let lowest = *inner.keys().next()?;
+            Self::pop_level(&mut inner, lowest)
+        };
+        v.map(|v| self.popped(v))
+    }
+
+    #[inline]
+    fn pop_level(inner: &mut BTreeMap<usize, VecDeque<T>>, level: usize) -> Option<T> {
+        let q = inner.get_mut(&level)?;
+        let v = q.pop_front();
+        if q.is_empty() {
+            inner.remove(&level);
+        }
+        v
+    }
+
+    ///Remove the values for which f returns true, the others keep their order, the queue is
+    ///rebuilt under its lock, the values pushed meanwhile wait for it
+    #[inline]
+    pub fn remove_if<F: FnMut(&T) -> bool>(&self, mut f: F) -> Vec<T> {
+        let mut removed = Vec::new();
+        {
+            let mut inner = self.inner.lock();
+            for q in inner.values_mut() {
+                let mut kept = VecDeque::with_capacity(q.len());
+                for v in q.drain(..) {
+                    if f(&v) {
+                        removed.push(v);
+                    } else {
+                        kept.push_back(v);
+                    }
+                }
+                *q = kept;
+            }
+            inner.retain(|_, q| !q.is_empty());
+        }
+        removed.into_iter().map(|v| self.popped(v)).collect()
+    }
+
+    ///Removes all the values, in the order of their priorities, highest first
+    #[inline]
+    pub fn drain(&self) -> Vec<T> {
+        let inner = std::mem::take(&mut *self.inner.lock());
+        let drained = inner.into_values().rev().flatten().collect::<Vec<_>>();
+        drained.into_iter().map(|v| self.popped(v)).collect()
+    }
+
+    ///Maps the values in the order of their priorities, highest first, the queue keeps them. They
+    ///are mapped under the lock, f is to be cheap
+    #[inline]
+    pub fn inspect<R, F: FnMut(&T) -> R>(&self, mut f: F) -> Vec<R> {
+        let inner = self.inner.lock();
+        let mut mapped = Vec::with_capacity(self.len());
+        for q in inner.values().rev() {
+            mapped.extend(q.iter().map(&mut f));
+        }
+        mapped
+    }
+
+    #[inline]
+    fn popped(&self, v: T) -> T {
+        self.len.fetch_sub(1, Ordering::SeqCst);
+        if let Some((_, on_pop)) = self.meter {
+            on_pop(&v);
+        }
+        v
     }
 
     #[inline]
@@ -186,7 +367,7 @@ impl<T> Queue<T> {
 
     #[inline]
     pub fn len(&self) -> usize {
-        self.inner.len()
+        self.len.load(Ordering::SeqCst)
     }
 
     #[inline]
@@ -196,6 +377,86 @@ impl<T> Queue<T> {
 }
 
 mod test {
+    #[test]
+    fn priorities() {
+        use super::Queue;
+
+        let q = Queue::<u64>::new(100).priorities(|v: &u64| (*v % 10) as usize);
+        for v in [10, 21, 32, 11, 42, 20] {
+            q.push(v).unwrap();
+        }
+        assert_eq!(q.len(), 6);
+        assert_eq!(q.pop_lowest(), Some(10));
+        assert_eq!(q.pop(), Some(32));
+        assert_eq!(q.pop(), Some(42));
+        assert_eq!(q.pop(), Some(21));
+        assert_eq!(q.pop(), Some(11));
+        assert_eq!(q.pop(), Some(20));
+        assert_eq!(q.pop(), None);
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn max_consecutive() {
+        use super::Queue;
+
+        let q = Queue::<u64>::new(100).priorities(|v: &u64| (*v % 10) as usize).max_consecutive(2);
+        for v in [10, 20, 12, 22, 32, 42, 21] {
+            q.push(v).unwrap();
+        }
+        assert_eq!(q.pop(), Some(12));
+        assert_eq!(q.pop(), Some(22));
+        assert_eq!(q.pop(), Some(10));
+        assert_eq!(q.pop(), Some(32));
+        assert_eq!(q.pop(), Some(42));
+        assert_eq!(q.pop(), Some(20));
+        assert_eq!(q.pop(), Some(21));
+        assert_eq!(q.pop(), None);
+    }
+
+    #[test]
+    fn max_consecutive_lower_turns() {
+        use super::Queue;
+
+        let q = Queue::<u64>::new(100).priorities(|v: &u64| (*v % 10) as usize).max_consecutive(1);
+        for v in [12, 22, 32, 42, 10, 20, 11, 21] {
+            q.push(v).unwrap();
+        }
+        let popped = std::iter::from_fn(|| q.pop()).collect::<Vec<_>>();
+        assert_eq!(popped, vec![12, 10, 22, 11, 32, 20, 42, 21]);
+    }
+
+    #[test]
+    fn remove_if() {
+        use super::Queue;
+
+        let q = Queue::<u64>::new(100).priorities(|v: &u64| (*v % 2) as usize);
+        for v in 1..=8 {
+            q.push(v).unwrap();
+        }
+        assert_eq!(q.remove_if(|v| *v > 5), vec![6, 8, 7]);
+        assert_eq!(q.len(), 5);
+        assert_eq!(q.pop(), Some(1));
+        assert_eq!(q.pop(), Some(3));
+        assert_eq!(q.pop(), Some(5));
+        assert_eq!(q.pop(), Some(2));
+        assert_eq!(q.pop(), Some(4));
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn drain() {
+        use super::Queue;
+
+        let q = Queue::<u64>::new(100).priorities(|v: &u64| (*v % 2) as usize);
+        for v in 1..=6 {
+            q.push(v).unwrap();
+        }
+        assert_eq!(q.drain(), vec![1, 3, 5, 2, 4, 6]);
+        assert!(q.is_empty());
+        assert_eq!(q.pop(), None);
+    }
+
     #[ntex::main]
     #[test]
     async fn channel() {
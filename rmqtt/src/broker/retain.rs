@@ -87,82 +87,158 @@ where
     #[inline]
     pub fn matches(&self, topic: &Topic) -> Vec<(Topic, V)> {
         let mut out = Vec::new();
-        self._matches(topic.levels(), Vec::new(), &mut out);
+        self.matches_with(topic, |levels, v| out.push((Topic::from(levels.to_vec()), v.clone())));
         out
     }
 
+    ///Walk the values matched by the topic filter, `f` is called with the levels of the topic and the value,
+    ///a single path buffer is reused for the walk so nothing is copied unless `f` does.
     #[inline]
-    fn _matches(&self, path: &[Level], mut sub_path: Vec<Level>, out: &mut Vec<(Topic, V)>) {
-        let add_to_out = |levels: Vec<Level>, v: V, out: &mut Vec<(Topic, V)>| {
-            out.push((Topic::from(levels), v));
-        };
-
-        //let node_map = &self.branches;
+    pub fn matches_with<F>(&self, topic: &Topic, mut f: F)
+    where
+        F: FnMut(&[Level], &V),
+    {
+        let mut path = Vec::new();
+        self._matches(topic.levels(), &mut path, &mut f);
+    }
 
-        if self.branches.is_empty() || path.is_empty() {
-            if path.is_empty() {
+    #[inline]
+    fn _matches<F>(&self, filter: &[Level], path: &mut Vec<Level>, f: &mut F)
+    where
+        F: FnMut(&[Level], &V),
+    {
+        match filter.first() {
+            None => {
                 //Precise matching
                 if let Some(v) = self.value.as_ref() {
-                    add_to_out(sub_path, v.clone(), out);
+                    f(path.as_slice(), v);
                 }
             }
-        } else if !path.is_empty() {
-            if let Some(r) = self.branches.get(&path[0]) {
-                //Precise matching
-                sub_path.push(path[0].clone());
-
-                if path.len() > 1 && path[1] == Level::MultiWildcard {
-                    //# Match parent, subscription ending with #
-                    if let Some(v) = r.value.as_ref() {
-                        add_to_out(sub_path.clone(), v.clone(), out);
+            Some(Level::MultiWildcard) => {
+                //# Match parent, subscription ending with #
+                if !path.is_empty() {
+                    if let Some(v) = self.value.as_ref() {
+                        f(path.as_slice(), v);
                     }
                 }
-                r._matches(&path[1..], sub_path, out);
-            } else if matches!(path[0], Level::SingleWildcard) {
+                //Multilayer matching
+                self._descendants(path, f);
+            }
+            Some(Level::SingleWildcard) => {
                 //Single layer matching
-                for (k, v) in self.branches.iter() {
-                    if sub_path.is_empty() && !matches!(k, Level::Blank) && k.is_metadata() {
-                        //TopicName names starting with the $character cannot be matched with topic
-                        //filters starting with wildcards (# or +)
+                for (k, child) in self.branches.iter() {
+                    if Self::is_hidden(path, k) {
                         continue;
                     }
-                    let mut sub_path = sub_path.clone();
-                    sub_path.push(k.clone());
+                    path.push(k.clone());
+                    child._matches(&filter[1..], path, f);
+                    path.pop();
+                }
+            }
+            Some(l) => {
+                if let Some(child) = self.branches.get(l) {
+                    path.push(l.clone());
+                    child._matches(&filter[1..], path, f);
+                    path.pop();
+                }
+            }
+        }
+    }
 
-                    if path.len() > 1 && path[1] == Level::MultiWildcard {
-                        //# Match parent, subscription ending with #
-                        if let Some(v) = v.value.as_ref() {
-                            add_to_out(sub_path.clone(), v.clone(), out);
-                        }
+    ///Walk all the values, including those of the topics starting with $
+    #[inline]
+    pub fn values_with<F>(&self, mut f: F)
+    where
+        F: FnMut(&V),
+    {
+        self._values_with(&mut f);
+    }
+
+    #[inline]
+    fn _values_with<F>(&self, f: &mut F)
+    where
+        F: FnMut(&V),
+    {
+        if let Some(v) = self.value.as_ref() {
+            f(v);
+        }
+        for child in self.branches.values() {
+            child._values_with(f);
+        }
+    }
+
+    #[inline]
+    fn _descendants<F>(&self, path: &mut Vec<Level>, f: &mut F)
+    where
+        F: FnMut(&[Level], &V),
+    {
+        for (k, child) in self.branches.iter() {
+            if Self::is_hidden(path, k) {
+                continue;
+            }
+            path.push(k.clone());
+            if let Some(v) = child.value.as_ref() {
+                f(path.as_slice(), v);
+            }
+            child._descendants(path, f);
+            path.pop();
+        }
+    }
+
+    ///Continues the walk of the values matched by the topic filter of `walk` for at most `budget`
+    ///nodes, `f` is called as in matches_with. The walk holds no reference into the tree, so the
+    ///tree may change before it is continued: the nodes removed meanwhile are skipped, and the ones
+    ///added under the nodes already visited are missed
+    #[inline]
+    pub fn matches_walk<F>(&self, walk: &mut MatchesWalk, mut budget: usize, mut f: F)
+    where
+        F: FnMut(&[Level], &V),
+    {
+        while budget > 0 {
+            let (path, idx) = match walk.pending.pop() {
+                Some(pending) => pending,
+                None => return,
+            };
+            budget -= 1;
+            let node = match path.iter().try_fold(self, |node, l| node.branches.get(l)) {
+                Some(node) => node,
+                None => continue,
+            };
+            match walk.filter.get(idx) {
+                None => {
+                    //Precise matching
+                    if let Some(v) = node.value.as_ref() {
+                        f(path.as_slice(), v);
                     }
-                    v._matches(&path[1..], sub_path, out);
                 }
-            } else if path[0] == Level::MultiWildcard {
-                //Multilayer matching
-                for (k, v) in self.branches.iter() {
-                    if sub_path.is_empty() && !matches!(k, Level::Blank) && k.is_metadata() {
-                        //TopicName names starting with the $character cannot be matched with topic
-                        //filters starting with wildcards (# or +)
-                        continue;
-                    }
-                    let mut sub_path = sub_path.clone();
-                    sub_path.push(k.clone());
-
-                    if v.branches.is_empty() {
-                        if let Some(v) = v.value.as_ref() {
-                            add_to_out(sub_path, v.clone(), out);
+                Some(Level::MultiWildcard) => {
+                    //# Match parent, and each descendant when it is visited
+                    if !path.is_empty() {
+                        if let Some(v) = node.value.as_ref() {
+                            f(path.as_slice(), v);
                         }
-                    } else {
-                        if let Some(v) = v.value.as_ref() {
-                            add_to_out(sub_path.clone(), v.clone(), out);
-                        }
-                        v._matches(path, sub_path, out);
+                    }
+                    walk.push_children(node, &path, idx);
+                }
+                Some(Level::SingleWildcard) => walk.push_children(node, &path, idx + 1),
+                Some(l) => {
+                    if node.branches.contains_key(l) {
+                        let mut child = path.clone();
+                        child.push(l.clone());
+                        walk.pending.push((child, idx + 1));
                     }
                 }
             }
         }
     }
 
+    //TopicName names starting with the $character cannot be matched with topic
+    //filters starting with wildcards (# or +)
+    #[inline]
+    fn is_hidden(path: &[Level], l: &Level) -> bool {
+        path.is_empty() && !matches!(l, Level::Blank) && l.is_metadata()
+    }
+
     #[inline]
     pub fn value(&self) -> Option<&V> {
         self.value.as_ref()
@@ -200,11 +276,45 @@ where
     }
 }
 
+///State of a walk of the values matched by a topic filter, see Node::matches_walk
+pub struct MatchesWalk {
+    filter: Vec<Level>,
+    //the paths of the nodes to visit, with the index of the level of the filter they are matched to
+    pending: Vec<(Vec<Level>, usize)>,
+}
+
+impl MatchesWalk {
+    #[inline]
+    pub fn new(topic: &Topic) -> Self {
+        Self { filter: topic.levels().clone(), pending: vec![(Vec::new(), 0)] }
+    }
+
+    #[inline]
+    pub fn is_done(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    #[inline]
+    fn push_children<V>(&mut self, node: &Node<V>, path: &[Level], idx: usize)
+    where
+        V: std::fmt::Debug + Clone,
+    {
+        for k in node.branches.keys() {
+            if Node::<V>::is_hidden(path, k) {
+                continue;
+            }
+            let mut child = path.to_vec();
+            child.push(k.clone());
+            self.pending.push((child, idx));
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
 
-    use super::{RetainTree, Topic};
+    use super::{MatchesWalk, RetainTree, Topic};
 
     fn match_one(tree: &RetainTree<i32>, topic_filter: &str, vs: &[i32]) -> bool {
         let mut matcheds = 0;
@@ -216,7 +326,20 @@ mod tests {
             }
             matcheds += 1;
         }
-        matcheds == vs.len()
+        matcheds == vs.len() && walk_one(tree, topic_filter, vs)
+    }
+
+    //the same values are matched when the walk is continued after every node
+    fn walk_one(tree: &RetainTree<i32>, topic_filter: &str, vs: &[i32]) -> bool {
+        let mut walk = MatchesWalk::new(&Topic::from_str(topic_filter).unwrap());
+        let mut matcheds = Vec::new();
+        while !walk.is_done() {
+            tree.matches_walk(&mut walk, 1, |_, v| matcheds.push(*v));
+        }
+        matcheds.sort();
+        let mut vs = vs.to_vec();
+        vs.sort();
+        matcheds == vs
     }
 
     #[test]
@@ -232,6 +355,20 @@ mod tests {
         assert!(match_one(&tree, "/iot/b/+", &[1, 2, 3]));
         assert!(match_one(&tree, "/x/y/z", &[4]));
         assert!(!match_one(&tree, "/x/y/z", &[1]));
+        assert!(match_one(&tree, "/iot/#", &[1, 2, 3, 123]));
+        assert!(match_one(&tree, "/iot/b/#", &[1, 2, 3, 123]));
+        assert!(match_one(&tree, "#", &[1, 2, 3, 123, 4]));
+        assert!(match_one(&tree, "+/+/b", &[123]));
+        assert!(match_one(&tree, "/+/y/#", &[4]));
+
+        tree.insert(&Topic::from_str("$SYS/a").unwrap(), 5);
+        assert!(match_one(&tree, "#", &[1, 2, 3, 123, 4]));
+        assert!(match_one(&tree, "+/a", &[]));
+        assert!(match_one(&tree, "$SYS/#", &[5]));
+        let mut vs = Vec::new();
+        tree.values_with(|v| vs.push(*v));
+        vs.sort();
+        assert_eq!(vs, vec![1, 2, 3, 4, 5, 123]);
 
         println!("1 tree.values_size: {}", tree.values_size());
         println!("1 tree.nodes_size: {}", tree.nodes_size());
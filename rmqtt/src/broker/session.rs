@@ -1,28 +1,318 @@
+use std::collections::VecDeque;
 use std::convert::AsRef;
 use std::convert::From as _f;
 use std::convert::TryFrom;
 use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::num::NonZeroU32;
 use std::ops::Deref;
 use std::rc::Rc;
-use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use futures::StreamExt;
 use ntex_mqtt::types::MQTT_LEVEL_5;
+use rust_box::dequemap::DequeMap;
 use tokio::sync::RwLock;
 use tokio::time::{Duration, Instant};
 
+use crate::broker::admission::{ConnectionSlot, TenantPublishRate};
+use crate::broker::alarm::Alarms;
+use crate::broker::budget::MemoryBudget;
 use crate::broker::inflight::{Inflight, InflightMessage, MomentStatus};
+use crate::broker::payload_filter::{PayloadFilter, PayloadJson};
+use crate::broker::payload_limit::PayloadLimits;
 use crate::broker::queue::{Limiter, Policy, Queue, Sender};
+use crate::broker::stats::{TaggedConnections, TenantStats};
+use crate::broker::topic::{Topic, TopicTree};
 use crate::broker::types::*;
-use crate::broker::{fitter::Fitter, hook::Hook};
-use crate::metrics::Metrics;
+use crate::broker::{
+    fitter::Fitter,
+    hook::{Hook, Type},
+};
+use crate::metrics::{DroppedReason, Metrics};
 use crate::settings::listener::Listener;
+use crate::telemetry::{self, Span};
 use crate::{MqttError, Result, Runtime};
 
 type MessageSender = Sender<(From, Publish)>;
 type MessageQueue = Queue<(From, Publish)>;
 
+#[inline]
+fn takeover_permits() -> &'static tokio::sync::Semaphore {
+    static INSTANCE: once_cell::sync::OnceCell<tokio::sync::Semaphore> = once_cell::sync::OnceCell::new();
+    INSTANCE.get_or_init(|| {
+        tokio::sync::Semaphore::new(Runtime::instance().settings.mqtt.max_concurrent_takeovers.max(1))
+    })
+}
+
+///Priority of a queued message, the higher of its priority user property and the highest of the
+///matching mqtt.topic_priorities
+#[inline]
+fn message_priority((_, p): &(From, Publish)) -> usize {
+    message_priorities().read().priority(p)
+}
+
+#[inline]
+pub(crate) fn message_priorities() -> &'static parking_lot::RwLock<Arc<MessagePriorities>> {
+    static INSTANCE: once_cell::sync::OnceCell<parking_lot::RwLock<Arc<MessagePriorities>>> =
+        once_cell::sync::OnceCell::new();
+    INSTANCE.get_or_init(|| {
+        parking_lot::RwLock::new(Arc::new(MessagePriorities::new(&Runtime::instance().settings.mqtt)))
+    })
+}
+
+//Maximum number of the topics whose priority is kept
+const TOPIC_PRIORITY_CACHE_MAX: usize = 100_000;
+
+///The priority settings of mqtt, parsed once, replaced by a reload of the configuration
+pub(crate) struct MessagePriorities {
+    topics: Option<TopicTree<u8>>,
+    user_property: String,
+    values: Vec<(String, u8)>,
+    user_property_max: u8,
+    //priorities of the published topics, a message delivered to many sessions is matched once
+    cache: dashmap::DashMap<TopicName, u8, ahash::RandomState>,
+}
+
+impl MessagePriorities {
+    #[inline]
+    pub(crate) fn new(cfg: &crate::settings::Mqtt) -> Self {
+        let mut topics = TopicTree::default();
+        for (topic_filter, priority) in cfg.topic_priorities.iter() {
+            match Topic::from_str(topic_filter) {
+                Ok(topic_filter) => {
+                    topics.insert(&topic_filter, *priority);
+                }
+                Err(e) => log::warn!("topic_priorities, invalid topic filter: {:?}, {:?}", topic_filter, e),
+            }
+        }
+        Self {
+            topics: if cfg.topic_priorities.is_empty() { None } else { Some(topics) },
+            user_property: cfg.priority_user_property.clone(),
+            values: cfg.priority_values.clone(),
+            user_property_max: cfg.priority_user_property_max,
+            cache: dashmap::DashMap::default(),
+        }
+    }
+
+    ///Apply the priority settings of a reloaded configuration, the messages already queued keep
+    ///their priority
+    #[inline]
+    pub(crate) fn reload(cfg: &crate::settings::Mqtt) {
+        *message_priorities().write() = Arc::new(Self::new(cfg));
+    }
+
+    #[inline]
+    fn priority(&self, p: &Publish) -> usize {
+        self.user_property_priority(p).max(self.topic_priority(&p.topic) as usize)
+    }
+
+    ///Priority of a message by the value of its mqtt.priority_user_property, at most
+    ///mqtt.priority_user_property_max
+    #[inline]
+    fn user_property_priority(&self, p: &Publish) -> usize {
+        if self.user_property.is_empty() {
+            return 0;
+        }
+        p.properties
+            .user_properties
+            .iter()
+            .filter(|(k, _)| &**k == self.user_property.as_str())
+            .filter_map(|(_, v)| {
+                self.values
+                    .iter()
+                    .find(|(value, _)| value.as_str() == &**v)
+                    .map(|(_, priority)| *priority as usize)
+                    .or_else(|| v.parse::<usize>().ok())
+            })
+            .max()
+            .unwrap_or_default()
+            .min(self.user_property_max as usize)
+    }
+
+    #[inline]
+    fn topic_priority(&self, topic: &TopicName) -> u8 {
+        let topics = if let Some(topics) = self.topics.as_ref() { topics } else { return 0 };
+        if let Some(priority) = self.cache.get(topic) {
+            return *priority;
+        }
+        let priority = match Topic::from_str(topic) {
+            Ok(t) => topics
+                .matches(&t)
+                .iter()
+                .flat_map(|(_, priorities)| priorities.into_iter().copied())
+                .max()
+                .unwrap_or_default(),
+            Err(_) => 0,
+        };
+        if self.cache.len() >= TOPIC_PRIORITY_CACHE_MAX {
+            self.cache.clear();
+        }
+        self.cache.insert(topic.clone(), priority);
+        priority
+    }
+}
+
+///Tracks how long a subscriber has been consuming too slowly
+struct SlowDetector {
+    queue_len: usize,
+    latency: Duration,
+    duration: Duration,
+    last_latency: Duration,
+    slow_since: Option<Instant>,
+    fired: bool,
+}
+
+impl SlowDetector {
+    #[inline]
+    fn new(listen_cfg: &Listener) -> Self {
+        Self {
+            queue_len: listen_cfg.slow_subscriber_queue_len,
+            latency: listen_cfg.slow_subscriber_latency,
+            duration: listen_cfg.slow_subscriber_duration,
+            last_latency: Duration::ZERO,
+            slow_since: None,
+            fired: false,
+        }
+    }
+
+    #[inline]
+    fn delivered(&mut self, create_time: TimestampMillis) {
+        let latency = chrono::Local::now().timestamp_millis() - create_time;
+        self.last_latency = Duration::from_millis(latency.max(0) as u64);
+    }
+
+    ///Returns the slow state once per slow period, after the subscriber has stayed slow for `duration`
+    #[inline]
+    fn check(&mut self, queue_len: usize, now: Instant) -> Option<SlowSubscriber> {
+        if queue_len == 0 {
+            self.last_latency = Duration::ZERO;
+        }
+        let slow = (self.queue_len > 0 && queue_len >= self.queue_len)
+            || (!self.latency.is_zero() && self.last_latency >= self.latency);
+        if !slow {
+            self.slow_since = None;
+            self.fired = false;
+            return None;
+        }
+        let duration = now.duration_since(*self.slow_since.get_or_insert(now));
+        if self.fired || duration < self.duration {
+            return None;
+        }
+        self.fired = true;
+        Some(SlowSubscriber { queue_len, latency: self.last_latency, duration })
+    }
+}
+
+///Remembers the recent QoS 1 publishes of a client, so that the repeats within the window,
+///such as those of a client retry storm, are dropped
+pub struct PublishDedup {
+    window: TimestampMillis,
+    max: usize,
+    seen: parking_lot::Mutex<DequeMap<u64, TimestampMillis>>,
+}
+
+impl PublishDedup {
+    #[inline]
+    fn new(listen_cfg: &Listener) -> Self {
+        Self {
+            window: listen_cfg.publish_dedup_window.as_millis() as TimestampMillis,
+            max: listen_cfg.publish_dedup_max,
+            seen: parking_lot::Mutex::new(DequeMap::default()),
+        }
+    }
+
+    ///Same packet id, topic and payload as a publish within the window
+    #[inline]
+    pub fn is_duplicate(&self, p: &Publish) -> bool {
+        if self.window <= 0 || !matches!(p.qos(), QoS::AtLeastOnce) {
+            return false;
+        }
+        let mut hasher = ahash::AHasher::default();
+        p.packet_id.hash(&mut hasher);
+        p.topic.hash(&mut hasher);
+        p.payload.hash(&mut hasher);
+        let key = hasher.finish();
+
+        let now = chrono::Local::now().timestamp_millis();
+        let mut seen = self.seen.lock();
+        while let Some((_, t)) = seen.front() {
+            if now - *t < self.window {
+                break;
+            }
+            seen.pop_front();
+        }
+        if seen.contains_key(&key) {
+            return true;
+        }
+        seen.insert(key, now);
+        while seen.len() > self.max.max(1) {
+            seen.pop_front();
+        }
+        false
+    }
+}
+
+///Token buckets of the publish rate limits of a connection or a tenant, refilled at the sustained
+///rates, each holds at most the burst capacity, one second of tokens by default. A message larger
+///than the remaining bytes is let through while any are left, and the bucket goes into debt, so
+///that a message larger than the bytes rate is not refused forever.
+pub struct PublishRateLimiter {
+    limit: PublishRateLimit,
+    //(messages, bytes, last refill)
+    tokens: parking_lot::Mutex<(f64, f64, Instant)>,
+}
+
+impl PublishRateLimiter {
+    #[inline]
+    pub(crate) fn new(limit: PublishRateLimit) -> Self {
+        Self {
+            limit,
+            tokens: parking_lot::Mutex::new((
+                limit.messages_burst() as f64,
+                limit.bytes_burst() as f64,
+                Instant::now(),
+            )),
+        }
+    }
+
+    #[inline]
+    pub fn limit(&self) -> PublishRateLimit {
+        self.limit
+    }
+
+    ///Time since the last message
+    #[inline]
+    pub fn idle(&self) -> Duration {
+        self.tokens.lock().2.elapsed()
+    }
+
+    ///Takes the tokens of a message, returns false if a rate limit is exceeded
+    #[inline]
+    pub fn acquire(&self, payload_len: usize) -> bool {
+        if self.limit.is_unlimited() {
+            return true;
+        }
+        let mut tokens = self.tokens.lock();
+        let (messages, bytes, last) = &mut *tokens;
+        let now = Instant::now();
+        let elapsed = now.duration_since(*last).as_secs_f64();
+        *last = now;
+        *messages =
+            (*messages + elapsed * self.limit.messages as f64).min(self.limit.messages_burst() as f64);
+        *bytes = (*bytes + elapsed * self.limit.bytes as f64).min(self.limit.bytes_burst() as f64);
+
+        if (self.limit.messages > 0 && *messages < 1.0) || (self.limit.bytes > 0 && *bytes <= 0.0) {
+            return false;
+        }
+        *messages -= 1.0;
+        *bytes -= payload_len as f64;
+        true
+    }
+}
+
 #[derive(Clone)]
 pub struct SessionState {
     pub tx: Option<Tx>,
@@ -32,6 +322,10 @@ pub struct SessionState {
     pub hook: Rc<dyn Hook>,
     pub deliver_queue_tx: Option<MessageSender>,
     pub fitter: Rc<dyn Fitter>,
+    pub publish_limiter: Rc<PublishRateLimiter>,
+    pub publish_lock: Rc<tokio::sync::Mutex<()>>,
+    ///Place of the connection in its listener, released when the last clone is dropped
+    pub slot: Rc<ConnectionSlot>,
 }
 
 impl fmt::Debug for SessionState {
@@ -55,8 +349,21 @@ impl SessionState {
         sink: Sink,
         hook: Rc<dyn Hook>,
         fitter: Rc<dyn Fitter>,
+        slot: ConnectionSlot,
     ) -> Self {
-        Self { tx: None, session, client, sink, hook, deliver_queue_tx: None, fitter }
+        let publish_limiter = Rc::new(PublishRateLimiter::new(fitter.publish_rate_limit()));
+        Self {
+            tx: None,
+            session,
+            client,
+            sink,
+            hook,
+            deliver_queue_tx: None,
+            fitter,
+            publish_limiter,
+            publish_lock: Rc::new(tokio::sync::Mutex::new(())),
+            slot: Rc::new(slot),
+        }
     }
 
     #[inline]
@@ -69,8 +376,14 @@ impl SessionState {
             Runtime::instance().stats.connections.inc();
 
             let limiter = {
-                let (burst, replenish_n_per) = state.fitter.mqueue_rate_limit();
-                Limiter::new(burst, replenish_n_per)
+                let (rate, replenish_n_per) = state.fitter.mqueue_rate_limit();
+                let burst = NonZeroU32::new(state.listen_cfg.mqueue_rate_burst).unwrap_or(rate);
+                let limiter = Limiter::with_burst(rate, replenish_n_per, burst);
+                if state.listen_cfg.mqueue_rate_limit_dry_run {
+                    limiter.dry_run(|| Metrics::instance().session_mqueue_rate_limit_dry_run_inc())
+                } else {
+                    limiter
+                }
             };
             let (deliver_queue_tx, mut deliver_queue_rx) = limiter.channel(state.deliver_queue.clone());
             //When the message queue is full, the message dropping policy is implemented
@@ -97,8 +410,21 @@ impl SessionState {
             let deliver_timeout_delay = tokio::time::sleep(Duration::from_secs(60));
             tokio::pin!(deliver_timeout_delay);
 
+            let slow_check_enable = state.listen_cfg.slow_subscriber_check();
+            let mut slow_detector = SlowDetector::new(&state.listen_cfg);
+            let mut slow_check_interval = tokio::time::interval(Duration::from_secs(1));
+
+            //With strict ordering, the messages of a publisher on a topic wait here, in order, while one
+            //of them is inflight, those of the other topics are delivered meanwhile
+            let strict_ordering = Runtime::instance().settings.mqtt.strict_ordering;
+            let mut held: VecDeque<(From, Publish)> = VecDeque::new();
+            let topic_released = state.inflight_win.read().await.topic_released();
+
             loop {
                 log::debug!("{:?} tokio::select! loop", state.id);
+                if !held.is_empty() {
+                    state.deliver_held(&mut held).await;
+                }
                 deliver_timeout_delay.as_mut().reset(
                     Instant::now()
                         + state
@@ -112,7 +438,7 @@ impl SessionState {
                 tokio::select! {
                     _ = &mut keep_alive_delay => {  //, if !keep_alive_delay.is_elapsed()
                         log::debug!("{:?} keep alive is timeout, is_elapsed: {:?}", state.id, keep_alive_delay.is_elapsed());
-                        state.client.add_disconnected_reason(Reason::from_static("Timeout(Read/Write)")).await;
+                        state.client.add_disconnected(DisconnectKind::KeepaliveTimeout, Reason::from_static("Timeout(Read/Write)")).await;
                         break
                     },
                     msg = msg_rx.next() => {
@@ -122,8 +448,10 @@ impl SessionState {
                                 Message::Forward(from, p) => {
                                     if let Err((from, p)) = deliver_queue_tx.send((from, p)).await{
                                         log::warn!("{:?} deliver_dropped, from: {:?}, {:?}", state.id, from, p);
+                                        state.stats.dropped_inc();
+                                        Alarms::instance().queue_overflowed();
                                         //hook, message_dropped
-                                        Runtime::instance().extends.hook_mgr().await.message_dropped(Some(state.id.clone()), from, p, Reason::from_static("deliver queue is full")).await;
+                                        Runtime::instance().extends.hook_mgr().await.message_dropped(Some(state.id.clone()), from, p, Reason::from_static(DroppedReason::QUEUE_FULL)).await;
                                     }
                                 },
                                 Message::Kick(sender, by_id, is_admin) => {
@@ -136,26 +464,46 @@ impl SessionState {
                                         if is_admin {
                                             flags.insert(StateFlags::ByAdminKick);
                                         }
-                                        state.client.add_disconnected_reason(Reason::from(format!("Kicked by {:?}, is_admin: {}", by_id, is_admin))).await;
+                                        let kind = if is_admin { DisconnectKind::Kicked } else { DisconnectKind::SessionTakenOver };
+                                        state.client.add_disconnected(kind, Reason::from(format!("Kicked by {:?}, is_admin: {}", by_id, is_admin))).await;
+                                        //Lets the client tell a takeover from a network failure
+                                        let reason_code = if is_admin { DisconnectReasonCode::AdministrativeAction } else { DisconnectReasonCode::SessionTakenOver };
+                                        if let Err(e) = state.sink.disconnect(reason_code, state.listen_cfg.v3_server_disconnect) {
+                                            log::debug!("{:?} Message::Kick, send disconnect error, {:?}", state.id, e);
+                                        }
                                         break
                                     }else{
                                         log::warn!("{:?} Message::Kick, kick sender is closed, to {:?}, is_admin: {}", state.id, by_id, is_admin);
                                     }
                                 },
+                                Message::Redirect(server_reference) => {
+                                    log::debug!("{:?} Message::Redirect, server_reference: {:?}", state.id, server_reference);
+                                    //The session stays until the client reconnects to a peer, which takes it over
+                                    state.client.add_disconnected(DisconnectKind::Redirected, Reason::from(format!("Redirected to {:?}, the node is evacuated", server_reference))).await;
+                                    if let Err(e) = state.sink.redirect(server_reference.as_deref(), state.listen_cfg.v3_server_disconnect) {
+                                        log::debug!("{:?} Message::Redirect, send disconnect error, {:?}", state.id, e);
+                                    }
+                                    break
+                                },
                                 Message::Disconnect(d) => {
                                     flags.insert(StateFlags::DisconnectReceived);
                                     state.client.set_mqtt_disconnect(d).await;
                                     state.client.add_disconnected_reason("Disconnect(true) message is received".into()).await;
 
                                 },
-                                Message::Closed(reason) => {
-                                    log::debug!("{:?} Closed({}) message received, reason: {}", state.id, flags.contains(StateFlags::DisconnectReceived), reason);
+                                Message::Closed(kind, reason) => {
+                                    log::debug!("{:?} Closed({}) message received, kind: {}, reason: {}", state.id, flags.contains(StateFlags::DisconnectReceived), kind, reason);
+                                    //The connections are closed by the server while it is stopping
+                                    let kind = if kind == DisconnectKind::RemoteClosed && Runtime::instance().node.is_stopping() { DisconnectKind::ServerShutdown } else { kind };
                                     if !state.client.has_disconnected_reason().await{
-                                        state.client.add_disconnected_reason(reason).await;
+                                        state.client.add_disconnected(kind, reason).await;
+                                    } else {
+                                        state.client.set_disconnected_kind(kind);
                                     }
                                     break
                                 },
                                 Message::Keepalive => {
+                                    state.stats.active();
                                     keep_alive_delay.as_mut().reset(Instant::now() + keep_alive_interval);
                                 },
                                 Message::Subscribe(sub, reply_tx) => {
@@ -181,25 +529,53 @@ impl SessionState {
                             }
                         }else{
                             log::warn!("{:?} None is received from the Rx", state.id);
-                            state.client.add_disconnected_reason(Reason::from_static("None is received from the Rx")).await;
+                            state.client.add_disconnected(DisconnectKind::Error, Reason::from_static("None is received from the Rx")).await;
                             break;
                         }
                     },
 
                     _ = &mut deliver_timeout_delay => {
-                        while let Some(iflt_msg) = state.inflight_win.write().await.pop_front_timeout(){
+                        loop {
+                            let (iflt_msg, retries, exhausted) = {
+                                let mut inflight_win = state.inflight_win.write().await;
+                                match inflight_win.pop_front_timeout() {
+                                    Some((iflt_msg, retries)) => {
+                                        let exhausted = inflight_win.is_retry_exhausted(retries);
+                                        (iflt_msg, retries, exhausted)
+                                    }
+                                    None => break,
+                                }
+                            };
                             log::debug!("{:?} has timeout message in inflight: {:?}", state.id, iflt_msg);
-                            if let Err(e) = state.reforward(iflt_msg).await{
+                            if exhausted {
+                                log::warn!("{:?} retransmission limit is reached, retries: {}, message: {:?}", state.id, retries, iflt_msg.publish);
+                                state.stats.dropped_inc();
+                                //hook, message_dropped
+                                Runtime::instance().extends.hook_mgr().await.message_dropped(Some(state.id.clone()), iflt_msg.from, iflt_msg.publish, Reason::from_static(DroppedReason::RETRY_EXHAUSTED)).await;
+                            } else if let Err(e) = state.reforward(iflt_msg).await{
                                 log::error!("{:?} redeliver message error, {:?}", state.id, e);
                             }
                         }
                     },
 
-                    deliver_packet = deliver_queue_rx.next(), if state.inflight_win.read().await.has_credit() => {
+                    _ = slow_check_interval.tick(), if slow_check_enable => {
+                        if let Some(slow) = slow_detector.check(state.deliver_queue.len(), Instant::now()) {
+                            log::warn!("{:?} slow subscriber, {:?}", state.id, slow);
+                            //hook, client_slow
+                            state.hook.client_slow(&slow).await;
+                        }
+                    },
+
+                    _ = topic_released.notified(), if !held.is_empty() => {},
+
+                    deliver_packet = deliver_queue_rx.next(), if state.can_deliver(held.len()).await => {
                         log::debug!("{:?} deliver_packet: {:?}", state.id, deliver_packet);
                         match deliver_packet{
                             Some(Some((from, p))) => {
-                                if let Err(e) = state.deliver(from, p).await{
+                                slow_detector.delivered(p.create_time);
+                                if strict_ordering && state.is_topic_busy(&held, &from, &p.topic).await {
+                                    held.push_back((from, p));
+                                } else if let Err(e) = state.deliver(from, p).await{
                                     log::error!("{:?} deliver message error, {:?}", state.id, e);
                                 }
                             },
@@ -208,7 +584,7 @@ impl SessionState {
                             },
                             None => {
                                 log::warn!("{:?} Deliver Queue is closed", state.id);
-                                state.client.add_disconnected_reason("Deliver Queue is closed".into()).await;
+                                state.client.add_disconnected(DisconnectKind::Error, "Deliver Queue is closed".into()).await;
                                 break;
                             }
                         }
@@ -216,6 +592,16 @@ impl SessionState {
                 }
             }
 
+            //The held messages go back to the front of the queue
+            if !held.is_empty() {
+                let queued = state.deliver_queue.drain();
+                for m in held.into_iter().chain(queued) {
+                    if let Err((_, p)) = state.deliver_queue.push(m) {
+                        log::warn!("{:?} requeue held message error, {:?}", state.id, p);
+                    }
+                }
+            }
+
             log::debug!(
                 "{:?} exit online worker, flags: {:?}, clean_session: {}",
                 state.id,
@@ -235,6 +621,7 @@ impl SessionState {
             state.sink.close();
 
             //hook, client_disconnected
+            state.client.set_disconnected_kind(DisconnectKind::RemoteClosed);
             let reason = state
                 .client
                 .get_disconnected_reason()
@@ -278,10 +665,21 @@ impl SessionState {
                     if let Some(msg) = msg{
                         match msg{
                             Message::Forward(from, p) => {
+                                //the message is kept for message_offline only if it has handlers
+                                let queued = if state.hook.is_registered(Type::MessageOffline) {
+                                    Some((from.clone(), p.clone()))
+                                } else {
+                                    None
+                                };
                                 if let Err((from, p)) = deliver_queue_tx.send((from, p)).await{
                                     log::warn!("{:?} offline deliver_dropped, from: {:?}, {:?}", state.id, from, p);
+                                    state.stats.dropped_inc();
+                                    Alarms::instance().queue_overflowed();
                                     //hook, message_dropped
-                                    Runtime::instance().extends.hook_mgr().await.message_dropped(Some(state.id.clone()), from, p, Reason::from_static("deliver queue is full")).await;
+                                    Runtime::instance().extends.hook_mgr().await.message_dropped(Some(state.id.clone()), from, p, Reason::from_static(DroppedReason::QUEUE_FULL)).await;
+                                }else if let Some((from, p)) = queued {
+                                    //hook, message_offline
+                                    state.hook.message_offline(from, &p).await;
                                 }
                             },
                             Message::Kick(sender, by_id, is_admin) => {
@@ -335,6 +733,7 @@ impl SessionState {
         };
 
         if let Err((from, p, reason)) = res {
+            self.stats.dropped_inc();
             //hook, message_dropped
             Runtime::instance()
                 .extends
@@ -369,6 +768,7 @@ impl SessionState {
 
     #[inline]
     pub async fn send_retain_messages(&self, retains: Vec<(TopicName, Retain)>, qos: QoS) -> Result<()> {
+        //the retained messages share the payload buffer of the stored message
         for (topic, mut retain) in retains {
             log::debug!("{:?} topic:{:?}, retain:{:?}", self.id, topic, retain);
 
@@ -400,12 +800,56 @@ impl SessionState {
         Ok(())
     }
 
+    ///There is a credit in the inflight window, and with strict ordering, fewer messages are held
+    ///than the window, so that a stalled topic does not take the whole queue
+    #[inline]
+    async fn can_deliver(&self, held: usize) -> bool {
+        let inflight_win = self.inflight_win.read().await;
+        inflight_win.has_credit() && held < inflight_win.window()
+    }
+
+    ///A message of the publisher on the topic is inflight or held
+    #[inline]
+    async fn is_topic_busy(&self, held: &VecDeque<(From, Publish)>, from: &From, topic: &TopicName) -> bool {
+        self.inflight_win.read().await.contains_topic(from, topic)
+            || held.iter().any(|(f, p)| f.client_id == from.client_id && p.topic == *topic)
+    }
+
+    ///Delivers the held messages whose topic is released, in order, the others keep waiting
+    #[inline]
+    async fn deliver_held(&self, held: &mut VecDeque<(From, Publish)>) {
+        let mut waiting = VecDeque::new();
+        while let Some((from, p)) = held.pop_front() {
+            let busy = {
+                let inflight_win = self.inflight_win.read().await;
+                !inflight_win.has_credit() || inflight_win.contains_topic(&from, &p.topic)
+            } || waiting
+                .iter()
+                .any(|(f, w): &(From, Publish)| f.client_id == from.client_id && w.topic == p.topic);
+            if busy {
+                waiting.push_back((from, p));
+            } else if let Err(e) = self.deliver(from, p).await {
+                log::error!("{:?} deliver message error, {:?}", self.id, e);
+            }
+        }
+        *held = waiting;
+    }
+
     #[inline]
     pub async fn deliver(&self, from: From, mut publish: Publish) -> Result<()> {
+        let span = Span::start("mqtt.deliver", publish.trace_context.as_ref());
+        span.set_attribute("mqtt.clientid", &*self.id.client_id);
+        span.set_attribute("mqtt.topic", &*publish.topic);
+        if let Some(cx) = span.context() {
+            publish.trace_context = Some(cx);
+        }
+        telemetry::inject_user_property(&mut publish);
+
         //hook, message_expiry_check
         let expiry = self.hook.message_expiry_check(from.clone(), &publish).await;
 
         if expiry {
+            self.stats.dropped_inc();
             Runtime::instance()
                 .extends
                 .hook_mgr()
@@ -414,7 +858,25 @@ impl SessionState {
                     Some(self.id.clone()),
                     from,
                     publish,
-                    Reason::from_static("message is expired"),
+                    Reason::from_static(DroppedReason::EXPIRED),
+                )
+                .await;
+            return Ok(());
+        }
+
+        //A subscription that overlaps a denied topic filter, such as sensors/# with sensors/secret/#
+        //denied, is accepted, the denied topics it matches are not delivered
+        if self.is_topic_denied(&publish.topic) {
+            self.stats.dropped_inc();
+            Runtime::instance()
+                .extends
+                .hook_mgr()
+                .await
+                .message_dropped(
+                    Some(self.id.clone()),
+                    from,
+                    publish,
+                    Reason::from_static(DroppedReason::TOPIC_DENIED),
                 )
                 .await;
             return Ok(());
@@ -430,18 +892,23 @@ impl SessionState {
         //hook, message_delivered
         let publish = self.hook.message_delivered(from.clone(), &publish).await.unwrap_or(publish);
 
-        //send message
-        self.sink.publish(publish.clone())?; //@TODO ... at exception, send hook and or store message
-
-        //cache messages to inflight window
+        //send message, and cache messages to inflight window
+        let payload_len = publish.payload.len();
         let moment_status = match publish.qos() {
             QoS::AtLeastOnce => Some(MomentStatus::UnAck),
             QoS::ExactlyOnce => Some(MomentStatus::UnReceived),
             _ => None,
         };
         if let Some(moment_status) = moment_status {
+            //the copy shares the topic and payload buffers
+            self.sink.publish(publish.clone())?; //@TODO ... at exception, send hook and or store message
             self.inflight_win.write().await.push_back(InflightMessage::new(moment_status, from, publish));
+        } else {
+            //QoS 0 messages are not cached, no copy of the message is needed
+            self.sink.publish(publish)?;
         }
+        //only the messages handed to the connection are counted as sent
+        self.stats.sent_inc(payload_len);
 
         Ok(())
     }
@@ -507,12 +974,25 @@ impl SessionState {
         ret
     }
 
+    ///Whether the topic is in the deny list of the listener, the reloaded listener configuration
+    ///is used so that a changed deny list also applies to the connected clients
+    #[inline]
+    fn is_topic_denied(&self, topic: &str) -> bool {
+        let listen_cfg =
+            self.id.local_addr.and_then(|addr| Runtime::instance().settings.listeners.get(addr.port()));
+        listen_cfg.as_ref().unwrap_or(&self.listen_cfg).topic_deny_list.is_denied(topic)
+    }
+
     #[inline]
     async fn _subscribe(&self, mut sub: Subscribe) -> Result<SubscribeReturn> {
-        if self.listen_cfg.max_subscriptions > 0
-            && (self.subscriptions.len() >= self.listen_cfg.max_subscriptions)
+        //A resubscription replaces the existing subscription, it does not count against the quota
+        if self.max_subscriptions > 0
+            && self.subscriptions.len() >= self.max_subscriptions
+            && !self.subscriptions.contains(&sub.topic_filter)
         {
-            return Err(MqttError::TooManySubscriptions);
+            log::debug!("{:?} too many subscriptions, max: {}", self.id, self.max_subscriptions);
+            Metrics::instance().client_subscribe_quota_exceeded_inc();
+            return Ok(SubscribeReturn::new_failure(SubscribeAckReason::QuotaExceeded));
         }
 
         sub.qos = sub.qos.less_value(self.listen_cfg.max_qos_allowed);
@@ -526,6 +1006,11 @@ impl SessionState {
             sub.topic_filter = topic_filter;
         }
 
+        if self.is_topic_denied(&sub.topic_filter) {
+            log::debug!("{:?} topic filter is denied, {:?}", self.id, sub.topic_filter);
+            return Ok(SubscribeReturn::new_failure(SubscribeAckReason::NotAuthorized));
+        }
+
         //hook, client_subscribe_check_acl
         let acl_result = self.hook.client_subscribe_check_acl(&sub).await;
         if let Some(acl_result) = acl_result {
@@ -535,6 +1020,8 @@ impl SessionState {
                 return Ok(acl_result);
             }
         }
+        //hook, subscribe_authorized
+        self.hook.subscribe_authorized(&sub).await;
 
         //subscribe
         let sub_ret =
@@ -543,8 +1030,17 @@ impl SessionState {
         if let Some(qos) = sub_ret.success() {
             //send retain messages
             if self.listen_cfg.retain_available {
-                let retain_messages =
+                let mut retain_messages =
                     Runtime::instance().extends.retain().await.get(&sub.topic_filter).await?;
+                if let Some(payload_filter) = sub.payload_filter.as_ref() {
+                    retain_messages.retain(|(_, retain)| {
+                        let matched = payload_filter.matches(&PayloadJson::new(&retain.publish.payload));
+                        if !matched {
+                            Runtime::instance().metrics.messages_filtered_inc();
+                        }
+                        matched
+                    });
+                }
                 self.send_retain_messages(retain_messages, qos).await?;
             };
             //hook, session_subscribed
@@ -572,12 +1068,39 @@ impl SessionState {
         Ok(())
     }
 
+    ///With strict ordering, the publishes of the connection are processed one by one
+    #[inline]
+    async fn ordered_publish(&self) -> Option<tokio::sync::MutexGuard<'_, ()>> {
+        if Runtime::instance().settings.mqtt.strict_ordering {
+            Some(self.publish_lock.lock().await)
+        } else {
+            None
+        }
+    }
+
     #[inline]
     pub async fn publish_v3(&self, publish: &v3::Publish) -> Result<bool> {
-        match self.publish(Publish::try_from(publish)?).await {
+        let _ordered = self.ordered_publish().await;
+        let publish = Publish::try_from(publish)?;
+        self.payload_limit_check(&publish).await?;
+        if self.dedup.is_duplicate(&publish) {
+            self.publish_duplicated(publish).await;
+            return Ok(true);
+        }
+        //MQTT 3.1.1 has no way to refuse a publish, the message is acked and dropped
+        if !self.publish_rate_acquire(&publish.topic, publish.payload.len()) {
+            self.publish_rate_limited(publish).await;
+            return Ok(false);
+        }
+        if !self.memory_budget_check(&publish).await {
+            return Ok(false);
+        }
+        match self.publish(publish).await {
             Err(e) => {
                 Metrics::instance().client_publish_error_inc();
-                self.client.add_disconnected_reason(Reason::from(format!("Publish failed, {:?}", e))).await;
+                self.client
+                    .add_disconnected(DisconnectKind::Error, Reason::from(format!("Publish failed, {:?}", e)))
+                    .await;
                 Err(e)
             }
             Ok(false) => {
@@ -590,10 +1113,42 @@ impl SessionState {
 
     #[inline]
     pub async fn publish_v5(&self, publish: &v5::Publish) -> Result<bool> {
-        match self.publish(Publish::try_from(publish)?).await {
+        let _ordered = self.ordered_publish().await;
+        let publish = Publish::try_from(publish)?;
+        self.payload_limit_check(&publish).await?;
+        if self.dedup.is_duplicate(&publish) {
+            self.publish_duplicated(publish).await;
+            return Ok(true);
+        }
+        if !self.publish_rate_acquire(&publish.topic, publish.payload.len()) {
+            let qos = publish.qos();
+            self.publish_rate_limited(publish).await;
+            return if let QoS::AtMostOnce = qos {
+                Ok(false)
+            } else {
+                Err(MqttError::PublishAckReason(
+                    PublishAckReason::QuotaExceeded,
+                    Reason::from_static(DroppedReason::RATE_LIMITED),
+                ))
+            };
+        }
+        if let QoS::AtMostOnce = publish.qos() {
+            if !self.memory_budget_check(&publish).await {
+                return Ok(false);
+            }
+        } else if MemoryBudget::instance().is_exceeded() {
+            Metrics::instance().client_publish_error_inc();
+            return Err(MqttError::PublishAckReason(
+                PublishAckReason::QuotaExceeded,
+                Reason::from_static(DroppedReason::MEMORY_BUDGET),
+            ));
+        }
+        match self.publish(publish).await {
             Err(e) => {
                 Metrics::instance().client_publish_error_inc();
-                self.client.add_disconnected_reason(Reason::from(format!("Publish failed, {:?}", e))).await;
+                self.client
+                    .add_disconnected(DisconnectKind::Error, Reason::from(format!("Publish failed, {:?}", e)))
+                    .await;
                 Err(e)
             }
             Ok(false) => {
@@ -604,10 +1159,158 @@ impl SessionState {
         }
     }
 
+    ///A payload larger than the mqtt.topic_payload_limits of the topic is refused, the client is told
+    ///Packet Too Large and disconnected
+    #[inline]
+    async fn payload_limit_check(&self, publish: &Publish) -> Result<()> {
+        let (topic_filter, max_size) =
+            if let Some(limit) = PayloadLimits::instance().check(&publish.topic, publish.payload.len()) {
+                limit
+            } else {
+                return Ok(());
+            };
+        let reason = format!(
+            "{}, {} > {} of {:?}",
+            DroppedReason::PAYLOAD_TOO_LARGE,
+            publish.payload.len(),
+            max_size,
+            topic_filter
+        );
+        log::debug!("{:?} {}, topic: {:?}", self.id, reason, publish.topic);
+        //hook, message_dropped
+        Runtime::instance()
+            .extends
+            .hook_mgr()
+            .await
+            .message_dropped(None, self.id.clone(), publish.clone(), Reason::from(reason.clone()))
+            .await;
+        if let Err(e) =
+            self.sink.disconnect(DisconnectReasonCode::PacketTooLarge, self.listen_cfg.v3_server_disconnect)
+        {
+            log::debug!("{:?} send disconnect error, {:?}", self.id, e);
+        }
+        Metrics::instance().client_publish_error_inc();
+        self.client.add_disconnected(DisconnectKind::Error, Reason::from(reason.clone())).await;
+        Err(MqttError::from(format!("Publish Refused, reason: {}", reason)))
+    }
+
+    ///The duplicate is acked as usual, so the client stops resending it, but not forwarded
+    #[inline]
+    async fn publish_duplicated(&self, publish: Publish) {
+        log::debug!("{:?} duplicate publish is suppressed, {:?}", self.id, publish);
+        self.stats.received_inc(publish.payload.len());
+        //hook, message_dropped
+        Runtime::instance()
+            .extends
+            .hook_mgr()
+            .await
+            .message_dropped(None, self.id.clone(), publish, Reason::from_static(DroppedReason::DUPLICATE))
+            .await;
+    }
+
+    ///Takes the publish rate tokens of a message from the connection and then from its tenant,
+    ///returns false if the message is to be refused, in dry run mode an exceeded limit is only
+    ///logged and counted. The messages of the tenant are counted in TenantStats
     #[inline]
-    async fn publish(&self, publish: Publish) -> Result<bool> {
+    fn publish_rate_acquire(&self, topic: &str, payload_len: usize) -> bool {
+        if !self.publish_limiter.acquire(payload_len) {
+            let limit = self.publish_limiter.limit();
+            if !limit.dry_run {
+                return false;
+            }
+            log::debug!("{:?} publish rate limit is exceeded, dry run, {:?}", self.id, limit);
+            Metrics::instance().client_publish_rate_limit_dry_run_inc();
+        }
+        let tenant = match TenantPublishRate::tenant(topic, Runtime::instance().settings.mqtt.tenant_levels) {
+            Some(tenant) => tenant,
+            None => return true,
+        };
+        if !TenantPublishRate::instance().acquire(&tenant, payload_len) {
+            let limit = TenantPublishRate::limit(&Runtime::instance().settings);
+            if !limit.dry_run {
+                TenantStats::instance().rate_limited(&tenant);
+                return false;
+            }
+            log::debug!("{:?} tenant publish rate limit is exceeded, dry run, {:?}", self.id, limit);
+            Metrics::instance().client_publish_rate_limit_dry_run_inc();
+        }
+        TenantStats::instance().published(&tenant, payload_len);
+        true
+    }
+
+    #[inline]
+    async fn publish_rate_limited(&self, publish: Publish) {
+        log::debug!("{:?} publish rate limit is exceeded, {:?}", self.id, self.publish_limiter.limit());
+        Metrics::instance().client_publish_error_inc();
+        //hook, message_dropped
+        Runtime::instance()
+            .extends
+            .hook_mgr()
+            .await
+            .message_dropped(None, self.id.clone(), publish, Reason::from_static(DroppedReason::RATE_LIMITED))
+            .await;
+    }
+
+    ///Pause reading while the memory budget is exceeded, returns false if a QoS 0 message
+    ///is still over budget after the pause and is dropped
+    #[inline]
+    async fn memory_budget_check(&self, publish: &Publish) -> bool {
+        let budget = MemoryBudget::instance();
+        if !budget.is_exceeded() || budget.wait().await {
+            return true;
+        }
+        if !matches!(publish.qos(), QoS::AtMostOnce) {
+            return true;
+        }
+        Metrics::instance().client_publish_error_inc();
+        //hook, message_dropped
+        Runtime::instance()
+            .extends
+            .hook_mgr()
+            .await
+            .message_dropped(
+                None,
+                self.id.clone(),
+                publish.clone(),
+                Reason::from_static(DroppedReason::MEMORY_BUDGET),
+            )
+            .await;
+        false
+    }
+
+    #[inline]
+    async fn publish(&self, mut publish: Publish) -> Result<bool> {
+        let parent = telemetry::extract_user_property(&publish.properties.user_properties);
+        let span = Span::start("mqtt.publish", parent.as_ref());
+        span.set_attribute("mqtt.clientid", &*self.id.client_id);
+        span.set_attribute("mqtt.topic", &*publish.topic);
+
+        self.stats.received_inc(publish.payload.len());
+
+        //Without tracing, the traceparent of the publisher is passed on as is
+        let trace_context = span.context().or(parent);
+        publish.trace_context = trace_context.clone();
+
         //hook, message_publish
-        let publish = self.hook.message_publish(&publish).await.unwrap_or(publish);
+        let mut publish = self.hook.message_publish(&publish).await.unwrap_or(publish);
+        publish.trace_context = trace_context;
+
+        if self.is_topic_denied(&publish.topic) {
+            Metrics::instance().client_publish_auth_error_inc();
+            //Message dropped
+            Runtime::instance()
+                .extends
+                .hook_mgr()
+                .await
+                .message_dropped(
+                    None,
+                    self.id.clone(),
+                    publish,
+                    Reason::from_static(DroppedReason::TOPIC_DENIED),
+                )
+                .await;
+            return Ok(false);
+        }
 
         //hook, message_publish_check_acl
         let acl_result = self.hook.message_publish_check_acl(&publish).await;
@@ -624,12 +1327,14 @@ impl SessionState {
                     self.id.clone(),
                     publish,
                     Reason::from(format!(
-                        "hook::message_publish_check_acl, publish rejected, disconnect:{}",
+                        "{}, publish rejected, disconnect:{}",
+                        DroppedReason::ACL_DENIED,
                         disconnect
                     )),
                 )
                 .await;
             return if disconnect {
+                self.client.set_disconnected_kind(DisconnectKind::NotAuthorized);
                 Err(MqttError::from(
                     "Publish Refused, reason: hook::message_publish_check_acl() -> Rejected(Disconnect)",
                 ))
@@ -645,6 +1350,7 @@ impl SessionState {
                 .await
                 .set(publish.topic(), Retain { from: self.id.clone(), publish: publish.clone() })
                 .await?;
+            MemoryBudget::instance().sample_retained().await;
         }
 
         if let Err(errs) = Runtime::instance().extends.shared().await.forwards(self.id.clone(), publish).await
@@ -722,15 +1428,24 @@ impl SessionState {
             offline_info.offline_messages.len(),
             clear_subscriptions
         );
+        //Bounds the takeovers in process, such as when the clients of a failed node reconnect at once
+        let _permit = takeover_permits().acquire().await;
+
         if !clear_subscriptions && !offline_info.subscriptions.is_empty() {
-            for (tf, (qos, shared_group)) in offline_info.subscriptions.iter() {
-                let shared_group = shared_group.as_ref().cloned();
-                let qos = *qos;
-                let id = self.id.clone();
-                log::debug!("{:?} transfer_session_state, router.add ... topic_filter: {:?}, shared_group: {:?}, qos: {:?}", id, tf, shared_group, qos);
-                if let Err(e) =
-                    Runtime::instance().extends.router().await.add(tf, id, qos, shared_group).await
-                {
+            let router = Runtime::instance().extends.router().await;
+            let router = &router;
+            let concurrency = Runtime::instance().settings.mqtt.takeover_subscribe_concurrency.max(1);
+            let mut adds = futures::stream::iter(offline_info.subscriptions.iter())
+                .map(|(tf, (qos, shared_group))| {
+                    let shared_group = shared_group.as_ref().cloned();
+                    let qos = *qos;
+                    let id = self.id.clone();
+                    log::debug!("{:?} transfer_session_state, router.add ... topic_filter: {:?}, shared_group: {:?}, qos: {:?}", id, tf, shared_group, qos);
+                    async move { router.add(tf, id, qos, shared_group).await }
+                })
+                .buffer_unordered(concurrency);
+            while let Some(res) = adds.next().await {
+                if let Err(e) = res {
                     log::warn!("transfer_session_state, router.add, {:?}", e);
                     return Err(e);
                 }
@@ -740,6 +1455,14 @@ impl SessionState {
         //Subscription transfer from previous session
         if !clear_subscriptions {
             self.subscriptions.extend(offline_info.subscriptions);
+            for (topic_filter, payload_filter) in offline_info.payload_filters.iter() {
+                match PayloadFilter::from_str(payload_filter) {
+                    Ok(payload_filter) => {
+                        self.subscriptions.set_payload_filter(topic_filter, Some(payload_filter))
+                    }
+                    Err(e) => log::warn!("{:?} transfer_session_state, {:?}", self.id, e),
+                }
+            }
         }
 
         //Send previous session unacked messages
@@ -762,7 +1485,7 @@ impl SessionState {
     async fn session_expiry_interval(&self) -> Duration {
         if let Some(Disconnect::V5(d)) = self.client.disconnect.read().await.as_ref() {
             if let Some(interval_secs) = d.session_expiry_interval_secs {
-                Duration::from_secs(interval_secs as u64)
+                self.listen_cfg.session_expiry_interval_limit(Duration::from_secs(interval_secs as u64))
             } else {
                 self.fitter.session_expiry_interval()
             }
@@ -789,6 +1512,93 @@ impl Deref for SessionState {
     }
 }
 
+///Per-session message counters
+pub struct SessionStats {
+    ///Number of received PUBLISH packets
+    pub msgs_in: AtomicUsize,
+    pub bytes_in: AtomicUsize,
+    ///Number of sent PUBLISH packets
+    pub msgs_out: AtomicUsize,
+    pub bytes_out: AtomicUsize,
+    pub dropped: AtomicUsize,
+    pub acked: AtomicUsize,
+    ///Number of messages purged from the queue for exceeding mqtt.offline_message_max_age
+    pub offline_purged: AtomicUsize,
+    pub last_activity: AtomicI64,
+}
+
+impl Default for SessionStats {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionStats {
+    ///The last activity is the connect, the session is created when its client connects
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            msgs_in: AtomicUsize::new(0),
+            bytes_in: AtomicUsize::new(0),
+            msgs_out: AtomicUsize::new(0),
+            bytes_out: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+            acked: AtomicUsize::new(0),
+            offline_purged: AtomicUsize::new(0),
+            last_activity: AtomicI64::new(chrono::Local::now().timestamp_millis()),
+        }
+    }
+
+    #[inline]
+    pub fn active(&self) {
+        self.last_activity.store(chrono::Local::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn received_inc(&self, bytes: usize) {
+        self.msgs_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+        self.active();
+    }
+
+    #[inline]
+    pub fn sent_inc(&self, bytes: usize) {
+        self.msgs_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn dropped_inc(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn offline_purged_add(&self, n: usize) {
+        self.offline_purged.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn acked_inc(&self) {
+        self.acked.fetch_add(1, Ordering::Relaxed);
+        self.active();
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "msgs_in": self.msgs_in.load(Ordering::Relaxed),
+            "bytes_in": self.bytes_in.load(Ordering::Relaxed),
+            "msgs_out": self.msgs_out.load(Ordering::Relaxed),
+            "bytes_out": self.bytes_out.load(Ordering::Relaxed),
+            "dropped": self.dropped.load(Ordering::Relaxed),
+            "acked": self.acked.load(Ordering::Relaxed),
+            "offline_purged": self.offline_purged.load(Ordering::Relaxed),
+            "last_activity": self.last_activity.load(Ordering::Relaxed),
+        })
+    }
+}
+
 // type SessionSerde =
 //     (u16, Vec<(TopicFilter, u8)>, Vec<(From, Publish)>, Vec<InflightMessage>, TimestampMillis);
 
@@ -799,6 +1609,80 @@ pub struct SessionOfflineInfo {
     pub offline_messages: Vec<(From, Publish)>,
     pub inflight_messages: Vec<InflightMessage>,
     pub created_at: TimestampMillis,
+    ///The payload filters of the subscriptions, by their expressions
+    #[serde(
+        default,
+        skip_serializing_if = "crate::grpc::codec::is_legacy_layout",
+        deserialize_with = "crate::grpc::codec::deserialize_since_legacy"
+    )]
+    pub payload_filters: Vec<(TopicFilter, String)>,
+}
+
+///How the payloads of the messages are included in a session dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadRedaction {
+    ///Only the size of the payload
+    Size,
+    ///The size and a fingerprint of the payload, which tells the same payloads apart but is not
+    ///a cryptographic hash
+    Hash,
+    ///The whole payload, base64 encoded
+    None,
+}
+
+impl Default for PayloadRedaction {
+    #[inline]
+    fn default() -> Self {
+        PayloadRedaction::Size
+    }
+}
+
+impl FromStr for PayloadRedaction {
+    type Err = MqttError;
+
+    #[inline]
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "size" => Ok(PayloadRedaction::Size),
+            "hash" => Ok(PayloadRedaction::Hash),
+            "none" => Ok(PayloadRedaction::None),
+            _ => Err(MqttError::from(format!("invalid payload redaction, {}", s))),
+        }
+    }
+}
+
+impl PayloadRedaction {
+    #[inline]
+    fn publish_to_json(&self, from: &From, p: &Publish) -> serde_json::Value {
+        let mut json = json!({
+            "from": from.to_json(),
+            "topic": p.topic,
+            "qos": p.qos.value(),
+            "retain": p.retain,
+            "dup": p.dup,
+            "packet_id": p.packet_id,
+            "create_time": p.create_time,
+            "expiry_at": p.properties.message_expiry_interval
+                .map(|interval| p.create_time + interval.get() as TimestampMillis * 1000),
+            "user_properties": p.properties.user_properties,
+            "payload_size": p.payload.len(),
+        });
+        if let Some(obj) = json.as_object_mut() {
+            match self {
+                PayloadRedaction::Size => {}
+                PayloadRedaction::Hash => {
+                    let mut hasher = ahash::RandomState::with_seeds(1, 2, 3, 4).build_hasher();
+                    hasher.write(&p.payload);
+                    obj.insert("payload_hash".into(), json!(format!("{:016x}", hasher.finish())));
+                }
+                PayloadRedaction::None => {
+                    obj.insert("payload".into(), json!(base64::encode(&p.payload)));
+                }
+            }
+        }
+        json
+    }
 }
 
 impl std::fmt::Debug for SessionOfflineInfo {
@@ -824,28 +1708,51 @@ impl Session {
         listen_cfg: Listener,
         max_mqueue_len: usize,
         max_inflight: usize,
+        max_subscriptions: usize,
         created_at: TimestampMillis,
     ) -> Self {
         let message_retry_interval = listen_cfg.message_retry_interval.as_millis() as TimestampMillis;
+        let message_retry_max_interval = listen_cfg.message_retry_max_interval.as_millis() as TimestampMillis;
         let message_expiry_interval = listen_cfg.message_expiry_interval.as_millis() as TimestampMillis;
+        let inflight_latency_target = listen_cfg.inflight_latency_target.as_millis() as TimestampMillis;
+        let mqtt_cfg = &Runtime::instance().settings.mqtt;
+        let inflight_win = Inflight::new(max_inflight, message_retry_interval, message_expiry_interval)
+            .adaptive(inflight_latency_target)
+            .retry_policy(
+                listen_cfg.message_retry_backoff,
+                message_retry_max_interval,
+                listen_cfg.message_retry_max,
+            )
+            //Without timed retransmission, the inflight messages are resent when the session is resumed
+            .retry_on_reconnect_only(listen_cfg.message_retry_on_reconnect_only);
+        let dedup = PublishDedup::new(&listen_cfg);
         Runtime::instance().stats.sessions.inc();
         Self(Arc::new(_SessionInner {
             id,
             listen_cfg,
             subscriptions: SessionSubs::new(),
-            deliver_queue: Arc::new(MessageQueue::new(max_mqueue_len)),
-            inflight_win: Arc::new(RwLock::new(Inflight::new(
-                max_inflight,
-                message_retry_interval,
-                message_expiry_interval,
-            ))),
+            deliver_queue: Arc::new(
+                MessageQueue::with_meter(
+                    max_mqueue_len,
+                    |(_, p): &(From, Publish)| MemoryBudget::instance().queued_add(p),
+                    |(_, p): &(From, Publish)| MemoryBudget::instance().queued_sub(p),
+                )
+                .priorities(message_priority)
+                .max_consecutive(mqtt_cfg.priority_max_consecutive),
+            ),
+            inflight_win: Arc::new(RwLock::new(inflight_win)),
             created_at,
+            stats: SessionStats::new(),
+            dedup,
+            max_subscriptions,
+            max_inflight,
         }))
     }
 
     #[inline]
     pub async fn to_offline_info(&self) -> SessionOfflineInfo {
         let id = self.id.clone();
+        let payload_filters = self.subscriptions.payload_filters();
         let subscriptions = self.subscriptions.drain();
         let mut offline_messages = Vec::new();
         while let Some(item) = self.deliver_queue.pop() {
@@ -864,6 +1771,7 @@ impl Session {
             offline_messages,
             inflight_messages,
             created_at: self.created_at,
+            payload_filters,
         }
     }
 }
@@ -891,6 +1799,12 @@ pub struct _SessionInner {
     pub deliver_queue: Arc<MessageQueue>,
     pub inflight_win: Arc<RwLock<Inflight>>,
     pub created_at: TimestampMillis,
+    pub stats: SessionStats,
+    pub dedup: PublishDedup,
+    ///Maximum number of subscriptions, 0 means unlimited
+    pub max_subscriptions: usize,
+    ///Maximum number of inflight messages, the inflight window never exceeds it
+    pub max_inflight: usize,
 }
 
 impl Drop for _SessionInner {
@@ -923,17 +1837,110 @@ impl _SessionInner {
             })
             .collect::<Vec<_>>();
 
+        let inflight_win = self.inflight_win.read().await;
         let data = json!({
             "subscriptions": {
                 "count": count,
+                "max": self.max_subscriptions,
                 "topic_filters": subs,
             },
             "queues": self.deliver_queue.len(),
-            "inflights": self.inflight_win.read().await.len(),
+            "inflights": inflight_win.len(),
+            "max_inflight": self.max_inflight,
+            "inflight_window": inflight_win.window(),
+            "inflight_ack_latency": inflight_win.latency(),
             "created_at": self.created_at,
+            "stats": self.stats.to_json(),
         });
         data
     }
+
+    ///The complete state of the session for diagnostics, the subscriptions, the queued and the
+    ///inflight messages and their timers, with the payloads included as redaction says
+    pub async fn dump(&self, redaction: PayloadRedaction) -> serde_json::Value {
+        let subs = self
+            .subscriptions
+            .iter()
+            .map(|entry| {
+                let (tf, (qos, shared_sub)) = entry.pair();
+                json!({
+                    "topic_filter": tf.to_string(),
+                    "qos": qos.value(),
+                    "shared_group": shared_sub,
+                })
+            })
+            .collect::<Vec<_>>();
+        let payload_filters = self
+            .subscriptions
+            .payload_filters()
+            .into_iter()
+            .map(|(tf, filter)| json!({ "topic_filter": tf, "payload_filter": filter }))
+            .collect::<Vec<_>>();
+
+        //The messages are cloned under the locks, their payloads are shared, and converted after
+        let queued = self.deliver_queue.inspect(|m| m.clone());
+        let queued = queued.iter().map(|(from, p)| redaction.publish_to_json(from, p)).collect::<Vec<_>>();
+
+        let inflight_win = self.inflight_win.read().await;
+        let (inflight_len, inflight_window, inflight_latency, next_timeout) = (
+            inflight_win.len(),
+            inflight_win.window(),
+            inflight_win.latency(),
+            inflight_win.get_timeout().map(|t| t.as_millis() as u64),
+        );
+        let inflights = inflight_win
+            .iter()
+            .map(|(packet_id, m)| {
+                let timers = (inflight_win.retries_of(packet_id), inflight_win.timeout_at(packet_id, m));
+                (*packet_id, m.from.clone(), m.publish.clone(), m.status, m.update_time, timers)
+            })
+            .collect::<Vec<_>>();
+        drop(inflight_win);
+        let inflights = inflights
+            .iter()
+            .map(|(packet_id, from, publish, status, update_time, (retries, timeout_at))| {
+                let mut json = redaction.publish_to_json(from, publish);
+                if let Some(obj) = json.as_object_mut() {
+                    obj.insert("inflight_packet_id".into(), json!(packet_id));
+                    obj.insert("status".into(), json!(status));
+                    obj.insert("update_time".into(), json!(update_time));
+                    obj.insert("retries".into(), json!(retries));
+                    obj.insert("timeout_at".into(), json!(timeout_at));
+                }
+                json
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "id": self.id.to_json(),
+            "created_at": self.created_at,
+            "subscriptions": {
+                "count": subs.len(),
+                "max": self.max_subscriptions,
+                "topic_filters": subs,
+                "payload_filters": payload_filters,
+            },
+            "queue": {
+                "len": self.deliver_queue.len(),
+                "capacity": self.deliver_queue.capacity(),
+                "messages": queued,
+            },
+            "inflight": {
+                "len": inflight_len,
+                "max": self.max_inflight,
+                "window": inflight_window,
+                "ack_latency": inflight_latency,
+                "next_timeout_ms": next_timeout,
+                "messages": inflights,
+            },
+            "listener": {
+                "message_retry_interval_ms": self.listen_cfg.message_retry_interval.as_millis() as u64,
+                "message_retry_on_reconnect_only": self.listen_cfg.message_retry_on_reconnect_only,
+                "message_expiry_interval_ms": self.listen_cfg.message_expiry_interval.as_millis() as u64,
+            },
+            "stats": self.stats.to_json(),
+        })
+    }
 }
 
 #[derive(Clone)]
@@ -946,6 +1953,7 @@ impl ClientInfo {
         connect_info: ConnectInfo,
         session_present: bool,
         superuser: bool,
+        publish_rate_limit: Option<PublishRateLimit>,
         connected_at: TimestampMillis,
     ) -> ClientInfo {
         let id = connect_info.id().clone();
@@ -954,12 +1962,16 @@ impl ClientInfo {
             connect_info,
             session_present,
             superuser,
+            publish_rate_limit,
             connected: AtomicBool::new(true),
             connected_at,
             disconnected_at: AtomicI64::new(0),
             disconnected_reason: RwLock::new(Vec::new()),
+            disconnected_kind: parking_lot::RwLock::new(None),
             disconnect: RwLock::new(None),
             extra_attrs: Arc::new(RwLock::new(ExtraAttrs::new())),
+            tags: parking_lot::RwLock::new(ClientTags::new()),
+            tags_counted: CountedTags::default(),
         }))
     }
 
@@ -986,11 +1998,13 @@ impl ClientInfo {
             } else {
                 json.insert("disconnected_reason".into(), serde_json::Value::Null);
             }
+            json.insert("disconnected_kind".into(), serde_json::json!(self.disconnected_kind()));
 
             json.insert(
                 "extra_attrs".into(),
                 serde_json::Value::Number(serde_json::Number::from(self.extra_attrs.read().await.len())),
             );
+            json.insert("tags".into(), serde_json::json!(*self.tags.read()));
         }
         json
     }
@@ -1018,9 +2032,21 @@ impl ClientInfo {
         self.disconnected_at.load(Ordering::SeqCst)
     }
 
+    #[inline]
+    pub fn tags(&self) -> ClientTags {
+        self.tags.read().clone()
+    }
+
+    ///Replaces the tags of the connection, counted by the tagged connections while it is connected
+    #[inline]
+    pub fn set_tags(&self, tags: ClientTags) {
+        let labels = &Runtime::instance().settings.mqtt.tag_metric_labels;
+        self.tags_counted.retag(&self.tags, &self.connected, tags, labels);
+    }
+
     #[inline]
     pub async fn set_disconnected(&self, reason: Option<Reason>) {
-        self.connected.store(false, Ordering::SeqCst);
+        self.tags_counted.disconnect(&self.tags, &self.connected);
         self.disconnected_at.store(chrono::Local::now().timestamp_millis(), Ordering::SeqCst);
         if let Some(reason) = reason {
             self.add_disconnected_reason(reason).await;
@@ -1038,7 +2064,28 @@ impl ClientInfo {
         self.disconnected_reason.write().await.push(r);
     }
 
+    ///Records why the connection is closed, the kind is kept if one is recorded already
+    #[inline]
+    pub async fn add_disconnected(&self, kind: DisconnectKind, r: Reason) {
+        self.set_disconnected_kind(kind);
+        self.add_disconnected_reason(r).await;
+    }
+
+    #[inline]
+    pub fn set_disconnected_kind(&self, kind: DisconnectKind) {
+        let mut disconnected_kind = self.disconnected_kind.write();
+        if disconnected_kind.is_none() {
+            disconnected_kind.replace(kind);
+        }
+    }
+
+    #[inline]
+    pub fn disconnected_kind(&self) -> Option<DisconnectKind> {
+        *self.disconnected_kind.read()
+    }
+
     pub(crate) async fn set_mqtt_disconnect(&self, d: Disconnect) {
+        self.set_disconnected_kind(DisconnectKind::ClientDisconnect);
         if let Some(r) = d.reason() {
             self.add_disconnected_reason(r.clone()).await;
         }
@@ -1081,10 +2128,95 @@ pub struct _ClientInfo {
     pub connect_info: ConnectInfo,
     pub session_present: bool,
     pub superuser: bool,
+    ///Publish rate limits set by the auth plugin, overriding those of the listener
+    pub publish_rate_limit: Option<PublishRateLimit>,
     pub connected: AtomicBool,
     pub connected_at: TimestampMillis,
     pub disconnected_at: AtomicI64,
     pub disconnected_reason: RwLock<Vec<Reason>>,
+    pub disconnected_kind: parking_lot::RwLock<Option<DisconnectKind>>,
     pub disconnect: RwLock<Option<Disconnect>>,
     pub extra_attrs: Arc<RwLock<ExtraAttrs>>,
+    ///Set by the ClientTagging hook after the connect
+    pub tags: parking_lot::RwLock<ClientTags>,
+    ///(tag, value) by which the connection is counted in TaggedConnections
+    pub tags_counted: CountedTags,
+}
+
+///(tag, value) by which a connection is counted in TaggedConnections, changed while holding the
+///lock of its tags, so that a retag cannot count the tags of a connection being disconnected
+#[derive(Default)]
+pub struct CountedTags(parking_lot::RwLock<Vec<(String, String)>>);
+
+impl CountedTags {
+    ///Replaces the tags, counting them by labels if the connection is still connected
+    #[inline]
+    fn retag(
+        &self,
+        tags: &parking_lot::RwLock<ClientTags>,
+        connected: &AtomicBool,
+        new_tags: ClientTags,
+        labels: &[String],
+    ) {
+        let mut curr = tags.write();
+        if connected.load(Ordering::SeqCst) {
+            let mut counted = self.0.write();
+            TaggedConnections::instance().decs(&counted);
+            *counted = TaggedConnections::instance().incs_by(labels, &new_tags);
+        }
+        *curr = new_tags;
+    }
+
+    ///Marks the connection disconnected and releases its counted tags, returns false if it was
+    ///disconnected already
+    #[inline]
+    fn disconnect(&self, tags: &parking_lot::RwLock<ClientTags>, connected: &AtomicBool) -> bool {
+        let _tags = tags.write();
+        if connected.swap(false, Ordering::SeqCst) {
+            let counted = std::mem::take(&mut *self.0.write());
+            TaggedConnections::instance().decs(&counted);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retag_and_disconnect() {
+        let labels = vec!["session-test-tag".to_owned()];
+        let counts = || {
+            TaggedConnections::instance()
+                .counts()
+                .into_iter()
+                .filter(|(tag, _, _)| tag == "session-test-tag")
+                .map(|(_, _, n)| n)
+                .sum::<isize>()
+        };
+        for i in 0..200 {
+            let tags = Arc::new(parking_lot::RwLock::new(ClientTags::new()));
+            let connected = Arc::new(AtomicBool::new(true));
+            let counted = Arc::new(CountedTags::default());
+            let retag = {
+                let (tags, connected, counted, labels) =
+                    (tags.clone(), connected.clone(), counted.clone(), labels.clone());
+                std::thread::spawn(move || {
+                    for j in 0..10 {
+                        let mut new_tags = ClientTags::new();
+                        new_tags.insert("session-test-tag".into(), format!("{}-{}", i, j));
+                        counted.retag(&tags, &connected, new_tags, &labels);
+                    }
+                })
+            };
+            assert!(counted.disconnect(&tags, &connected));
+            retag.join().unwrap();
+            assert!(!counted.disconnect(&tags, &connected));
+            assert!(counted.0.read().is_empty());
+            assert_eq!(counts(), 0);
+        }
+    }
 }
@@ -1,10 +1,16 @@
+use std::collections::BTreeMap;
 use std::fmt;
-use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::atomic::{AtomicI64, AtomicIsize, AtomicUsize, Ordering};
 
+use dashmap::DashMap;
 use ntex_mqtt::handshakings;
 use once_cell::sync::OnceCell;
 
+use crate::broker::admission::HandshakeAdmission;
+use crate::broker::budget::MemoryBudget;
 use crate::broker::executor::{get_active_count, get_rate};
+use crate::broker::process::{cpu_usage, fd_usage, memory_rss};
+use crate::broker::types::{monotonic_secs, ClientTags};
 use crate::{HashMap, NodeId, Runtime};
 
 type Current = AtomicIsize;
@@ -119,15 +125,62 @@ impl Counter {
 pub struct Stats {
     pub handshakings: Counter,
     pub handshakings_active: Counter,
+    pub handshakings_pending: Counter,
     pub handshakings_rate: Counter,
     pub connections: Counter,
     pub sessions: Counter,
     pub subscriptions: Counter,
     pub subscriptions_shared: Counter,
     pub retaineds: Counter,
+    pub memory_budget: Counter,
+
+    #[serde(
+        default,
+        skip_serializing_if = "crate::grpc::codec::is_legacy_layout",
+        deserialize_with = "crate::grpc::codec::deserialize_since_legacy"
+    )]
+    pub process_cpu: Counter,
+    #[serde(
+        default,
+        skip_serializing_if = "crate::grpc::codec::is_legacy_layout",
+        deserialize_with = "crate::grpc::codec::deserialize_since_legacy"
+    )]
+    pub process_memory: Counter,
+    #[serde(
+        default,
+        skip_serializing_if = "crate::grpc::codec::is_legacy_layout",
+        deserialize_with = "crate::grpc::codec::deserialize_since_legacy"
+    )]
+    pub process_fds: Counter,
+    #[serde(
+        default,
+        skip_serializing_if = "crate::grpc::codec::is_legacy_layout",
+        deserialize_with = "crate::grpc::codec::deserialize_since_legacy"
+    )]
+    pub tasks_active: Counter,
+    #[serde(
+        default,
+        skip_serializing_if = "crate::grpc::codec::is_legacy_layout",
+        deserialize_with = "crate::grpc::codec::deserialize_since_legacy"
+    )]
+    pub tasks_waiting: Counter,
 
     topics_map: HashMap<NodeId, Counter>,
     routes_map: HashMap<NodeId, Counter>,
+    ///(tag, value, connections)
+    #[serde(
+        default,
+        skip_serializing_if = "crate::grpc::codec::is_legacy_layout",
+        deserialize_with = "crate::grpc::codec::deserialize_since_legacy"
+    )]
+    tagged_connections: Vec<(String, String, isize)>,
+    ///(tenant, messages, bytes, rate limited messages)
+    #[serde(
+        default,
+        skip_serializing_if = "crate::grpc::codec::is_legacy_layout",
+        deserialize_with = "crate::grpc::codec::deserialize_since_legacy"
+    )]
+    tenants: Vec<(String, usize, usize, usize)>,
 
     #[cfg(feature = "debug")]
     debug_clinet_states_map: HashMap<NodeId, usize>,
@@ -142,15 +195,25 @@ impl Stats {
         INSTANCE.get_or_init(|| Self {
             handshakings: Counter::new(),
             handshakings_active: Counter::new(),
+            handshakings_pending: Counter::new(),
             handshakings_rate: Counter::new(),
             connections: Counter::new(),
             sessions: Counter::new(),
             subscriptions: Counter::new(),
             subscriptions_shared: Counter::new(),
             retaineds: Counter::new(),
+            memory_budget: Counter::new(),
+
+            process_cpu: Counter::new(),
+            process_memory: Counter::new(),
+            process_fds: Counter::new(),
+            tasks_active: Counter::new(),
+            tasks_waiting: Counter::new(),
 
             topics_map: HashMap::default(),
             routes_map: HashMap::default(),
+            tagged_connections: Vec::new(),
+            tenants: Vec::new(),
 
             #[cfg(feature = "debug")]
             debug_clinet_states_map: HashMap::default(),
@@ -172,9 +235,17 @@ impl Stats {
 
         self.handshakings.current_set(handshakings());
         self.handshakings_active.current_set(get_active_count());
+        self.handshakings_pending.current_set(HandshakeAdmission::instance().pending() as isize);
         self.handshakings_rate.sets((get_rate() * 100.0) as isize);
 
         self.sessions.current_set(shared.sessions_count() as isize);
+        self.memory_budget.sets(MemoryBudget::instance().used() as isize);
+
+        self.process_cpu.sets((cpu_usage() * 100.0) as isize);
+        self.process_memory.sets(memory_rss() as isize);
+        self.process_fds.sets(fd_usage().map(|(opens, _)| opens).unwrap_or_default() as isize);
+        self.tasks_active.sets(Runtime::instance().exec.active_count());
+        self.tasks_waiting.sets(Runtime::instance().exec.waiting_count());
 
         #[cfg(feature = "debug")]
         let mut debug_clinet_states_map = HashMap::default();
@@ -189,15 +260,25 @@ impl Stats {
         Self {
             handshakings: self.handshakings.clone(),
             handshakings_active: self.handshakings_active.clone(),
+            handshakings_pending: self.handshakings_pending.clone(),
             handshakings_rate: self.handshakings_rate.clone(),
             connections: self.connections.clone(),
             sessions: self.sessions.clone(),
             subscriptions: self.subscriptions.clone(),
             subscriptions_shared: self.subscriptions_shared.clone(),
             retaineds: self.retaineds.clone(), //retained messages
+            memory_budget: self.memory_budget.clone(),
+
+            process_cpu: self.process_cpu.clone(),
+            process_memory: self.process_memory.clone(),
+            process_fds: self.process_fds.clone(),
+            tasks_active: self.tasks_active.clone(),
+            tasks_waiting: self.tasks_waiting.clone(),
 
             topics_map,
             routes_map,
+            tagged_connections: TaggedConnections::instance().counts(),
+            tenants: TenantStats::instance().counts(),
 
             #[cfg(feature = "debug")]
             debug_clinet_states_map,
@@ -210,15 +291,47 @@ impl Stats {
     pub fn add(&mut self, other: Self) {
         self.handshakings.add(&other.handshakings);
         self.handshakings_active.add(&other.handshakings_active);
+        self.handshakings_pending.add(&other.handshakings_pending);
         self.handshakings_rate.add(&other.handshakings_rate);
         self.connections.add(&other.connections);
         self.sessions.add(&other.sessions);
         self.subscriptions.add(&other.subscriptions);
         self.subscriptions_shared.add(&other.subscriptions_shared);
         self.retaineds.add(&other.retaineds);
+        self.memory_budget.add(&other.memory_budget);
+
+        self.process_cpu.add(&other.process_cpu);
+        self.process_memory.add(&other.process_memory);
+        self.process_fds.add(&other.process_fds);
+        self.tasks_active.add(&other.tasks_active);
+        self.tasks_waiting.add(&other.tasks_waiting);
 
         self.topics_map.extend(other.topics_map);
         self.routes_map.extend(other.routes_map);
+        let mut tagged = self
+            .tagged_connections
+            .drain(..)
+            .map(|(tag, value, n)| ((tag, value), n))
+            .collect::<BTreeMap<_, _>>();
+        for (tag, value, n) in other.tagged_connections {
+            *tagged.entry((tag, value)).or_default() += n;
+        }
+        self.tagged_connections = tagged.into_iter().map(|((tag, value), n)| (tag, value, n)).collect();
+        let mut tenants = self
+            .tenants
+            .drain(..)
+            .map(|(tenant, messages, bytes, rate_limited)| (tenant, (messages, bytes, rate_limited)))
+            .collect::<BTreeMap<_, _>>();
+        for (tenant, messages, bytes, rate_limited) in other.tenants {
+            let counts = tenants.entry(tenant).or_default();
+            counts.0 += messages;
+            counts.1 += bytes;
+            counts.2 += rate_limited;
+        }
+        self.tenants = tenants
+            .into_iter()
+            .map(|(tenant, (messages, bytes, rate_limited))| (tenant, messages, bytes, rate_limited))
+            .collect();
 
         #[cfg(feature = "debug")]
         {
@@ -238,6 +351,8 @@ impl Stats {
             "handshakings.count": self.handshakings.count(),
             "handshakings.max": self.handshakings.max(),
             "handshakings_active.count": self.handshakings_active.count(),
+            "handshakings_pending.count": self.handshakings_pending.count(),
+            "handshakings_pending.max": self.handshakings_pending.max(),
             "handshakings_rate.count": self.handshakings_rate.count() as f64 / 100.0,
             "handshakings_rate.max": self.handshakings_rate.max() as f64 / 100.0,
             "connections.count": self.connections.count(),
@@ -250,6 +365,19 @@ impl Stats {
             "subscriptions_shared.max": self.subscriptions_shared.max(),
             "retained.count": self.retaineds.count(),
             "retained.max": self.retaineds.max(),
+            "memory_budget.count": self.memory_budget.count(),
+            "memory_budget.max": self.memory_budget.max(),
+
+            "process_cpu.count": self.process_cpu.count() as f64 / 100.0,
+            "process_cpu.max": self.process_cpu.max() as f64 / 100.0,
+            "process_memory.count": self.process_memory.count(),
+            "process_memory.max": self.process_memory.max(),
+            "process_fds.count": self.process_fds.count(),
+            "process_fds.max": self.process_fds.max(),
+            "tasks_active.count": self.tasks_active.count(),
+            "tasks_active.max": self.tasks_active.max(),
+            "tasks_waiting.count": self.tasks_waiting.count(),
+            "tasks_waiting.max": self.tasks_waiting.max(),
 
             "topics.count": topics.count(),
             "topics.max": topics.max(),
@@ -257,6 +385,35 @@ impl Stats {
             "routes.max": routes.max(),
         });
 
+        if !self.tagged_connections.is_empty() {
+            if let Some(obj) = json_val.as_object_mut() {
+                let tagged = self
+                    .tagged_connections
+                    .iter()
+                    .map(|(tag, value, n)| json!({"tag": tag, "value": value, "count": n}))
+                    .collect::<Vec<_>>();
+                obj.insert("tagged_connections".into(), json!(tagged));
+            }
+        }
+
+        if !self.tenants.is_empty() {
+            if let Some(obj) = json_val.as_object_mut() {
+                let tenants = self
+                    .tenants
+                    .iter()
+                    .map(|(tenant, messages, bytes, rate_limited)| {
+                        json!({
+                            "tenant": tenant,
+                            "messages": messages,
+                            "bytes": bytes,
+                            "rate_limited": rate_limited,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                obj.insert("tenants".into(), json!(tenants));
+            }
+        }
+
         #[cfg(feature = "debug")]
         {
             if let Some(obj) = json_val.as_object_mut() {
@@ -268,3 +425,170 @@ impl Stats {
         json_val
     }
 }
+
+///Connections of this node by the values of their tags listed in mqtt.tag_metric_labels
+pub struct TaggedConnections {
+    counts: parking_lot::RwLock<BTreeMap<(String, String), isize>>,
+}
+
+impl TaggedConnections {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<TaggedConnections> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { counts: parking_lot::RwLock::new(BTreeMap::new()) })
+    }
+
+    ///Counts the connection by each tag of mqtt.tag_metric_labels it has, returns the counted
+    ///(tag, value), which are given to `decs` when it is closed, even if the labels were reloaded
+    #[inline]
+    pub fn incs(&self, tags: &ClientTags) -> Vec<(String, String)> {
+        self.incs_by(&Runtime::instance().settings.mqtt.tag_metric_labels, tags)
+    }
+
+    #[inline]
+    pub(crate) fn incs_by(&self, labels: &[String], tags: &ClientTags) -> Vec<(String, String)> {
+        if labels.is_empty() || tags.is_empty() {
+            return Vec::new();
+        }
+        let counted = labels
+            .iter()
+            .filter_map(|tag| tags.get(tag).map(|value| (tag.clone(), value.clone())))
+            .collect::<Vec<_>>();
+        let mut counts = self.counts.write();
+        for key in counted.iter() {
+            *counts.entry(key.clone()).or_default() += 1;
+        }
+        counted
+    }
+
+    #[inline]
+    pub fn decs(&self, counted: &[(String, String)]) {
+        if counted.is_empty() {
+            return;
+        }
+        let mut counts = self.counts.write();
+        for key in counted {
+            if let Some(n) = counts.get_mut(key) {
+                *n -= 1;
+                if *n <= 0 {
+                    counts.remove(key);
+                }
+            }
+        }
+    }
+
+    ///(tag, value, connections), sorted by tag and value
+    #[inline]
+    pub fn counts(&self) -> Vec<(String, String, isize)> {
+        self.counts.read().iter().map(|((tag, value), n)| (tag.clone(), value.clone(), *n)).collect()
+    }
+}
+
+///Publishes of the tenants on this node, a tenant is made of the first mqtt.tenant_levels levels
+///of the topic. The tenants idle for an hour are dropped as the tenants grow
+pub struct TenantStats {
+    tenants: DashMap<String, TenantCounts>,
+    sweep_at: AtomicUsize,
+}
+
+#[derive(Default)]
+struct TenantCounts {
+    messages: AtomicUsize,
+    bytes: AtomicUsize,
+    rate_limited: AtomicUsize,
+    active_at: AtomicI64,
+}
+
+impl TenantStats {
+    const MIN_SWEEP_AT: usize = 1024;
+    const IDLE_SECS: i64 = 3600;
+
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<TenantStats> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self {
+            tenants: DashMap::default(),
+            sweep_at: AtomicUsize::new(Self::MIN_SWEEP_AT),
+        })
+    }
+
+    ///Counts a message of the tenant that is within the publish rate limits of the tenant
+    #[inline]
+    pub fn published(&self, tenant: &str, payload_len: usize) {
+        self.with(tenant, |counts| {
+            counts.messages.fetch_add(1, Ordering::Relaxed);
+            counts.bytes.fetch_add(payload_len, Ordering::Relaxed);
+        })
+    }
+
+    ///Counts a message of the tenant refused by the publish rate limits of the tenant
+    #[inline]
+    pub fn rate_limited(&self, tenant: &str) {
+        self.with(tenant, |counts| {
+            counts.rate_limited.fetch_add(1, Ordering::Relaxed);
+        })
+    }
+
+    #[inline]
+    fn with<F: FnOnce(&TenantCounts)>(&self, tenant: &str, f: F) {
+        let now = monotonic_secs();
+        if let Some(counts) = self.tenants.get(tenant) {
+            counts.active_at.store(now, Ordering::Relaxed);
+            return f(&counts);
+        }
+        self.sweep(now);
+        let counts = self.tenants.entry(tenant.to_owned()).or_default();
+        counts.active_at.store(now, Ordering::Relaxed);
+        f(&counts)
+    }
+
+    ///Drops the counts of the idle tenants once their number doubles
+    #[inline]
+    fn sweep(&self, now: i64) {
+        if self.tenants.len() < self.sweep_at.load(Ordering::SeqCst) {
+            return;
+        }
+        self.tenants.retain(|_, counts| now - counts.active_at.load(Ordering::Relaxed) < Self::IDLE_SECS);
+        self.sweep_at.store((self.tenants.len() * 2).max(Self::MIN_SWEEP_AT), Ordering::SeqCst);
+    }
+
+    ///(tenant, messages, bytes, rate limited messages), sorted by tenant
+    #[inline]
+    pub fn counts(&self) -> Vec<(String, usize, usize, usize)> {
+        let mut counts = self
+            .tenants
+            .iter()
+            .map(|entry| {
+                let counts = entry.value();
+                (
+                    entry.key().clone(),
+                    counts.messages.load(Ordering::Relaxed),
+                    counts.bytes.load(Ordering::Relaxed),
+                    counts.rate_limited.load(Ordering::Relaxed),
+                )
+            })
+            .collect::<Vec<_>>();
+        counts.sort_by(|a, b| a.0.cmp(&b.0));
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tenant_stats() {
+        let stats = TenantStats::instance();
+        stats.published("stats-test-t1", 10);
+        stats.published("stats-test-t1", 5);
+        stats.rate_limited("stats-test-t1");
+        stats.published("stats-test-t2", 1);
+        let counts =
+            stats.counts().into_iter().filter(|(t, ..)| t.starts_with("stats-test-")).collect::<Vec<_>>();
+        assert_eq!(
+            counts,
+            vec![("stats-test-t1".to_owned(), 2, 15, 1), ("stats-test-t2".to_owned(), 1, 1, 0)]
+        );
+    }
+}
@@ -4,8 +4,11 @@ use std::cmp::Ord;
 use std::default::Default;
 use std::fmt;
 use std::fmt::Debug;
-use std::hash::Hash;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
 
+use arc_swap::ArcSwap;
+use parking_lot::Mutex;
 use serde::de::Deserialize;
 use serde::ser::Serialize;
 
@@ -18,10 +21,22 @@ pub type Level = ntex_mqtt::TopicLevel;
 pub type Topic = ntex_mqtt::Topic;
 pub type TopicTree<V> = Node<V>;
 
+///The branches and the value sets are shared between the clones of a tree, a modification copies
+///the nodes on its path, each with its map of branches, and the value set it changes
 #[derive(Serialize, Deserialize)]
 pub struct Node<V: Ord> {
-    values: ValueSet<V>,
-    branches: HashMap<Level, Node<V>>,
+    values: Arc<ValueSet<V>>,
+    branches: HashMap<Level, Arc<Node<V>>>,
+}
+
+impl<V> Clone for Node<V>
+where
+    V: Ord + Clone,
+{
+    #[inline]
+    fn clone(&self) -> Self {
+        Self { values: self.values.clone(), branches: self.branches.clone() }
+    }
 }
 
 impl<V> Default for Node<V>
@@ -30,7 +45,7 @@ where
 {
     #[inline]
     fn default() -> Node<V> {
-        Self { values: ValueSet::default(), branches: HashMap::default() }
+        Self { values: Arc::new(ValueSet::default()), branches: HashMap::default() }
     }
 }
 
@@ -57,24 +72,28 @@ where
     #[inline]
     fn _insert(&mut self, mut path: Vec<Level>, value: V) -> bool {
         if let Some(first) = path.pop() {
-            self.branches.entry(first).or_default()._insert(path, value)
+            Arc::make_mut(self.branches.entry(first).or_default())._insert(path, value)
+        } else if self.values.contains(&value) {
+            false
         } else {
-            self.values.insert(value)
+            Arc::make_mut(&mut self.values).insert(value)
         }
     }
 
     #[inline]
     pub fn remove(&mut self, topic_filter: &Topic, value: &V) -> bool {
-        self._remove(topic_filter.levels().as_ref(), value)
+        let path = topic_filter.levels();
+        self.contains(path, value) && self._remove(path, value)
     }
 
     #[inline]
     fn _remove(&mut self, path: &[Level], value: &V) -> bool {
         if path.is_empty() {
-            self.values.remove(value)
+            self.values.contains(value) && Arc::make_mut(&mut self.values).remove(value)
         } else {
             let t = &path[0];
             if let Some(x) = self.branches.get_mut(t) {
+                let x = Arc::make_mut(x);
                 let res = x._remove(&path[1..], value);
                 if x.values.is_empty() && x.branches.is_empty() {
                     self.branches.remove(t);
@@ -86,6 +105,16 @@ where
         }
     }
 
+    ///The value is in the node of the path, checked before a removal so that the nodes are not
+    ///copied for nothing
+    #[inline]
+    fn contains(&self, path: &[Level], value: &V) -> bool {
+        match path.split_first() {
+            None => self.values.contains(value),
+            Some((t, path)) => self.branches.get(t).map(|x| x.contains(path, value)).unwrap_or(false),
+        }
+    }
+
     #[inline]
     pub fn is_match(&self, topic: &Topic) -> bool {
         self.matches(topic).first().is_some()
@@ -175,13 +204,13 @@ where
     }
 
     #[inline]
-    pub fn children(&self) -> &HashMap<Level, Node<V>> {
+    pub fn children(&self) -> &HashMap<Level, Arc<Node<V>>> {
         &self.branches
     }
 
     #[inline]
     pub fn child(&self, l: &Level) -> Option<&Node<V>> {
-        self.branches.get(l)
+        self.branches.get(l).map(|n| n.as_ref())
     }
 
     #[inline]
@@ -213,6 +242,148 @@ where
     }
 }
 
+///Topic tree split into shards by the leading levels of the topic filter, each shard has its own lock,
+///so that subscribe/unsubscribe on different topics do not contend with each other.
+///
+///The leading levels are the first level, or the first two levels if the first level is blank ("/a/b").
+///Topic filters with a wildcard in the leading levels are kept in a separate shard that is
+///matched for every topic.
+pub struct ShardedTopicTree<V: Ord> {
+    shards: Vec<Shard<V>>,
+    wildcards: Shard<V>,
+}
+
+impl<V> ShardedTopicTree<V>
+where
+    V: Hash + Ord + Eq + Clone + Debug + Serialize + Deserialize<'static>,
+{
+    #[inline]
+    pub fn new(shards: usize) -> Self {
+        Self { shards: (0..shards.max(1)).map(|_| Shard::default()).collect(), wildcards: Shard::default() }
+    }
+
+    #[inline]
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    #[inline]
+    pub fn insert(&self, topic_filter: &Topic, value: V) -> bool {
+        self.shard(topic_filter).update(|tree| tree.insert(topic_filter, value))
+    }
+
+    #[inline]
+    pub fn remove(&self, topic_filter: &Topic, value: &V) -> bool {
+        self.shard(topic_filter).update(|tree| tree.remove(topic_filter, value))
+    }
+
+    #[inline]
+    pub fn is_match(&self, topic: &Topic) -> bool {
+        self.shard(topic).load().is_match(topic) || self.wildcards.load().is_match(topic)
+    }
+
+    ///Matched topic filters and their values, the same topic filter may occur more than once
+    #[inline]
+    pub fn matches(&self, topic: &Topic) -> Vec<(TopicFilter, Vec<V>)> {
+        let collect = |tree: &TopicTree<V>| {
+            tree.matches(topic)
+                .iter()
+                .map(|(topic_filter, vs)| {
+                    (topic_filter.to_topic_filter(), vs.into_iter().cloned().collect::<Vec<_>>())
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut matcheds = collect(&self.shard(topic).load());
+        matcheds.extend(collect(&self.wildcards.load()));
+        matcheds
+    }
+
+    #[inline]
+    pub fn values_size(&self) -> usize {
+        self.shards.iter().map(|s| s.load().values_size()).sum::<usize>()
+            + self.wildcards.load().values_size()
+    }
+
+    #[inline]
+    pub fn list(&self, top: usize) -> Vec<String> {
+        let mut out = self.wildcards.load().list(top);
+        for s in self.shards.iter() {
+            out.extend(s.load().list(top));
+        }
+        out
+    }
+
+    #[inline]
+    pub fn clear(&self) {
+        for s in self.shards.iter() {
+            s.update(|tree| *tree = TopicTree::default());
+        }
+        self.wildcards.update(|tree| *tree = TopicTree::default());
+    }
+
+    #[inline]
+    fn shard(&self, topic: &Topic) -> &Shard<V> {
+        let levels = topic.levels();
+        let n = if matches!(levels.first(), Some(Level::Blank)) { 2 } else { 1 };
+        let leading = &levels[..n.min(levels.len())];
+        if leading.iter().any(|l| matches!(l, Level::SingleWildcard | Level::MultiWildcard)) {
+            return &self.wildcards;
+        }
+        let mut hasher = ahash::AHasher::default();
+        leading.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+}
+
+///A shard is read from an immutable snapshot without locking. A modification is made to the
+///writable tree under the lock and published as a new snapshot before the lock is released, the
+///writable tree shares all but the copied nodes with the snapshot.
+struct Shard<V: Ord> {
+    snapshot: ArcSwap<TopicTree<V>>,
+    writer: Mutex<Arc<TopicTree<V>>>,
+}
+
+impl<V> Default for Shard<V>
+where
+    V: Hash + Ord + Eq + Clone + Debug,
+{
+    #[inline]
+    fn default() -> Self {
+        Self {
+            snapshot: ArcSwap::from_pointee(TopicTree::default()),
+            writer: Mutex::new(Arc::new(TopicTree::default())),
+        }
+    }
+}
+
+impl<V> Shard<V>
+where
+    V: Hash + Ord + Eq + Clone + Debug + Serialize + Deserialize<'static>,
+{
+    #[inline]
+    fn load(&self) -> arc_swap::Guard<Arc<TopicTree<V>>> {
+        self.snapshot.load()
+    }
+
+    ///Modify the writable tree, the modification is visible to the readers when this returns
+    #[inline]
+    fn update<R>(&self, f: impl FnOnce(&mut TopicTree<V>) -> R) -> R {
+        let mut tree = self.writer.lock();
+        let res = f(Arc::make_mut(&mut tree));
+        self.snapshot.store(tree.clone());
+        res
+    }
+}
+
+impl<V> Debug for ShardedTopicTree<V>
+where
+    V: Hash + Eq + Ord + Clone + Debug + Serialize + Deserialize<'static>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ShardedTopicTree {{ shards: {}, values_size: {} }}", self.shards(), self.values_size())
+    }
+}
+
 type Item<'a, V> = (Vec<&'a Level>, Vec<&'a V>);
 
 pub struct Matcher<'a, V: Ord> {
@@ -226,7 +397,7 @@ where
 {
     #[inline]
     pub fn iter(&self) -> MatchedIter<'a, V> {
-        MatchedIter::new(self.node, self.path, Vec::new())
+        MatchedIter::new(self.node, self.path, Vec::with_capacity(self.path.len() + 1))
     }
 
     #[inline]
@@ -242,15 +413,29 @@ pub trait VecToString {
 impl<'a> VecToString for Vec<&'a Level> {
     #[inline]
     fn to_string(&self) -> String {
-        self.iter().map(|l| l.to_string()).collect::<Vec<String>>().join("/")
+        join_levels(self.iter().copied(), self.len())
     }
 }
 
 impl<'a> VecToString for &'a [Level] {
     #[inline]
     fn to_string(&self) -> String {
-        self.iter().map(|l| l.to_string()).collect::<Vec<String>>().join("/")
+        join_levels(self.iter(), self.len())
+    }
+}
+
+///Writes the levels into a single buffer, without a String per level and a joined copy
+#[inline]
+fn join_levels<'a>(levels: impl Iterator<Item = &'a Level>, n: usize) -> String {
+    use std::fmt::Write;
+    let mut out = String::with_capacity(n * 8);
+    for (i, l) in levels.enumerate() {
+        if i > 0 {
+            out.push('/');
+        }
+        let _ = write!(out, "{}", l);
     }
+    out
 }
 
 pub trait VecToTopic {
@@ -266,7 +451,7 @@ impl<'a> VecToTopic for Vec<&'a Level> {
 
     #[inline]
     fn to_topic_filter(&self) -> TopicFilter {
-        TopicFilter::from(self.to_topic().to_string())
+        TopicFilter::from(VecToString::to_string(self))
     }
 }
 
@@ -394,9 +579,11 @@ where
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
+    use std::sync::Arc;
 
     use super::super::NodeId;
-    use super::{Topic, TopicTree, VecToString};
+
+    use super::{Level, ShardedTopicTree, Topic, TopicTree, VecToString};
 
     fn match_one(topics: &TopicTree<NodeId>, topic: &str, vs: &[NodeId]) -> bool {
         let mut matcheds = 0;
@@ -527,4 +714,69 @@ mod tests {
         let topics: TopicTree<()> = bincode::deserialize(&bincode::serialize(&topics).unwrap()).unwrap();
         assert_eq!(val_size, topics.values_size());
     }
+
+    #[test]
+    fn topic_clone() {
+        let mut topics: TopicTree<NodeId> = TopicTree::default();
+        topics.insert(&Topic::from_str("/iot/b/x").unwrap(), 1);
+        let snapshot = topics.clone();
+        topics.insert(&Topic::from_str("/iot/b/x").unwrap(), 2);
+        topics.insert(&Topic::from_str("/iot/c").unwrap(), 3);
+        assert!(topics.remove(&Topic::from_str("/iot/b/x").unwrap(), &1));
+
+        assert!(match_one(&snapshot, "/iot/b/x", &[1]));
+        assert!(!snapshot.is_match(&Topic::from_str("/iot/c").unwrap()));
+        assert!(match_one(&topics, "/iot/b/x", &[2]));
+        assert!(match_one(&topics, "/iot/c", &[3]));
+
+        //only the path of a modification is copied, the other branches and value sets are shared
+        topics.insert(&Topic::from_str("/ddl/x").unwrap(), 4);
+        let snapshot = topics.clone();
+        topics.insert(&Topic::from_str("/iot/d").unwrap(), 5);
+        let branch = |tree: &TopicTree<NodeId>, level: &str| {
+            tree.children()[&Level::Blank].children()[&Level::Normal(level.into())].clone()
+        };
+        assert!(Arc::ptr_eq(&branch(&snapshot, "ddl"), &branch(&topics, "ddl")));
+        assert!(!Arc::ptr_eq(&branch(&snapshot, "iot"), &branch(&topics, "iot")));
+        let c = |tree: &TopicTree<NodeId>| branch(tree, "iot").children()[&Level::Normal("c".into())].clone();
+        assert!(Arc::ptr_eq(&c(&snapshot), &c(&topics)));
+        assert!(!topics.remove(&Topic::from_str("/ddl/x").unwrap(), &5));
+        assert!(Arc::ptr_eq(&branch(&snapshot, "ddl"), &branch(&topics, "ddl")));
+    }
+
+    #[test]
+    fn sharded_topic() {
+        let topics: ShardedTopicTree<NodeId> = ShardedTopicTree::new(8);
+        topics.insert(&Topic::from_str("/iot/b/x").unwrap(), 1);
+        topics.insert(&Topic::from_str("/iot/+/x").unwrap(), 2);
+        topics.insert(&Topic::from_str("/+/b/x").unwrap(), 3);
+        topics.insert(&Topic::from_str("#").unwrap(), 4);
+        topics.insert(&Topic::from_str("iot/#").unwrap(), 5);
+        topics.insert(&Topic::from_str("$SYS/#").unwrap(), 6);
+        assert_eq!(topics.values_size(), 6);
+
+        let matched = |topic: &str| {
+            let mut vs = topics
+                .matches(&Topic::from_str(topic).unwrap())
+                .into_iter()
+                .flat_map(|(_, vs)| vs)
+                .collect::<Vec<_>>();
+            vs.sort();
+            vs
+        };
+        assert_eq!(matched("/iot/b/x"), vec![1, 2, 3, 4]);
+        assert_eq!(matched("/iot/c/x"), vec![2, 4]);
+        assert_eq!(matched("iot"), vec![4, 5]);
+        assert_eq!(matched("iot/b/x"), vec![4, 5]);
+        assert_eq!(matched("$SYS/brokers"), vec![6]);
+        assert!(topics.is_match(&Topic::from_str("/xyz").unwrap()));
+
+        assert!(topics.remove(&Topic::from_str("#").unwrap(), &4));
+        assert!(!topics.remove(&Topic::from_str("#").unwrap(), &4));
+        assert!(!topics.is_match(&Topic::from_str("/xyz").unwrap()));
+        assert_eq!(matched("/iot/b/x"), vec![1, 2, 3]);
+
+        topics.clear();
+        assert_eq!(topics.values_size(), 0);
+    }
 }
@@ -0,0 +1,414 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use regex::Regex;
+
+use crate::broker::types::{NodeId, TopicName};
+use crate::{MqttError, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    ///%t
+    Topic,
+    ///%c
+    ClientId,
+    ///%u
+    Username,
+    ///%n
+    Node,
+    ///%1 .. %9, the levels of the topic
+    Level(usize),
+    ///$0 .. $9, the captures of the regex
+    Capture(usize),
+}
+
+///Builds the topic of a message on the remote side of a bridge rule from its local topic.
+///
+///The template is static text with the placeholders %t, the whole topic, %1 .. %9, its levels,
+///%c, the client ID, %u, the username, %n, the node ID, and $0 .. $9, the captures of the regex
+///matched against the topic. %% and $$ are a literal % and $, a $ not followed by a digit is
+///kept as is, such as that of $SYS. With a regex, a topic it does not match is not remapped
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "TopicTemplateConfig", into = "TopicTemplateConfig")]
+pub struct TopicTemplate {
+    cfg: TopicTemplateConfig,
+    parts: Vec<Part>,
+    regex: Option<Regex>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TopicTemplateConfig {
+    pub template: String,
+    #[serde(default)]
+    pub regex: String,
+}
+
+impl TopicTemplate {
+    #[inline]
+    pub fn new(template: &str, regex: &str) -> Result<Self> {
+        Self::try_from(TopicTemplateConfig { template: template.into(), regex: regex.into() })
+    }
+
+    ///The remote topic of a message published by the client, None if the regex does not match the
+    ///topic, if the client ID or the username is not a valid identity, or if the result is empty,
+    ///contains a wildcard or starts with a $ that is not in the text of the template
+    #[inline]
+    pub fn render(
+        &self,
+        topic: &str,
+        client_id: &str,
+        username: Option<&str>,
+        node_id: NodeId,
+    ) -> Option<TopicName> {
+        let captures = match &self.regex {
+            Some(regex) => Some(regex.captures(topic)?),
+            None => None,
+        };
+        let mut out = String::with_capacity(self.cfg.template.len() + topic.len());
+        for part in self.parts.iter() {
+            match part {
+                Part::Text(text) => out.push_str(text),
+                Part::Topic => out.push_str(topic),
+                Part::ClientId if is_valid_identity(client_id) => out.push_str(client_id),
+                Part::Username => match username {
+                    Some(username) if is_valid_identity(username) => out.push_str(username),
+                    Some(_) => return None,
+                    None => {}
+                },
+                Part::ClientId => return None,
+                Part::Node => out.push_str(&node_id.to_string()),
+                Part::Level(n) => out.push_str(topic.split('/').nth(*n - 1).unwrap_or_default()),
+                Part::Capture(n) => out.push_str(
+                    captures.as_ref().and_then(|c| c.get(*n)).map(|m| m.as_str()).unwrap_or_default(),
+                ),
+            }
+        }
+        if out.is_empty() || out.contains(['+', '#']) {
+            return None;
+        }
+        if out.starts_with('$') && !matches!(self.parts.first(), Some(Part::Text(t)) if t.starts_with('$')) {
+            return None;
+        }
+        Some(TopicName::from(out))
+    }
+
+    #[inline]
+    fn parse(template: &str) -> Result<Vec<Part>> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            let is_capture = c == '$' && matches!(chars.peek(), Some('0'..='9' | '$'));
+            if c != '%' && !is_capture {
+                text.push(c);
+                continue;
+            }
+            let part = match (c, chars.next()) {
+                (_, Some(n)) if n == c => {
+                    text.push(c);
+                    continue;
+                }
+                ('%', Some('t')) => Part::Topic,
+                ('%', Some('c')) => Part::ClientId,
+                ('%', Some('u')) => Part::Username,
+                ('%', Some('n')) => Part::Node,
+                ('%', Some(n @ '1'..='9')) => Part::Level(n as usize - '0' as usize),
+                ('$', Some(n @ '0'..='9')) => Part::Capture(n as usize - '0' as usize),
+                (c, n) => {
+                    return Err(MqttError::from(format!(
+                        "invalid placeholder {}{} in topic template {:?}",
+                        c,
+                        n.map(String::from).unwrap_or_default(),
+                        template
+                    )))
+                }
+            };
+            if !text.is_empty() {
+                parts.push(Part::Text(std::mem::take(&mut text)));
+            }
+            parts.push(part);
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(parts)
+    }
+}
+
+impl TryFrom<TopicTemplateConfig> for TopicTemplate {
+    type Error = MqttError;
+
+    #[inline]
+    fn try_from(cfg: TopicTemplateConfig) -> Result<Self> {
+        let parts = Self::parse(&cfg.template)?;
+        let regex = if cfg.regex.is_empty() {
+            if parts.iter().any(|p| matches!(p, Part::Capture(_))) {
+                return Err(MqttError::from(format!(
+                    "topic template {:?} has captures but no regex",
+                    cfg.template
+                )));
+            }
+            None
+        } else {
+            Some(Regex::new(&cfg.regex).map_err(|e| MqttError::from(format!("{:?}, {}", cfg.regex, e)))?)
+        };
+        Ok(Self { cfg, parts, regex })
+    }
+}
+
+impl From<TopicTemplate> for TopicTemplateConfig {
+    #[inline]
+    fn from(t: TopicTemplate) -> Self {
+        t.cfg
+    }
+}
+
+impl fmt::Debug for TopicTemplate {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TopicTemplate({:?}, regex: {:?})", self.cfg.template, self.cfg.regex)
+    }
+}
+
+///Whether a client ID or a username can be substituted in a topic. A value that is empty, contains a
+///wildcard or a / or starts with $ would widen the topic to the topics of the other clients, or to
+///the system topics
+#[inline]
+pub fn is_valid_identity(v: &str) -> bool {
+    !v.is_empty() && !v.starts_with('$') && !v.contains(['+', '#', '/'])
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Level {
+    ///+
+    Single,
+    ///#
+    Multi,
+    Parts(Vec<Part>),
+}
+
+///A topic filter with the placeholders %c, the client ID, and %u, the username, as in the ACL rules,
+///the topic templates of rmqtt-acl and the listener configuration. It is parsed once, with the
+///parser of TopicTemplate, and a check compares the levels of a topic with those of the client
+///without building a topic tree. A placeholder does not match a client ID or a username that is
+///not a valid identity, and %u does not match the clients without a username
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IdentityFilter {
+    cfg: String,
+    levels: Vec<Level>,
+}
+
+impl IdentityFilter {
+    #[inline]
+    pub fn new(topic_filter: &str) -> Result<Self> {
+        Self::try_from(topic_filter.to_owned())
+    }
+
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        &self.cfg
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.cfg.is_empty()
+    }
+
+    #[inline]
+    pub fn has_placeholders(&self) -> bool {
+        self.levels.iter().any(|l| match l {
+            Level::Parts(parts) => parts.iter().any(|p| !matches!(p, Part::Text(_))),
+            _ => false,
+        })
+    }
+
+    ///Whether the topic filter of the client covers the topic, or all the topics of the topic filter.
+    ///Level by level, a + of the topic filter is only covered by a + or a # of this one, and a # only
+    ///by a #. The wildcards at the first level do not cover the topics starting with $
+    #[inline]
+    pub fn is_match(&self, topic: &str, client_id: &str, username: Option<&str>) -> bool {
+        if topic.is_empty() {
+            return false;
+        }
+        let is_sys = topic.starts_with('$');
+        let mut items = topic.split('/');
+        for (i, level) in self.levels.iter().enumerate() {
+            match level {
+                Level::Multi => return !(i == 0 && is_sys),
+                Level::Single => match items.next() {
+                    Some(item) if item != "#" && !(i == 0 && is_sys) => {}
+                    _ => return false,
+                },
+                Level::Parts(parts) => match items.next() {
+                    Some(item) if Self::parts_match(parts, item, client_id, username) => {}
+                    _ => return false,
+                },
+            }
+        }
+        items.next().is_none()
+    }
+
+    ///The topic filter of the client, None if a placeholder is not resolved
+    #[inline]
+    pub fn render(&self, client_id: &str, username: Option<&str>) -> Option<String> {
+        let mut out = String::with_capacity(self.cfg.len() + client_id.len());
+        for (i, level) in self.levels.iter().enumerate() {
+            if i > 0 {
+                out.push('/');
+            }
+            match level {
+                Level::Single => out.push('+'),
+                Level::Multi => out.push('#'),
+                Level::Parts(parts) => {
+                    for part in parts {
+                        out.push_str(Self::resolve(part, client_id, username)?);
+                    }
+                }
+            }
+        }
+        Some(out)
+    }
+
+    #[inline]
+    fn resolve<'a>(part: &'a Part, client_id: &'a str, username: Option<&'a str>) -> Option<&'a str> {
+        let v = match part {
+            Part::Text(text) => return Some(text.as_str()),
+            Part::ClientId => client_id,
+            Part::Username => username?,
+            _ => return None,
+        };
+        if is_valid_identity(v) {
+            Some(v)
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    fn parts_match(parts: &[Part], item: &str, client_id: &str, username: Option<&str>) -> bool {
+        let mut rest = item;
+        for part in parts {
+            rest = match Self::resolve(part, client_id, username).and_then(|v| rest.strip_prefix(v)) {
+                Some(rest) => rest,
+                None => return false,
+            };
+        }
+        rest.is_empty()
+    }
+}
+
+impl TryFrom<String> for IdentityFilter {
+    type Error = MqttError;
+
+    #[inline]
+    fn try_from(cfg: String) -> Result<Self> {
+        let err = || MqttError::from(format!("invalid topic filter {:?}", cfg));
+        let items = cfg.split('/').collect::<Vec<_>>();
+        let mut levels = Vec::with_capacity(items.len());
+        for (i, item) in items.iter().enumerate() {
+            let level = match *item {
+                "#" if i == items.len() - 1 => Level::Multi,
+                "+" => Level::Single,
+                item if item.contains(['+', '#']) => return Err(err()),
+                item => {
+                    let parts = TopicTemplate::parse(item)?;
+                    if parts.iter().any(|p| !matches!(p, Part::Text(_) | Part::ClientId | Part::Username)) {
+                        return Err(MqttError::from(format!(
+                            "only the placeholders %c and %u are allowed in the topic filter {:?}",
+                            cfg
+                        )));
+                    }
+                    Level::Parts(parts)
+                }
+            };
+            levels.push(level);
+        }
+        Ok(Self { cfg, levels })
+    }
+}
+
+impl From<IdentityFilter> for String {
+    #[inline]
+    fn from(f: IdentityFilter) -> Self {
+        f.cfg
+    }
+}
+
+impl fmt::Debug for IdentityFilter {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "IdentityFilter({:?})", self.cfg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            TopicTemplate::parse("a/%t/%1-$2%%$$/$SYS").unwrap(),
+            vec![
+                Part::Text("a/".into()),
+                Part::Topic,
+                Part::Text("/".into()),
+                Part::Level(1),
+                Part::Text("-".into()),
+                Part::Capture(2),
+                Part::Text("%$/$SYS".into()),
+            ]
+        );
+        assert!(TopicTemplate::parse("a/%x").is_err());
+        assert!(TopicTemplate::parse("a/%").is_err());
+        assert!(TopicTemplate::new("a/$1", "").is_err());
+        assert!(TopicTemplate::new("a/$1", "(").is_err());
+    }
+
+    #[test]
+    fn render() {
+        let t = TopicTemplate::new("dc1/%n/%c/%2/$1", "^a/(b)/").unwrap();
+        assert_eq!(t.render("a/b/c", "c1", None, 1).as_deref(), Some("dc1/1/c1/b/b"));
+        assert_eq!(t.render("x/b/c", "c1", None, 1), None);
+        assert_eq!(t.render("a/b/c", "c/1", None, 1), None);
+        assert_eq!(t.render("a/b/c", "$c1", None, 1), None);
+        let t = TopicTemplate::new("%c/%t", "").unwrap();
+        assert_eq!(t.render("a", "c1", None, 1).as_deref(), Some("c1/a"));
+        let t = TopicTemplate::new("%t", "").unwrap();
+        assert_eq!(t.render("$SYS/a", "c1", None, 1), None);
+        let t = TopicTemplate::new("$share/%t", "").unwrap();
+        assert_eq!(t.render("a", "c1", None, 1).as_deref(), Some("$share/a"));
+    }
+
+    #[test]
+    fn identity_filter() {
+        let f = IdentityFilter::new("devices/%c/#").unwrap();
+        assert!(f.has_placeholders());
+        assert!(f.is_match("devices/c1", "c1", None));
+        assert!(f.is_match("devices/c1/a/+", "c1", None));
+        assert!(!f.is_match("devices/c2/a", "c1", None));
+        assert!(!f.is_match("devices/a/b/x", "a/b", None));
+        assert!(!f.is_match("devices/+/x", "+", None));
+        assert_eq!(f.render("c1", None).as_deref(), Some("devices/c1/#"));
+        assert_eq!(f.render("a/b", None), None);
+        assert_eq!(f.render("$SYS", None), None);
+
+        let f = IdentityFilter::new("%u/+/x%c").unwrap();
+        assert!(f.is_match("u1/a/xc1", "c1", Some("u1")));
+        assert!(!f.is_match("u1/#/xc1", "c1", Some("u1")));
+        assert!(!f.is_match("u1/a/xc1", "c1", None));
+        assert_eq!(f.render("c1", None), None);
+
+        let f = IdentityFilter::new("#").unwrap();
+        assert!(!f.has_placeholders());
+        assert!(f.is_match("a/b", "c1", None));
+        assert!(!f.is_match("$SYS/a", "c1", None));
+        assert!(IdentityFilter::new("$SYS/#").unwrap().is_match("$SYS/a", "c1", None));
+
+        assert!(IdentityFilter::new("a/#/b").is_err());
+        assert!(IdentityFilter::new("a/b+").is_err());
+        assert!(IdentityFilter::new("a/%t").is_err());
+    }
+}
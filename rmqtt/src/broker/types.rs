@@ -21,7 +21,7 @@ pub use ntex_mqtt::v3::{
 pub use ntex_mqtt::v5::{
     self, codec::Connect as ConnectV5, codec::ConnectAckReason as ConnectAckReasonV5,
     codec::Disconnect as DisconnectV5, codec::DisconnectReasonCode, codec::LastWill as LastWillV5,
-    codec::Packet as PacketV5, codec::PublishAck2, codec::PublishAck2Reason,
+    codec::Packet as PacketV5, codec::PublishAck2, codec::PublishAck2Reason, codec::PublishAckReason,
     codec::PublishProperties as PublishPropertiesV5, codec::Subscribe as SubscribeV5,
     codec::SubscribeAck as SubscribeAckV5, codec::SubscribeAckReason, codec::SubscriptionOptions,
     codec::Unsubscribe as UnsubscribeV5, codec::UnsubscribeAck as UnsubscribeAckV5, codec::UserProperties,
@@ -31,6 +31,7 @@ use serde::de::{Deserialize, Deserializer};
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 use tokio::sync::oneshot;
 
+use crate::broker::payload_filter::{self, PayloadFilter, PayloadJson};
 use crate::{MqttError, Result, Runtime};
 
 pub type NodeId = u64;
@@ -57,6 +58,8 @@ pub type Timestamp = i64;
 pub type IsOnline = bool;
 pub type IsAdmin = bool;
 pub type LimiterName = u16;
+///Key/value tags attached to a connection by the ClientTagging hook, such as its region or device model
+pub type ClientTags = std::collections::BTreeMap<String, String>;
 
 pub type Tx = futures::channel::mpsc::UnboundedSender<Message>;
 pub type Rx = futures::channel::mpsc::UnboundedReceiver<Message>;
@@ -185,6 +188,15 @@ impl ConnectInfo {
             ConnectInfo::V5(_, _) => MQTT_LEVEL_5,
         }
     }
+
+    ///Whether the client accepts a Reason String on the acks other than CONNACK, MQTT 5.0 only
+    #[inline]
+    pub fn request_problem_info(&self) -> bool {
+        match self {
+            ConnectInfo::V3(_, _) => false,
+            ConnectInfo::V5(_, conn_info) => conn_info.request_problem_info,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -247,12 +259,73 @@ pub enum PublishAclResult {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AuthResult {
     Allow(Superuser),
+    ///Allow, with the publish rate limits of the connection instead of those of the listener
+    AllowWithRateLimit(Superuser, PublishRateLimit),
     ///User is not found
     NotFound,
     BadUsernameOrPassword,
     NotAuthorized,
 }
 
+///Publish rate limits of a connection, 0 means unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct PublishRateLimit {
+    ///Messages per second
+    pub messages: u32,
+    ///Payload bytes per second
+    pub bytes: u64,
+    ///Messages that can be published at once after being idle, 0 means one second of messages
+    #[serde(default)]
+    pub burst_messages: u32,
+    ///Payload bytes that can be published at once after being idle, 0 means one second of bytes
+    #[serde(default)]
+    pub burst_bytes: u64,
+    ///The publishes over the limits are only logged and counted, not refused
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+impl PublishRateLimit {
+    #[inline]
+    pub fn is_unlimited(&self) -> bool {
+        self.messages == 0 && self.bytes == 0
+    }
+
+    ///Capacity of the messages bucket
+    #[inline]
+    pub fn messages_burst(&self) -> u32 {
+        if self.burst_messages > 0 {
+            self.burst_messages
+        } else {
+            self.messages
+        }
+    }
+
+    ///Capacity of the bytes bucket
+    #[inline]
+    pub fn bytes_burst(&self) -> u64 {
+        if self.burst_bytes > 0 {
+            self.burst_bytes
+        } else {
+            self.bytes
+        }
+    }
+}
+
+///FNV-1a, a hash that is the same on all nodes and does not change between builds
+#[inline]
+pub fn fnv1a(data: impl IntoIterator<Item = u8>) -> u64 {
+    data.into_iter().fold(0xcbf29ce484222325, |hash, b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+///Seconds of a monotonic clock since it was first read, for hot paths that only compare the
+///seconds with each other and need no wall clock time
+#[inline]
+pub fn monotonic_secs() -> i64 {
+    static START: once_cell::sync::Lazy<Instant> = once_cell::sync::Lazy::new(Instant::now);
+    START.elapsed().as_secs() as i64
+}
+
 pub fn parse_topic_filter(
     topic_filter: &ByteString,
     shared_subscription_supported: bool,
@@ -287,20 +360,25 @@ pub struct Subscribe {
     pub topic_filter: TopicFilter,
     pub qos: QoS,
     pub shared_group: Option<SharedGroup>,
+    ///Only the messages whose payload matches are delivered
+    pub payload_filter: Option<PayloadFilter>,
 }
 
 impl Subscribe {
     pub fn from_v3(topic_filter: &ByteString, qos: QoS, shared_subscription_supported: bool) -> Result<Self> {
         let (topic_filter, shared_group) = parse_topic_filter(topic_filter, shared_subscription_supported)?;
-        Ok(Subscribe { topic_filter, qos, shared_group })
+        Ok(Subscribe { topic_filter, qos, shared_group, payload_filter: None })
     }
 
     pub fn from_v5(
         topic_filter: &ByteString,
         opt: &SubscriptionOptions,
         shared_subscription_supported: bool,
+        payload_filter: Option<PayloadFilter>,
     ) -> Result<Self> {
-        Subscribe::from_v3(topic_filter, opt.qos, shared_subscription_supported)
+        let mut sub = Subscribe::from_v3(topic_filter, opt.qos, shared_subscription_supported)?;
+        sub.payload_filter = payload_filter;
+        Ok(sub)
     }
 
     #[inline]
@@ -567,6 +645,15 @@ impl<'a> Serialize for LastWill<'a> {
     }
 }
 
+///The user property advising a refused or disconnected 5.0 client how many seconds to wait before
+///it reconnects, None if reconnect_advice is disabled
+#[inline]
+pub(crate) fn retry_after_property() -> Option<UserProperty> {
+    let cfg = &Runtime::instance().settings.reconnect_advice;
+    cfg.retry_after()
+        .map(|secs| (ByteString::from(cfg.user_property.as_str()), ByteString::from(secs.to_string())))
+}
+
 #[derive(Clone, Debug)]
 pub enum Sink {
     V3(MqttSinkV3),
@@ -584,6 +671,32 @@ impl Sink {
         }
     }
 
+    ///Tells the client why the server closes the connection. MQTT 3.1.1 does not define a DISCONNECT
+    ///sent by the server, it is sent to a 3.1.1 client only if v3_disconnect is true.
+    #[inline]
+    pub(crate) fn disconnect(&self, reason_code: DisconnectReasonCode, v3_disconnect: bool) -> Result<()> {
+        match self {
+            Sink::V3(_) if v3_disconnect => self.send(Packet::V3(PacketV3::Disconnect)),
+            Sink::V3(_) => Ok(()),
+            Sink::V5(_) => self.send(Packet::V5(PacketV5::Disconnect(DisconnectV5::new(reason_code)))),
+        }
+    }
+
+    ///Tells the client to reconnect to another server, a 5.0 client also receives the server reference
+    #[inline]
+    pub(crate) fn redirect(&self, server_reference: Option<&str>, v3_disconnect: bool) -> Result<()> {
+        match self {
+            Sink::V3(_) => self.disconnect(DisconnectReasonCode::UseAnotherServer, v3_disconnect),
+            Sink::V5(_) => {
+                let mut d = DisconnectV5::new(DisconnectReasonCode::UseAnotherServer);
+                d.server_reference = server_reference.map(ByteString::from);
+                d.user_properties.extend(retry_after_property());
+                self.send(Packet::V5(PacketV5::Disconnect(d)))
+            }
+        }
+    }
+
+    ///The payload buffer is moved into the packet, the codec then writes it to the connection
     #[inline]
     pub(crate) fn publish(&self, p: Publish) -> Result<()> {
         let pkt = match self {
@@ -690,11 +803,43 @@ pub struct Publish {
     pub topic: TopicName,
     /// only present in PUBLISH Packets where the QoS level is 1 or 2.
     pub packet_id: Option<NonZeroU16>,
-    /// the Application Message that is being published.
+    /// the Application Message that is being published, a reference-counted buffer shared by
+    /// all copies of the message, from the decoded packet to the encoding for each subscriber.
     pub payload: Bytes,
 
     pub properties: PublishProperties,
     pub create_time: TimestampMillis,
+    /// the trace context of the publish span, used to follow the message across the cluster.
+    #[serde(
+        default,
+        skip_serializing_if = "crate::grpc::codec::is_legacy_layout",
+        deserialize_with = "crate::grpc::codec::deserialize_since_legacy"
+    )]
+    pub trace_context: Option<crate::telemetry::TraceContext>,
+    /// the idempotency key of a publish forwarded to other nodes, a forward retried after an
+    /// ambiguous failure is delivered only once.
+    #[serde(
+        default,
+        skip_serializing_if = "crate::grpc::codec::is_legacy_layout",
+        deserialize_with = "crate::grpc::codec::deserialize_since_legacy"
+    )]
+    pub forward_id: Option<ForwardId>,
+}
+
+///Idempotency key of a forwarded publish, the id of the forwarding node and a sequence number
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ForwardId(pub NodeId, pub u64);
+
+impl ForwardId {
+    ///The sequence starts at the startup time in nanoseconds, so the keys of a restarted node are
+    ///not mistaken for the keys it used before
+    #[inline]
+    pub fn next() -> Self {
+        static SEQ: once_cell::sync::Lazy<std::sync::atomic::AtomicU64> = once_cell::sync::Lazy::new(|| {
+            std::sync::atomic::AtomicU64::new(chrono::Local::now().timestamp_millis() as u64 * 1_000_000)
+        });
+        ForwardId(Runtime::instance().node.id(), SEQ.fetch_add(1, std::sync::atomic::Ordering::SeqCst))
+    }
 }
 
 impl<'a> std::convert::TryFrom<LastWill<'a>> for Publish {
@@ -735,6 +880,8 @@ impl<'a> std::convert::TryFrom<LastWill<'a>> for Publish {
 
             properties: PublishProperties::from(user_props),
             create_time: chrono::Local::now().timestamp_millis(),
+            trace_context: None,
+            forward_id: None,
         })
     }
 }
@@ -765,6 +912,8 @@ impl std::convert::TryFrom<&v3::Publish> for Publish {
 
             properties: p_props,
             create_time: chrono::Local::now().timestamp_millis(),
+            trace_context: None,
+            forward_id: None,
         })
     }
 }
@@ -784,6 +933,8 @@ impl std::convert::TryFrom<&v5::Publish> for Publish {
 
             properties: PublishProperties::from(p.packet().properties.clone()),
             create_time: chrono::Local::now().timestamp_millis(),
+            trace_context: None,
+            forward_id: None,
         })
     }
 }
@@ -923,6 +1074,21 @@ impl Id {
         Self::new(node_id, None, None, client_id, None)
     }
 
+    ///The same connection with another client_id
+    #[inline]
+    pub fn with_client_id(&self, client_id: ClientId) -> Self {
+        Self(Arc::new(_Id { client_id, ..self.0.as_ref().clone() }))
+    }
+
+    ///The client_ids "<client_id>-<n>", n from 1 to MAX_CLIENT_ID_SUFFIX, that may be given to a
+    ///connect whose client_id is in use
+    #[inline]
+    pub fn suffixed_client_ids(client_id: &str) -> impl Iterator<Item = ClientId> + '_ {
+        (1..=Self::MAX_CLIENT_ID_SUFFIX).map(move |n| ClientId::from(format!("{}-{}", client_id, n)))
+    }
+
+    pub const MAX_CLIENT_ID_SUFFIX: usize = 100;
+
     #[inline]
     pub fn node(&self) -> NodeId {
         self.node_id
@@ -1032,13 +1198,64 @@ pub struct Retain {
 pub enum Message {
     Forward(From, Publish),
     Kick(oneshot::Sender<()>, Id, IsAdmin),
+    ///Disconnects the client to reconnect to another server, with the server reference if any
+    Redirect(Option<String>),
     Disconnect(Disconnect),
-    Closed(Reason),
+    Closed(DisconnectKind, Reason),
     Keepalive,
     Subscribe(Subscribe, oneshot::Sender<Result<SubscribeReturn>>),
     Unsubscribe(Unsubscribe, oneshot::Sender<Result<()>>),
 }
 
+///Why a connection is closed, the first one recorded for the connection
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectKind {
+    ///A DISCONNECT is received from the client
+    ClientDisconnect,
+    ///The connection is closed by the client or the network without a DISCONNECT
+    RemoteClosed,
+    KeepaliveTimeout,
+    ///A malformed or unexpected packet is received
+    ProtocolError,
+    ///Taken over by a new connection with the same client id
+    SessionTakenOver,
+    ///Kicked by an administrator
+    Kicked,
+    ///A publish is rejected by the ACL with disconnect
+    NotAuthorized,
+    ///Redirected to another node while this node is evacuated
+    Redirected,
+    ServerShutdown,
+    ///A publish, subscribe or unsubscribe fails, or the session is broken
+    Error,
+}
+
+impl DisconnectKind {
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisconnectKind::ClientDisconnect => "client_disconnect",
+            DisconnectKind::RemoteClosed => "remote_closed",
+            DisconnectKind::KeepaliveTimeout => "keepalive_timeout",
+            DisconnectKind::ProtocolError => "protocol_error",
+            DisconnectKind::SessionTakenOver => "session_taken_over",
+            DisconnectKind::Kicked => "kicked",
+            DisconnectKind::NotAuthorized => "not_authorized",
+            DisconnectKind::Redirected => "redirected",
+            DisconnectKind::ServerShutdown => "server_shutdown",
+            DisconnectKind::Error => "error",
+        }
+    }
+}
+
+impl std::fmt::Display for DisconnectKind {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SessionStatus {
     pub id: Id,
@@ -1046,6 +1263,17 @@ pub struct SessionStatus {
     pub handshaking: bool,
 }
 
+///Slow consumption state of a subscriber, for the client_slow hook
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SlowSubscriber {
+    ///Current length of the deliver queue
+    pub queue_len: usize,
+    ///Latency of the last delivered message, from publish to delivery
+    pub latency: Duration,
+    ///How long the subscriber has been slow
+    pub duration: Duration,
+}
+
 #[derive(Deserialize, Serialize, Debug, Default, Clone)]
 pub struct SubsSearchParams {
     #[serde(default)]
@@ -1093,12 +1321,55 @@ impl Deref for SessionSubs {
 
 pub struct _SessionSubs {
     subs: DashMap<TopicFilter, SubscriptionValue>,
+    payload_filters: DashMap<TopicFilter, PayloadFilter>,
+}
+
+impl Drop for _SessionSubs {
+    #[inline]
+    fn drop(&mut self) {
+        payload_filter::count(-(self.payload_filters.len() as isize));
+    }
 }
 
 impl _SessionSubs {
     #[inline]
     pub(crate) fn new() -> Self {
-        Self { subs: DashMap::default() }
+        Self { subs: DashMap::default(), payload_filters: DashMap::default() }
+    }
+
+    ///Sets the payload filter of a subscription, None removes it
+    #[inline]
+    pub fn set_payload_filter(&self, topic_filter: &TopicFilter, payload_filter: Option<PayloadFilter>) {
+        match payload_filter {
+            Some(payload_filter) => {
+                if self.payload_filters.insert(topic_filter.clone(), payload_filter).is_none() {
+                    payload_filter::count(1);
+                }
+            }
+            None => {
+                if self.payload_filters.remove(topic_filter).is_some() {
+                    payload_filter::count(-1);
+                }
+            }
+        }
+    }
+
+    ///Whether the payload is delivered to the subscription, true if it has no payload filter
+    #[inline]
+    pub fn payload_matches(&self, topic_filter: &str, payload: &PayloadJson) -> bool {
+        if self.payload_filters.is_empty() {
+            return true;
+        }
+        self.payload_filters.get(topic_filter).map(|f| f.matches(payload)).unwrap_or(true)
+    }
+
+    ///The payload filters of the subscriptions, by their expressions
+    #[inline]
+    pub fn payload_filters(&self) -> Vec<(TopicFilter, String)> {
+        self.payload_filters
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().as_str().to_owned()))
+            .collect()
     }
 
     #[inline]
@@ -1127,6 +1398,9 @@ impl _SessionSubs {
 
     #[inline]
     pub fn remove(&self, topic_filter: &str) -> Option<(TopicFilter, SubscriptionValue)> {
+        if self.payload_filters.remove(topic_filter).is_some() {
+            payload_filter::count(-1);
+        }
         let removed = self.subs.remove(topic_filter);
         if let Some((_, (_, group))) = &removed {
             Runtime::instance().stats.subscriptions.dec();
@@ -1161,6 +1435,8 @@ impl _SessionSubs {
             }
         }
         self.subs.clear();
+        payload_filter::count(-(self.payload_filters.len() as isize));
+        self.payload_filters.clear();
     }
 
     #[inline]
@@ -1173,6 +1449,11 @@ impl _SessionSubs {
         self.len() == 0
     }
 
+    #[inline]
+    pub fn contains(&self, topic_filter: &str) -> bool {
+        self.subs.contains_key(topic_filter)
+    }
+
     #[inline]
     pub fn to_topic_filters(&self) -> TopicFilters {
         self.subs.iter().map(|entry| TopicFilter::from(entry.key().as_ref())).collect()
@@ -1237,6 +1518,11 @@ impl ExtraAttrs {
         self.attrs.get_mut(key).and_then(|v| v.downcast_mut::<T>())
     }
 
+    #[inline]
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.attrs.remove(key).is_some()
+    }
+
     #[inline]
     pub fn get_default_mut<T: Any + Sync + Send, F: Fn() -> T>(
         &mut self,
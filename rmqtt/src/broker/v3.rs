@@ -3,10 +3,15 @@ use std::net::SocketAddr;
 
 use ntex_mqtt::v3::{self};
 
+use crate::broker::admission::{ConnectionRate, ConnectionSlot, HandshakeAdmission, ListenerConnections};
+use crate::broker::evacuation::Evacuation;
 use crate::broker::executor::get_handshake_exec;
+use crate::broker::proxied::ProxiedPeers;
 use crate::broker::{inflight::MomentStatus, types::*};
 use crate::runtime::Runtime;
 use crate::settings::listener::Listener;
+use crate::settings::DuplicateClientId;
+use crate::telemetry::Span;
 use crate::{ClientInfo, MqttError, Result, Session, SessionState};
 
 #[inline]
@@ -39,6 +44,7 @@ pub async fn handshake<Io: 'static>(
     remote_addr: SocketAddr,
     local_addr: SocketAddr,
 ) -> Result<v3::HandshakeAck<Io, SessionState>, MqttError> {
+    let remote_addr = ProxiedPeers::instance().resolve(remote_addr);
     log::debug!(
         "new Connection: local_addr: {:?}, remote: {:?}, {:?}, listen_cfg: {:?}",
         local_addr,
@@ -55,10 +61,70 @@ pub async fn handshake<Io: 'static>(
         handshake.packet().username.clone(),
     );
 
+    let span = Span::start("mqtt.connect", None);
+    span.set_attribute("mqtt.clientid", &*id.client_id);
+    span.set_attribute_with("net.peer.addr", || remote_addr.to_string());
+
     Runtime::instance().stats.handshakings.max_max(handshake.handshakings());
 
+    if Evacuation::instance().is_evacuating() {
+        let connect_info = ConnectInfo::V3(id.clone(), handshake.packet().clone());
+        return Ok(refused_ack(
+            handshake,
+            &connect_info,
+            ConnectAckReasonV3::ServiceUnavailable,
+            "the node is evacuated".into(),
+        )
+        .await);
+    }
+
+    if !ConnectionRate::instance().check(&listen_cfg) {
+        let connect_info = ConnectInfo::V3(id.clone(), handshake.packet().clone());
+        return Ok(refused_ack(
+            handshake,
+            &connect_info,
+            ConnectAckReasonV3::ServiceUnavailable,
+            format!(
+                "connection rate of the listener is exceeded, max_conn_rate: {}",
+                listen_cfg.max_conn_rate
+            ),
+        )
+        .await);
+    }
+
+    let slot = match ListenerConnections::instance().acquire(listen_cfg.addr, listen_cfg.max_connections) {
+        Some(slot) => slot,
+        None => {
+            let connect_info = ConnectInfo::V3(id.clone(), handshake.packet().clone());
+            return Ok(refused_ack(
+                handshake,
+                &connect_info,
+                ConnectAckReasonV3::ServiceUnavailable,
+                format!(
+                    "connections of the listener are exceeded, max_connections: {}",
+                    listen_cfg.max_connections
+                ),
+            )
+            .await);
+        }
+    };
+
+    let _admitted = match HandshakeAdmission::instance().admit().await {
+        Some(admitted) => admitted,
+        None => {
+            let connect_info = ConnectInfo::V3(id.clone(), handshake.packet().clone());
+            return Ok(refused_ack(
+                handshake,
+                &connect_info,
+                ConnectAckReasonV3::ServiceUnavailable,
+                "handshake is shed by admission control".into(),
+            )
+            .await);
+        }
+    };
+
     let exec = get_handshake_exec(local_addr.port(), listen_cfg.clone());
-    match exec.spawn(_handshake(id.clone(), listen_cfg, handshake)).await {
+    match exec.spawn(_handshake(id.clone(), listen_cfg, handshake, slot)).await {
         Ok(Ok(res)) => Ok(res),
         Ok(Err(e)) => {
             log::warn!("{:?} Connection Refused, handshake error, reason: {:?}", id, e);
@@ -73,11 +139,12 @@ pub async fn handshake<Io: 'static>(
 
 #[inline]
 async fn _handshake<Io: 'static>(
-    id: Id,
+    mut id: Id,
     listen_cfg: Listener,
     mut handshake: v3::Handshake<Io>,
+    slot: ConnectionSlot,
 ) -> Result<v3::HandshakeAck<Io, SessionState>, MqttError> {
-    let connect_info = ConnectInfo::V3(id.clone(), handshake.packet().clone());
+    let mut connect_info = ConnectInfo::V3(id.clone(), handshake.packet().clone());
 
     //hook, client connect
     let _ = Runtime::instance().extends.hook_mgr().await.client_connect(&connect_info).await;
@@ -92,8 +159,34 @@ async fn _handshake<Io: 'static>(
         .await);
     }
 
+    let is_v31 = handshake.packet().protocol.level() == MQTT_LEVEL_31;
+    if is_v31 {
+        if !listen_cfg.mqtt_v31 {
+            return Ok(refused_ack(
+                handshake,
+                &connect_info,
+                ConnectAckReasonV3::UnacceptableProtocolVersion,
+                "MQTT 3.1 is not accepted by the listener".into(),
+            )
+            .await);
+        }
+        //MQTT 3.1 has no server assigned client ids
+        if id.client_id.is_empty()
+            || (listen_cfg.mqtt_v31_max_clientid_len > 0
+                && id.client_id.chars().count() > listen_cfg.mqtt_v31_max_clientid_len)
+        {
+            return Ok(refused_ack(
+                handshake,
+                &connect_info,
+                ConnectAckReasonV3::IdentifierRejected,
+                "client_id is empty or too long for MQTT 3.1".into(),
+            )
+            .await);
+        }
+    }
+
     //hook, client authenticate
-    let (ack, superuser) = Runtime::instance()
+    let (ack, superuser, publish_rate_limit) = Runtime::instance()
         .extends
         .hook_mgr()
         .await
@@ -110,7 +203,23 @@ async fn _handshake<Io: 'static>(
     let sink = handshake.sink();
     let packet = handshake.packet_mut();
 
-    let mut entry = match { Runtime::instance().extends.shared().await.entry(id.clone()) }.try_lock().await {
+    //MQTT 3.1.1 has no Assigned Client Identifier, a client could not know its suffixed client_id
+    let policy = match Runtime::instance().settings.mqtt.duplicate_clientid {
+        DuplicateClientId::Suffix => DuplicateClientId::Reject,
+        policy => policy,
+    };
+    let entry =
+        { Runtime::instance().extends.shared().await.entry(id.clone()) }.try_lock_connect(policy).await;
+    let mut entry = match entry {
+        Err(MqttError::ClientIdInUse) => {
+            return Ok(refused_ack(
+                handshake,
+                &connect_info,
+                ConnectAckReasonV3::IdentifierRejected,
+                "client_id is in use".into(),
+            )
+            .await);
+        }
         Err(e) => {
             return Ok(refused_ack(
                 handshake,
@@ -123,6 +232,13 @@ async fn _handshake<Io: 'static>(
         Ok(entry) => entry,
     };
 
+    //The client_id is in use, the connection continues with a suffixed client_id
+    if entry.id().client_id != id.client_id {
+        id = entry.id();
+        packet.client_id = id.client_id.clone();
+        connect_info = ConnectInfo::V3(id.clone(), packet.clone());
+    }
+
     // Kick out the current session, if it exists
     let (session_present, offline_info) = match entry.kick(packet.clean_session, false).await {
         Err(e) => {
@@ -139,7 +255,7 @@ async fn _handshake<Io: 'static>(
     };
 
     let connected_at = chrono::Local::now().timestamp_millis();
-    let client = ClientInfo::new(connect_info, session_present, superuser, connected_at);
+    let client = ClientInfo::new(connect_info, session_present, superuser, publish_rate_limit, connected_at);
     let fitter =
         Runtime::instance().extends.fitter_mgr().await.get(client.clone(), id.clone(), listen_cfg.clone());
 
@@ -152,6 +268,7 @@ async fn _handshake<Io: 'static>(
         listen_cfg,
         fitter.max_mqueue_len(),
         fitter.max_inflight().get() as usize,
+        fitter.max_subscriptions(),
         created_at,
     );
 
@@ -176,7 +293,7 @@ async fn _handshake<Io: 'static>(
     }
 
     let (state, tx) =
-        SessionState::new(session, client, Sink::V3(sink), hook, fitter).start(keep_alive).await;
+        SessionState::new(session, client, Sink::V3(sink), hook, fitter, slot).start(keep_alive).await;
     if let Err(e) = entry.set(state.session.clone(), tx, state.client.clone()).await {
         return Ok(refused_ack(
             handshake,
@@ -198,6 +315,9 @@ async fn _handshake<Io: 'static>(
         )
         .await;
 
+    //hook, client tagging
+    state.hook.client_tagging().await;
+
     //hook, client connected
     state.hook.client_connected().await;
 
@@ -212,7 +332,8 @@ async fn _handshake<Io: 'static>(
         });
     }
 
-    Ok(handshake.ack(state, session_present).idle_timeout(keep_alive))
+    //the Session Present flag is reserved in MQTT 3.1
+    Ok(handshake.ack(state, session_present && !is_v31).idle_timeout(keep_alive))
 }
 
 async fn subscribes(
@@ -260,7 +381,10 @@ pub async fn control_message(
             Err(e) => {
                 state
                     .client
-                    .add_disconnected_reason(Reason::from(format!("Subscribe failed, {:?}", e)))
+                    .add_disconnected(
+                        DisconnectKind::Error,
+                        Reason::from(format!("Subscribe failed, {:?}", e)),
+                    )
                     .await;
                 log::error!("{:?} Subscribe failed, reason: {:?}", state.id, e);
                 return Err(e);
@@ -271,7 +395,10 @@ pub async fn control_message(
             Err(e) => {
                 state
                     .client
-                    .add_disconnected_reason(Reason::from(format!("Unsubscribe failed, {:?}", e)))
+                    .add_disconnected(
+                        DisconnectKind::Error,
+                        Reason::from(format!("Unsubscribe failed, {:?}", e)),
+                    )
                     .await;
                 log::error!("{:?} Unsubscribe failed, reason: {:?}", state.id, e);
                 return Err(e);
@@ -284,7 +411,10 @@ pub async fn control_message(
             disc.ack()
         }
         v3::ControlMessage::Closed(m) => {
-            if let Err(e) = state.send(Message::Closed(Reason::from_static("Remote close connect"))) {
+            if let Err(e) = state.send(Message::Closed(
+                DisconnectKind::RemoteClosed,
+                Reason::from_static("Remote close connect"),
+            )) {
                 log::debug!("{:?} Closed error, reason: {:?}", state.id, e);
             }
             m.ack()
@@ -313,6 +443,7 @@ pub async fn publish(state: v3::Session<SessionState>, pub_msg: v3::PublishMessa
         }
         v3::PublishMessage::PublishAck(packet_id) => {
             if let Some(iflt_msg) = state.inflight_win.write().await.remove(&packet_id.get()) {
+                state.stats.acked_inc();
                 //hook, message_ack
                 state.hook.message_acked(iflt_msg.from, &iflt_msg.publish).await;
             }
@@ -322,6 +453,7 @@ pub async fn publish(state: v3::Session<SessionState>, pub_msg: v3::PublishMessa
         }
         v3::PublishMessage::PublishComplete(packet_id) => {
             if let Some(iflt_msg) = state.inflight_win.write().await.remove(&packet_id.get()) {
+                state.stats.acked_inc();
                 //hook, message_ack
                 state.hook.message_acked(iflt_msg.from, &iflt_msg.publish).await;
             }
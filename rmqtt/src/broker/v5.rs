@@ -1,12 +1,20 @@
 use std::convert::From as _f;
 use std::net::SocketAddr;
+use std::str::FromStr;
 
 use ntex_mqtt::v5;
 use ntex_mqtt::v5::codec::{Auth, DisconnectReasonCode};
 
+use bytestring::ByteString;
+
+use crate::broker::admission::{ConnectionRate, ConnectionSlot, HandshakeAdmission, ListenerConnections};
+use crate::broker::evacuation::Evacuation;
 use crate::broker::executor::get_handshake_exec;
+use crate::broker::payload_filter::PayloadFilter;
+use crate::broker::proxied::ProxiedPeers;
 use crate::broker::{inflight::MomentStatus, types::*};
 use crate::settings::listener::Listener;
+use crate::telemetry::Span;
 use crate::{ClientInfo, MqttError, Result, Runtime, Session, SessionState};
 
 #[inline]
@@ -29,7 +37,55 @@ async fn refused_ack<Io>(
         new_ack_code,
         reason,
     );
-    new_ack_code.v5_error_ack(handshake)
+    match new_ack_code {
+        //Advises the shed client when to retry, so that a mass reconnect is spread out
+        ConnectAckReason::V5(reason_code @ ConnectAckReasonV5::ServerBusy) => {
+            handshake.fail_with(v5::codec::ConnectAck {
+                reason_code,
+                user_properties: retry_after_property().into_iter().collect(),
+                ..Default::default()
+            })
+        }
+        //Tells the client which quota was exceeded
+        ConnectAckReason::V5(
+            reason_code @ (ConnectAckReasonV5::QuotaExceeded | ConnectAckReasonV5::ConnectionRateExceeded),
+        ) => handshake.fail_with(v5::codec::ConnectAck {
+            reason_code,
+            reason_string: Some(ByteString::from(reason)),
+            user_properties: retry_after_property().into_iter().collect(),
+            ..Default::default()
+        }),
+        _ => new_ack_code.v5_error_ack(handshake),
+    }
+}
+
+///Refuses a connection while the node is evacuated, with the server the client should connect to
+async fn redirected_ack<Io>(
+    handshake: v5::Handshake<Io>,
+    connect_info: &ConnectInfo,
+    server_reference: Option<String>,
+) -> v5::HandshakeAck<Io, SessionState> {
+    let ack_code = Runtime::instance()
+        .extends
+        .hook_mgr()
+        .await
+        .client_connack(connect_info, ConnectAckReason::V5(ConnectAckReasonV5::UseAnotherServer))
+        .await;
+    log::info!(
+        "{:?} Connection Refused, the node is evacuated, ack_code: {:?}, server_reference: {:?}",
+        connect_info.id(),
+        ack_code,
+        server_reference
+    );
+    match ack_code {
+        ConnectAckReason::V5(reason_code) => handshake.fail_with(v5::codec::ConnectAck {
+            reason_code,
+            server_reference: server_reference.map(ByteString::from),
+            user_properties: retry_after_property().into_iter().collect(),
+            ..Default::default()
+        }),
+        _ => ack_code.v5_error_ack(handshake),
+    }
 }
 
 #[inline]
@@ -39,6 +95,7 @@ pub async fn handshake<Io: 'static>(
     remote_addr: SocketAddr,
     local_addr: SocketAddr,
 ) -> Result<v5::HandshakeAck<Io, SessionState>, MqttError> {
+    let remote_addr = ProxiedPeers::instance().resolve(remote_addr);
     log::debug!(
         "new Connection: local_addr: {:?}, remote: {:?}, {:?}, listen_cfg: {:?}",
         local_addr,
@@ -55,10 +112,65 @@ pub async fn handshake<Io: 'static>(
         handshake.packet().username.clone(),
     );
 
+    let span = Span::start("mqtt.connect", None);
+    span.set_attribute("mqtt.clientid", &*id.client_id);
+    span.set_attribute_with("net.peer.addr", || remote_addr.to_string());
+
     Runtime::instance().stats.handshakings.max_max(handshake.handshakings());
 
+    if Evacuation::instance().is_evacuating() {
+        let connect_info = ConnectInfo::V5(id.clone(), Box::new(handshake.packet().clone()));
+        let server_reference = Evacuation::instance().server_reference();
+        return Ok(redirected_ack(handshake, &connect_info, server_reference).await);
+    }
+
+    if !ConnectionRate::instance().check(&listen_cfg) {
+        let connect_info = ConnectInfo::V5(id.clone(), Box::new(handshake.packet().clone()));
+        return Ok(refused_ack(
+            handshake,
+            &connect_info,
+            ConnectAckReasonV5::ConnectionRateExceeded,
+            format!(
+                "connection rate of the listener is exceeded, max_conn_rate: {}",
+                listen_cfg.max_conn_rate
+            ),
+        )
+        .await);
+    }
+
+    let slot = match ListenerConnections::instance().acquire(listen_cfg.addr, listen_cfg.max_connections) {
+        Some(slot) => slot,
+        None => {
+            let connect_info = ConnectInfo::V5(id.clone(), Box::new(handshake.packet().clone()));
+            return Ok(refused_ack(
+                handshake,
+                &connect_info,
+                ConnectAckReasonV5::QuotaExceeded,
+                format!(
+                    "connections of the listener are exceeded, max_connections: {}",
+                    listen_cfg.max_connections
+                ),
+            )
+            .await);
+        }
+    };
+
+    let _admitted = match HandshakeAdmission::instance().admit().await {
+        Some(admitted) => admitted,
+        None => {
+            let connect_info = ConnectInfo::V5(id.clone(), Box::new(handshake.packet().clone()));
+            return Ok(refused_ack(
+                handshake,
+                &connect_info,
+                ConnectAckReasonV5::ServerBusy,
+                "handshake is shed by admission control".into(),
+            )
+            .await);
+        }
+    };
+
     let exec = get_handshake_exec(local_addr.port(), listen_cfg.clone());
-    match exec.spawn(_handshake(id.clone(), listen_cfg, handshake)).await {
+    match exec.spawn(_handshake(id.clone(), listen_cfg, handshake, slot)).await {
         Ok(Ok(res)) => Ok(res),
         Ok(Err(e)) => {
             log::warn!("{:?} Connection Refused, handshake error, reason: {:?}", id, e);
@@ -73,11 +185,12 @@ pub async fn handshake<Io: 'static>(
 
 #[inline]
 pub async fn _handshake<Io: 'static>(
-    id: Id,
+    mut id: Id,
     listen_cfg: Listener,
     mut handshake: v5::Handshake<Io>,
+    slot: ConnectionSlot,
 ) -> Result<v5::HandshakeAck<Io, SessionState>, MqttError> {
-    let connect_info = ConnectInfo::V5(id.clone(), Box::new(handshake.packet().clone()));
+    let mut connect_info = ConnectInfo::V5(id.clone(), Box::new(handshake.packet().clone()));
 
     //hook, client connect
     let _user_props = Runtime::instance().extends.hook_mgr().await.client_connect(&connect_info).await;
@@ -104,7 +217,7 @@ pub async fn _handshake<Io: 'static>(
     }
 
     //hook, client authenticate
-    let (ack, superuser) = Runtime::instance()
+    let (ack, superuser, publish_rate_limit) = Runtime::instance()
         .extends
         .hook_mgr()
         .await
@@ -121,7 +234,19 @@ pub async fn _handshake<Io: 'static>(
     let sink = handshake.sink();
     let packet = handshake.packet_mut();
 
-    let mut entry = match { Runtime::instance().extends.shared().await.entry(id.clone()) }.try_lock().await {
+    let policy = Runtime::instance().settings.mqtt.duplicate_clientid;
+    let entry =
+        { Runtime::instance().extends.shared().await.entry(id.clone()) }.try_lock_connect(policy).await;
+    let mut entry = match entry {
+        Err(MqttError::ClientIdInUse) => {
+            return Ok(refused_ack(
+                handshake,
+                &connect_info,
+                ConnectAckReasonV5::ClientIdentifierNotValid,
+                "client_id is in use".into(),
+            )
+            .await);
+        }
         Err(e) => {
             return Ok(refused_ack(
                 handshake,
@@ -134,6 +259,16 @@ pub async fn _handshake<Io: 'static>(
         Ok(entry) => entry,
     };
 
+    //The client_id is in use, the connection continues with a suffixed client_id
+    let assigned_client_id = if entry.id().client_id != id.client_id {
+        id = entry.id();
+        packet.client_id = id.client_id.clone();
+        connect_info = ConnectInfo::V5(id.clone(), Box::new(packet.clone()));
+        Some(id.client_id.clone())
+    } else {
+        None
+    };
+
     // Kick out the current session, if it exists
     let (session_present, offline_info) = match entry.kick(packet.clean_start, false).await {
         Err(e) => {
@@ -150,7 +285,7 @@ pub async fn _handshake<Io: 'static>(
     };
 
     let connected_at = chrono::Local::now().timestamp_millis();
-    let client = ClientInfo::new(connect_info, session_present, superuser, connected_at);
+    let client = ClientInfo::new(connect_info, session_present, superuser, publish_rate_limit, connected_at);
 
     let fitter =
         Runtime::instance().extends.fitter_mgr().await.get(client.clone(), id.clone(), listen_cfg.clone());
@@ -160,8 +295,14 @@ pub async fn _handshake<Io: 'static>(
         if let Some(ref offline_info) = offline_info { offline_info.created_at } else { connected_at };
 
     let max_inflight = fitter.max_inflight();
-    let session =
-        Session::new(id, listen_cfg, fitter.max_mqueue_len(), max_inflight.get() as usize, created_at);
+    let session = Session::new(
+        id,
+        listen_cfg,
+        fitter.max_mqueue_len(),
+        max_inflight.get() as usize,
+        fitter.max_subscriptions(),
+        created_at,
+    );
 
     let keep_alive = match fitter.keep_alive(&mut packet.keep_alive) {
         Ok(keep_alive) => keep_alive,
@@ -184,7 +325,7 @@ pub async fn _handshake<Io: 'static>(
     }
 
     let (state, tx) =
-        SessionState::new(session, client, Sink::V5(sink), hook, fitter).start(keep_alive).await;
+        SessionState::new(session, client, Sink::V5(sink), hook, fitter, slot).start(keep_alive).await;
 
     if let Err(e) = entry.set(state.session.clone(), tx, state.client.clone()).await {
         return Ok(refused_ack(
@@ -204,6 +345,9 @@ pub async fn _handshake<Io: 'static>(
         .client_connack(&state.client.connect_info, ConnectAckReason::V5(ConnectAckReasonV5::Success))
         .await;
 
+    //hook, client tagging
+    state.hook.client_tagging().await;
+
     //hook, client connected
     state.hook.client_connected().await;
 
@@ -220,8 +364,18 @@ pub async fn _handshake<Io: 'static>(
 
     log::debug!("{:?} keep_alive: {}", state.id, keep_alive);
     let id = state.id.clone();
-    let session_expiry_interval_secs = packet.session_expiry_interval_secs;
+    //The Session Expiry Interval is returned as lowered by the listener
+    let session_expiry_interval_secs =
+        packet.session_expiry_interval_secs.map(|_| state.fitter.session_expiry_interval().as_secs() as u32);
     let server_keepalive_sec = packet.keep_alive;
+    let response_info = if packet.request_response_info {
+        state
+            .listen_cfg
+            .response_info(&state.id.client_id, state.id.username.as_deref())
+            .map(ByteString::from)
+    } else {
+        None
+    };
     let max_qos = state.listen_cfg.max_qos_allowed;
     let retain_available = Runtime::instance().extends.retain().await.is_supported(&state.listen_cfg);
     let max_packet_size = state.fitter.max_packet_size();
@@ -235,7 +389,8 @@ pub async fn _handshake<Io: 'static>(
         ack.max_qos = Some(max_qos);
         ack.retain_available = Some(retain_available);
         ack.max_packet_size = Some(max_packet_size);
-        //ack.assigned_client_id = None; //@TODO ... If the client ID is assigned by the broker, the server needs to return the client ID to the terminal.
+        ack.assigned_client_id = assigned_client_id;
+        ack.response_info = response_info;
         ack.topic_alias_max = 0; //@TODO ...
         ack.wildcard_subscription_available = Some(true);
         ack.subscription_identifiers_available = Some(false);
@@ -251,18 +406,51 @@ async fn subscribes(
 ) -> Result<v5::ControlResult> {
     let shared_subscription_supported =
         Runtime::instance().extends.shared_subscription().await.is_supported(&state.listen_cfg);
+    //The payload filter applies to all the topic filters of the SUBSCRIBE packet
+    let payload_filter = match payload_filter(&subs.packet().user_properties) {
+        Ok(payload_filter) => payload_filter,
+        Err(e) => {
+            log::warn!("{:?} subscribe, {:?}", state.id, e);
+            for mut sub in subs.iter_mut() {
+                sub.fail(SubscribeAckReason::ImplementationSpecificError)
+            }
+            return Ok(subs.ack());
+        }
+    };
+    let mut quota_exceeded = false;
     for mut sub in subs.iter_mut() {
-        let s = Subscribe::from_v5(sub.topic(), sub.options(), shared_subscription_supported)?;
+        let s = Subscribe::from_v5(
+            sub.topic(),
+            sub.options(),
+            shared_subscription_supported,
+            payload_filter.clone(),
+        )?;
         let sub_ret = state.subscribe(s).await?;
         if let Some(qos) = sub_ret.success() {
             sub.confirm(qos)
         } else {
+            quota_exceeded |= matches!(sub_ret.0, SubscribeAckReason::QuotaExceeded);
             sub.fail(sub_ret.into_inner())
         }
     }
+    if quota_exceeded && state.client.connect_info.request_problem_info() {
+        let reason =
+            format!("subscription quota is exceeded, max_subscriptions: {}", state.max_subscriptions);
+        return Ok(subs.ack_reason(ByteString::from(reason)).ack());
+    }
     Ok(subs.ack())
 }
 
+///The payload filter of the mqtt.payload_filter_user_property of a SUBSCRIBE
+#[inline]
+fn payload_filter(props: &UserProperties) -> Result<Option<PayloadFilter>> {
+    let name = &Runtime::instance().settings.mqtt.payload_filter_user_property;
+    if name.is_empty() {
+        return Ok(None);
+    }
+    props.iter().find(|(k, _)| &**k == name.as_str()).map(|(_, v)| PayloadFilter::from_str(v)).transpose()
+}
+
 async fn unsubscribes(
     state: &v5::Session<SessionState>,
     unsubs: v5::control::Unsubscribe,
@@ -291,7 +479,10 @@ pub async fn control_message<E: std::fmt::Debug>(
             Err(e) => {
                 state
                     .client
-                    .add_disconnected_reason(Reason::from(format!("Subscribe failed, {:?}", e)))
+                    .add_disconnected(
+                        DisconnectKind::Error,
+                        Reason::from(format!("Subscribe failed, {:?}", e)),
+                    )
                     .await;
                 log::error!("{:?} Subscribe failed, reason: {:?}", state.id, e);
                 return Err(e);
@@ -302,7 +493,10 @@ pub async fn control_message<E: std::fmt::Debug>(
             Err(e) => {
                 state
                     .client
-                    .add_disconnected_reason(Reason::from(format!("Unsubscribe failed, {:?}", e)))
+                    .add_disconnected(
+                        DisconnectKind::Error,
+                        Reason::from(format!("Unsubscribe failed, {:?}", e)),
+                    )
                     .await;
                 log::error!("{:?} Unsubscribe failed, reason: {:?}", state.id, e);
                 return Err(e);
@@ -315,21 +509,27 @@ pub async fn control_message<E: std::fmt::Debug>(
             disconnect.ack()
         }
         v5::ControlMessage::Closed(closed) => {
-            if let Err(e) = state.send(Message::Closed(Reason::from_static("Remote close connect"))) {
+            if let Err(e) = state.send(Message::Closed(
+                DisconnectKind::RemoteClosed,
+                Reason::from_static("Remote close connect"),
+            )) {
                 log::debug!("{:?} Closed error, reason: {:?}", state.id, e);
             }
             closed.ack()
         }
         v5::ControlMessage::Error(err) => {
-            if let Err(e) = state.send(Message::Closed(Reason::from(format!("{:?}", err.get_err())))) {
+            if let Err(e) = state
+                .send(Message::Closed(DisconnectKind::Error, Reason::from(format!("{:?}", err.get_err()))))
+            {
                 log::debug!("{:?} Closed error, reason: {:?}", state.id, e);
             }
             err.ack(DisconnectReasonCode::ServerBusy)
         }
         v5::ControlMessage::ProtocolError(protocol_error) => {
-            if let Err(e) =
-                state.send(Message::Closed(Reason::from(format!("{:?}", protocol_error.get_ref()))))
-            {
+            if let Err(e) = state.send(Message::Closed(
+                DisconnectKind::ProtocolError,
+                Reason::from(format!("{:?}", protocol_error.get_ref())),
+            )) {
                 log::debug!("{:?} Closed error, reason: {:?}", state.id, e);
             }
             protocol_error.ack()
@@ -351,6 +551,13 @@ pub async fn publish(
     match &pub_msg {
         v5::PublishMessage::Publish(publish) => {
             if let Err(e) = state.publish_v5(publish).await {
+                if let MqttError::PublishAckReason(reason, reason_string) = e {
+                    log::debug!("{:?} Publish refused, reason: {:?}", state.id, reason_string);
+                    if matches!(reason, PublishAckReason::QuotaExceeded) {
+                        Runtime::instance().metrics.client_publish_quota_exceeded_inc();
+                    }
+                    return Ok(pub_msg.ack_reason(reason, reason_string));
+                }
                 log::error!(
                     "{:?} Publish failed, reason: {:?}",
                     state.id,
@@ -361,6 +568,7 @@ pub async fn publish(
         }
         v5::PublishMessage::PublishAck(ack) => {
             if let Some(iflt_msg) = state.inflight_win.write().await.remove(&ack.packet_id.get()) {
+                state.stats.acked_inc();
                 //hook, message_ack
                 state.hook.message_acked(iflt_msg.from, &iflt_msg.publish).await;
             }
@@ -370,6 +578,7 @@ pub async fn publish(
         }
         v5::PublishMessage::PublishComplete(ack2) => {
             if let Some(iflt_msg) = state.inflight_win.write().await.remove(&ack2.packet_id.get()) {
+                state.stats.acked_inc();
                 //hook, message_ack
                 state.hook.message_acked(iflt_msg.from, &iflt_msg.publish).await;
             }
@@ -9,16 +9,19 @@ use tokio::sync::oneshot::Sender as OneshotSender;
 use tokio::sync::RwLock;
 use tonic::transport::{Channel, Endpoint};
 
-use crate::{MqttError, Result, Runtime};
+use crate::{MqttError, NodeId, Result, Runtime};
 
 use super::pb::{self, node_service_client::NodeServiceClient};
-use super::{Message, MessageReply, MessageType};
+use super::protocol::{self, PeerProtocol, CAPABILITIES, LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use super::{codec, Message, MessageReply, MessageType};
 
 type NodeServiceClientType = NodeServiceClient<Channel>;
 
 #[derive(Clone)]
 pub struct NodeGrpcClient {
     grpc_client: Arc<RwLock<Option<NodeServiceClientType>>>,
+    node_id: Option<NodeId>,
+    peer: Arc<RwLock<Option<Arc<PeerProtocol>>>>,
     active_tasks: Arc<AtomicUsize>,
     channel_tasks: Arc<AtomicUsize>,
     endpoint: Endpoint,
@@ -29,6 +32,13 @@ impl NodeGrpcClient {
     //server_addr - ip:port, 127.0.0.1:6666
     #[inline]
     pub async fn new(server_addr: &str) -> Result<Self> {
+        Self::with_node_id(server_addr, None).await
+    }
+
+    ///Client of a cluster node whose id is known, so that the peer is registered by its id even if
+    ///it does not negotiate the protocol
+    #[inline]
+    pub async fn with_node_id(server_addr: &str, node_id: Option<NodeId>) -> Result<Self> {
         log::debug!("rpc.client_timeout: {:?}", Runtime::instance().settings.rpc.client_timeout);
         let concurrency_limit = Runtime::instance().settings.rpc.client_concurrency_limit + 1;
         let endpoint = Channel::from_shared(format!("http://{}", server_addr))
@@ -41,8 +51,9 @@ impl NodeGrpcClient {
         let active_tasks = Arc::new(AtomicUsize::new(0));
         let channel_tasks = Arc::new(AtomicUsize::new(0));
         let grpc_client = Arc::new(RwLock::new(None));
+        let peer = Arc::new(RwLock::new(None));
         let (tx, rx) = channel();
-        let c = Self { grpc_client, active_tasks, channel_tasks, endpoint, tx };
+        let c = Self { grpc_client, node_id, peer, active_tasks, channel_tasks, endpoint, tx };
         c.start(rx);
         Ok(c)
     }
@@ -73,13 +84,77 @@ impl NodeGrpcClient {
         if let Some(c) = self.grpc_client.read().await.as_ref() {
             return Ok(c.clone());
         }
-        let c = Self::_connect(&self.endpoint).await?;
+        let mut c = Self::_connect(&self.endpoint).await?;
+        let peer = self.hello(&mut c).await?;
+        self.peer.write().await.replace(Arc::new(peer));
         self.grpc_client.write().await.replace(c.clone());
         Ok(c)
     }
 
+    ///Negotiates the protocol version with the peer, a peer that does not implement Hello is of
+    ///the legacy version
+    #[inline]
+    async fn hello(&self, c: &mut NodeServiceClientType) -> Result<PeerProtocol> {
+        let req = pb::Hello {
+            node_id: Runtime::instance().node.id(),
+            protocol_version: PROTOCOL_VERSION as u32,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        };
+        match c.hello(tonic::Request::new(req)).await {
+            Ok(reply) => {
+                let reply = reply.into_inner();
+                let version = reply.protocol_version as u16;
+                protocol::register_peer(reply.node_id, version);
+                Ok(PeerProtocol::new(Some(reply.node_id), version, reply.capabilities))
+            }
+            Err(status) if status.code() == tonic::Code::Unimplemented => {
+                if let Some(node_id) = self.node_id {
+                    protocol::register_peer(node_id, LEGACY_PROTOCOL_VERSION);
+                }
+                Ok(PeerProtocol::legacy(self.node_id))
+            }
+            Err(e) => Err(anyhow::Error::new(e).into()),
+        }
+    }
+
+    ///Protocol negotiated with the peer, negotiated again after a failed send since the peer may
+    ///have been restarted with another version
+    #[inline]
+    pub async fn peer_protocol(&self) -> Result<Arc<PeerProtocol>> {
+        if let Some(peer) = self.peer.read().await.as_ref() {
+            return Ok(peer.clone());
+        }
+        let mut c = self.connect().await?;
+        if let Some(peer) = self.peer.read().await.as_ref() {
+            return Ok(peer.clone());
+        }
+        let peer = Arc::new(self.hello(&mut c).await?);
+        self.peer.write().await.replace(peer.clone());
+        Ok(peer)
+    }
+
+    ///Forgets the negotiated protocol after a failed send, the peer may be down or restarted
+    #[inline]
+    async fn reset_peer(&self) {
+        if let Some(peer) = self.peer.write().await.take() {
+            if let Some(node_id) = peer.node_id {
+                protocol::unregister_peer(node_id);
+            }
+        }
+    }
+
     #[inline]
-    pub async fn batch_send_message(&self, typ: MessageType, msg: Message) -> Result<MessageReply> {
+    pub async fn batch_send_message(&self, typ: MessageType, mut msg: Message) -> Result<MessageReply> {
+        let peer = self.peer_protocol().await?;
+        if !peer.supports(msg.capability()) {
+            return Err(MqttError::from(format!(
+                "{} is not supported by the peer {:?} of protocol version {}",
+                msg.capability(),
+                self.endpoint.uri(),
+                peer.version
+            )));
+        }
+        let _span = msg.start_span("grpc.send");
         let (r_tx, r_rx) = tokio::sync::oneshot::channel::<Result<MessageReply>>();
         self.tx
             .send((typ, msg, r_tx))
@@ -101,20 +176,26 @@ impl NodeGrpcClient {
     #[inline]
     async fn inner_send_message(&self, typ: MessageType, msg: Message) -> Result<MessageReply> {
         let mut grpc_client = self.connect().await?;
+        let peer = self.peer_protocol().await?;
         self.active_tasks.fetch_add(1, Ordering::SeqCst);
-        let result = Self::_inner_send_message(&mut grpc_client, typ, msg).await;
+        let result = Self::_inner_send_message(&mut grpc_client, peer.version, typ, msg).await;
         self.active_tasks.fetch_sub(1, Ordering::SeqCst);
+        if result.is_err() {
+            self.reset_peer().await;
+        }
         result
     }
 
     #[inline]
     async fn _inner_send_message(
         c: &mut NodeServiceClientType,
+        peer_version: u16,
         typ: MessageType,
         msg: Message,
     ) -> Result<MessageReply> {
+        let data = codec::encode_for(&msg, peer_version)?;
         let response = c
-            .send_message(tonic::Request::new(pb::Message { typ, data: msg.encode()? }))
+            .send_message(tonic::Request::new(pb::Message { typ, data }))
             .await
             .map_err(anyhow::Error::new)?;
         log::trace!("response: {:?}", response);
@@ -128,18 +209,23 @@ impl NodeGrpcClient {
         msgs: Vec<(MessageType, Message)>,
     ) -> Result<Vec<MessageReply>> {
         let mut grpc_client = self.connect().await?;
+        let peer = self.peer_protocol().await?;
         self.active_tasks.fetch_add(1, Ordering::SeqCst);
-        let result = Self::_inner_batch_send_messages(&mut grpc_client, msgs).await;
+        let result = Self::_inner_batch_send_messages(&mut grpc_client, peer.version, msgs).await;
         self.active_tasks.fetch_sub(1, Ordering::SeqCst);
+        if result.is_err() {
+            self.reset_peer().await;
+        }
         result
     }
 
     #[inline]
     async fn _inner_batch_send_messages(
         c: &mut NodeServiceClientType,
+        peer_version: u16,
         msgs: Vec<(MessageType, Message)>,
     ) -> Result<Vec<MessageReply>> {
-        let data = bincode::serialize(&msgs).map_err(anyhow::Error::new)?;
+        let data = codec::encode_for(&msgs, peer_version)?;
         let response = c
             .batch_send_messages(tonic::Request::new(pb::BatchMessages { data }))
             .await
@@ -147,7 +233,7 @@ impl NodeGrpcClient {
         log::trace!("response: {:?}", response);
         let message_reply = response.into_inner();
 
-        Ok(bincode::deserialize::<Vec<MessageReply>>(&message_reply.data).map_err(anyhow::Error::new)?)
+        codec::decode::<Vec<MessageReply>>(&message_reply.data)
     }
 
     fn start(&self, mut rx: Receiver<(MessageType, Message, OneshotSender<Result<MessageReply>>)>) {
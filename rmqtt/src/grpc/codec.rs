@@ -0,0 +1,227 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{MqttError, Result};
+
+use super::protocol::{self, LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+const MAGIC: &[u8; 3] = b"RMQ";
+///Version of the envelope layout: MAGIC, version, codec, protocol version (u16, big endian), body
+const VERSION: u8 = 2;
+const HEADER_LEN: usize = MAGIC.len() + 4;
+
+static CURRENT: AtomicU8 = AtomicU8::new(Codec::Bincode as u8);
+
+thread_local! {
+    //set while encoding or decoding the layout of the nodes that do not negotiate
+    static LEGACY_LAYOUT: Cell<bool> = Cell::new(false);
+}
+
+///Encoding of the messages exchanged between the nodes, each message is wrapped in an envelope
+///that records the codec and the protocol version, so a node decodes the messages of the nodes
+///using another codec. The nodes that do not negotiate the protocol only decode bincode without
+///an envelope, in the layout of the messages before the negotiation, see `is_legacy_layout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Bincode = 1,
+    Msgpack = 2,
+}
+
+impl Default for Codec {
+    #[inline]
+    fn default() -> Self {
+        Codec::Bincode
+    }
+}
+
+impl Codec {
+    ///Codec used to encode the messages of this node
+    #[inline]
+    pub fn current() -> Codec {
+        Self::from_u8(CURRENT.load(Ordering::Relaxed)).unwrap_or_default()
+    }
+
+    #[inline]
+    pub fn set_current(codec: Codec) {
+        CURRENT.store(codec as u8, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn from_u8(v: u8) -> Option<Codec> {
+        match v {
+            1 => Some(Codec::Bincode),
+            2 => Some(Codec::Msgpack),
+            _ => None,
+        }
+    }
+}
+
+struct LegacyLayout(bool);
+
+impl LegacyLayout {
+    #[inline]
+    fn enter() -> Self {
+        Self(LEGACY_LAYOUT.with(|l| l.replace(true)))
+    }
+}
+
+impl Drop for LegacyLayout {
+    #[inline]
+    fn drop(&mut self) {
+        LEGACY_LAYOUT.with(|l| l.set(self.0));
+    }
+}
+
+///Leaves a field added since the negotiation of the protocol out of the layout of the nodes that do
+///not negotiate, used with `skip_serializing_if` together with `deserialize_since_legacy`
+#[inline]
+pub fn is_legacy_layout<T: ?Sized>(_: &T) -> bool {
+    LEGACY_LAYOUT.with(|l| l.get())
+}
+
+///Reads a field added since the negotiation of the protocol, the default in the layout of the nodes
+///that do not negotiate, where it is absent
+#[inline]
+pub fn deserialize_since_legacy<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    if LEGACY_LAYOUT.with(|l| l.get()) {
+        Ok(T::default())
+    } else {
+        T::deserialize(deserializer)
+    }
+}
+
+///Encoded for all the nodes of the cluster, such as the raft log entries or the data carried by a
+///message: in the legacy layout while a node does not negotiate, with msgpack while the nodes run
+///different protocol versions
+#[inline]
+pub fn encode<T: Serialize + ?Sized>(v: &T) -> Result<Vec<u8>> {
+    if protocol::has_legacy_peer() {
+        encode_for(v, LEGACY_PROTOCOL_VERSION)
+    } else if protocol::is_mixed() {
+        encode_with(v, Codec::Msgpack)
+    } else {
+        encode_with(v, Codec::current())
+    }
+}
+
+///Encoded for a peer of the protocol version, with msgpack if it runs another version
+#[inline]
+pub fn encode_for<T: Serialize + ?Sized>(v: &T, peer_version: u16) -> Result<Vec<u8>> {
+    if peer_version <= LEGACY_PROTOCOL_VERSION {
+        let _legacy = LegacyLayout::enter();
+        return Ok(bincode::serialize(v).map_err(anyhow::Error::new)?);
+    }
+    encode_with(v, if peer_version == PROTOCOL_VERSION { Codec::current() } else { Codec::Msgpack })
+}
+
+#[inline]
+fn encode_with<T: Serialize + ?Sized>(v: &T, codec: Codec) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(128);
+    data.extend_from_slice(MAGIC);
+    data.push(VERSION);
+    data.push(codec as u8);
+    data.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    match codec {
+        Codec::Bincode => bincode::serialize_into(&mut data, v).map_err(anyhow::Error::new)?,
+        Codec::Msgpack => rmp_serde::encode::write_named(&mut data, v).map_err(anyhow::Error::new)?,
+    }
+    Ok(data)
+}
+
+#[inline]
+pub fn decode<'a, T: Deserialize<'a>>(data: &'a [u8]) -> Result<T> {
+    decode_from(data).map(|(v, _)| v)
+}
+
+///Also returns the protocol version of the sender, so that the reply is encoded for it. Data
+///without an envelope is decoded as bincode in the legacy layout, the encoding of the nodes that do
+///not negotiate
+#[inline]
+pub fn decode_from<'a, T: Deserialize<'a>>(data: &'a [u8]) -> Result<(T, u16)> {
+    if data.len() < HEADER_LEN || !data.starts_with(MAGIC) {
+        let _legacy = LegacyLayout::enter();
+        let v = bincode::deserialize(data).map_err(anyhow::Error::new)?;
+        return Ok((v, LEGACY_PROTOCOL_VERSION));
+    }
+    let version = data[MAGIC.len()];
+    if version != VERSION {
+        return Err(MqttError::from(format!("unsupported message envelope version: {}", version)));
+    }
+    let codec = Codec::from_u8(data[MAGIC.len() + 1])
+        .ok_or_else(|| MqttError::from(format!("unknown message codec: {}", data[MAGIC.len() + 1])))?;
+    let protocol_version = u16::from_be_bytes([data[MAGIC.len() + 2], data[MAGIC.len() + 3]]);
+    let body = &data[HEADER_LEN..];
+    let res = match codec {
+        Codec::Bincode => bincode::deserialize(body).map_err(anyhow::Error::new),
+        Codec::Msgpack => rmp_serde::from_slice(body).map_err(anyhow::Error::new),
+    };
+    match res {
+        Ok(v) => Ok((v, protocol_version)),
+        Err(e) if protocol_version != PROTOCOL_VERSION => Err(MqttError::from(format!(
+            "decode error, the message is of protocol version {}, local: {}, codec: {:?}, {}",
+            protocol_version, PROTOCOL_VERSION, codec, e
+        ))),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Legacy {
+        a: u32,
+        b: String,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Current {
+        a: u32,
+        b: String,
+        #[serde(
+            default,
+            skip_serializing_if = "is_legacy_layout",
+            deserialize_with = "deserialize_since_legacy"
+        )]
+        c: Option<u64>,
+    }
+
+    #[test]
+    fn roundtrip() {
+        let v = Current { a: 1, b: "b".into(), c: Some(3) };
+        for codec in [Codec::Bincode, Codec::Msgpack] {
+            let data = encode_with(&v, codec).unwrap();
+            assert!(data.starts_with(MAGIC));
+            assert_eq!(decode_from::<Current>(&data).unwrap(), (v.clone(), PROTOCOL_VERSION));
+        }
+        //a peer of another version gets msgpack
+        let data = encode_for(&v, PROTOCOL_VERSION + 1).unwrap();
+        assert_eq!(data[MAGIC.len() + 1], Codec::Msgpack as u8);
+        assert_eq!(decode::<Current>(&data).unwrap(), v);
+    }
+
+    #[test]
+    fn legacy_layout() {
+        //a node that does not negotiate decodes plain bincode without the added fields
+        let v = Current { a: 1, b: "b".into(), c: Some(3) };
+        let data = encode_for(&v, LEGACY_PROTOCOL_VERSION).unwrap();
+        assert!(!data.starts_with(MAGIC));
+        let legacy: Legacy = bincode::deserialize(&data).unwrap();
+        assert_eq!(legacy, Legacy { a: 1, b: "b".into() });
+
+        //and its messages are decoded with the added fields defaulted
+        let data = bincode::serialize(&legacy).unwrap();
+        let (current, version) = decode_from::<Current>(&data).unwrap();
+        assert_eq!(current, Current { a: 1, b: "b".into(), c: None });
+        assert_eq!(version, LEGACY_PROTOCOL_VERSION);
+        assert!(!is_legacy_layout(&()));
+    }
+}
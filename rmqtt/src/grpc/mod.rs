@@ -12,9 +12,13 @@ use crate::broker::types::{
     TopicFilter, TopicName,
 };
 use crate::broker::{ClearSubscriptions, SubRelations, SubRelationsMap};
+use crate::telemetry::Span;
 use crate::{Addr, ClientId, Result};
 
 pub mod client;
+pub mod codec;
+pub mod protocol;
+pub mod registry;
 pub mod server;
 
 #[allow(dead_code)]
@@ -45,11 +49,45 @@ pub enum Message {
 impl Message {
     #[inline]
     pub fn encode(&self) -> Result<Vec<u8>> {
-        Ok(bincode::serialize(self).map_err(anyhow::Error::new)?)
+        codec::encode(self)
     }
     #[inline]
     pub fn decode(data: &[u8]) -> Result<Message> {
-        Ok(bincode::deserialize::<Message>(data).map_err(anyhow::Error::new)?)
+        codec::decode(data)
+    }
+
+    ///Name of the message in the capabilities negotiated with the peers
+    #[inline]
+    pub fn capability(&self) -> &'static str {
+        match self {
+            Message::Forwards(..) => "Forwards",
+            Message::ForwardsTo(..) => "ForwardsTo",
+            Message::Kick(..) => "Kick",
+            Message::GetRetains(..) => "GetRetains",
+            Message::SubscriptionsSearch(..) => "SubscriptionsSearch",
+            Message::SubscriptionsGet(..) => "SubscriptionsGet",
+            Message::RoutesGet(..) => "RoutesGet",
+            Message::RoutesGetBy(..) => "RoutesGetBy",
+            Message::NumberOfClients => "NumberOfClients",
+            Message::NumberOfSessions => "NumberOfSessions",
+            Message::Online(..) => "Online",
+            Message::SessionStatus(..) => "SessionStatus",
+            Message::Data(..) => "Data",
+        }
+    }
+
+    ///Start a span on a traced forwarding message, the span becomes the parent of the next hop
+    #[inline]
+    pub(crate) fn start_span(&mut self, name: &'static str) -> Option<Span> {
+        let trace_context = match self {
+            Message::Forwards(_, p) | Message::ForwardsTo(_, p, _) => &mut p.trace_context,
+            _ => return None,
+        };
+        let span = Span::start(name, Some(trace_context.as_ref()?));
+        if let Some(cx) = span.context() {
+            *trace_context = Some(cx);
+        }
+        Some(span)
     }
 }
 
@@ -74,11 +112,11 @@ pub enum MessageReply {
 impl MessageReply {
     #[inline]
     pub fn encode(&self) -> Result<Vec<u8>> {
-        Ok(bincode::serialize(self).map_err(anyhow::Error::new)?)
+        codec::encode(self)
     }
     #[inline]
     pub fn decode(data: &[u8]) -> Result<MessageReply> {
-        Ok(bincode::deserialize::<MessageReply>(data).map_err(anyhow::Error::new)?)
+        codec::decode(data)
     }
 }
 
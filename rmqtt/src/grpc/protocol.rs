@@ -0,0 +1,174 @@
+//! Version of the messages exchanged between the nodes, negotiated when a node connects to a peer.
+//!
+//! The nodes of a cluster run different versions during a rolling upgrade. While a peer runs
+//! another protocol version, the messages are encoded with msgpack, whose named fields let a node
+//! decode the messages of an older or newer layout, and a message the peer does not handle is not
+//! sent to it.
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use once_cell::sync::OnceCell;
+
+use crate::NodeId;
+
+type DashMap<K, V> = dashmap::DashMap<K, V, ahash::RandomState>;
+
+///Incremented when a message exchanged between the nodes changes:
+///2 - negotiation of the protocol, the message envelope
+///3 - Publish.forward_id
+///4 - Publish.trace_context
+///5 - the process and task counters of Stats
+///6 - PluginInfo.dependencies and hooks_before
+///7 - PluginInfo.metrics
+///8 - the disconnected kind of ClientSearchResult and ConnectionEvent
+///9 - Stats.tagged_connections, ClientSearchResult.tags
+///10 - SessionOfflineInfo.payload_filters
+///11 - TopicMetricsInfo.topics
+///12 - the incarnation of the members gossiped by rmqtt-cluster-raft
+///13 - EvacuateParams.timeout, EvacuationStatus.kicked and discarded_sessions
+///14 - Stats.tenants
+pub const PROTOCOL_VERSION: u16 = 14;
+
+///Version of the nodes that do not negotiate
+pub const LEGACY_PROTOCOL_VERSION: u16 = 1;
+
+///Messages handled by this node, see Message::capability()
+pub const CAPABILITIES: &[&str] = &[
+    "Forwards",
+    "ForwardsTo",
+    "Kick",
+    "GetRetains",
+    "SubscriptionsSearch",
+    "SubscriptionsGet",
+    "RoutesGet",
+    "RoutesGetBy",
+    "NumberOfClients",
+    "NumberOfSessions",
+    "Online",
+    "SessionStatus",
+    "Data",
+];
+
+///Messages handled by the nodes that do not negotiate, the messages added since are not sent to them
+const LEGACY_CAPABILITIES: &[&str] = &[
+    "Forwards",
+    "ForwardsTo",
+    "Kick",
+    "GetRetains",
+    "SubscriptionsSearch",
+    "SubscriptionsGet",
+    "RoutesGet",
+    "RoutesGetBy",
+    "NumberOfClients",
+    "NumberOfSessions",
+    "Online",
+    "SessionStatus",
+    "Data",
+];
+
+#[derive(Debug, Clone)]
+pub struct PeerProtocol {
+    ///Node id of the peer, unknown for a peer that does not negotiate when not given to the client
+    pub node_id: Option<NodeId>,
+    pub version: u16,
+    pub capabilities: HashSet<String>,
+}
+
+impl PeerProtocol {
+    #[inline]
+    pub fn new(node_id: Option<NodeId>, version: u16, capabilities: Vec<String>) -> Self {
+        Self { node_id, version, capabilities: capabilities.into_iter().collect() }
+    }
+
+    #[inline]
+    pub fn legacy(node_id: Option<NodeId>) -> Self {
+        Self::new(
+            node_id,
+            LEGACY_PROTOCOL_VERSION,
+            LEGACY_CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        )
+    }
+
+    #[inline]
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.contains(capability)
+    }
+}
+
+struct Peers {
+    versions: DashMap<NodeId, u16>,
+    mixed: AtomicBool,
+}
+
+#[inline]
+fn peers() -> &'static Peers {
+    static INSTANCE: OnceCell<Peers> = OnceCell::new();
+    INSTANCE.get_or_init(|| Peers { versions: DashMap::default(), mixed: AtomicBool::new(false) })
+}
+
+///Records the protocol version negotiated with a peer, replacing the version it ran before
+#[inline]
+pub fn register_peer(node_id: NodeId, version: u16) {
+    if peers().versions.insert(node_id, version) != Some(version) {
+        log::info!("node {} runs protocol version {}, local: {}", node_id, version, PROTOCOL_VERSION);
+        update_mixed();
+    }
+}
+
+///Forgets a peer that can no longer be reached, its version is negotiated again when it is
+#[inline]
+pub fn unregister_peer(node_id: NodeId) {
+    if peers().versions.remove(&node_id).is_some() {
+        update_mixed();
+    }
+}
+
+#[inline]
+fn update_mixed() {
+    let peers = peers();
+    let mixed = peers.versions.iter().any(|entry| *entry.value() != PROTOCOL_VERSION);
+    if peers.mixed.swap(mixed, Ordering::SeqCst) != mixed {
+        log::warn!("the nodes run different protocol versions: {}", mixed);
+    }
+}
+
+///A known peer runs another protocol version
+#[inline]
+pub fn is_mixed() -> bool {
+    peers().mixed.load(Ordering::SeqCst)
+}
+
+///A known peer does not negotiate, it does not decode the envelope carrying the protocol version
+#[inline]
+pub fn has_legacy_peer() -> bool {
+    is_mixed() && peers().versions.iter().any(|entry| *entry.value() <= LEGACY_PROTOCOL_VERSION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate() {
+        register_peer(1001, PROTOCOL_VERSION);
+        register_peer(1002, PROTOCOL_VERSION);
+        assert!(!is_mixed());
+
+        //a node restarted with the legacy version, then upgraded again
+        register_peer(1002, LEGACY_PROTOCOL_VERSION);
+        assert!(is_mixed() && has_legacy_peer());
+        register_peer(1002, PROTOCOL_VERSION);
+        assert!(!is_mixed() && !has_legacy_peer());
+
+        //a node of another version that leaves
+        register_peer(1003, PROTOCOL_VERSION + 1);
+        assert!(is_mixed() && !has_legacy_peer());
+        unregister_peer(1003);
+        assert!(!is_mixed());
+
+        unregister_peer(1001);
+        unregister_peer(1002);
+        assert!(PeerProtocol::legacy(None).supports("Forwards"));
+    }
+}
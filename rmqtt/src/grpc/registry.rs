@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+
+use once_cell::sync::OnceCell;
+
+use crate::{MqttError, Result, RwLock};
+
+use super::MessageType;
+
+///The message types of the plugins by name, registered at startup so that two plugins configured
+///with the same number are detected instead of handling each other's messages
+pub struct MessageTypes {
+    types: RwLock<BTreeMap<MessageType, (String, usize)>>,
+}
+
+impl MessageTypes {
+    #[inline]
+    pub fn instance() -> &'static MessageTypes {
+        static INSTANCE: OnceCell<MessageTypes> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { types: RwLock::new(BTreeMap::default()) })
+    }
+
+    ///Registers the message type of name, replacing the one it registered before, fails if the
+    ///message type is registered by another name. Registrations of the same name and type are
+    ///counted, so that the old instance of a reloaded plugin does not unregister the new one
+    #[inline]
+    pub fn register(&self, name: &str, typ: MessageType) -> Result<()> {
+        let mut types = self.types.write();
+        if let Some((other, count)) = types.get_mut(&typ) {
+            if other != name {
+                return Err(MqttError::from(format!(
+                    "grpc message type {} of {} is already registered by {}",
+                    typ, name, other
+                )));
+            }
+            *count += 1;
+            return Ok(());
+        }
+        types.retain(|_, (n, _)| n != name);
+        types.insert(typ, (name.into(), 1));
+        log::info!("grpc message type {} is registered by {}", typ, name);
+        Ok(())
+    }
+
+    ///Releases a registration of the message type of name, the message type is removed once
+    ///all of them are released
+    #[inline]
+    pub fn unregister(&self, name: &str, typ: MessageType) {
+        let mut types = self.types.write();
+        if let Some((n, count)) = types.get_mut(&typ) {
+            if n != name {
+                return;
+            }
+            *count -= 1;
+            if *count == 0 {
+                types.remove(&typ);
+                log::info!("grpc message type {} is unregistered by {}", typ, name);
+            }
+        }
+    }
+
+    ///The name that registered the message type
+    #[inline]
+    pub fn get(&self, typ: MessageType) -> Option<String> {
+        self.types.read().get(&typ).map(|(name, _)| name.clone())
+    }
+
+    ///The registered message types with their names, in ascending order
+    #[inline]
+    pub fn list(&self) -> Vec<(MessageType, String)> {
+        self.types.read().iter().map(|(typ, (name, _))| (*typ, name.clone())).collect()
+    }
+}
@@ -9,7 +9,9 @@ use super::pb::{
     self,
     node_service_server::{NodeService, NodeServiceServer},
 };
-use super::{Message, MessageReply, MessageType};
+use super::protocol::{self, CAPABILITIES, PROTOCOL_VERSION};
+use super::registry::MessageTypes;
+use super::{codec, Message, MessageReply, MessageType};
 
 pub struct Server {}
 
@@ -45,6 +47,14 @@ impl Server {
     // }
 }
 
+///Messages of a type that no plugin registered, or whose plugin is stopped, are not handled
+#[inline]
+fn check_registered(typ: MessageType) {
+    if MessageTypes::instance().get(typ).is_none() {
+        log::debug!("grpc message type {} is not registered by any plugin", typ);
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct NodeGrpcService {}
 
@@ -57,11 +67,13 @@ impl NodeService for NodeGrpcService {
     ) -> Result<tonic::Response<pb::MessageReply>, tonic::Status> {
         log::trace!("request: {:?}", request);
         let req = request.into_inner();
-        let msg = Message::decode(&req.data)?;
+        let (mut msg, peer_version) = codec::decode_from::<Message>(&req.data)?;
+        let _span = msg.start_span("grpc.received");
+        check_registered(req.typ);
         ACTIVE_REQUEST_COUNT.fetch_add(1, Ordering::SeqCst);
         let reply = Runtime::instance().extends.hook_mgr().await.grpc_message_received(req.typ, msg).await;
         ACTIVE_REQUEST_COUNT.fetch_sub(1, Ordering::SeqCst);
-        Ok(Response::new(pb::MessageReply { data: reply?.encode()? }))
+        Ok(Response::new(pb::MessageReply { data: codec::encode_for(&reply?, peer_version)? }))
     }
 
     #[inline]
@@ -71,14 +83,17 @@ impl NodeService for NodeGrpcService {
     ) -> Result<tonic::Response<pb::BatchMessagesReply>, tonic::Status> {
         log::trace!("request: {:?}", request);
         let req = request.into_inner();
-        let msgs = bincode::deserialize::<Vec<(MessageType, Message)>>(&req.data)
+        let (mut msgs, peer_version) = codec::decode_from::<Vec<(MessageType, Message)>>(&req.data)
             .map_err(|e| tonic::Status::unavailable(e.to_string()))?;
+        let _spans =
+            msgs.iter_mut().filter_map(|(_, msg)| msg.start_span("grpc.received")).collect::<Vec<_>>();
         ACTIVE_REQUEST_COUNT.fetch_add(1, Ordering::SeqCst);
 
         let hook_mgr = Runtime::instance().extends.hook_mgr().await;
 
         let mut futs = Vec::new();
         for (typ, msg) in msgs {
+            check_registered(typ);
             futs.push(hook_mgr.grpc_message_received(typ, msg));
         }
         let reply = futures::future::join_all(futs)
@@ -91,9 +106,25 @@ impl NodeService for NodeGrpcService {
             .collect::<Vec<MessageReply>>();
         ACTIVE_REQUEST_COUNT.fetch_sub(1, Ordering::SeqCst);
 
-        let reply = bincode::serialize(&reply).map_err(|e| tonic::Status::unavailable(e.to_string()))?;
+        let reply =
+            codec::encode_for(&reply, peer_version).map_err(|e| tonic::Status::unavailable(e.to_string()))?;
         Ok(Response::new(pb::BatchMessagesReply { data: reply }))
     }
+
+    #[inline]
+    async fn hello(
+        &self,
+        request: tonic::Request<pb::Hello>,
+    ) -> Result<tonic::Response<pb::Hello>, tonic::Status> {
+        let req = request.into_inner();
+        log::debug!("hello, peer: {}, protocol_version: {}", req.node_id, req.protocol_version);
+        protocol::register_peer(req.node_id, req.protocol_version as u16);
+        Ok(Response::new(pb::Hello {
+            node_id: Runtime::instance().node.id(),
+            protocol_version: PROTOCOL_VERSION as u32,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }))
+    }
 }
 
 lazy_static::lazy_static! {
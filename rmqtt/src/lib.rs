@@ -51,3 +51,4 @@ pub mod node;
 pub mod plugin;
 pub mod runtime;
 pub mod settings;
+pub mod telemetry;
@@ -1,14 +1,22 @@
+use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io::{self, Write};
+use std::panic::RefUnwindSafe;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+use parking_lot::RwLock;
 
 pub use slog::Logger;
-use slog::{o, Drain, Record};
+use slog::{o, Drain, Key, OwnedKVList, Record, KV};
 use slog_scope::GlobalLoggerGuard;
-use slog_term::{CountingWriter, RecordDecorator, ThreadSafeTimestampFn};
+use slog_term::{CountingWriter, FullFormat, PlainSyncDecorator, RecordDecorator, ThreadSafeTimestampFn};
 
-use crate::{MqttError, Result, Runtime};
+use crate::{HashMap, MqttError, NodeId, Result, Runtime, TimestampMillis};
 
-use super::settings::log::{Level, To};
+use super::settings::log::{Log, To};
 
 /// Initializes a logger using `slog` and `slog_scope`.
 ///
@@ -39,74 +47,338 @@ fn slog_log_to_level(level: slog::Level) -> log::Level {
 /// Creates a new `slog::Logger` with two `Drain`s: one for printing to the console and another for
 /// printing to a file.
 ///
-/// This function takes three arguments: `filename`, which specifies the name of the file to print
-/// to; `to`, which specifies where to print the logs (either the console or a file); and `level`,
-/// which specifies the minimum log level to print. The function sets the format for the logs and
-/// creates the two `Drain`s using the provided parameters. It then combines the two `Drain`s using a
-/// `Tee` and returns the resulting `Logger`.
-pub fn config_logger(filename: String, to: To, level: Level) -> slog::Logger {
-    let custom_timestamp =
-        |io: &mut dyn io::Write| write!(io, "{}", chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"));
-
-    let print_msg_header = |fn_timestamp: &dyn ThreadSafeTimestampFn<Output = io::Result<()>>,
-                            mut rd: &mut dyn RecordDecorator,
-                            record: &Record,
-                            _use_file_location: bool|
-     -> io::Result<bool> {
-        rd.start_timestamp()?;
-        fn_timestamp(&mut rd)?;
-
-        rd.start_whitespace()?;
-        write!(rd, " ")?;
-
-        rd.start_level()?;
-        write!(rd, "{}", record.level().as_short_str())?;
-
-        rd.start_location()?;
-        if record.function().is_empty() {
-            write!(rd, " {}.{} | ", record.module(), record.line())?;
-        } else {
-            write!(rd, " {}::{}.{} | ", record.module(), record.function(), record.line())?;
-        }
+/// The function takes the log settings and the id of this node. `to` specifies where to print the
+/// logs, `level` the minimum log level to print, and `console_format` / `file_format` whether each
+/// target prints free-form text or one JSON record per line. The two `Drain`s are combined using a
+/// `Duplicate`, filtered by the runtime adjustable `log_levels()` and the resulting `Logger` is returned.
+pub fn config_logger(cfg: &Log, node_id: NodeId) -> slog::Logger {
+    log_levels().set_default(cfg.level.inner());
 
-        rd.start_msg()?;
-        let mut count_rd = CountingWriter::new(&mut rd);
-        write!(count_rd, "{}", record.msg())?;
-        Ok(count_rd.count() != 0)
+    //Console
+    let stdout_drain: BoxDrain = if cfg.console_format.json() {
+        Box::new(JsonDrain::new(io::stdout(), node_id).fuse())
+    } else {
+        Box::new(text_drain(io::stdout()))
     };
 
-    //Console
-    let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
-    let stdout_drain = slog_term::FullFormat::new(plain)
-        .use_custom_timestamp(custom_timestamp)
-        .use_custom_header_print(print_msg_header)
-        .build()
-        .fuse();
+    //File
+    let file = open_file(&cfg.filename()).unwrap();
+    let file_drain = if cfg.file_format.json() {
+        async_drain(JsonDrain::new(file, node_id).fuse())
+    } else {
+        async_drain(text_drain(file))
+    };
+    let file_drain: BoxDrain = Box::new(file_drain);
+
+    match cfg.to {
+        To::Console => slog::Logger::root(RuntimeLevelFilter(stdout_drain).fuse(), o!()),
+        To::File => slog::Logger::root(RuntimeLevelFilter(file_drain).fuse(), o!()),
+        To::Both => slog::Logger::root(
+            RuntimeLevelFilter(slog::Duplicate::new(stdout_drain, file_drain).fuse()).fuse(),
+            o!(),
+        ),
+        To::Off => slog::Logger::root(slog::Discard, o!()),
+    }
+}
 
-    let stdout_drain = stdout_drain.filter_level(level.inner()).fuse();
+///Log levels that can be changed at runtime, per module path
+pub struct LogLevels {
+    default: RwLock<slog::Level>,
+    modules: RwLock<HashMap<String, ModuleLevel>>,
+}
 
-    //File
-    let decorator = slog_term::PlainSyncDecorator::new(open_file(&filename).unwrap());
-    let file_drain = slog_term::FullFormat::new(decorator)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModuleLevel {
+    pub module: String,
+    pub level: String,
+    ///The level reverts when it expires, 0 means never
+    pub expire_at: TimestampMillis,
+}
+
+impl ModuleLevel {
+    #[inline]
+    fn is_expired(&self, now: TimestampMillis) -> bool {
+        self.expire_at > 0 && now >= self.expire_at
+    }
+
+    #[inline]
+    fn matches(&self, module: &str) -> bool {
+        self.module.is_empty()
+            || (module.starts_with(self.module.as_str())
+                && (module.len() == self.module.len() || module[self.module.len()..].starts_with("::")))
+    }
+}
+
+#[inline]
+pub fn log_levels() -> &'static LogLevels {
+    static INSTANCE: OnceCell<LogLevels> = OnceCell::new();
+    INSTANCE.get_or_init(|| LogLevels {
+        default: RwLock::new(slog::Level::Info),
+        modules: RwLock::new(HashMap::default()),
+    })
+}
+
+impl LogLevels {
+    ///Set the level of the configuration, it applies to the modules without a level of their own
+    #[inline]
+    pub fn set_default(&self, level: slog::Level) {
+        *self.default.write() = level;
+        self.update_max_level();
+    }
+
+    ///The level of the configuration in effect, it changes with a reload of the configuration
+    #[inline]
+    pub fn default_level(&self) -> slog::Level {
+        *self.default.read()
+    }
+
+    ///Set the level of a module and its submodules, reverted after `duration` if specified
+    #[inline]
+    pub fn set(&self, module: &str, level: &str, duration: Option<Duration>) -> Result<ModuleLevel> {
+        let level = slog::Level::from_str(level)
+            .map_err(|_| MqttError::from(format!("invalid log level: {}", level)))?;
+        let expire_at =
+            duration.map(|d| chrono::Local::now().timestamp_millis() + d.as_millis() as TimestampMillis);
+        let item = ModuleLevel {
+            module: module.into(),
+            level: level.as_str().into(),
+            expire_at: expire_at.unwrap_or_default(),
+        };
+        self.modules.write().insert(module.into(), item.clone());
+        self.update_max_level();
+        if let Some(d) = duration {
+            tokio::spawn(async move {
+                tokio::time::sleep(d).await;
+                log_levels().remove_expireds();
+            });
+        }
+        Ok(item)
+    }
+
+    ///Revert a module to the default level
+    #[inline]
+    pub fn reset(&self, module: &str) -> bool {
+        let removed = self.modules.write().remove(module).is_some();
+        self.update_max_level();
+        removed
+    }
+
+    #[inline]
+    pub fn list(&self) -> Vec<ModuleLevel> {
+        let now = chrono::Local::now().timestamp_millis();
+        self.modules.read().values().filter(|m| !m.is_expired(now)).cloned().collect()
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "default": self.default.read().as_str(),
+            "modules": self.list(),
+        })
+    }
+
+    #[inline]
+    fn remove_expireds(&self) {
+        let now = chrono::Local::now().timestamp_millis();
+        self.modules.write().retain(|_, m| !m.is_expired(now));
+        self.update_max_level();
+    }
+
+    ///The level of the longest module path that matches
+    #[inline]
+    fn level(&self, module: &str) -> slog::Level {
+        let modules = self.modules.read();
+        if !modules.is_empty() {
+            let now = chrono::Local::now().timestamp_millis();
+            if let Some(m) = modules
+                .values()
+                .filter(|m| !m.is_expired(now) && m.matches(module))
+                .max_by_key(|m| m.module.len())
+            {
+                if let Ok(level) = slog::Level::from_str(&m.level) {
+                    return level;
+                }
+            }
+        }
+        *self.default.read()
+    }
+
+    ///The `log` crate filters records before they reach slog, so its max level must allow the most verbose level
+    #[inline]
+    fn update_max_level(&self) {
+        let level = self
+            .modules
+            .read()
+            .values()
+            .filter_map(|m| slog::Level::from_str(&m.level).ok())
+            .fold(*self.default.read(), |max, l| if l.is_at_least(max) { max } else { l });
+        log::set_max_level(slog_log_to_level(level).to_level_filter());
+    }
+}
+
+///Filters records by the runtime adjustable `log_levels()`
+struct RuntimeLevelFilter<D>(D);
+
+impl<D: Drain> Drain for RuntimeLevelFilter<D> {
+    type Ok = Option<D::Ok>;
+    type Err = D::Err;
+
+    #[inline]
+    fn log(&self, record: &Record, values: &OwnedKVList) -> std::result::Result<Self::Ok, Self::Err> {
+        if record.level().is_at_least(log_levels().level(record.module())) {
+            self.0.log(record, values).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+type BoxDrain = Box<dyn Drain<Ok = (), Err = slog::Never> + Send + Sync + RefUnwindSafe>;
+
+fn text_drain<W: io::Write + Send + 'static>(io: W) -> slog::Fuse<FullFormat<PlainSyncDecorator<W>>> {
+    FullFormat::new(PlainSyncDecorator::new(io))
         .use_custom_timestamp(custom_timestamp)
         .use_custom_header_print(print_msg_header)
         .build()
-        .fuse();
+        .fuse()
+}
 
-    //@TODO config ...
-    let file_drain = slog_async::Async::new(file_drain)
+//@TODO config ...
+fn async_drain<D: Drain<Ok = (), Err = slog::Never> + Send + 'static>(
+    drain: D,
+) -> slog::Fuse<slog_async::Async> {
+    slog_async::Async::new(drain)
         .chan_size(100_000)
         .overflow_strategy(slog_async::OverflowStrategy::DropAndReport)
         .build()
-        .fuse();
+        .fuse()
+}
+
+fn custom_timestamp(io: &mut dyn io::Write) -> io::Result<()> {
+    write!(io, "{}", chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"))
+}
 
-    let file_drain = file_drain.filter_level(level.inner()).fuse();
+fn print_msg_header(
+    fn_timestamp: &dyn ThreadSafeTimestampFn<Output = io::Result<()>>,
+    mut rd: &mut dyn RecordDecorator,
+    record: &Record,
+    _use_file_location: bool,
+) -> io::Result<bool> {
+    rd.start_timestamp()?;
+    fn_timestamp(&mut rd)?;
 
-    match to {
-        To::Console => slog::Logger::root(stdout_drain, o!()),
-        To::File => slog::Logger::root(file_drain, o!()),
-        To::Both => slog::Logger::root(slog::Duplicate::new(stdout_drain, file_drain).fuse(), o!()),
-        To::Off => slog::Logger::root(slog::Discard, o!()),
+    rd.start_whitespace()?;
+    write!(rd, " ")?;
+
+    rd.start_level()?;
+    write!(rd, "{}", record.level().as_short_str())?;
+
+    rd.start_location()?;
+    if record.function().is_empty() {
+        write!(rd, " {}.{} | ", record.module(), record.line())?;
+    } else {
+        write!(rd, " {}::{}.{} | ", record.module(), record.function(), record.line())?;
+    }
+
+    rd.start_msg()?;
+    let mut count_rd = CountingWriter::new(&mut rd);
+    write!(count_rd, "{}", record.msg())?;
+    Ok(count_rd.count() != 0)
+}
+
+///Fields that are always present in a JSON record, null if the record does not carry them
+const STABLE_FIELDS: [&str; 4] = ["clientid", "listener", "topic", "reason"];
+
+///Interval of the flushes of the buffered JSON records
+const JSON_FLUSH_INTERVAL: Duration = Duration::from_millis(200);
+
+///Writes one JSON object per record into a buffer, flushed by a background thread every
+///JSON_FLUSH_INTERVAL and right away for the warnings and errors
+struct JsonDrain<W: io::Write> {
+    io: Arc<Mutex<io::BufWriter<W>>>,
+    node_id: NodeId,
+}
+
+impl<W: io::Write + Send + 'static> JsonDrain<W> {
+    fn new(io: W, node_id: NodeId) -> Self {
+        let io = Arc::new(Mutex::new(io::BufWriter::new(io)));
+        let flushed = Arc::downgrade(&io);
+        std::thread::Builder::new()
+            .name("log-flush".into())
+            .spawn(move || loop {
+                std::thread::sleep(JSON_FLUSH_INTERVAL);
+                let io = match flushed.upgrade() {
+                    Some(io) => io,
+                    None => break,
+                };
+                if let Ok(mut io) = io.lock() {
+                    let _ = io.flush();
+                }
+            })
+            .expect("failed to spawn the log flush thread");
+        Self { io, node_id }
+    }
+}
+
+impl<W: io::Write> Drain for JsonDrain<W> {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> io::Result<()> {
+        let mut kvs = JsonSerializer(serde_json::Map::new());
+        values.serialize(record, &mut kvs)?;
+        record.kv().serialize(record, &mut kvs)?;
+        let mut kvs = kvs.0;
+
+        let mut obj = serde_json::Map::new();
+        obj.insert("time".into(), chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z").to_string().into());
+        obj.insert("level".into(), record.level().as_str().into());
+        obj.insert("node".into(), self.node_id.into());
+        obj.insert("module".into(), record.module().into());
+        obj.insert("line".into(), record.line().into());
+        obj.insert("msg".into(), record.msg().to_string().into());
+        for field in STABLE_FIELDS {
+            obj.insert(field.into(), kvs.remove(field).unwrap_or(serde_json::Value::Null));
+        }
+        obj.extend(kvs);
+
+        let mut line = serde_json::to_vec(&obj)?;
+        line.push(b'\n');
+        let mut io = self.io.lock().map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        io.write_all(&line)?;
+        if record.level().is_at_least(slog::Level::Warning) {
+            io.flush()?;
+        }
+        Ok(())
+    }
+}
+
+struct JsonSerializer(serde_json::Map<String, serde_json::Value>);
+
+impl slog::Serializer for JsonSerializer {
+    fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments) -> slog::Result {
+        self.0.insert(key.to_string(), val.to_string().into());
+        Ok(())
+    }
+    fn emit_str(&mut self, key: Key, val: &str) -> slog::Result {
+        self.0.insert(key.to_string(), val.into());
+        Ok(())
+    }
+    fn emit_bool(&mut self, key: Key, val: bool) -> slog::Result {
+        self.0.insert(key.to_string(), val.into());
+        Ok(())
+    }
+    fn emit_u64(&mut self, key: Key, val: u64) -> slog::Result {
+        self.0.insert(key.to_string(), val.into());
+        Ok(())
+    }
+    fn emit_i64(&mut self, key: Key, val: i64) -> slog::Result {
+        self.0.insert(key.to_string(), val.into());
+        Ok(())
+    }
+    fn emit_none(&mut self, key: Key) -> slog::Result {
+        self.0.insert(key.to_string(), serde_json::Value::Null);
+        Ok(())
     }
 }
 
@@ -1,3 +1,5 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use systemstat::Platform;
 
 use crate::grpc::client::NodeGrpcClient;
@@ -11,11 +13,23 @@ mod version {
 
 pub struct Node {
     pub start_time: chrono::DateTime<chrono::Local>,
+    stopping: AtomicBool,
 }
 
 impl Node {
     pub(crate) fn new() -> Self {
-        Self { start_time: chrono::Local::now() }
+        Self { start_time: chrono::Local::now(), stopping: AtomicBool::new(false) }
+    }
+
+    ///The node is asked to stop, the connections closed from then on are closed by the server
+    #[inline]
+    pub fn set_stopping(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
     }
 
     #[inline]
@@ -33,6 +47,12 @@ impl Node {
         NodeGrpcClient::new(remote_addr).await
     }
 
+    ///Client of the cluster node `id`
+    #[inline]
+    pub async fn new_peer_grpc_client(&self, id: NodeId, remote_addr: &str) -> Result<NodeGrpcClient> {
+        NodeGrpcClient::with_node_id(remote_addr, Some(id)).await
+    }
+
     pub fn start_grpc_server(&self) {
         std::thread::spawn(move || {
             let rt = tokio::runtime::Builder::new_multi_thread()
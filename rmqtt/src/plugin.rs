@@ -1,10 +1,12 @@
 use core::pin::Pin;
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
 
 use dashmap::iter::Iter;
 use dashmap::mapref::one::{Ref, RefMut};
 
-use crate::{MqttError, Result};
+use crate::settings::schema::ConfigSchema;
+use crate::{MqttError, Result, Runtime};
 
 type DashMap<K, V> = dashmap::DashMap<K, V, ahash::RandomState>;
 pub type EntryRef<'a> = Ref<'a, String, Entry, ahash::RandomState>;
@@ -33,6 +35,12 @@ pub trait Plugin: Send + Sync {
         Err(MqttError::from("unimplemented!"))
     }
 
+    ///Schema of the config, None if the plugin does not declare one
+    #[inline]
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        None
+    }
+
     #[inline]
     async fn start(&mut self) -> Result<()> {
         Ok(())
@@ -58,10 +66,111 @@ pub trait Plugin: Send + Sync {
         serde_json::Value::Null
     }
 
+    ///Counters and gauges of the plugin, exported by the Prometheus exporter of the core
+    ///as "<plugin>_<name>", use attrs() for the values that are not metrics
+    #[inline]
+    async fn metrics(&self) -> Vec<Metric> {
+        Vec::new()
+    }
+
     #[inline]
     async fn send(&self, _msg: serde_json::Value) -> Result<serde_json::Value> {
         Ok(serde_json::Value::Null)
     }
+
+    ///Called when the node is evacuated, after its clients have been redirected, to hand off the
+    ///roles of the node in the cluster to the peers
+    #[inline]
+    async fn evacuate(&self) -> Result<()> {
+        Ok(())
+    }
+
+    ///Whether a running instance can be replaced by a new one without restarting the broker
+    #[inline]
+    fn reloadable(&self) -> bool {
+        true
+    }
+
+    ///Plugins that must be started before this one, it is refused to start without them
+    #[inline]
+    fn dependencies(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    ///Plugins whose hook handlers must be executed after the handlers of this one, checked
+    ///against the hook priorities when the plugins are started
+    #[inline]
+    fn hooks_before(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricType {
+    ///Only increases, until the plugin is restarted
+    Counter,
+    ///Goes up and down
+    Gauge,
+}
+
+impl MetricType {
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+        }
+    }
+}
+
+///A metric of a plugin
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Metric {
+    ///Such as "requests_total", letters, digits and underscores
+    pub name: String,
+    #[serde(rename = "type")]
+    pub typ: MetricType,
+    pub value: f64,
+    pub labels: Vec<(String, String)>,
+    pub descr: String,
+}
+
+impl Metric {
+    #[inline]
+    pub fn counter<N: Into<String>>(name: N, value: f64) -> Self {
+        Self { name: name.into(), typ: MetricType::Counter, value, labels: Vec::new(), descr: String::new() }
+    }
+
+    #[inline]
+    pub fn gauge<N: Into<String>>(name: N, value: f64) -> Self {
+        Self { name: name.into(), typ: MetricType::Gauge, value, labels: Vec::new(), descr: String::new() }
+    }
+
+    #[inline]
+    pub fn label<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.labels.push((key.into(), value.into()));
+        self
+    }
+
+    #[inline]
+    pub fn descr<D: Into<String>>(mut self, descr: D) -> Self {
+        self.descr = descr.into();
+        self
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        let labels =
+            self.labels.iter().map(|(k, v)| (k.clone(), json!(v))).collect::<serde_json::Map<_, _>>();
+        json!({
+            "name": self.name,
+            "type": self.typ.as_str(),
+            "value": self.value,
+            "labels": labels,
+            "descr": self.descr,
+        })
+    }
 }
 
 type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
@@ -80,8 +189,11 @@ pub struct Entry {
     active: bool,
     //will reject start, stop, and load config operations
     immutable: bool,
+    //to be started by Manager::startup
+    default_startup: bool,
     plugin: Option<DynPlugin>,
-    plugin_f: Option<DynPluginFn>,
+    //kept to build new instances on reload
+    plugin_f: DynPluginFn,
 }
 
 impl Entry {
@@ -111,8 +223,8 @@ impl Entry {
 
     #[inline]
     async fn plugin_mut(&mut self) -> Result<&mut dyn Plugin> {
-        if let Some(plugin_f) = self.plugin_f.take() {
-            self.plugin.replace(plugin_f().await?);
+        if self.plugin.is_none() {
+            self.plugin.replace((self.plugin_f)().await?);
         }
 
         if let Some(plugin) = self.plugin.as_mut() {
@@ -132,7 +244,10 @@ impl Entry {
                 "inited": self.inited,
                 "active": self.active,
                 "immutable": self.immutable,
+                "dependencies": plugin.dependencies(),
+                "hooks_before": plugin.hooks_before(),
                 "attrs": plugin.attrs().await,
+                "metrics": plugin.metrics().await.iter().map(|m| m.to_json()).collect::<Vec<_>>(),
             }))
         } else {
             Ok(json!({
@@ -155,7 +270,10 @@ impl Entry {
                 inited: self.inited,
                 active: self.active,
                 immutable: self.immutable,
+                dependencies: plugin.dependencies(),
+                hooks_before: plugin.hooks_before(),
                 attrs,
+                metrics: plugin.metrics().await,
             })
         } else {
             Ok(PluginInfo {
@@ -177,7 +295,25 @@ pub struct PluginInfo {
     pub inited: bool,
     pub active: bool,
     pub immutable: bool,
+    #[serde(
+        default,
+        skip_serializing_if = "crate::grpc::codec::is_legacy_layout",
+        deserialize_with = "crate::grpc::codec::deserialize_since_legacy"
+    )]
+    pub dependencies: Vec<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "crate::grpc::codec::is_legacy_layout",
+        deserialize_with = "crate::grpc::codec::deserialize_since_legacy"
+    )]
+    pub hooks_before: Vec<String>,
     pub attrs: Vec<u8>, //json data
+    #[serde(
+        default,
+        skip_serializing_if = "crate::grpc::codec::is_legacy_layout",
+        deserialize_with = "crate::grpc::codec::deserialize_since_legacy"
+    )]
+    pub metrics: Vec<Metric>,
 }
 
 impl PluginInfo {
@@ -195,7 +331,10 @@ impl PluginInfo {
             "inited": self.inited,
             "active": self.active,
             "immutable": self.immutable,
+            "dependencies": self.dependencies,
+            "hooks_before": self.hooks_before,
             "attrs": attrs,
+            "metrics": self.metrics.iter().map(|m| m.to_json()).collect::<Vec<_>>(),
         }))
     }
 }
@@ -209,7 +348,7 @@ impl Manager {
         Self { plugins: DashMap::default() }
     }
 
-    ///Register a Plugin
+    ///Register a Plugin, a default startup plugin is started by startup()
     pub async fn register<N: Into<String>, F: PluginFn>(
         &self,
         name: N,
@@ -225,21 +364,52 @@ impl Manager {
             }
         }
 
-        let (plugin, plugin_f) = if default_startup {
-            let mut plugin = plugin_f().await?;
-            plugin.init().await?;
-            plugin.start().await?;
-            (Some(plugin), None)
-        } else {
-            let boxed_f: Box<dyn PluginFn> = Box::new(plugin_f);
-            (None, Some(boxed_f))
-        };
-
-        let entry = Entry { inited: default_startup, active: default_startup, immutable, plugin, plugin_f };
+        let plugin_f: DynPluginFn = Box::new(plugin_f);
+        let entry =
+            Entry { inited: false, active: false, immutable, default_startup, plugin: None, plugin_f };
         self.plugins.insert(name, entry);
         Ok(())
     }
 
+    ///Start the registered default startup plugins, each one after its dependencies.
+    ///
+    ///Fails without starting any of them if a dependency is neither registered for startup
+    ///nor already started, or if the dependencies form a cycle.
+    pub async fn startup(&self) -> Result<()> {
+        let names = self
+            .plugins
+            .iter()
+            .filter(|entry| entry.default_startup && !entry.active)
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        let mut deps = HashMap::new();
+        for name in &names {
+            if let Some(mut entry) = self.plugins.get_mut(name) {
+                deps.insert(name.clone(), entry.plugin_mut().await?.dependencies());
+            }
+        }
+        let active = self
+            .plugins
+            .iter()
+            .filter(|entry| entry.active)
+            .map(|entry| entry.key().clone())
+            .collect::<HashSet<_>>();
+
+        for name in resolve_order(&names, &deps, &active)? {
+            if let Some(mut entry) = self.plugins.get_mut(&name) {
+                entry.default_startup = false;
+                let plugin = entry.plugin_mut().await?;
+                plugin.init().await?;
+                plugin.start().await?;
+                entry.inited = true;
+                entry.active = true;
+                log::info!("{} the plug-in is started", name);
+            }
+        }
+        self.check_hooks_order().await;
+        Ok(())
+    }
+
     ///Return Config
     pub async fn get_config(&self, name: &str) -> Result<serde_json::Value> {
         if let Some(entry) = self.get(name) {
@@ -249,6 +419,18 @@ impl Manager {
         }
     }
 
+    ///Return Config Schema, null if the plugin does not declare one
+    pub async fn get_config_schema(&self, name: &str) -> Result<serde_json::Value> {
+        if let Some(entry) = self.get(name) {
+            match entry.plugin().await?.config_schema() {
+                Some(schema) => schema.to_json(),
+                None => Ok(serde_json::Value::Null),
+            }
+        } else {
+            Err(MqttError::from(format!("{} the plug-in does not exist", name)))
+        }
+    }
+
     ///Load Config
     pub async fn load_config(&self, name: &str) -> Result<()> {
         if let Some(mut entry) = self.get_mut(name)? {
@@ -263,9 +445,33 @@ impl Manager {
         }
     }
 
-    ///Start a Plugin
+    ///Reload the configs of all initialized and mutable plugins
+    pub async fn load_configs(&self) -> Vec<(String, Result<()>)> {
+        let names = self
+            .plugins
+            .iter()
+            .filter(|entry| entry.inited && !entry.immutable)
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        let mut results = Vec::new();
+        for name in names {
+            let res = self.load_config(&name).await;
+            results.push((name, res));
+        }
+        results
+    }
+
+    ///Start a Plugin, its dependencies must be started
     pub async fn start(&self, name: &str) -> Result<()> {
-        if let Some(mut entry) = self.get_mut(name)? {
+        let deps = match self.get_mut_inactive(name)? {
+            Some(mut entry) => entry.plugin_mut().await?.dependencies(),
+            None => return Err(MqttError::from(format!("{} the plug-in does not exist", name))),
+        };
+        if let Some(dep) = deps.iter().find(|dep| !self.is_active(dep)) {
+            return Err(MqttError::from(format!("{} depends on {}, which is not started", name, dep)));
+        }
+
+        if let Some(mut entry) = self.get_mut_inactive(name)? {
             if !entry.inited {
                 entry.plugin_mut().await?.init().await?;
                 entry.inited = true;
@@ -274,14 +480,18 @@ impl Manager {
                 entry.plugin_mut().await?.start().await?;
                 entry.active = true;
             }
-            Ok(())
         } else {
-            Err(MqttError::from(format!("{} the plug-in does not exist", name)))
+            return Err(MqttError::from(format!("{} the plug-in does not exist", name)));
         }
+        self.check_hooks_order().await;
+        Ok(())
     }
 
-    ///Stop a Plugin
+    ///Stop a Plugin, refused while a started plugin depends on it
     pub async fn stop(&self, name: &str) -> Result<bool> {
+        if let Some(dependent) = self.dependents(name).await.into_iter().next() {
+            return Err(MqttError::from(format!("{} is required by {}, which is started", name, dependent)));
+        }
         if let Some(mut entry) = self.get_mut(name)? {
             if entry.active {
                 let stopped = entry.plugin_mut().await?.stop().await?;
@@ -295,6 +505,91 @@ impl Manager {
         }
     }
 
+    ///Replace a running Plugin with a new instance, the new instance loads the current config.
+    ///The code of a plugin is linked into the broker, a new version of it takes a restart.
+    ///
+    ///The new instance is started before the old one is stopped, if it cannot be started alongside
+    ///the old one, the old one is stopped first, and is started again if the new one still fails.
+    ///The hooks are handed over once the instances are swapped, for each hook type the handlers of
+    ///the old instance are disabled and those of the new one enabled together, so that no event is
+    ///lost or handled twice.
+    pub async fn reload(&self, name: &str) -> Result<()> {
+        if let Some(mut entry) = self.get_mut(name)? {
+            if !entry.active {
+                return Err(MqttError::from(format!("{} the plug-in is not started", name)));
+            }
+            if !entry.plugin_mut().await?.reloadable() {
+                return Err(MqttError::from(format!("{} the plug-in cannot be reloaded", name)));
+            }
+
+            let plugin = (entry.plugin_f)().await?;
+            Runtime::instance().extends.hook_mgr().await.handover_begin(name).await;
+            let res = Self::swap(&mut entry, name, plugin).await;
+            Runtime::instance().extends.hook_mgr().await.handover_end(name).await;
+            res
+        } else {
+            Err(MqttError::from(format!("{} the plug-in does not exist", name)))
+        }
+    }
+
+    #[inline]
+    async fn swap(entry: &mut Entry, name: &str, mut plugin: DynPlugin) -> Result<()> {
+        plugin.init().await?;
+        if let Err(e) = plugin.start().await {
+            log::warn!("{} start the new instance alongside the old one failed, {:?}", name, e);
+            Self::stop_new(name, &mut plugin).await;
+            match entry.plugin_mut().await?.stop().await {
+                Ok(true) => {}
+                Ok(false) => return Err(e),
+                Err(stop_err) => {
+                    log::warn!("{} stop the old instance failed, {:?}", name, stop_err);
+                    Self::restart_old(entry, name).await;
+                    return Err(e);
+                }
+            }
+            if let Err(e) = plugin.start().await {
+                Self::stop_new(name, &mut plugin).await;
+                Self::restart_old(entry, name).await;
+                return Err(e);
+            }
+        } else {
+            match entry.plugin_mut().await?.stop().await {
+                Ok(true) => {}
+                Ok(false) => {
+                    Self::stop_new(name, &mut plugin).await;
+                    return Err(MqttError::from(format!("{} the plug-in cannot be stopped", name)));
+                }
+                Err(e) => {
+                    Self::stop_new(name, &mut plugin).await;
+                    Self::restart_old(entry, name).await;
+                    return Err(e);
+                }
+            }
+        }
+        entry.plugin.replace(plugin);
+        Ok(())
+    }
+
+    ///The errors of stopping a new instance that is discarded are only logged
+    #[inline]
+    async fn stop_new(name: &str, plugin: &mut DynPlugin) {
+        if let Err(e) = plugin.stop().await {
+            log::warn!("{} stop the new instance failed, {:?}", name, e);
+        }
+    }
+
+    ///Starts the old instance again after the swap failed, the entry stays active
+    #[inline]
+    async fn restart_old(entry: &mut Entry, name: &str) {
+        let res = match entry.plugin_mut().await {
+            Ok(plugin) => plugin.start().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            log::error!("{} restart the old instance failed, {:?}", name, e);
+        }
+    }
+
     ///Plugin is active
     pub fn is_active(&self, name: &str) -> bool {
         if let Some(entry) = self.plugins.get(name) {
@@ -322,6 +617,20 @@ impl Manager {
         }
     }
 
+    ///Get a mut Plugin, an immutable Plugin can be got until it is started, so that it can be started
+    ///at runtime once, such as the cluster plug-in converting a standalone broker into a cluster node
+    fn get_mut_inactive(&self, name: &str) -> Result<Option<EntryRefMut>> {
+        if let Some(entry) = self.plugins.get_mut(name) {
+            if entry.immutable && entry.active {
+                Err(MqttError::from("the plug-in is immutable"))
+            } else {
+                Ok(Some(entry))
+            }
+        } else {
+            Ok(None)
+        }
+    }
+
     ///Sending messages to plug-in
     pub async fn send(&self, name: &str, msg: serde_json::Value) -> Result<serde_json::Value> {
         if let Some(entry) = self.plugins.get(name) {
@@ -335,4 +644,141 @@ impl Manager {
     pub fn iter(&self) -> EntryIter {
         self.plugins.iter()
     }
+
+    ///Metrics of the started plugins
+    pub async fn metrics(&self) -> Vec<(String, Vec<Metric>)> {
+        let mut metrics = Vec::new();
+        for entry in self.plugins.iter().filter(|entry| entry.active) {
+            if let Ok(plugin) = entry.plugin().await {
+                let plugin_metrics = plugin.metrics().await;
+                if !plugin_metrics.is_empty() {
+                    metrics.push((entry.key().clone(), plugin_metrics));
+                }
+            }
+        }
+        metrics
+    }
+
+    ///Hands off the roles of the node, returns the errors of the plugins that failed
+    pub async fn evacuate(&self) -> Vec<(String, String)> {
+        let mut errors = Vec::new();
+        for entry in self.plugins.iter().filter(|entry| entry.active) {
+            if let Ok(plugin) = entry.plugin().await {
+                if let Err(e) = plugin.evacuate().await {
+                    errors.push((entry.key().clone(), e.to_string()));
+                }
+            }
+        }
+        errors
+    }
+
+    ///Started plugins that depend on the plugin
+    async fn dependents(&self, name: &str) -> Vec<String> {
+        let mut dependents = Vec::new();
+        for entry in self.plugins.iter().filter(|entry| entry.active) {
+            if let Ok(plugin) = entry.plugin().await {
+                if plugin.dependencies().iter().any(|dep| dep == name) {
+                    dependents.push(entry.key().clone());
+                }
+            }
+        }
+        dependents
+    }
+
+    ///Warns about the started plugins whose hook handlers are not executed in the declared
+    ///order, handlers of equal priority are executed in no particular order
+    pub async fn check_hooks_order(&self) -> Vec<String> {
+        let mut befores = Vec::new();
+        for entry in self.plugins.iter().filter(|entry| entry.active) {
+            if let Ok(plugin) = entry.plugin().await {
+                for after in plugin.hooks_before() {
+                    befores.push((entry.key().clone(), after));
+                }
+            }
+        }
+        if befores.is_empty() {
+            return Vec::new();
+        }
+
+        //lowest and highest priority of the handlers of each plugin per hook type
+        let mut ranges = HashMap::new();
+        for h in Runtime::instance().extends.hook_mgr().await.handlers().await {
+            //handlers of a plugin may be owned by "<plugin>/<sub>"
+            let owner = h.owner.split('/').next().unwrap_or_default().to_owned();
+            let range = ranges.entry((owner, h.typ)).or_insert((h.priority, h.priority));
+            range.0 = range.0.min(h.priority);
+            range.1 = range.1.max(h.priority);
+        }
+
+        let mut violations = Vec::new();
+        for (before, after) in befores {
+            for ((owner, typ), (min, _)) in ranges.iter().filter(|((owner, _), _)| *owner == before) {
+                if let Some((_, max)) = ranges.get(&(after.clone(), *typ)) {
+                    if min <= max {
+                        violations.push(format!(
+                            "{} hooks {:?} should be executed before {}, but priority {} <= {}",
+                            owner, typ, after, min, max
+                        ));
+                    }
+                }
+            }
+        }
+        for v in &violations {
+            log::warn!("{}", v);
+        }
+        violations
+    }
+}
+
+///Order in which the plugins are started, dependencies first
+fn resolve_order(
+    names: &[String],
+    deps: &HashMap<String, Vec<String>>,
+    active: &HashSet<String>,
+) -> Result<Vec<String>> {
+    for (name, name_deps) in deps {
+        if let Some(dep) = name_deps.iter().find(|dep| !active.contains(*dep) && !names.contains(dep)) {
+            return Err(MqttError::from(format!(
+                "{} depends on {}, which is neither started nor a default startup plug-in",
+                name, dep
+            )));
+        }
+    }
+
+    let mut order: Vec<String> = Vec::new();
+    let mut pending = names.to_vec();
+    pending.sort();
+    while !pending.is_empty() {
+        let ready = pending
+            .iter()
+            .position(|name| {
+                deps.get(name)
+                    .map(|name_deps| name_deps.iter().all(|dep| active.contains(dep) || order.contains(dep)))
+                    .unwrap_or(true)
+            })
+            .ok_or_else(|| MqttError::from(format!("plug-in dependencies form a cycle, {:?}", pending)))?;
+        order.push(pending.remove(ready));
+    }
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve() {
+        let names = vec!["bridge".to_owned(), "rule-engine".to_owned(), "web-hook".to_owned()];
+        let mut deps = HashMap::new();
+        deps.insert("bridge".to_owned(), vec!["rule-engine".to_owned(), "acl".to_owned()]);
+        let mut active = HashSet::new();
+        active.insert("acl".to_owned());
+        let order = resolve_order(&names, &deps, &active).unwrap();
+        assert_eq!(order, vec!["rule-engine", "bridge", "web-hook"]);
+
+        assert!(resolve_order(&names, &deps, &HashSet::new()).is_err());
+
+        deps.insert("rule-engine".to_owned(), vec!["bridge".to_owned()]);
+        assert!(resolve_order(&names, &deps, &active).is_err());
+    }
 }
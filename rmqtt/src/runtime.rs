@@ -6,11 +6,12 @@ use tokio_cron_scheduler::JobScheduler;
 
 use crate::logger::{config_logger, Logger};
 use crate::{
-    broker::{metrics::Metrics, stats::Stats},
+    broker::{budget::MemoryBudget, metrics::Metrics, stats::Stats},
     extend,
     node::Node,
     plugin,
-    settings::Settings,
+    settings::{ReloadResult, Settings},
+    Result,
 };
 
 pub struct Runtime {
@@ -40,7 +41,7 @@ impl Runtime {
 
         let settings = Settings::instance();
         let r = Self {
-            logger: config_logger(settings.log.filename(), settings.log.to, settings.log.level),
+            logger: config_logger(&settings.log, settings.node.id),
             settings: settings.clone(),
             extends: extend::Manager::new(),
             plugins: plugin::Manager::new(),
@@ -51,6 +52,7 @@ impl Runtime {
             sched,
         };
         INSTANCE.set(r).unwrap();
+        spawn(MemoryBudget::sample_retained_loop());
         return INSTANCE.get().unwrap();
     }
 
@@ -58,6 +60,23 @@ impl Runtime {
     pub fn instance() -> &'static Self {
         INSTANCE.get().unwrap()
     }
+
+    ///Reload the main configuration and the configurations of the plugins
+    #[inline]
+    pub async fn reload_config(&self) -> Result<ReloadResult> {
+        let mut res = self.settings.reload()?;
+        for (name, r) in self.plugins.load_configs().await {
+            match r {
+                Ok(()) => res.applied.push(format!("plugins.{}", name)),
+                Err(e) => {
+                    log::warn!("reload plugin config failed, {}, {:?}", name, e);
+                    res.failed.push(format!("plugins.{}: {}", name, e));
+                }
+            }
+        }
+        log::info!("reload config, {:?}", res);
+        Ok(res)
+    }
 }
 
 impl fmt::Debug for Runtime {
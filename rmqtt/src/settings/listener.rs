@@ -1,3 +1,4 @@
+use std::fmt;
 use std::net::SocketAddr;
 use std::num::NonZeroU32;
 use std::ops::Deref;
@@ -5,8 +6,11 @@ use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
+use parking_lot::RwLock;
 use serde::de::{self, Deserialize, Deserializer};
 
+use crate::broker::topic::{Topic, TopicTree};
+use crate::broker::topic_template::IdentityFilter;
 use crate::broker::types::QoS;
 
 use super::{deserialize_addr, deserialize_duration, to_duration, Bytesize};
@@ -41,6 +45,10 @@ pub struct Listeners {
     pub wss: HashMap<Port, Listener>,
     #[serde(default, skip)]
     pub wsss: HashMap<Port, Listener>,
+
+    //Listener configurations replaced by a configuration reload
+    #[serde(default, skip)]
+    reloadeds: Arc<RwLock<HashMap<Port, Listener>>>,
 }
 
 impl Listeners {
@@ -77,22 +85,22 @@ impl Listeners {
 
     #[inline]
     pub fn tcp(&self, port: u16) -> Option<Listener> {
-        self.tcps.get(&port).cloned()
+        self.tcps.get(&port).map(|l| self.reloaded(port).unwrap_or_else(|| l.clone()))
     }
 
     #[inline]
     pub fn tls(&self, port: u16) -> Option<Listener> {
-        self.tlss.get(&port).cloned()
+        self.tlss.get(&port).map(|l| self.reloaded(port).unwrap_or_else(|| l.clone()))
     }
 
     #[inline]
     pub fn ws(&self, port: u16) -> Option<Listener> {
-        self.wss.get(&port).cloned()
+        self.wss.get(&port).map(|l| self.reloaded(port).unwrap_or_else(|| l.clone()))
     }
 
     #[inline]
     pub fn wss(&self, port: u16) -> Option<Listener> {
-        self.wsss.get(&port).cloned()
+        self.wsss.get(&port).map(|l| self.reloaded(port).unwrap_or_else(|| l.clone()))
     }
 
     #[inline]
@@ -112,6 +120,61 @@ impl Listeners {
         None
     }
 
+    ///The largest max_packet_size of the listeners
+    #[inline]
+    pub fn max_packet_size(&self) -> usize {
+        self.tcps
+            .keys()
+            .filter_map(|port| self.tcp(*port))
+            .chain(self.tlss.keys().filter_map(|port| self.tls(*port)))
+            .chain(self.wss.keys().filter_map(|port| self.ws(*port)))
+            .chain(self.wsss.keys().filter_map(|port| self.wss(*port)))
+            .map(|l| l.max_packet_size.as_u32() as usize)
+            .max()
+            .unwrap_or_else(|| ListenerInner::max_packet_size_default().as_u32() as usize)
+    }
+
+    #[inline]
+    fn reloaded(&self, port: u16) -> Option<Listener> {
+        self.reloadeds.read().get(&port).cloned()
+    }
+
+    ///Apply the reloadable listener settings, the new configuration takes effect on new connections.
+    ///Returns the applied items and the items that require a restart.
+    #[inline]
+    pub(crate) fn reload(&self, new: &Listeners) -> (Vec<String>, Vec<String>) {
+        let mut applieds = Vec::new();
+        let mut restarts = Vec::new();
+        for (typ, olds, news) in [
+            ("tcp", &self.tcps, &new.tcps),
+            ("tls", &self.tlss, &new.tlss),
+            ("ws", &self.wss, &new.wss),
+            ("wss", &self.wsss, &new.wsss),
+        ] {
+            for (port, new_l) in news.iter() {
+                let old_l = if let Some(old_l) = olds.get(port) {
+                    self.reloaded(*port).unwrap_or_else(|| old_l.clone())
+                } else {
+                    restarts.push(format!("listener.{}.{}", typ, new_l.name));
+                    continue;
+                };
+                for field in old_l.restart_required(new_l) {
+                    restarts.push(format!("listener.{}.{}.{}", typ, new_l.name, field));
+                }
+                if format!("{:?}", old_l.inner) != format!("{:?}", new_l.inner) {
+                    self.reloadeds.write().insert(*port, new_l.clone());
+                    applieds.push(format!("listener.{}.{}", typ, new_l.name));
+                }
+            }
+            for (port, old_l) in olds.iter() {
+                if !news.contains_key(port) {
+                    restarts.push(format!("listener.{}.{}", typ, old_l.name));
+                }
+            }
+        }
+        (applieds, restarts)
+    }
+
     #[inline]
     pub(crate) fn set_default(&mut self) {
         let inner = Listener::default();
@@ -148,10 +211,23 @@ pub struct ListenerInner {
     pub addr: SocketAddr,
     #[serde(default = "ListenerInner::workers_default")]
     pub workers: usize,
+    ///Each worker has its own SO_REUSEPORT acceptor and connections (TCP listeners on unix only)
+    #[serde(default)]
+    pub reuseport: bool,
     #[serde(default = "ListenerInner::max_connections_default")]
     pub max_connections: usize,
     #[serde(default = "ListenerInner::max_handshaking_limit_default")]
     pub max_handshaking_limit: usize,
+    ///Maximum new connections per second, the excess is refused with Connection Rate Exceeded,
+    ///0 means unlimited
+    #[serde(default)]
+    pub max_conn_rate: u32,
+    ///New connections accepted at once after being idle, 0 means max_conn_rate
+    #[serde(default)]
+    pub max_conn_burst: u32,
+    ///The connections over max_conn_rate are only logged and counted, not refused
+    #[serde(default)]
+    pub conn_rate_limit_dry_run: bool,
     #[serde(default = "ListenerInner::max_packet_size_default")]
     pub max_packet_size: Bytesize,
     #[serde(default = "ListenerInner::backlog_default")]
@@ -160,6 +236,26 @@ pub struct ListenerInner {
     pub idle_timeout: Duration,
     #[serde(default = "ListenerInner::allow_anonymous_default")]
     pub allow_anonymous: bool,
+    ///What a connect does when the auth backends are unreachable, decided by each auth plugin if
+    ///not set
+    #[serde(default)]
+    pub auth_outage: Option<AuthOutage>,
+    ///How long a connect waits for the auth backends to recover, with auth_outage = "queue"
+    #[serde(
+        default = "ListenerInner::auth_outage_queue_timeout_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub auth_outage_queue_timeout: Duration,
+    ///Maximum age of the cached decisions used, with auth_outage = "cached"
+    #[serde(
+        default = "ListenerInner::auth_outage_cache_ttl_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub auth_outage_cache_ttl: Duration,
+    ///Topic filters the clients connected with auth_outage = "restricted" may publish and
+    ///subscribe to, %c is replaced with the client ID and %u with the username
+    #[serde(default)]
+    pub auth_outage_acl: Vec<IdentityFilter>,
     #[serde(
     default = "ListenerInner::min_keepalive_default",
     //deserialize_with = "deserialize_duration"
@@ -167,8 +263,31 @@ pub struct ListenerInner {
     pub min_keepalive: u16,
     #[serde(default = "ListenerInner::keepalive_backoff_default")]
     pub keepalive_backoff: f32,
+    ///The connection is closed when nothing is received within keepalive * factor,
+    ///keepalive_backoff * 2 if not set
+    #[serde(default)]
+    pub keepalive_factor: Option<f32>,
+    ///Maximum allowable keepalive, a larger one of a MQTT 5.0 client, or 0, is lowered to it with
+    ///Server Keep Alive, that of a MQTT 3.1.1 client is rejected, 0 means unlimited
+    #[serde(default)]
+    pub max_keepalive: u16,
+    ///Keepalive assigned to the MQTT 5.0 clients with Server Keep Alive, whatever they request
+    #[serde(default)]
+    pub server_keepalive: Option<u16>,
+    ///Response Information sent in CONNACK to the MQTT 5.0 clients that request it, the prefix of
+    ///their response topics, %c is replaced with the client ID and %u with the username, empty
+    ///to not send it
+    #[serde(default)]
+    pub response_info: IdentityFilter,
     #[serde(default = "ListenerInner::max_inflight_default")]
     pub max_inflight: usize,
+    ///Target ack latency of the adaptive inflight window, the window then stays between 1 and
+    ///max_inflight (or Receive Maximum), 0s keeps it fixed
+    #[serde(
+        default = "ListenerInner::inflight_latency_target_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub inflight_latency_target: Duration,
     #[serde(default = "ListenerInner::handshake_timeout_default", deserialize_with = "deserialize_duration")]
     pub handshake_timeout: Duration,
     #[serde(default = "ListenerInner::max_mqueue_len_default")]
@@ -178,9 +297,53 @@ pub struct ListenerInner {
         deserialize_with = "ListenerInner::deserialize_mqueue_rate_limit"
     )]
     pub mqueue_rate_limit: (NonZeroU32, Duration),
+    ///Messages delivered at once from the message queue after being idle, 0 means the number of
+    ///messages of mqueue_rate_limit
+    #[serde(default)]
+    pub mqueue_rate_burst: u32,
+    ///The deliveries over mqueue_rate_limit are only counted, not delayed
+    #[serde(default)]
+    pub mqueue_rate_limit_dry_run: bool,
 
     #[serde(default = "ListenerInner::max_clientid_len_default")]
     pub max_clientid_len: usize,
+    ///Accept MQTT 3.1 (MQIsdp, protocol level 3) connections
+    #[serde(default = "ListenerInner::mqtt_v31_default")]
+    pub mqtt_v31: bool,
+    ///Maximum length of the client ID of a MQTT 3.1 connection, MQTT 3.1 allows 1 to 23 characters,
+    ///0 means only max_clientid_len applies, so that the longer client IDs of the legacy clients
+    ///are accepted
+    #[serde(default)]
+    pub mqtt_v31_max_clientid_len: usize,
+    ///Send a DISCONNECT to a MQTT 3.1.1 client whose session is taken over or kicked, before the
+    ///connection is closed, it is not defined by MQTT 3.1.1. MQTT 5.0 clients always receive one,
+    ///with Session Taken Over or Administrative Action.
+    #[serde(default)]
+    pub v3_server_disconnect: bool,
+    ///QoS 1 publishes of a session with the same packet id, topic and payload as one within this
+    ///window are acked but dropped, 0s means disabled
+    #[serde(
+        default = "ListenerInner::publish_dedup_window_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub publish_dedup_window: Duration,
+    ///Maximum number of publishes remembered by the dedup window of a session
+    #[serde(default = "ListenerInner::publish_dedup_max_default")]
+    pub publish_dedup_max: usize,
+    ///Maximum publish messages per second of a connection, mqtt.max_publish_rate if not set
+    #[serde(default)]
+    pub max_publish_rate: Option<u32>,
+    ///Maximum publish payload bytes per second of a connection, mqtt.max_publish_bytes_rate if not set
+    #[serde(default)]
+    pub max_publish_bytes_rate: Option<Bytesize>,
+    ///Publish bursts of a connection, mqtt.max_publish_burst and mqtt.max_publish_bytes_burst if not set
+    #[serde(default)]
+    pub max_publish_burst: Option<u32>,
+    #[serde(default)]
+    pub max_publish_bytes_burst: Option<Bytesize>,
+    ///Only log and count the publishes over the rate limits, mqtt.publish_rate_limit_dry_run if not set
+    #[serde(default)]
+    pub publish_rate_limit_dry_run: Option<bool>,
 
     #[serde(
         default = "ListenerInner::max_qos_allowed_default",
@@ -190,6 +353,10 @@ pub struct ListenerInner {
 
     #[serde(default = "ListenerInner::max_topic_levels_default")]
     pub max_topic_levels: usize,
+    ///Topic filters that can never be published or subscribed to, regardless of the ACL results,
+    ///a reloaded deny list also applies to the connected clients
+    #[serde(default, deserialize_with = "ListenerInner::deserialize_topic_deny_list")]
+    pub topic_deny_list: TopicDenyList,
 
     #[serde(default = "ListenerInner::retain_available_default")]
     pub retain_available: bool,
@@ -198,12 +365,31 @@ pub struct ListenerInner {
         deserialize_with = "deserialize_duration"
     )]
     pub session_expiry_interval: Duration,
+    ///Maximum Session Expiry Interval, a longer one requested by a MQTT 5.0 client is lowered to it
+    ///and returned in CONNACK, 0 means unlimited
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub max_session_expiry_interval: Duration,
 
     #[serde(
         default = "ListenerInner::message_retry_interval_default",
         deserialize_with = "deserialize_duration"
     )]
     pub message_retry_interval: Duration,
+    ///The retry interval is multiplied by this factor after each retransmission of a message, 1.0 keeps it fixed
+    #[serde(default = "ListenerInner::message_retry_backoff_default")]
+    pub message_retry_backoff: f64,
+    ///Upper limit of the retry interval grown by message_retry_backoff, 0s means no limit
+    #[serde(
+        default = "ListenerInner::message_retry_max_interval_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub message_retry_max_interval: Duration,
+    ///Maximum number of retransmissions of a message, the message is then dropped, 0 means no limit
+    #[serde(default = "ListenerInner::message_retry_max_default")]
+    pub message_retry_max: usize,
+    ///Resend the unacknowledged messages only when the session is resumed, not at message_retry_interval
+    #[serde(default = "ListenerInner::message_retry_on_reconnect_only_default")]
+    pub message_retry_on_reconnect_only: bool,
 
     #[serde(
         default = "ListenerInner::message_expiry_interval_default",
@@ -222,8 +408,40 @@ pub struct ListenerInner {
     #[serde(default = "ListenerInner::shared_subscription_default")]
     pub shared_subscription: bool,
 
+    ///Deliver queue length at which a subscriber is considered slow, 0 disables the check
+    #[serde(default = "ListenerInner::slow_subscriber_queue_len_default")]
+    pub slow_subscriber_queue_len: usize,
+    ///Delivery latency at which a subscriber is considered slow, 0s disables the check
+    #[serde(
+        default = "ListenerInner::slow_subscriber_latency_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub slow_subscriber_latency: Duration,
+    ///How long a subscriber must stay slow before the client_slow hook is fired
+    #[serde(
+        default = "ListenerInner::slow_subscriber_duration_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub slow_subscriber_duration: Duration,
+
+    ///WebSocket subprotocols accepted by the ws and wss listeners, the first one offered by the client
+    ///in Sec-WebSocket-Protocol that is in the list is selected
+    #[serde(default = "ListenerInner::ws_subprotocols_default")]
+    pub ws_subprotocols: Vec<String>,
+    ///The WebSocket handshakes that offer none of ws_subprotocols are rejected, otherwise they are
+    ///accepted without a subprotocol
+    #[serde(default = "ListenerInner::ws_subprotocol_required_default")]
+    pub ws_subprotocol_required: bool,
+
     pub cert: Option<String>,
     pub key: Option<String>,
+    ///Interval of checking the cert and key files for changes, the changed certificate is used by
+    ///the new connections, 0s disables the check, they are also reloaded on SIGHUP
+    #[serde(
+        default = "ListenerInner::cert_reload_interval_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub cert_reload_interval: Duration,
 }
 
 impl Default for ListenerInner {
@@ -233,31 +451,68 @@ impl Default for ListenerInner {
             enable: ListenerInner::enable_default(),
             addr: ListenerInner::addr_default(),
             workers: ListenerInner::workers_default(),
+            reuseport: false,
             max_connections: ListenerInner::max_connections_default(),
             max_handshaking_limit: ListenerInner::max_handshaking_limit_default(),
+            max_conn_rate: 0,
+            max_conn_burst: 0,
+            conn_rate_limit_dry_run: false,
             max_packet_size: ListenerInner::max_packet_size_default(),
             backlog: ListenerInner::backlog_default(),
             idle_timeout: ListenerInner::idle_timeout_default(),
             allow_anonymous: ListenerInner::allow_anonymous_default(),
+            auth_outage: None,
+            auth_outage_queue_timeout: ListenerInner::auth_outage_queue_timeout_default(),
+            auth_outage_cache_ttl: ListenerInner::auth_outage_cache_ttl_default(),
+            auth_outage_acl: Vec::new(),
             min_keepalive: ListenerInner::min_keepalive_default(),
             keepalive_backoff: ListenerInner::keepalive_backoff_default(),
+            keepalive_factor: None,
+            max_keepalive: 0,
+            server_keepalive: None,
+            response_info: IdentityFilter::default(),
             max_inflight: ListenerInner::max_inflight_default(),
+            inflight_latency_target: ListenerInner::inflight_latency_target_default(),
             handshake_timeout: ListenerInner::handshake_timeout_default(),
             max_mqueue_len: ListenerInner::max_mqueue_len_default(),
             mqueue_rate_limit: ListenerInner::mqueue_rate_limit_default(),
+            mqueue_rate_burst: 0,
+            mqueue_rate_limit_dry_run: false,
             max_clientid_len: ListenerInner::max_clientid_len_default(),
+            mqtt_v31: ListenerInner::mqtt_v31_default(),
+            mqtt_v31_max_clientid_len: 0,
+            v3_server_disconnect: false,
+            publish_dedup_window: ListenerInner::publish_dedup_window_default(),
+            publish_dedup_max: ListenerInner::publish_dedup_max_default(),
+            max_publish_rate: None,
+            max_publish_bytes_rate: None,
+            max_publish_burst: None,
+            max_publish_bytes_burst: None,
+            publish_rate_limit_dry_run: None,
             max_qos_allowed: ListenerInner::max_qos_allowed_default(),
             max_topic_levels: ListenerInner::max_topic_levels_default(),
+            topic_deny_list: TopicDenyList::default(),
             retain_available: ListenerInner::retain_available_default(),
             session_expiry_interval: ListenerInner::session_expiry_interval_default(),
+            max_session_expiry_interval: Duration::ZERO,
             message_retry_interval: ListenerInner::message_retry_interval_default(),
+            message_retry_backoff: ListenerInner::message_retry_backoff_default(),
+            message_retry_max_interval: ListenerInner::message_retry_max_interval_default(),
+            message_retry_max: ListenerInner::message_retry_max_default(),
+            message_retry_on_reconnect_only: ListenerInner::message_retry_on_reconnect_only_default(),
             message_expiry_interval: ListenerInner::message_expiry_interval_default(),
             max_awaiting_rel: ListenerInner::max_awaiting_rel_default(),
             await_rel_timeout: ListenerInner::await_rel_timeout_default(),
             max_subscriptions: ListenerInner::max_subscriptions_default(),
             shared_subscription: ListenerInner::shared_subscription_default(),
+            slow_subscriber_queue_len: ListenerInner::slow_subscriber_queue_len_default(),
+            slow_subscriber_latency: ListenerInner::slow_subscriber_latency_default(),
+            slow_subscriber_duration: ListenerInner::slow_subscriber_duration_default(),
+            ws_subprotocols: ListenerInner::ws_subprotocols_default(),
+            ws_subprotocol_required: ListenerInner::ws_subprotocol_required_default(),
             cert: None,
             key: None,
+            cert_reload_interval: ListenerInner::cert_reload_interval_default(),
         }
     }
 }
@@ -311,6 +566,10 @@ impl ListenerInner {
         16
     }
     #[inline]
+    fn inflight_latency_target_default() -> Duration {
+        Duration::ZERO
+    }
+    #[inline]
     fn handshake_timeout_default() -> Duration {
         Duration::from_secs(15)
     }
@@ -327,6 +586,10 @@ impl ListenerInner {
         65535
     }
     #[inline]
+    fn mqtt_v31_default() -> bool {
+        true
+    }
+    #[inline]
     fn max_qos_allowed_default() -> QoS {
         QoS::ExactlyOnce
     }
@@ -348,6 +611,30 @@ impl ListenerInner {
         Duration::from_secs(30)
     }
     #[inline]
+    fn publish_dedup_window_default() -> Duration {
+        Duration::ZERO
+    }
+    #[inline]
+    fn publish_dedup_max_default() -> usize {
+        1000
+    }
+    #[inline]
+    fn message_retry_backoff_default() -> f64 {
+        1.0
+    }
+    #[inline]
+    fn message_retry_max_interval_default() -> Duration {
+        Duration::ZERO
+    }
+    #[inline]
+    fn message_retry_max_default() -> usize {
+        0
+    }
+    #[inline]
+    fn message_retry_on_reconnect_only_default() -> bool {
+        false
+    }
+    #[inline]
     fn message_expiry_interval_default() -> Duration {
         Duration::from_secs(30)
     }
@@ -367,6 +654,124 @@ impl ListenerInner {
     fn shared_subscription_default() -> bool {
         true
     }
+    #[inline]
+    fn slow_subscriber_queue_len_default() -> usize {
+        0
+    }
+    #[inline]
+    fn slow_subscriber_latency_default() -> Duration {
+        Duration::ZERO
+    }
+    #[inline]
+    fn slow_subscriber_duration_default() -> Duration {
+        Duration::from_secs(10)
+    }
+    #[inline]
+    fn ws_subprotocols_default() -> Vec<String> {
+        vec!["mqtt".into()]
+    }
+
+    fn ws_subprotocol_required_default() -> bool {
+        true
+    }
+
+    fn cert_reload_interval_default() -> Duration {
+        Duration::from_secs(60)
+    }
+    #[inline]
+    fn auth_outage_queue_timeout_default() -> Duration {
+        Duration::from_secs(5)
+    }
+    #[inline]
+    fn auth_outage_cache_ttl_default() -> Duration {
+        Duration::from_secs(60 * 60 * 24)
+    }
+
+    ///Whether auth_outage_acl allows a client connected during an auth outage to publish to the
+    ///topic, or to subscribe to the topic filter. A topic filter with %u does not apply to a client
+    ///without a username, nor a placeholder to a client ID or username that is not a valid identity
+    #[inline]
+    pub fn auth_outage_acl_allows(&self, topic: &str, client_id: &str, username: Option<&str>) -> bool {
+        self.auth_outage_acl.iter().any(|tf| tf.is_match(topic, client_id, username))
+    }
+
+    ///The Response Information of a client, None if it is not configured, if it contains %u and the
+    ///client has no username, or if the client ID or the username is not a valid identity
+    #[inline]
+    pub fn response_info(&self, client_id: &str, username: Option<&str>) -> Option<String> {
+        if self.response_info.is_empty() {
+            return None;
+        }
+        self.response_info.render(client_id, username)
+    }
+
+    ///The session expiry interval lowered to max_session_expiry_interval
+    #[inline]
+    pub fn session_expiry_interval_limit(&self, interval: Duration) -> Duration {
+        if self.max_session_expiry_interval.is_zero() {
+            interval
+        } else {
+            interval.min(self.max_session_expiry_interval)
+        }
+    }
+
+    ///Whether the slow subscriber detection is enabled
+    #[inline]
+    pub fn slow_subscriber_check(&self) -> bool {
+        self.slow_subscriber_queue_len > 0 || !self.slow_subscriber_latency.is_zero()
+    }
+
+    ///Settings that are bound when the listener is started, changing them requires a restart
+    #[inline]
+    fn restart_required(&self, other: &ListenerInner) -> Vec<&'static str> {
+        let mut fields = Vec::new();
+        if self.addr != other.addr {
+            fields.push("addr");
+        }
+        if self.workers != other.workers {
+            fields.push("workers");
+        }
+        if self.reuseport != other.reuseport {
+            fields.push("reuseport");
+        }
+        if self.max_connections != other.max_connections {
+            fields.push("max_connections");
+        }
+        if self.max_handshaking_limit != other.max_handshaking_limit {
+            fields.push("max_handshaking_limit");
+        }
+        if *self.max_packet_size != *other.max_packet_size {
+            fields.push("max_packet_size");
+        }
+        if self.backlog != other.backlog {
+            fields.push("backlog");
+        }
+        if self.max_inflight != other.max_inflight {
+            fields.push("max_inflight");
+        }
+        if self.handshake_timeout != other.handshake_timeout {
+            fields.push("handshake_timeout");
+        }
+        if self.max_qos_allowed != other.max_qos_allowed {
+            fields.push("max_qos_allowed");
+        }
+        if self.max_awaiting_rel != other.max_awaiting_rel {
+            fields.push("max_awaiting_rel");
+        }
+        if self.await_rel_timeout != other.await_rel_timeout {
+            fields.push("await_rel_timeout");
+        }
+        if self.cert != other.cert {
+            fields.push("cert");
+        }
+        if self.key != other.key {
+            fields.push("key");
+        }
+        if self.cert_reload_interval != other.cert_reload_interval {
+            fields.push("cert_reload_interval");
+        }
+        fields
+    }
 
     #[inline]
     pub fn handshake_timeout(&self) -> u16 {
@@ -401,6 +806,21 @@ impl ListenerInner {
         }
     }
     #[inline]
+    fn deserialize_topic_deny_list<'de, D>(deserializer: D) -> Result<TopicDenyList, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let topic_filters: Vec<String> = Vec::deserialize(deserializer)?;
+        let mut tree = TopicTree::default();
+        for topic_filter in topic_filters.iter() {
+            let topic = Topic::from_str(topic_filter).map_err(|e| {
+                de::Error::custom(format!("topic_deny_list, format error, {:?}, {:?}", topic_filter, e))
+            })?;
+            tree.insert(&topic, ());
+        }
+        Ok(TopicDenyList { tree: Arc::new(tree), topic_filters })
+    }
+    #[inline]
     fn deserialize_max_qos_allowed<'de, D>(deserializer: D) -> Result<QoS, D::Error>
     where
         D: Deserializer<'de>,
@@ -414,3 +834,41 @@ impl ListenerInner {
         Ok(qos)
     }
 }
+
+///What a connect does when the auth backends are unreachable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthOutage {
+    ///The connect is refused
+    Reject,
+    ///The connect is allowed, its publishes and subscriptions are limited to auth_outage_acl
+    Restricted,
+    ///The connect waits for the backends to recover, up to auth_outage_queue_timeout
+    Queue,
+    ///The last decision of the backends for the same client ID, username and password is used, up
+    ///to auth_outage_cache_ttl old
+    Cached,
+}
+
+#[derive(Clone, Default)]
+pub struct TopicDenyList {
+    tree: Arc<TopicTree<()>>,
+    topic_filters: Vec<String>,
+}
+
+impl TopicDenyList {
+    ///Whether the topic, or every topic matched by the topic filter, is denied
+    #[inline]
+    pub fn is_denied(&self, topic: &str) -> bool {
+        if self.topic_filters.is_empty() {
+            return false;
+        }
+        Topic::from_str(topic).map(|t| self.tree.is_match(&t)).unwrap_or_default()
+    }
+}
+
+impl fmt::Debug for TopicDenyList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.topic_filters)
+    }
+}
@@ -13,6 +13,12 @@ pub struct Log {
     pub dir: String,
     #[serde(default = "Log::file_default")]
     pub file: String,
+    ///Record format of the console output
+    #[serde(default = "Log::format_default")]
+    pub console_format: Format,
+    ///Record format of the file output
+    #[serde(default = "Log::format_default")]
+    pub file_format: Format,
 }
 
 impl Default for Log {
@@ -23,6 +29,8 @@ impl Default for Log {
             level: Self::level_default(),
             dir: Self::dir_default(),
             file: Self::file_default(),
+            console_format: Self::format_default(),
+            file_format: Self::format_default(),
         }
     }
 }
@@ -45,6 +53,10 @@ impl Log {
         "rmqtt.log".into()
     }
     #[inline]
+    fn format_default() -> Format {
+        Format::Text
+    }
+    #[inline]
     pub fn filename(&self) -> String {
         let file = &self.file;
         if file.is_empty() {
@@ -99,6 +111,34 @@ impl<'de> Deserialize<'de> for To {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    #[inline]
+    pub fn json(&self) -> bool {
+        matches!(self, Format::Json)
+    }
+}
+
+impl<'de> Deserialize<'de> for Format {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let format = String::deserialize(deserializer)?;
+        match format.to_ascii_lowercase().as_str() {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(de::Error::custom(format!("log format error, {}, expected text or json", format))),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Level {
     inner: slog::Level,
@@ -6,13 +6,13 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use chrono::TimeZone;
-use config::{Config, ConfigError, File};
+use config::{Config, ConfigError};
 use once_cell::sync::OnceCell;
 use serde::de::{self, Deserialize, Deserializer};
 use serde::ser::Serializer;
 use serde::Serialize;
 
-use crate::{Addr, MqttError, NodeId, Result};
+use crate::{fnv1a, Addr, MqttError, NodeId, Result};
 
 pub use self::listener::Listener;
 use self::listener::Listeners;
@@ -22,6 +22,8 @@ pub use self::options::Options;
 pub mod listener;
 pub mod log;
 pub mod options;
+pub mod schema;
+mod source;
 
 static SETTINGS: OnceCell<Settings> = OnceCell::new();
 
@@ -43,6 +45,20 @@ pub struct Inner {
     pub plugins: Plugins,
     #[serde(default)]
     pub mqtt: Mqtt,
+    #[serde(default)]
+    pub router: Router,
+    #[serde(default)]
+    pub telemetry: Telemetry,
+    #[serde(default)]
+    pub alarm: Alarm,
+    #[serde(default)]
+    pub connection_history: ConnectionHistory,
+    #[serde(default)]
+    pub memory_budget: MemoryBudget,
+    #[serde(default)]
+    pub handshake_admission: HandshakeAdmission,
+    #[serde(default)]
+    pub reconnect_advice: ReconnectAdvice,
     #[serde(default, skip)]
     pub opts: Options,
 }
@@ -61,11 +77,11 @@ impl Settings {
         // if let Ok(cfg_filename) = std::env::var("RMQTT-CONFIG-FILENAME") {
         //     s.merge(File::with_name(&cfg_filename).required(false))?;
         // }
-        s.merge(File::with_name("/etc/rmqtt/rmqtt").required(false))?;
-        s.merge(File::with_name("/etc/rmqtt").required(false))?;
-        s.merge(File::with_name("rmqtt").required(false))?;
+        source::merge(&mut s, "/etc/rmqtt/rmqtt", false)?;
+        source::merge(&mut s, "/etc/rmqtt", false)?;
+        source::merge(&mut s, "rmqtt", false)?;
         if let Some(cfg) = opts.cfg_name.as_ref() {
-            s.merge(File::with_name(cfg).required(false))?;
+            source::merge(&mut s, cfg, false)?;
         }
 
         let mut inner: Inner = match s.try_into() {
@@ -81,6 +97,17 @@ impl Settings {
             inner.listeners.set_default();
         }
 
+        if inner.node.id_from_ordinal {
+            let (_, ordinal) = Node::pod_ordinal().ok_or_else(|| {
+                ConfigError::Message("node.id_from_ordinal, HOSTNAME is not of a StatefulSet pod".into())
+            })?;
+            inner.node.id = ordinal + 1;
+        } else if inner.node.id_from_machine_id {
+            inner.node.id = Node::machine_node_id(inner.rpc.server_addr.port()).ok_or_else(|| {
+                ConfigError::Message("node.id_from_machine_id, the machine id is not found".into())
+            })?;
+        }
+
         //Command line configuration overriding file configuration
         if let Some(id) = opts.node_id {
             if id > 0 {
@@ -120,6 +147,105 @@ impl Settings {
     }
 }
 
+impl Settings {
+    ///Re-read the configuration files and apply the settings that are safe to change at runtime,
+    ///listener limits take effect on new connections.
+    #[inline]
+    pub fn reload(&self) -> Result<ReloadResult> {
+        let new = Settings::new(self.opts.clone())?;
+        let mut res = ReloadResult::default();
+
+        if format!("{:?}", self.node) != format!("{:?}", new.node) {
+            res.restart_required.push("node".into());
+        }
+        if format!("{:?}", self.rpc) != format!("{:?}", new.rpc) {
+            res.restart_required.push("rpc".into());
+        }
+        if format!("{:?}", self.plugins) != format!("{:?}", new.plugins) {
+            res.restart_required.push("plugins".into());
+        }
+        //the priorities of the queued messages and the payload limits are applied, the other mqtt
+        //settings need a restart
+        let mut new_mqtt = new.mqtt.clone();
+        new_mqtt.topic_priorities = self.mqtt.topic_priorities.clone();
+        new_mqtt.priority_user_property = self.mqtt.priority_user_property.clone();
+        new_mqtt.priority_values = self.mqtt.priority_values.clone();
+        new_mqtt.priority_user_property_max = self.mqtt.priority_user_property_max;
+        new_mqtt.topic_payload_limits = self.mqtt.topic_payload_limits.clone();
+        if format!("{:?}", self.mqtt) != format!("{:?}", new_mqtt) {
+            res.restart_required.push("mqtt".into());
+        }
+        if self.mqtt.topic_priorities != new.mqtt.topic_priorities
+            || self.mqtt.priority_user_property != new.mqtt.priority_user_property
+            || self.mqtt.priority_values != new.mqtt.priority_values
+            || self.mqtt.priority_user_property_max != new.mqtt.priority_user_property_max
+        {
+            crate::broker::session::MessagePriorities::reload(&new.mqtt);
+            res.applied.push("mqtt.topic_priorities".into());
+        }
+        if self.mqtt.topic_payload_limits != new.mqtt.topic_payload_limits {
+            crate::broker::payload_limit::PayloadLimits::reload(&new.mqtt);
+            res.applied.push("mqtt.topic_payload_limits".into());
+        }
+        if format!("{:?}", self.router) != format!("{:?}", new.router) {
+            res.restart_required.push("router".into());
+        }
+        if format!("{:?}", self.telemetry) != format!("{:?}", new.telemetry) {
+            res.restart_required.push("telemetry".into());
+        }
+        if format!("{:?}", self.alarm) != format!("{:?}", new.alarm) {
+            res.restart_required.push("alarm".into());
+        }
+        if format!("{:?}", self.connection_history) != format!("{:?}", new.connection_history) {
+            res.restart_required.push("connection_history".into());
+        }
+        if format!("{:?}", self.memory_budget) != format!("{:?}", new.memory_budget) {
+            res.restart_required.push("memory_budget".into());
+        }
+        if format!("{:?}", self.handshake_admission) != format!("{:?}", new.handshake_admission) {
+            res.restart_required.push("handshake_admission".into());
+        }
+        if format!("{:?}", self.reconnect_advice) != format!("{:?}", new.reconnect_advice) {
+            res.restart_required.push("reconnect_advice".into());
+        }
+        if self.log.filename() != new.log.filename()
+            || format!("{:?}", self.log.to) != format!("{:?}", new.log.to)
+            || self.log.console_format != new.log.console_format
+            || self.log.file_format != new.log.file_format
+        {
+            res.restart_required.push("log".into());
+        }
+        //the settings are not replaced by a reload, the level in effect is that of the last reload
+        if crate::logger::log_levels().default_level() != new.log.level.inner() {
+            crate::logger::log_levels().set_default(new.log.level.inner());
+            res.applied.push("log.level".into());
+        }
+
+        let (applieds, restarts) = self.listeners.reload(&new.listeners);
+        res.applied.extend(applieds);
+        res.restart_required.extend(restarts);
+        Ok(res)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReloadResult {
+    pub applied: Vec<String>,
+    pub restart_required: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+impl ReloadResult {
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "applied": self.applied,
+            "restart_required": self.restart_required,
+            "failed": self.failed,
+        })
+    }
+}
+
 impl fmt::Debug for Settings {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Settings ...")?;
@@ -133,6 +259,13 @@ pub struct Node {
     pub id: NodeId,
     #[serde(default = "Node::cookie_default")]
     pub cookie: String,
+    ///The node id is the ordinal of the StatefulSet pod plus 1, taken from HOSTNAME
+    #[serde(default)]
+    pub id_from_ordinal: bool,
+    ///The node id is derived from the machine id and the port of rpc.server_addr, so it is stable
+    ///across restarts and unique among the nodes of the same machine
+    #[serde(default)]
+    pub id_from_machine_id: bool,
     // #[serde(default = "Node::crash_dump_default")]
     // pub crash_dump: String,
 }
@@ -141,6 +274,38 @@ impl Node {
     fn cookie_default() -> String {
         "rmqttsecretcookie".into()
     }
+
+    ///Name of the StatefulSet and ordinal of the pod this node runs in, HOSTNAME is
+    ///"<statefulset>-<ordinal>"
+    #[inline]
+    pub fn pod_ordinal() -> Option<(String, u64)> {
+        let hostname = std::env::var("HOSTNAME").ok()?;
+        let (statefulset, ordinal) = hostname.rsplit_once('-')?;
+        Some((statefulset.to_owned(), ordinal.parse().ok()?))
+    }
+
+    ///The id of the machine, from /etc/machine-id or /var/lib/dbus/machine-id
+    #[inline]
+    pub fn machine_id() -> Option<String> {
+        ["/etc/machine-id", "/var/lib/dbus/machine-id"].iter().find_map(|path| {
+            let id = std::fs::read_to_string(path).ok()?;
+            let id = id.trim();
+            if id.is_empty() {
+                None
+            } else {
+                Some(id.to_owned())
+            }
+        })
+    }
+
+    ///A node id in 1..=u32::MAX, FNV-1a hash of the machine id and the rpc port, which does not
+    ///change between builds
+    #[inline]
+    pub fn machine_node_id(rpc_port: u16) -> Option<NodeId> {
+        let machine_id = Self::machine_id()?;
+        let hash = fnv1a(machine_id.bytes().chain(rpc_port.to_be_bytes()));
+        Some(hash % u32::MAX as u64 + 1)
+    }
     // fn crash_dump_default() -> String {
     //     "/var/log/rmqtt/crash.dump".into()
     // }
@@ -212,13 +377,560 @@ impl Plugins {
     pub fn load_config<'de, T: serde::Deserialize<'de>>(&self, name: &str) -> Result<T, ConfigError> {
         let dir = self.dir.trim_end_matches(|c| c == '/' || c == '\\');
         let mut s = Config::new();
-        s.merge(File::with_name(&format!("{}/{}", dir, name)).required(true))?;
+        source::merge(&mut s, &format!("{}/{}", dir, name), true)?;
         s.try_into::<T>()
     }
+
+    ///Load the config of a plugin, it is validated against the schema, then deserialized as by
+    ///load_config, so that the defaults are those of the config
+    pub fn load_config_with_schema<'de, T: serde::Deserialize<'de>>(
+        &self,
+        name: &str,
+        schema: &schema::ConfigSchema,
+    ) -> Result<T> {
+        let mut cfg = self.load_config::<serde_json::Value>(name)?;
+        schema.validate(&mut cfg).map_err(|e| MqttError::from(format!("{} {}", name, e)))?;
+        Ok(self.load_config::<T>(name)?)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Mqtt {
+    ///Maximum number of session states taken over concurrently on this node, such as when the
+    ///clients of a failed node reconnect
+    #[serde(default = "Mqtt::max_concurrent_takeovers_default")]
+    pub max_concurrent_takeovers: usize,
+    ///Maximum number of subscriptions of a taken over session restored concurrently
+    #[serde(default = "Mqtt::takeover_subscribe_concurrency_default")]
+    pub takeover_subscribe_concurrency: usize,
+    ///Priorities of the messages in the queue of a session, "topic_filter,priority", the messages of
+    ///a higher priority are delivered first, the others have priority 0
+    #[serde(default, deserialize_with = "Mqtt::deserialize_topic_priorities")]
+    pub topic_priorities: Vec<(String, u8)>,
+    ///Name of the MQTT 5.0 user property setting the priority of a message in the queues of the
+    ///sessions, the higher of it and the priority of the topic applies, empty means disabled
+    #[serde(default)]
+    pub priority_user_property: String,
+    ///Priorities of the values of priority_user_property, "value,priority", a numeric value is
+    ///the priority itself
+    #[serde(default, deserialize_with = "Mqtt::deserialize_priority_values")]
+    pub priority_values: Vec<(String, u8)>,
+    ///Highest priority a publisher can set by priority_user_property, the higher ones are lowered
+    ///to it
+    #[serde(default = "Mqtt::priority_user_property_max_default")]
+    pub priority_user_property_max: u8,
+    ///Maximum number of messages of the higher priorities delivered in a row while those of a lower
+    ///priority wait, then one of the lowest priority is delivered, 0 means strict priority
+    #[serde(default = "Mqtt::priority_max_consecutive_default")]
+    pub priority_max_consecutive: usize,
+    ///Name of the MQTT 5.0 user property of a SUBSCRIBE carrying a payload filter, only the
+    ///messages whose JSON payload matches are delivered to its subscriptions, empty means disabled
+    #[serde(default = "Mqtt::payload_filter_user_property_default")]
+    pub payload_filter_user_property: String,
+    ///Maximum payload sizes of the topics, "topic_filter,size", the smallest of the matching topic
+    ///filters applies, a client publishing a larger payload is disconnected with Packet Too Large
+    #[serde(default, deserialize_with = "Mqtt::deserialize_topic_payload_limits")]
+    pub topic_payload_limits: Vec<(String, usize)>,
+    ///Maximum publish messages per second of a connection, 0 means unlimited,
+    ///can be overridden by a listener or an auth plugin
+    #[serde(default)]
+    pub max_publish_rate: u32,
+    ///Maximum publish payload bytes per second of a connection, 0 means unlimited,
+    ///can be overridden by a listener or an auth plugin
+    #[serde(default = "Mqtt::max_publish_bytes_rate_default")]
+    pub max_publish_bytes_rate: Bytesize,
+    ///Messages a connection can publish at once after being idle, 0 means max_publish_rate
+    #[serde(default)]
+    pub max_publish_burst: u32,
+    ///Payload bytes a connection can publish at once after being idle, 0 means max_publish_bytes_rate
+    #[serde(default = "Mqtt::max_publish_bytes_rate_default")]
+    pub max_publish_bytes_burst: Bytesize,
+    ///The publishes over the rate limits are only logged and counted, not refused
+    #[serde(default)]
+    pub publish_rate_limit_dry_run: bool,
+    ///Levels of the topic that make the tenant of a publish, such as 1 for "tenant1/...", 0 means
+    ///no tenant publish rate limits and no tenant statistics
+    #[serde(default)]
+    pub tenant_levels: usize,
+    ///Maximum publish messages per second of a tenant on this node, shared by its connections,
+    ///0 means unlimited
+    #[serde(default)]
+    pub max_tenant_publish_rate: u32,
+    ///Maximum publish payload bytes per second of a tenant on this node, 0 means unlimited
+    #[serde(default = "Mqtt::max_publish_bytes_rate_default")]
+    pub max_tenant_publish_bytes_rate: Bytesize,
+    ///Messages a tenant can publish at once after being idle, 0 means max_tenant_publish_rate
+    #[serde(default)]
+    pub max_tenant_publish_burst: u32,
+    ///Payload bytes a tenant can publish at once after being idle, 0 means max_tenant_publish_bytes_rate
+    #[serde(default = "Mqtt::max_publish_bytes_rate_default")]
+    pub max_tenant_publish_bytes_burst: Bytesize,
+    ///Maximum age of the messages in the queues of the offline sessions, independent of the
+    ///MQTT 5.0 message expiry, 0s means disabled
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub offline_message_max_age: Duration,
+    ///Interval of purging the messages older than offline_message_max_age
+    #[serde(
+        default = "Mqtt::offline_message_purge_interval_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub offline_message_purge_interval: Duration,
+    ///Deliver the messages of a publisher on a topic to each subscriber in order, across the
+    ///retransmissions and the forwarding between nodes, at the cost of some parallelism
+    #[serde(default)]
+    pub strict_ordering: bool,
+    ///What a connect does when its client_id is connected already in the cluster
+    #[serde(default)]
+    pub duplicate_clientid: DuplicateClientId,
+    ///Keys of the client tags by whose values the connections of the node are counted, in the
+    ///stats and as labels of the exported metrics
+    #[serde(default)]
+    pub tag_metric_labels: Vec<String>,
+}
+
+impl Default for Mqtt {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            max_concurrent_takeovers: Self::max_concurrent_takeovers_default(),
+            takeover_subscribe_concurrency: Self::takeover_subscribe_concurrency_default(),
+            topic_priorities: Vec::new(),
+            priority_user_property: String::new(),
+            priority_values: Vec::new(),
+            priority_user_property_max: Self::priority_user_property_max_default(),
+            priority_max_consecutive: Self::priority_max_consecutive_default(),
+            payload_filter_user_property: Self::payload_filter_user_property_default(),
+            topic_payload_limits: Vec::new(),
+            max_publish_rate: 0,
+            max_publish_bytes_rate: Self::max_publish_bytes_rate_default(),
+            max_publish_burst: 0,
+            max_publish_bytes_burst: Self::max_publish_bytes_rate_default(),
+            publish_rate_limit_dry_run: false,
+            tenant_levels: 0,
+            max_tenant_publish_rate: 0,
+            max_tenant_publish_bytes_rate: Self::max_publish_bytes_rate_default(),
+            max_tenant_publish_burst: 0,
+            max_tenant_publish_bytes_burst: Self::max_publish_bytes_rate_default(),
+            offline_message_max_age: Duration::ZERO,
+            offline_message_purge_interval: Self::offline_message_purge_interval_default(),
+            strict_ordering: false,
+            duplicate_clientid: DuplicateClientId::default(),
+            tag_metric_labels: Vec::new(),
+        }
+    }
+}
+
+impl Mqtt {
+    fn max_concurrent_takeovers_default() -> usize {
+        256
+    }
+    fn takeover_subscribe_concurrency_default() -> usize {
+        16
+    }
+    fn max_publish_bytes_rate_default() -> Bytesize {
+        Bytesize::from(0)
+    }
+    fn offline_message_purge_interval_default() -> Duration {
+        Duration::from_secs(60)
+    }
+    fn priority_user_property_max_default() -> u8 {
+        1
+    }
+    fn priority_max_consecutive_default() -> usize {
+        16
+    }
+    fn payload_filter_user_property_default() -> String {
+        "payload-filter".into()
+    }
+
+    #[inline]
+    fn deserialize_topic_priorities<'de, D>(deserializer: D) -> Result<Vec<(String, u8)>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let items = Vec::<String>::deserialize(deserializer)?;
+        items
+            .iter()
+            .map(|item| {
+                let (topic_filter, priority) = item.rsplit_once(',').ok_or_else(|| {
+                    de::Error::custom(format!("topic_priorities, format error, {:?}", item))
+                })?;
+                let priority = priority.trim().parse::<u8>().map_err(|e| {
+                    de::Error::custom(format!("topic_priorities, priority format error, {:?}, {:?}", item, e))
+                })?;
+                Ok((topic_filter.trim().to_owned(), priority))
+            })
+            .collect()
+    }
+
+    #[inline]
+    fn deserialize_priority_values<'de, D>(deserializer: D) -> Result<Vec<(String, u8)>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let items = Vec::<String>::deserialize(deserializer)?;
+        items
+            .iter()
+            .map(|item| {
+                let (value, priority) = item
+                    .rsplit_once(',')
+                    .ok_or_else(|| de::Error::custom(format!("priority_values, format error, {:?}", item)))?;
+                let priority = priority.trim().parse::<u8>().map_err(|e| {
+                    de::Error::custom(format!("priority_values, priority format error, {:?}, {:?}", item, e))
+                })?;
+                Ok((value.trim().to_owned(), priority))
+            })
+            .collect()
+    }
+
+    #[inline]
+    fn deserialize_topic_payload_limits<'de, D>(deserializer: D) -> Result<Vec<(String, usize)>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let items = Vec::<String>::deserialize(deserializer)?;
+        items
+            .iter()
+            .map(|item| {
+                let (topic_filter, max_size) = item.rsplit_once(',').ok_or_else(|| {
+                    de::Error::custom(format!("topic_payload_limits, format error, {:?}", item))
+                })?;
+                Ok((topic_filter.trim().to_owned(), to_bytesize(max_size.trim())))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateClientId {
+    ///The connected client is kicked and its session taken over
+    Kick,
+    ///The new connect is refused
+    Reject,
+    ///The new connect continues with the client_id suffixed, "<client_id>-<n>" of no session,
+    ///MQTT 3.1.1 connects are rejected
+    Suffix,
+}
+
+impl Default for DuplicateClientId {
+    #[inline]
+    fn default() -> Self {
+        DuplicateClientId::Kick
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Router {
+    ///Number of shards of the subscription topic tree
+    #[serde(default = "Router::shards_default")]
+    pub shards: usize,
+    ///Maximum number of topic names whose resolved subscribers are cached, 0 disables the cache
+    #[serde(default = "Router::match_cache_size_default")]
+    pub match_cache_size: usize,
+    ///Publishes per second of a topic name from which its resolved subscribers are kept up to date
+    ///on each subscription change, 0 disables it
+    #[serde(default = "Router::hot_topic_rate_default")]
+    pub hot_topic_rate: usize,
+    ///Maximum number of the hot topic names, they are not counted in match_cache_size
+    #[serde(default = "Router::hot_topic_max_default")]
+    pub hot_topic_max: usize,
+}
+
+impl Default for Router {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            shards: Self::shards_default(),
+            match_cache_size: Self::match_cache_size_default(),
+            hot_topic_rate: Self::hot_topic_rate_default(),
+            hot_topic_max: Self::hot_topic_max_default(),
+        }
+    }
+}
+
+impl Router {
+    fn shards_default() -> usize {
+        16
+    }
+    fn match_cache_size_default() -> usize {
+        10_000
+    }
+    fn hot_topic_rate_default() -> usize {
+        100
+    }
+    fn hot_topic_max_default() -> usize {
+        1_000
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Telemetry {
+    #[serde(default)]
+    pub enable: bool,
+    ///OTLP gRPC collector endpoint
+    #[serde(default = "Telemetry::endpoint_default")]
+    pub endpoint: String,
+    #[serde(default = "Telemetry::service_name_default")]
+    pub service_name: String,
+    ///Ratio of the traces to be sampled, 0.0 ~ 1.0
+    #[serde(default = "Telemetry::sample_ratio_default")]
+    pub sample_ratio: f64,
+    ///MQTT 5 user property that carries the W3C traceparent of the messages, it is extracted on
+    ///publish and set on delivery and replication, empty means disabled
+    #[serde(default)]
+    pub trace_user_property: String,
+}
+
+impl Default for Telemetry {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            enable: false,
+            endpoint: Self::endpoint_default(),
+            service_name: Self::service_name_default(),
+            sample_ratio: Self::sample_ratio_default(),
+            trace_user_property: String::new(),
+        }
+    }
+}
+
+impl Telemetry {
+    fn endpoint_default() -> String {
+        "http://127.0.0.1:4317".into()
+    }
+    fn service_name_default() -> String {
+        "rmqtt".into()
+    }
+    fn sample_ratio_default() -> f64 {
+        1.0
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Alarm {
+    ///Interval of the resource checks, 0 means disabled
+    #[serde(default = "Alarm::check_interval_default", deserialize_with = "deserialize_duration")]
+    pub check_interval: Duration,
+    ///Ratio of the used system memory that raises the high_memory alarm
+    #[serde(default = "Alarm::memory_high_watermark_default")]
+    pub memory_high_watermark: f64,
+    ///Ratio of the open file descriptors to the limit that raises the fd_exhaustion alarm
+    #[serde(default = "Alarm::fd_high_watermark_default")]
+    pub fd_high_watermark: f64,
+    ///Raise the certificate_expiring alarm when a listener certificate expires within this time
+    #[serde(default = "Alarm::cert_expiry_warning_default", deserialize_with = "deserialize_duration")]
+    pub cert_expiry_warning: Duration,
+    ///Maximum number of deactivated alarms kept in history
+    #[serde(default = "Alarm::history_max_default")]
+    pub history_max: usize,
+}
+
+impl Default for Alarm {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            check_interval: Self::check_interval_default(),
+            memory_high_watermark: Self::memory_high_watermark_default(),
+            fd_high_watermark: Self::fd_high_watermark_default(),
+            cert_expiry_warning: Self::cert_expiry_warning_default(),
+            history_max: Self::history_max_default(),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Default, Deserialize)]
-pub struct Mqtt {}
+impl Alarm {
+    fn check_interval_default() -> Duration {
+        Duration::from_secs(30)
+    }
+    fn memory_high_watermark_default() -> f64 {
+        0.8
+    }
+    fn fd_high_watermark_default() -> f64 {
+        0.9
+    }
+    fn cert_expiry_warning_default() -> Duration {
+        Duration::from_secs(30 * 86400)
+    }
+    fn history_max_default() -> usize {
+        1000
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConnectionHistory {
+    ///Record the connect/disconnect events of the clients
+    #[serde(default = "ConnectionHistory::enable_default")]
+    pub enable: bool,
+    ///Maximum number of events kept for each client, the oldest are discarded
+    #[serde(default = "ConnectionHistory::max_events_default")]
+    pub max_events: usize,
+    ///Maximum number of clients kept in history, the least recently active are discarded
+    #[serde(default = "ConnectionHistory::max_clients_default")]
+    pub max_clients: usize,
+}
+
+impl Default for ConnectionHistory {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            enable: Self::enable_default(),
+            max_events: Self::max_events_default(),
+            max_clients: Self::max_clients_default(),
+        }
+    }
+}
+
+impl ConnectionHistory {
+    fn enable_default() -> bool {
+        true
+    }
+    fn max_events_default() -> usize {
+        20
+    }
+    fn max_clients_default() -> usize {
+        100_000
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MemoryBudget {
+    ///Maximum bytes used by the message queues, inflight windows and retained messages, 0 means disabled
+    #[serde(default = "MemoryBudget::max_bytes_default")]
+    pub max_bytes: Bytesize,
+    ///Paused reads resume when the used bytes fall below this ratio of max_bytes
+    #[serde(default = "MemoryBudget::resume_ratio_default")]
+    pub resume_ratio: f64,
+    ///Maximum time a read is paused, QoS 0 messages that are still over budget are then dropped
+    #[serde(default = "MemoryBudget::pause_timeout_default", deserialize_with = "deserialize_duration")]
+    pub pause_timeout: Duration,
+}
+
+impl Default for MemoryBudget {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            max_bytes: Self::max_bytes_default(),
+            resume_ratio: Self::resume_ratio_default(),
+            pause_timeout: Self::pause_timeout_default(),
+        }
+    }
+}
+
+impl MemoryBudget {
+    fn max_bytes_default() -> Bytesize {
+        Bytesize::from(0)
+    }
+    fn resume_ratio_default() -> f64 {
+        0.9
+    }
+    fn pause_timeout_default() -> Duration {
+        Duration::from_secs(5)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HandshakeAdmission {
+    ///Maximum number of handshakes processed concurrently on this node, across all listeners, 0 means disabled
+    #[serde(default = "HandshakeAdmission::max_concurrent_default")]
+    pub max_concurrent: usize,
+    ///What to do with a handshake that arrives while max_concurrent handshakes are in process
+    #[serde(default = "HandshakeAdmission::policy_default")]
+    pub policy: AdmissionPolicy,
+    ///Maximum number of handshakes waiting for admission, the excess is shed, only used by the queue policy
+    #[serde(default = "HandshakeAdmission::max_pending_default")]
+    pub max_pending: usize,
+    ///Maximum time a handshake waits for admission before it is shed, only used by the queue policy
+    #[serde(
+        default = "HandshakeAdmission::queue_timeout_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub queue_timeout: Duration,
+}
+
+impl Default for HandshakeAdmission {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            max_concurrent: Self::max_concurrent_default(),
+            policy: Self::policy_default(),
+            max_pending: Self::max_pending_default(),
+            queue_timeout: Self::queue_timeout_default(),
+        }
+    }
+}
+
+impl HandshakeAdmission {
+    fn max_concurrent_default() -> usize {
+        0
+    }
+    fn policy_default() -> AdmissionPolicy {
+        AdmissionPolicy::Queue
+    }
+    fn max_pending_default() -> usize {
+        10_000
+    }
+    fn queue_timeout_default() -> Duration {
+        Duration::from_secs(5)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdmissionPolicy {
+    ///Wait for admission, up to max_pending handshakes and queue_timeout
+    Queue,
+    ///Shed at once
+    Reject,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReconnectAdvice {
+    ///Name of the MQTT 5.0 user property carrying the number of seconds a refused or disconnected
+    ///client should wait before it reconnects, empty means disabled
+    #[serde(default = "ReconnectAdvice::user_property_default")]
+    pub user_property: String,
+    ///The advised delay is random between retry_after_min and retry_after_max, so that the clients
+    ///spread their reconnects
+    #[serde(default = "ReconnectAdvice::retry_after_min_default", deserialize_with = "deserialize_duration")]
+    pub retry_after_min: Duration,
+    #[serde(default = "ReconnectAdvice::retry_after_max_default", deserialize_with = "deserialize_duration")]
+    pub retry_after_max: Duration,
+}
+
+impl Default for ReconnectAdvice {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            user_property: Self::user_property_default(),
+            retry_after_min: Self::retry_after_min_default(),
+            retry_after_max: Self::retry_after_max_default(),
+        }
+    }
+}
+
+impl ReconnectAdvice {
+    fn user_property_default() -> String {
+        "retry-after".into()
+    }
+    fn retry_after_min_default() -> Duration {
+        Duration::from_secs(1)
+    }
+    fn retry_after_max_default() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    ///Picks the advised delay in seconds, None if disabled
+    #[inline]
+    pub fn retry_after(&self) -> Option<u64> {
+        if self.user_property.is_empty() {
+            return None;
+        }
+        let min = self.retry_after_min.as_secs();
+        let max = self.retry_after_max.as_secs().max(min);
+        Some(rand::Rng::gen_range(&mut rand::thread_rng(), min..=max))
+    }
+}
 
 const BYTESIZE_K: usize = 1024;
 const BYTESIZE_M: usize = 1048576;
@@ -317,6 +1029,48 @@ impl<'de> Deserialize<'de> for Bytesize {
     }
 }
 
+///A secret of the configuration, left out of the configuration shown by the API and of the logs
+#[derive(Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    #[inline]
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    #[inline]
+    fn redacted(&self) -> &'static str {
+        if self.0.is_empty() {
+            ""
+        } else {
+            "******"
+        }
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.redacted())
+    }
+}
+
+impl Serialize for Secret {
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.redacted())
+    }
+}
+
 #[inline]
 pub fn to_bytesize(text: &str) -> usize {
     let text = text.to_uppercase().replace("GB", "G").replace("MB", "M").replace("KB", "K");
@@ -0,0 +1,335 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use super::Bytesize;
+use crate::{MqttError, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    Bool,
+    Integer,
+    Float,
+    String,
+    ///Such as "30s", "1m", "1h30m", "500ms"
+    Duration,
+    ///Such as "512K", "1M", "1G"
+    Bytesize,
+    ///"ip:port"
+    Addr,
+    Array,
+    Object,
+}
+
+impl FieldType {
+    #[inline]
+    fn accepts(&self, v: &Value) -> bool {
+        match self {
+            FieldType::Bool => v.is_boolean(),
+            FieldType::Integer => v.is_i64() || v.is_u64(),
+            FieldType::Float => v.is_number(),
+            FieldType::String => v.is_string(),
+            FieldType::Duration => v.as_str().map(is_duration).unwrap_or(false),
+            FieldType::Bytesize => v.as_str().map(is_bytesize).unwrap_or(false),
+            FieldType::Addr => v.as_str().map(|s| s.parse::<std::net::SocketAddr>().is_ok()).unwrap_or(false),
+            FieldType::Array => v.is_array(),
+            FieldType::Object => v.is_object(),
+        }
+    }
+}
+
+///A field of a plugin config
+#[derive(Debug, Clone, Serialize)]
+pub struct Field {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub typ: FieldType,
+    pub required: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<Value>,
+    ///Minimum of a number, or of the length of a string or an array
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    ///Maximum of a number, or of the length of a string or an array
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<Vec<Value>>,
+    pub descr: String,
+    ///Fields of an object
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<Field>,
+}
+
+impl Field {
+    #[inline]
+    pub fn new<N: Into<String>>(name: N, typ: FieldType) -> Self {
+        Self {
+            name: name.into(),
+            typ,
+            required: false,
+            default: None,
+            min: None,
+            max: None,
+            options: None,
+            descr: String::new(),
+            fields: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+
+    #[inline]
+    pub fn default<V: Into<Value>>(mut self, v: V) -> Self {
+        self.default = Some(v.into());
+        self
+    }
+
+    ///Default of a Duration field, such as the value of the default fn of the config
+    #[inline]
+    pub fn default_duration(self, d: Duration) -> Self {
+        if d.subsec_millis() == 0 {
+            self.default(format!("{}s", d.as_secs()))
+        } else {
+            self.default(format!("{}ms", d.as_millis()))
+        }
+    }
+
+    ///Default of a Bytesize field, such as "1M"
+    #[inline]
+    pub fn default_bytesize(self, b: Bytesize) -> Self {
+        if *b == 0 {
+            self.default("0")
+        } else {
+            self.default(b.string())
+        }
+    }
+
+    ///Default of a field serialized as by the config, such as an enum or a list of addresses
+    #[inline]
+    pub fn default_of<V: Serialize>(self, v: V) -> Self {
+        match serde_json::to_value(v) {
+            Ok(v) => self.default(v),
+            Err(e) => {
+                log::warn!("{}: default serialization error, {:?}", self.name, e);
+                self
+            }
+        }
+    }
+
+    #[inline]
+    pub fn range(mut self, min: f64, max: f64) -> Self {
+        self.min = Some(min);
+        self.max = Some(max);
+        self
+    }
+
+    #[inline]
+    pub fn min(mut self, min: f64) -> Self {
+        self.min = Some(min);
+        self
+    }
+
+    #[inline]
+    pub fn max(mut self, max: f64) -> Self {
+        self.max = Some(max);
+        self
+    }
+
+    #[inline]
+    pub fn options<V: Into<Value>>(mut self, options: Vec<V>) -> Self {
+        self.options = Some(options.into_iter().map(|v| v.into()).collect());
+        self
+    }
+
+    #[inline]
+    pub fn descr<D: Into<String>>(mut self, descr: D) -> Self {
+        self.descr = descr.into();
+        self
+    }
+
+    #[inline]
+    pub fn field(mut self, field: Field) -> Self {
+        self.fields.push(field);
+        self
+    }
+
+    #[inline]
+    fn validate(&self, path: &str, v: &mut Value, errs: &mut Vec<String>) {
+        if !self.typ.accepts(v) {
+            errs.push(format!("{}: expected {}, found {}", path, type_name(self.typ), v));
+            return;
+        }
+        let measure = match v {
+            Value::Number(n) => n.as_f64(),
+            Value::String(s) if self.typ == FieldType::String => Some(s.chars().count() as f64),
+            Value::Array(a) => Some(a.len() as f64),
+            _ => None,
+        };
+        if let Some(measure) = measure {
+            if let Some(min) = self.min {
+                if measure < min {
+                    errs.push(format!("{}: {} is less than the minimum {}", path, v, min));
+                }
+            }
+            if let Some(max) = self.max {
+                if measure > max {
+                    errs.push(format!("{}: {} is greater than the maximum {}", path, v, max));
+                }
+            }
+        }
+        if let Some(options) = &self.options {
+            if !options.contains(v) {
+                errs.push(format!("{}: {} is not one of {}", path, v, Value::Array(options.clone())));
+            }
+        }
+        if let Value::Object(obj) = v {
+            validate_fields(&self.fields, path, obj, errs);
+        }
+    }
+}
+
+///Config schema declared by a plugin, used to validate its config on load and exposed via
+///the management API for UI generation.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConfigSchema {
+    pub fields: Vec<Field>,
+}
+
+impl ConfigSchema {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn field(mut self, field: Field) -> Self {
+        self.fields.push(field);
+        self
+    }
+
+    ///Check the config against the schema and fill in the defaults of the missing fields,
+    ///all violations are reported in one error.
+    #[inline]
+    pub fn validate(&self, cfg: &mut Value) -> Result<()> {
+        let mut errs = Vec::new();
+        if let Value::Object(obj) = cfg {
+            validate_fields(&self.fields, "", obj, &mut errs);
+        } else {
+            errs.push(format!("expected object, found {}", cfg));
+        }
+        if errs.is_empty() {
+            Ok(())
+        } else {
+            Err(MqttError::from(format!("invalid config, {}", errs.join("; "))))
+        }
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> Result<Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+}
+
+#[inline]
+fn validate_fields(fields: &[Field], parent: &str, obj: &mut Map<String, Value>, errs: &mut Vec<String>) {
+    for field in fields {
+        let path = if parent.is_empty() { field.name.clone() } else { format!("{}.{}", parent, field.name) };
+        match obj.get_mut(&field.name) {
+            Some(v) => field.validate(&path, v, errs),
+            None => {
+                if let Some(default) = &field.default {
+                    obj.insert(field.name.clone(), default.clone());
+                } else if field.required {
+                    errs.push(format!("{}: is required", path));
+                }
+            }
+        }
+    }
+}
+
+#[inline]
+fn type_name(typ: FieldType) -> &'static str {
+    match typ {
+        FieldType::Bool => "bool",
+        FieldType::Integer => "integer",
+        FieldType::Float => "float",
+        FieldType::String => "string",
+        FieldType::Duration => "duration, such as \"30s\"",
+        FieldType::Bytesize => "bytesize, such as \"1M\"",
+        FieldType::Addr => "address, such as \"127.0.0.1:8080\"",
+        FieldType::Array => "array",
+        FieldType::Object => "object",
+    }
+}
+
+#[inline]
+fn is_measure(text: &str, units: &[&str]) -> bool {
+    let mut rest = text.trim();
+    if rest.is_empty() {
+        return false;
+    }
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        if digits == 0 {
+            return false;
+        }
+        rest = &rest[digits..];
+        match units.iter().find(|u| rest.to_lowercase().starts_with(*u)) {
+            Some(u) => rest = &rest[u.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+#[inline]
+fn is_duration(text: &str) -> bool {
+    is_measure(text, &["ms", "s", "m", "h", "d", "w", "f"])
+}
+
+#[inline]
+fn is_bytesize(text: &str) -> bool {
+    text.trim() == "0" || is_measure(text, &["gb", "mb", "kb", "g", "m", "k", "b"])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate() {
+        let schema = ConfigSchema::new()
+            .field(Field::new("interval", FieldType::Duration).default("10s"))
+            .field(Field::new("workers", FieldType::Integer).range(1.0, 64.0).required())
+            .field(Field::new("mode", FieldType::String).options(vec!["a", "b"]))
+            .field(
+                Field::new("http", FieldType::Object).field(Field::new("addr", FieldType::Addr).required()),
+            );
+
+        let mut cfg = json!({"workers": 8, "mode": "a", "http": {"addr": "127.0.0.1:80"}});
+        assert!(schema.validate(&mut cfg).is_ok());
+        assert_eq!(cfg["interval"], json!("10s"));
+
+        let mut cfg = json!({"workers": 0, "mode": "c", "interval": "10x", "http": {}});
+        let err = schema.validate(&mut cfg).unwrap_err().to_string();
+        assert!(err.contains("interval: expected duration"));
+        assert!(err.contains("workers: 0 is less than the minimum 1"));
+        assert!(err.contains("mode: \"c\" is not one of"));
+        assert!(err.contains("http.addr: is required"));
+
+        assert!(is_duration("1h30m") && is_duration("500ms") && !is_duration("") && !is_duration("s"));
+        let field = Field::new("interval", FieldType::Duration).default_duration(Duration::from_millis(1500));
+        assert_eq!(field.default, Some(json!("1500ms")));
+        assert!(is_bytesize("1M") && is_bytesize("512KB") && is_bytesize("0") && !is_bytesize("1X"));
+        let field =
+            Field::new("max_bytes", FieldType::Bytesize).default_bytesize(Bytesize::from(1024 * 1024));
+        assert_eq!(field.default, Some(json!("1M")));
+    }
+}
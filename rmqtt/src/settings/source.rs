@@ -0,0 +1,188 @@
+use std::path::{Path, PathBuf};
+
+use config::{Config, ConfigError, File, FileFormat};
+
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+///Merges the config file `name`, with or without the .toml extension, and then the files it
+///includes, so that they override it. A file of another extension is merged in its own format,
+///without interpolation or includes.
+///
+///In the string values, `${NAME}` is replaced with the environment variable NAME, an error if it is
+///not set, and `${NAME:-default}` with default if it is not set, `$${` is a literal `${`. The file
+///is parsed before, so the value of a variable is kept as is, whatever quotes or newlines it has,
+///and the comments are not interpolated. A number or a boolean is given as a string, such as
+///`workers = "${WORKERS:-4}"`.
+///
+///`include = "file"` or `include = ["file", ...]`, before the first table, lists the included
+///files, relative to the directory of the including file.
+pub(crate) fn merge(s: &mut Config, name: &str, required: bool) -> Result<(), ConfigError> {
+    match find(Path::new(name)) {
+        Some(path) if is_toml(&path) => {
+            let mut contents = Vec::new();
+            read(&path, 0, &mut contents)?;
+            for content in contents {
+                s.merge(File::from_str(&content, FileFormat::Toml))?;
+            }
+            Ok(())
+        }
+        Some(path) => {
+            s.merge(File::from(path.as_path()))?;
+            Ok(())
+        }
+        None if required => Err(ConfigError::Message(format!("configuration file \"{}\" not found", name))),
+        None => Ok(()),
+    }
+}
+
+#[inline]
+fn find(name: &Path) -> Option<PathBuf> {
+    if name.is_file() {
+        return Some(name.to_path_buf());
+    }
+    let path = PathBuf::from(format!("{}.toml", name.display()));
+    if path.is_file() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+#[inline]
+fn is_toml(path: &Path) -> bool {
+    path.extension().map(|ext| ext.eq_ignore_ascii_case("toml")).unwrap_or(true)
+}
+
+fn read(path: &Path, depth: usize, contents: &mut Vec<String>) -> Result<(), ConfigError> {
+    let err = |e: String| ConfigError::Message(format!("{}, {}", path.display(), e));
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(err("includes are nested too deep".into()));
+    }
+    let content = std::fs::read_to_string(path).map_err(|e| err(e.to_string()))?;
+    let (content, includes) = parse(&content).map_err(err)?;
+    contents.push(content);
+
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    for include in includes {
+        let include_path = find(&dir.join(&include))
+            .ok_or_else(|| err(format!("included file \"{}\" not found", include)))?;
+        read(&include_path, depth + 1, contents)?;
+    }
+    Ok(())
+}
+
+///Takes out the includes and interpolates the string values
+fn parse(content: &str) -> Result<(String, Vec<String>), String> {
+    let mut value = content.parse::<toml::Value>().map_err(|e| e.to_string())?;
+    let includes = match value.as_table_mut().and_then(|table| table.remove("include")) {
+        None => Vec::new(),
+        Some(toml::Value::String(include)) => vec![include],
+        Some(toml::Value::Array(items)) => items
+            .into_iter()
+            .map(|item| match item {
+                toml::Value::String(include) => Ok(include),
+                _ => Err("include is not a list of files".to_owned()),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        Some(_) => return Err("include is not a file or a list of files".into()),
+    };
+    interpolate(&mut value)?;
+    Ok((toml::to_string(&value).map_err(|e| e.to_string())?, includes))
+}
+
+fn interpolate(value: &mut toml::Value) -> Result<(), String> {
+    match value {
+        toml::Value::String(s) => *s = interpolate_str(s)?,
+        toml::Value::Array(items) => {
+            for item in items {
+                interpolate(item)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, item) in table.iter_mut() {
+                interpolate(item)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn interpolate_str(text: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find("${") {
+        let (before, after) = rest.split_at(pos);
+        if let Some(before) = before.strip_suffix('$') {
+            out.push_str(before);
+            out.push_str("${");
+            rest = &after[2..];
+            continue;
+        }
+        out.push_str(before);
+        let end = after.find('}').ok_or_else(|| format!("\"${{\" is not closed, {}", text))?;
+        let expr = &after[2..end];
+        let value = match expr.split_once(":-") {
+            Some((name, default)) => std::env::var(name).unwrap_or_else(|_| default.to_owned()),
+            None => std::env::var(expr).map_err(|_| format!("environment variable {} is not set", expr))?,
+        };
+        out.push_str(&value);
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolate() {
+        std::env::set_var("RMQTT_SOURCE_TEST_QUOTED", "a\"b\nc = 1");
+        std::env::remove_var("RMQTT_SOURCE_TEST_UNSET");
+        let (content, includes) = parse(
+            r#"
+            #${RMQTT_SOURCE_TEST_UNSET}
+            quoted = "${RMQTT_SOURCE_TEST_QUOTED}"
+            default = "x${RMQTT_SOURCE_TEST_UNSET:-1883}"
+            literal = "$${RMQTT_SOURCE_TEST_QUOTED}"
+            [table]
+            list = ["${RMQTT_SOURCE_TEST_UNSET:-a}", 1]
+            "#,
+        )
+        .unwrap();
+        assert!(includes.is_empty());
+        let value = content.parse::<toml::Value>().unwrap();
+        assert_eq!(value["quoted"].as_str(), Some("a\"b\nc = 1"));
+        assert!(value.get("c").is_none());
+        assert_eq!(value["default"].as_str(), Some("x1883"));
+        assert_eq!(value["literal"].as_str(), Some("${RMQTT_SOURCE_TEST_QUOTED}"));
+        assert_eq!(value["table"]["list"][0].as_str(), Some("a"));
+
+        assert!(parse(r#"a = "${RMQTT_SOURCE_TEST_UNSET}""#).is_err());
+        assert!(parse(r#"a = "${RMQTT_SOURCE_TEST_UNSET""#).is_err());
+    }
+
+    #[test]
+    fn include() {
+        let dir = std::env::temp_dir().join(format!("rmqtt-source-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.toml"), "include = [\"node\"]\na = 1\nb = 1\n").unwrap();
+        std::fs::write(dir.join("node.toml"), "b = 2\n").unwrap();
+        std::fs::write(dir.join("other.json"), "{\"c\": 3}").unwrap();
+
+        let mut s = Config::new();
+        merge(&mut s, dir.join("main").to_str().unwrap(), true).unwrap();
+        assert_eq!(s.get_int("a").unwrap(), 1);
+        assert_eq!(s.get_int("b").unwrap(), 2);
+        assert!(s.get_str("include").is_err());
+
+        assert!(merge(&mut s, dir.join("missing").to_str().unwrap(), false).is_ok());
+        assert!(merge(&mut s, dir.join("missing").to_str().unwrap(), true).is_err());
+        assert!(!is_toml(&dir.join("other.json")));
+        assert!(is_toml(&dir.join("main.toml")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
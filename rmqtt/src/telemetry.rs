@@ -0,0 +1,204 @@
+//! OpenTelemetry tracing of the connect/publish/deliver path.
+//!
+//! Spans are only recorded when rmqtt is built with the `otel` feature and `telemetry.enable` is set,
+//! otherwise `Span` is a no-op. The span context of a message is carried by `Publish::trace_context`
+//! so that a single message can be followed across the nodes of the cluster.
+//!
+//! With `telemetry.trace_user_property` set, the trace context is also taken from and given to the
+//! MQTT 5 clients in that user property, for tracing from the publisher to the subscribers.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use bytestring::ByteString;
+
+use crate::broker::types::{Publish, UserProperties};
+use crate::{Result, Runtime};
+
+///W3C trace context, the value of the `traceparent` header
+pub type TraceContext = String;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[inline]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+///Install the OTLP exporter, called once at startup
+pub fn init() -> Result<()> {
+    let cfg = &Runtime::instance().settings.telemetry;
+    if !cfg.enable {
+        return Ok(());
+    }
+
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::sdk::{trace as sdktrace, Resource};
+        use opentelemetry::KeyValue;
+        use opentelemetry_otlp::WithExportConfig;
+
+        opentelemetry::global::set_text_map_propagator(
+            opentelemetry::sdk::propagation::TraceContextPropagator::new(),
+        );
+        opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(cfg.endpoint.clone()))
+            .with_trace_config(
+                sdktrace::config()
+                    .with_sampler(sdktrace::Sampler::TraceIdRatioBased(cfg.sample_ratio))
+                    .with_resource(Resource::new(vec![
+                        KeyValue::new("service.name", cfg.service_name.clone()),
+                        KeyValue::new("rmqtt.node_id", Runtime::instance().node.id() as i64),
+                    ])),
+            )
+            .install_batch(opentelemetry::runtime::Tokio)
+            .map_err(anyhow::Error::new)?;
+        ENABLED.store(true, Ordering::SeqCst);
+        log::info!("OpenTelemetry tracing is enabled, endpoint: {}", cfg.endpoint);
+    }
+
+    #[cfg(not(feature = "otel"))]
+    log::warn!("telemetry.enable is set, but rmqtt is built without the `otel` feature");
+
+    Ok(())
+}
+
+///Flush the pending spans, called at shutdown
+#[inline]
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    if enabled() {
+        opentelemetry::global::shutdown_tracer_provider();
+    }
+}
+
+///A span that ends when dropped
+pub struct Span {
+    #[cfg(feature = "otel")]
+    cx: Option<opentelemetry::Context>,
+}
+
+impl Span {
+    ///Start a span, `parent` is the trace context of the remote parent span
+    #[inline]
+    pub fn start(name: &'static str, parent: Option<&TraceContext>) -> Span {
+        #[cfg(feature = "otel")]
+        {
+            use opentelemetry::trace::{TraceContextExt, Tracer};
+
+            if !enabled() {
+                return Span { cx: None };
+            }
+            let parent_cx = parent.map(|p| extract(p)).unwrap_or_default();
+            let span = opentelemetry::global::tracer("rmqtt").start_with_context(name, &parent_cx);
+            Span { cx: Some(parent_cx.with_span(span)) }
+        }
+        #[cfg(not(feature = "otel"))]
+        {
+            let _ = (name, parent);
+            Span {}
+        }
+    }
+
+    #[inline]
+    pub fn set_attribute<V: Into<String>>(&self, key: &'static str, value: V) {
+        #[cfg(feature = "otel")]
+        {
+            use opentelemetry::trace::TraceContextExt;
+            if let Some(cx) = &self.cx {
+                cx.span().set_attribute(opentelemetry::KeyValue::new(key, value.into()));
+            }
+        }
+        #[cfg(not(feature = "otel"))]
+        let _ = (key, value);
+    }
+
+    ///Like `set_attribute`, the value is only built when the span is recorded
+    #[inline]
+    pub fn set_attribute_with<V: Into<String>, F: FnOnce() -> V>(&self, key: &'static str, f: F) {
+        #[cfg(feature = "otel")]
+        if self.cx.is_some() {
+            self.set_attribute(key, f());
+        }
+        #[cfg(not(feature = "otel"))]
+        let _ = (key, f);
+    }
+
+    ///The trace context of this span, to be propagated to the child spans
+    #[inline]
+    pub fn context(&self) -> Option<TraceContext> {
+        #[cfg(feature = "otel")]
+        {
+            use opentelemetry::propagation::TextMapPropagator;
+            let cx = self.cx.as_ref()?;
+            let mut carrier = std::collections::HashMap::new();
+            opentelemetry::sdk::propagation::TraceContextPropagator::new().inject_context(cx, &mut carrier);
+            carrier.remove("traceparent")
+        }
+        #[cfg(not(feature = "otel"))]
+        None
+    }
+}
+
+#[cfg(feature = "otel")]
+impl Drop for Span {
+    #[inline]
+    fn drop(&mut self) {
+        use opentelemetry::trace::TraceContextExt;
+        if let Some(cx) = self.cx.take() {
+            cx.span().end();
+        }
+    }
+}
+
+///The traceparent in the trace user property, if it is configured and the value is valid
+#[inline]
+pub fn extract_user_property(props: &UserProperties) -> Option<TraceContext> {
+    let name = &Runtime::instance().settings.telemetry.trace_user_property;
+    if name.is_empty() {
+        return None;
+    }
+    props
+        .iter()
+        .find(|(k, _)| &**k == name.as_str())
+        .map(|(_, v)| v)
+        .filter(|v| is_traceparent(v))
+        .map(|v| v.to_string())
+}
+
+///Sets the trace user property of the message to its trace context, replacing the existing one
+#[inline]
+pub fn inject_user_property(publish: &mut Publish) {
+    let name = &Runtime::instance().settings.telemetry.trace_user_property;
+    if name.is_empty() {
+        return;
+    }
+    if let Some(trace_context) = publish.trace_context.as_ref() {
+        let props = &mut publish.properties.user_properties;
+        props.retain(|(k, _)| &**k != name.as_str());
+        props.push((ByteString::from(name.as_str()), ByteString::from(trace_context.as_str())));
+    }
+}
+
+///version-trace_id-parent_id-flags, such as 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01
+#[inline]
+fn is_traceparent(v: &str) -> bool {
+    let parts = v.split('-').collect::<Vec<_>>();
+    parts.len() == 4
+        && parts
+            .iter()
+            .zip([2, 32, 16, 2])
+            .all(|(p, len)| p.len() == len && p.bytes().all(|b| b.is_ascii_hexdigit()))
+        && parts[0] != "ff"
+        && parts[1].bytes().any(|b| b != b'0')
+        && parts[2].bytes().any(|b| b != b'0')
+}
+
+#[cfg(feature = "otel")]
+#[inline]
+fn extract(trace_context: &str) -> opentelemetry::Context {
+    use opentelemetry::propagation::TextMapPropagator;
+    let mut carrier = std::collections::HashMap::new();
+    carrier.insert("traceparent".to_string(), trace_context.to_string());
+    opentelemetry::sdk::propagation::TraceContextPropagator::new().extract(&carrier)
+}